@@ -7,13 +7,18 @@ struct parrot {
     backend: Backend<Sentence, Body>,
 }
 
+#[allow(non_camel_case_types)]
+struct mumbler {
+    backend: Backend<Sentence, Body>,
+}
+
 /// a simple STRING dictionary in your VCL
 #[varnish::vmod(docs = "README.md")]
 mod be {
     use varnish::ffi::VCL_BACKEND;
     use varnish::vcl::{Backend, Ctx, VclError};
 
-    use super::{parrot, Sentence};
+    use super::{mumbler, parrot, Sentence};
 
     /// parrot is our VCL object, which just holds a rust Backend,
     /// it only needs two functions:
@@ -36,6 +41,7 @@ mod be {
                 name,
                 Sentence {
                     data: Vec::from(to_repeat),
+                    chunked: false,
                 },
                 false,
             )?;
@@ -47,11 +53,39 @@ mod be {
             self.backend.vcl_ptr()
         }
     }
+
+    /// mumbler is a parrot that doesn't announce the length of what it says,
+    /// the body is chunked and followed by an x-length trailer
+    impl mumbler {
+        pub fn new(
+            ctx: &mut Ctx,
+            #[vcl_name] name: &str,
+            to_repeat: &str,
+        ) -> Result<Self, VclError> {
+            let backend = Backend::new(
+                ctx,
+                name,
+                Sentence {
+                    data: Vec::from(to_repeat),
+                    chunked: true,
+                },
+                false,
+            )?;
+
+            Ok(mumbler { backend })
+        }
+
+        pub unsafe fn backend(&self) -> VCL_BACKEND {
+            self.backend.vcl_ptr()
+        }
+    }
 }
 
 // Sentence is just a Vec<u8> holding the string we were asked to repeat
 struct Sentence {
     data: Vec<u8>,
+    // hide the length, and send it as a trailer instead
+    chunked: bool,
 }
 
 // a lot of the Serve trait's methods are optional, but we need to implement
@@ -64,13 +98,15 @@ impl Serve<Body> for Sentence {
     }
 
     fn get_headers(&self, ctx: &mut Ctx) -> Result<Option<Body>, VclError> {
-        let beresp = ctx.http_beresp.as_mut().unwrap();
+        let beresp = ctx.beresp_mut().unwrap();
         beresp.set_status(200);
         beresp.set_header("server", "parrot")?;
 
         Ok(Some(Body {
             p: self.data.as_ptr(),
             left: self.data.len(),
+            sent: 0,
+            chunked: self.chunked,
         }))
     }
 }
@@ -81,6 +117,8 @@ impl Serve<Body> for Sentence {
 struct Body {
     p: *const u8,
     left: usize,
+    sent: usize,
+    chunked: bool,
 }
 
 impl Transfer for Body {
@@ -107,13 +145,23 @@ impl Transfer for Body {
             self.p = self.p.add(l);
         }
         self.left -= l;
+        self.sent += l;
 
         // everything went fine, we copied l bytes into buf
         Ok(l)
     }
 
-    // we know from the start how much we'll send
+    // we know from the start how much we'll send, but we may keep it to ourselves
     fn len(&self) -> Option<usize> {
-        Some(self.left)
+        if self.chunked {
+            None
+        } else {
+            Some(self.left)
+        }
+    }
+
+    // only called for bodies of unknown length, once everything was read
+    fn trailers(&mut self) -> Vec<(String, String)> {
+        vec![("x-length".to_string(), self.sent.to_string())]
     }
 }
@@ -0,0 +1,141 @@
+use std::ffi::c_char;
+use std::hint::black_box;
+use std::ptr;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use varnish::bench::{BenchCtx, BenchHttp};
+use varnish::ffi::{vrt_ctx, VCL_BOOL, VCL_INT, VCL_STRING};
+use varnish::vcl::{Ctx, IntoVCL, VclError};
+
+/// The C arguments of `example.captain_obvious()`, as generated by `#[vmod]`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CaptainObviousArgs {
+    valid_opt: c_char,
+    opt: VCL_INT,
+}
+
+fn wrappers(c: &mut Criterion) {
+    let vmod = varnish::bench_vmod!(vmod_example, Vmod_example_Data);
+    let mut bench_ctx = BenchCtx::new(16 * 1024);
+    let mut g = c.benchmark_group("wrapper");
+
+    varnish::bench_vmod_fn!(g, "is_even", bench_ctx, vmod.is_even(VCL_INT(8)) => VCL_BOOL);
+    let args = CaptainObviousArgs {
+        valid_opt: 1,
+        opt: VCL_INT(42),
+    };
+    let args = ptr::from_ref(&args);
+    varnish::bench_vmod_fn!(
+        g, "captain_obvious", bench_ctx, vmod.captain_obvious(args) => VCL_STRING
+    );
+    assert!(!bench_ctx.failed());
+}
+
+/// How the generated wrappers read the optional arguments: field by field from the C struct
+/// before, from a copy of the struct resolved into `Option`s now
+fn optional_args(c: &mut Criterion) {
+    unsafe extern "C" fn before(_ctx: *mut vrt_ctx, args: *const CaptainObviousArgs) {
+        let args = args.as_ref().unwrap();
+        let opt: Option<i64> = if args.valid_opt != 0 {
+            args.opt.into()
+        } else {
+            None
+        };
+        black_box(opt);
+    }
+    unsafe extern "C" fn after(_ctx: *mut vrt_ctx, args: *const CaptainObviousArgs) {
+        let args = *args.as_ref().unwrap();
+        let opt = (args.valid_opt != 0).then_some(args.opt);
+        let opt: Option<i64> = opt.and_then(Into::into);
+        black_box(opt);
+    }
+
+    let args = CaptainObviousArgs {
+        valid_opt: 1,
+        opt: VCL_INT(42),
+    };
+    let mut g = c.benchmark_group("optional_args");
+    for (name, func) in [
+        ("before", before as unsafe extern "C" fn(_, _)),
+        ("after", after),
+    ] {
+        g.bench_function(name, |b| {
+            b.iter(|| unsafe { func(ptr::null_mut(), black_box(ptr::from_ref(&args))) });
+        });
+    }
+}
+
+/// Creating a `Ctx`: it used to wrap all the HTTP objects, it now only checks the context
+fn ctx(c: &mut Criterion) {
+    let http = || BenchHttp::request("GET", "/", &[("host", "example.com")]);
+    let mut bench_ctx = BenchCtx::new(1024)
+        .with_req(http())
+        .with_resp(BenchHttp::response(200, &[]))
+        .with_bereq(http())
+        .with_beresp(BenchHttp::response(200, &[]));
+    let raw = bench_ctx.as_ptr();
+    let mut g = c.benchmark_group("ctx");
+
+    g.bench_function("before", |b| {
+        b.iter(|| {
+            let ctx = unsafe { Ctx::from_ptr(black_box(raw)) };
+            black_box((
+                ctx.req(),
+                ctx.req_top(),
+                ctx.resp(),
+                ctx.bereq(),
+                ctx.beresp(),
+            ));
+        });
+    });
+    g.bench_function("after", |b| {
+        b.iter(|| black_box(unsafe { Ctx::from_ptr(black_box(raw)) }));
+    });
+}
+
+/// Converting strings into `VCL_STRING`: copied after looking for a NULL byte before, written
+/// through `WsStrBuffer` now
+fn into_vcl(c: &mut Criterion) {
+    let mut bench_ctx = BenchCtx::new(16 * 1024);
+    let s = "I was given 42 as argument";
+    let mut g = c.benchmark_group("into_vcl");
+
+    g.bench_function("str/before", |b| {
+        b.iter(|| {
+            bench_ctx.reset();
+            let mut ctx = bench_ctx.ctx();
+            let txt = ctx.ws.copy_bytes_with_null(black_box(s)).unwrap();
+            black_box(VCL_STRING(txt.b));
+        });
+    });
+    g.bench_function("str/after", |b| {
+        b.iter(|| {
+            bench_ctx.reset();
+            let mut ctx = bench_ctx.ctx();
+            let s: Result<VCL_STRING, VclError> = black_box(s).into_vcl(&mut ctx.ws);
+            black_box(s.unwrap());
+        });
+    });
+    g.bench_function("format/before", |b| {
+        b.iter(|| {
+            bench_ctx.reset();
+            let mut ctx = bench_ctx.ctx();
+            let s = format!("I was given {} as argument", black_box(42));
+            let txt = ctx.ws.copy_bytes_with_null(s).unwrap();
+            black_box(VCL_STRING(txt.b));
+        });
+    });
+    g.bench_function("format/after", |b| {
+        b.iter(|| {
+            bench_ctx.reset();
+            let mut ctx = bench_ctx.ctx();
+            let s: Result<VCL_STRING, VclError> =
+                format_args!("I was given {} as argument", black_box(42)).into_vcl(&mut ctx.ws);
+            black_box(s.unwrap());
+        });
+    });
+}
+
+criterion_group!(benches, wrappers, optional_args, ctx, into_vcl);
+criterion_main!(benches);
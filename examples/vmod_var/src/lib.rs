@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use varnish::ffi::VCL_STRING;
+
+varnish::run_vtc_tests!("tests/*.vtc");
+
+/// A typed variable value
+#[derive(Debug, Clone, Copy)]
+pub enum Value {
+    /// A string copied to the task workspace
+    Str(VCL_STRING),
+    Int(i64),
+    Real(f64),
+    Duration(Duration),
+    Bool(bool),
+}
+
+/// The variables of a task, stored in its `PRIV_TASK`
+///
+/// String values are copied to the task workspace, so they live exactly as long as the task,
+/// and are handed back to VCL without any other copy.
+#[derive(Debug, Default)]
+pub struct Vars {
+    values: HashMap<String, Value>,
+}
+
+impl Vars {
+    fn set(vars: &mut Option<Box<Self>>, name: &str, value: Value) {
+        let vars = vars.get_or_insert_with(Box::default);
+        if let Some(v) = vars.values.get_mut(name) {
+            *v = value;
+        } else {
+            vars.values.insert(name.to_string(), value);
+        }
+    }
+
+    fn get(vars: Option<&Self>, name: &str) -> Option<Value> {
+        vars?.values.get(name).copied()
+    }
+}
+
+/// Typed variables, scoped to the current task
+///
+/// Each client request and each backend request has its own set of variables, which disappear at
+/// the end of the task. A variable has the type of the last value it was set to: reading it with
+/// the getter of another type, or reading an unset variable, returns the default value of that
+/// type, e.g. `0` or an empty string.
+#[varnish::vmod(docs = "README.md")]
+mod var {
+    use std::time::Duration;
+
+    use varnish::ffi::VCL_STRING;
+    use varnish::vcl::{VclError, Workspace};
+
+    use super::{Value, Vars};
+
+    /// Set the `name` variable to a string.
+    pub fn set(
+        ws: &mut Workspace,
+        #[shared_per_task] vars: &mut Option<Box<Vars>>,
+        name: &str,
+        value: &str,
+    ) -> Result<(), VclError> {
+        let value = VCL_STRING(ws.copy_bytes_with_null(value)?.b);
+        Vars::set(vars, name, Value::Str(value));
+        Ok(())
+    }
+
+    /// Get the string value of the `name` variable, unset if the variable is not a string.
+    pub unsafe fn get(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str) -> VCL_STRING {
+        match Vars::get(vars.as_deref(), name) {
+            Some(Value::Str(v)) => v,
+            _ => VCL_STRING::default(),
+        }
+    }
+
+    /// Set the `name` variable to an integer.
+    pub fn set_int(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str, value: i64) {
+        Vars::set(vars, name, Value::Int(value));
+    }
+
+    /// Get the integer value of the `name` variable, `0` if not set.
+    pub fn get_int(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str) -> i64 {
+        match Vars::get(vars.as_deref(), name) {
+            Some(Value::Int(v)) => v,
+            _ => 0,
+        }
+    }
+
+    /// Set the `name` variable to a real number.
+    pub fn set_real(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str, value: f64) {
+        Vars::set(vars, name, Value::Real(value));
+    }
+
+    /// Get the real value of the `name` variable, `0.0` if not set.
+    pub fn get_real(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str) -> f64 {
+        match Vars::get(vars.as_deref(), name) {
+            Some(Value::Real(v)) => v,
+            _ => 0.0,
+        }
+    }
+
+    /// Set the `name` variable to a duration.
+    pub fn set_duration(
+        #[shared_per_task] vars: &mut Option<Box<Vars>>,
+        name: &str,
+        value: Duration,
+    ) {
+        Vars::set(vars, name, Value::Duration(value));
+    }
+
+    /// Get the duration value of the `name` variable, `0s` if not set.
+    pub fn get_duration(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str) -> Duration {
+        match Vars::get(vars.as_deref(), name) {
+            Some(Value::Duration(v)) => v,
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Set the `name` variable to a boolean.
+    pub fn set_bool(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str, value: bool) {
+        Vars::set(vars, name, Value::Bool(value));
+    }
+
+    /// Get the boolean value of the `name` variable, `false` if not set.
+    pub fn get_bool(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str) -> bool {
+        matches!(Vars::get(vars.as_deref(), name), Some(Value::Bool(true)))
+    }
+
+    /// Check if the `name` variable is set, with any type.
+    pub fn is_set(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str) -> bool {
+        Vars::get(vars.as_deref(), name).is_some()
+    }
+
+    /// Unset the `name` variable.
+    pub fn unset(#[shared_per_task] vars: &mut Option<Box<Vars>>, name: &str) {
+        if let Some(vars) = vars {
+            vars.values.remove(name);
+        }
+    }
+
+    /// Unset all the variables of the task.
+    pub fn clear(#[shared_per_task] vars: &mut Option<Box<Vars>>) {
+        *vars = None;
+    }
+}
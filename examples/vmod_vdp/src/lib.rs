@@ -1,7 +1,11 @@
 use std::ffi::CStr;
+use std::sync::OnceLock;
 
 use varnish::ffi::VdpAction;
-use varnish::vcl::{Ctx, DeliveryProcCtx, DeliveryProcessor, InitResult, PushResult};
+use varnish::vcl::{
+    Ctx, DeliveryProcCtx, DeliveryProcessor, InitResult, MaxSize, MaxSizeCounters, Meter,
+    MeterCounters, Overflow, PushResult,
+};
 
 varnish::run_vtc_tests!("tests/*.vtc");
 
@@ -13,9 +17,12 @@ varnish::run_vtc_tests!("tests/*.vtc");
 /// processor (VDP) named "flipper".
 #[varnish::vmod(docs = "README.md")]
 mod vdp {
-    use varnish::vcl::{DeliveryFilters, Event};
+    use varnish::vcl::{
+        DeliveryFilters, Event, MaxSizeCounters, MaxSizeDeliver, MeterCounters,
+        MeterDeliveryProcessor,
+    };
 
-    use super::Flipper;
+    use super::{Flipper, HeaderLimit, Tenant, COUNTERS, TENANT_COUNTERS};
 
     /// We need the event function here to declare our VDP.
     /// However, there's no "manual" VCL function for us to implement here,
@@ -25,6 +32,14 @@ mod vdp {
     pub fn event(vdp: &mut DeliveryFilters, event: Event) {
         if let Event::Load = event {
             vdp.register::<Flipper>();
+            if let Ok(counters) = MaxSizeCounters::new("vdp", "maxsize") {
+                COUNTERS.get_or_init(|| counters);
+            }
+            vdp.register::<MaxSizeDeliver<HeaderLimit>>();
+            if let Ok(counters) = MeterCounters::new("vdp", 2) {
+                TENANT_COUNTERS.get_or_init(|| counters);
+            }
+            vdp.register::<MeterDeliveryProcessor<Tenant>>();
         }
     }
 }
@@ -64,3 +79,50 @@ impl DeliveryProcessor for Flipper {
         }
     }
 }
+
+static COUNTERS: OnceLock<MaxSizeCounters> = OnceLock::new();
+
+// a size limit named "maxsize", read from the `X-Max-Size` request header, the body being
+// truncated rather than aborted if `X-Overflow` is "truncate"
+struct HeaderLimit;
+
+impl MaxSize for HeaderLimit {
+    fn name() -> &'static CStr {
+        c"maxsize"
+    }
+
+    fn limit(ctx: &Ctx) -> Option<u64> {
+        ctx.req()?.header("X-Max-Size")?.parse().ok()
+    }
+
+    fn overflow(ctx: &Ctx) -> Overflow {
+        match ctx.req().and_then(|req| req.header("X-Overflow")) {
+            Some("truncate") => Overflow::Truncate,
+            _ => Overflow::Abort,
+        }
+    }
+
+    fn counters() -> Option<&'static MaxSizeCounters> {
+        COUNTERS.get()
+    }
+}
+
+static TENANT_COUNTERS: OnceLock<MeterCounters> = OnceLock::new();
+
+// a meter named "meter", counting the bytes delivered to each `X-Tenant`, the first two tenants
+// getting counters of their own
+struct Tenant;
+
+impl Meter for Tenant {
+    fn name() -> &'static CStr {
+        c"meter"
+    }
+
+    fn header() -> &'static str {
+        "X-Tenant"
+    }
+
+    fn counters() -> Option<&'static MeterCounters> {
+        TENANT_COUNTERS.get()
+    }
+}
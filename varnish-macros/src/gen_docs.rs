@@ -71,14 +71,14 @@ import {ident} from "path/to/lib{ident}.so";
         ident = info.ident
     );
 
-    for func in &info.funcs {
+    for func in info.all_funcs() {
         if !matches!(func.func_type, FuncType::Function) {
             continue;
         }
         write_function(&mut docs, "###", "Function", func);
     }
 
-    for obj in &info.objects {
+    for obj in info.all_objects() {
         ln!(docs, "\n### Object `{}`", obj.ident);
 
         write_docs(&mut docs, &obj.docs, "###");
@@ -149,8 +149,8 @@ fn fn_sig(func: &FuncInfo, user_args: &Vec<(&ParamTypeInfo, &ParamInfo)>) -> Str
             wrt!(res, ", ");
         }
         res.push_str(&bracketed_name(arg, ty));
-        if !ty.default.is_null() {
-            wrt!(res, " = {}", ty.default);
+        if let Some(default) = &ty.default {
+            wrt!(res, " = {default}");
         }
     }
     wrt!(res, ")");
@@ -174,7 +174,10 @@ fn get_user_args(func: &FuncInfo) -> Vec<(&ParamTypeInfo, &ParamInfo)> {
 }
 
 fn bracketed_name(arg: &ParamTypeInfo, ty: &ParamInfo) -> String {
-    let vcc = ty.ty_info.to_vcc_type();
+    let vcc = match &ty.enum_ty {
+        Some(e) => format!("ENUM {{{}}}", e.vcl_names().collect::<Vec<_>>().join(", ")),
+        None => ty.to_vcc_type().to_string(),
+    };
     let ident = &arg.ident;
     if matches!(ty.kind, ParamKind::Optional) {
         format!("[{vcc} {ident}]")
@@ -26,6 +26,8 @@ pub struct FuncProcessor {
     func_always_after_call: Vec<TokenStream>,
     /// Rust wrapper requires `__ctx`
     func_needs_ctx: bool,
+    /// The VMOD was declared with `#[vmod(inline)]`
+    inline: bool,
 
     /// C function list of arguments for funcs with no optional args, e.g. `["VCL_INT", "VCL_STRING"]`
     cproto_wrapper_args: Vec<&'static str>,
@@ -63,10 +65,17 @@ pub struct FuncProcessor {
     pub wrapper_function_body: TokenStream,
     /// JSON blob for the function
     pub json: Value,
+    /// This is the event handler
+    pub is_event: bool,
 }
 
 impl FuncProcessor {
-    pub fn from_info(names: Names, info: &FuncInfo, shared_types: &SharedTypes) -> Self {
+    pub fn from_info(
+        names: Names,
+        info: &FuncInfo,
+        shared_types: &SharedTypes,
+        inline: bool,
+    ) -> Self {
         let mut obj = Self {
             opt_args_ty_name: if info.has_optional_args {
                 names.arg_struct_name()
@@ -74,24 +83,34 @@ impl FuncProcessor {
                 String::new()
             },
             names,
+            inline,
             ..Default::default()
         };
         obj.init(info, shared_types);
         obj
     }
 
+    /// The name of the export "C" function
+    pub fn wrapper_fn_ident(&self) -> Ident {
+        self.names.wrapper_fn_name().to_ident()
+    }
+
     fn init(&mut self, info: &FuncInfo, shared_types: &SharedTypes) {
+        self.is_event = matches!(info.func_type, Event);
         if matches!(info.func_type, Destructor) {
-            self.func_pre_call
-                .push(quote! { drop(Box::from_raw(*__objp)); *__objp = ::std::ptr::null_mut(); });
+            self.func_pre_call.push(quote! {
+                ::varnish::vcl::leaks::untrack(*__objp);
+                drop(Box::from_raw(*__objp));
+                *__objp = ::std::ptr::null_mut();
+            });
         } else {
             self.wrap_fn_arg_decl.push(quote! { __ctx: *mut vrt_ctx });
             self.cproto_fn_arg_decl.push("VRT_CTX".to_string());
         }
         if matches!(info.func_type, Constructor | Destructor) {
-            let obj_name = self.names.obj_name().to_ident();
+            let obj_ty = self.names.obj_ty();
             self.wrap_fn_arg_decl
-                .push(quote! { __objp: *mut *mut #obj_name });
+                .push(quote! { __objp: *mut *mut #obj_ty });
             self.cproto_fn_arg_decl
                 .push(format!("{} **", self.names.struct_obj_name()));
         }
@@ -112,8 +131,9 @@ impl FuncProcessor {
             self.wrap_fn_arg_decl.push(quote! { __ev: VclEvent });
         }
         if info.has_optional_args {
+            // Copy all arguments at once, optional ones are then resolved into `Option`s
             self.func_pre_call
-                .push(quote! { let __args = __args.as_ref().unwrap(); });
+                .push(quote! { let __args = *__args.as_ref().unwrap(); });
             let ty = self.opt_args_ty_name.to_ident();
             self.wrap_fn_arg_decl.push(quote! { __args: *const #ty });
         }
@@ -123,19 +143,17 @@ impl FuncProcessor {
             let shared_ty = shared_types.get_per_vcl_ty();
             let shared_ty = syn::parse_str::<Type>(shared_ty).expect("Unable to parse second time");
             self.add_wrapper_arg(info, quote! { #arg_name: *mut vmod_priv });
-            self.func_pre_call.push(
-                quote! { let mut __obj_per_vcl = (* #arg_value).take_per_vcl::<#shared_ty>(); },
-            );
             let meth = if cfg!(varnishsys_6_priv_free_f) {
                 quote!(PRIV_VCL_METHODS)
             } else {
                 quote!(&PRIV_VCL_METHODS)
             };
-            self.func_always_after_call.push(quote! {
-                // Release ownership back to Varnish
-                (* #arg_value).put(__obj_per_vcl, #meth);
+            // The state stays owned by Varnish, and is modified in place
+            self.func_pre_call.push(quote! {
+                let __obj_per_vcl = (* #arg_value).get_per_vcl_mut::<#shared_ty>(#meth);
+                ::varnish::vcl::leaks::track(&__ctx, ::std::ptr::from_ref(__obj_per_vcl));
             });
-            let json = Self::arg_to_json("__vp".to_string(), false, "PRIV_VCL", Value::Null);
+            let json = Self::arg_to_json("__vp".to_string(), false, "PRIV_VCL", None, None);
             self.args_json.push(json);
             self.add_cproto_arg(info, "struct vmod_priv *", "__vp");
         }
@@ -143,7 +161,28 @@ impl FuncProcessor {
         for arg in &info.args {
             self.do_fn_param(info, arg);
         }
+        if let Some(ParamType::Workspace { is_mut }) = info
+            .args
+            .iter()
+            .map(|a| &a.ty)
+            .find(|ty| matches!(ty, ParamType::Workspace { .. }))
+            .filter(|_| info.has_ctx_and_ws())
+        {
+            // Borrows the context until the call, so the other steps must be done before
+            let ws = if *is_mut {
+                quote! { mut __ws }
+            } else {
+                quote! { __ws }
+            };
+            self.func_pre_call
+                .push(quote! { let (__ctx_ref, #ws) = __ctx.split_ws(); });
+        }
         self.do_fn_return(info);
+        if matches!(info.func_type, Event) {
+            self.func_always_after_call.push(quote! {
+                ::varnish::vcl::leaks::on_event(&__ctx, __vp, __ev);
+            });
+        }
 
         let wrapper_fn_name = self.names.wrapper_fn_name().to_ident();
         let signature = self.get_wrapper_fn_sig(false);
@@ -212,6 +251,22 @@ impl FuncProcessor {
         let arg_value = Self::get_arg_value(func_info, &arg_name_ident);
 
         match &arg_info.ty {
+            ParamType::Context { .. } | ParamType::Workspace { .. }
+                if func_info.has_ctx_and_ws() =>
+            {
+                // The context is split into disjoint borrows, see `Ctx::split_ws()`
+                self.func_needs_ctx = true;
+                // The split itself comes after the other steps, see `Self::init()`
+                if let ParamType::Workspace { is_mut } = arg_info.ty {
+                    self.func_call_vars.push(if is_mut {
+                        quote! { &mut __ws }
+                    } else {
+                        quote! { &__ws }
+                    });
+                } else {
+                    self.func_call_vars.push(quote! { __ctx_ref });
+                }
+            }
             ParamType::Context { is_mut } => {
                 self.func_needs_ctx = true;
                 self.func_call_vars.push(if *is_mut {
@@ -228,13 +283,24 @@ impl FuncProcessor {
                     quote! { &__ctx.ws }
                 });
             }
+            ParamType::ContextView { view, is_mut } => {
+                // The view is created inside the user function call so that a mismatch is reported as an error
+                self.func_needs_ctx = true;
+                let view = view.type_name().to_ident();
+                let view = quote! { ::varnish::vcl::#view::new(&mut __ctx)? };
+                self.func_call_vars.push(if *is_mut {
+                    quote! { &mut #view }
+                } else {
+                    quote! { &#view }
+                });
+            }
             ParamType::SelfType => {
                 self.func_pre_call
                     .push(quote! { let __obj = __obj.as_ref().unwrap(); });
             }
             ParamType::Event => {
                 self.func_call_vars.push(quote! { __ev });
-                let json = Self::arg_to_json(arg_info.ident.clone(), false, "EVENT", Value::Null);
+                let json = Self::arg_to_json(arg_info.ident.clone(), false, "EVENT", None, None);
                 self.args_json.push(json);
             }
             ParamType::VclName(pi) => {
@@ -249,23 +315,24 @@ impl FuncProcessor {
             ParamType::SharedPerTask => {
                 self.add_wrapper_arg(func_info, quote! { #arg_name_ident: *mut vmod_priv });
                 let temp_var = format_ident!("__obj_per_task");
-                self.func_pre_call
-                    .push(quote! { let mut #temp_var = (* #arg_value).take(); });
-                self.func_call_vars.push(quote! { &mut #temp_var });
+                let before_var = format_ident!("__obj_per_task_before");
+                // The object stays in the vmod_priv, and is only registered again if it changed
+                self.func_pre_call.push(quote! {
+                    let #temp_var = (* #arg_value).as_box_mut();
+                    let #before_var = #temp_var.as_deref().map_or(::std::ptr::null(), ::std::ptr::from_ref);
+                });
+                self.func_call_vars.push(quote! { #temp_var });
                 let meth = if cfg!(varnishsys_6_priv_free_f) {
                     quote!(PRIV_TASK_METHODS)
                 } else {
                     quote!(&PRIV_TASK_METHODS)
                 };
                 self.func_always_after_call.push(quote! {
-                    // Release ownership back to Varnish
-                    if let Some(obj) = #temp_var {
-                        (* #arg_value).put(obj, #meth);
-                    }
+                    (* #arg_value).adopt(&__ctx, #before_var, #meth);
                 });
 
                 let json =
-                    Self::arg_to_json(arg_info.ident.clone(), false, "PRIV_TASK", Value::Null);
+                    Self::arg_to_json(arg_info.ident.clone(), false, "PRIV_TASK", None, None);
                 self.args_json.push(json);
                 self.add_cproto_arg(func_info, "struct vmod_priv *", &arg_info.ident);
             }
@@ -279,8 +346,7 @@ impl FuncProcessor {
                         .and_then::<&PerVclState<_>, _>(|v| v.get_ref())
                         .and_then(|v| v.get_user_data())
                 });
-                let json =
-                    Self::arg_to_json(arg_info.ident.clone(), false, "PRIV_VCL", Value::Null);
+                let json = Self::arg_to_json(arg_info.ident.clone(), false, "PRIV_VCL", None, None);
                 self.args_json.push(json);
                 self.add_cproto_arg(func_info, "struct vmod_priv *", &arg_info.ident);
             }
@@ -302,31 +368,108 @@ impl FuncProcessor {
             }
             ParamType::Value(pi) => {
                 // Convert all other C arg types into a Rust arg, and pass it to the user's function
-                let mut input_expr = if pi.ty_info.use_try_from() {
-                    quote! { #arg_value.try_into()? }
-                } else {
-                    quote! { #arg_value.into() }
+                let convert = |value: TokenStream| {
+                    if let Some(enum_ty) = &pi.enum_ty {
+                        // Compare the pointer with the entries of the VMOD table
+                        let ty = enum_ty.ident.to_ident();
+                        let values = enum_ty.vcl_names().map(|v| format_ident!("ENUM_{v}"));
+                        quote! {
+                            <#ty as ::varnish::vcl::VclEnum>::from_vcl(#value, &[#(&#values),*])
+                                .ok_or("Invalid ENUM value")?
+                        }
+                    } else if pi.ty_info.use_try_from() {
+                        quote! { #value.try_into()? }
+                    } else {
+                        quote! { #value.into() }
+                    }
                 };
-                if matches!(pi.kind, ParamKind::Optional) {
+                let input_expr = if pi.config {
+                    let name = &arg_info.ident;
+                    let value = quote! { ::varnish::config::from_arg(#arg_value, #name)? };
+                    if matches!(pi.kind, ParamKind::Optional) {
+                        let arg_valid = format_ident!("valid_{}", arg_info.ident);
+                        self.add_wrapper_arg(func_info, quote! { #arg_valid: c_char });
+                        self.cproto_opt_arg_decl.push(format!("char {arg_valid}"));
+                        quote! { if __args.#arg_valid != 0 { Some(#value) } else { None } }
+                    } else {
+                        value
+                    }
+                } else if pi.strands {
+                    // Joined into a Rust buffer, a temporary that lives until the call returns
+                    let mut value = quote! {
+                        <Option<::varnish::vcl::Strands>>::from(#arg_value)
+                            .map(::varnish::vcl::Strands::to_str)
+                            .transpose()?
+                    };
+                    match pi.kind {
+                        ParamKind::Optional => {
+                            let arg_valid = format_ident!("valid_{}", arg_info.ident);
+                            self.add_wrapper_arg(func_info, quote! { #arg_valid: c_char });
+                            self.cproto_opt_arg_decl.push(format!("char {arg_valid}"));
+                            value = quote! { if __args.#arg_valid != 0 { #value } else { None } };
+                        }
+                        ParamKind::Regular => value = quote! { #value.unwrap_or_default() },
+                        ParamKind::Required => {}
+                    }
+                    if matches!(pi.kind, ParamKind::Regular) {
+                        quote! { &*#value }
+                    } else {
+                        quote! { (#value).as_deref() }
+                    }
+                } else if pi.lossy {
+                    // Converted before the call, as logging needs the context the call may borrow too
+                    self.func_needs_ctx = true;
+                    let name = &arg_info.ident;
+                    let mut value =
+                        quote! { ::varnish::vcl::lossy_str_arg(&mut __ctx, #arg_value, #name) };
+                    match pi.kind {
+                        ParamKind::Optional => {
+                            let arg_valid = format_ident!("valid_{}", arg_info.ident);
+                            self.add_wrapper_arg(func_info, quote! { #arg_valid: c_char });
+                            self.cproto_opt_arg_decl.push(format!("char {arg_valid}"));
+                            value = quote! { if __args.#arg_valid != 0 { #value } else { None } };
+                        }
+                        ParamKind::Regular => value = quote! { #value.unwrap_or_default() },
+                        ParamKind::Required => {}
+                    }
+                    self.func_pre_call
+                        .push(quote! { let #arg_name_ident = #value; });
+                    quote! { #arg_name_ident }
+                } else if matches!(pi.kind, ParamKind::Optional) {
                     let arg_valid = format_ident!("valid_{}", arg_info.ident);
-                    let is_arg_valid = quote! { __args.#arg_valid != 0 };
-                    input_expr = quote! { if #is_arg_valid { #input_expr } else { None } };
                     self.add_wrapper_arg(func_info, quote! { #arg_valid: c_char });
                     self.cproto_opt_arg_decl.push(format!("char {arg_valid}"));
-                }
+                    self.func_pre_call.push(quote! {
+                        let #arg_name_ident = (__args.#arg_valid != 0).then_some(#arg_value);
+                    });
+                    if pi.enum_ty.is_some() {
+                        let input_expr = convert(quote! { __v });
+                        quote! { match #arg_name_ident { Some(__v) => Some(#input_expr), None => None } }
+                    } else if pi.ty_info.use_try_from() {
+                        let input_expr = convert(quote! { __v });
+                        quote! { match #arg_name_ident { Some(__v) => #input_expr, None => None } }
+                    } else {
+                        quote! { #arg_name_ident.and_then(Into::into) }
+                    }
+                } else {
+                    convert(arg_value)
+                };
 
-                let c_type = pi.ty_info.to_c_type().to_ident();
+                let c_type = pi.to_c_type().to_ident();
                 self.add_wrapper_arg(func_info, quote! { #arg_name_ident: #c_type });
                 self.func_call_vars.push(quote! { #input_expr });
 
                 let json = Self::arg_to_json(
                     arg_info.ident.clone(),
                     matches!(pi.kind, ParamKind::Optional),
-                    pi.ty_info.to_vcc_type(),
+                    pi.to_vcc_type(),
                     pi.default.clone(),
+                    pi.enum_ty
+                        .as_ref()
+                        .map(|e| e.vcl_names().collect::<Vec<_>>().into()),
                 );
                 self.args_json.push(json);
-                self.add_cproto_arg(func_info, pi.ty_info.to_c_type(), &arg_info.ident);
+                self.add_cproto_arg(func_info, pi.to_c_type(), &arg_info.ident);
             }
         };
     }
@@ -363,20 +506,18 @@ impl FuncProcessor {
         arg_name: String,
         is_optional_arg: bool,
         vcc_type: &str,
-        mut default: Value,
+        default: Option<String>,
+        spec: Option<Value>,
     ) -> Value {
         // JSON data for each argument:
-        //   [VCC_type, arg_name, default_value, spec(?), is_optional]
-        if !default.is_null() {
-            // The default value must be a string containing C code (?)
-            // This ensures the string is properly escaped and surrounded by quotes
-            default = default.to_string().into();
-        }
+        //   [VCC_type, arg_name, default_value, spec, is_optional]
+        // The default value is VCL source, e.g. a quoted string or `10s`
+        // The spec is only used by `ENUM` arguments, to list their values
         let mut json_arg: Vec<Value> = vec![
             vcc_type.into(),
             arg_name.into(),
-            default,
-            Value::Null, // spec param is not used at this point
+            default.map_or(Value::Null, Value::from),
+            spec.unwrap_or(Value::Null),
         ];
 
         if is_optional_arg {
@@ -440,6 +581,7 @@ impl FuncProcessor {
     }
 
     /// Generate an extern "C" wrapper function that calls user's Rust function
+    #[expect(clippy::too_many_lines)]
     fn gen_callback_fn(&self, info: &FuncInfo) -> TokenStream {
         let opt_param_struct = self.gen_opt_param_struct(info);
         let signature = self.get_wrapper_fn_sig(true);
@@ -465,6 +607,8 @@ impl FuncProcessor {
                 // Ignore the result of the event function, override it with 0
                 func_steps.push(quote! { #func_call; });
                 func_call = quote! { VCL_INT(0) }
+            } else if info.output_ty.is_scalar() {
+                func_call = quote! { #func_call.into() };
             } else if !is_void && !matches!(info.output_ty, OutputTy::VclType(_)) {
                 needs_ctx = true;
                 func_call = quote! { #func_call.into_vcl(&mut __ctx.ws)? };
@@ -474,6 +618,7 @@ impl FuncProcessor {
                 func_steps.push(quote! {
                     let __result = Box::new( #func_call );
                     *__objp = Box::into_raw(__result);
+                    ::varnish::vcl::leaks::track(&__ctx, *__objp);
                 });
                 func_call = quote! {};
             }
@@ -481,7 +626,19 @@ impl FuncProcessor {
             func_call
         };
 
-        let result = if self.func_may_fail(info) {
+        let fail = if self.inline {
+            quote! { fail_cold }
+        } else {
+            quote! { fail }
+        };
+        let result = if self.inline
+            && self.fails_only_by_result(info)
+            && func_steps.is_empty()
+            && func_always_after_call.is_empty()
+        {
+            needs_ctx = true;
+            self.gen_match_result(info, &fail)
+        } else if self.func_may_fail(info) {
             let error_value = if self.output_hdr == "VCL_VOID" {
                 quote! {}
             } else if matches!(info.func_type, Event) {
@@ -513,7 +670,7 @@ impl FuncProcessor {
             needs_ctx = true;
             quote! {
                 #res.unwrap_or_else(|err| {
-                    __ctx.fail(err);
+                    __ctx.#fail(err);
                     #error_value
                 })
             }
@@ -551,11 +708,54 @@ impl FuncProcessor {
         }
     }
 
-    /// Will be true if the wrapper uses `try_from`, or the user function returns a `Result<T, E>`, or the output may fail conversion to a VCL type
+    /// Call a user function whose only fallible step is its `Result`, handling the error without
+    /// the closure otherwise used to collect errors
+    fn gen_match_result(&self, info: &FuncInfo, fail: &TokenStream) -> TokenStream {
+        let user_fn_name = self.names.fn_callable_name(info.func_type);
+        let var_args = &self.func_call_vars;
+        let (ok_arm, error_value) = if self.output_hdr == "VCL_VOID" {
+            (quote! { Ok(()) => {} }, quote! {})
+        } else {
+            (
+                quote! { Ok(__value) => __value.into() },
+                quote! { Default::default() },
+            )
+        };
+        quote! {
+            match #user_fn_name(#(#var_args),*) {
+                #ok_arm,
+                Err(err) => {
+                    __ctx.#fail(err.into());
+                    #error_value
+                }
+            }
+        }
+    }
+
+    /// Will be true if the user function returns a `Result<T, E>` and nothing else in the wrapper
+    /// may fail, i.e. the output is a scalar and no argument needs a fallible conversion
+    fn fails_only_by_result(&self, info: &FuncInfo) -> bool {
+        let fallible_arg = |ty: &ParamType| match ty {
+            ParamType::VclName(p) => p.ty_info.use_try_from(),
+            ParamType::Value(p) => {
+                p.ty_info.use_try_from() || p.enum_ty.is_some() || p.config || p.strands
+            }
+            ParamType::ContextView { .. } => true,
+            _ => false,
+        };
+        info.out_result
+            && !matches!(info.func_type, Constructor | Destructor | Event)
+            && (self.output_hdr == "VCL_VOID" || info.output_ty.is_scalar())
+            && !info.args.iter().any(|arg| fallible_arg(&arg.ty))
+    }
+
+    /// Will be true if the wrapper uses `try_from` or a context view, or the user function returns a `Result<T, E>`, or the output may fail conversion to a VCL type (anything but scalars)
     fn func_may_fail(&self, info: &FuncInfo) -> bool {
-        info.args.iter().any(|arg| matches!(&arg.ty, ParamType::VclName(p) | ParamType::Value(p) if p.ty_info.use_try_from()))
+        info.args.iter().any(|arg| matches!(&arg.ty, ParamType::VclName(p) | ParamType::Value(p) if p.ty_info.use_try_from() || p.strands))
+            || info.args.iter().any(|arg| matches!(arg.ty, ParamType::ContextView { .. }))
             || info.out_result
             || (self.output_hdr != "VCL_VOID"
+                && !info.output_ty.is_scalar()
                 && !matches!(info.output_ty, OutputTy::Default | OutputTy::VclType(_)))
     }
 
@@ -575,6 +775,7 @@ impl FuncProcessor {
             let opt_args_arg_decl = &self.opt_args_arg_decl;
             quote! {
                 #[repr(C)]
+                #[derive(Clone, Copy)]
                 struct #opt_args_ty_name {
                     #(#opt_args_arg_decl,)*
                 }
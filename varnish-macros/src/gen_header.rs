@@ -0,0 +1,60 @@
+//! Code to generate a C header declaring the VMOD functions into a file
+
+use std::fs;
+use std::path::Path;
+
+use crate::generator::Generator;
+use crate::model::VmodInfo;
+
+/// Generate the C header for the VMOD and save it to a file
+pub fn generate_header(info: &VmodInfo) {
+    let Some(ref header_file) = info.params.header else {
+        return; // header file is not set, skipping
+    };
+    let Ok(dir) = std::env::var("CARGO_MANIFEST_DIR") else {
+        panic!("Unable to get the CARGO_MANIFEST_DIR env var to save the C header, you may need to remove the `header` parameter from the `#[vmod]` attribute");
+    };
+    let header_file = Path::new(&dir).join(header_file);
+    let header = gen_header_content(info);
+    if let Err(e) = fs::write(header_file.as_path(), header) {
+        panic!(
+            "Unable to save the C header to file {}: {e}",
+            header_file.display()
+        );
+    }
+}
+
+/// Generate the C header for the VMOD as a single string.
+///
+/// It contains the same typedefs as the `$CPROTO` section given to Varnish, so that other C code
+/// can call the VMOD functions through the function table referenced by the VMOD data.
+pub fn gen_header_content(info: &VmodInfo) -> String {
+    let generator = Generator::new(info);
+    let name = generator.names.mod_name();
+    let guard = format!("VMOD_{}_H", name.to_uppercase());
+    format!(
+        r#"/*
+ * WARNING: DO NOT EDIT THIS FILE!
+ *
+ * This file was generated from the Rust source code of the `{name}` VMOD.
+ * It will be automatically updated on each build.
+ *
+ * "vdef.h" and "vrt.h" must be included before this file. The functions are
+ * reachable through the table referenced by the VMOD data, e.g.:
+ *
+ *   const struct {func} *f = {data}.func;
+ */
+
+#ifndef {guard}
+#define {guard}
+{types}
+
+extern const struct vmod_data {data};
+
+#endif /* {guard} */
+"#,
+        func = generator.names.func_struct_name(),
+        data = generator.names.data_struct_name(),
+        types = generator.generate_proto_types(),
+    )
+}
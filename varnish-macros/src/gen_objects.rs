@@ -21,11 +21,12 @@ pub struct ObjProcessor {
 }
 
 impl ObjProcessor {
-    pub fn from_info(names: Names, info: &ObjInfo, types: &SharedTypes) -> Self {
+    pub fn from_info(names: Names, info: &ObjInfo, types: &SharedTypes, inline: bool) -> Self {
         let funcs = info
             .iter()
             .map(|f| {
-                FuncProcessor::from_info(names.to_func(f.func_type, f.ident.as_str()), f, types)
+                let names = names.to_func(f.func_type, f.ident.as_str());
+                FuncProcessor::from_info(names, f, types, inline)
             })
             .collect();
 
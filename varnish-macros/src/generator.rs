@@ -1,10 +1,11 @@
 //! The main generator for the varnish vmod.
 
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::fmt::Write as _;
+use std::iter::once;
 
 use proc_macro2::TokenStream;
-use quote::{quote, TokenStreamExt};
+use quote::{format_ident, quote, TokenStreamExt};
 use serde_json::{json, Value};
 use sha2::{Digest as _, Sha256};
 use syn::{Item, ItemMod, Type};
@@ -12,7 +13,7 @@ use syn::{Item, ItemMod, Type};
 use crate::gen_func::FuncProcessor;
 use crate::gen_objects::ObjProcessor;
 use crate::model::{FuncInfo, ParamType, VmodInfo};
-use crate::names::{ForceCstr, Names, ToIdent};
+use crate::names::{hook_path_from_generated, part_path_from_generated, ForceCstr, Names, ToIdent};
 
 pub fn render_model(mut item_mod: ItemMod, info: &VmodInfo) -> TokenStream {
     let output = Generator::render(info);
@@ -26,37 +27,99 @@ pub fn render_model(mut item_mod: ItemMod, info: &VmodInfo) -> TokenStream {
     quote! { #item_mod }
 }
 
+/// Render a `#[vmod_part]` module. `original` is the user code as it was before parsing,
+/// it is handed over to the `#[vmod]` module when it lists this part.
+pub fn render_part(mut item_mod: ItemMod, info: &VmodInfo, original: &TokenStream) -> TokenStream {
+    let output = Generator::new(info).render_part_mod(info, original);
+    item_mod
+        .content
+        .as_mut()
+        .unwrap()
+        .1
+        .insert(0, Item::Verbatim(output));
+
+    quote! { #item_mod }
+}
+
 #[derive(Debug, Default)]
 pub struct Generator {
     pub names: Names,
     pub file_id: CString,
     pub functions: Vec<FuncProcessor>,
     pub objects: Vec<ObjProcessor>,
+    /// The `#[vmod_part]` modules, with their path as seen from the generated module
+    pub parts: Vec<(TokenStream, Generator)>,
+    /// The values of all `ENUM` arguments, sorted and without duplicates
+    pub enum_values: Vec<String>,
+    /// The `global_init` function, as seen from the generated module
+    pub global_init: Option<TokenStream>,
+    /// The `global_fini` function, as seen from the generated module
+    pub global_fini: Option<TokenStream>,
 }
 
 /// See also <https://varnish-cache.org/docs/7.6/reference/vmod.html>
 impl Generator {
     pub fn render(vmod: &VmodInfo) -> TokenStream {
+        Self::new(vmod).render_generated_mod(vmod)
+    }
+
+    pub fn new(vmod: &VmodInfo) -> Self {
         let mut obj = Self {
             names: Names::new(&vmod.ident),
             file_id: Self::calc_file_id(vmod).force_cstr(),
+            global_init: vmod
+                .params
+                .global_init
+                .as_deref()
+                .map(hook_path_from_generated),
+            global_fini: vmod
+                .params
+                .global_fini
+                .as_deref()
+                .map(hook_path_from_generated),
             ..Self::default()
         };
+        obj.add_items(vmod, vmod.params.inline);
+        let mut enum_values: Vec<String> = vmod
+            .shared_types
+            .enums
+            .iter()
+            .flat_map(|e| e.vcl_names().map(str::to_string))
+            .collect();
+        enum_values.sort();
+        enum_values.dedup();
+        obj.enum_values = enum_values;
+        for (path, info) in vmod.params.parts.iter().zip(&vmod.parts) {
+            // The C names of the part items are the ones of the VMOD
+            let mut part = Self {
+                names: Names::new(&vmod.ident),
+                ..Self::default()
+            };
+            part.add_items(info, vmod.params.inline);
+            obj.parts.push((part_path_from_generated(path), part));
+        }
+        obj
+    }
+
+    /// Add the functions and objects of `vmod`, a `#[vmod]` module or one of its parts.
+    /// `inline` is the parameter of the `#[vmod]` module, which also applies to its parts.
+    fn add_items(&mut self, vmod: &VmodInfo, inline: bool) {
         for info in &vmod.funcs {
-            obj.functions.push(FuncProcessor::from_info(
-                obj.names.to_func(info.func_type, &info.ident),
+            self.functions.push(FuncProcessor::from_info(
+                self.names.to_func(info.func_type, &info.ident),
                 info,
                 &vmod.shared_types,
+                inline,
             ));
         }
         for info in &vmod.objects {
-            obj.objects.push(ObjProcessor::from_info(
-                obj.names.to_obj(&info.ident),
+            self.objects.push(ObjProcessor::from_info(
+                self.names.to_obj(&info.ident, info.ty.as_deref()),
                 info,
                 &vmod.shared_types,
+                inline,
             ));
         }
-        obj.render_generated_mod(vmod)
     }
 
     /// Use the entire data model parsed from sources to generate a hash.
@@ -114,6 +177,51 @@ impl Generator {
             .chain(self.objects.iter().flat_map(|o| o.funcs.iter()))
     }
 
+    /// All functions in the order of the function table: the ones of this module, then the
+    /// ones of each part, matching the layout of the nested `VmodExports` structs.
+    fn iter_table_funcs(&self) -> impl Iterator<Item = &FuncProcessor> {
+        self.iter_all_funcs()
+            .chain(self.parts.iter().flat_map(|(_, p)| p.iter_all_funcs()))
+    }
+
+    fn has_global_hooks(&self) -> bool {
+        self.global_init.is_some() || self.global_fini.is_some()
+    }
+
+    /// The global hooks need an event handler, which is added if the VMOD has none
+    fn adds_global_event(&self) -> bool {
+        self.has_global_hooks() && !self.functions.iter().any(|f| f.is_event)
+    }
+
+    /// The event handler calling the global hooks, then the user's event handler if any
+    fn gen_global_event(&self) -> TokenStream {
+        if !self.has_global_hooks() {
+            return quote! {};
+        }
+        let call = |hook: &Option<TokenStream>| {
+            hook.as_ref()
+                .map_or_else(|| quote! { () }, |path| quote! { #path() })
+        };
+        let (init, fini) = (call(&self.global_init), call(&self.global_fini));
+        let next = self.functions.iter().find(|f| f.is_event).map_or_else(
+            || quote! { VCL_INT(0) },
+            |func| {
+                let name = func.wrapper_fn_ident();
+                quote! { #name(__ctx, __vp, __ev) }
+            },
+        );
+        quote! {
+            unsafe extern "C" fn vmod_c__global_event(
+                __ctx: *mut vrt_ctx,
+                __vp: *mut vmod_priv,
+                __ev: VclEvent,
+            ) -> VCL_INT {
+                static GLOBAL_REFS: ::varnish::vcl::GlobalRefs = ::varnish::vcl::GlobalRefs::new();
+                GLOBAL_REFS.on_event(__ctx, __ev, || #init, || #fini, || #next)
+            }
+        }
+    }
+
     fn gen_json(&self) -> String {
         let mut header: Vec<Value> = vec!["$VMOD".into(), "1.0".into()];
         if !cfg!(varnishsys_6) {
@@ -140,12 +248,17 @@ impl Generator {
             json.push(json! {[ "$CPROTO", self.generate_proto() ]});
         }
 
-        for func in &self.functions {
-            json.push(func.json.clone());
+        for gen in once(self).chain(self.parts.iter().map(|(_, p)| p)) {
+            for func in &gen.functions {
+                json.push(func.json.clone());
+            }
+            for obj in &gen.objects {
+                json.push(obj.json.clone());
+            }
         }
-
-        for obj in &self.objects {
-            json.push(obj.json.clone());
+        if self.adds_global_event() {
+            let callback_fn = format!("{}.f__global_event", self.names.func_struct_name());
+            json.push(json! {[ "$EVENT", callback_fn ]});
         }
 
         let mut json = serde_json::to_string_pretty(&json! {json}).unwrap();
@@ -159,50 +272,65 @@ impl Generator {
     }
 
     fn generate_proto(&self) -> String {
+        let mut cproto = self.generate_proto_types();
+        let _ = write!(
+            cproto,
+            "\n\nstatic struct {struct_name} {struct_name};",
+            struct_name = self.names.func_struct_name()
+        );
+        cproto
+    }
+
+    /// The typedefs and the function table struct of `$CPROTO`, without the table instance
+    pub fn generate_proto_types(&self) -> String {
         let mut cproto = String::new();
         for obj in &self.objects {
             cproto.push_str(&obj.cproto_typedef_decl);
         }
-        for func in self.iter_all_funcs() {
+        for (_, part) in &self.parts {
+            for obj in &part.objects {
+                cproto.push_str(&obj.cproto_typedef_decl);
+            }
+        }
+        for func in self.iter_table_funcs() {
             cproto.push_str(&func.cproto_typedef_decl);
         }
         let _ = write!(cproto, "\nstruct {} {{\n", self.names.func_struct_name());
-        for func in self.iter_all_funcs() {
+        for func in self.iter_table_funcs() {
             cproto.push_str(&func.cproto_typedef_init);
         }
-        let _ = write!(
-            cproto,
-            "}};\n\nstatic struct {struct_name} {struct_name};",
-            struct_name = self.names.func_struct_name()
-        );
+        if self.adds_global_event() {
+            cproto.push_str("  vmod_event_f *f__global_event;\n");
+        }
+        // VCL passes `*Vmod_..._Func.enum_<value>` to the functions with an `ENUM` argument
+        for value in &self.enum_values {
+            let _ = writeln!(cproto, "  VCL_ENUM *enum_{value};");
+        }
+        cproto.push_str("};");
         cproto
     }
 
-    fn render_generated_mod(&self, vmod: &VmodInfo) -> TokenStream {
-        let cproto = self.generate_proto().force_cstr();
-        let vmod_name_data = self.names.data_struct_name().to_ident();
-        let c_name = self.names.mod_name().force_cstr();
-        let file_id = &self.file_id;
+    fn gen_priv_structs(vmod: &VmodInfo) -> Vec<TokenStream> {
         let mut priv_structs = Vec::new();
         if let Some(s) = vmod.shared_types.shared_per_task_ty.as_ref() {
             Self::gen_priv_struct(&mut priv_structs, "PRIV_TASK_METHODS", s, false);
         }
         Self::gen_per_vcl_priv_struct(&mut priv_structs, vmod);
+        priv_structs
+    }
 
-        let functions = self.iter_all_funcs().map(|f| &f.wrapper_function_body);
-        let json = &self.gen_json().force_cstr();
-        let export_decls: Vec<_> = self.iter_all_funcs().map(|f| &f.export_decl).collect();
-        let export_inits: Vec<_> = self.iter_all_funcs().map(|f| &f.export_init).collect();
-
+    fn use_ffi_items() -> TokenStream {
         // WARNING: This list must match the list in varnish-macros/src/lib.rs
         let mut use_ffi_items = quote![
             VCL_BACKEND,
             VCL_BOOL,
             VCL_DURATION,
+            VCL_ENUM,
             VCL_INT,
             VCL_IP,
             VCL_PROBE,
             VCL_REAL,
+            VCL_STRANDS,
             VCL_STRING,
             VCL_VOID,
             VMOD_ABI_Version,
@@ -217,6 +345,151 @@ impl Generator {
             use_ffi_items.append_all(quote![VMOD_PRIV_METHODS_MAGIC, vmod_priv_methods]);
         }
         // WARNING: This list must match the list in varnish-macros/src/lib.rs
+        use_ffi_items
+    }
+
+    /// The generated module of a `#[vmod_part]`: the wrappers and their table, but no VMOD data.
+    /// The `vmod_part!` macro passes the part's code to the `#[vmod]` module listing it.
+    fn render_part_mod(&self, vmod: &VmodInfo, original: &TokenStream) -> TokenStream {
+        let priv_structs = Self::gen_priv_structs(vmod);
+        let functions = self.iter_all_funcs().map(|f| &f.wrapper_function_body);
+        let export_decls = self.iter_all_funcs().map(|f| &f.export_decl);
+        let export_inits = self.iter_all_funcs().map(|f| &f.export_init);
+        let use_ffi_items = Self::use_ffi_items();
+
+        quote!(
+            #[doc(hidden)]
+            #[allow(
+                dead_code,
+                non_snake_case,
+                unused_imports,
+                unused_qualifications,
+                unused_variables,
+            )]
+            #[allow(
+                clippy::needless_question_mark,
+            )]
+            pub mod varnish_generated {
+                use std::ffi::{c_char, c_int, c_uint, c_void, CStr};
+                use std::ptr::null;
+                use varnish::ffi::{#use_ffi_items};
+                use varnish::vcl::{Ctx, EnumValue, IntoVCL, PerVclState, Workspace};
+                use super::*;
+
+                #( #priv_structs )*
+                #( #functions )*
+
+                #[repr(C)]
+                pub struct VmodExports {
+                    #(#export_decls,)*
+                }
+
+                pub const VMOD_PART_EXPORTS: VmodExports = VmodExports {
+                    #(#export_inits,)*
+                };
+
+                macro_rules! vmod_part {
+                    ($args:tt $item:tt $($parts:tt)*) => {
+                        varnish::__vmod_parts! { $args $item $($parts)* { #original } }
+                    };
+                }
+                pub(crate) use vmod_part;
+            }
+        )
+    }
+
+    /// The `ENUM` values, and their entries at the end of the function table
+    fn gen_enum_table(
+        &self,
+        export_decls: &mut Vec<TokenStream>,
+        export_inits: &mut Vec<TokenStream>,
+    ) -> Vec<TokenStream> {
+        let mut enum_statics = Vec::new();
+        for value in &self.enum_values {
+            let field = format_ident!("enum_{value}");
+            let name = format_ident!("ENUM_{value}");
+            let c_value = value.force_cstr();
+            enum_statics.push(quote! { static #name: EnumValue = EnumValue::new(#c_value); });
+            export_decls.push(quote! { #field: &'static EnumValue });
+            export_inits.push(quote! { #field: &#name });
+        }
+        enum_statics
+    }
+
+    /// A library constructor explaining an ABI mismatch as soon as varnishd loads the library,
+    /// before it refuses it
+    fn gen_abi_check(c_name: &CStr) -> TokenStream {
+        quote! {
+            #[used]
+            #[cfg_attr(target_vendor = "apple", link_section = "__DATA,__mod_init_func")]
+            #[cfg_attr(not(target_vendor = "apple"), link_section = ".init_array")]
+            static __ABI_CHECK: extern "C" fn() = {
+                extern "C" fn __abi_check() {
+                    ::varnish::vcl::check_abi_at_load(#c_name, VMOD_ABI_Version);
+                }
+                __abi_check
+            };
+        }
+    }
+
+    /// The static returned by the `vmod_info()` function added to the user module
+    fn gen_vmod_info(&self) -> TokenStream {
+        let mod_name = self.names.mod_name();
+        quote! {
+            pub static VMOD_INFO: ::varnish::vcl::VmodInfo = ::varnish::vcl::VmodInfo {
+                name: #mod_name,
+                version: env!("CARGO_PKG_VERSION"),
+                git_hash: option_env!("VMOD_GIT_HASH"),
+                abi: VMOD_ABI_Version,
+            };
+        }
+    }
+
+    #[expect(clippy::too_many_lines)]
+    fn render_generated_mod(&self, vmod: &VmodInfo) -> TokenStream {
+        let cproto = self.generate_proto().force_cstr();
+        let vmod_name_data = self.names.data_struct_name().to_ident();
+        let c_name = self.names.mod_name().force_cstr();
+        let file_id = &self.file_id;
+        let priv_structs = Self::gen_priv_structs(vmod);
+
+        let functions = self.iter_all_funcs().map(|f| &f.wrapper_function_body);
+        let json = &self.gen_json().force_cstr();
+        let mut export_decls: Vec<_> = self
+            .iter_all_funcs()
+            .map(|f| f.export_decl.clone())
+            .collect();
+        let mut export_inits: Vec<_> = self
+            .iter_all_funcs()
+            .map(|f| {
+                if f.is_event && self.has_global_hooks() {
+                    // The global event handler calls the user's one
+                    let name = f.wrapper_fn_ident();
+                    quote! { #name: Some(vmod_c__global_event) }
+                } else {
+                    f.export_init.clone()
+                }
+            })
+            .collect();
+        // Each part has its own table of functions, nested in this one
+        for (idx, (path, _)) in self.parts.iter().enumerate() {
+            let field = format_ident!("__part{idx}");
+            export_decls.push(quote! { #field: #path::varnish_generated::VmodExports });
+            export_inits.push(quote! { #field: #path::varnish_generated::VMOD_PART_EXPORTS });
+        }
+        if self.adds_global_event() {
+            export_decls.push(quote! {
+                vmod_c__global_event: Option<
+                    unsafe extern "C" fn(*mut vrt_ctx, *mut vmod_priv, VclEvent) -> VCL_INT,
+                >
+            });
+            export_inits.push(quote! { vmod_c__global_event: Some(vmod_c__global_event) });
+        }
+        let global_event = self.gen_global_event();
+        let abi_check = Self::gen_abi_check(&c_name);
+        let vmod_info = self.gen_vmod_info();
+        let enum_statics = self.gen_enum_table(&mut export_decls, &mut export_inits);
+        let use_ffi_items = Self::use_ffi_items();
 
         let func_name;
         let cproto_ptr;
@@ -235,6 +508,7 @@ impl Generator {
         quote!(
             #[allow(
                 non_snake_case,
+                non_upper_case_globals,
                 unused_imports,
                 unused_qualifications,
                 unused_variables,
@@ -246,11 +520,13 @@ impl Generator {
                 use std::ffi::{c_char, c_int, c_uint, c_void, CStr};
                 use std::ptr::null;
                 use varnish::ffi::{#use_ffi_items};
-                use varnish::vcl::{Ctx, IntoVCL, PerVclState, Workspace};
+                use varnish::vcl::{Ctx, EnumValue, IntoVCL, PerVclState, Workspace};
                 use super::*;
 
                 #( #priv_structs )*
+                #( #enum_statics )*
                 #( #functions )*
+                #global_event
 
                 #[repr(C)]
                 pub struct VmodExports {
@@ -279,6 +555,14 @@ impl Generator {
 
                 const JSON: &CStr = #json;
                 #cproto_def
+                #abi_check
+                #vmod_info
+            }
+
+            /// The name and the build of this vmod
+            #[allow(dead_code)]
+            fn vmod_info() -> &'static ::varnish::vcl::VmodInfo {
+                &varnish_generated::VMOD_INFO
             }
         )
     }
@@ -2,23 +2,35 @@
 // #![allow(warnings)]
 
 use errors::Errors;
-use syn::{parse_macro_input, ItemMod};
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, ItemMod};
 use {proc_macro as pm, proc_macro2 as pm2};
 
 use crate::gen_docs::generate_docs;
-use crate::generator::render_model;
-use crate::parser::tokens_to_model;
+use crate::gen_header::generate_header;
+use crate::generator::{render_model, render_part};
+use crate::model::EnumInfo;
+use crate::names::part_path_from_parent;
+use crate::parser::{parse_params, part_to_model, tokens_to_model_with_parts};
+use crate::parser_stats::gen_stats;
+use crate::vcc_compat::check_vcc;
 
 mod errors;
 mod gen_docs;
 mod gen_func;
+mod gen_header;
 mod gen_objects;
 mod generator;
 mod model;
 mod names;
 mod parser;
 mod parser_args;
+mod parser_enums;
+mod parser_names;
+mod parser_records;
+mod parser_stats;
 mod parser_utils;
+mod vcc_compat;
 
 pub(crate) type ProcResult<T> = Result<T, Errors>;
 
@@ -35,24 +47,162 @@ mod tests;
 ///   - `#[event]` attribute on a function will export it as an event function.
 ///   - `#[shared_per_task]` attribute on a function argument will treat it as a `PRIV_TASK` object.
 ///   - `#[shared_per_vcl]` attribute on a function argument will treat it as a `PRIV_VCL` object.
+///     Event handlers and object constructors get it as `&mut Option<Box<T>>`, while functions and
+///     methods get an `Option<&T>` that may be used by many requests at once. The value is updated in
+///     place and never leaves the `PRIV_VCL`, so to change it while handling requests, pick the
+///     locking that fits, e.g. `T` can be an atomic, a `Mutex<...>`, or an `RwLock<...>`.
+///   - `&Ctx` and `&mut Workspace` arguments can be used together, to read the context while
+///     allocating from the workspace, see `Ctx::split_ws()`.
+///   - `#[arg(lossy)]` attribute on a `Cow<str>` argument replaces invalid UTF-8 with `U+FFFD`,
+///     logging it as an `Error` record, instead of failing the task like `&str` arguments do.
+///   - `&[u8]` arguments get the raw bytes of a VCL `STRING`, without any UTF-8 validation.
+///   - `Strands` arguments are VCL `STRANDS`, the pieces of a string concatenation, only joined if
+///     the function asks for it. `#[arg(strands)]` on a `&str` argument also makes it a `STRANDS`,
+///     joined into a Rust buffer before the call instead of the workspace by Varnish.
+///   - `#[arg(config)]` attribute on an object constructor's argument makes it a VCL `STRING`,
+///     holding inline TOML or YAML, or a `file://` path, deserialized into the Rust type of the argument,
+///     e.g. `#[arg(config)] cfg: MyConfig` where `MyConfig` implements `serde::Deserialize`.
+///     Requires the `config` feature of the `varnish` crate, see `varnish::config`.
+///   - `&RecvCtx`, `&FetchCtx`, or `&DeliverCtx` arguments (or their `&mut` variants) can be used instead of `Ctx`,
+///     and will fail the transaction if the function is called from an incompatible VCL subroutine.
 /// - `impl` blocks' public methods are exported as VMOD object methods. The object itself may reside outside the module.
 ///   - `pub fn new(...)` is treated as the object constructor.
 ///   - `#[vcl_name]` attribute on an object constructor's argument will set it to the VCL name.
+///   - `#[vmod_object(name = "...")]` attribute on an `impl` block sets the name of the object in VCL.
+///     It is required to export a concrete instantiation of a generic type, e.g.
+///     `#[vmod_object(name = "intstore")] impl Store<i64>`, and lets each instantiation be a
+///     separate VCL object with its own constructor and methods.
+/// - Structs tagged with `#[derive(VclRecord)]` can be returned by public functions, which are then
+///   exported as objects with a `call(...)` method and one accessor method per struct field.
+/// - Enums tagged with `#[derive(VclEnum)]` can be used as `ENUM` arguments, see [`macro@VclEnum`].
+/// - A private `vmod_info()` function is added to the module, returning the name, the version, and
+///   the git hash of the VMOD build, see `varnish::vcl::VmodInfo` and `Ctx::log_banner()`.
+///
+/// The attribute accepts the following parameters:
+/// - `docs = "README.md"` generates the VMOD documentation into that file.
+/// - `header = "vmod_example.h"` generates a C header with the typedefs of the VMOD functions, so
+///   that C code, e.g. another VMOD, can call them through the table referenced by `Vmod_<name>_Data`.
+/// - `parts(crate::cache::funcs, ...)` adds the functions and objects of modules tagged with
+///   `#[vmod_part]` to the VMOD, see [`macro@vmod_part`].
+/// - `vcc = "vmod.vcc"` checks that the module matches a legacy `.vcc` file, e.g. while porting a
+///   C VMOD. Any drift in names, return types, argument types or optionality is a compile error.
+/// - `global_init = "setup"` and `global_fini = "teardown"` name functions called once while the
+///   VMOD shared object is loaded: `global_init` before the first VCL importing the VMOD is loaded,
+///   and `global_fini` after the last one is discarded, unlike `#[event]` which runs for every VCL.
+///   `global_init` may return `Result<(), E>` to fail the VCL load. The functions live outside of
+///   the module, e.g. `crate::setup` or `super::setup`. The `#[event]` handler, if any, is called
+///   after `global_init` and before `global_fini`, and must be in the `#[vmod]` module itself.
+/// - `inline` shortens the call path of the functions for release builds: the public functions
+///   and methods of the module get `#[inline]`, unless they have an `inline` or `cold` attribute,
+///   so that they can be inlined into their generated wrappers, and the failure handling is kept
+///   out of line. Functions whose only fallible step is their `Result` are called without the
+///   closure otherwise used to collect errors. The wrappers of the `parts` follow the flag too,
+///   but their functions must be marked by hand.
 #[proc_macro_attribute]
 pub fn vmod(args: pm::TokenStream, input: pm::TokenStream) -> pm::TokenStream {
+    expand_vmod(&args.into(), input.into(), Vec::new()).into()
+}
+
+/// Handle the `#[vmod_part]` attribute, to split a large VMOD into several modules, possibly in
+/// different files. The module accepts the same items as a `#[vmod]` module, and must be listed
+/// in the `parts` parameter of the `#[vmod]` attribute, e.g. `#[vmod(parts(crate::cache::funcs))]`,
+/// to be part of the VMOD. Part paths must start with `crate::` or `super::`.
+///
+/// Since attributes cannot be applied to a `mod foo;` declaration, the part is an inline module
+/// inside its file, e.g. `#[vmod_part] pub mod funcs { ... }` in `src/cache.rs`. Objects, and the
+/// types used by `#[shared_per_task]` and `#[shared_per_vcl]` arguments, are resolved from the part
+/// itself, but the shared types must be written the same way in every module using them.
+#[proc_macro_attribute]
+pub fn vmod_part(args: pm::TokenStream, input: pm::TokenStream) -> pm::TokenStream {
+    let args = pm2::TokenStream::from(args);
+    if !args.is_empty() {
+        return syn::Error::new_spanned(args, "`#[vmod_part]` does not accept any parameters")
+            .into_compile_error()
+            .into();
+    }
+    let original = pm2::TokenStream::from(input.clone());
+    let mut item_mod = parse_macro_input!(input as ItemMod);
+    let info = match part_to_model(&mut item_mod) {
+        Ok(v) => v,
+        Err(err) => return err.into_compile_error().into(),
+    };
+    render_part(item_mod, &info, &original).into()
+}
+
+/// Called by the `vmod_part!` macro generated for each `#[vmod_part]` module, with the code of
+/// the part appended to the input. Once the code of all parts is collected, the VMOD is generated.
+#[doc(hidden)]
+#[proc_macro]
+pub fn __vmod_parts(input: pm::TokenStream) -> pm::TokenStream {
+    let mut groups = Vec::new();
+    for tt in pm2::TokenStream::from(input) {
+        match tt {
+            pm2::TokenTree::Group(group) => groups.push(group.stream()),
+            tt => {
+                return syn::Error::new_spanned(tt, "Unexpected token")
+                    .into_compile_error()
+                    .into()
+            }
+        }
+    }
+    if groups.len() < 2 {
+        return syn::Error::new(pm2::Span::call_site(), "Missing the `#[vmod]` module")
+            .into_compile_error()
+            .into();
+    }
+    let parts = groups.split_off(2);
+    let input = groups.pop().unwrap();
+    let args = groups.pop().unwrap();
+    expand_vmod(&args, input, parts).into()
+}
+
+/// Generate the VMOD, or if some of its parts have not been collected yet,
+/// ask the next part for its code.
+fn expand_vmod(
+    args: &pm2::TokenStream,
+    input: pm2::TokenStream,
+    parts: Vec<pm2::TokenStream>,
+) -> pm2::TokenStream {
     // parse the module code into a data model.
     // Most error checking is done here.
     // Magical attributes like `#[event]` are removed from the user's code.
-    // let args = parse_macro_input!(args);
-    // let args = parse_macro_input!(args);
-    // let input = parse_macro_input!(input);
-    let args = pm2::TokenStream::from(args);
-    let mut item_mod = parse_macro_input!(input as ItemMod);
+    let params = match parse_params(args.clone()) {
+        Ok(v) => v,
+        Err(err) => return err.into_compile_error(),
+    };
+    if let Some(path) = params.parts.get(parts.len()) {
+        let first = &path.segments[0].ident;
+        if path.leading_colon.is_some() || (first != "crate" && first != "super") {
+            return syn::Error::new_spanned(
+                path,
+                "Part paths must start with `crate::` or `super::`",
+            )
+            .into_compile_error();
+        }
+        let path = part_path_from_parent(path);
+        return quote! {
+            #path::varnish_generated::vmod_part! { (#args) { #input } #({ #parts })* }
+        };
+    }
+    let mut item_mod: ItemMod = match syn::parse2(input) {
+        Ok(v) => v,
+        Err(err) => return err.into_compile_error(),
+    };
+    let mut part_mods = Vec::new();
+    for part in parts {
+        match syn::parse2::<ItemMod>(part) {
+            Ok(v) => part_mods.push(v),
+            Err(err) => return err.into_compile_error(),
+        }
+    }
 
-    let info = match tokens_to_model(args, &mut item_mod) {
+    let info = match tokens_to_model_with_parts(params, &mut item_mod, &mut part_mods) {
         Ok(v) => v,
-        Err(err) => return err.into_compile_error().into(),
+        Err(err) => return err.into_compile_error(),
     };
+    if let Err(err) = check_vcc(&info, &item_mod) {
+        return err.into_compile_error();
+    }
 
     // generate the code for the VMOD.
     // This will output the slightly modified original user code,
@@ -62,5 +212,53 @@ pub fn vmod(args: pm::TokenStream, input: pm::TokenStream) -> pm::TokenStream {
     // generate documentation file if needed
     generate_docs(&info);
 
-    result.into()
+    // generate the C header if needed
+    generate_header(&info);
+
+    result
+}
+
+/// Mark a struct as a record that can be returned by a function of a `#[vmod]` module.
+/// The struct must be declared inside that module.
+#[proc_macro_derive(VclRecord)]
+pub fn derive_vcl_record(input: pm::TokenStream) -> pm::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote! {
+        impl #impl_generics ::varnish::vcl::VclRecord for #ident #ty_generics #where_clause {}
+    }
+    .into()
+}
+
+/// Use an enum as a VCL `ENUM` argument of the functions of a `#[vmod]` module, e.g.
+/// `pub fn digest(algo: Algo, data: &str)` is called from VCL as `mod.digest(sha256, "...")`.
+/// The enum must be declared inside that module, and its variants cannot have fields.
+///
+/// Each variant is named after its identifier in snake case, e.g. `NoCache` becomes `no_cache`,
+/// unless it is renamed with `#[vcl_enum(rename = "...")]`. A default value is set with
+/// `#[default(Algo::Sha256)]` on the argument, and `Option<Algo>` makes it optional.
+#[proc_macro_derive(VclEnum, attributes(vcl_enum))]
+pub fn derive_vcl_enum(input: pm::TokenStream) -> pm::TokenStream {
+    let input = parse_macro_input!(input as syn::ItemEnum);
+    match EnumInfo::parse(&input) {
+        Ok(info) => info.gen_impl().into(),
+        Err(err) => err.into_compile_error().into(),
+    }
+}
+
+/// Add methods updating the `AtomicU64` fields of a struct of statistics, e.g. for a field `hits`:
+/// `hits_inc()`, `hits_add(n)`, and `hits_get()`. Fields tagged with `#[stats(gauge)]` also get
+/// `hits_dec()`, `hits_sub(n)`, and `hits_set(value)`, while the other ones are counters that only
+/// go up. The methods have the visibility of a `pub` method, whatever the visibility of the fields.
+///
+/// All the methods use `Ordering::Relaxed`: the values are only statistics, they don't order any
+/// other memory access, so vmods don't need to pick an ordering for each update.
+#[proc_macro_derive(Stats, attributes(stats))]
+pub fn derive_stats(input: pm::TokenStream) -> pm::TokenStream {
+    let input = parse_macro_input!(input as syn::ItemStruct);
+    match gen_stats(&input) {
+        Ok(v) => v.into(),
+        Err(err) => err.into_compile_error().into(),
+    }
 }
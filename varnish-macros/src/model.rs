@@ -3,6 +3,7 @@
 
 use std::iter::once;
 
+use darling::util::PathList;
 use darling::FromMeta;
 
 /// Represents the entire VMOD. A single instance of this struct is parsed for each VMOD.
@@ -14,6 +15,8 @@ pub struct VmodInfo {
     pub funcs: Vec<FuncInfo>,
     pub objects: Vec<ObjInfo>,
     pub shared_types: SharedTypes,
+    /// The modules tagged with `#[vmod_part]`, in the order of the `parts` parameter
+    pub parts: Vec<VmodInfo>,
 }
 
 impl VmodInfo {
@@ -32,6 +35,20 @@ impl VmodInfo {
     pub fn count_args<F: Copy + Fn(&&ParamTypeInfo) -> bool>(&self, filter: F) -> usize {
         self.iter_all_funcs().map(|f| f.count_args(filter)).sum()
     }
+
+    /// Functions and events of this module, followed by the ones of its parts
+    pub fn all_funcs(&self) -> impl Iterator<Item = &FuncInfo> {
+        self.funcs
+            .iter()
+            .chain(self.parts.iter().flat_map(|p| p.funcs.iter()))
+    }
+
+    /// Objects of this module, followed by the ones of its parts
+    pub fn all_objects(&self) -> impl Iterator<Item = &ObjInfo> {
+        self.objects
+            .iter()
+            .chain(self.parts.iter().flat_map(|p| p.objects.iter()))
+    }
 }
 
 /// Represents the shared types used by multiple functions. Each of these types is unique per VMOD.
@@ -39,6 +56,8 @@ impl VmodInfo {
 pub struct SharedTypes {
     pub shared_per_task_ty: Option<String>,
     pub shared_per_vcl_ty: Option<String>,
+    /// The enums tagged with `#[derive(VclEnum)]` in the module
+    pub enums: Vec<EnumInfo>,
 }
 
 impl SharedTypes {
@@ -47,17 +66,48 @@ impl SharedTypes {
     }
 }
 
+/// An enum tagged with `#[derive(VclEnum)]`, used as an `ENUM` argument
+#[derive(Debug, Clone)]
+pub struct EnumInfo {
+    pub ident: String,
+    /// The Rust name and the VCL name of each variant, in declaration order
+    pub variants: Vec<(String, String)>,
+}
+
 /// Represents the parameters inside the `#[vmod(....)]` attribute itself.
 #[derive(Default, Debug, FromMeta)]
 #[darling(default)]
 pub struct VmodParams {
     pub docs: Option<String>,
+    /// A C header file to generate with the function typedefs
+    pub header: Option<String>,
+    /// A legacy `vmod.vcc` file the VMOD must match
+    pub vcc: Option<String>,
+    /// Modules tagged with `#[vmod_part]` whose functions and objects are part of the VMOD
+    pub parts: PathList,
+    /// A function called before the first VCL importing the VMOD is loaded
+    pub global_init: Option<String>,
+    /// A function called after the last VCL importing the VMOD is discarded
+    pub global_fini: Option<String>,
+    /// Mark the user functions `#[inline]`, and keep the error path out of the wrappers
+    pub inline: bool,
+}
+
+/// Represents the parameters inside the optional `#[vmod_object(....)]` attribute of an `impl` block.
+#[derive(Default, Debug, FromMeta)]
+#[darling(default)]
+pub struct ObjParams {
+    /// The name of the object in VCL, required for instantiations of generic types
+    pub name: Option<String>,
 }
 
 /// Represents the object information parsed from an `impl` block.
 #[derive(Debug)]
 pub struct ObjInfo {
+    /// The name of the object in VCL
     pub ident: String,
+    /// The Rust type of the object if it is not `ident`, e.g. `Store < i64 >`
+    pub ty: Option<String>,
     pub docs: String,
     pub constructor: FuncInfo,
     pub destructor: FuncInfo,
@@ -77,6 +127,17 @@ pub struct FuncInfo {
 }
 
 impl FuncInfo {
+    /// `true` if the function takes both `&Ctx` and a `Workspace`
+    pub fn has_ctx_and_ws(&self) -> bool {
+        self.args
+            .iter()
+            .any(|a| matches!(a.ty, ParamType::Context { .. }))
+            && self
+                .args
+                .iter()
+                .any(|a| matches!(a.ty, ParamType::Workspace { .. }))
+    }
+
     pub fn count_args<F: Fn(&&ParamTypeInfo) -> bool>(&self, filter: F) -> usize {
         self.args.iter().filter(filter).count()
     }
@@ -120,6 +181,8 @@ pub enum ParamType {
     Context { is_mut: bool },
     /// An argument representing Varnish Workspace wrapper
     Workspace { is_mut: bool },
+    /// A view of the Varnish context restricted to some VCL subroutines, checked at runtime
+    ContextView { view: CtxView, is_mut: bool },
     /// For object methods, the first argument is always a reference to the object
     SelfType,
     /// An argument is an event type
@@ -140,6 +203,25 @@ pub enum ParamType {
     Value(ParamInfo),
 }
 
+/// The context views, see `varnish::vcl::RecvCtx` and its siblings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtxView {
+    Recv,
+    Fetch,
+    Deliver,
+}
+
+impl CtxView {
+    /// Name of the Rust type in `varnish::vcl`
+    pub fn type_name(self) -> &'static str {
+        match self {
+            Self::Recv => "RecvCtx",
+            Self::Fetch => "FetchCtx",
+            Self::Deliver => "DeliverCtx",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ParamKind {
     /// Type is declared without the `Option<...>`
@@ -155,8 +237,35 @@ pub enum ParamKind {
 #[derive(Debug, Clone)]
 pub struct ParamInfo {
     pub kind: ParamKind,
-    pub default: serde_json::Value,
+    /// The default value as VCL source, e.g. `"foo"`, `10`, or `10s`
+    pub default: Option<String>,
     pub ty_info: ParamTy,
+    /// For `ENUM` arguments, the enum type
+    pub enum_ty: Option<EnumInfo>,
+    /// `Cow<str>` arguments declared with `#[arg(lossy)]`, converted without failing on invalid UTF-8
+    pub lossy: bool,
+    /// Arguments declared with `#[arg(config)]`, parsed from TOML or YAML into the Rust type of the argument
+    pub config: bool,
+    /// `&str` arguments declared with `#[arg(strands)]`, a `STRANDS` joined before the call
+    pub strands: bool,
+}
+
+impl ParamInfo {
+    pub fn to_vcc_type(&self) -> &'static str {
+        if self.strands {
+            ParamTy::Strands.to_vcc_type()
+        } else {
+            self.ty_info.to_vcc_type()
+        }
+    }
+
+    pub fn to_c_type(&self) -> &'static str {
+        if self.strands {
+            ParamTy::Strands.to_c_type()
+        } else {
+            self.ty_info.to_c_type()
+        }
+    }
 }
 
 /// Represents the common function argument types. These could also be returned.
@@ -169,8 +278,18 @@ pub enum ParamTy {
     Probe, // FIXME: can probes be returned?
     ProbeCow,
     SocketAddr,
+    IpAddr,
+    /// `BackendRef`, only as `Option<BackendRef>` in arguments
+    Backend,
     Str,
     CStr,
+    /// `Cow<str>`, only as an argument with `#[arg(lossy)]`
+    CowStr,
+    /// `&[u8]`, the raw bytes of a `VCL_STRING`, only as an argument
+    Bytes,
+    /// `Strands`, the pieces of a `VCL_STRANDS`, only as an argument
+    Strands,
+    Enum,
 }
 
 impl ParamTy {
@@ -181,8 +300,11 @@ impl ParamTy {
             Self::F64 => "REAL",
             Self::I64 => "INT",
             Self::Probe | Self::ProbeCow => "PROBE",
-            Self::SocketAddr => "IP",
-            Self::Str | Self::CStr => "STRING",
+            Self::SocketAddr | Self::IpAddr => "IP",
+            Self::Backend => "BACKEND",
+            Self::Str | Self::CStr | Self::CowStr | Self::Bytes => "STRING",
+            Self::Strands => "STRANDS",
+            Self::Enum => "ENUM",
         }
     }
 
@@ -195,16 +317,28 @@ impl ParamTy {
             Self::F64 => "VCL_REAL",
             Self::I64 => "VCL_INT",
             Self::Probe | Self::ProbeCow => "VCL_PROBE",
-            Self::SocketAddr => "VCL_IP",
-            Self::Str | Self::CStr => "VCL_STRING",
+            Self::SocketAddr | Self::IpAddr => "VCL_IP",
+            Self::Backend => "VCL_BACKEND",
+            Self::Str | Self::CStr | Self::CowStr | Self::Bytes => "VCL_STRING",
+            Self::Strands => "VCL_STRANDS",
+            Self::Enum => "VCL_ENUM",
         }
     }
 
     /// User MUST use some types with `Option`
     pub fn must_be_optional(self) -> bool {
         match self {
-            Self::Bool | Self::Duration | Self::F64 | Self::I64 | Self::Str | Self::CStr => false,
-            Self::Probe | Self::ProbeCow | Self::SocketAddr => true,
+            Self::Bool
+            | Self::Duration
+            | Self::F64
+            | Self::I64
+            | Self::Str
+            | Self::CStr
+            | Self::CowStr
+            | Self::Bytes
+            | Self::Strands
+            | Self::Enum => false,
+            Self::Probe | Self::ProbeCow | Self::SocketAddr | Self::IpAddr | Self::Backend => true,
         }
     }
 
@@ -215,12 +349,18 @@ impl ParamTy {
             Self::Probe
             | Self::ProbeCow
             | Self::SocketAddr
+            | Self::IpAddr
+            | Self::Backend
             | Self::Bool
             | Self::Duration
             | Self::F64
             | Self::I64
-            | Self::CStr => false,
-            Self::Str => true,
+            | Self::CStr
+            | Self::CowStr
+            | Self::Bytes
+            | Self::Strands => false,
+            // VCL only passes pointers from the VMOD table, but they are checked anyway
+            Self::Str | Self::Enum => true,
         }
     }
 }
@@ -237,6 +377,14 @@ pub enum OutputTy {
 }
 
 impl OutputTy {
+    /// Scalars are converted to VCL types with `From`, so they need neither the workspace nor error handling
+    pub fn is_scalar(&self) -> bool {
+        matches!(
+            self,
+            Self::ParamType(ParamTy::Bool | ParamTy::Duration | ParamTy::F64 | ParamTy::I64)
+        )
+    }
+
     pub fn to_vcc_type(&self) -> String {
         match self {
             // Self is returned by obj constructors which are void in VCC
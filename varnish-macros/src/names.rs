@@ -4,7 +4,8 @@ use std::ffi::CString;
 use std::fmt::Display;
 
 use proc_macro2::{Ident, TokenStream};
-use quote::{format_ident, quote, IdentFragment};
+use quote::{format_ident, quote, IdentFragment, ToTokens as _};
+use syn::Path;
 
 use crate::model::FuncType;
 
@@ -16,6 +17,8 @@ pub struct Names {
     module: String,
     /// In case this is an object, its name
     object: Option<String>,
+    /// In case this is an object whose Rust type is not its name, e.g. `Store<i64>`
+    object_ty: Option<String>,
     /// In case this is a function, its name
     function: Option<(FuncType, String)>,
 }
@@ -25,16 +28,18 @@ impl Names {
         Self {
             module: mod_name.to_string(),
             object: None,
+            object_ty: None,
             function: None,
         }
     }
 
-    pub fn to_obj(&self, obj_name: &str) -> Self {
+    pub fn to_obj(&self, obj_name: &str, obj_ty: Option<&str>) -> Self {
         assert!(self.object.is_none());
         assert!(self.function.is_none());
         Self {
             module: self.module.clone(),
             object: Some(obj_name.to_string()),
+            object_ty: obj_ty.map(ToString::to_string),
             function: None,
         }
     }
@@ -44,6 +49,7 @@ impl Names {
         Self {
             module: self.module.clone(),
             object: self.object.clone(),
+            object_ty: self.object_ty.clone(),
             function: Some((func_type, fn_name.to_string())),
         }
     }
@@ -72,6 +78,10 @@ impl Names {
     pub fn fn_callable_name(&self, func: FuncType) -> TokenStream {
         let name = self.fn_name_user().to_ident();
         match func {
+            FuncType::Constructor if self.object_ty.is_some() => {
+                let obj = self.obj_access();
+                quote! { <#obj>::#name }
+            }
             FuncType::Constructor => {
                 let obj = self.obj_access();
                 quote! { #obj::#name }
@@ -81,9 +91,17 @@ impl Names {
         }
     }
 
+    /// The Rust type of the object, as seen from the generated module
+    pub fn obj_ty(&self) -> TokenStream {
+        match &self.object_ty {
+            Some(ty) => ty.parse().expect("object type was parsed by syn"),
+            None => self.obj_name().to_ident().into_token_stream(),
+        }
+    }
+
     pub fn obj_access(&self) -> TokenStream {
-        let name = self.obj_name().to_ident();
-        quote! { super::#name }
+        let ty = self.obj_ty();
+        quote! { super::#ty }
     }
 
     pub fn func_struct_name(&self) -> String {
@@ -140,6 +158,38 @@ impl Names {
     }
 }
 
+/// The path of a `#[vmod_part]` module, as seen from the generated module inside the `#[vmod]`.
+/// Part paths always start with `crate` or `super`.
+pub fn part_path_from_generated(path: &Path) -> TokenStream {
+    if path.segments[0].ident == "super" {
+        quote! { super::#path }
+    } else {
+        quote! { #path }
+    }
+}
+
+/// The path of a `#[vmod_part]` module, as seen from the parent of the `#[vmod]` module,
+/// i.e. where the `#[vmod]` attribute is expanded.
+pub fn part_path_from_parent(path: &Path) -> TokenStream {
+    if path.segments[0].ident == "super" {
+        let rest = path.segments.iter().skip(1);
+        quote! { self #(::#rest)* }
+    } else {
+        quote! { #path }
+    }
+}
+
+/// The path of a `global_init` or `global_fini` function, as seen from the generated module.
+/// Unless they start with `crate` or `::`, the paths are relative to the `#[vmod]` module.
+pub fn hook_path_from_generated(path: &str) -> TokenStream {
+    let path: Path = syn::parse_str(path).expect("hook paths are validated by the parser");
+    if path.leading_colon.is_some() || path.segments[0].ident == "crate" {
+        quote! { #path }
+    } else {
+        quote! { super::#path }
+    }
+}
+
 pub trait ForceCstr {
     fn force_cstr(&self) -> CString;
 }
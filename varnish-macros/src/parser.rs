@@ -2,37 +2,107 @@
 //! The model describes the functions, objects, and events that should be exposed to Varnish.
 //! The model should be thoroughly validated before generating the output, and is treated as the source of truth.
 
+use std::collections::HashMap;
+use std::iter::once;
+
 use darling::ast::NestedMeta;
 use darling::FromMeta;
 use proc_macro2::TokenStream;
-use syn::{Attribute, ImplItem, Item, ItemImpl, ItemMod, ReturnType, Signature, Visibility};
+use quote::ToTokens as _;
+use syn::{
+    Attribute, FnArg, ImplItem, Item, ItemImpl, ItemMod, ReturnType, Signature, Type, TypePath,
+    Visibility,
+};
 
 use crate::errors::Errors;
 use crate::model::{
-    FuncInfo, FuncType, ObjInfo, OutputTy, ParamKind, ParamType, ParamTypeInfo, SharedTypes,
-    VmodInfo, VmodParams,
+    EnumInfo, FuncInfo, FuncType, ObjInfo, ObjParams, OutputTy, ParamKind, ParamType,
+    ParamTypeInfo, SharedTypes, VmodInfo, VmodParams,
 };
 use crate::parser_args::FuncStatus;
+use crate::parser_enums::is_vcl_enum;
+use crate::parser_names::{check_name, NameKind, NameScope};
+use crate::parser_records::{is_vcl_record, Record};
 use crate::{parser_utils, ProcResult};
 
+#[cfg(test)]
 pub fn tokens_to_model(args: TokenStream, item_mod: &mut ItemMod) -> ProcResult<VmodInfo> {
+    tokens_to_model_with_parts(parse_params(args)?, item_mod, &mut [])
+}
+
+pub fn parse_params(args: TokenStream) -> ProcResult<VmodParams> {
     let args = NestedMeta::parse_meta_list(args).map_err(syn::Error::from)?;
-    let args = VmodParams::from_list(&args)?;
-    let info = VmodInfo::parse(args, item_mod)?;
+    Ok(VmodParams::from_list(&args)?)
+}
+
+/// Parse the `#[vmod]` module together with the modules listed in its `parts` parameter
+pub fn tokens_to_model_with_parts(
+    params: VmodParams,
+    item_mod: &mut ItemMod,
+    part_mods: &mut [ItemMod],
+) -> ProcResult<VmodInfo> {
+    let mut errors = Errors::new();
+    let ident = item_mod.ident.to_string();
+    check_name(&item_mod.ident, &ident, NameKind::Vmod, &mut errors);
+    let mut info = VmodInfo::parse(params, item_mod, &mut errors);
+    for part in part_mods {
+        let part = VmodInfo::parse(VmodParams::default(), part, &mut errors);
+        part.check_no_enums(&mut errors);
+        info.parts.push(part);
+    }
+    info.validate(item_mod, &mut errors);
+    errors.into_result()?;
+    if info.params.inline {
+        parser_utils::add_inline_attrs(item_mod);
+    }
+    Ok(info)
+}
+
+/// Parse a module tagged with `#[vmod_part]`. Only the checks that do not depend on the rest
+/// of the VMOD are done here, the others are done once the part is merged into its `#[vmod]`.
+pub fn part_to_model(item_mod: &mut ItemMod) -> ProcResult<VmodInfo> {
+    let mut errors = Errors::new();
+    let info = VmodInfo::parse(VmodParams::default(), item_mod, &mut errors);
+    info.check_no_enums(&mut errors);
+    if info.funcs.is_empty() && info.objects.is_empty() && errors.is_empty() {
+        errors.add(
+            &item_mod.ident,
+            "No functions or objects found in this module",
+        );
+    }
+    errors.into_result()?;
     Ok(info)
 }
 
 impl VmodInfo {
-    /// Parse the `mod` item and generate the model of everything
-    fn parse(params: VmodParams, item: &mut ItemMod) -> ProcResult<Self> {
-        let mut errors = Errors::new();
+    /// Parse the `mod` item and generate the model of everything it contains.
+    /// The result must be validated with [`VmodInfo::validate`] once the parts are known.
+    #[expect(clippy::too_many_lines)]
+    fn parse(params: VmodParams, item: &mut ItemMod, errors: &mut Errors) -> Self {
         let mut funcs = Vec::<FuncInfo>::new();
         let mut objects = Vec::<ObjInfo>::new();
         let mut shared_types = SharedTypes::default();
+        let mut names = NameScope::default();
 
         if let Some((_, content)) = &mut item.content {
-            for item in content {
+            // enums must be known before parsing the arguments using them
+            shared_types.enums = EnumInfo::collect(content, errors);
+            let records = Record::collect(content, errors);
+            let mut record_items = Vec::new();
+            for item in content.iter_mut() {
                 match item {
+                    Item::Fn(fn_item) if Record::find(&records, &fn_item.sig).is_some() => {
+                        // a function returning a record is exposed as an object
+                        let record = Record::find(&records, &fn_item.sig).unwrap();
+                        let ident = fn_item.sig.ident.to_string();
+                        check_name(&fn_item.sig.ident, &ident, NameKind::Object, errors);
+                        names.insert(&fn_item.sig.ident, &ident, errors);
+                        let obj = ObjInfo::parse_record_fn(fn_item, record, &mut shared_types);
+                        if let Some((obj, items)) = errors.on_err(obj) {
+                            objects.push(obj);
+                            record_items.extend(items);
+                        }
+                    }
                     Item::Fn(fn_item) => {
                         // a function or an event handler
                         let func = FuncInfo::parse(
@@ -43,6 +113,11 @@ impl VmodInfo {
                             false,
                         );
                         if let Some(func) = errors.on_err(func) {
+                            if !matches!(func.func_type, FuncType::Event) {
+                                let ident = &fn_item.sig.ident;
+                                check_name(ident, &func.ident, NameKind::Function, errors);
+                                names.insert(ident, &func.ident, errors);
+                            }
                             funcs.push(func);
                         }
                     }
@@ -51,13 +126,27 @@ impl VmodInfo {
                         if let Some(obj) =
                             errors.on_err(ObjInfo::parse(impl_item, &mut shared_types))
                         {
+                            if objects.iter().any(|o| o.ident == obj.ident) {
+                                errors.add(
+                                    &impl_item.self_ty,
+                                    &format!("Object `{}` is declared more than once. Use #[vmod_object(name = \"...\")] to give each instantiation its own name", obj.ident),
+                                );
+                            } else {
+                                names.insert(&impl_item.self_ty, &obj.ident, errors);
+                            }
                             objects.push(obj);
                         }
                     }
                     Item::Use(_) => { /* ignore */ }
+                    Item::Struct(item_struct) if is_vcl_record(item_struct) => {
+                        // already parsed above
+                    }
                     Item::Struct { .. } => {
                         errors.add(item, &err_msg_item_not_allowed("Structs"));
                     }
+                    Item::Enum(item_enum) if is_vcl_enum(item_enum) => {
+                        // already parsed above
+                    }
                     Item::Enum { .. } => {
                         errors.add(item, &err_msg_item_not_allowed("Enums"));
                     }
@@ -105,40 +194,148 @@ impl VmodInfo {
                     }
                 }
             }
+            content.extend(record_items);
         }
-        let info = Self {
+        Self {
             params,
             ident: item.ident.to_string(),
             docs: parser_utils::parse_doc_str(&item.attrs),
             shared_types,
             funcs,
             objects,
-        };
-        info.validate(item, &mut errors);
-        errors.into_result()?;
-        Ok(info)
+            parts: Vec::new(),
+        }
+    }
+
+    /// The `ENUM` values are exported by the `#[vmod]` module, so parts cannot declare enums
+    fn check_no_enums(&self, errors: &mut Errors) {
+        for enum_ty in &self.shared_types.enums {
+            errors.add(
+                &self.ident,
+                &format!(
+                    "`{}`: enums deriving `VclEnum` must be declared in the `#[vmod]` module, not in a `#[vmod_part]`",
+                    enum_ty.ident
+                ),
+            );
+        }
     }
 
     pub fn validate(&self, item: &ItemMod, errors: &mut Errors) {
-        if self.count_funcs(|v| matches!(v.func_type, FuncType::Event)) > 1 {
+        let modules = || once(self).chain(&self.parts);
+        let events = modules()
+            .map(|v| v.count_funcs(|v| matches!(v.func_type, FuncType::Event)))
+            .sum::<usize>();
+        if events > 1 {
             errors.add(
                 &item,
                 "More than one event handler found. Only one event handler is allowed",
             );
         }
-        let per_vcl_mut = self.count_args(|v| matches!(v.ty, ParamType::SharedPerVclMut));
-        let per_vcl_ref = self.count_args(|v| matches!(v.ty, ParamType::SharedPerVclRef));
+        for hook in [&self.params.global_init, &self.params.global_fini]
+            .into_iter()
+            .flatten()
+        {
+            if syn::parse_str::<syn::Path>(hook).is_err() {
+                errors.add(
+                    &item.ident,
+                    &format!("`{hook}` is not a valid path to a global hook function"),
+                );
+            }
+        }
+        let has_hooks = self.params.global_init.is_some() || self.params.global_fini.is_some();
+        if has_hooks
+            && self
+                .parts
+                .iter()
+                .any(|p| p.count_funcs(|f| matches!(f.func_type, FuncType::Event)) > 0)
+        {
+            errors.add(
+                &item.ident,
+                "With `global_init` or `global_fini`, the event handler must be in the `#[vmod]` module, not in a `#[vmod_part]`",
+            );
+        }
+        let per_vcl_mut = modules()
+            .map(|v| v.count_args(|v| matches!(v.ty, ParamType::SharedPerVclMut)))
+            .sum::<usize>();
+        let per_vcl_ref = modules()
+            .map(|v| v.count_args(|v| matches!(v.ty, ParamType::SharedPerVclRef)))
+            .sum::<usize>();
         if per_vcl_ref > 0 && per_vcl_mut == 0 {
             errors.add(
                 &item,
                 "#[shared_per_vcl] value has not been initialized. Add a `&mut Option<Box<...>>` param to an event handler or an object new() function",
             );
         }
-        if self.funcs.is_empty() && self.objects.is_empty() && errors.is_empty() {
+        if !self.parts.is_empty() {
+            self.validate_parts(item, errors);
+        }
+        if self.all_funcs().next().is_none()
+            && self.all_objects().next().is_none()
+            && errors.is_empty()
+        {
             // If another error is reported, most likely it was not added to funcs or objects, so we don't need to report this one
             errors.add(&self.ident, "No functions or objects found in this module");
         }
     }
+
+    /// Check that the parts agree with the main module and with each other
+    fn validate_parts(&self, item: &ItemMod, errors: &mut Errors) {
+        let paths = once("the `#[vmod]` module".to_string()).chain(
+            self.params
+                .parts
+                .iter()
+                .map(|p| format!("`{}`", p.to_token_stream().to_string().replace(' ', ""))),
+        );
+        let modules: Vec<_> = paths.zip(once(self).chain(&self.parts)).collect();
+
+        let mut names = HashMap::new();
+        let mut task_ty = None;
+        let mut vcl_ty = None;
+        for (path, info) in &modules {
+            let funcs = info
+                .funcs
+                .iter()
+                .filter(|f| !matches!(f.func_type, FuncType::Event));
+            let idents = funcs
+                .map(|f| &f.ident)
+                .chain(info.objects.iter().map(|o| &o.ident));
+            for ident in idents {
+                // VCL cannot tell apart the names that only differ by case
+                if let Some((other_ident, other)) =
+                    names.insert(ident.to_ascii_lowercase(), (ident, path))
+                {
+                    let msg = if other_ident == ident {
+                        format!("`{ident}` is declared in both {other} and {path}")
+                    } else {
+                        format!("`{ident}` in {path} and `{other_ident}` in {other} only differ by case, VCL cannot tell them apart")
+                    };
+                    errors.add(&item.ident, &msg);
+                }
+            }
+            for (seen, ty, attr) in [
+                (
+                    &mut task_ty,
+                    &info.shared_types.shared_per_task_ty,
+                    "shared_per_task",
+                ),
+                (
+                    &mut vcl_ty,
+                    &info.shared_types.shared_per_vcl_ty,
+                    "shared_per_vcl",
+                ),
+            ] {
+                let Some(ty) = ty else { continue };
+                match seen {
+                    None => *seen = Some((ty, path)),
+                    Some((seen_ty, other)) if *seen_ty != ty => errors.add(
+                        &item.ident,
+                        &format!("#[{attr}] type is `{seen_ty}` in {other}, but `{ty}` in {path}. All modules must use the same type"),
+                    ),
+                    Some(_) => {}
+                }
+            }
+        }
+    }
 }
 
 fn err_msg_item_not_allowed(typ: &str) -> String {
@@ -149,7 +346,15 @@ impl ObjInfo {
     /// Parse an `impl` block and treat all public functions as object methods
     fn parse(item_impl: &mut ItemImpl, shared_types: &mut SharedTypes) -> ProcResult<Self> {
         let mut errors = Errors::new();
-        let ident = parser_utils::as_simple_ty(item_impl.self_ty.as_ref()).map(ToString::to_string);
+        let params = match parser_utils::remove_attr(&mut item_impl.attrs, "vmod_object") {
+            Some(attr) => ObjParams::from_meta(&attr.meta)?,
+            None => ObjParams::default(),
+        };
+        let simple_ty = parser_utils::as_simple_ty(item_impl.self_ty.as_ref());
+        let is_single_segment = matches!(
+            item_impl.self_ty.as_ref(),
+            Type::Path(TypePath { qself: None, path }) if path.segments.len() == 1
+        );
 
         // Add only one error per object impl declaration
         if item_impl.trait_.as_ref().is_some() {
@@ -157,17 +362,36 @@ impl ObjInfo {
         } else if !item_impl.generics.params.is_empty() {
             errors.add(
                 &item_impl.generics.params,
-                "Generics are not supported for object impls",
+                "Generics are not supported for object impls. Export each instantiation with its own impl block, e.g. `#[vmod_object(name = \"intstore\")] impl Store<i64>`",
             );
-        } else if ident.is_none() {
+        } else if !is_single_segment {
             errors.add(
                 &item_impl.self_ty,
                 "Expected a simple type for object. If the object is defined elsewhere, use `use` to import it.",
             );
+        } else if simple_ty.is_none() && params.name.is_none() {
+            errors.add(
+                &item_impl.self_ty,
+                "Objects with generic arguments must be named with #[vmod_object(name = \"...\")]",
+            );
+        } else if let Some(name) = params
+            .name
+            .clone()
+            .or_else(|| simple_ty.map(ToString::to_string))
+        {
+            check_name(&item_impl.self_ty, &name, NameKind::Object, &mut errors);
         }
+        let (ident, ty) = match (params.name, simple_ty) {
+            (Some(name), _) => (
+                Some(name),
+                Some(item_impl.self_ty.to_token_stream().to_string()),
+            ),
+            (None, ident) => (ident.map(ToString::to_string), None),
+        };
 
         let mut funcs = Vec::new();
         let mut constructor = None;
+        let mut names = NameScope::default();
         for item in &mut item_impl.items {
             if let ImplItem::Fn(fn_item) = item {
                 let Some(func) = errors.on_err(FuncInfo::parse(
@@ -182,6 +406,9 @@ impl ObjInfo {
                 if func.ident == "new" {
                     constructor = Some(func);
                 } else {
+                    let ident = &fn_item.sig.ident;
+                    check_name(ident, &func.ident, NameKind::Method, &mut errors);
+                    names.insert(ident, &func.ident, &mut errors);
                     funcs.push(func);
                 }
             }
@@ -197,25 +424,31 @@ impl ObjInfo {
         errors.into_result()?;
         Ok(Self {
             ident: ident.expect("ident err already reported"),
+            ty,
             docs: parser_utils::parse_doc_str(&item_impl.attrs),
             constructor: constructor.expect("ctor err already reported"),
-            destructor: FuncInfo {
-                func_type: FuncType::Destructor,
-                ident: "_fini".to_string(),
-                docs: String::new(),
-                has_optional_args: false,
-                args: Vec::new(),
-                output_ty: OutputTy::Default,
-                out_result: false,
-            },
+            destructor: FuncInfo::destructor(),
             funcs,
         })
     }
 }
 
 impl FuncInfo {
+    /// The implicit object destructor
+    pub fn destructor() -> Self {
+        Self {
+            func_type: FuncType::Destructor,
+            ident: "_fini".to_string(),
+            docs: String::new(),
+            has_optional_args: false,
+            args: Vec::new(),
+            output_ty: OutputTy::Default,
+            out_result: false,
+        }
+    }
+
     /// Parse a function or a method signature
-    fn parse(
+    pub fn parse(
         shared_types: &mut SharedTypes,
         signature: &mut Signature,
         vis: &Visibility,
@@ -264,11 +497,17 @@ impl FuncInfo {
 
         let mut status = FuncStatus::new(func_type);
         let mut args = Vec::new();
+        let mut names = NameScope::default();
 
         for (idx, arg) in signature.inputs.iter_mut().enumerate() {
-            let arg = ParamTypeInfo::parse(shared_types, &mut status, idx, arg);
-            if let Some(arg) = errors.on_err(arg) {
-                args.push(arg);
+            let parsed = ParamTypeInfo::parse(shared_types, &mut status, idx, arg);
+            if let Some(parsed) = errors.on_err(parsed) {
+                // only the values are named from VCL
+                if let (ParamType::Value(_), FnArg::Typed(pat_ty)) = (&parsed.ty, &*arg) {
+                    check_name(&pat_ty.pat, &parsed.ident, NameKind::Argument, &mut errors);
+                    names.insert(&pat_ty.pat, &parsed.ident, &mut errors);
+                }
+                args.push(parsed);
             }
         }
 
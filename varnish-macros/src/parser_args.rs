@@ -1,25 +1,32 @@
 use darling::ast::NestedMeta;
+use quote::ToTokens as _;
 use serde_json::Value;
 use syn::Type::Tuple;
-use syn::{FnArg, GenericArgument, Lit, Meta, Pat, PatType, Type};
+use syn::{Attribute, Expr, ExprLit, FnArg, GenericArgument, Ident, Lit, Meta, Pat, PatType, Type};
 
 use crate::errors::error;
 use crate::model::FuncType::{Constructor, Event, Function, Method};
 use crate::model::{
-    FuncType, OutputTy, ParamInfo, ParamKind, ParamTy, ParamType, ParamTypeInfo, SharedTypes,
+    CtxView, EnumInfo, FuncType, OutputTy, ParamInfo, ParamKind, ParamTy, ParamType, ParamTypeInfo,
+    SharedTypes,
 };
 use crate::parser_utils::{
     as_one_gen_arg, as_option_type, as_ref_mut_ty, as_ref_ty, as_simple_ty, as_slice_ty,
-    parse_and_rm_doc, parse_shared_mut, parse_shared_ref, remove_attr,
+    is_cow_str, parse_and_rm_doc, parse_shared_mut, parse_shared_ref, remove_attr,
 };
 use crate::ProcResult;
 
+const ERR_MUT_CTX_WITH_WS: &str = "A Workspace param can only be combined with `&Ctx`. With `&mut Ctx` or a context view, use its `ws` field instead";
+
 /// Parser state for a function parser. This is not part of the model, but helps with error detection.
 #[derive(Debug, Default)]
 #[expect(clippy::struct_excessive_bools)]
 pub struct FuncStatus {
     func_type: FuncType,
-    has_ctx_or_ws: bool,
+    has_ctx: bool,
+    /// `&mut Ctx` or a context view, which cannot be used together with a `Workspace`
+    has_ctx_mut: bool,
+    has_ws: bool,
     has_shared_per_task: bool,
     has_shared_per_vcl: bool,
     has_event: bool,
@@ -155,7 +162,7 @@ impl ParamType {
                 Some(ty) if matches!(ty, ParamTy::Str | ParamTy::CStr) => ty,
                 _ => error! { "#[vcl_name] params must be declared as `&str` or `&CStr`" },
             };
-            Self::VclName(ParamInfo::new(arg_ty, Value::Null, ParamKind::Regular))
+            Self::VclName(ParamInfo::new(arg_ty, None, ParamKind::Regular))
         } else if as_simple_ty(arg_ty)
             .filter(|ident| *ident == "Event")
             .is_some()
@@ -163,26 +170,40 @@ impl ParamType {
             only_in! { Event, "Event parameters are only allowed in event handlers. Try adding `#[event]` to this function." }
             unique! { has_event, "Event param is allowed only once in a function args list" }
             Self::Event
-        } else if let Some(arg_ty) = as_ref_ty(arg_ty)
-            .and_then(as_simple_ty)
-            .filter(|ident| *ident == "Ctx" || *ident == "Workspace")
+        } else if let Some((arg_ty, is_mut)) = as_ref_ty(arg_ty)
+            .map(|ty| (ty, false))
+            .or_else(|| as_ref_mut_ty(arg_ty).map(|ty| (ty, true)))
+            .and_then(|(ty, is_mut)| Some((as_simple_ty(ty)?, is_mut)))
+            .filter(|(ident, _)| *ident == "Ctx" || *ident == "Workspace")
         {
-            unique! { has_ctx_or_ws, "Context or Workspace param is allowed only once in a function args list" }
             if arg_ty == "Ctx" {
-                Self::Context { is_mut: false }
+                unique! { has_ctx, "Context param is allowed only once in a function args list" }
+                if is_mut {
+                    if status.has_ws {
+                        error! { ERR_MUT_CTX_WITH_WS }
+                    }
+                    status.has_ctx_mut = true;
+                }
+                Self::Context { is_mut }
             } else {
-                Self::Workspace { is_mut: false }
+                unique! { has_ws, "Workspace param is allowed only once in a function args list" }
+                if status.has_ctx_mut {
+                    error! { ERR_MUT_CTX_WITH_WS }
+                }
+                Self::Workspace { is_mut }
             }
-        } else if let Some(arg_ty) = as_ref_mut_ty(arg_ty)
-            .and_then(as_simple_ty)
-            .filter(|ident| *ident == "Ctx" || *ident == "Workspace")
+        } else if let Some((view, is_mut)) = as_ref_ty(arg_ty)
+            .map(|ty| (ty, false))
+            .or_else(|| as_ref_mut_ty(arg_ty).map(|ty| (ty, true)))
+            .and_then(|(ty, is_mut)| Some((CtxView::try_parse(as_simple_ty(ty)?)?, is_mut)))
         {
-            unique! { has_ctx_or_ws, "Context or Workspace param is allowed only once in a function args list" }
-            if arg_ty == "Ctx" {
-                Self::Context { is_mut: true }
-            } else {
-                Self::Workspace { is_mut: true }
+            only_in! { Function | Method, "Context views are only allowed in functions and methods, use `Ctx` instead" }
+            unique! { has_ctx, "Context param is allowed only once in a function args list" }
+            if status.has_ws {
+                error! { ERR_MUT_CTX_WITH_WS }
             }
+            status.has_ctx_mut = true;
+            Self::ContextView { view, is_mut }
         } else if as_ref_mut_ty(arg_ty)
             .and_then(as_simple_ty)
             .filter(|ident| *ident == "FetchFilters")
@@ -207,23 +228,60 @@ impl ParamType {
             } }
             unique! { has_delivery_filters, "A DeliveryFilters param is allowed only once in a function args list" }
             Self::DeliveryFilters
+        } else if Self::has_config_attr(pat_ty) {
+            only_in! { Constructor, "#[arg(config)] params are only allowed in object constructors" }
+            let opt = if as_option_type(arg_ty).is_some() {
+                ParamKind::Optional
+            } else {
+                ParamKind::Regular
+            };
+            let (default, _, strands) = Self::get_arg_opts(pat_ty, ParamTy::Str, None)?;
+            if strands {
+                error! { "#[arg(config)] and #[arg(strands)] can't be combined" }
+            }
+            let mut info = ParamInfo::new(ParamTy::Str, default, opt);
+            info.config = true;
+            Self::Value(info)
         } else {
             // Only standard types left, possibly optional
             not_in! { Event, "Event functions can only have `Ctx`, `#[event] Event`, and `#[shared_per_vcl] &mut Option<Box<T>>` arguments." }
-            let Some((opt, arg_ty)) = ParamTy::try_parse_or_optional(arg_ty) else {
-                error! {"unsupported argument type" }
+            let enum_ty = as_simple_ty(as_option_type(arg_ty).unwrap_or(arg_ty))
+                .and_then(|ident| shared_types.enums.iter().find(|e| *ident == e.ident))
+                .cloned();
+            let parsed = if enum_ty.is_some() {
+                Some((as_option_type(arg_ty).is_some(), ParamTy::Enum))
+            } else {
+                ParamTy::try_parse_or_optional(arg_ty)
+            };
+            let Some((opt, arg_ty)) = parsed else {
+                Err(error(
+                    arg_ty,
+                    &format!(
+                        "unsupported argument type `{}`. {}",
+                        type_to_string(arg_ty),
+                        type_hint(arg_ty, true)
+                    ),
+                ))?
             };
             if !opt && arg_ty.must_be_optional() {
                 error! { "This type of argument must be declared as optional with `Option<...>`" }
             }
-            let default = Self::get_arg_opts(pat_ty, arg_ty)?;
+            let (default, lossy, strands) = Self::get_arg_opts(pat_ty, arg_ty, enum_ty.as_ref())?;
+            if strands && default.is_some() {
+                error! { "#[arg(strands)] params can't have a default value" }
+            }
             let has_required = Self::get_required_attr(pat_ty)?;
             let opt = if has_required {
                 if !opt {
                     error! { "The `required` attribute is only allowed on Option<...> arguments" }
                 }
-                if !arg_ty.must_be_optional() && !matches!(arg_ty, ParamTy::CStr | ParamTy::Str) {
-                    error! { "The `required` attribute is only allowed on CStr, str, Probe, ProbeCow, and SocketAddr arguments" }
+                if !arg_ty.must_be_optional()
+                    && !matches!(
+                        arg_ty,
+                        ParamTy::CStr | ParamTy::Str | ParamTy::CowStr | ParamTy::Bytes
+                    )
+                {
+                    error! { "The `required` attribute is only allowed on CStr, str, Cow<str>, [u8], Probe, ProbeCow, SocketAddr, IpAddr, and BackendRef arguments" }
                 }
                 ParamKind::Required
             } else if opt {
@@ -231,19 +289,68 @@ impl ParamType {
             } else {
                 ParamKind::Regular
             };
-            Self::Value(ParamInfo::new(arg_ty, default, opt))
+            let mut info = ParamInfo::new(arg_ty, default, opt);
+            info.enum_ty = enum_ty;
+            info.lossy = lossy;
+            info.strands = strands;
+            Self::Value(info)
         })
     }
 
-    /// Try to get the default value from the #[default(...)] attribute on an argument
-    fn get_arg_opts(pat_ty: &mut PatType, arg_type: ParamTy) -> ProcResult<Value> {
+    /// Try to get the default value from the `#[default(...)]` or `#[arg(default_expr = "...")]`
+    /// attribute on an argument, as VCL source, and the `#[arg(lossy)]` and `#[arg(strands)]` flags
+    fn get_arg_opts(
+        pat_ty: &mut PatType,
+        arg_type: ParamTy,
+        enum_ty: Option<&EnumInfo>,
+    ) -> ProcResult<(Option<String>, bool, bool)> {
+        let (expr, lossy, strands) = Self::get_arg_attr(pat_ty, arg_type)?;
+        let default = Self::get_default(pat_ty, arg_type, enum_ty, expr)?;
+        Ok((default, lossy, strands))
+    }
+
+    /// Try to get the default value from the `#[default(...)]` attribute, unless it was already
+    /// set by `#[arg(default_expr = "...")]`
+    fn get_default(
+        pat_ty: &mut PatType,
+        arg_type: ParamTy,
+        enum_ty: Option<&EnumInfo>,
+        expr: Option<String>,
+    ) -> ProcResult<Option<String>> {
         let Some(arg) = remove_attr(&mut pat_ty.attrs, "default") else {
-            return Ok(Value::Null);
+            return Ok(expr);
         };
+        if expr.is_some() {
+            Err(error(
+                &pat_ty,
+                "Use either #[default(...)] or #[arg(default_expr = ...)], not both",
+            ))?;
+        }
         let Meta::List(arg) = arg.meta else {
             Err(error(&pat_ty, "Unexpected #[default(...)] attribute"))?
         };
         let arg = NestedMeta::parse_meta_list(arg.tokens)?;
+        if let Some(enum_ty) = enum_ty {
+            // The default of an `ENUM` is the bare VCL name of a variant
+            let [NestedMeta::Meta(Meta::Path(path))] = arg.as_slice() else {
+                Err(error(
+                    &pat_ty,
+                    "Default value must be a variant of the enum, e.g. #[default(Color::Red)]",
+                ))?
+            };
+            let variant = path.segments.last().map(|s| &s.ident);
+            return match enum_ty
+                .variants
+                .iter()
+                .find(|(v, _)| variant.is_some_and(|i| i == v))
+            {
+                Some((_, name)) => Ok(Some(name.clone())),
+                None => Err(error(
+                    path,
+                    &format!("Expected a variant of `{}`", enum_ty.ident),
+                ))?,
+            };
+        }
         let [NestedMeta::Lit(lit)] = arg.as_slice() else {
             Err(error(&pat_ty, "Default value must be a literal value"))?
         };
@@ -256,31 +363,137 @@ impl ParamType {
             };
         }
 
-        Ok(match lit {
+        // Strings are escaped and surrounded by quotes
+        Ok(Some(match lit {
             Lit::Str(v) => {
-                only! { ParamTy::Str | ParamTy::CStr, "Only `&str` and `&CStr` arguments can have a default string value" }
-                Value::String(v.value())
+                only! { ParamTy::Str | ParamTy::CStr | ParamTy::CowStr | ParamTy::Bytes, "Only `&str`, `&CStr`, `Cow<str>`, and `&[u8]` arguments can have a default string value" }
+                Value::String(v.value()).to_string()
             }
             Lit::CStr(v) => {
-                only! { ParamTy::Str | ParamTy::CStr, "Only `&str` and `&CStr` arguments can have a default string value" }
-                Value::String(v.value().to_str().unwrap().to_string())
+                only! { ParamTy::Str | ParamTy::CStr | ParamTy::CowStr | ParamTy::Bytes, "Only `&str`, `&CStr`, `Cow<str>`, and `&[u8]` arguments can have a default string value" }
+                Value::String(v.value().to_str().unwrap().to_string()).to_string()
             }
             Lit::Int(v) => {
                 only! { ParamTy::I64, "Only `i64` arguments can have a default integer value" }
-                serde_json::from_str(&v.to_string()).unwrap()
+                serde_json::from_str::<Value>(&v.to_string())
+                    .unwrap()
+                    .to_string()
             }
             Lit::Float(v) => {
                 only! { ParamTy::F64, "Only `f64` arguments can have a default float value" }
-                serde_json::from_str(&v.to_string()).unwrap()
+                serde_json::from_str::<Value>(&v.to_string())
+                    .unwrap()
+                    .to_string()
             }
             Lit::Bool(v) => {
                 only! { ParamTy::Bool, "Only `bool` arguments can have a default boolean value" }
-                Value::Number(i32::from(v.value).into())
+                i32::from(v.value).to_string()
             }
             _ => Err(error(&pat_ty, "Unrecognized value in #[default(...)]"))?,
+        }))
+    }
+
+    /// Check for the `config` flag of `#[arg(...)]`, without removing the attribute. The argument
+    /// is then a `STRING` in VCL, whatever its Rust type.
+    fn has_config_attr(pat_ty: &PatType) -> bool {
+        pat_ty.attrs.iter().any(|attr| match &attr.meta {
+            Meta::List(list) if attr.path().is_ident("arg") => {
+                NestedMeta::parse_meta_list(list.tokens.clone()).is_ok_and(|args| {
+                    args.iter().any(|arg| {
+                        matches!(arg, NestedMeta::Meta(Meta::Path(path)) if path.is_ident("config"))
+                    })
+                })
+            }
+            _ => false,
         })
     }
 
+    /// Parse the `#[arg(...)]` attribute on an argument: a VCL literal from `default_expr = "10s"`,
+    /// and the `lossy` and `strands` flags. The `config` flag is handled by [`Self::has_config_attr`].
+    fn get_arg_attr(
+        pat_ty: &mut PatType,
+        arg_type: ParamTy,
+    ) -> ProcResult<(Option<String>, bool, bool)> {
+        const EXPECTED: &str = "Expected #[arg(default_expr = \"...\")], #[arg(lossy)], #[arg(strands)] or #[arg(config)]";
+        let mut default_expr = None;
+        let mut lossy = false;
+        let mut strands = false;
+        let args = match remove_attr(&mut pat_ty.attrs, "arg") {
+            Some(Attribute {
+                meta: Meta::List(arg),
+                ..
+            }) => NestedMeta::parse_meta_list(arg.tokens)?,
+            Some(_) => Err(error(&pat_ty, EXPECTED))?,
+            None => Vec::new(),
+        };
+        for arg in args {
+            match &arg {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("lossy") => {
+                    if !matches!(arg_type, ParamTy::CowStr) {
+                        Err(error(
+                            &pat_ty,
+                            "`lossy` is only supported on `Cow<str>` arguments, as the replaced value must be owned",
+                        ))?;
+                    }
+                    lossy = true;
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("strands") => {
+                    if !matches!(arg_type, ParamTy::Str) {
+                        Err(error(
+                            &pat_ty,
+                            "`strands` is only supported on `&str` arguments, use `Strands` to read the pieces",
+                        ))?;
+                    }
+                    strands = true;
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("config") => {}
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("default_expr") => {
+                    default_expr = Some(Self::get_default_expr(&nv.value, pat_ty, arg_type)?);
+                }
+                NestedMeta::Meta(Meta::NameValue(nv)) => Err(error(
+                    &nv.path,
+                    "Unknown parameter, expected `default_expr`, `lossy`, `strands` or `config`",
+                ))?,
+                _ => Err(error(&arg, EXPECTED))?,
+            }
+        }
+        if matches!(arg_type, ParamTy::CowStr) && !lossy {
+            Err(error(
+                &pat_ty,
+                "`Cow<str>` arguments must be declared with #[arg(lossy)], use `&str` to fail on invalid UTF-8",
+            ))?;
+        }
+        Ok((default_expr, lossy, strands))
+    }
+
+    /// Check the VCL literal of `#[arg(default_expr = "10s")]`
+    fn get_default_expr(value: &Expr, pat_ty: &PatType, arg_type: ParamTy) -> ProcResult<String> {
+        let Expr::Lit(ExprLit {
+            lit: Lit::Str(expr),
+            ..
+        }) = value
+        else {
+            Err(error(
+                value,
+                "`default_expr` must be a string, e.g. \"10s\"",
+            ))?
+        };
+        let expr = expr.value();
+        if !matches!(arg_type, ParamTy::Duration) {
+            Err(error(
+                &pat_ty,
+                "`default_expr` is only supported on `Duration` arguments",
+            ))?;
+        }
+        if !is_duration_literal(&expr) {
+            Err(error(
+                value,
+                "`default_expr` must be a VCL duration like `10s`, `1.5m` or `100ms`",
+            ))?;
+        }
+        Ok(expr)
+    }
+
     /// Try to get the #[required] attribute on an argument
     fn get_required_attr(pat_ty: &mut PatType) -> ProcResult<bool> {
         let Some(arg) = remove_attr(&mut pat_ty.attrs, "required") else {
@@ -298,11 +511,15 @@ impl ParamType {
 }
 
 impl ParamInfo {
-    fn new(ty_info: ParamTy, default: Value, optional: ParamKind) -> Self {
+    fn new(ty_info: ParamTy, default: Option<String>, optional: ParamKind) -> Self {
         Self {
             kind: optional,
             default,
             ty_info,
+            enum_ty: None,
+            lossy: false,
+            config: false,
+            strands: false,
         }
     }
 }
@@ -310,10 +527,32 @@ impl ParamInfo {
 impl ParamTy {
     /// Tries parsing supported VCL types like `i64`, `Probe`, `Duration`, or Option-wrapped like `Option<&str>`.
     pub fn try_parse_or_optional(ty: &Type) -> Option<(bool, Self)> {
-        if let Some(ty) = as_option_type(ty).and_then(Self::try_parse) {
+        if let Some(ty) = as_option_type(ty).and_then(Self::try_parse_arg) {
             Some((true, ty))
         } else {
-            Self::try_parse(ty).map(|ty| (false, ty))
+            Self::try_parse_arg(ty).map(|ty| (false, ty))
+        }
+    }
+
+    /// Tries parsing the types only supported as arguments, as well as the regular ones
+    fn try_parse_arg(ty: &Type) -> Option<Self> {
+        if is_cow_str(ty) {
+            Some(Self::CowStr)
+        } else if as_ref_ty(ty)
+            .and_then(as_slice_ty)
+            .and_then(as_simple_ty)
+            .is_some_and(|ident| ident == "u8")
+        {
+            Some(Self::Bytes)
+        } else if as_simple_ty(ty).is_some_and(|ident| ident == "Strands")
+            || matches!(
+                as_one_gen_arg(ty, "Strands"),
+                Some(GenericArgument::Lifetime(_))
+            )
+        {
+            Some(Self::Strands)
+        } else {
+            Self::try_parse(ty)
         }
     }
 
@@ -332,6 +571,10 @@ impl ParamTy {
                 return Some(Self::Probe);
             } else if ident == "SocketAddr" {
                 return Some(Self::SocketAddr);
+            } else if ident == "IpAddr" {
+                return Some(Self::IpAddr);
+            } else if ident == "BackendRef" {
+                return Some(Self::Backend);
             }
         }
 
@@ -354,7 +597,14 @@ impl ParamTy {
 impl OutputTy {
     pub fn parse(ty: &Type, func_type: FuncType) -> ProcResult<Self> {
         let Some(ret_ty) = Self::try_parse(ty) else {
-            Err(error(&ty, "This content type is not supported"))?
+            Err(error(
+                &ty,
+                &format!(
+                    "unsupported return type `{}`. {}",
+                    type_to_string(ty),
+                    type_hint(ty, false)
+                ),
+            ))?
         };
 
         if matches!(func_type, Event) && !matches!(ret_ty, Self::Default) {
@@ -415,3 +665,79 @@ impl OutputTy {
         None
     }
 }
+
+impl CtxView {
+    pub fn try_parse(ident: &Ident) -> Option<Self> {
+        [Self::Recv, Self::Fetch, Self::Deliver]
+            .into_iter()
+            .find(|view| ident == view.type_name())
+    }
+}
+
+/// `ty` as written, without the spaces between the tokens added by `to_string()`
+fn type_to_string(ty: &Type) -> String {
+    ty.to_token_stream()
+        .to_string()
+        .replace(" <", "<")
+        .replace("< ", "<")
+        .replace(" >", ">")
+        .replace("& ", "&")
+        .replace(" ,", ",")
+}
+
+/// Point to the supported type closest to `ty`, an argument or a return type that VCL doesn't have
+fn type_hint(ty: &Type, is_arg: bool) -> &'static str {
+    const INTEGERS: &[&str] = &[
+        "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i128", "isize",
+    ];
+    const LISTS: &[&str] = &["Vec", "VecDeque", "HashSet", "BTreeSet"];
+    const MAPS: &[&str] = &["HashMap", "BTreeMap"];
+    const STRINGS: &[&str] = &["String", "Box", "Rc", "Arc"];
+
+    let ty = as_option_type(ty).unwrap_or(ty);
+    let ty = as_ref_ty(ty).unwrap_or(ty);
+    let name = match ty {
+        Type::Path(path) => path.path.segments.last().map(|s| s.ident.to_string()),
+        Type::Slice(_) | Type::Array(_) => Some("Vec".to_string()),
+        _ => None,
+    };
+    let name = name.as_deref().unwrap_or_default();
+    if INTEGERS.contains(&name) {
+        "VCL integers are `i64`: use it, and convert it with `try_from()` to fail the task on out of range values"
+    } else if name == "f32" {
+        "VCL numbers are `f64`: use it instead"
+    } else if matches!(name, "Ipv4Addr" | "Ipv6Addr") {
+        "Use `IpAddr`"
+    } else if LISTS.contains(&name) {
+        if is_arg {
+            "VCL has no lists: take a `&str` and split it, e.g. a comma-separated list"
+        } else {
+            "VCL has no lists: return a `String`, e.g. the items joined with commas or as JSON, or an object with a method giving the items one at a time"
+        }
+    } else if MAPS.contains(&name) {
+        if is_arg {
+            "VCL has no maps: take a JSON `&str` and parse it"
+        } else {
+            "VCL has no maps: return a JSON `String`, or keep it in an object with a `get(key)` method"
+        }
+    } else if is_arg && (STRINGS.contains(&name) || name == "char") {
+        "Use `&str`, or `Cow<str>` with `#[arg(lossy)]` to accept invalid UTF-8"
+    } else if name == "char" {
+        "Return a `String`"
+    } else {
+        "See the supported types in the documentation of `varnish::vcl::convert`"
+    }
+}
+
+/// Check that `value` is a VCL duration literal, i.e. a number followed by a unit
+fn is_duration_literal(value: &str) -> bool {
+    let Some(pos) = value.find(|c: char| c.is_ascii_alphabetic()) else {
+        return false;
+    };
+    let (number, unit) = value.split_at(pos);
+    let mut parts = number.splitn(2, '.');
+    let is_digits = |v: &str| !v.is_empty() && v.bytes().all(|c| c.is_ascii_digit());
+    parts.next().is_some_and(is_digits)
+        && parts.next().is_none_or(is_digits)
+        && matches!(unit, "ms" | "s" | "m" | "h" | "d" | "w" | "y")
+}
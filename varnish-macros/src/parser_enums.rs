@@ -0,0 +1,170 @@
+//! Enums tagged with `#[derive(VclEnum)]` can be used as `ENUM` arguments. The `#[vmod]` macro
+//! and the derive both parse the enum with [`EnumInfo::parse`], so that they agree on the VCL
+//! names and on their order: the derive publishes them as `VclEnum::VARIANTS`, and the generated
+//! wrappers pass the matching entries of the VMOD table to `VclEnum::from_vcl`.
+
+use darling::ast::NestedMeta;
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::token::Comma;
+use syn::{Expr, ExprLit, Fields, Item, ItemEnum, Lit, Meta, Path};
+
+use crate::errors::{error, Errors};
+use crate::model::EnumInfo;
+use crate::names::ToIdent;
+use crate::ProcResult;
+
+/// Check if the enum derives `VclEnum`
+pub fn is_vcl_enum(item: &ItemEnum) -> bool {
+    item.attrs.iter().any(|attr| {
+        attr.path().is_ident("derive")
+            && attr
+                .parse_args_with(Punctuated::<Path, Comma>::parse_terminated)
+                .is_ok_and(|paths| {
+                    paths
+                        .iter()
+                        .any(|p| p.segments.last().is_some_and(|s| s.ident == "VclEnum"))
+                })
+    })
+}
+
+/// Convert a variant name to the default VCL name, e.g. `NoCache` to `no_cache`
+fn to_snake_case(ident: &str) -> String {
+    let mut name = String::new();
+    for (idx, c) in ident.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if idx > 0 && !name.ends_with('_') {
+                name.push('_');
+            }
+            name.push(c.to_ascii_lowercase());
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
+/// Check that `name` can be written as a VCL `ENUM` value, and used in a C identifier
+fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl EnumInfo {
+    /// Parse all the enums declared in the module
+    pub fn collect(content: &[Item], errors: &mut Errors) -> Vec<Self> {
+        content
+            .iter()
+            .filter_map(|item| match item {
+                Item::Enum(item) if is_vcl_enum(item) => errors.on_err(Self::parse(item)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Parse the enum, without removing the `#[vcl_enum(...)]` attributes needed by the derive
+    pub fn parse(item: &ItemEnum) -> ProcResult<Self> {
+        let mut errors = Errors::new();
+        if !item.generics.params.is_empty() {
+            errors.add(&item.generics, "Generic enums are not supported");
+        }
+        if item.variants.is_empty() {
+            errors.add(&item.ident, "Enums must have at least one variant");
+        }
+        let mut variants: Vec<(String, String)> = Vec::new();
+        for variant in &item.variants {
+            if !matches!(variant.fields, Fields::Unit) {
+                errors.add(variant, "Enum variants cannot have fields");
+                continue;
+            }
+            let ident = variant.ident.to_string();
+            let name = match Self::get_rename(&variant.attrs) {
+                Ok(Some(name)) => name,
+                Ok(None) => to_snake_case(&ident),
+                Err(e) => {
+                    errors.combine(e);
+                    continue;
+                }
+            };
+            if !is_valid_name(&name) {
+                errors.add(
+                    &variant.ident,
+                    &format!("`{name}` is not a valid VCL enum value. Use letters, digits, and `_`, starting with a letter"),
+                );
+            } else if variants.iter().any(|(_, v)| *v == name) {
+                errors.add(
+                    &variant.ident,
+                    &format!("The VCL enum value `{name}` is used more than once"),
+                );
+            }
+            variants.push((ident, name));
+        }
+        errors.into_result()?;
+        Ok(Self {
+            ident: item.ident.to_string(),
+            variants,
+        })
+    }
+
+    /// Get the name set with `#[vcl_enum(rename = "...")]`, if any
+    fn get_rename(attrs: &[syn::Attribute]) -> ProcResult<Option<String>> {
+        let mut rename = None;
+        for attr in attrs.iter().filter(|a| a.path().is_ident("vcl_enum")) {
+            let Meta::List(list) = &attr.meta else {
+                Err(error(attr, "Expected #[vcl_enum(rename = \"...\")]"))?
+            };
+            let args = NestedMeta::parse_meta_list(list.tokens.clone())?;
+            let [NestedMeta::Meta(Meta::NameValue(nv))] = args.as_slice() else {
+                Err(error(attr, "Expected #[vcl_enum(rename = \"...\")]"))?
+            };
+            if !nv.path.is_ident("rename") {
+                Err(error(&nv.path, "Unknown parameter, expected `rename`"))?;
+            }
+            let Expr::Lit(ExprLit {
+                lit: Lit::Str(name),
+                ..
+            }) = &nv.value
+            else {
+                Err(error(&nv.value, "`rename` must be a string"))?
+            };
+            if rename.is_some() {
+                Err(error(attr, "A variant can only be renamed once"))?;
+            }
+            rename = Some(name.value());
+        }
+        Ok(rename)
+    }
+
+    /// The names of the VCL values, in declaration order
+    pub fn vcl_names(&self) -> impl Iterator<Item = &str> {
+        self.variants.iter().map(|(_, name)| name.as_str())
+    }
+
+    /// Implement `VclEnum` for the enum
+    pub fn gen_impl(&self) -> TokenStream {
+        let ident = self.ident.to_ident();
+        let names = self.vcl_names();
+        let idents: Vec<_> = self.variants.iter().map(|(v, _)| v.to_ident()).collect();
+        let indexes = 0..idents.len();
+        let indexes2 = indexes.clone();
+        quote! {
+            impl ::varnish::vcl::VclEnum for #ident {
+                const VARIANTS: &'static [&'static str] = &[#(#names),*];
+
+                fn from_index(index: usize) -> Option<Self> {
+                    match index {
+                        #(#indexes => Some(Self::#idents),)*
+                        _ => None,
+                    }
+                }
+
+                fn index(self) -> usize {
+                    match self {
+                        #(Self::#idents => #indexes2,)*
+                    }
+                }
+            }
+        }
+    }
+}
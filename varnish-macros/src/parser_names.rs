@@ -0,0 +1,126 @@
+//! Check the names exposed to VCL: the VMOD, its functions, objects, methods, and arguments.
+//! A name that VCC rejects would only be reported by `varnishd` when the VCL is loaded, far from
+//! its declaration, so they are checked here and reported at their span.
+
+use std::collections::HashMap;
+
+use syn::spanned::Spanned;
+
+use crate::errors::Errors;
+
+/// The VCL keywords, which the VMOD name would collide with in `import`
+const VCL_RESERVED: &[&str] = &[
+    "acl",
+    "backend",
+    "ban",
+    "call",
+    "else",
+    "elseif",
+    "elsif",
+    "false",
+    "hash_data",
+    "if",
+    "import",
+    "include",
+    "new",
+    "probe",
+    "regsub",
+    "regsuball",
+    "return",
+    "set",
+    "sub",
+    "synthetic",
+    "true",
+    "unset",
+    "vcl",
+];
+
+/// The C keywords, the arguments being struct fields in the C code generated by VCC
+const C_RESERVED: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+    "union", "unsigned", "void", "volatile", "while",
+];
+
+/// What a name is given to, used in the error messages
+#[derive(Debug, Clone, Copy)]
+pub enum NameKind {
+    Vmod,
+    Function,
+    Object,
+    Method,
+    Argument,
+}
+
+impl NameKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Vmod => "VMOD",
+            Self::Function => "function",
+            Self::Object => "object",
+            Self::Method => "method",
+            Self::Argument => "argument",
+        }
+    }
+
+    fn reserved(self) -> &'static [&'static str] {
+        match self {
+            Self::Vmod => VCL_RESERVED,
+            Self::Argument => C_RESERVED,
+            // always used after a dot, e.g. `kv.set()`, so VCL keywords are fine
+            Self::Function | Self::Object | Self::Method => &[],
+        }
+    }
+}
+
+/// Report `name` if VCC would not accept it
+pub fn check_name<T: Spanned>(spanned: &T, name: &str, kind: NameKind, errors: &mut Errors) {
+    let what = kind.as_str();
+    let name = name.strip_prefix("r#").unwrap_or(name);
+    // the arguments are mostly passed by position, so they may be named `_unused`
+    let first = name.trim_start_matches(|c| c == '_' && matches!(kind, NameKind::Argument));
+    if !first.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        errors.add(
+            spanned,
+            &format!("`{name}` is not a valid VCL {what} name. It must start with an ASCII letter, followed by ASCII letters, digits, or `_`"),
+        );
+    } else if kind
+        .reserved()
+        .iter()
+        .any(|word| word.eq_ignore_ascii_case(name))
+    {
+        errors.add(
+            spanned,
+            &format!("`{name}` is reserved and cannot be used as a VCL {what} name"),
+        );
+    }
+}
+
+/// The names declared in the same VCL scope, which must not only differ by case
+#[derive(Debug, Default)]
+pub struct NameScope {
+    names: HashMap<String, String>,
+}
+
+impl NameScope {
+    /// Report `name` if another name of the scope only differs by case
+    pub fn insert<T: Spanned>(&mut self, spanned: &T, name: &str, errors: &mut Errors) {
+        let name = name.strip_prefix("r#").unwrap_or(name);
+        if let Some(other) = self
+            .names
+            .insert(name.to_ascii_lowercase(), name.to_string())
+        {
+            if other != name {
+                errors.add(
+                    spanned,
+                    &format!(
+                        "`{name}` and `{other}` only differ by case, VCL cannot tell them apart"
+                    ),
+                );
+            }
+        }
+    }
+}
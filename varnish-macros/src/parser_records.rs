@@ -0,0 +1,276 @@
+//! Functions returning a `#[derive(VclRecord)]` struct cannot be exported as is because VCL has no
+//! structured types. Instead, each one becomes an object whose `call()` method runs the function
+//! and keeps the result for the current task, and whose other methods read the record fields.
+//! The object's struct and `impl` block are generated here, and appended to the user's module.
+
+use proc_macro2::Ident;
+use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::token::Comma;
+use syn::{
+    parse_quote, Attribute, Fields, FnArg, Item, ItemFn, ItemStruct, Pat, Path, ReturnType,
+    Signature, Type, Visibility,
+};
+
+use crate::errors::{error, Errors};
+use crate::model::{FuncInfo, FuncType, ObjInfo, OutputTy, ParamType, SharedTypes};
+use crate::parser_utils::{
+    as_ref_mut_ty, as_ref_ty, as_result_type, as_simple_ty, parse_doc_str, remove_attr,
+};
+use crate::ProcResult;
+
+/// A struct tagged with `#[derive(VclRecord)]`
+pub struct Record {
+    ident: Ident,
+    fields: Vec<RecordField>,
+}
+
+struct RecordField {
+    ident: Ident,
+    ty: Type,
+    docs: Vec<Attribute>,
+}
+
+/// Check if the struct derives `VclRecord`
+pub fn is_vcl_record(item: &ItemStruct) -> bool {
+    item.attrs.iter().any(|attr| {
+        attr.path().is_ident("derive")
+            && attr
+                .parse_args_with(Punctuated::<Path, Comma>::parse_terminated)
+                .is_ok_and(|paths| {
+                    paths
+                        .iter()
+                        .any(|p| p.segments.last().is_some_and(|s| s.ident == "VclRecord"))
+                })
+    })
+}
+
+impl Record {
+    /// Parse all the records declared in the module
+    pub fn collect(content: &[Item], errors: &mut Errors) -> Vec<Self> {
+        content
+            .iter()
+            .filter_map(|item| match item {
+                Item::Struct(item) if is_vcl_record(item) => errors.on_err(Self::parse(item)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn parse(item: &ItemStruct) -> ProcResult<Self> {
+        let mut errors = Errors::new();
+        if !item.generics.params.is_empty() {
+            errors.add(&item.generics, "Generic records are not supported");
+        }
+        let Fields::Named(named) = &item.fields else {
+            Err(error(item, "Records must have named fields"))?
+        };
+        let mut fields = Vec::new();
+        for field in &named.named {
+            let ident = field.ident.clone().expect("named field");
+            if ident == "new" || ident == "call" {
+                errors.add(&ident, "Record fields cannot be named `new` or `call`");
+            }
+            match OutputTy::parse(&field.ty, FuncType::Method) {
+                Ok(OutputTy::VclType(_)) => {
+                    errors.add(&field.ty, "Record fields cannot use raw VCL_* types");
+                }
+                Ok(_) => {}
+                Err(e) => errors.combine(e),
+            }
+            fields.push(RecordField {
+                ident,
+                ty: field.ty.clone(),
+                docs: field
+                    .attrs
+                    .iter()
+                    .filter(|a| a.path().is_ident("doc"))
+                    .cloned()
+                    .collect(),
+            });
+        }
+        errors.into_result()?;
+        Ok(Self {
+            ident: item.ident.clone(),
+            fields,
+        })
+    }
+
+    /// Find the record returned by this function, if any, either directly or as `Result<Record, _>`
+    pub fn find<'a>(records: &'a [Self], sig: &Signature) -> Option<&'a Self> {
+        let ReturnType::Type(_, ty) = &sig.output else {
+            return None;
+        };
+        let ty = as_result_type(ty).unwrap_or(ty);
+        let ident = as_simple_ty(ty)?;
+        records.iter().find(|r| r.ident == *ident)
+    }
+}
+
+impl ObjInfo {
+    /// Model the object replacing a function that returns a record, and generate its code
+    #[expect(clippy::too_many_lines)]
+    pub fn parse_record_fn(
+        fn_item: &mut ItemFn,
+        record: &Record,
+        shared_types: &mut SharedTypes,
+    ) -> ProcResult<(Self, Vec<Item>)> {
+        if let Some(attr) = remove_attr(&mut fn_item.attrs, "event") {
+            Err(error(&attr, "Event functions cannot return a record"))?;
+        }
+        let public: Visibility = parse_quote! { pub };
+        let sig = &fn_item.sig;
+        let fn_ident = sig.ident.clone();
+        let n_inputs = sig.inputs.len();
+
+        let mut ctor_sig: Signature = parse_quote! { fn new(#[vcl_name] __vcl_name: &str) -> Self };
+        let constructor =
+            FuncInfo::parse(shared_types, &mut ctor_sig, &public, &mut Vec::new(), true)?;
+
+        // Use the user's context if there is one, it will also be needed to store the record
+        let user_ctx = sig.inputs.iter().find_map(|arg| match arg {
+            FnArg::Typed(pat_ty) => as_ref_ty(&pat_ty.ty)
+                .or_else(|| as_ref_mut_ty(&pat_ty.ty))
+                .and_then(as_simple_ty)
+                .filter(|ident| *ident == "Ctx")
+                .and_then(|_| match pat_ty.pat.as_ref() {
+                    Pat::Ident(ident) => Some(ident.ident.clone()),
+                    _ => None,
+                }),
+            FnArg::Receiver(_) => None,
+        });
+        let mut call_sig: Signature = parse_quote! { fn call(&self) -> Result<(), VclError> };
+        call_sig.unsafety = sig.unsafety;
+        call_sig.asyncness = sig.asyncness;
+        if user_ctx.is_none() {
+            call_sig.inputs.push(parse_quote! { __ctx: &Ctx });
+        }
+        call_sig.inputs.extend(sig.inputs.iter().cloned());
+        let doc = format!("Run `{fn_ident}()`, and keep its result for the rest of the task.");
+        let mut call_attrs = vec![parse_quote! { #[doc = #doc] }];
+        let call = FuncInfo::parse(
+            shared_types,
+            &mut call_sig,
+            &fn_item.vis,
+            &mut call_attrs,
+            true,
+        )?;
+        if call.args.iter().any(|arg| {
+            matches!(
+                arg.ty,
+                ParamType::Workspace { .. } | ParamType::ContextView { .. }
+            )
+        }) {
+            Err(error(
+                &fn_item.sig,
+                "Functions returning a record cannot use a Workspace or a context view param, use `Ctx` instead",
+            ))?;
+        }
+
+        // The special attributes were removed while parsing, the user function must lose them too
+        let user_args = call_sig
+            .inputs
+            .iter()
+            .skip(call_sig.inputs.len() - n_inputs);
+        for (arg, parsed) in fn_item.sig.inputs.iter_mut().zip(user_args) {
+            if let (FnArg::Typed(arg), FnArg::Typed(parsed)) = (arg, parsed) {
+                arg.attrs.clone_from(&parsed.attrs);
+            }
+        }
+        let sig = &fn_item.sig;
+        let call_vars = sig
+            .inputs
+            .iter()
+            .zip(&call.args[call.args.len() - n_inputs..]);
+        let call_vars: Vec<_> = call_vars
+            .filter_map(|(arg, info)| {
+                let FnArg::Typed(pat_ty) = arg else {
+                    return None;
+                };
+                let Pat::Ident(ident) = pat_ty.pat.as_ref() else {
+                    return None;
+                };
+                let ident = &ident.ident;
+                Some(if let ParamType::Context { is_mut: true } = info.ty {
+                    quote! { &mut *#ident }
+                } else {
+                    quote! { #ident }
+                })
+            })
+            .collect();
+        let inputs = &sig.inputs;
+        let (ctx_decl, ctx_ident) = match &user_ctx {
+            Some(ident) => (quote! {}, quote! { #ident }),
+            None => (quote! { __ctx: &::varnish::vcl::Ctx, }, quote! { __ctx }),
+        };
+        let try_op = as_result_type(match &sig.output {
+            ReturnType::Type(_, ty) => ty,
+            ReturnType::Default => unreachable!("records are always returned"),
+        })
+        .map(|_| quote! { ? });
+
+        let mut funcs = vec![call];
+        let mut accessors = Vec::new();
+        for field in &record.fields {
+            let RecordField { ident, ty, docs } = field;
+            let mut sig: Signature =
+                parse_quote! { fn #ident(&self, __ctx: &Ctx) -> Result<#ty, VclError> };
+            funcs.push(FuncInfo::parse(
+                shared_types,
+                &mut sig,
+                &public,
+                &mut docs.clone(),
+                true,
+            )?);
+            accessors.push(quote! {
+                #(#docs)*
+                pub fn #ident(
+                    &self,
+                    __ctx: &::varnish::vcl::Ctx,
+                ) -> ::std::result::Result<#ty, ::varnish::vcl::VclError> {
+                    Ok(::std::clone::Clone::clone(&self.__slot.get(__ctx)?.#ident))
+                }
+            });
+        }
+
+        let record_ident = &record.ident;
+        let docs = fn_item.attrs.iter().filter(|a| a.path().is_ident("doc"));
+        let obj_struct: Item = parse_quote! {
+            #(#docs)*
+            #[allow(non_camel_case_types)]
+            pub struct #fn_ident {
+                __slot: ::varnish::vcl::RecordSlot<#record_ident>,
+            }
+        };
+        let obj_impl: Item = parse_quote! {
+            impl #fn_ident {
+                pub fn new(__vcl_name: &str) -> Self {
+                    Self {
+                        __slot: ::varnish::vcl::RecordSlot::new(__vcl_name),
+                    }
+                }
+
+                pub fn call(
+                    &self,
+                    #ctx_decl
+                    #inputs
+                ) -> ::std::result::Result<(), ::varnish::vcl::VclError> {
+                    let __record = #fn_ident(#(#call_vars),*) #try_op;
+                    self.__slot.store(#ctx_ident, __record)
+                }
+
+                #(#accessors)*
+            }
+        };
+
+        let obj = Self {
+            ident: fn_ident.to_string(),
+            ty: None,
+            docs: parse_doc_str(&fn_item.attrs),
+            constructor,
+            destructor: FuncInfo::destructor(),
+            funcs,
+        };
+        Ok((obj, vec![obj_struct, obj_impl]))
+    }
+}
@@ -0,0 +1,129 @@
+//! Structs tagged with `#[derive(Stats)]` get one set of methods per `AtomicU64` field, e.g.
+//! `hits_inc()` and `hits_get()`, so that the memory ordering of the statistics is chosen here
+//! once instead of at every `fetch_add()` of the vmod code.
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{Attribute, Fields, ItemStruct, Meta, Type};
+
+use crate::errors::{error, Errors};
+use crate::ProcResult;
+
+/// How the value of a field may change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StatKind {
+    /// Only goes up
+    Counter,
+    /// Goes up and down, or is set
+    Gauge,
+}
+
+/// Implement the methods of each field of the struct
+pub fn gen_stats(item: &ItemStruct) -> ProcResult<TokenStream> {
+    let Fields::Named(fields) = &item.fields else {
+        Err(error(&item.ident, "Stats structs must have named fields"))?
+    };
+    let mut errors = Errors::new();
+    let mut methods = Vec::new();
+    for field in &fields.named {
+        let ident = field.ident.as_ref().unwrap();
+        if !is_atomic_u64(&field.ty) {
+            errors.add(&field.ty, "Stats fields must be `AtomicU64`");
+            continue;
+        }
+        let kind = match get_kind(&field.attrs) {
+            Ok(kind) => kind,
+            Err(e) => {
+                errors.combine(e);
+                continue;
+            }
+        };
+        let name = ident.to_string();
+        let inc = format_ident!("{ident}_inc");
+        let add = format_ident!("{ident}_add");
+        let get = format_ident!("{ident}_get");
+        let inc_doc = format!("Add 1 to `{name}`");
+        let add_doc = format!("Add `n` to `{name}`");
+        let get_doc = format!("The current value of `{name}`");
+        methods.push(quote! {
+            #[doc = #inc_doc]
+            #[inline]
+            pub fn #inc(&self) {
+                self.#ident.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+            }
+            #[doc = #add_doc]
+            #[inline]
+            pub fn #add(&self, n: u64) {
+                self.#ident.fetch_add(n, ::std::sync::atomic::Ordering::Relaxed);
+            }
+            #[doc = #get_doc]
+            #[inline]
+            pub fn #get(&self) -> u64 {
+                self.#ident.load(::std::sync::atomic::Ordering::Relaxed)
+            }
+        });
+        if kind == StatKind::Gauge {
+            let dec = format_ident!("{ident}_dec");
+            let sub = format_ident!("{ident}_sub");
+            let set = format_ident!("{ident}_set");
+            let dec_doc = format!("Remove 1 from `{name}`");
+            let sub_doc = format!("Remove `n` from `{name}`");
+            let set_doc = format!("Set `{name}` to `value`");
+            methods.push(quote! {
+                #[doc = #dec_doc]
+                #[inline]
+                pub fn #dec(&self) {
+                    self.#ident.fetch_sub(1, ::std::sync::atomic::Ordering::Relaxed);
+                }
+                #[doc = #sub_doc]
+                #[inline]
+                pub fn #sub(&self, n: u64) {
+                    self.#ident.fetch_sub(n, ::std::sync::atomic::Ordering::Relaxed);
+                }
+                #[doc = #set_doc]
+                #[inline]
+                pub fn #set(&self, value: u64) {
+                    self.#ident.store(value, ::std::sync::atomic::Ordering::Relaxed);
+                }
+            });
+        }
+    }
+    errors.into_result()?;
+
+    let ident = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        // a vmod rarely needs every method of every field
+        #[allow(dead_code)]
+        impl #impl_generics #ident #ty_generics #where_clause {
+            #(#methods)*
+        }
+    })
+}
+
+fn is_atomic_u64(ty: &Type) -> bool {
+    matches!(ty, Type::Path(path) if path.qself.is_none()
+        && path.path.segments.last().is_some_and(|s| s.ident == "AtomicU64" && s.arguments.is_empty()))
+}
+
+/// Get the kind set with `#[stats(counter)]` or `#[stats(gauge)]`, a counter by default
+fn get_kind(attrs: &[Attribute]) -> ProcResult<StatKind> {
+    let mut kind = None;
+    for attr in attrs.iter().filter(|a| a.path().is_ident("stats")) {
+        let Meta::List(list) = &attr.meta else {
+            Err(error(attr, "Expected #[stats(counter)] or #[stats(gauge)]"))?
+        };
+        let value = match list.parse_args::<syn::Ident>() {
+            Ok(v) if v == "counter" => StatKind::Counter,
+            Ok(v) if v == "gauge" => StatKind::Gauge,
+            _ => Err(error(attr, "Expected #[stats(counter)] or #[stats(gauge)]"))?,
+        };
+        if kind.replace(value).is_some() {
+            Err(error(
+                attr,
+                "A field can only have one #[stats(...)] attribute",
+            ))?;
+        }
+    }
+    Ok(kind.unwrap_or(StatKind::Counter))
+}
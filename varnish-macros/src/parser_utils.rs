@@ -5,7 +5,10 @@ use syn::Lit::Str;
 use syn::Meta::NameValue;
 use syn::PathArguments::AngleBracketed;
 use syn::Type::{Path, Reference};
-use syn::{Attribute, ExprLit, GenericArgument, MetaNameValue, PathSegment, Type, TypePath};
+use syn::{
+    parse_quote, Attribute, ExprLit, GenericArgument, ImplItem, Item, ItemMod, MetaNameValue,
+    PathSegment, Type, TypePath, Visibility,
+};
 
 use crate::errors::error;
 use crate::model::{FuncInfo, ObjInfo};
@@ -45,6 +48,36 @@ pub fn remove_attr(attrs: &mut Vec<Attribute>, name: &str) -> Option<Attribute>
         .map(|idx| attrs.swap_remove(idx))
 }
 
+/// Add `#[inline]` to the public functions and methods of the module, so that the compiler can
+/// inline them into the generated wrappers, unless they already have an `inline` or `cold`
+/// attribute.
+pub fn add_inline_attrs(item_mod: &mut ItemMod) {
+    let add = |vis: &Visibility, attrs: &mut Vec<Attribute>| {
+        let is_marked = attrs
+            .iter()
+            .any(|attr| attr.path().is_ident("inline") || attr.path().is_ident("cold"));
+        if matches!(vis, Visibility::Public(_)) && !is_marked {
+            attrs.push(parse_quote! { #[inline] });
+        }
+    };
+    let Some((_, content)) = &mut item_mod.content else {
+        return;
+    };
+    for item in content {
+        match item {
+            Item::Fn(fn_item) => add(&fn_item.vis, &mut fn_item.attrs),
+            Item::Impl(impl_item) => {
+                for item in &mut impl_item.items {
+                    if let ImplItem::Fn(fn_item) = item {
+                        add(&fn_item.vis, &mut fn_item.attrs);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 /// Try to get the inner types of the `Result<Ok, Err>` type, or return None if it's not a `Result<Ok, Err>`.
 pub fn as_result_type(ty: &Type) -> Option<&Type> {
     if let Path(type_path) = ty {
@@ -99,6 +132,27 @@ pub fn as_one_gen_arg<'a>(ty: &'a Type, name: &'static str) -> Option<&'a Generi
     None
 }
 
+/// Check if the type is `Cow<str>`, possibly with a lifetime like `Cow<'_, str>`
+pub fn is_cow_str(ty: &Type) -> bool {
+    let Path(type_path) = ty else {
+        return false;
+    };
+    let Some(PathSegment {
+        ident,
+        arguments: AngleBracketed(args),
+    }) = type_path.path.segments.last()
+    else {
+        return false;
+    };
+    let mut args = args.args.iter();
+    if matches!(args.clone().next(), Some(GenericArgument::Lifetime(_))) {
+        args.next();
+    }
+    ident == "Cow"
+        && matches!(args.next(), Some(GenericArgument::Type(ty)) if as_simple_ty(ty).is_some_and(|i| i == "str"))
+        && args.next().is_none()
+}
+
 /// Try to get the inner type of the `&T` reference, or return None if it's not a `&T` reference.
 pub fn as_ref_ty(ty: &Type) -> Option<&Type> {
     if let Reference(rf) = ty {
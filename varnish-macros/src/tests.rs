@@ -8,9 +8,12 @@ use regex::Regex;
 use syn::ItemMod;
 
 use crate::gen_docs::gen_doc_content;
-use crate::generator::render_model;
-use crate::parser::tokens_to_model;
+use crate::gen_header::gen_header_content;
+use crate::generator::{render_model, render_part};
+use crate::model::VmodInfo;
+use crate::parser::{parse_params, part_to_model, tokens_to_model, tokens_to_model_with_parts};
 use crate::parser_utils::remove_attr;
+use crate::vcc_compat::compare;
 
 /// Read content of the ../../varnish/tests/pass directory that should also pass full compilation tests,
 /// parse them and create snapshots of the model and the generated code.
@@ -24,6 +27,246 @@ fn parse_pass_ffi_tests() {
     run_parse_tests("../varnish/tests/pass_ffi/*.rs");
 }
 
+#[test]
+fn vcc_compat() {
+    let mut item_mod: ItemMod = syn::parse_quote! {
+        mod kv {
+            use varnish::vcl::Ctx;
+            pub fn hello(name: &str, times: Option<i64>) -> String { String::new() }
+            pub fn count(ctx: &mut Ctx, #[default(1)] step: i64) {}
+            impl store {
+                pub fn new(cap: Option<i64>, #[vcl_name] name: &str) -> Self { Self }
+                pub fn get(&self, key: &str) -> Option<String> { None }
+            }
+        }
+    };
+    let info = tokens_to_model(quote! {}, &mut item_mod).unwrap_or_else(|e| panic!("{e}"));
+
+    let vcc = r#"
+$Module kv 3 "Key/value store"
+
+$Function STRING hello(STRING name, [INT times])
+
+Say hello.
+
+$Function VOID count(PRIV_TASK, INT step = 1)
+
+$Object store([INT cap])
+
+$Method STRING .get(STRING key)
+"#;
+    assert_eq!(compare(&info, vcc), Vec::<String>::new());
+
+    let vcc = r"
+$Function STRING hello(STRING name, INT times)
+$Function VOID count(
+    REAL step)
+$Event on_event
+$Object store([INT cap])
+";
+    assert_eq!(
+        compare(&info, vcc),
+        [
+            "function `hello`: argument `times` must be required, not optional",
+            "function `count`: argument `step` must be REAL, not INT",
+            "function `count`: argument `step` must not have a default value",
+            "event `on_event` is declared but not implemented",
+            "method `store.get` is not declared",
+        ]
+    );
+}
+
+#[test]
+fn parts() {
+    let part: ItemMod = syn::parse_quote! {
+        mod part {
+            use super::Counter;
+            pub fn concat(a: &str, #[default("-")] sep: &str, b: &str) -> String { String::new() }
+            impl Counter {
+                pub fn new(#[shared_per_vcl] vcl: &mut Option<Box<Shared>>) -> Self { Self }
+                pub fn incr(&self, #[shared_per_task] tsk: &mut Option<Box<Shared>>) -> i64 { 0 }
+            }
+        }
+    };
+    let mut cleaned = part.clone();
+    let info = part_to_model(&mut cleaned).unwrap_or_else(|e| panic!("{e}"));
+    let code = render_part(cleaned, &info, &quote! { #part });
+    with_settings!({ snapshot_path => snapshot_path(), omit_expression => true, prepend_module_to_snapshot => false }, {
+        with_settings!({ snapshot_suffix => "code" }, {
+            assert_snapshot!("parts_part", prettyplease::unparse(&syn::parse_file(&code.to_string()).unwrap()));
+        });
+    });
+
+    let item_mod: ItemMod = syn::parse_quote! {
+        mod main {
+            pub fn hello() {}
+        }
+    };
+    let args = quote! { parts(super::counter::part) };
+    let info = tokens_to_model_with_parts(
+        parse_params(args.clone()).unwrap_or_else(|e| panic!("{e}")),
+        &mut item_mod.clone(),
+        &mut [part.clone()],
+    );
+    let info = info.unwrap_or_else(|e| panic!("{e}"));
+    assert_eq!(info.parts.len(), 1);
+    test_model("parts", &info, item_mod);
+
+    // Two modules exporting the same name, or disagreeing on a shared type
+    let item_mod: ItemMod = syn::parse_quote! {
+        mod main {
+            pub fn concat(#[shared_per_task] tsk: &mut Option<Box<Other>>) {}
+        }
+    };
+    let err = tokens_to_model_with_parts(
+        parse_params(args).unwrap_or_else(|e| panic!("{e}")),
+        &mut item_mod.clone(),
+        &mut [part],
+    )
+    .map(|_| ())
+    .unwrap_err()
+    .into_compile_error()
+    .to_string();
+    assert!(
+        err.contains(
+            "`concat` is declared in both the `#[vmod]` module and `super::counter::part`"
+        ),
+        "{err}"
+    );
+    assert!(err.contains("#[shared_per_task] type is `Other` in the `#[vmod]` module, but `Shared` in `super::counter::part`"), "{err}");
+}
+
+#[test]
+fn global_hooks() {
+    let args = quote! { global_init = "crate::setup", global_fini = "super::teardown" };
+    let item_mod: ItemMod = syn::parse_quote! {
+        mod hooks {
+            pub fn hello() {}
+        }
+    };
+    test("global_hooks", args, item_mod);
+
+    let item_mod: ItemMod = syn::parse_quote! {
+        mod hooks_event {
+            #[event]
+            pub fn on_event(ctx: &Ctx, event: Event) {}
+        }
+    };
+    test(
+        "global_hooks_event",
+        quote! { global_fini = "teardown" },
+        item_mod,
+    );
+
+    let mut item_mod: ItemMod = syn::parse_quote! {
+        mod hooks_error {
+            pub fn hello() {}
+        }
+    };
+    let err = tokens_to_model(quote! { global_init = "not a path" }, &mut item_mod)
+        .map(|_| ())
+        .unwrap_err()
+        .into_compile_error()
+        .to_string();
+    assert!(err.contains("`not a path` is not a valid path"), "{err}");
+}
+
+#[test]
+fn config_arg() {
+    let item_mod: ItemMod = syn::parse_quote! {
+        mod config {
+            impl Pool {
+                pub fn new(#[arg(config)] cfg: PoolConfig, #[arg(config)] extra: Option<Extra>) -> Self {
+                    Self
+                }
+            }
+        }
+    };
+    test("config_arg", quote! {}, item_mod);
+
+    let mut item_mod: ItemMod = syn::parse_quote! {
+        mod config_error {
+            pub fn load(#[arg(config)] cfg: PoolConfig) {}
+        }
+    };
+    let err = tokens_to_model(quote! {}, &mut item_mod)
+        .map(|_| ())
+        .unwrap_err()
+        .into_compile_error()
+        .to_string();
+    assert!(err.contains("only allowed in object constructors"), "{err}");
+}
+
+#[test]
+fn vcl_names() {
+    let errors = |mut item_mod: ItemMod| {
+        tokens_to_model(quote! {}, &mut item_mod)
+            .map(|_| ())
+            .unwrap_err()
+            .into_compile_error()
+            .to_string()
+    };
+
+    let err = errors(syn::parse_quote! {
+        mod set {
+            pub fn hello() {}
+        }
+    });
+    assert!(err.contains("`set` is reserved"), "{err}");
+
+    let err = errors(syn::parse_quote! {
+        mod names {
+            pub fn _hidden() {}
+            pub fn add(default: i64, _unused: i64) {}
+            pub fn fooBar() {}
+            pub fn foobar() {}
+            pub fn pair(a: i64, A: i64) {}
+            impl Store {
+                pub fn new() -> Self {
+                    Self
+                }
+                pub fn get(&self) {}
+                pub fn Get(&self) {}
+            }
+        }
+    });
+    for msg in [
+        "`_hidden` is not a valid VCL function name",
+        "`default` is reserved and cannot be used as a VCL argument name",
+        "`foobar` and `fooBar` only differ by case",
+        "`A` and `a` only differ by case",
+        "`Get` and `get` only differ by case",
+    ] {
+        assert!(err.contains(msg), "{msg}: {err}");
+    }
+    assert!(!err.contains("_unused"), "{err}");
+}
+
+#[test]
+fn unsupported_types() {
+    let mut item_mod: ItemMod = syn::parse_quote! {
+        mod types {
+            pub fn int(v: u64) {}
+            pub fn list(v: Option<&[String]>) {}
+            pub fn map() -> HashMap<String, String> {}
+            pub fn other(v: Instant) {}
+        }
+    };
+    let err = tokens_to_model(quote! {}, &mut item_mod)
+        .map(|_| ())
+        .unwrap_err()
+        .into_compile_error()
+        .to_string();
+    for msg in [
+        "unsupported argument type `u64`. VCL integers are `i64`",
+        "unsupported argument type `Option<&[String]>`. VCL has no lists: take a `&str`",
+        "unsupported return type `HashMap<String, String>`. VCL has no maps: return a JSON `String`",
+        "unsupported argument type `Instant`. See the supported types",
+    ] {
+        assert!(err.contains(msg), "{msg}: {err}");
+    }
+}
+
 fn run_parse_tests(path: &str) {
     let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path);
     for file in glob::glob(path.to_str().unwrap()).unwrap() {
@@ -37,36 +280,45 @@ fn run_parse_tests(path: &str) {
             if let syn::Item::Mod(mut item) = item {
                 assert!(!has_vmod, "Multiple vmod modules found in file {file}");
                 has_vmod = true;
-                // FIXME: use this attribute as an arg for the test
-                let _arg = remove_attr(&mut item.attrs, "vmod").unwrap();
+                let args = match remove_attr(&mut item.attrs, "vmod").unwrap().meta {
+                    syn::Meta::List(list) => list.tokens,
+                    _ => quote! {},
+                };
                 let name = format!(
                     "{}_{}",
                     filepath.file_stem().unwrap().to_string_lossy(),
                     item.ident
                 );
-                test(&name, quote! {}, item);
-                // FIXME: pass proper attribute info
-                // test(&name, quote! { #arg }, item);
+                test(&name, args, item);
             }
         }
         assert!(has_vmod, "No vmod modules found in file {file}");
     }
 }
 
-fn test(name: &str, args: TokenStream, mut item_mod: ItemMod) {
+fn snapshot_path() -> String {
     let version = if cfg!(varnishsys_6) { "_v6" } else { "" };
-    let snapshot_path = format!("../../varnish/snapshots{version}");
-    with_settings!({ snapshot_path => snapshot_path, omit_expression => true, prepend_module_to_snapshot => false }, {
+    format!("../../varnish/snapshots{version}")
+}
+
+fn test(name: &str, args: TokenStream, mut item_mod: ItemMod) {
+    with_settings!({ snapshot_path => snapshot_path(), omit_expression => true, prepend_module_to_snapshot => false }, {
         let Ok(info) = tokens_to_model(args, &mut item_mod).map_err(|err| {
             // On error, save the error output as a snapshot and return early.
             let err = err.into_compile_error();
             with_settings!({ snapshot_suffix => "error" }, { assert_snapshot!(name, err) });
         }) else { return };
+        test_model(name, &info, item_mod);
+    });
+}
 
+fn test_model(name: &str, info: &VmodInfo, item_mod: ItemMod) {
+    with_settings!({ snapshot_path => snapshot_path(), omit_expression => true, prepend_module_to_snapshot => false }, {
         with_settings!({ snapshot_suffix => "model" }, { assert_snapshot!(name, format!("{info:#?}")) });
-        with_settings!({ snapshot_suffix => "docs" }, { assert_snapshot!(name, gen_doc_content(&info)) });
+        with_settings!({ snapshot_suffix => "docs" }, { assert_snapshot!(name, gen_doc_content(info)) });
+        with_settings!({ snapshot_suffix => "header" }, { assert_snapshot!(name, gen_header_content(info)) });
 
-        let file = render_model(item_mod, &info).to_string();
+        let file = render_model(item_mod, info).to_string();
         let parsed = match syn::parse_file(&file) {
             Ok(v) => v,
             Err(e) => {
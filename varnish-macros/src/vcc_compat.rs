@@ -0,0 +1,329 @@
+//! Check the VMOD against a legacy `vmod.vcc` file, set with `#[vmod(vcc = "vmod.vcc")]`.
+//!
+//! When porting a C VMOD, the `.vcc` file stays the source of truth: every function, event,
+//! object and method it declares must exist in the Rust module with the same return type and
+//! the same arguments (names, types and optionality), and the Rust module must not expose more.
+//! Only what is visible from VCL is compared, `PRIV_*` arguments are ignored.
+
+use std::fs;
+use std::path::Path;
+
+use syn::ItemMod;
+
+use crate::errors::Errors;
+use crate::model::{FuncInfo, FuncType, ParamKind, ParamType, VmodInfo};
+use crate::ProcResult;
+
+/// A function-like declaration, as seen from VCL
+#[derive(Debug, PartialEq)]
+struct Decl {
+    /// e.g. "function `foo`" or "method `obj.bar`"
+    what: String,
+    /// `None` for events and constructors
+    ret: Option<String>,
+    args: Vec<Arg>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Arg {
+    ty: String,
+    name: String,
+    optional: bool,
+    has_default: bool,
+}
+
+/// Compare the model with the `.vcc` file set in the `#[vmod]` attribute, if any
+pub fn check_vcc(info: &VmodInfo, item_mod: &ItemMod) -> ProcResult<()> {
+    let Some(ref vcc_file) = info.params.vcc else {
+        return Ok(());
+    };
+    let mut errors = Errors::new();
+    let Ok(dir) = std::env::var("CARGO_MANIFEST_DIR") else {
+        errors.add(
+            &item_mod.ident,
+            "Unable to get the CARGO_MANIFEST_DIR env var to read the `vcc` file",
+        );
+        return Err(errors);
+    };
+    let path = Path::new(&dir).join(vcc_file);
+    let src = match fs::read_to_string(&path) {
+        Ok(v) => v,
+        Err(e) => {
+            errors.add(
+                &item_mod.ident,
+                &format!("Unable to read {}: {e}", path.display()),
+            );
+            return Err(errors);
+        }
+    };
+    for msg in compare(info, &src) {
+        errors.add(&item_mod.ident, &format!("{vcc_file}: {msg}"));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// List the differences between the model and the content of a `.vcc` file
+pub fn compare(info: &VmodInfo, vcc: &str) -> Vec<String> {
+    let mut diffs = Vec::new();
+    let expected = match parse_vcc(vcc) {
+        Ok(v) => v,
+        Err(e) => return vec![e],
+    };
+    let actual = model_decls(info);
+
+    for exp in &expected {
+        let Some(act) = actual.iter().find(|d| d.what == exp.what) else {
+            diffs.push(format!("{} is declared but not implemented", exp.what));
+            continue;
+        };
+        if exp.ret != act.ret {
+            diffs.push(format!(
+                "{} must return {}, not {}",
+                exp.what,
+                exp.ret.as_deref().unwrap_or("VOID"),
+                act.ret.as_deref().unwrap_or("VOID")
+            ));
+        }
+        if exp.args.len() != act.args.len() {
+            diffs.push(format!(
+                "{} must have {} argument(s), not {}",
+                exp.what,
+                exp.args.len(),
+                act.args.len()
+            ));
+            continue;
+        }
+        for (e, a) in exp.args.iter().zip(&act.args) {
+            if e.name != a.name {
+                diffs.push(format!(
+                    "{}: argument `{}` is named `{}` in the Rust code",
+                    exp.what, e.name, a.name
+                ));
+            }
+            if e.ty != a.ty {
+                diffs.push(format!(
+                    "{}: argument `{}` must be {}, not {}",
+                    exp.what, e.name, e.ty, a.ty
+                ));
+            }
+            if e.optional != a.optional {
+                let (must, not) = if e.optional {
+                    ("optional", "required")
+                } else {
+                    ("required", "optional")
+                };
+                diffs.push(format!(
+                    "{}: argument `{}` must be {must}, not {not}",
+                    exp.what, e.name
+                ));
+            }
+            if e.has_default != a.has_default {
+                let has = if e.has_default {
+                    "needs"
+                } else {
+                    "must not have"
+                };
+                diffs.push(format!(
+                    "{}: argument `{}` {has} a default value",
+                    exp.what, e.name
+                ));
+            }
+        }
+    }
+    for act in &actual {
+        if !expected.iter().any(|d| d.what == act.what) {
+            diffs.push(format!("{} is not declared", act.what));
+        }
+    }
+    diffs
+}
+
+/// Build the VCL-visible declarations of the model
+fn model_decls(info: &VmodInfo) -> Vec<Decl> {
+    let mut decls = Vec::new();
+    for func in info.all_funcs() {
+        match func.func_type {
+            FuncType::Event => decls.push(Decl {
+                what: format!("event `{}`", func.ident),
+                ret: None,
+                args: Vec::new(),
+            }),
+            _ => decls.push(model_decl(format!("function `{}`", func.ident), func)),
+        }
+    }
+    for obj in info.all_objects() {
+        let mut ctor = model_decl(format!("object `{}`", obj.ident), &obj.constructor);
+        ctor.ret = None;
+        decls.push(ctor);
+        for method in &obj.funcs {
+            let what = format!("method `{}.{}`", obj.ident, method.ident);
+            decls.push(model_decl(what, method));
+        }
+    }
+    decls
+}
+
+fn model_decl(what: String, func: &FuncInfo) -> Decl {
+    let ret = func.output_ty.to_vcc_type();
+    Decl {
+        what,
+        ret: (ret != "VOID").then_some(ret),
+        args: func
+            .args
+            .iter()
+            .filter_map(|arg| match &arg.ty {
+                ParamType::Value(pi) => Some(Arg {
+                    ty: pi.to_vcc_type().to_string(),
+                    name: arg.ident.clone(),
+                    optional: matches!(pi.kind, ParamKind::Optional),
+                    has_default: pi.default.is_some(),
+                }),
+                _ => None,
+            })
+            .collect(),
+    }
+}
+
+/// Parse the declarations of a `.vcc` file, ignoring the documentation and the other stanzas
+fn parse_vcc(vcc: &str) -> Result<Vec<Decl>, String> {
+    let mut decls = Vec::new();
+    let mut object: Option<String> = None;
+    let mut lines = vcc.lines();
+    while let Some(line) = lines.next() {
+        let Some(line) = line.strip_prefix('$') else {
+            continue;
+        };
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        // a declaration may span several lines, until its parentheses are balanced
+        let mut decl = rest.trim().to_string();
+        while decl.matches('(').count() > decl.matches(')').count() {
+            let Some(next) = lines.next() else {
+                return Err(format!("unterminated declaration `${keyword} {decl}`"));
+            };
+            decl.push(' ');
+            decl.push_str(next.trim());
+        }
+        match keyword {
+            "Event" => decls.push(Decl {
+                what: format!("event `{decl}`"),
+                ret: None,
+                args: Vec::new(),
+            }),
+            "Function" => {
+                let (ret, proto) = split_ret(&decl)?;
+                let (name, args) = parse_proto(proto)?;
+                decls.push(Decl {
+                    what: format!("function `{name}`"),
+                    ret,
+                    args,
+                });
+            }
+            "Object" => {
+                let (name, args) = parse_proto(&decl)?;
+                decls.push(Decl {
+                    what: format!("object `{name}`"),
+                    ret: None,
+                    args,
+                });
+                object = Some(name);
+            }
+            "Method" => {
+                let Some(ref obj) = object else {
+                    return Err(format!("`$Method {decl}` is not preceded by an `$Object`"));
+                };
+                let (ret, proto) = split_ret(&decl)?;
+                let Some(proto) = proto.strip_prefix('.') else {
+                    return Err(format!("method name must start with a dot: `{proto}`"));
+                };
+                let (name, args) = parse_proto(proto)?;
+                decls.push(Decl {
+                    what: format!("method `{obj}.{name}`"),
+                    ret,
+                    args,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(decls)
+}
+
+/// Split `TYPE name(...)` into the return type and the prototype
+fn split_ret(decl: &str) -> Result<(Option<String>, &str), String> {
+    let (ret, proto) = match decl.split_once('{') {
+        // ENUM return types contain spaces
+        Some((ty, rest)) if ty.trim() == "ENUM" => {
+            let (_, proto) = rest
+                .split_once('}')
+                .ok_or_else(|| format!("unterminated ENUM in `{decl}`"))?;
+            ("ENUM", proto)
+        }
+        _ => decl
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("missing return type in `{decl}`"))?,
+    };
+    let ret = (ret != "VOID").then(|| ret.to_string());
+    Ok((ret, proto.trim()))
+}
+
+/// Parse `name(args)` into the name and the list of VCL-visible arguments
+fn parse_proto(proto: &str) -> Result<(String, Vec<Arg>), String> {
+    let (name, args) = proto
+        .split_once('(')
+        .and_then(|(name, args)| Some((name.trim(), args.trim().strip_suffix(')')?)))
+        .ok_or_else(|| format!("invalid declaration `{proto}`"))?;
+    let mut result = Vec::new();
+    for arg in split_args(args) {
+        let (optional, arg) = match arg.strip_prefix('[') {
+            Some(arg) => (true, arg.strip_suffix(']').unwrap_or(arg).trim()),
+            None => (false, arg),
+        };
+        let (arg, has_default) = match arg.split_once('=') {
+            Some((arg, _)) => (arg.trim(), true),
+            None => (arg, false),
+        };
+        let (ty, name) = match arg.rsplit_once('}') {
+            Some((_, name)) if arg.starts_with("ENUM") => ("ENUM", name.trim()),
+            _ => arg.split_once(char::is_whitespace).unwrap_or((arg, "")),
+        };
+        if ty.starts_with("PRIV_") {
+            continue;
+        }
+        result.push(Arg {
+            ty: ty.to_string(),
+            name: name.trim().to_string(),
+            optional,
+            has_default,
+        });
+    }
+    Ok((name.to_string(), result))
+}
+
+/// Split the arguments on commas, except inside braces and quotes
+fn split_args(args: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut depth = 0_usize;
+    let mut quoted = false;
+    let mut start = 0;
+    for (idx, c) in args.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '{' | '[' if !quoted => depth += 1,
+            '}' | ']' if !quoted => depth = depth.saturating_sub(1),
+            ',' if !quoted && depth == 0 => {
+                result.push(args[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = args[start..].trim();
+    if !last.is_empty() {
+        result.push(last);
+    }
+    result
+}
@@ -2,7 +2,7 @@ use std::ffi::c_void;
 use std::ptr;
 
 use crate::ffi::{vmod_data, vmod_priv};
-use crate::vcl::PerVclState;
+use crate::vcl::leaks;
 
 /// SAFETY: ensured by Varnish itself
 unsafe impl Sync for vmod_data {}
@@ -16,6 +16,7 @@ unsafe fn get_owned_bbox<T>(priv_: &mut *mut c_void) -> Option<Box<T>> {
     if obj.is_null() {
         None
     } else {
+        leaks::untrack(obj.cast::<T>());
         Some(Box::from_raw(obj.cast::<T>()))
     }
 }
@@ -33,16 +34,6 @@ impl vmod_priv {
         get_owned_bbox(&mut self.priv_)
     }
 
-    #[expect(clippy::unnecessary_box_returns)]
-    pub unsafe fn take_per_vcl<T>(&mut self) -> Box<PerVclState<T>> {
-        if let Some(v) = self.take::<PerVclState<T>>() {
-            v
-        } else {
-            let o = PerVclState::<T>::default();
-            Box::new(o)
-        }
-    }
-
     /// Use the object as a reference, without taking ownership.
     ///
     /// SAFETY:
@@ -53,6 +44,16 @@ impl vmod_priv {
     pub unsafe fn get_ref<T>(&self) -> Option<&T> {
         self.priv_.cast::<T>().as_ref()
     }
+
+    /// Use the object in place as an `Option<Box<T>>`, without taking it out of the `vmod_priv`.
+    /// Once done, `adopt()` must be called with the address the object had before.
+    ///
+    /// SAFETY:
+    /// * `priv_` must reference a valid `T` object pointer or `NULL`
+    /// * relies on `Option<Box<T>>` having the same layout as a nullable pointer
+    pub unsafe fn as_box_mut<T>(&mut self) -> &mut Option<Box<T>> {
+        &mut *ptr::from_mut(&mut self.priv_).cast::<Option<Box<T>>>()
+    }
 }
 
 #[cfg(varnishsys_6)]
@@ -61,6 +62,7 @@ mod version_v6 {
 
     use super::get_owned_bbox;
     use crate::ffi::{vmod_priv, vmod_priv_free_f};
+    use crate::vcl::leaks::{self, VclOf};
     use crate::vcl::PerVclState;
 
     impl vmod_priv {
@@ -72,6 +74,40 @@ mod version_v6 {
             self.free = free_method;
         }
 
+        /// Finish using the object through `as_box_mut()`. If it was replaced, Varnish is told how
+        /// to free the new one, which normally only happens on the first use in a task.
+        ///
+        /// SAFETY: The type of `before` must match the type of the function pointers in `methods`.
+        pub unsafe fn adopt<T>(
+            &mut self,
+            ctx: &impl VclOf,
+            before: *const T,
+            free_method: vmod_priv_free_f,
+        ) {
+            let now = self.priv_.cast_const().cast::<T>();
+            if now != before {
+                leaks::untrack(before);
+                leaks::track(ctx, now);
+                self.free = free_method;
+            }
+        }
+
+        /// Get the per-VCL state in place, creating it on first use.
+        ///
+        /// Unlike `take()` followed by `put()`, the state is never removed from the `vmod_priv`,
+        /// so a reader never finds it missing, and an update is not lost if the caller panics.
+        ///
+        /// SAFETY: `priv_` must reference a valid `PerVclState<T>` object pointer or `NULL`
+        pub unsafe fn get_per_vcl_mut<T>(
+            &mut self,
+            free_method: vmod_priv_free_f,
+        ) -> &mut PerVclState<T> {
+            if self.priv_.is_null() {
+                self.put(Box::new(PerVclState::<T>::default()), free_method);
+            }
+            &mut *self.priv_.cast::<PerVclState<T>>()
+        }
+
         /// A Varnish callback function to free a `vmod_priv` object.
         /// Here we take the ownership and immediately drop the object of type `T`.
         /// Note that here we get `*priv_` directly, not the `*vmod_priv`
@@ -101,6 +137,7 @@ mod version_after_v6 {
     use super::get_owned_bbox;
     use crate::ffi::{vmod_priv, vmod_priv_methods, vrt_ctx};
     use crate::validate_vrt_ctx;
+    use crate::vcl::leaks::{self, VclOf};
     use crate::vcl::PerVclState;
 
     /// SAFETY: ensured by Varnish itself
@@ -115,6 +152,40 @@ mod version_after_v6 {
             self.methods = methods;
         }
 
+        /// Finish using the object through `as_box_mut()`. If it was replaced, Varnish is told how
+        /// to free the new one, which normally only happens on the first use in a task.
+        ///
+        /// SAFETY: The type of `before` must match the type of the function pointers in `methods`.
+        pub unsafe fn adopt<T>(
+            &mut self,
+            ctx: &impl VclOf,
+            before: *const T,
+            methods: &'static vmod_priv_methods,
+        ) {
+            let now = self.priv_.cast_const().cast::<T>();
+            if now != before {
+                leaks::untrack(before);
+                leaks::track(ctx, now);
+                self.methods = methods;
+            }
+        }
+
+        /// Get the per-VCL state in place, creating it on first use.
+        ///
+        /// Unlike `take()` followed by `put()`, the state is never removed from the `vmod_priv`,
+        /// so a reader never finds it missing, and an update is not lost if the caller panics.
+        ///
+        /// SAFETY: `priv_` must reference a valid `PerVclState<T>` object pointer or `NULL`
+        pub unsafe fn get_per_vcl_mut<T>(
+            &mut self,
+            methods: &'static vmod_priv_methods,
+        ) -> &mut PerVclState<T> {
+            if self.priv_.is_null() {
+                self.put(Box::new(PerVclState::<T>::default()), methods);
+            }
+            &mut *self.priv_.cast::<PerVclState<T>>()
+        }
+
         /// A Varnish callback function to free a `vmod_priv` object.
         /// Here we take the ownership and immediately drop the object of type `T`.
         /// Note that here we get `*priv_` directly, not the `*vmod_priv`
@@ -11,6 +11,7 @@ extern crate core;
     clippy::pedantic,
     clippy::ptr_offset_with_cast,
     clippy::too_many_arguments,
+    clippy::upper_case_acronyms,
     clippy::useless_transmute
 )]
 pub mod ffi {
@@ -0,0 +1,100 @@
+//! Explain why varnishd refuses to load a vmod built for another version of Varnish
+//!
+//! A Rust vmod reads the internal structures of Varnish, so it is bound to the exact version of
+//! Varnish whose headers it was built with, i.e. its `VMOD_ABI_Version`. When the versions differ,
+//! e.g. after upgrading the distribution package of Varnish but not the vmod, varnishd refuses
+//! the `import` with a terse "ABI mismatch". The code generated by `#[vmod]` checks the version of
+//! the running varnishd as soon as the library is loaded, and explains the mismatch along with
+//! the way to fix it.
+//!
+//! The mismatch is caught by the VCL compiler, which opens the library to read its description
+//! and rejects it before any VCL is loaded. No event of the vmod runs, so there is no [`Ctx`]
+//! to fail: the explanation is written to the standard error of the compiler, which varnishd
+//! captures and returns with the compilation errors. It shows in the output of `vcl.load` (or of
+//! the `-f` VCL at startup), right before the "ABI mismatch" of the compiler:
+//!
+//! ```text
+//! Message from VCC-compiler:
+//! Error: vmod_foo was built against Varnish 7.6.1 c3d5882..., but this varnishd is Varnish 7.5.0
+//! eef2526..., varnishd will refuse to import it (ABI mismatch). Rebuild the vmod with ...
+//! Incompatible VMOD foo
+//! ```
+//!
+//! [`Ctx`]: crate::vcl::Ctx
+
+use std::ffi::{c_char, c_void, CStr};
+use std::io::Write as _;
+
+extern "C" {
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+}
+
+#[cfg(target_os = "linux")]
+const RTLD_DEFAULT: *mut c_void = std::ptr::null_mut();
+#[cfg(not(target_os = "linux"))]
+const RTLD_DEFAULT: *mut c_void = (usize::MAX - 1) as *mut c_void;
+
+/// The version of the running varnishd, in the form of `VMOD_ABI_Version`, e.g.
+/// `Varnish 7.6.1 c3d5882003eb87e5e93dc09fb9513ca96db3ca3c`. `None` outside of varnishd, or with
+/// versions of Varnish that do not export `VCS_String()`.
+pub fn running_varnish_version() -> Option<String> {
+    let sym = unsafe { dlsym(RTLD_DEFAULT, c"VCS_String".as_ptr()) };
+    if sym.is_null() {
+        return None;
+    }
+    let vcs_string: unsafe extern "C" fn(*const c_char) -> *const c_char =
+        unsafe { std::mem::transmute(sym) };
+    let get = |which: &CStr| {
+        let value = unsafe { vcs_string(which.as_ptr()) };
+        (!value.is_null()).then(|| unsafe { CStr::from_ptr(value) }.to_string_lossy())
+    };
+    Some(format!("Varnish {} {}", get(c"P")?, get(c"R")?))
+}
+
+/// Explain the mismatch between the version of Varnish a vmod was `built` with, and the one
+/// `running` it, if any
+pub fn abi_mismatch(vmod: &str, built: &str, running: &str) -> Option<String> {
+    (built != running).then(|| {
+        format!(
+            "vmod_{vmod} was built against {built}, but this varnishd is {running}, varnishd will \
+            refuse to import it (ABI mismatch). Rebuild the vmod with the development files of the \
+            running Varnish installed, i.e. the varnish-dev or varnish-devel package of the same \
+            version, or run the Varnish it was built against."
+        )
+    })
+}
+
+/// Called when the library of the vmod is loaded, before the VCL compiler checks its ABI.
+/// Its standard error ends up in the compilation errors, see the [module documentation](self).
+#[doc(hidden)]
+pub fn check_abi_at_load(vmod: &CStr, built: &CStr) {
+    let Some(running) = running_varnish_version() else {
+        return;
+    };
+    if let Some(msg) = abi_mismatch(&vmod.to_string_lossy(), &built.to_string_lossy(), &running) {
+        // A single write, so that the message is not interleaved with the compiler's output
+        let _ = std::io::stderr().write_all(format!("Error: {msg}\n").as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abi_mismatch_test() {
+        let built = "Varnish 7.6.1 c3d5882003eb87e5e93dc09fb9513ca96db3ca3c";
+        assert_eq!(abi_mismatch("foo", built, built), None);
+        let msg = abi_mismatch(
+            "foo",
+            built,
+            "Varnish 7.5.0 eef25264e5ca5f96a77129308edb83ccf84cb1b1",
+        )
+        .unwrap();
+        assert!(msg.starts_with("vmod_foo was built against Varnish 7.6.1 c3d5882003eb"));
+        assert!(
+            msg.contains("this varnishd is Varnish 7.5.0 eef25264e5ca"),
+            "{msg}"
+        );
+    }
+}
@@ -66,18 +66,24 @@
 //!     let ptr = backend.vcl_ptr();
 //! }
 //! ```
-use std::ffi::{c_char, c_int, c_void, CString};
+use std::ffi::{c_char, c_int, c_void};
 use std::marker::PhantomData;
 use std::mem::size_of;
 use std::net::{SocketAddr, TcpStream};
 use std::os::unix::io::FromRawFd;
 use std::ptr;
 use std::ptr::{null, null_mut};
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::ffi::{VclEvent, VfpStatus, VCL_BACKEND, VCL_BOOL, VCL_IP, VCL_TIME};
 use crate::utils::get_backend;
-use crate::vcl::{Buffer, Ctx, IntoVCL, LogTag, VclError, VclResult, Workspace};
+#[cfg(feature = "chaos")]
+use crate::vcl::chaos::{self, Fault};
+use crate::vcl::{
+    intern, Buffer, Ctx, HttpHeaders, IntoVCL, LogTag, VclError, VclResult, Workspace,
+};
 use crate::{
     ffi, validate_director, validate_vdir, validate_vfp_ctx, validate_vfp_entry, validate_vrt_ctx,
 };
@@ -97,9 +103,7 @@ pub struct Backend<S: Serve<T>, T: Transfer> {
     bep: VCL_BACKEND,
     #[expect(dead_code)]
     methods: Box<ffi::vdi_methods>,
-    inner: Box<S>,
-    #[expect(dead_code)]
-    type_: CString,
+    inner: Box<DirectorPriv<S>>,
     phantom: PhantomData<T>,
 }
 
@@ -107,7 +111,12 @@ impl<S: Serve<T>, T: Transfer> Backend<S, T> {
     /// Access the inner type wrapped by [Backend]. Note that it isn't `mut` as other threads are
     /// likely to have access to it too.
     pub fn get_inner(&self) -> &S {
-        &self.inner
+        &self.inner.serve
+    }
+
+    /// The in-flight requests and latency of this backend, to be shared with a director
+    pub fn load(&self) -> &Arc<LoadTracker> {
+        &self.inner.load
     }
 
     /// Return the C pointer wrapped by the [`Backend`]. Conventionally used by the `.backend()`
@@ -120,10 +129,12 @@ impl<S: Serve<T>, T: Transfer> Backend<S, T> {
     /// calling the backend `name`. If the backend has a probe attached to it, set `has_probe` to
     /// true.
     pub fn new(ctx: &mut Ctx, name: &str, be: S, has_probe: bool) -> VclResult<Self> {
-        let mut inner = Box::new(be);
-        let type_: CString = CString::new(inner.get_type()).map_err(|e| e.to_string())?;
+        let mut inner = Box::new(DirectorPriv {
+            serve: be,
+            load: Arc::default(),
+        });
         let methods = Box::new(ffi::vdi_methods {
-            type_: type_.as_ptr(),
+            type_: intern(inner.serve.get_type())?.as_ptr(),
             magic: ffi::VDI_METHODS_MAGIC,
             destroy: None,
             event: Some(wrap_event::<S, T>),
@@ -142,7 +153,7 @@ impl<S: Serve<T>, T: Transfer> Backend<S, T> {
             ffi::VRT_AddDirector(
                 ctx.raw,
                 &*methods,
-                ptr::from_mut::<S>(&mut *inner).cast::<c_void>(),
+                ptr::from_mut::<DirectorPriv<S>>(&mut *inner).cast::<c_void>(),
                 c"%.*s".as_ptr(),
                 name.len(),
                 name.as_ptr().cast::<c_char>(),
@@ -154,7 +165,6 @@ impl<S: Serve<T>, T: Transfer> Backend<S, T> {
 
         Ok(Backend {
             bep,
-            type_,
             inner,
             methods,
             phantom: PhantomData,
@@ -162,6 +172,93 @@ impl<S: Serve<T>, T: Transfer> Backend<S, T> {
     }
 }
 
+/// What the private pointer of the director points to. `serve` must stay first, so that
+/// [`get_backend()`] can cast the pointer to `S`.
+#[derive(Debug)]
+#[repr(C)]
+struct DirectorPriv<S> {
+    serve: S,
+    load: Arc<LoadTracker>,
+}
+
+/// Return the private pointer of the director, with the load of the backend
+fn get_priv<S>(v: &ffi::director) -> &DirectorPriv<S> {
+    unsafe { v.priv_.cast::<DirectorPriv<S>>().as_ref().unwrap() }
+}
+
+/// Weight of the newest sample in [`LoadTracker::latency()`]
+const EWMA_ALPHA: f64 = 0.2;
+
+/// Per-backend accounting of the requests, kept up to date by [`Backend`]
+///
+/// A request is in flight from the moment `Serve::get_headers()` is called until
+/// `Serve::finish()` returns, or until `get_headers()` fails. Piped requests are in flight for
+/// the duration of `Serve::pipe()`. Directors written in Rust can use it to send requests to the
+/// least busy backend, or to the fastest one.
+#[derive(Debug, Default)]
+pub struct LoadTracker {
+    in_flight: AtomicUsize,
+    total: AtomicU64,
+    failures: AtomicU64,
+    /// The `f64` bits of the average time to headers, in seconds, `0` before the first sample
+    latency: AtomicU64,
+}
+
+impl LoadTracker {
+    /// The number of requests currently handled by the backend
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests sent to the backend so far
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests for which `Serve::get_headers()` failed
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// The exponentially weighted moving average of the time spent in `Serve::get_headers()`,
+    /// `None` until a request succeeded
+    pub fn latency(&self) -> Option<Duration> {
+        match f64::from_bits(self.latency.load(Ordering::Relaxed)) {
+            0.0 => None,
+            v => Some(Duration::from_secs_f64(v)),
+        }
+    }
+
+    pub(crate) fn start(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the outcome of `Serve::get_headers()`. A failed request is no longer in flight.
+    pub(crate) fn headers(&self, elapsed: Duration, success: bool) {
+        if !success {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            self.done();
+            return;
+        }
+        // keep the average non-zero, zero means no sample yet
+        let sample = elapsed.as_secs_f64().max(f64::MIN_POSITIVE);
+        let _ = self
+            .latency
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let avg = match f64::from_bits(bits) {
+                    0.0 => sample,
+                    avg => avg + EWMA_ALPHA * (sample - avg),
+                };
+                Some(avg.to_bits())
+            });
+    }
+
+    pub(crate) fn done(&self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 /// The trait to implement to "be" a backend
 ///
 /// `Serve` maps to the `vdi_methods` structure of the C api, but presented in a more
@@ -172,17 +269,18 @@ impl<S: Serve<T>, T: Transfer> Backend<S, T> {
 pub trait Serve<T: Transfer> {
     /// What kind of backend this is, for example, pick a descriptive name, possibly linked to the
     /// vmod which creates it. Pick an ASCII string, otherwise building the [`Backend`] via
-    /// [`Backend::new`] will fail.
+    /// [`Backend::new`] will fail. The string is [interned](crate::vcl::intern), all the backends
+    /// of the same type share it.
     fn get_type(&self) -> &str;
 
     /// If the VCL pick this backend (or a director ended up choosing it), this method gets called
     /// so that the `Serve` implementer can:
-    /// - inspect the request headers (`ctx.http_bereq`)
-    /// - fill the response headers (`ctx.http_beresp`)
+    /// - inspect the request headers (`ctx.bereq()`)
+    /// - fill the response headers (`ctx.beresp_mut()`)
     /// - possibly return a `Transfer` object that will generate the response body
     ///
     /// If this function returns a `Ok(_)` without having set the method and protocol of
-    /// `ctx.http_beresp`, we'll default to `HTTP/1.1 200 OK`
+    /// `ctx.beresp_mut()`, we'll default to `HTTP/1.1 200 OK`
     fn get_headers(&self, _ctx: &mut Ctx) -> Result<Option<T>, VclError>;
 
     /// Once a backend transaction is finished, the [`Backend`] has a chance to clean up, collect
@@ -269,6 +367,18 @@ pub trait Transfer {
     fn get_ip(&self) -> Result<Option<SocketAddr>, VclError> {
         Ok(None)
     }
+
+    /// Called once `.read()` returned `Ok(0)`, for bodies of unknown length (see [`Transfer::len`])
+    /// to provide HTTP trailers, such as a checksum or timing information computed while
+    /// streaming.
+    ///
+    /// The trailers are appended to `beresp` once the body is over, where the fetch processors
+    /// further down the pipeline can read them with [`FetchProcCtx::beresp()`](crate::vcl::FetchProcCtx::beresp), and logged as
+    /// `BerespHeader`. Varnish stores the object headers before the body is fetched, so they are
+    /// not delivered to clients.
+    fn trailers(&mut self) -> Vec<(String, String)> {
+        Vec::new()
+    }
 }
 
 impl Transfer for () {
@@ -298,10 +408,34 @@ unsafe extern "C" fn vfp_pull<T: Transfer>(
             // TODO: we should grow a VSL object
             // SAFETY: we assume ffi::VSLbt() will not store the pointer to the string's content
             let msg = ffi::txt::from_str(e.as_str().as_ref());
-            ffi::VSLbt(ctx.req.as_ref().unwrap().vsl, ffi::VslTag::Error, msg);
+            // the HTTP objects carry the log of the task, if there is one
+            let vsl = [ctx.req, ctx.resp]
+                .into_iter()
+                .find_map(|http| http.as_ref())
+                .map(|http| http.vsl);
+            if let Some(vsl) = vsl.filter(|vsl| !vsl.is_null()) {
+                ffi::VSLbt(vsl, ffi::VslTag::Error, msg);
+            }
             VfpStatus::Error
         }
         Ok(0) => {
+            if reader.len().is_none() {
+                let trailers = reader.trailers();
+                if !trailers.is_empty() {
+                    let Some(mut beresp) = HttpHeaders::from_ptr(ffi::VCL_HTTP(ctx.resp)) else {
+                        ffi::VFP_Error(ctx, c"no beresp to store the trailers".as_ptr());
+                        return VfpStatus::Error;
+                    };
+                    for (name, value) in trailers {
+                        if let Err(e) = beresp.set_header(&name, &value) {
+                            let e = e.as_str();
+                            let len = c_int::try_from(e.len()).unwrap_or(c_int::MAX);
+                            ffi::VFP_Error(ctx, c"trailer: %.*s".as_ptr(), len, e.as_ptr());
+                            return VfpStatus::Error;
+                        }
+                    }
+                }
+            }
             *len = 0;
             VfpStatus::End
         }
@@ -347,8 +481,11 @@ unsafe extern "C" fn wrap_pipe<S: Serve<T>, T: Transfer>(
     assert_ne!(fd, 0);
     let tcp_stream = TcpStream::from_raw_fd(fd);
 
-    let backend: &S = get_backend(validate_director(be));
-    sc_to_ptr(backend.pipe(&mut ctx, tcp_stream))
+    let backend = get_priv::<S>(validate_director(be));
+    backend.load.start();
+    let sc = backend.serve.pipe(&mut ctx, tcp_stream);
+    backend.load.done();
+    sc_to_ptr(sc)
 }
 
 unsafe extern "C" fn wrap_gethdrs<S: Serve<T>, T: Transfer>(
@@ -357,14 +494,29 @@ unsafe extern "C" fn wrap_gethdrs<S: Serve<T>, T: Transfer>(
 ) -> c_int {
     let mut ctx = Ctx::from_ptr(ctxp);
     let be = validate_director(be);
-    let backend: &S = get_backend(be);
+    let backend = get_priv::<S>(be);
     assert!(!be.vcl_name.is_null()); // FIXME: is this validation needed?
     validate_vdir(be); // FIXME: is this validation needed?
 
-    match backend.get_headers(&mut ctx) {
+    backend.load.start();
+    let start = Instant::now();
+    let res = gethdrs(&mut ctx, &backend.serve);
+    // on failure, wrap_finish() will not be called
+    backend.load.headers(start.elapsed(), res == 0);
+    res
+}
+
+unsafe fn gethdrs<S: Serve<T>, T: Transfer>(ctx: &mut Ctx, backend: &S) -> c_int {
+    #[cfg(feature = "chaos")]
+    if chaos::fails(Fault::Fetch) {
+        let typ = backend.get_type();
+        ctx.log(LogTag::FetchError, format!("{typ}: chaos: fetch failed"));
+        return 1;
+    }
+    match backend.get_headers(ctx) {
         Ok(res) => {
             // default to HTTP/1.1 200 if the backend didn't provide anything
-            let beresp = ctx.http_beresp.as_mut().unwrap();
+            let beresp = ctx.beresp_mut().unwrap();
             if beresp.status().is_none() {
                 beresp.set_status(200);
             }
@@ -494,7 +646,7 @@ unsafe extern "C" fn wrap_finish<S: Serve<T>, T: Transfer>(
     ctxp: *const ffi::vrt_ctx,
     be: VCL_BACKEND,
 ) {
-    let prev_backend: &S = get_backend(validate_director(be));
+    let prev_backend = get_priv::<S>(validate_director(be));
 
     // FIXME: shouldn't the ctx magic number be checked? If so, use validate_vrt_ctx()
     let ctx = ctxp.as_ref().unwrap();
@@ -509,7 +661,8 @@ unsafe extern "C" fn wrap_finish<S: Serve<T>, T: Transfer>(
     bo.htc = null_mut();
 
     // FIXME?: should _prev be set to NULL?
-    prev_backend.finish(&mut Ctx::from_ptr(ctx));
+    prev_backend.serve.finish(&mut Ctx::from_ptr(ctx));
+    prev_backend.load.done();
 }
 
 impl<S: Serve<T>, T: Transfer> Drop for Backend<S, T> {
@@ -570,3 +723,30 @@ fn sc_to_ptr(sc: StreamClose) -> ffi::stream_close_t {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_tracker() {
+        let load = LoadTracker::default();
+        assert_eq!(load.latency(), None);
+        load.start();
+        load.start();
+        load.start();
+        assert_eq!((load.in_flight(), load.total()), (3, 3));
+
+        load.headers(Duration::from_millis(100), true);
+        assert_eq!(load.latency(), Some(Duration::from_millis(100)));
+        load.headers(Duration::from_millis(200), true);
+        assert_eq!(load.latency(), Some(Duration::from_millis(120)));
+        load.headers(Duration::from_secs(10), false);
+        assert_eq!(load.latency(), Some(Duration::from_millis(120)));
+        assert_eq!((load.in_flight(), load.failures()), (2, 1));
+
+        load.done();
+        load.done();
+        assert_eq!((load.in_flight(), load.total()), (0, 3));
+    }
+}
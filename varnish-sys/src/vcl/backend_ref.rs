@@ -0,0 +1,128 @@
+//! A non-null handle to a backend or director received from VCL
+//!
+//! `BACKEND` arguments of vmod functions can be unset in VCL, e.g. `req.backend_hint` before it
+//! is assigned, or a director with no healthy member. The code generated by `#[vmod]` passes them
+//! as `Option<BackendRef>`, `None` standing for a `NULL` backend, so that a vmod never reads a
+//! null pointer by mistake.
+//!
+//! ``` rust,ignore
+//! #[varnish::vmod]
+//! mod example {
+//!     use varnish::vcl::{BackendRef, Ctx};
+//!
+//!     /// e.g. `example.describe(req.backend_hint)`
+//!     pub fn describe(ctx: &Ctx, be: Option<BackendRef>) -> String {
+//!         match be {
+//!             Some(be) => format!("{} is healthy: {}", be.name(), be.healthy(ctx).0),
+//!             None => "no backend".to_string(),
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Backends can also be found by name at runtime with [`Ctx::find_backend()`], e.g. to route
+//! requests from a configuration file, without keeping pointers taken in `vcl_init`. A director
+//! is asked which backend it picks with [`BackendRef::resolve()`].
+
+use std::ffi::CStr;
+use std::fmt;
+use std::ptr::NonNull;
+#[cfg(not(varnishsys_6))]
+use std::time::SystemTime;
+
+#[cfg(not(varnishsys_6))]
+use crate::ffi;
+use crate::ffi::{director, VCL_BACKEND};
+#[cfg(not(varnishsys_6))]
+use crate::vcl::Ctx;
+use crate::vcl::{IntoVCL, VclError, Workspace};
+
+/// A backend or a director, never `NULL`, see the [module documentation](self)
+///
+/// Like a [`VCL_BACKEND`], it is only valid while the VCL that created the backend is loaded.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BackendRef(NonNull<director>);
+
+impl BackendRef {
+    /// Wrap a `VCL_BACKEND`, `None` if it is `NULL`
+    pub fn new(be: VCL_BACKEND) -> Option<Self> {
+        NonNull::new(be.0.cast_mut()).map(Self)
+    }
+
+    /// The name of the backend, as declared in VCL, or as given to a dynamic backend
+    pub fn name(&self) -> &str {
+        let name = unsafe { self.0.as_ref().vcl_name };
+        if name.is_null() {
+            return "";
+        }
+        unsafe { CStr::from_ptr(name) }.to_str().unwrap_or("")
+    }
+
+    /// Whether the backend is healthy, and since when, see [`backend_health()`](crate::vcl::backend_health)
+    #[cfg(not(varnishsys_6))]
+    pub fn healthy(self, ctx: &Ctx) -> (bool, SystemTime) {
+        crate::vcl::backend_health(ctx, self.vcl_ptr())
+    }
+
+    /// The backend picked by this director for the current task, `None` if it has none to offer,
+    /// e.g. no healthy member. A plain backend resolves to itself.
+    #[cfg(not(varnishsys_6))]
+    pub fn resolve(self, ctx: &Ctx) -> Option<Self> {
+        Self::new(unsafe { ffi::VRT_DirectorResolve(ctx.raw, self.vcl_ptr()) })
+    }
+
+    /// The raw pointer, e.g. to return it to VCL or to call the C API
+    pub fn vcl_ptr(self) -> VCL_BACKEND {
+        VCL_BACKEND(self.0.as_ptr().cast_const())
+    }
+}
+
+#[cfg(not(varnishsys_6))]
+impl Ctx<'_> {
+    /// The backend or director declared as `name` in the active VCL, see [`BackendRef`]
+    pub fn find_backend(&self, name: &str) -> Option<BackendRef> {
+        crate::vcl::lookup_backend(self, name).and_then(BackendRef::new)
+    }
+}
+
+impl From<VCL_BACKEND> for Option<BackendRef> {
+    fn from(value: VCL_BACKEND) -> Self {
+        BackendRef::new(value)
+    }
+}
+
+impl IntoVCL<VCL_BACKEND> for BackendRef {
+    fn into_vcl(self, _: &mut Workspace) -> Result<VCL_BACKEND, VclError> {
+        Ok(self.vcl_ptr())
+    }
+}
+
+impl fmt::Debug for BackendRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BackendRef").field(&self.0).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn backend_ref() {
+        assert!(<Option<BackendRef>>::from(VCL_BACKEND(ptr::null())).is_none());
+
+        let mut dir = director {
+            vcl_name: c"origin".as_ptr().cast_mut(),
+            ..director::default()
+        };
+        let be = BackendRef::new(VCL_BACKEND(ptr::from_mut(&mut dir))).unwrap();
+        assert_eq!(be.name(), "origin");
+        assert!(ptr::eq(be.vcl_ptr().0, ptr::from_ref(&dir)));
+
+        let mut unnamed = director::default();
+        let be = BackendRef::new(VCL_BACKEND(ptr::from_mut(&mut unnamed))).unwrap();
+        assert_eq!(be.name(), "");
+    }
+}
@@ -0,0 +1,334 @@
+//! Build ban expressions that the ban lurker can process
+//!
+//! Varnish checks bans against the objects in cache either on lookup, or in the background with the
+//! ban lurker thread. The lurker has no request at hand, so it can only evaluate bans that solely
+//! use `obj.*` fields. Any ban mentioning `req.*` stays on the ban list until every object it
+//! could match has been looked up or has expired, which slows down every lookup as the list grows.
+//!
+//! [`BanBuilder`] can only express `obj.*` conditions, with operators matching the type of each
+//! field. Submit the ban with [`Ctx::ban_with()`]:
+//!
+//! ``` rust
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::{BanBuilder, Ctx, NumOp, StrOp, VclError};
+//!
+//! fn ban_images(ctx: &mut Ctx, host: &str) -> Result<(), VclError> {
+//!     let ban = BanBuilder::new()
+//!         .obj_http("x-host", StrOp::Eq, host)
+//!         .obj_http("x-url", StrOp::Match, "^/images/")
+//!         .obj_status(NumOp::Lt, 400);
+//!     ctx.ban_with(&ban)
+//! }
+//! ```
+//!
+//! The values are passed as they are: the ban parser has no escape sequences, so a value can't
+//! contain a double quote or a control character, and [`BanBuilder::build()`] rejects them.
+
+use std::ffi::{CStr, CString};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(varnishsys_6))]
+use std::sync::Mutex;
+#[cfg(not(varnishsys_6))]
+use std::time::Duration;
+
+use crate::ffi;
+use crate::ffi::VCL_STRING;
+use crate::vcl::{Ctx, VclError};
+#[cfg(not(varnishsys_6))]
+use crate::vcl::{VscField, VscSegment};
+
+/// Operators for string fields, i.e. `obj.http.*`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrOp {
+    /// `==`, exact match
+    Eq,
+    /// `!=`
+    Ne,
+    /// `~`, the value is a regular expression
+    Match,
+    /// `!~`, the value is a regular expression
+    NoMatch,
+}
+
+impl StrOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::Match => "~",
+            Self::NoMatch => "!~",
+        }
+    }
+}
+
+/// Operators for numeric fields, i.e. `obj.status` and the `obj.*` durations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumOp {
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+}
+
+impl NumOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Cond {
+    Http(String, StrOp, String),
+    Status(NumOp, u16),
+    #[cfg(not(varnishsys_6))]
+    Duration(&'static str, NumOp, Duration),
+}
+
+/// A ban expression made of `obj.*` conditions only, joined with `&&`
+///
+/// Header names and values are checked when the expression is built by [`BanBuilder::build()`].
+#[derive(Debug, Clone, Default)]
+pub struct BanBuilder {
+    conds: Vec<Cond>,
+}
+
+impl BanBuilder {
+    /// Create an empty ban, at least one condition must be added before submitting it
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a condition on the `obj.http.<name>` header
+    #[must_use]
+    pub fn obj_http(mut self, name: &str, op: StrOp, value: &str) -> Self {
+        self.conds
+            .push(Cond::Http(name.to_string(), op, value.to_string()));
+        self
+    }
+
+    /// Add a condition on `obj.status`
+    #[must_use]
+    pub fn obj_status(mut self, op: NumOp, status: u16) -> Self {
+        self.conds.push(Cond::Status(op, status));
+        self
+    }
+
+    /// Add a condition on `obj.ttl`
+    #[cfg(not(varnishsys_6))]
+    #[must_use]
+    pub fn obj_ttl(self, op: NumOp, value: Duration) -> Self {
+        self.duration("obj.ttl", op, value)
+    }
+
+    /// Add a condition on `obj.age`
+    #[cfg(not(varnishsys_6))]
+    #[must_use]
+    pub fn obj_age(self, op: NumOp, value: Duration) -> Self {
+        self.duration("obj.age", op, value)
+    }
+
+    /// Add a condition on `obj.grace`
+    #[cfg(not(varnishsys_6))]
+    #[must_use]
+    pub fn obj_grace(self, op: NumOp, value: Duration) -> Self {
+        self.duration("obj.grace", op, value)
+    }
+
+    /// Add a condition on `obj.keep`
+    #[cfg(not(varnishsys_6))]
+    #[must_use]
+    pub fn obj_keep(self, op: NumOp, value: Duration) -> Self {
+        self.duration("obj.keep", op, value)
+    }
+
+    #[cfg(not(varnishsys_6))]
+    fn duration(mut self, field: &'static str, op: NumOp, value: Duration) -> Self {
+        self.conds.push(Cond::Duration(field, op, value));
+        self
+    }
+
+    /// Build the ban expression, as accepted by `ban()` in VCL or `varnishadm ban`
+    pub fn build(&self) -> Result<String, VclError> {
+        if self.conds.is_empty() {
+            return Err(VclError::CStr(c"a ban needs at least one condition"));
+        }
+        let mut expr = String::new();
+        for cond in &self.conds {
+            if !expr.is_empty() {
+                expr.push_str(" && ");
+            }
+            match cond {
+                Cond::Http(name, op, value) => {
+                    if name.is_empty()
+                        || !name
+                            .bytes()
+                            .all(|b| b.is_ascii_graphic() && !b":\"\\".contains(&b))
+                    {
+                        return Err(VclError::String(format!(
+                            "invalid header name in ban: {name:?}"
+                        )));
+                    }
+                    if value.chars().any(|c| c == '"' || c.is_control()) {
+                        return Err(VclError::String(format!(
+                            "invalid value in ban, it has quotes or control characters: {value:?}"
+                        )));
+                    }
+                    let _ = write!(expr, "obj.http.{name} {} \"{value}\"", op.as_str());
+                }
+                Cond::Status(op, status) => {
+                    let _ = write!(expr, "obj.status {} {status}", op.as_str());
+                }
+                #[cfg(not(varnishsys_6))]
+                Cond::Duration(field, op, value) => {
+                    let _ = write!(expr, "{field} {} {}s", op.as_str(), value.as_secs_f64());
+                }
+            }
+        }
+        Ok(expr)
+    }
+}
+
+static BANS_SUBMITTED: AtomicU64 = AtomicU64::new(0);
+static BANS_REJECTED: AtomicU64 = AtomicU64::new(0);
+/// The `varnishstat` counters, see [`BanStats::publish()`]
+#[cfg(not(varnishsys_6))]
+static SEGMENT: Mutex<Option<VscSegment>> = Mutex::new(None);
+
+/// Counters of the bans submitted with [`Ctx::ban_with()`] by this vmod
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanStats {
+    /// Bans accepted by Varnish
+    pub submitted: u64,
+    /// Bans that failed to build, or were refused by Varnish
+    pub rejected: u64,
+}
+
+impl BanStats {
+    /// Read the current values of the counters
+    pub fn get() -> Self {
+        Self {
+            submitted: BANS_SUBMITTED.load(Ordering::Relaxed),
+            rejected: BANS_REJECTED.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Publish the counters to `varnishstat` as `vmod.<vmod>.ban.submitted` and `.rejected`,
+    /// e.g. when the vmod gets the [`Event::Load`](crate::vcl::Event::Load) event.
+    ///
+    /// The segment must be removed with [`BanStats::unpublish()`] before the vmod is unloaded.
+    /// Publishing it again replaces it.
+    #[cfg(not(varnishsys_6))]
+    pub fn publish(vmod: &str) -> Result<(), VclError> {
+        let mut segment = SEGMENT.lock().unwrap();
+        // the name must be free before it can be registered again
+        *segment = None;
+        let new = VscSegment::new(
+            vmod,
+            "ban",
+            "Bans submitted with Ctx::ban_with()",
+            vec![
+                VscField::counter("submitted", "Bans accepted by Varnish"),
+                VscField::counter("rejected", "Bans that failed to build, or were refused"),
+            ],
+        )?;
+        let stats = Self::get();
+        new.values()[0].store(stats.submitted, Ordering::Relaxed);
+        new.values()[1].store(stats.rejected, Ordering::Relaxed);
+        *segment = Some(new);
+        Ok(())
+    }
+
+    /// Remove the counters published by [`BanStats::publish()`], e.g. when the vmod gets the
+    /// [`Event::Discard`](crate::vcl::Event::Discard) event of its last VCL
+    #[cfg(not(varnishsys_6))]
+    pub fn unpublish() {
+        SEGMENT.lock().unwrap().take();
+    }
+
+    fn count(accepted: bool) {
+        let (counter, idx) = if accepted {
+            (&BANS_SUBMITTED, 0)
+        } else {
+            (&BANS_REJECTED, 1)
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        #[cfg(not(varnishsys_6))]
+        if let Some(segment) = &*SEGMENT.lock().unwrap() {
+            segment.values()[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        #[cfg(varnishsys_6)]
+        let _ = idx;
+    }
+}
+
+impl Ctx<'_> {
+    /// Submit a ban built with [`BanBuilder`], like `ban()` does in VCL
+    pub fn ban_with(&mut self, ban: &BanBuilder) -> Result<(), VclError> {
+        let res = self.submit_ban(ban);
+        BanStats::count(res.is_ok());
+        res
+    }
+
+    fn submit_ban(&mut self, ban: &BanBuilder) -> Result<(), VclError> {
+        let expr = CString::new(ban.build()?)
+            .map_err(|_| VclError::CStr(c"ban values cannot contain NULL bytes"))?;
+        let err = unsafe { ffi::VRT_ban_string(self.raw, VCL_STRING(expr.as_ptr())) };
+        match <Option<&CStr>>::from(err) {
+            None => Ok(()),
+            Some(err) => Err(VclError::String(err.to_string_lossy().into_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_ban() {
+        let ban = BanBuilder::new()
+            .obj_http("x-url", StrOp::Match, r"^/a\.b\\c")
+            .obj_status(NumOp::Ge, 200);
+        // the regex gets to PCRE as it is
+        assert_eq!(
+            ban.build().unwrap(),
+            r#"obj.http.x-url ~ "^/a\.b\\c" && obj.status >= 200"#
+        );
+        #[cfg(not(varnishsys_6))]
+        assert_eq!(
+            BanBuilder::new()
+                .obj_ttl(NumOp::Lt, Duration::from_millis(1500))
+                .build()
+                .unwrap(),
+            "obj.ttl < 1.5s"
+        );
+
+        assert!(BanBuilder::new().build().is_err());
+        assert!(BanBuilder::new()
+            .obj_http("x url", StrOp::Eq, "a")
+            .build()
+            .is_err());
+        for value in [r#"a"b"#, "a\nb", "a\tb"] {
+            assert!(BanBuilder::new()
+                .obj_http("x-url", StrOp::Eq, value)
+                .build()
+                .is_err());
+        }
+    }
+}
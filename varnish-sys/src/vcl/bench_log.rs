@@ -0,0 +1,55 @@
+//! Stand-in for the shared memory log, when running outside of Varnish with the `bench` feature
+//!
+//! [`Ctx::log()`](crate::vcl::Ctx::log), [`Ctx::fail()`](crate::vcl::Ctx::fail) and the header
+//! changes of [`HttpHeaders`](crate::vcl::HttpHeaders) copy their records into a per-thread
+//! buffer, the way `VSLb()` copies them into the VSL buffer of the task, so that benchmarks
+//! measure the cost of logging, and tests can check what was logged.
+
+use std::cell::RefCell;
+use std::mem::{size_of, transmute};
+
+use crate::vcl::LogTag;
+
+/// Size of the buffer, like the default `vsl_buffer` parameter. Once full, it starts over.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+thread_local! {
+    static BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(BUFFER_SIZE));
+}
+
+/// Append a record to the log of the current thread
+pub(crate) fn bench_vsl(tag: LogTag, msg: &[u8]) {
+    BUFFER.with_borrow_mut(|buf| {
+        let len = u32::try_from(msg.len()).unwrap_or(u32::MAX);
+        let msg = &msg[..len as usize];
+        if buf.len() + 2 * size_of::<u32>() + msg.len() > BUFFER_SIZE {
+            buf.clear();
+        }
+        buf.extend_from_slice(&(tag as u32).to_ne_bytes());
+        buf.extend_from_slice(&len.to_ne_bytes());
+        buf.extend_from_slice(msg);
+    });
+}
+
+/// Take the records logged by the current thread since the last call, at most the last 16KB.
+pub fn take_bench_log() -> Vec<(LogTag, String)> {
+    let buf = BUFFER.with_borrow_mut(std::mem::take);
+    let mut records = Vec::new();
+    let mut rest = buf.as_slice();
+    while let Some((tag, tail)) = rest.split_first_chunk::<4>() {
+        let Some((len, tail)) = tail.split_first_chunk::<4>() else {
+            break;
+        };
+        let len = u32::from_ne_bytes(*len) as usize;
+        // SAFETY: the tag was written from a `LogTag` by `bench_vsl()`
+        let tag = unsafe { transmute::<u32, LogTag>(u32::from_ne_bytes(*tag)) };
+        records.push((tag, String::from_utf8_lossy(&tail[..len]).into_owned()));
+        rest = &tail[len..];
+    }
+    records
+}
+
+/// Drop the records logged by the current thread
+pub fn clear_bench_log() {
+    BUFFER.with_borrow_mut(Vec::clear);
+}
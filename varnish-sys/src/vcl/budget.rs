@@ -0,0 +1,214 @@
+//! Stop long-running work before the task runs out of time
+//!
+//! A vmod function parsing a huge body or walking a large data set can take longer than the task
+//! is allowed to. [`Ctx::deadline()`] tells how much of the timeout of the task is left, and a
+//! [`Budget`] can be polled inside a loop to give up in time, logging why:
+//!
+//! ``` rust,no_run
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::{Budget, Ctx, VclError};
+//!
+//! fn count_lines(ctx: &mut Ctx, body: &[&[u8]]) -> Result<usize, VclError> {
+//!     let budget = Budget::from_ctx(ctx);
+//!     let mut lines = 0;
+//!     for chunk in body {
+//!         budget.check(ctx)?;
+//!         lines += chunk.iter().filter(|&&b| b == b'\n').count();
+//!     }
+//!     Ok(lines)
+//! }
+//! ```
+
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::ffi;
+use crate::vcl::{Ctx, LogTag, Timeout, VclError};
+
+impl Ctx<'_> {
+    /// The time left before the timeout of the current task, counted from its start
+    ///
+    /// On the backend side, this is `bereq.first_byte_timeout`, on the client side, the
+    /// `send_timeout` of the session, each falling back to its parameter when VCL did not set
+    /// it. `None` outside of a task, or on the client side with Varnish 6.
+    pub fn deadline(&self) -> Option<Duration> {
+        let timings = self.timings()?;
+        let (timeout, kind) = if self.raw.req.is_null() {
+            let bo = unsafe { self.raw.bo.as_ref()? };
+            (bo.first_byte_timeout, Timeout::BereqFirstByte)
+        } else {
+            send_timeout(self)?
+        };
+        // NaN until VCL sets it
+        let timeout = if timeout.0.is_nan() {
+            kind.param(self)
+        } else {
+            timeout
+        };
+        let timeout = Duration::try_from_secs_f64(timeout.0).ok()?;
+        Some(remaining(timings.first + timeout, SystemTime::now()))
+    }
+}
+
+#[cfg(not(varnishsys_6))]
+fn send_timeout(ctx: &Ctx) -> Option<(ffi::vtim_dur, Timeout)> {
+    let sp = unsafe { ctx.raw.sp.as_ref()? };
+    Some((sp.send_timeout, Timeout::SessSend))
+}
+
+#[cfg(varnishsys_6)]
+fn send_timeout(_ctx: &Ctx) -> Option<(ffi::vtim_dur, Timeout)> {
+    None
+}
+
+fn remaining(deadline: SystemTime, now: SystemTime) -> Duration {
+    deadline.duration_since(now).unwrap_or_default()
+}
+
+/// An amount of time that a vmod function allows itself, see the [module documentation](self)
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    start: Instant,
+    limit: Duration,
+}
+
+impl Budget {
+    /// A budget of `limit`, starting now
+    pub fn new(limit: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            limit,
+        }
+    }
+
+    /// A budget of the time left to the task, see [`Ctx::deadline()`], unlimited if unknown
+    pub fn from_ctx(ctx: &Ctx) -> Self {
+        Self::new(ctx.deadline().unwrap_or(Duration::MAX))
+    }
+
+    /// The time given to this budget
+    pub fn limit(&self) -> Duration {
+        self.limit
+    }
+
+    /// The time spent since the budget was created
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// The time left
+    pub fn remaining(&self) -> Duration {
+        self.limit.saturating_sub(self.elapsed())
+    }
+
+    /// The budget is spent
+    pub fn is_exceeded(&self) -> bool {
+        self.elapsed() >= self.limit
+    }
+
+    /// Fail if the budget is spent, after logging an `Error` record like
+    /// `Budget: exceeded elapsed=1.203 limit=1.000`, in seconds
+    pub fn check(&self, ctx: &mut Ctx) -> Result<(), VclError> {
+        let elapsed = self.elapsed();
+        if elapsed < self.limit {
+            return Ok(());
+        }
+        let msg = exceeded_msg(elapsed, self.limit);
+        ctx.log(LogTag::Error, &msg);
+        Err(VclError::String(msg))
+    }
+}
+
+fn exceeded_msg(elapsed: Duration, limit: Duration) -> String {
+    format!(
+        "Budget: exceeded elapsed={:.3} limit={:.3}",
+        elapsed.as_secs_f64(),
+        limit.as_secs_f64()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcl::TestCtx;
+
+    #[test]
+    fn budget() {
+        let budget = Budget::new(Duration::from_secs(3600));
+        assert!(!budget.is_exceeded());
+        assert!(budget.remaining() <= budget.limit());
+
+        let budget = Budget::new(Duration::ZERO);
+        assert!(budget.is_exceeded());
+        assert_eq!(budget.remaining(), Duration::ZERO);
+
+        assert_eq!(
+            exceeded_msg(Duration::from_millis(1203), Duration::from_secs(1)),
+            "Budget: exceeded elapsed=1.203 limit=1.000"
+        );
+    }
+
+    #[test]
+    fn deadline() {
+        let mut test_ctx = TestCtx::new(100);
+        let ctx = test_ctx.ctx();
+        assert!(ctx.deadline().is_none());
+        assert_eq!(Budget::from_ctx(&ctx).limit(), Duration::MAX);
+
+        let start = ffi::vtim_real(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+        );
+        let mut bo = ffi::busyobj {
+            t_first: start,
+            t_prev: start,
+            first_byte_timeout: ffi::vtim_dur(60.0),
+            ..ffi::busyobj::default()
+        };
+        ctx.raw.bo = std::ptr::from_mut(&mut bo);
+        let left = ctx.deadline().unwrap();
+        assert!(left > Duration::from_secs(50) && left <= Duration::from_secs(60));
+
+        // `bereq.first_byte_timeout` was not set by VCL
+        bo.first_byte_timeout = ffi::vtim_dur(f64::NAN);
+        ctx.raw.bo = std::ptr::from_mut(&mut bo);
+        let left = ctx.deadline().unwrap();
+        assert!(left > Duration::from_secs(50) && left <= Duration::from_secs(60));
+
+        let now = SystemTime::now();
+        assert_eq!(remaining(now, now + Duration::from_secs(1)), Duration::ZERO);
+    }
+
+    #[test]
+    #[cfg(not(varnishsys_6))]
+    fn client_deadline() {
+        let mut test_ctx = TestCtx::new(100);
+        let ctx = test_ctx.ctx();
+        let start = ffi::vtim_real(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+        );
+        let mut req = ffi::req {
+            t_first: start,
+            t_prev: start,
+            ..ffi::req::default()
+        };
+        // `sess.send_timeout` was not set by VCL
+        let mut sp = ffi::sess {
+            send_timeout: ffi::vtim_dur(f64::NAN),
+            ..ffi::sess::default()
+        };
+        ctx.raw.req = std::ptr::from_mut(&mut req);
+        ctx.raw.sp = std::ptr::from_mut(&mut sp);
+        let left = ctx.deadline().unwrap();
+        assert!(left > Duration::from_secs(590) && left <= Duration::from_secs(600));
+
+        sp.send_timeout = ffi::vtim_dur(30.0);
+        ctx.raw.sp = std::ptr::from_mut(&mut sp);
+        let left = ctx.deadline().unwrap();
+        assert!(left > Duration::from_secs(20) && left <= Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,194 @@
+//! Keep the beginning of a body for later inspection
+//!
+//! Sniffing the type of a body, detecting its language, or looking for an injection only needs
+//! its first bytes. A [`CaptureFetchProcessor`] copies them into the state of the task while the
+//! body goes through unchanged, and [`CaptureFetchProcessor::captured()`] gets them from a later
+//! step of the same task:
+//!
+//! ``` rust,no_run
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use std::ffi::CStr;
+//! use varnish::vcl::{Capture, CaptureFetchProcessor, Ctx, VclError};
+//!
+//! struct Head;
+//!
+//! impl Capture for Head {
+//!     fn name() -> &'static CStr {
+//!         c"head"
+//!     }
+//!
+//!     fn len() -> usize {
+//!         512
+//!     }
+//! }
+//!
+//! // in `vcl_recv`, before `std.cache_req_body()`
+//! fn capture(ctx: &mut Ctx) -> Result<bool, VclError> {
+//!     ctx.add_req_body_filter::<CaptureFetchProcessor<Head>>()
+//! }
+//!
+//! // in a later subroutine of the same request
+//! fn is_json(ctx: &Ctx) -> bool {
+//!     CaptureFetchProcessor::<Head>::captured(ctx)
+//!         .is_some_and(|head| head.bytes().trim_ascii_start().starts_with(b"{"))
+//! }
+//! ```
+//!
+//! On the backend side, the processor is registered with
+//! [`FetchFilters::register()`](crate::vcl::FetchFilters::register) and named in
+//! `beresp.filters`, the capture being available to the rest of the fetch.
+
+use std::ffi::{c_void, CStr};
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::ffi::{self, vmod_priv, vmod_priv_methods, VMOD_PRIV_METHODS_MAGIC};
+use crate::vcl::{Ctx, FetchProcCtx, FetchProcessor, InitResult, PullResult};
+
+/// Describes what a [`CaptureFetchProcessor`] keeps
+pub trait Capture {
+    /// The name of the processor, as used in `beresp.filters`.
+    fn name() -> &'static CStr;
+    /// How many bytes to keep from the start of the body
+    fn len() -> usize;
+}
+
+/// The beginning of a body, captured by a [`CaptureFetchProcessor`]
+#[derive(Debug)]
+pub struct Captured {
+    bytes: Mutex<Vec<u8>>,
+    limit: usize,
+    finished: AtomicBool,
+}
+
+impl Captured {
+    fn new(limit: usize) -> Self {
+        Self {
+            bytes: Mutex::new(Vec::new()),
+            limit,
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    /// The bytes captured so far, at most [`Capture::len()`]
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Whether the whole body went through the processor
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    /// Whether the bytes are the whole body, rather than its beginning
+    pub fn is_complete(&self) -> bool {
+        let len = self
+            .bytes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len();
+        self.is_finished() && len < self.limit
+    }
+
+    /// Keep the part of `buf` that fits, returns whether more is wanted
+    fn append(&self, buf: &[u8]) -> bool {
+        let mut bytes = self.bytes.lock().unwrap_or_else(PoisonError::into_inner);
+        let len = buf.len().min(self.limit - bytes.len());
+        bytes.extend_from_slice(&buf[..len]);
+        bytes.len() < self.limit
+    }
+}
+
+static CAPTURE_PRIV_METHODS: vmod_priv_methods = vmod_priv_methods {
+    magic: VMOD_PRIV_METHODS_MAGIC,
+    type_: c"Captured".as_ptr(),
+    fini: Some(vmod_priv::on_fini::<Arc<Captured>>),
+};
+
+/// A ready-made [`FetchProcessor`] passing the body through unchanged while keeping its first
+/// [`Capture::len()`] bytes, see the [module documentation](self)
+#[derive(Debug)]
+pub struct CaptureFetchProcessor<C: Capture> {
+    captured: Arc<Captured>,
+    /// The capture is full, the rest of the body is only passed through
+    full: bool,
+    _capture: PhantomData<C>,
+}
+
+impl<C: Capture> CaptureFetchProcessor<C> {
+    /// The capture of the current task, if this processor is part of one of its fetches
+    pub fn captured(ctx: &Ctx) -> Option<Arc<Captured>> {
+        let priv_ = unsafe {
+            ffi::VRT_priv_task_get(ctx.raw, C::name().as_ptr().cast::<c_void>()).as_ref()?
+        };
+        if !ptr::eq(priv_.methods, &raw const CAPTURE_PRIV_METHODS) {
+            return None;
+        }
+        unsafe { priv_.get_ref::<Arc<Captured>>() }.cloned()
+    }
+}
+
+impl<C: Capture> FetchProcessor for CaptureFetchProcessor<C> {
+    fn name() -> &'static CStr {
+        C::name()
+    }
+
+    fn new(vrt_ctx: &mut Ctx, _: &mut FetchProcCtx) -> InitResult<Self> {
+        let captured = Arc::new(Captured::new(C::len()));
+        let id = C::name().as_ptr().cast::<c_void>();
+        let Some(priv_) = (unsafe { ffi::VRT_priv_task(vrt_ctx.raw, id).as_mut() }) else {
+            return InitResult::Err("failed to allocate the capture task slot".into());
+        };
+        unsafe {
+            drop(priv_.take::<Arc<Captured>>());
+            priv_.put(Box::new(Arc::clone(&captured)), &CAPTURE_PRIV_METHODS);
+        }
+        InitResult::Ok(Self {
+            full: C::len() == 0,
+            captured,
+            _capture: PhantomData,
+        })
+    }
+
+    fn pull(&mut self, ctx: &mut FetchProcCtx, buf: &mut [u8]) -> PullResult {
+        let res = ctx.pull(buf);
+        let len = match res {
+            PullResult::Err => return res,
+            PullResult::Ok(len) | PullResult::End(len) => len,
+        };
+        if !self.full {
+            self.full = !self.captured.append(&buf[..len]);
+        }
+        if matches!(res, PullResult::End(_)) {
+            self.captured.finished.store(true, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captured() {
+        let captured = Captured::new(8);
+        assert!(captured.append(b"hello"));
+        assert!(!captured.append(b" world"));
+        assert!(!captured.append(b"!"));
+        assert_eq!(captured.bytes(), b"hello wo");
+        captured.finished.store(true, Ordering::Relaxed);
+        assert!(!captured.is_complete());
+
+        let captured = Captured::new(8);
+        assert!(captured.append(b"short"));
+        assert!(!captured.is_complete());
+        captured.finished.store(true, Ordering::Relaxed);
+        assert!(captured.is_complete());
+    }
+}
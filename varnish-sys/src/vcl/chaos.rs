@@ -0,0 +1,297 @@
+//! Make some operations fail on purpose, to exercise the error paths of a vmod
+//!
+//! The error paths of a vmod, e.g. an exhausted workspace, are rarely taken, so they are rarely
+//! tested. With the `chaos` feature, the workspace allocations, the fetches of the Rust backends,
+//! and the conversions of strings between VCL and Rust fail with a given probability. The
+//! failures are drawn from a seeded generator, so that a run can be replayed exactly.
+//!
+//! Under `varnishtest`, the injection is configured by the `VARNISH_RS_CHAOS` environment
+//! variable, read when the first decision is made:
+//!
+//! ``` vtc
+//! setenv VARNISH_RS_CHAOS "seed=42,ws=0.1,fetch=0.5,convert=0.01"
+//!
+//! varnish v1 -vcl+backend { ... } -start
+//! ```
+//!
+//! Each key of [`Fault`] is given the probability of a failure, between `0` and `1`, the missing
+//! ones never failing. In unit tests, [`with()`] applies a [`Chaos`] to the current thread only,
+//! leaving the tests running in parallel untouched:
+//!
+//! ```
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::chaos::{self, Chaos, Fault};
+//!
+//! let chaos = Chaos::new(7).rate(Fault::Workspace, 0.5);
+//! let failures = chaos::with(chaos, || (0..1000).filter(|_| chaos::fails(Fault::Workspace)).count());
+//! assert!((400..600).contains(&failures));
+//! ```
+//!
+//! The decisions of a thread are replayed exactly, but when several threads share the
+//! configuration from the environment, which one gets a given failure depends on scheduling.
+//!
+//! This is a development tool: the feature must not be enabled in production builds.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use crate::vcl::VclError;
+
+/// The environment variable holding the [`Chaos`] of the process
+pub const CHAOS_ENV: &str = "VARNISH_RS_CHAOS";
+
+/// The operations that can be made to fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fault {
+    /// Workspace allocations and reservations, as if the workspace was exhausted (`ws`)
+    Workspace,
+    /// The `get_headers()` calls of the Rust backends, as if the fetch failed (`fetch`)
+    Fetch,
+    /// The conversions of strings between VCL and Rust (`convert`)
+    Convert,
+}
+
+impl Fault {
+    const ALL: [Self; 3] = [Self::Workspace, Self::Fetch, Self::Convert];
+
+    /// The key of the fault in [`CHAOS_ENV`]
+    pub fn key(self) -> &'static str {
+        match self {
+            Self::Workspace => "ws",
+            Self::Fetch => "fetch",
+            Self::Convert => "convert",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.key())
+    }
+}
+
+/// The seed and the failure rates of the injection
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chaos {
+    seed: u64,
+    rates: [f64; 3],
+}
+
+impl Chaos {
+    /// No failures yet, with the given seed
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rates: [0.0; 3],
+        }
+    }
+
+    /// Make `fault` fail with the probability `rate`, clamped between `0` and `1`
+    #[must_use]
+    pub fn rate(mut self, fault: Fault, rate: f64) -> Self {
+        self.rates[fault.index()] = if rate.is_nan() {
+            0.0
+        } else {
+            rate.clamp(0.0, 1.0)
+        };
+        self
+    }
+
+    /// The seed of the generator
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The probability of a failure of `fault`
+    pub fn rate_of(&self, fault: Fault) -> f64 {
+        self.rates[fault.index()]
+    }
+}
+
+impl FromStr for Chaos {
+    type Err = VclError;
+
+    /// Parse a comma-separated list of `key=value`, `seed` being the seed and the other keys
+    /// the ones of [`Fault`]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Self::default();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let Some((key, value)) = item.split_once('=') else {
+                return Err(format!("chaos: `{item}` is not a key=value pair").into());
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if key == "seed" {
+                chaos.seed = value
+                    .parse()
+                    .map_err(|e| format!("chaos: invalid seed `{value}`: {e}"))?;
+                continue;
+            }
+            let Some(fault) = Fault::ALL.into_iter().find(|f| f.key() == key) else {
+                return Err(format!("chaos: unknown key `{key}`").into());
+            };
+            let rate: f64 = value
+                .parse()
+                .map_err(|e| format!("chaos: invalid rate `{value}` for `{key}`: {e}"))?;
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("chaos: the rate of `{key}` must be between 0 and 1").into());
+            }
+            chaos = chaos.rate(fault, rate);
+        }
+        Ok(chaos)
+    }
+}
+
+/// A [`Chaos`] with its own sequence of draws
+#[derive(Debug)]
+struct Injector {
+    chaos: Chaos,
+    draws: [AtomicU64; 3],
+}
+
+impl Injector {
+    fn new(chaos: Chaos) -> Self {
+        Self {
+            chaos,
+            draws: Default::default(),
+        }
+    }
+
+    #[expect(clippy::cast_precision_loss)]
+    fn fails(&self, fault: Fault) -> bool {
+        let rate = self.chaos.rate_of(fault);
+        if rate <= 0.0 {
+            return false;
+        }
+        let draw = self.draws[fault.index()].fetch_add(1, Ordering::Relaxed);
+        let hash = mix(self.chaos.seed ^ mix(fault.index() as u64 + 1) ^ mix(draw));
+        // the top 53 bits give a uniform value in [0, 1)
+        ((hash >> 11) as f64) / ((1_u64 << 53) as f64) < rate
+    }
+}
+
+/// The finalizer of splitmix64
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+static GLOBAL: OnceLock<Option<Injector>> = OnceLock::new();
+
+thread_local! {
+    static LOCAL: RefCell<Option<Rc<Injector>>> = const { RefCell::new(None) };
+}
+
+fn global() -> Option<&'static Injector> {
+    GLOBAL
+        .get_or_init(|| {
+            let value = std::env::var(CHAOS_ENV).ok()?;
+            match value.parse() {
+                Ok(chaos) => Some(Injector::new(chaos)),
+                Err(e) => {
+                    // there is no task to log to yet, varnishd reports stderr
+                    eprintln!("Error: {CHAOS_ENV}: {e}");
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Whether the next operation of the kind `fault` must fail, according to the [`Chaos`] of the
+/// current thread, or else the one of [`CHAOS_ENV`]
+///
+/// Vmods can call it to inject failures in their own operations.
+pub fn fails(fault: Fault) -> bool {
+    if let Some(local) = LOCAL.with(|local| local.borrow().clone()) {
+        return local.fails(fault);
+    }
+    global().is_some_and(|injector| injector.fails(fault))
+}
+
+/// Run `f` with the [`Chaos`] applied to the current thread, instead of the one of
+/// [`CHAOS_ENV`]
+pub fn with<R>(chaos: Chaos, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Rc<Injector>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            LOCAL.with(|local| *local.borrow_mut() = previous);
+        }
+    }
+
+    let injector = Rc::new(Injector::new(chaos));
+    let _restore = Restore(LOCAL.with(|local| local.borrow_mut().replace(injector)));
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+    use crate::ffi::VCL_STRING;
+    use crate::vcl::{IntoVCL, TestWS};
+
+    #[test]
+    fn parse() {
+        let chaos: Chaos = "seed=42, ws=0.1,fetch=1,".parse().unwrap();
+        assert_eq!(chaos.seed(), 42);
+        assert!((chaos.rate_of(Fault::Workspace) - 0.1).abs() < f64::EPSILON);
+        assert!((chaos.rate_of(Fault::Fetch) - 1.0).abs() < f64::EPSILON);
+        assert!(chaos.rate_of(Fault::Convert) == 0.0);
+        assert_eq!("".parse::<Chaos>().unwrap(), Chaos::default());
+
+        for invalid in ["ws", "ws=2", "ws=-0.1", "disk=0.1", "seed=-1", "convert=x"] {
+            assert!(invalid.parse::<Chaos>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let draw = |seed| {
+            with(Chaos::new(seed).rate(Fault::Convert, 0.3), || {
+                (0..64).map(|_| fails(Fault::Convert)).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(draw(1), draw(1));
+        assert_ne!(draw(1), draw(2));
+        assert!(draw(1).contains(&true));
+        assert!(draw(1).contains(&false));
+
+        with(Chaos::new(1).rate(Fault::Fetch, 1.0), || {
+            assert!(fails(Fault::Fetch));
+            assert!(!fails(Fault::Workspace));
+            // the innermost configuration wins, and the outer one is restored
+            with(Chaos::new(1), || assert!(!fails(Fault::Fetch)));
+            assert!(fails(Fault::Fetch));
+        });
+    }
+
+    #[test]
+    fn injected() {
+        let mut test_ws = TestWS::new(160);
+        let mut ws = test_ws.workspace();
+        with(Chaos::new(0).rate(Fault::Workspace, 1.0), || {
+            assert!(ws.allocate(NonZeroUsize::new(8).unwrap()).is_err());
+            assert!(ws.reserve().buf.is_empty());
+        });
+        with(Chaos::new(0).rate(Fault::Convert, 1.0), || {
+            assert!(IntoVCL::<VCL_STRING>::into_vcl("hello", &mut ws).is_err());
+            assert!(<&str>::try_from(VCL_STRING(c"hello".as_ptr())).is_err());
+        });
+        assert!(ws.allocate(NonZeroUsize::new(8).unwrap()).is_ok());
+        let s = IntoVCL::<VCL_STRING>::into_vcl("hello", &mut ws).unwrap();
+        assert_eq!(<&str>::try_from(s).unwrap(), "hello");
+    }
+}
@@ -0,0 +1,281 @@
+//! Read the client certificate checked by the TLS proxy in front of Varnish
+//!
+//! With mutual TLS, the client certificate is verified by the proxy terminating TLS, which passes
+//! what it found along to Varnish. [`ClientCert`] is the common form of that information,
+//! whichever way it came:
+//! - [`Ctx::client_cert()`] reads the PROXY protocol version 2 TLVs, e.g. from `hitch` or
+//!   `haproxy` with `send-proxy-v2-ssl-cn`. They carry the verification result and the common
+//!   name of the subject, but neither the alternative names nor the fingerprint.
+//! - [`ClientCert::from_xfcc()`] parses an `X-Forwarded-Client-Cert` header, as set by Envoy or
+//!   `haproxy`. It carries the subject, the alternative names, and the fingerprint, but not the
+//!   verification result, which the proxy is trusted to have enforced.
+//!
+//! Either way, the data is only as trustworthy as the proxy: the header must be removed from the
+//! requests that did not come through it.
+//!
+//! ```
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::{ClientCert, Ctx, VclError};
+//!
+//! // e.g. in a function called from `vcl_recv`
+//! fn is_allowed(ctx: &Ctx) -> Result<bool, VclError> {
+//!     let Some(cert) = ctx.client_cert() else {
+//!         return Ok(false);
+//!     };
+//!     cert.require_verified()?;
+//!     Ok(cert.common_name() == Some("billing"))
+//! }
+//!
+//! let header = r#"Hash=AB:CD:EF;Subject="CN=api,O=Example";DNS=api.example.com"#;
+//! let cert = ClientCert::from_xfcc(header)?;
+//! assert!(cert.matches_dns("API.example.com"));
+//! assert!(cert.fingerprint_matches("abcdef"));
+//! assert_eq!(cert.common_name(), Some("api"));
+//! # Ok::<(), VclError>(())
+//! ```
+
+use crate::vcl::protocol::{proxy_tlvs, tlvs};
+use crate::vcl::{Ctx, VclError, VclResult};
+
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+const PP2_CLIENT_CERT_SESS: u8 = 0x04;
+
+/// What the TLS proxy found when checking the client certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertVerify {
+    /// The certificate was verified
+    Success,
+    /// The certificate did not pass the verification
+    Failed,
+    /// The client did not present a certificate
+    NotPresented,
+    /// The proxy did not tell, e.g. with `X-Forwarded-Client-Cert`
+    Unknown,
+}
+
+/// A subject alternative name of a certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Uri(String),
+    Email(String),
+}
+
+/// A client certificate, see the [module documentation](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+    /// The distinguished name of the subject, e.g. `CN=api,O=Example`
+    pub subject: Option<String>,
+    pub san: Vec<SubjectAltName>,
+    /// The fingerprint, as lowercase hexadecimal digits
+    pub fingerprint: Option<String>,
+    pub verify: CertVerify,
+}
+
+impl ClientCert {
+    /// Parse the first element of an `X-Forwarded-Client-Cert` header, i.e. the one added by the
+    /// proxy facing the client. The `Hash` key is the fingerprint, `Subject` the subject, and
+    /// `DNS`, `URI`, and `Email` the alternative names. Other keys are ignored.
+    pub fn from_xfcc(value: &str) -> VclResult<Self> {
+        let element = split_quoted(value, ',').next().unwrap_or_default();
+        let mut cert = Self {
+            subject: None,
+            san: Vec::new(),
+            fingerprint: None,
+            verify: CertVerify::Unknown,
+        };
+        for pair in split_quoted(element, ';') {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(VclError::String(format!(
+                    "Invalid X-Forwarded-Client-Cert pair {pair:?}"
+                )));
+            };
+            let value = unquote(value.trim())?;
+            match key.trim().to_ascii_lowercase().as_str() {
+                "hash" => cert.fingerprint = Some(normalize_fingerprint(&value)),
+                "subject" => cert.subject = Some(value),
+                "dns" => cert.san.push(SubjectAltName::Dns(value)),
+                "uri" => cert.san.push(SubjectAltName::Uri(value)),
+                "email" => cert.san.push(SubjectAltName::Email(value)),
+                _ => {}
+            }
+        }
+        Ok(cert)
+    }
+
+    /// Parse the value of the PROXY protocol `PP2_TYPE_SSL` TLV, `None` if it is truncated
+    fn from_ssl_tlv(value: &[u8]) -> Option<Self> {
+        let [client, v0, v1, v2, v3, sub @ ..] = value else {
+            return None;
+        };
+        let verify = if client & (PP2_CLIENT_CERT_CONN | PP2_CLIENT_CERT_SESS) == 0 {
+            CertVerify::NotPresented
+        } else if u32::from_be_bytes([*v0, *v1, *v2, *v3]) == 0 {
+            CertVerify::Success
+        } else {
+            CertVerify::Failed
+        };
+        let subject = tlvs(sub)
+            .find(|(typ, _)| *typ == PP2_SUBTYPE_SSL_CN)
+            .map(|(_, cn)| format!("CN={}", String::from_utf8_lossy(cn)));
+        Some(Self {
+            subject,
+            san: Vec::new(),
+            fingerprint: None,
+            verify,
+        })
+    }
+
+    /// The common name of the subject, i.e. its first `CN` attribute
+    pub fn common_name(&self) -> Option<&str> {
+        self.subject
+            .as_deref()?
+            .split(',')
+            .filter_map(|rdn| rdn.trim().split_once('='))
+            .find(|(attr, _)| attr.eq_ignore_ascii_case("CN"))
+            .map(|(_, cn)| cn)
+    }
+
+    /// The certificate was presented, and verified by the proxy
+    pub fn is_verified(&self) -> bool {
+        self.verify == CertVerify::Success
+    }
+
+    /// Fail unless the certificate was presented and verified by the proxy
+    pub fn require_verified(&self) -> VclResult<()> {
+        match self.verify {
+            CertVerify::Success => Ok(()),
+            CertVerify::Failed => Err("The client certificate failed the verification".into()),
+            CertVerify::NotPresented => Err("No client certificate was presented".into()),
+            CertVerify::Unknown => Err("The client certificate verification is unknown".into()),
+        }
+    }
+
+    /// One of the `DNS` alternative names matches `host`, ignoring the case. A wildcard name,
+    /// e.g. `*.example.com`, matches exactly one label.
+    pub fn matches_dns(&self, host: &str) -> bool {
+        self.san.iter().any(|san| match san {
+            SubjectAltName::Dns(name) => dns_matches(name, host),
+            _ => false,
+        })
+    }
+
+    /// One of the `URI` alternative names is `uri`, e.g. a SPIFFE id
+    pub fn has_uri(&self, uri: &str) -> bool {
+        self.san
+            .iter()
+            .any(|san| matches!(san, SubjectAltName::Uri(u) if u == uri))
+    }
+
+    /// The fingerprint is `fingerprint`, written in hexadecimal in any case, with or without
+    /// colons
+    pub fn fingerprint_matches(&self, fingerprint: &str) -> bool {
+        self.fingerprint.as_deref() == Some(normalize_fingerprint(fingerprint).as_str())
+    }
+}
+
+impl Ctx<'_> {
+    /// The client certificate, as passed by the TLS proxy with the PROXY protocol version 2.
+    /// `None` outside of a client task, or if the proxy did not pass the TLS information.
+    pub fn client_cert(&self) -> Option<ClientCert> {
+        let req = unsafe { self.raw.req.as_ref()? };
+        let sp = unsafe { req.sp.as_ref()? };
+        let (_, value) = tlvs(proxy_tlvs(sp)?).find(|(typ, _)| *typ == PP2_TYPE_SSL)?;
+        ClientCert::from_ssl_tlv(value)
+    }
+}
+
+/// Lowercase hexadecimal digits, without the colons
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn dns_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(parent) => host
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(parent)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Split `value` on `sep`, except within double quotes
+fn split_quoted(value: &str, sep: char) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    let mut escaped = false;
+    value
+        .split(move |c| {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                _ => return c == sep && !quoted,
+            }
+            false
+        })
+        .filter(|s| !s.trim().is_empty())
+}
+
+/// Remove the double quotes around `value`, if any, and their escapes
+fn unquote(value: &str) -> VclResult<String> {
+    let Some(inner) = value.strip_prefix('"') else {
+        return Ok(value.to_string());
+    };
+    let inner = inner
+        .strip_suffix('"')
+        .ok_or_else(|| VclError::String(format!("Unterminated quoted value {value}")))?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        out.push(if c == '\\' {
+            chars.next().unwrap_or(c)
+        } else {
+            c
+        });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_cert() {
+        let header = concat!(
+            r#"By=spiffe://proxy;Hash=0A:1b:2C;Subject="CN=api,O=Example\, Inc.";"#,
+            r"URI=spiffe://example/api;DNS=*.example.com;DNS=api.local,",
+            r"Hash=ffff;Subject=CN=other",
+        );
+        let cert = ClientCert::from_xfcc(header).unwrap();
+        assert_eq!(cert.subject.as_deref(), Some("CN=api,O=Example, Inc."));
+        assert_eq!(cert.common_name(), Some("api"));
+        assert_eq!(cert.fingerprint.as_deref(), Some("0a1b2c"));
+        assert!(cert.fingerprint_matches("0A1B2C"));
+        assert!(cert.has_uri("spiffe://example/api"));
+        assert!(cert.matches_dns("www.Example.com") && cert.matches_dns("api.local."));
+        assert!(!cert.matches_dns("example.com") && !cert.matches_dns("a.b.example.com"));
+        assert!(cert.require_verified().is_err());
+        assert!(ClientCert::from_xfcc("Hash").is_err());
+        assert!(ClientCert::from_xfcc(r#"Subject="CN=x"#).is_err());
+
+        let tlv =
+            |client, verify: u32, sub: &[u8]| [&[client][..], &verify.to_be_bytes(), sub].concat();
+        let cn = [&[PP2_SUBTYPE_SSL_CN, 0, 3][..], b"bob"].concat();
+        let cert = ClientCert::from_ssl_tlv(&tlv(0x07, 0, &cn)).unwrap();
+        assert!(cert.is_verified());
+        assert_eq!(cert.common_name(), Some("bob"));
+        let cert = ClientCert::from_ssl_tlv(&tlv(0x03, 20, &[])).unwrap();
+        assert_eq!((cert.verify, cert.subject), (CertVerify::Failed, None));
+        let cert = ClientCert::from_ssl_tlv(&tlv(0x01, 0, &[])).unwrap();
+        assert_eq!(cert.verify, CertVerify::NotPresented);
+        assert!(ClientCert::from_ssl_tlv(&[0x07, 0]).is_none());
+    }
+}
@@ -24,13 +24,24 @@
 //! | `bool` | <-> | `VCL_BOOL` |
 //! | `std::time::Duration` | <-> | `VCL_DURATION` |
 //! | `&str` | <-> | `VCL_STRING` |
+//! | `Cow<str>` | <- | `VCL_STRING` (arguments with `#[arg(lossy)]`) |
+//! | `&[u8]` | <- | `VCL_STRING` (the raw bytes, without UTF-8 validation) |
+//! | `Strands` | <- | `VCL_STRANDS` |
+//! | `&str` | <- | `VCL_STRANDS` (arguments with `#[arg(strands)]`) |
 //! | `String` | -> | `VCL_STRING` |
+//! | `std::fmt::Arguments` | -> | `VCL_STRING` (formatted directly into the workspace) |
 //! | `Option<CowProbe>` | <-> | `VCL_PROBE` |
 //! | `Option<Probe>` | <-> | `VCL_PROBE` |
 //! | `Option<std::net::SockAdd>` | -> | `VCL_IP` |
+//! | `Option<std::net::IpAddr>` | <-> | `VCL_IP` (the port is ignored, and set to 0) |
+//! | `Option<BackendRef>` | <- | `VCL_BACKEND` |
+//! | `BackendRef` | -> | `VCL_BACKEND` |
 //!
 //! For all the other types, which are pointers, you will need to use the native types.
 //!
+//! The arguments that VCL may pass as `NULL`, i.e. probes, IPs and backends, must be declared as
+//! `Option`s. A `NULL` string is an empty `&str`, or `None` for an `Option<&str>`.
+//!
 //! *Note:* It is possible to simply return a `VCL_*` type (or a Result<VCL_*, _>), in which case
 //! the boilerplate will just skip the conversion.
 //!
@@ -45,6 +56,8 @@
 
 use std::borrow::Cow;
 use std::ffi::{c_char, CStr};
+use std::fmt;
+use std::fmt::Write as _;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ptr::{null, null_mut};
 use std::time::{Duration, SystemTime};
@@ -54,7 +67,11 @@ use crate::ffi::{
     VCL_BLOB, VCL_BODY, VCL_BOOL, VCL_DURATION, VCL_ENUM, VCL_HEADER, VCL_HTTP, VCL_INT, VCL_IP,
     VCL_PROBE, VCL_REAL, VCL_STEVEDORE, VCL_STRANDS, VCL_STRING, VCL_TIME, VCL_VCL,
 };
-use crate::vcl::{from_vcl_probe, into_vcl_probe, CowProbe, Probe, VclError, Workspace};
+#[cfg(feature = "chaos")]
+use crate::vcl::chaos::{self, Fault};
+use crate::vcl::{
+    from_vcl_probe, into_vcl_probe, CowProbe, Ctx, LogTag, Probe, VclError, Workspace,
+};
 
 /// Convert a Rust type into a VCL one
 ///
@@ -82,6 +99,7 @@ macro_rules! default_null_ptr {
 macro_rules! into_vcl_using_from {
     ($rust_ty:ty, $vcl_ty:ident) => {
         impl IntoVCL<$vcl_ty> for $rust_ty {
+            #[inline]
             fn into_vcl(self, _: &mut Workspace) -> Result<$vcl_ty, VclError> {
                 Ok(self.into())
             }
@@ -92,6 +110,7 @@ macro_rules! into_vcl_using_from {
 macro_rules! from_rust_to_vcl {
     ($rust_ty:ty, $vcl_ty:ident) => {
         impl From<$rust_ty> for $vcl_ty {
+            #[inline]
             fn from(b: $rust_ty) -> Self {
                 Self(b.into())
             }
@@ -102,6 +121,7 @@ macro_rules! from_rust_to_vcl {
 macro_rules! from_vcl_to_opt_rust {
     ($vcl_ty:ident, $rust_ty:ty) => {
         impl From<$vcl_ty> for Option<$rust_ty> {
+            #[inline]
             fn from(b: $vcl_ty) -> Self {
                 Some(b.into())
             }
@@ -128,6 +148,7 @@ into_vcl_using_from!(bool, VCL_BOOL);
 from_rust_to_vcl!(bool, VCL_BOOL);
 from_vcl_to_opt_rust!(VCL_BOOL, bool);
 impl From<VCL_BOOL> for bool {
+    #[inline]
     fn from(b: VCL_BOOL) -> Self {
         b.0 != 0
     }
@@ -139,11 +160,13 @@ impl From<VCL_BOOL> for bool {
 into_vcl_using_from!(Duration, VCL_DURATION);
 from_vcl_to_opt_rust!(VCL_DURATION, Duration);
 impl From<VCL_DURATION> for Duration {
+    #[inline]
     fn from(value: VCL_DURATION) -> Self {
         value.0.into()
     }
 }
 impl From<Duration> for VCL_DURATION {
+    #[inline]
     fn from(value: Duration) -> Self {
         Self(value.into())
     }
@@ -153,11 +176,13 @@ impl From<Duration> for VCL_DURATION {
 // vtim_dur -- this is a sub-structure of VCL_DURATION, equal to f64
 //
 impl From<vtim_dur> for Duration {
+    #[inline]
     fn from(value: vtim_dur) -> Self {
         Self::from_secs_f64(value.0)
     }
 }
 impl From<Duration> for vtim_dur {
+    #[inline]
     fn from(value: Duration) -> Self {
         Self(value.as_secs_f64())
     }
@@ -183,6 +208,7 @@ into_vcl_using_from!(i64, VCL_INT);
 from_rust_to_vcl!(i64, VCL_INT);
 from_vcl_to_opt_rust!(VCL_INT, i64);
 impl From<VCL_INT> for i64 {
+    #[inline]
     fn from(b: VCL_INT) -> Self {
         b.0
     }
@@ -222,6 +248,12 @@ impl From<VCL_IP> for Option<SocketAddr> {
     }
 }
 
+impl From<VCL_IP> for Option<IpAddr> {
+    fn from(value: VCL_IP) -> Self {
+        <Option<SocketAddr>>::from(value).map(|sa| sa.ip())
+    }
+}
+
 //
 // VCL_PROBE
 //
@@ -254,6 +286,7 @@ into_vcl_using_from!(f64, VCL_REAL);
 from_rust_to_vcl!(f64, VCL_REAL);
 from_vcl_to_opt_rust!(VCL_REAL, f64);
 impl From<VCL_REAL> for f64 {
+    #[inline]
     fn from(b: VCL_REAL) -> Self {
         b.0
     }
@@ -265,12 +298,20 @@ impl From<VCL_REAL> for f64 {
 default_null_ptr!(VCL_STRING);
 impl IntoVCL<VCL_STRING> for &[u8] {
     fn into_vcl(self, ws: &mut Workspace) -> Result<VCL_STRING, VclError> {
+        #[cfg(feature = "chaos")]
+        if chaos::fails(Fault::Convert) {
+            return Err(VclError::CStr(c"chaos: string conversion failed"));
+        }
         // Try to save some work if the buffer is already in the workspace.
         // We assume that &[u8] has always been readonly, so workspace data is valid.
         if ws.contains(self) {
             Ok(VCL_STRING(self.as_ptr().cast::<c_char>()))
         } else {
-            Ok(VCL_STRING(ws.copy_bytes_with_null(self)?.b))
+            // an optional NULL byte may end the slice
+            let src = self.strip_suffix(b"\0").unwrap_or(self);
+            let mut buf = ws.str_buffer();
+            buf.push_bytes(src)?;
+            buf.finish()
         }
     }
 }
@@ -281,6 +322,10 @@ impl IntoVCL<VCL_STRING> for &str {
 }
 impl IntoVCL<VCL_STRING> for &CStr {
     fn into_vcl(self, ws: &mut Workspace) -> Result<VCL_STRING, VclError> {
+        #[cfg(feature = "chaos")]
+        if chaos::fails(Fault::Convert) {
+            return Err(VclError::CStr(c"chaos: string conversion failed"));
+        }
         ws.copy_cstr(self)
     }
 }
@@ -294,6 +339,18 @@ impl IntoVCL<VCL_STRING> for String {
         self.as_str().into_vcl(ws)
     }
 }
+impl IntoVCL<VCL_STRING> for fmt::Arguments<'_> {
+    fn into_vcl(self, ws: &mut Workspace) -> Result<VCL_STRING, VclError> {
+        #[cfg(feature = "chaos")]
+        if chaos::fails(Fault::Convert) {
+            return Err(VclError::CStr(c"chaos: string conversion failed"));
+        }
+        // the buffer remembers why a write failed, and finish() returns that error
+        let mut buf = ws.str_buffer();
+        let _ = buf.write_fmt(self);
+        buf.finish()
+    }
+}
 impl<T: IntoVCL<VCL_STRING> + AsRef<[u8]>> IntoVCL<VCL_STRING> for Option<T> {
     fn into_vcl(self, ws: &mut Workspace) -> Result<VCL_STRING, VclError> {
         match self {
@@ -317,9 +374,24 @@ impl From<VCL_STRING> for &CStr {
         <Option<&CStr>>::from(value).unwrap_or_default()
     }
 }
+impl From<VCL_STRING> for Option<&[u8]> {
+    fn from(value: VCL_STRING) -> Self {
+        <Option<&CStr>>::from(value).map(CStr::to_bytes)
+    }
+}
+impl From<VCL_STRING> for &[u8] {
+    fn from(value: VCL_STRING) -> Self {
+        // Treat a null pointer as an empty string
+        <Option<&[u8]>>::from(value).unwrap_or_default()
+    }
+}
 impl TryFrom<VCL_STRING> for Option<&str> {
     type Error = VclError;
     fn try_from(value: VCL_STRING) -> Result<Self, Self::Error> {
+        #[cfg(feature = "chaos")]
+        if chaos::fails(Fault::Convert) {
+            return Err(VclError::CStr(c"chaos: string conversion failed"));
+        }
         Ok(<Option<&CStr>>::from(value).map(CStr::to_str).transpose()?)
     }
 }
@@ -330,6 +402,21 @@ impl<'a> TryFrom<VCL_STRING> for &'a str {
     }
 }
 
+/// Convert a `VCL_STRING` argument declared with `#[arg(lossy)]`. Invalid UTF-8 sequences are
+/// replaced with `U+FFFD` instead of failing the task, and the substitution is logged.
+/// It must be public because it is used by the macro-generated code.
+#[doc(hidden)]
+pub fn lossy_str_arg<'a>(ctx: &mut Ctx, value: VCL_STRING, arg: &str) -> Option<Cow<'a, str>> {
+    let value = String::from_utf8_lossy(<Option<&'a CStr>>::from(value)?.to_bytes());
+    if let Cow::Owned(_) = value {
+        ctx.log(
+            LogTag::Error,
+            format!("Invalid UTF-8 in argument `{arg}`, replaced with U+FFFD"),
+        );
+    }
+    Some(value)
+}
+
 // VCL_STEVEDORE
 default_null_ptr!(VCL_STEVEDORE);
 // VCL_STRANDS
@@ -362,7 +449,7 @@ default_null_ptr!(mut VCL_VCL);
 #[cfg(not(varnishsys_6))]
 mod version_after_v6 {
     use std::ffi::c_void;
-    use std::net::SocketAddr;
+    use std::net::{IpAddr, SocketAddr};
     use std::num::NonZeroUsize;
     use std::ptr;
     use std::ptr::null;
@@ -414,4 +501,10 @@ mod version_after_v6 {
             }
         }
     }
+
+    impl IntoVCL<VCL_IP> for IpAddr {
+        fn into_vcl(self, ws: &mut Workspace) -> Result<VCL_IP, VclError> {
+            SocketAddr::new(self, 0).into_vcl(ws)
+        }
+    }
 }
@@ -2,9 +2,12 @@
 //!
 #[cfg(not(varnishsys_6))]
 use std::ffi::{c_int, c_uint, c_void};
+use std::time::{Duration, SystemTime};
 
 use crate::ffi;
-use crate::ffi::{vrt_ctx, VRT_fail, VRT_CTX_MAGIC};
+#[cfg(not(feature = "bench"))]
+use crate::ffi::VRT_fail;
+use crate::ffi::{vrt_ctx, VRT_CTX_MAGIC};
 use crate::vcl::{HttpHeaders, LogTag, TestWS, VclError, Workspace};
 
 /// VCL context
@@ -16,31 +19,69 @@ use crate::vcl::{HttpHeaders, LogTag, TestWS, VclError, Workspace};
 /// the provided methods to interact with them. If they are not enough, the `raw` field is actually
 /// the C original pointer that can be used to directly, and unsafely, act on the structure.
 ///
-/// Which `http_*` are present will depend on which VCL sub routine the function is called from.
+/// Which HTTP objects are present will depend on which VCL sub routine the function is called
+/// from. They are only looked up when one of their accessors, e.g. [`Ctx::req()`], is called.
 ///
 /// ``` rust
 /// # mod varnish { pub use varnish_sys::vcl; }
 /// use varnish::vcl::Ctx;
 ///
-/// fn foo(ctx: &Ctx) {
-///     if let Some(ref req) = ctx.http_req {
-///         for (name, value) in req {
-///             println!("header {name} has value {value}");
-///         }
+/// fn foo(ctx: &Ctx) -> Option<()> {
+///     for (name, value) in ctx.req()? {
+///         println!("header {name} has value {value}");
 ///     }
+///     Some(())
 /// }
 /// ```
 #[derive(Debug)]
 pub struct Ctx<'a> {
     pub raw: &'a mut vrt_ctx,
-    pub http_req: Option<HttpHeaders<'a>>,
-    pub http_req_top: Option<HttpHeaders<'a>>,
-    pub http_resp: Option<HttpHeaders<'a>>,
-    pub http_bereq: Option<HttpHeaders<'a>>,
-    pub http_beresp: Option<HttpHeaders<'a>>,
     pub ws: Workspace<'a>,
 }
 
+/// Use a nullable `struct http` pointer of [`vrt_ctx`] in place as an [`HttpHeaders`]
+///
+/// SAFETY: `field` must be `NULL` or point to a valid `struct http`
+unsafe fn http_ref<'r, 'a, P>(field: &'r P) -> Option<&'r HttpHeaders<'a>> {
+    const { assert!(size_of::<P>() == size_of::<*mut ffi::http>()) };
+    let field = std::ptr::from_ref(field);
+    if field.cast::<*mut ffi::http>().read().is_null() {
+        None
+    } else {
+        // HttpHeaders is a transparent wrapper around a non-null pointer
+        Some(&*field.cast::<HttpHeaders<'a>>())
+    }
+}
+
+/// Mutable version of [`http_ref()`]
+///
+/// SAFETY: `field` must be `NULL` or point to a valid `struct http`
+unsafe fn http_mut<'r, 'a, P>(field: &'r mut P) -> Option<&'r mut HttpHeaders<'a>> {
+    const { assert!(size_of::<P>() == size_of::<*mut ffi::http>()) };
+    let field = std::ptr::from_mut(field);
+    if field.cast::<*mut ffi::http>().read().is_null() {
+        None
+    } else {
+        Some(&mut *field.cast::<HttpHeaders<'a>>())
+    }
+}
+
+macro_rules! http_accessors {
+    ($($field:ident, $field_mut:ident => $http:ident),+ $(,)?) => {
+        $(
+            #[doc = concat!("The `", stringify!($field), "` HTTP object, if available in the current VCL subroutine")]
+            pub fn $field(&self) -> Option<&HttpHeaders<'a>> {
+                unsafe { http_ref(&self.raw.$http) }
+            }
+
+            #[doc = concat!("The `", stringify!($field), "` HTTP object, for modification")]
+            pub fn $field_mut(&mut self) -> Option<&mut HttpHeaders<'a>> {
+                unsafe { http_mut(&mut self.raw.$http) }
+            }
+        )+
+    };
+}
+
 impl<'a> Ctx<'a> {
     /// Wrap a raw pointer into an object we can use.
     ///
@@ -50,20 +91,31 @@ impl<'a> Ctx<'a> {
     }
 
     /// Instantiate from a mutable reference to a [`vrt_ctx`].
-    #[cfg_attr(not(varnishsys_6), expect(clippy::useless_conversion))] // Varnish v6 has a different struct, requiring .into()
     pub fn from_ref(raw: &'a mut vrt_ctx) -> Self {
         assert_eq!(raw.magic, VRT_CTX_MAGIC);
         Self {
-            http_req: HttpHeaders::from_ptr(raw.http_req.into()),
-            http_req_top: HttpHeaders::from_ptr(raw.http_req_top.into()),
-            http_resp: HttpHeaders::from_ptr(raw.http_resp.into()),
-            http_bereq: HttpHeaders::from_ptr(raw.http_bereq.into()),
-            http_beresp: HttpHeaders::from_ptr(raw.http_beresp.into()),
             ws: Workspace::from_ptr(raw.ws),
             raw,
         }
     }
 
+    http_accessors! {
+        req, req_mut => http_req,
+        req_top, req_top_mut => http_req_top,
+        resp, resp_mut => http_resp,
+        bereq, bereq_mut => http_bereq,
+        beresp, beresp_mut => http_beresp,
+    }
+
+    /// Split the context into a shared borrow, and a handle to its workspace to allocate from.
+    ///
+    /// This lets code read the context, e.g. its HTTP objects, while writing into the workspace.
+    /// Vmod functions get the same split by taking both `&Ctx` and `&mut Workspace` arguments.
+    pub fn split_ws(&mut self) -> (&Self, Workspace<'_>) {
+        let ws = Workspace::from_ptr(self.ws.raw);
+        (self, ws)
+    }
+
     /// Log an error message and fail the current VSL task.
     ///
     /// Once the control goes back to Varnish, it will see that the transaction was marked as fail
@@ -71,13 +123,35 @@ impl<'a> Ctx<'a> {
     pub fn fail(&mut self, msg: impl Into<VclError>) {
         let msg = msg.into();
         let msg = msg.as_str();
+        #[cfg(not(feature = "bench"))]
         unsafe {
             VRT_fail(self.raw, c"%.*s".as_ptr(), msg.len(), msg.as_ptr());
         }
+        // Benchmarks run outside of Varnish, do what `VRT_fail()` does
+        #[cfg(feature = "bench")]
+        {
+            crate::vcl::bench_vsl(LogTag::VclError, msg.as_bytes());
+            if let Some(handling) = unsafe { self.raw.handling.as_mut() } {
+                *handling = ffi::VCL_RET_FAIL;
+            }
+        }
+    }
+
+    /// Same as [`Ctx::fail()`], kept out of line for the error path of the wrappers generated
+    /// with `#[vmod(inline)]`
+    #[doc(hidden)]
+    #[cold]
+    #[inline(never)]
+    pub fn fail_cold(&mut self, err: VclError) {
+        self.fail(err);
     }
 
     /// Log a message, attached to the current context
+    #[cfg_attr(feature = "bench", expect(clippy::unused_self))]
     pub fn log(&mut self, tag: LogTag, msg: impl AsRef<str>) {
+        #[cfg(feature = "bench")]
+        crate::vcl::bench_vsl(tag, msg.as_ref().as_bytes());
+        #[cfg(not(feature = "bench"))]
         unsafe {
             let vsl = self.raw.vsl;
             if vsl.is_null() {
@@ -88,6 +162,132 @@ impl<'a> Ctx<'a> {
             }
         }
     }
+
+    /// Append `data` to the cache key of the current request, like `hash_data()` does in VCL.
+    ///
+    /// This is only possible from `vcl_hash`, and `data` can't contain `NULL` bytes (except for a
+    /// trailing one).
+    #[cfg(not(varnishsys_6))]
+    pub fn hash_data(&mut self, data: impl AsRef<[u8]>) -> Result<(), VclError> {
+        // `specific` is only the hash context in `vcl_hash`, e.g. it is the body in `vcl_synth`
+        if self.raw.method != ffi::VCL_MET_HASH {
+            return Err(VclError::CStr(c"hash_data() can only be used in vcl_hash"));
+        }
+        let mut p = self.ws.copy_bytes_with_null(data)?.b;
+        let strands = ffi::strands {
+            n: 1,
+            p: std::ptr::from_mut(&mut p),
+        };
+        unsafe {
+            ffi::VRT_hashdata(self.raw, ffi::VCL_STRANDS(std::ptr::from_ref(&strands)));
+        }
+        Ok(())
+    }
+
+    /// The timestamps of the current task, `None` outside of a request or a backend fetch.
+    ///
+    /// These are the times the `Timestamp` records of the log are computed from, so a vmod can
+    /// check how much of a latency budget is left when it makes a decision.
+    pub fn timings(&self) -> Option<Timings> {
+        if let Some(req) = unsafe { self.raw.req.as_ref() } {
+            return Some(Timings {
+                first: real_time(req.t_first)?,
+                prev: real_time(req.t_prev)?,
+                req: real_time(req.t_req),
+                resp: real_time(req.t_resp),
+            });
+        }
+        let bo = unsafe { self.raw.bo.as_ref()? };
+        Some(Timings {
+            first: real_time(bo.t_first)?,
+            prev: real_time(bo.t_prev)?,
+            req: None,
+            #[cfg(not(varnishsys_6))]
+            resp: real_time(bo.t_resp),
+            #[cfg(varnishsys_6)]
+            resp: None,
+        })
+    }
+
+    /// The sizes of the workspaces around the current task, to size buffers from the actual
+    /// `workspace_*` parameters instead of hardcoding a guess.
+    ///
+    /// Each size is the parameter minus what `varnishd` carves out of it for its own structures,
+    /// e.g. the `struct req` and its HTTP objects for `workspace_client`. Outside of a task, e.g.
+    /// in `vcl_init`, they are all `None`.
+    pub fn workspace_sizes(&self) -> WorkspaceSizes {
+        let req = unsafe { self.raw.req.as_ref() };
+        let bo = unsafe { self.raw.bo.as_ref() };
+        let wrk = req.map(|req| req.wrk).or(bo.map(|bo| bo.wrk));
+        WorkspaceSizes {
+            client: req.and_then(|req| ws_size(&req.ws[0])),
+            backend: bo.and_then(|bo| ws_size(&bo.ws[0])),
+            session: unsafe { self.raw.sp.as_ref() }.and_then(|sp| ws_size(&sp.ws[0])),
+            thread: wrk
+                .and_then(|wrk| unsafe { wrk.as_ref() })
+                .and_then(|wrk| ws_size(&wrk.aws[0])),
+        }
+    }
+
+    /// Override a timeout for the current task, like setting the matching VCL variable would.
+    ///
+    /// Session timeouts need a client connection, and backend request timeouts can only be set
+    /// from the backend side.
+    pub fn set_timeout(&mut self, kind: Timeout, value: Duration) -> Result<(), VclError> {
+        *self.timeout_mut(kind)? = value.into();
+        Ok(())
+    }
+
+    /// Get the current value of a timeout, see [`Ctx::set_timeout()`]
+    ///
+    /// Unless it was set for the task, this is the value of the matching parameter, e.g.
+    /// `first_byte_timeout` for [`Timeout::BereqFirstByte`].
+    pub fn timeout(&mut self, kind: Timeout) -> Result<Duration, VclError> {
+        let mut value = *self.timeout_mut(kind)?;
+        // Varnish leaves the fields at NaN until VCL sets them
+        if value.0.is_nan() {
+            value = kind.param(self);
+        }
+        Ok(Duration::from_secs_f64(value.0.max(0.0)))
+    }
+
+    fn timeout_mut(&mut self, kind: Timeout) -> Result<&mut ffi::vtim_dur, VclError> {
+        let sess = || unsafe { self.raw.sp.as_mut() }.ok_or(kind.unavailable());
+        let bo = || unsafe { self.raw.bo.as_mut() }.ok_or(kind.unavailable());
+        Ok(match kind {
+            Timeout::SessIdle => &mut sess()?.timeout_idle,
+            #[cfg(not(varnishsys_6))]
+            Timeout::SessLinger => &mut sess()?.timeout_linger,
+            #[cfg(not(varnishsys_6))]
+            Timeout::SessSend => &mut sess()?.send_timeout,
+            #[cfg(not(varnishsys_6))]
+            Timeout::SessIdleSend => &mut sess()?.idle_send_timeout,
+            Timeout::BereqConnect => &mut bo()?.connect_timeout,
+            Timeout::BereqFirstByte => &mut bo()?.first_byte_timeout,
+            Timeout::BereqBetweenBytes => &mut bo()?.between_bytes_timeout,
+        })
+    }
+
+    /// The `-a` listen socket the client connection came in on, `None` without a client session.
+    ///
+    /// This is what `local.socket` and `local.endpoint` give in VCL, e.g. to serve several
+    /// tenants from different listeners.
+    pub fn listen_endpoint(&self) -> Option<ListenEndpoint<'_>> {
+        if self.raw.sp.is_null() {
+            return None;
+        }
+        let (name, endpoint) = unsafe {
+            (
+                ffi::VRT_r_local_socket(self.raw),
+                ffi::VRT_r_local_endpoint(self.raw),
+            )
+        };
+        Some(ListenEndpoint {
+            name: <Option<&str>>::try_from(name).ok()??,
+            endpoint: <Option<&str>>::try_from(endpoint).ok()??,
+        })
+    }
+
     #[cfg(not(varnishsys_6))]
     pub fn cached_req_body(&mut self) -> Result<Vec<&'a [u8]>, VclError> {
         unsafe extern "C" fn chunk_collector(
@@ -125,6 +325,149 @@ impl<'a> Ctx<'a> {
     }
 }
 
+/// A listen socket of Varnish, as returned by [`Ctx::listen_endpoint()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenEndpoint<'a> {
+    /// The name given with `-a name=...`, or `a0`, `a1`... in the order of the arguments
+    pub name: &'a str,
+    /// The address as given to `-a`, e.g. `:80`, or the path of a Unix domain socket
+    pub endpoint: &'a str,
+}
+
+impl ListenEndpoint<'_> {
+    /// The socket is a Unix domain socket, named by a path or an abstract `@name`
+    pub fn is_unix(&self) -> bool {
+        self.endpoint.starts_with(['/', '@'])
+    }
+}
+
+/// The timestamps of a task, as returned by [`Ctx::timings()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    /// The start of the task, the `Start` timestamp
+    pub first: SystemTime,
+    /// The last `Timestamp` record logged by the task
+    pub prev: SystemTime,
+    /// When the request was received, i.e. `req.time`, only on the client side
+    pub req: Option<SystemTime>,
+    /// When the response was started, the delivery on the client side, or when the backend
+    /// response headers were received on the backend side
+    pub resp: Option<SystemTime>,
+}
+
+impl Timings {
+    /// The time since the start of the task
+    pub fn elapsed(&self) -> Duration {
+        self.first.elapsed().unwrap_or_default()
+    }
+
+    /// The time since the last `Timestamp` record
+    pub fn since_prev(&self) -> Duration {
+        self.prev.elapsed().unwrap_or_default()
+    }
+}
+
+/// The sizes of the workspaces, in bytes, as returned by [`Ctx::workspace_sizes()`]
+///
+/// These are the only `varnishd` parameters a vmod can read: the others, e.g. `default_ttl` or
+/// `default_grace`, are kept in the private memory of `varnishd`, and are neither in the VSM nor
+/// reachable through the VRT API.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WorkspaceSizes {
+    /// The workspace of the request, from `workspace_client`, only on the client side
+    pub client: Option<usize>,
+    /// The workspace of the backend fetch, from `workspace_backend`, only on the backend side
+    pub backend: Option<usize>,
+    /// The workspace of the client connection, from `workspace_session`, only on the client side
+    pub session: Option<usize>,
+    /// The workspace of the worker thread running the task, from `workspace_thread`
+    pub thread: Option<usize>,
+}
+
+fn ws_size(ws: &ffi::ws) -> Option<usize> {
+    if ws.s.is_null() {
+        None
+    } else {
+        Some(unsafe { ws.e.offset_from(ws.s) } as usize)
+    }
+}
+
+/// Varnish leaves the timestamps it hasn't reached yet at 0 or `NaN`
+fn real_time(t: ffi::vtim_real) -> Option<SystemTime> {
+    if t.0 > 0.0 {
+        Some(SystemTime::UNIX_EPOCH + Duration::try_from_secs_f64(t.0).ok()?)
+    } else {
+        None
+    }
+}
+
+/// Timeouts that can be changed for the current task with [`Ctx::set_timeout()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeout {
+    /// `sess.timeout_idle`
+    SessIdle,
+    /// `sess.timeout_linger`
+    #[cfg(not(varnishsys_6))]
+    SessLinger,
+    /// `sess.send_timeout`
+    #[cfg(not(varnishsys_6))]
+    SessSend,
+    /// `sess.idle_send_timeout`
+    #[cfg(not(varnishsys_6))]
+    SessIdleSend,
+    /// `bereq.connect_timeout`
+    BereqConnect,
+    /// `bereq.first_byte_timeout`
+    BereqFirstByte,
+    /// `bereq.between_bytes_timeout`
+    BereqBetweenBytes,
+}
+
+impl Timeout {
+    /// The value of the parameter, read through the VCL variable which falls back to it
+    #[cfg(not(any(test, miri, feature = "bench")))]
+    pub(crate) fn param(self, ctx: &Ctx) -> ffi::vtim_dur {
+        let read = match self {
+            Self::SessIdle => ffi::VRT_r_sess_timeout_idle,
+            #[cfg(not(varnishsys_6))]
+            Self::SessLinger => ffi::VRT_r_sess_timeout_linger,
+            #[cfg(not(varnishsys_6))]
+            Self::SessSend => ffi::VRT_r_sess_send_timeout,
+            #[cfg(not(varnishsys_6))]
+            Self::SessIdleSend => ffi::VRT_r_sess_idle_send_timeout,
+            Self::BereqConnect => ffi::VRT_r_bereq_connect_timeout,
+            Self::BereqFirstByte => ffi::VRT_r_bereq_first_byte_timeout,
+            Self::BereqBetweenBytes => ffi::VRT_r_bereq_between_bytes_timeout,
+        };
+        unsafe { read(ctx.raw) }.0
+    }
+
+    /// The default value of the parameter, when testing or benchmarking without varnishd
+    #[cfg(any(test, miri, feature = "bench"))]
+    pub(crate) fn param(self, _ctx: &Ctx) -> ffi::vtim_dur {
+        ffi::vtim_dur(match self {
+            Self::SessIdle => 5.0,
+            #[cfg(not(varnishsys_6))]
+            Self::SessLinger => 0.05,
+            #[cfg(not(varnishsys_6))]
+            Self::SessSend => 600.0,
+            #[cfg(not(varnishsys_6))]
+            Self::SessIdleSend => 60.0,
+            Self::BereqConnect => 3.5,
+            Self::BereqFirstByte | Self::BereqBetweenBytes => 60.0,
+        })
+    }
+
+    fn unavailable(self) -> VclError {
+        VclError::CStr(match self {
+            Self::BereqConnect | Self::BereqFirstByte | Self::BereqBetweenBytes => {
+                c"bereq timeouts can only be used on the backend side"
+            }
+            _ => c"sess timeouts need a client session",
+        })
+    }
+}
+
 /// A struct holding both a native [`vrt_ctx`] struct and the space it points to.
 ///
 /// As the name implies, this struct mainly exist to facilitate testing and should probably not be
@@ -176,6 +519,136 @@ mod tests {
         let mut test_ctx = TestCtx::new(100);
         test_ctx.ctx();
     }
+
+    #[test]
+    fn http_accessors_test() {
+        let mut test_ctx = TestCtx::new(100);
+        let mut ctx = test_ctx.ctx();
+        assert!(ctx.req().is_none() && ctx.beresp_mut().is_none());
+
+        let mut http = ffi::http::default();
+        let ptr = std::ptr::from_mut(&mut http);
+        ctx.raw.http_resp = ffi::VCL_HTTP(ptr);
+        assert!(std::ptr::eq(ctx.resp().unwrap().raw, ptr));
+        assert!(std::ptr::eq(ctx.resp_mut().unwrap().raw, ptr));
+        assert!(ctx.req().is_none());
+    }
+
+    #[test]
+    #[cfg(not(varnishsys_6))]
+    fn hash_data_test() {
+        let mut test_ctx = TestCtx::new(100);
+        let mut ctx = test_ctx.ctx();
+        let mut req = ffi::req::default();
+        let mut vsb = 0_u64;
+        ctx.raw.req = std::ptr::from_mut(&mut req);
+        ctx.raw.specific = std::ptr::from_mut(&mut vsb).cast();
+        ctx.raw.method = ffi::VCL_MET_SYNTH;
+        assert!(ctx.hash_data("foo").is_err());
+    }
+
+    #[test]
+    fn listen_endpoint_test() {
+        let uds = ListenEndpoint {
+            name: "tenant1",
+            endpoint: "/run/varnish/tenant1.sock",
+        };
+        assert!(uds.is_unix());
+        let tcp = ListenEndpoint {
+            name: "a0",
+            endpoint: ":80",
+        };
+        assert!(!tcp.is_unix());
+    }
+
+    #[test]
+    fn timings_test() {
+        let mut test_ctx = TestCtx::new(100);
+        let ctx = test_ctx.ctx();
+        assert!(ctx.timings().is_none());
+
+        let mut bo = ffi::busyobj {
+            t_first: ffi::vtim_real(1000.0),
+            t_prev: ffi::vtim_real(1000.5),
+            ..ffi::busyobj::default()
+        };
+        ctx.raw.bo = std::ptr::from_mut(&mut bo);
+        let at = |secs: f64| SystemTime::UNIX_EPOCH + Duration::from_secs_f64(secs);
+        let timings = ctx.timings().unwrap();
+        assert_eq!(timings.first, at(1000.0));
+        assert_eq!(timings.prev, at(1000.5));
+        assert_eq!((timings.req, timings.resp), (None, None));
+
+        let mut req = ffi::req {
+            t_first: ffi::vtim_real(2000.0),
+            t_prev: ffi::vtim_real(2000.25),
+            t_req: ffi::vtim_real(2000.25),
+            t_resp: ffi::vtim_real(f64::NAN),
+            ..ffi::req::default()
+        };
+        ctx.raw.req = std::ptr::from_mut(&mut req);
+        let timings = ctx.timings().unwrap();
+        assert_eq!(timings.first, at(2000.0));
+        assert_eq!(timings.req, Some(at(2000.25)));
+        assert_eq!(timings.resp, None);
+        assert!(timings.elapsed() > timings.since_prev());
+    }
+
+    #[test]
+    fn workspace_sizes_test() {
+        let mut test_ctx = TestCtx::new(100);
+        let ctx = test_ctx.ctx();
+        assert_eq!(ctx.workspace_sizes(), WorkspaceSizes::default());
+
+        let mut client = [0u8; 64];
+        let mut thread = [0u8; 16];
+        let ws = |buf: &mut [u8]| {
+            let s = buf.as_mut_ptr().cast::<std::ffi::c_char>();
+            ffi::ws {
+                s,
+                f: s,
+                e: unsafe { s.add(buf.len()) },
+                ..ffi::ws::default()
+            }
+        };
+        let mut wrk = ffi::worker {
+            aws: [ws(&mut thread)],
+            ..ffi::worker::default()
+        };
+        let mut req = ffi::req {
+            ws: [ws(&mut client)],
+            wrk: std::ptr::from_mut(&mut wrk),
+            ..ffi::req::default()
+        };
+        ctx.raw.req = std::ptr::from_mut(&mut req);
+        let sizes = ctx.workspace_sizes();
+        assert_eq!((sizes.client, sizes.thread), (Some(64), Some(16)));
+        assert_eq!((sizes.backend, sizes.session), (None, None));
+    }
+
+    #[test]
+    fn timeout_test() {
+        let mut test_ctx = TestCtx::new(100);
+        let mut ctx = test_ctx.ctx();
+        assert!(ctx.set_timeout(Timeout::SessIdle, Duration::ZERO).is_err());
+        assert!(ctx.timeout(Timeout::BereqConnect).is_err());
+
+        // not set by VCL
+        let mut bo = ffi::busyobj {
+            connect_timeout: ffi::vtim_dur(f64::NAN),
+            first_byte_timeout: ffi::vtim_dur(f64::NAN),
+            between_bytes_timeout: ffi::vtim_dur(f64::NAN),
+            ..ffi::busyobj::default()
+        };
+        ctx.raw.bo = std::ptr::from_mut(&mut bo);
+        assert_eq!(
+            ctx.timeout(Timeout::BereqFirstByte).unwrap(),
+            Duration::from_secs(60)
+        );
+        let value = Duration::from_millis(1500);
+        ctx.set_timeout(Timeout::BereqConnect, value).unwrap();
+        assert_eq!(ctx.timeout(Timeout::BereqConnect).unwrap(), value);
+    }
 }
 
 /// This is an unsafe struct that holds the per-VCL state.
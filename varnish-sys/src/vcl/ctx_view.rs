@@ -0,0 +1,92 @@
+//! Views of [`Ctx`] restricted to a family of VCL subroutines
+//!
+//! Which HTTP objects are available depends on the subroutine a vmod function is called from. A
+//! function using a view instead of [`Ctx`] as its parameter gets these objects directly, and
+//! fails the VCL transaction with an explicit error if it is called from the wrong place.
+//!
+//! ``` rust
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::DeliverCtx;
+//!
+//! fn copy_header(ctx: &mut DeliverCtx, name: &str) {
+//!     if let Some(value) = ctx.req().header(name).map(str::to_owned) {
+//!         let _ = ctx.resp_mut().set_header(name, &value);
+//!     }
+//! }
+//! ```
+
+use std::ops::{Deref, DerefMut};
+
+use crate::vcl::{Ctx, HttpHeaders, VclError};
+
+macro_rules! ctx_view {
+    (
+        $(#[$attr:meta])*
+        $name:ident: $($field:ident, $field_mut:ident),+
+    ) => {
+        $(#[$attr])*
+        #[derive(Debug)]
+        pub struct $name<'c, 'a> {
+            ctx: &'c mut Ctx<'a>,
+        }
+
+        impl<'c, 'a> $name<'c, 'a> {
+            /// Check that the context has all the HTTP objects this view requires
+            pub fn new(ctx: &'c mut Ctx<'a>) -> Result<Self, VclError> {
+                if $(ctx.$field().is_none())||+ {
+                    return Err(VclError::Str(concat!(
+                        "`",
+                        stringify!($name),
+                        "` requires ",
+                        $("`", stringify!($field), "` ",)+
+                        "to be available, but the function was called from an incompatible VCL subroutine"
+                    )));
+                }
+                Ok(Self { ctx })
+            }
+
+            $(
+                #[doc = concat!("The `", stringify!($field), "` HTTP object")]
+                pub fn $field(&self) -> &HttpHeaders<'a> {
+                    self.ctx.$field().expect("checked by new()")
+                }
+
+                #[doc = concat!("The `", stringify!($field), "` HTTP object, for modification")]
+                pub fn $field_mut(&mut self) -> &mut HttpHeaders<'a> {
+                    self.ctx.$field_mut().expect("checked by new()")
+                }
+            )+
+        }
+
+        impl<'a> Deref for $name<'_, 'a> {
+            type Target = Ctx<'a>;
+
+            fn deref(&self) -> &Self::Target {
+                self.ctx
+            }
+        }
+
+        impl DerefMut for $name<'_, '_> {
+            fn deref_mut(&mut self) -> &mut Self::Target {
+                self.ctx
+            }
+        }
+    };
+}
+
+ctx_view! {
+    /// Client-side view of [`Ctx`], with the client request, e.g. in `vcl_recv` or `vcl_hash`
+    RecvCtx: req, req_mut
+}
+
+ctx_view! {
+    /// Backend-side view of [`Ctx`], with the backend request and response, e.g. in
+    /// `vcl_backend_response` or `vcl_backend_error`
+    FetchCtx: bereq, bereq_mut, beresp, beresp_mut
+}
+
+ctx_view! {
+    /// Client-side view of [`Ctx`], with the client request and response, e.g. in `vcl_deliver` or
+    /// `vcl_synth`
+    DeliverCtx: req, req_mut, resp, resp_mut
+}
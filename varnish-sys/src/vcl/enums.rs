@@ -0,0 +1,98 @@
+//! Support for `ENUM` arguments.
+//!
+//! An enum tagged with `#[derive(VclEnum)]` inside a `#[vmod]` module can be used as a function
+//! argument, possibly optional. Each variant becomes a VCL value, named after the variant in
+//! snake case, e.g. `NoCache` becomes `no_cache`, or as set with `#[vcl_enum(rename = "...")]`.
+//! VCL passes `ENUM` values as pointers into the VMOD's own table, so an argument is converted by
+//! comparing pointers, never strings, and the function gets a plain Rust enum to `match` on.
+
+use std::ffi::{c_char, CStr};
+use std::ptr;
+
+use crate::ffi::VCL_ENUM;
+
+/// An enum that can be used as a VCL `ENUM` argument, see the [module documentation](self).
+/// Implement it with `#[derive(VclEnum)]`.
+pub trait VclEnum: Copy + 'static {
+    /// The VCL names of the variants, in declaration order
+    const VARIANTS: &'static [&'static str];
+
+    /// Get the variant at `index` in [`VclEnum::VARIANTS`]
+    fn from_index(index: usize) -> Option<Self>;
+
+    /// The index of this variant in [`VclEnum::VARIANTS`]
+    fn index(self) -> usize;
+
+    /// The VCL name of this variant
+    fn vcl_name(self) -> &'static str {
+        Self::VARIANTS[self.index()]
+    }
+
+    /// Find the variant of a VCL value. `values` are the entries of the VMOD table, in the order
+    /// of [`VclEnum::VARIANTS`].
+    #[doc(hidden)]
+    fn from_vcl(value: VCL_ENUM, values: &[&EnumValue]) -> Option<Self> {
+        values
+            .iter()
+            .position(|v| ptr::eq(v.0, value.0))
+            .and_then(Self::from_index)
+    }
+}
+
+/// An entry of the `ENUM` table of a VMOD, i.e. the pointer VCL passes for one value.
+/// It must be public because it is used by the macro-generated code.
+#[doc(hidden)]
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct EnumValue(*const c_char);
+
+// The pointer is only ever read, and points to a static string
+unsafe impl Sync for EnumValue {}
+
+impl EnumValue {
+    pub const fn new(name: &'static CStr) -> Self {
+        Self(name.as_ptr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Color {
+        Red,
+        Green,
+    }
+
+    impl VclEnum for Color {
+        const VARIANTS: &'static [&'static str] = &["red", "green"];
+
+        fn from_index(index: usize) -> Option<Self> {
+            [Self::Red, Self::Green].get(index).copied()
+        }
+
+        fn index(self) -> usize {
+            self as usize
+        }
+    }
+
+    static RED: EnumValue = EnumValue::new(c"red");
+    static GREEN: EnumValue = EnumValue::new(c"green");
+
+    #[test]
+    fn from_vcl() {
+        let table = [&RED, &GREEN];
+        assert_eq!(
+            Color::from_vcl(VCL_ENUM(GREEN.0), &table),
+            Some(Color::Green)
+        );
+        assert_eq!(Color::from_vcl(VCL_ENUM(RED.0), &table), Some(Color::Red));
+        // same string, different pointer
+        let red = CString::new("red").unwrap();
+        assert_eq!(Color::from_vcl(VCL_ENUM(red.as_ptr()), &table), None);
+        assert_eq!(Color::Green.vcl_name(), "green");
+    }
+}
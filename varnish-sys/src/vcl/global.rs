@@ -0,0 +1,132 @@
+//! Support for the `global_init` and `global_fini` parameters of `#[vmod]`
+//!
+//! Varnish loads the shared object of a VMOD once, however many VCLs import it, and unloads it
+//! after the last of these VCLs is discarded. The generated event handler counts the VCLs with a
+//! [`GlobalRefs`], calling the init hook before the first `Load` event, and the fini hook after the
+//! last `Discard` event.
+
+use std::sync::{Mutex, PoisonError};
+
+use crate::ffi::{vrt_ctx, VclEvent, VCL_INT};
+use crate::vcl::{Ctx, VclError};
+
+/// The value returned by a `global_init` hook: `()`, or a `Result` failing the VCL load
+#[doc(hidden)]
+pub trait HookResult {
+    fn into_result(self) -> Result<(), VclError>;
+}
+
+impl HookResult for () {
+    fn into_result(self) -> Result<(), VclError> {
+        Ok(())
+    }
+}
+
+impl<E: Into<VclError>> HookResult for Result<(), E> {
+    fn into_result(self) -> Result<(), VclError> {
+        self.map_err(Into::into)
+    }
+}
+
+/// The number of VCLs using the VMOD, see the [module documentation](self)
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct GlobalRefs {
+    count: Mutex<usize>,
+}
+
+impl Default for GlobalRefs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GlobalRefs {
+    pub const fn new() -> Self {
+        Self {
+            count: Mutex::new(0),
+        }
+    }
+
+    /// Handle `event`, calling `next`, the user's event handler, in between the hooks.
+    ///
+    /// A VCL whose `Load` event fails gets no `Discard` event, so it is released right away.
+    ///
+    /// SAFETY: `ctx` must be valid
+    pub unsafe fn on_event<R: HookResult>(
+        &self,
+        ctx: *mut vrt_ctx,
+        event: VclEvent,
+        init: impl FnOnce() -> R,
+        fini: impl FnOnce(),
+        next: impl FnOnce() -> VCL_INT,
+    ) -> VCL_INT {
+        match event {
+            VclEvent::Load => {
+                if let Err(err) = self.acquire(init) {
+                    Ctx::from_ptr(ctx).fail(err);
+                    return VCL_INT(1);
+                }
+                let res = next();
+                if res.0 != 0 {
+                    self.release(fini);
+                }
+                res
+            }
+            VclEvent::Discard => {
+                let res = next();
+                self.release(fini);
+                res
+            }
+            _ => next(),
+        }
+    }
+
+    fn acquire<R: HookResult>(&self, init: impl FnOnce() -> R) -> Result<(), VclError> {
+        let mut count = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        if *count == 0 {
+            init().into_result()?;
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    fn release(&self, fini: impl FnOnce()) {
+        let mut count = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            fini();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn global_refs() {
+        let refs = GlobalRefs::new();
+        let (inits, finis) = (Cell::new(0), Cell::new(0));
+        let init = || -> Result<(), &'static str> {
+            inits.set(inits.get() + 1);
+            Ok(())
+        };
+        let fini = || finis.set(finis.get() + 1);
+
+        refs.acquire(init).unwrap();
+        refs.acquire(init).unwrap();
+        assert_eq!(inits.get(), 1);
+        refs.release(fini);
+        assert_eq!(finis.get(), 0);
+        refs.release(fini);
+        assert_eq!(finis.get(), 1);
+
+        // a failed init is retried by the next VCL
+        assert!(refs.acquire(|| Err("no")).is_err());
+        refs.acquire(init).unwrap();
+        assert_eq!((inits.get(), *refs.count.lock().unwrap()), (2, 1));
+    }
+}
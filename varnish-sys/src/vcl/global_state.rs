@@ -0,0 +1,223 @@
+//! State shared by all the VCLs importing a VMOD
+//!
+//! A `static` lives as long as the VMOD shared object, i.e. across VCL reloads, and is shared by
+//! every loaded VCL. Data kept in a plain `static` is never freed, and must be carefully
+//! synchronized. [`GlobalState`] counts the VCLs using it from the `#[event]` handler: the value
+//! is created when the first VCL is loaded, and dropped when the last one is discarded.
+//!
+//! Readers get a cheap [`Snapshot`] of the current value, which stays valid for as long as they
+//! hold it. Writers publish a new version of the value with [`GlobalState::update()`], e.g. on a
+//! configuration push, without blocking the readers while the new value is built.
+//!
+//! ```
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::{Event, GlobalState, VclError};
+//!
+//! struct Config {
+//!     hosts: Vec<String>,
+//! }
+//!
+//! static CONFIG: GlobalState<Config> = GlobalState::new();
+//!
+//! // the `#[event]` function of the VMOD
+//! fn on_event(event: Event) -> Result<(), VclError> {
+//!     CONFIG.on_event(event, || Ok(Config { hosts: Vec::new() }))
+//! }
+//!
+//! fn add_host(host: &str) -> Option<u64> {
+//!     CONFIG.update(|old| Config {
+//!         hosts: [&old.hosts[..], &[host.to_string()]].concat(),
+//!     })
+//! }
+//!
+//! fn host_count() -> usize {
+//!     CONFIG.get().map_or(0, |config| config.hosts.len())
+//! }
+//! ```
+
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::vcl::Event;
+
+/// A version of the value of a [`GlobalState`]
+#[derive(Debug)]
+pub struct Snapshot<T> {
+    version: u64,
+    value: Arc<T>,
+}
+
+impl<T> Snapshot<T> {
+    /// The version of the value, increasing with each update, and never reused
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl<T> Clone for Snapshot<T> {
+    fn clone(&self) -> Self {
+        Self {
+            version: self.version,
+            value: Arc::clone(&self.value),
+        }
+    }
+}
+
+impl<T> Deref for Snapshot<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+#[derive(Debug)]
+struct Inner<T> {
+    refs: usize,
+    version: u64,
+    current: Option<Snapshot<T>>,
+}
+
+/// A value shared by all the VCLs importing the VMOD, see the [module documentation](self)
+#[derive(Debug)]
+pub struct GlobalState<T> {
+    inner: Mutex<Inner<T>>,
+    /// Serializes the updates, so that none is lost
+    writer: Mutex<()>,
+}
+
+impl<T> Default for GlobalState<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> GlobalState<T> {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                refs: 0,
+                version: 0,
+                current: None,
+            }),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Handle a VCL event: [`acquire()`](Self::acquire) on `Load`, and
+    /// [`release()`](Self::release) on `Discard`.
+    ///
+    /// A VCL whose `Load` event fails gets no `Discard` event: if the event handler fails after
+    /// this call, it must call `release()` itself.
+    pub fn on_event<E>(&self, event: Event, init: impl FnOnce() -> Result<T, E>) -> Result<(), E> {
+        match event {
+            Event::Load => self.acquire(init),
+            Event::Discard => {
+                self.release();
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Count one more VCL using the state, creating the value with `init` for the first one.
+    /// If `init` fails, the VCL is not counted.
+    pub fn acquire<E>(&self, init: impl FnOnce() -> Result<T, E>) -> Result<(), E> {
+        let mut inner = self.lock();
+        if inner.current.is_none() {
+            let value = Arc::new(init()?);
+            inner.publish(value);
+        }
+        inner.refs += 1;
+        Ok(())
+    }
+
+    /// Count one less VCL using the state, dropping the value after the last one. Tasks still
+    /// holding a [`Snapshot`] keep their copy alive until they are done.
+    pub fn release(&self) {
+        let mut inner = self.lock();
+        inner.refs = inner.refs.saturating_sub(1);
+        if inner.refs == 0 {
+            inner.current = None;
+        }
+    }
+
+    /// The current value, `None` if no VCL is using the state
+    pub fn get(&self) -> Option<Snapshot<T>> {
+        self.lock().current.clone()
+    }
+
+    /// The number of VCLs using the state
+    pub fn refs(&self) -> usize {
+        self.lock().refs
+    }
+
+    /// Publish a new version of the value, built by `f` from the current one, and return its
+    /// version. Readers keep getting the previous version while `f` runs. Does nothing and returns
+    /// `None` if no VCL is using the state.
+    pub fn update(&self, f: impl FnOnce(&T) -> T) -> Option<u64> {
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let old = self.get()?;
+        let value = Arc::new(f(&old));
+        let mut inner = self.lock();
+        // the state may have been dropped, and even recreated, while `f` was running
+        match &inner.current {
+            Some(current) if current.version == old.version => Some(inner.publish(value)),
+            _ => None,
+        }
+    }
+
+    /// Replace the value with a new version, see [`update()`](Self::update)
+    pub fn replace(&self, value: T) -> Option<u64> {
+        self.update(|_| value)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<T>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Inner<T> {
+    fn publish(&mut self, value: Arc<T>) -> u64 {
+        self.version += 1;
+        self.current = Some(Snapshot {
+            version: self.version,
+            value,
+        });
+        self.version
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_state() {
+        let state = GlobalState::<Vec<i32>>::new();
+        assert!(state.get().is_none());
+        assert_eq!(state.update(|_| vec![]), None);
+
+        state
+            .on_event(Event::Load, || Ok::<_, ()>(vec![1]))
+            .unwrap();
+        state.on_event(Event::Load, || Err(())).unwrap();
+        assert_eq!(state.refs(), 2);
+
+        let old = state.get().unwrap();
+        assert_eq!(state.update(|v| [&v[..], &[2]].concat()), Some(2));
+        assert_eq!((old.version(), &old[..]), (1, &[1][..]));
+        assert_eq!(&state.get().unwrap()[..], &[1, 2]);
+
+        state.on_event(Event::Discard, || Err(())).unwrap();
+        assert!(state.get().is_some());
+        state.release();
+        assert!(state.get().is_none());
+        assert_eq!(&old[..], &[1]);
+
+        // a failed creation is retried by the next VCL, versions are never reused
+        assert!(state.acquire(|| Err(())).is_err());
+        state.acquire(|| Ok::<_, ()>(vec![])).unwrap();
+        assert_eq!((state.refs(), state.get().unwrap().version()), (1, 3));
+    }
+}
@@ -0,0 +1,203 @@
+//! Choose the `return` action of the VCL subroutine from a vmod
+//!
+//! [`Ctx::fail()`] fails the whole task, while [`Ctx::set_handling()`] ends the current
+//! subroutine as if the VCL had called `return(pass)`, `return(synth(403))`, etc. A vmod taking
+//! a decision, e.g. a WAF or a rate limiter, can enforce it without setting a header for the VCL
+//! to test:
+//!
+//! ``` rust,ignore
+//! #[varnish::vmod]
+//! mod waf {
+//!     use varnish::vcl::{Action, Ctx, VclResult};
+//!
+//!     /// Called from `vcl_recv`, the remaining code of `vcl_recv` is skipped on a match
+//!     pub fn check(ctx: &mut Ctx) -> VclResult<()> {
+//!         if ctx.req().and_then(|req| req.header("x-evil")).is_some() {
+//!             ctx.set_handling(Action::Synth(403))?;
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//! ```
+//!
+//! The actions are only legal in some subroutines, like in VCL, and the check happens when the
+//! vmod is called since the compiler of the VCL does not know what the vmod will do.
+
+use std::fmt;
+
+use crate::ffi;
+use crate::ffi::{
+    VCL_MET_BACKEND_ERROR, VCL_MET_BACKEND_FETCH, VCL_MET_BACKEND_RESPONSE, VCL_MET_DELIVER,
+    VCL_MET_FINI, VCL_MET_HASH, VCL_MET_HIT, VCL_MET_INIT, VCL_MET_MISS, VCL_MET_PASS,
+    VCL_MET_PIPE, VCL_MET_PURGE, VCL_MET_RECV, VCL_MET_SYNTH,
+};
+use crate::vcl::{Ctx, VclError, VclResult};
+
+// The subroutines, as the bits of `vrt_ctx.method`
+const METHODS: [(u32, &str); 14] = [
+    (VCL_MET_RECV, "vcl_recv"),
+    (VCL_MET_PIPE, "vcl_pipe"),
+    (VCL_MET_PASS, "vcl_pass"),
+    (VCL_MET_HASH, "vcl_hash"),
+    (VCL_MET_PURGE, "vcl_purge"),
+    (VCL_MET_MISS, "vcl_miss"),
+    (VCL_MET_HIT, "vcl_hit"),
+    (VCL_MET_DELIVER, "vcl_deliver"),
+    (VCL_MET_SYNTH, "vcl_synth"),
+    (VCL_MET_BACKEND_FETCH, "vcl_backend_fetch"),
+    (VCL_MET_BACKEND_RESPONSE, "vcl_backend_response"),
+    (VCL_MET_BACKEND_ERROR, "vcl_backend_error"),
+    (VCL_MET_INIT, "vcl_init"),
+    (VCL_MET_FINI, "vcl_fini"),
+];
+
+/// A `return` action of a VCL subroutine, see the [module documentation](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Action {
+    /// `return(pass)`, from `vcl_recv`, `vcl_hit` and `vcl_miss`. The `return(pass(DURATION))` of
+    /// `vcl_backend_response` is not supported, it also makes the object a hit-for-pass one.
+    Pass,
+    /// `return(pipe)`, from `vcl_recv` and `vcl_pipe`
+    Pipe,
+    /// `return(restart)`, from the client subroutines, except `vcl_hash` and `vcl_pipe`
+    Restart,
+    /// `return(synth(status))`, from the client subroutines, except `vcl_hash` and `vcl_synth`.
+    /// The reason is the default one for the status.
+    Synth(u16),
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Pass => "pass",
+            Self::Pipe => "pipe",
+            Self::Restart => "restart",
+            Self::Synth(_) => "synth",
+        }
+    }
+
+    /// The subroutines this action can be returned from, as a `vrt_ctx.method` mask
+    fn methods(self) -> u32 {
+        match self {
+            Self::Pass => VCL_MET_RECV | VCL_MET_MISS | VCL_MET_HIT,
+            Self::Pipe => VCL_MET_RECV | VCL_MET_PIPE,
+            Self::Restart => {
+                VCL_MET_RECV
+                    | VCL_MET_PASS
+                    | VCL_MET_PURGE
+                    | VCL_MET_MISS
+                    | VCL_MET_HIT
+                    | VCL_MET_DELIVER
+                    | VCL_MET_SYNTH
+            }
+            Self::Synth(_) => {
+                VCL_MET_RECV
+                    | VCL_MET_PIPE
+                    | VCL_MET_PASS
+                    | VCL_MET_PURGE
+                    | VCL_MET_MISS
+                    | VCL_MET_HIT
+                    | VCL_MET_DELIVER
+            }
+        }
+    }
+
+    fn check(self, method: u32) -> VclResult<()> {
+        if let Self::Synth(status) = self {
+            if !(100..=999).contains(&status) {
+                return Err(VclError::new(format!(
+                    "illegal synth status code {status} (100..999)"
+                )));
+            }
+        }
+        if self.methods() & method == 0 {
+            return Err(VclError::new(format!(
+                "return({self}) is not allowed in {}",
+                method_name(method)
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Synth(status) => write!(f, "synth({status})"),
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+fn method_name(method: u32) -> &'static str {
+    METHODS
+        .iter()
+        .find(|(met, _)| method == *met)
+        .map_or("this context", |(_, name)| name)
+}
+
+impl Ctx<'_> {
+    /// End the current subroutine with `action`, as if the VCL called `return(action)`.
+    ///
+    /// Fails if the action is not allowed in the current subroutine, or if the subroutine already
+    /// has an outcome, e.g. after [`Ctx::fail()`]. Unlike [`Ctx::fail()`], an error does not fail
+    /// the task by itself.
+    pub fn set_handling(&mut self, action: Action) -> VclResult<()> {
+        action.check(self.raw.method)?;
+        unsafe {
+            if ffi::VRT_handled(self.raw) != 0 {
+                return Err(VclError::new(format!(
+                    "return({action}) ignored, {} already returned",
+                    method_name(self.raw.method)
+                )));
+            }
+            let hand = match action {
+                Action::Pass => ffi::VCL_RET_PASS,
+                Action::Pipe => ffi::VCL_RET_PIPE,
+                Action::Restart => ffi::VCL_RET_RESTART,
+                Action::Synth(status) => {
+                    ffi::VRT_synth(
+                        self.raw,
+                        ffi::VCL_INT(status.into()),
+                        ffi::VCL_STRING(std::ptr::null()),
+                    );
+                    ffi::VCL_RET_SYNTH
+                }
+            };
+            ffi::VRT_handling(self.raw, hand);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn action_check() {
+        assert!(Action::Pass.check(VCL_MET_RECV).is_ok());
+        assert!(Action::Pass.check(VCL_MET_BACKEND_RESPONSE).is_err());
+        assert!(Action::Pipe.check(VCL_MET_PIPE).is_ok());
+        assert!(Action::Synth(403).check(VCL_MET_MISS).is_ok());
+        assert!(Action::Restart.check(VCL_MET_SYNTH).is_ok());
+
+        assert_eq!(
+            Action::Synth(403)
+                .check(VCL_MET_SYNTH)
+                .unwrap_err()
+                .to_string(),
+            "return(synth(403)) is not allowed in vcl_synth"
+        );
+        assert_eq!(
+            Action::Pipe.check(VCL_MET_HASH).unwrap_err().to_string(),
+            "return(pipe) is not allowed in vcl_hash"
+        );
+        assert_eq!(
+            Action::Restart.check(0).unwrap_err().to_string(),
+            "return(restart) is not allowed in this context"
+        );
+        assert!(Action::Synth(42).check(VCL_MET_RECV).is_err());
+    }
+}
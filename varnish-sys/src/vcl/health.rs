@@ -0,0 +1,228 @@
+//! Follow the health of the backends of a VCL
+//!
+//! Varnish does not tell the vmods when a backend goes sick or healthy, it only answers when asked.
+//! A [`HealthWatch`] remembers the last known health of a set of backends, looked up by name, and
+//! turns the answers into [`HealthChange`] events, delivered to its subscribers and returned by
+//! [`HealthWatch::poll()`]. Polling needs a [`Ctx`], so it is done while handling requests, e.g.
+//! in `vcl_recv`, and at most once per interval, however many requests call it.
+//!
+//! ```
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use std::time::Duration;
+//! use varnish::vcl::{Ctx, HealthWatch};
+//!
+//! let watch = HealthWatch::new(Duration::from_secs(1));
+//! watch.watch("origin1");
+//! watch.watch("origin2");
+//! watch.subscribe(|change| {
+//!     // update a routing table, send an alert, ...
+//!     eprintln!("{} is now {}", change.backend, if change.healthy { "healthy" } else { "sick" });
+//! });
+//!
+//! // e.g. in a function called from `vcl_recv`
+//! fn recv(ctx: &Ctx, watch: &HealthWatch) {
+//!     watch.poll(ctx);
+//! }
+//! ```
+
+use std::ffi::CString;
+use std::fmt::{Debug, Formatter};
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::ffi;
+use crate::ffi::{vtim_real, VCL_BACKEND, VCL_STRING, VCL_TIME};
+use crate::vcl::Ctx;
+
+/// Find a backend or a director of the active VCL by name
+pub fn lookup_backend(ctx: &Ctx, name: &str) -> Option<VCL_BACKEND> {
+    let name = CString::new(name).ok()?;
+    let be = unsafe { ffi::VRT_LookupDirector(ctx.raw, VCL_STRING(name.as_ptr())) };
+    (!be.0.is_null()).then_some(be)
+}
+
+/// Ask a backend or a director if it is healthy, and since when
+pub fn backend_health(ctx: &Ctx, be: VCL_BACKEND) -> (bool, SystemTime) {
+    let mut changed = VCL_TIME(vtim_real(0.0));
+    let healthy = unsafe { ffi::VRT_Healthy(ctx.raw, be, ptr::from_mut(&mut changed)) };
+    let since = Duration::try_from_secs_f64(changed.0 .0).unwrap_or_default();
+    (healthy.into(), SystemTime::UNIX_EPOCH + since)
+}
+
+/// A backend went sick or healthy, as seen by [`HealthWatch::poll()`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthChange {
+    /// The name of the backend, as passed to [`HealthWatch::watch()`]
+    pub backend: String,
+    pub healthy: bool,
+    /// When the health changed, as reported by the backend
+    pub changed: SystemTime,
+}
+
+type Listener = Arc<dyn Fn(&HealthChange) + Send + Sync>;
+
+struct Inner {
+    last_poll: Option<Instant>,
+    /// The watched backends, and their last known health
+    backends: Vec<(String, Option<bool>)>,
+    listeners: Vec<Listener>,
+}
+
+/// Tracks the health of backends, see the [module documentation](self)
+pub struct HealthWatch {
+    interval: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl Debug for HealthWatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let inner = self.lock();
+        f.debug_struct("HealthWatch")
+            .field("interval", &self.interval)
+            .field("backends", &inner.backends)
+            .field("listeners", &inner.listeners.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl HealthWatch {
+    /// Create a watch polling the backends at most once per `interval`
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            inner: Mutex::new(Inner {
+                last_poll: None,
+                backends: Vec::new(),
+                listeners: Vec::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Start watching the backend or director called `name` in VCL
+    pub fn watch(&self, name: &str) {
+        let mut inner = self.lock();
+        if !inner.backends.iter().any(|(n, _)| n == name) {
+            inner.backends.push((name.to_string(), None));
+        }
+    }
+
+    /// Call `listener` on every change, from the task running [`HealthWatch::poll()`]
+    pub fn subscribe(&self, listener: impl Fn(&HealthChange) + Send + Sync + 'static) {
+        self.lock().listeners.push(Arc::new(listener));
+    }
+
+    /// The last known health of a watched backend, `None` if it was not polled yet, or not found
+    pub fn is_healthy(&self, name: &str) -> Option<bool> {
+        self.lock()
+            .backends
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, h)| *h)
+    }
+
+    /// Ask the watched backends for their health, unless it was done less than an interval ago,
+    /// and return the changes since the previous poll. The first poll only records the current
+    /// health, without reporting any change.
+    pub fn poll(&self, ctx: &Ctx) -> Vec<HealthChange> {
+        self.poll_with(Instant::now(), |name| {
+            lookup_backend(ctx, name).map(|be| backend_health(ctx, be))
+        })
+    }
+
+    fn poll_with(
+        &self,
+        now: Instant,
+        mut health: impl FnMut(&str) -> Option<(bool, SystemTime)>,
+    ) -> Vec<HealthChange> {
+        let (changes, listeners) = {
+            let mut inner = self.lock();
+            if inner
+                .last_poll
+                .is_some_and(|last| now.duration_since(last) < self.interval)
+            {
+                return Vec::new();
+            }
+            inner.last_poll = Some(now);
+            let mut changes = Vec::new();
+            for (name, last) in &mut inner.backends {
+                let Some((healthy, changed)) = health(name) else {
+                    // the backend is gone, or not created yet
+                    *last = None;
+                    continue;
+                };
+                if last.is_some_and(|h| h != healthy) {
+                    changes.push(HealthChange {
+                        backend: name.clone(),
+                        healthy,
+                        changed,
+                    });
+                }
+                *last = Some(healthy);
+            }
+            (changes, inner.listeners.clone())
+        };
+        // listeners are called without the lock, so they can use the watch
+        for change in &changes {
+            for listener in &listeners {
+                listener(change);
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn health_watch() {
+        let watch = Arc::new(HealthWatch::new(Duration::from_secs(1)));
+        watch.watch("a");
+        watch.watch("b");
+        watch.watch("a");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (events, w) = (Arc::clone(&seen), Arc::clone(&watch));
+        watch.subscribe(move |c| {
+            // the listener may use the watch
+            assert_eq!(w.is_healthy(&c.backend), Some(c.healthy));
+            events.lock().unwrap().push((c.backend.clone(), c.healthy));
+        });
+
+        let mut state = HashMap::from([("a", true), ("b", false)]);
+        let t0 = Instant::now();
+        let poll = |at: u64, state: &HashMap<&str, bool>| {
+            let changes = watch.poll_with(t0 + Duration::from_millis(at), |name| {
+                state.get(name).map(|h| (*h, SystemTime::UNIX_EPOCH))
+            });
+            changes.len()
+        };
+        assert_eq!(poll(0, &state), 0);
+        assert_eq!(watch.is_healthy("b"), Some(false));
+
+        state.insert("a", false);
+        state.insert("b", true);
+        // too early
+        assert_eq!(poll(500, &state), 0);
+        assert_eq!(poll(1000, &state), 2);
+        assert_eq!(poll(2000, &state), 0);
+        // a disappears, then comes back without a change being reported
+        state.remove("a");
+        assert_eq!(poll(3000, &state), 0);
+        assert_eq!(watch.is_healthy("a"), None);
+        state.insert("a", true);
+        assert_eq!(poll(4000, &state), 0);
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [("a".to_string(), false), ("b".to_string(), true)]
+        );
+    }
+}
@@ -10,6 +10,8 @@
 //! **Note:** at this stage, headers are assumed to be utf8, and you will get a panic if it's not
 //! the case. Future work needs to sanitize the headers to make this safer to use. It is tracked in
 //! this [issue](https://github.com/gquintard/varnish-rs/issues/4).
+//!
+//! [`negotiate`] picks the best value for `Accept`-like headers.
 
 use std::mem::transmute;
 use std::slice::from_raw_parts_mut;
@@ -18,6 +20,8 @@ use crate::ffi;
 use crate::ffi::VslTag;
 use crate::vcl::{VclResult, Workspace};
 
+pub mod negotiate;
+
 // C constants pop up as u32, but header indexing uses u16, redefine
 // some stuff to avoid casting all the time
 const HDR_FIRST: u16 = ffi::HTTP_HDR_FIRST as u16;
@@ -30,6 +34,7 @@ const HDR_URL: u16 = ffi::HTTP_HDR_URL as u16;
 
 /// HTTP headers of an object, wrapping `HTTP` from Varnish
 #[derive(Debug)]
+#[repr(transparent)]
 pub struct HttpHeaders<'a> {
     pub raw: &'a mut ffi::http,
 }
@@ -56,6 +61,20 @@ impl HttpHeaders<'_> {
         Ok(())
     }
 
+    /// Log the header at `idx`, using the VSL tag at `tag_offset` from the object's base tag
+    fn log_header(&self, tag_offset: u32, idx: usize) {
+        let tag = unsafe { transmute::<u32, VslTag>((self.raw.logtag as u32) + tag_offset) };
+        #[cfg(not(feature = "bench"))]
+        unsafe {
+            ffi::VSLbt(self.raw.vsl, tag, *self.raw.hd.add(idx));
+        }
+        // Benchmarks run outside of Varnish, without a VSL buffer to write to
+        #[cfg(feature = "bench")]
+        unsafe {
+            crate::vcl::bench_vsl(tag, (*self.raw.hd.add(idx)).to_slice().unwrap_or_default());
+        }
+    }
+
     /// Append a new header using `name` and `value`. This can fail if we run out of internal slots
     /// to store the new header
     pub fn set_header(&mut self, name: &str, value: &str) -> VclResult<()> {
@@ -68,13 +87,7 @@ impl HttpHeaders<'_> {
         self.raw.nhd += 1;
         let res = self.change_header(idx, &format!("{name}: {value}"));
         if res.is_ok() {
-            unsafe {
-                ffi::VSLbt(
-                    self.raw.vsl,
-                    transmute::<u32, VslTag>((self.raw.logtag as u32) + u32::from(HDR_FIRST)),
-                    *self.raw.hd.add(idx as usize),
-                );
-            }
+            self.log_header(u32::from(HDR_FIRST), idx as usize);
         } else {
             self.raw.nhd -= 1;
         }
@@ -90,15 +103,10 @@ impl HttpHeaders<'_> {
         for (idx, hd) in hdrs.iter().enumerate() {
             let (n, _) = hd.parse_header().unwrap();
             if name.eq_ignore_ascii_case(n) {
-                unsafe {
-                    ffi::VSLbt(
-                        self.raw.vsl,
-                        transmute::<u32, VslTag>(
-                            (self.raw.logtag as u32) + u32::from(HDR_UNSET) + u32::from(HDR_METHOD),
-                        ),
-                        *self.raw.hd.add(HDR_FIRST as usize + idx),
-                    );
-                }
+                self.log_header(
+                    u32::from(HDR_UNSET) + u32::from(HDR_METHOD),
+                    HDR_FIRST as usize + idx,
+                );
                 continue;
             }
             if idx != idx_empty {
@@ -141,6 +149,11 @@ impl HttpHeaders<'_> {
         self.field(HDR_URL)
     }
 
+    /// Set the URL of a request
+    pub fn set_url(&mut self, value: &str) -> VclResult<()> {
+        self.change_header(HDR_URL, value)
+    }
+
     /// Protocol of an object
     ///
     /// It should exist for both requests and responses, but the `Option` is maintained for
@@ -0,0 +1,208 @@
+//! Content negotiation with the `Accept`, `Accept-Encoding` and `Accept-Language` headers
+//!
+//! [`accept()`] picks the best of the values a vmod supports, following the quality values of the
+//! header as described in RFC 9110. Media ranges like `text/*` and language prefixes like `en`
+//! match the more specific values, and the most specific range decides the quality of each value.
+//! [`vary_key()`] turns a header into a canonical form, so that equivalent headers produce the
+//! same cache variant:
+//!
+//! ``` rust
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::http::negotiate::{accept, vary_key};
+//!
+//! let header = Some("text/*;q=0.5, application/json, */*;q=0.1");
+//! assert_eq!(accept(header, &["text/html", "application/json"]), Some("application/json"));
+//! assert_eq!(accept(header, &["image/png", "text/html"]), Some("text/html"));
+//!
+//! let header = Some("fr-CH, fr;q=0.9, en;q=0.8, de;q=0");
+//! assert_eq!(accept(header, &["de", "en-US", "fr"]), Some("fr"));
+//! assert_eq!(accept(header, &["de"]), None);
+//!
+//! assert_eq!(vary_key(Some("gzip;q=0.5, BR")), "br,gzip;q=0.5");
+//! ```
+
+use std::cmp::Reverse;
+use std::fmt::Write as _;
+
+/// A quality value, in thousandths
+type Quality = u16;
+
+/// One element of a header, e.g. `text/html;q=0.5`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Range<'a> {
+    value: &'a str,
+    quality: Quality,
+}
+
+/// Pick the value of `supported` that `header` prefers, or `None` if none is acceptable.
+///
+/// Values with the same quality are ordered as in `supported`. Without a header, any value is
+/// acceptable and the first one is returned. `identity` is acceptable unless explicitly refused,
+/// as required for `Accept-Encoding`.
+pub fn accept<'a>(header: Option<&str>, supported: &[&'a str]) -> Option<&'a str> {
+    let Some(header) = header else {
+        return supported.first().copied();
+    };
+    let ranges = parse(header);
+    let mut best: Option<(&str, Quality)> = None;
+    for &value in supported {
+        let quality = ranges
+            .iter()
+            .filter_map(|r| specificity(r.value, value).map(|s| (s, r.quality)))
+            .max_by_key(|(s, _)| *s)
+            .map(|(_, q)| q)
+            .or_else(|| value.eq_ignore_ascii_case("identity").then_some(1000))
+            .unwrap_or(0);
+        if quality > 0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((value, quality));
+        }
+    }
+    best.map(|(v, _)| v)
+}
+
+/// Normalize `header` into a key suitable for `Vary`-like cache variations.
+///
+/// Values are lowercased, their parameters other than the quality are kept, refused values are
+/// dropped, and the rest is sorted by decreasing quality, e.g. `gzip;q=0.5, BR` becomes
+/// `br,gzip;q=0.5`. An empty string is returned for a missing or empty header.
+pub fn vary_key(header: Option<&str>) -> String {
+    let mut ranges = header.map(parse).unwrap_or_default();
+    ranges.retain(|r| r.quality > 0);
+    ranges.sort_by_key(|r| Reverse(r.quality));
+    let mut key = String::new();
+    for r in ranges {
+        if !key.is_empty() {
+            key.push(',');
+        }
+        for c in r.value.chars().filter(|c| !c.is_ascii_whitespace()) {
+            key.push(c.to_ascii_lowercase());
+        }
+        if r.quality < 1000 {
+            let q = format!("{:03}", r.quality);
+            let _ = write!(key, ";q=0.{}", q.trim_end_matches('0'));
+        }
+    }
+    key
+}
+
+/// Split a header into its elements, ignoring the ones with an invalid quality
+fn parse(header: &str) -> Vec<Range<'_>> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            let element = element.trim();
+            let (value, quality) = match find_quality(element) {
+                Some((pos, q)) => (element[..pos].trim_end(), parse_quality(q)?),
+                None => (element, 1000),
+            };
+            let value = value.trim_end_matches(';').trim_end();
+            (!value.is_empty()).then_some(Range { value, quality })
+        })
+        .collect()
+}
+
+/// Find the `q` parameter of an element, returning its position and its value
+fn find_quality(element: &str) -> Option<(usize, &str)> {
+    let mut pos = 0;
+    for param in element.split(';') {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        if pos > 0 && name.trim().eq_ignore_ascii_case("q") {
+            // the quality is the last parameter, anything after it is an extension
+            return Some((pos - 1, value.trim()));
+        }
+        pos += param.len() + 1;
+    }
+    None
+}
+
+/// Parse a quality value, `0` to `1` with up to three decimals
+fn parse_quality(q: &str) -> Option<Quality> {
+    let (int, dec) = q.split_once('.').unwrap_or((q, ""));
+    if dec.len() > 3 || !dec.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let dec = format!("{dec:0<3}").parse::<Quality>().ok()?;
+    match int {
+        "0" => Some(dec),
+        "1" if dec == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// How specifically `range` matches `value`, or `None` if it does not match
+fn specificity(range: &str, value: &str) -> Option<(u8, usize)> {
+    if range.eq_ignore_ascii_case(value) {
+        Some((3, range.len()))
+    } else if range == "*" || range == "*/*" {
+        Some((0, 0))
+    } else if let Some(prefix) = range.strip_suffix("/*") {
+        let (ty, _) = value.split_once('/')?;
+        ty.eq_ignore_ascii_case(prefix).then_some((1, range.len()))
+    } else if !range.contains('/') {
+        // language prefix, e.g. `en` matches `en-US`
+        let prefix = value.get(..range.len())?;
+        (prefix.eq_ignore_ascii_case(range) && value[range.len()..].starts_with('-'))
+            .then_some((2, range.len()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_headers() {
+        let media = Some("text/html;level=1;q=0.2, text/*;q=0.4, image/png, */*;q=0.1");
+        assert_eq!(
+            accept(media, &["image/png", "text/html"]),
+            Some("image/png")
+        );
+        assert_eq!(
+            accept(media, &["text/css", "application/json"]),
+            Some("text/css")
+        );
+        assert_eq!(
+            accept(media, &["application/json"]),
+            Some("application/json")
+        );
+        assert_eq!(accept(Some("text/html"), &["text/plain"]), None);
+        assert_eq!(accept(None, &["a", "b"]), Some("a"));
+        assert_eq!(accept(Some("a;q=0.5, b;q=0.5"), &["b", "a"]), Some("b"));
+
+        let enc = Some("gzip;q=1.0, br; q=0.9, identity;q=0.5");
+        assert_eq!(accept(enc, &["br", "gzip"]), Some("gzip"));
+        assert_eq!(accept(enc, &["zstd", "identity"]), Some("identity"));
+        assert_eq!(
+            accept(Some("gzip"), &["zstd", "identity"]),
+            Some("identity")
+        );
+        assert_eq!(accept(Some("*;q=0"), &["identity"]), None);
+        assert_eq!(accept(Some("gzip;q=2, br;q=0.0001"), &["gzip", "br"]), None);
+
+        let lang = Some("en-US, en;q=0.8, *;q=0.1, de;q=0");
+        assert_eq!(accept(lang, &["fr", "en-GB"]), Some("en-GB"));
+        assert_eq!(accept(lang, &["fr", "de-AT"]), Some("fr"));
+        assert_eq!(accept(lang, &["EN-us", "en"]), Some("EN-us"));
+        assert_eq!(accept(Some("en"), &["eng"]), None);
+    }
+
+    #[test]
+    fn vary_keys() {
+        assert_eq!(vary_key(None), "");
+        assert_eq!(vary_key(Some(" , ")), "");
+        assert_eq!(
+            vary_key(Some("en-US, en;q=0.80 ,fr;q=0.5, de;q=0")),
+            "en-us,en;q=0.8,fr;q=0.5"
+        );
+        assert_eq!(
+            vary_key(Some("en-us,fr;q=0.5,en;q=0.8")),
+            "en-us,en;q=0.8,fr;q=0.5"
+        );
+        assert_eq!(
+            vary_key(Some("text/html; level=1, text/*;q=0.125")),
+            "text/html;level=1,text/*;q=0.125"
+        );
+    }
+}
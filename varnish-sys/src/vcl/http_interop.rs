@@ -0,0 +1,159 @@
+//! Conversions between [`HttpHeaders`] and the types of the [`http`] crate
+//!
+//! VMODs embedding `hyper`- or `reqwest`-based logic can build an [`http::Request`] from
+//! `bereq`, or a [`HeaderMap`] from any object, and copy an [`http::Response`] back into `resp`
+//! or `beresp`, without writing the translation loops by hand.
+//!
+//! ```
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use http::{HeaderMap, Request, Response};
+//! use varnish::vcl::{Ctx, VclError};
+//!
+//! // e.g. in a function called from `vcl_backend_fetch`
+//! fn to_request(ctx: &Ctx) -> Result<Request<()>, VclError> {
+//!     let bereq = ctx.bereq().ok_or("no backend request")?;
+//!     let headers = HeaderMap::try_from(bereq)?;
+//!     assert_eq!(headers.len(), bereq.iter().count());
+//!     Ok(bereq.to_request_builder().body(()).map_err(|e| e.to_string())?)
+//! }
+//!
+//! // e.g. in a function called from `vcl_backend_response`
+//! fn from_response(ctx: &mut Ctx, response: &Response<()>) -> Result<(), VclError> {
+//!     let beresp = ctx.beresp_mut().ok_or("no backend response")?;
+//!     beresp.apply_response(response)
+//! }
+//! ```
+
+use http::header::{HeaderName, HeaderValue};
+use http::{HeaderMap, Request, Response, Version};
+
+use crate::vcl::{HttpHeaders, VclError, VclResult};
+
+/// The `http` version of the protocol string of a Varnish object, e.g. `HTTP/1.1`
+fn version_from_proto(proto: &str) -> Option<Version> {
+    match proto {
+        "HTTP/0.9" => Some(Version::HTTP_09),
+        "HTTP/1.0" => Some(Version::HTTP_10),
+        "HTTP/1.1" => Some(Version::HTTP_11),
+        "HTTP/2.0" => Some(Version::HTTP_2),
+        _ => None,
+    }
+}
+
+/// The protocol string of a Varnish object for an `http` version, `None` for HTTP/3
+fn version_to_proto(version: Version) -> Option<&'static str> {
+    match version {
+        Version::HTTP_09 => Some("HTTP/0.9"),
+        Version::HTTP_10 => Some("HTTP/1.0"),
+        Version::HTTP_11 => Some("HTTP/1.1"),
+        Version::HTTP_2 => Some("HTTP/2.0"),
+        _ => None,
+    }
+}
+
+/// Convert one header, failing on names or values the `http` crate rejects
+fn to_header_pair(name: &str, value: &str) -> VclResult<(HeaderName, HeaderValue)> {
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|e| VclError::String(format!("Invalid header name {name:?}: {e}")))?;
+    let value = HeaderValue::from_str(value)
+        .map_err(|e| VclError::String(format!("Invalid value of header {name}: {e}")))?;
+    Ok((name, value))
+}
+
+impl TryFrom<&HttpHeaders<'_>> for HeaderMap {
+    type Error = VclError;
+
+    /// Copy the headers, keeping the repeated ones in order
+    fn try_from(headers: &HttpHeaders<'_>) -> Result<Self, Self::Error> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            let (name, value) = to_header_pair(name, value)?;
+            map.append(name, value);
+        }
+        Ok(map)
+    }
+}
+
+impl HttpHeaders<'_> {
+    /// Append all the headers of `map`. The values must be valid UTF-8.
+    pub fn extend_headers(&mut self, map: &HeaderMap) -> VclResult<()> {
+        for (name, value) in map {
+            let value = value
+                .to_str()
+                .map_err(|_| VclError::String(format!("Invalid value of header {name}")))?;
+            self.set_header(name.as_str(), value)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the headers named in `map` with their values in `map`, keeping the others
+    pub fn replace_headers(&mut self, map: &HeaderMap) -> VclResult<()> {
+        for name in map.keys() {
+            self.unset_header(name.as_str());
+        }
+        self.extend_headers(map)
+    }
+
+    /// A request builder with the method, URL, protocol and headers of this request. Invalid
+    /// values are reported by the builder, when the request is built.
+    pub fn to_request_builder(&self) -> http::request::Builder {
+        let mut builder = Request::builder();
+        if let Some(method) = self.method() {
+            builder = builder.method(method);
+        }
+        if let Some(url) = self.url() {
+            builder = builder.uri(url);
+        }
+        if let Some(version) = self.proto().and_then(version_from_proto) {
+            builder = builder.version(version);
+        }
+        self.iter().fold(builder, |builder, (name, value)| {
+            builder.header(name, value)
+        })
+    }
+
+    /// A response builder with the status, protocol and headers of this response. Invalid values
+    /// are reported by the builder, when the response is built.
+    pub fn to_response_builder(&self) -> http::response::Builder {
+        let mut builder = Response::builder();
+        if let Some(status) = self.status() {
+            builder = builder.status(status);
+        }
+        if let Some(version) = self.proto().and_then(version_from_proto) {
+            builder = builder.version(version);
+        }
+        self.iter().fold(builder, |builder, (name, value)| {
+            builder.header(name, value)
+        })
+    }
+
+    /// Copy the status, the protocol and the headers of `response` into this response, replacing
+    /// the headers with the same names. The reason is the default one for the status.
+    pub fn apply_response<T>(&mut self, response: &Response<T>) -> VclResult<()> {
+        self.set_status(response.status().as_u16());
+        if let Some(proto) = version_to_proto(response.version()) {
+            self.set_proto(proto)?;
+        }
+        self.replace_headers(response.headers())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_interop() {
+        for proto in ["HTTP/0.9", "HTTP/1.0", "HTTP/1.1", "HTTP/2.0"] {
+            let version = version_from_proto(proto).unwrap();
+            assert_eq!(version_to_proto(version), Some(proto));
+        }
+        assert_eq!(version_from_proto("HTTP/3"), None);
+        assert_eq!(version_to_proto(Version::HTTP_3), None);
+
+        let (name, value) = to_header_pair("X-Foo", "bar").unwrap();
+        assert_eq!((name.as_str(), value.to_str().unwrap()), ("x-foo", "bar"));
+        assert!(to_header_pair("bad name", "bar").is_err());
+        assert!(to_header_pair("x-foo", "line\nbreak").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! Share one static copy of each C string given to Varnish as a name
+//!
+//! Varnish keeps the pointers to the backend types and the filter names, i.e. `vdi_methods.type_`
+//! or `vfp.name`, for as long as the backend or the filter exists. An interned string lives until
+//! the process ends, and is only allocated once whatever the number of backends using it, so
+//! thousands of dynamic backends of the same type share a single copy. Interned strings with the
+//! same content are the same pointer, which makes comparing them by address reliable.
+
+use std::collections::BTreeSet;
+use std::ffi::{CStr, CString};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::vcl::{VclError, VclResult};
+
+static INTERNED: Mutex<BTreeSet<&'static CStr>> = Mutex::new(BTreeSet::new());
+
+fn interned() -> MutexGuard<'static, BTreeSet<&'static CStr>> {
+    // a panic while holding the lock does not corrupt the set
+    INTERNED
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// The interned copy of `s`, allocated on the first call with this content. Fails if `s`
+/// contains a NUL byte.
+pub fn intern(s: &str) -> VclResult<&'static CStr> {
+    let s = CString::new(s).map_err(|e| VclError::String(e.to_string()))?;
+    let mut set = interned();
+    if let Some(interned) = set.get(s.as_c_str()) {
+        return Ok(interned);
+    }
+    let interned: &'static CStr = Box::leak(s.into_boxed_c_str());
+    set.insert(interned);
+    Ok(interned)
+}
+
+/// The interned pointer for `s`: `s` itself for the first string with this content, without any
+/// allocation, or the string that came first, e.g. the same literal from another crate.
+pub fn intern_cstr(s: &'static CStr) -> &'static CStr {
+    let mut set = interned();
+    if let Some(interned) = set.get(s) {
+        return interned;
+    }
+    set.insert(s);
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intern_test() {
+        let a = intern("intern_test").unwrap();
+        assert_eq!(a, c"intern_test");
+        assert_eq!(intern("intern_test").unwrap().as_ptr(), a.as_ptr());
+
+        let other: &'static CStr =
+            Box::leak(CString::new("intern_test").unwrap().into_boxed_c_str());
+        assert_ne!(other.as_ptr(), a.as_ptr());
+        assert_eq!(intern_cstr(other).as_ptr(), a.as_ptr());
+
+        let lit = intern_cstr(c"intern_test_literal");
+        assert_eq!(
+            intern("intern_test_literal").unwrap().as_ptr(),
+            lit.as_ptr()
+        );
+
+        assert!(intern("intern\0test").is_err());
+    }
+}
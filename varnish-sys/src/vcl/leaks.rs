@@ -0,0 +1,180 @@
+//! Find the objects and shared states that outlive their VCL
+//!
+//! With the `leak-check` feature, the code generated by `#[vmod]` records every object, and every
+//! `#[shared_per_task]` and `#[shared_per_vcl]` value, while Varnish owns it. When a VCL is
+//! discarded, its event handler logs a `VCL_Error` record with the list of the types still alive
+//! for that VCL, e.g. a `PerVclState` that was never freed. Varnish discards the VMOD's own
+//! `PRIV_VCL` right after the event, so it is not reported.
+//!
+//! Varnish asserts that a VMOD never fails the `Discard` event, and a panic can't unwind through
+//! the C code calling the handler: either would bring `varnishd` down, so the leaks are only
+//! logged. The handler only panics in the unit tests of this crate.
+//!
+//! The feature is meant for tests, e.g. `varnish = { version = "...", features = ["leak-check"] }`
+//! in the `[dev-dependencies]` of a VMOD crate, or in the crate used by `run_vtc_tests!`. A VTC
+//! test loading a second VCL and running `vcl.discard` on the first one exercises the check,
+//! with a `logexpect -g raw` expecting no `VCL_Error` record starting with
+//! `Values still alive when discarding the VCL`. Without the feature, all functions of this
+//! module are no-ops.
+
+use std::ffi::c_void;
+
+use crate::ffi::{vmod_priv, vrt_ctx, VclEvent};
+use crate::vcl::Ctx;
+
+/// The two forms `__ctx` takes in the generated code
+#[doc(hidden)]
+pub trait VclOf {
+    /// The address of the VCL the context belongs to
+    ///
+    /// SAFETY: the context must be valid
+    unsafe fn vcl_addr(&self) -> usize;
+}
+
+impl VclOf for *mut vrt_ctx {
+    unsafe fn vcl_addr(&self) -> usize {
+        self.as_ref().map_or(0, |ctx| ctx.vcl.0 as usize)
+    }
+}
+
+impl VclOf for *const vrt_ctx {
+    unsafe fn vcl_addr(&self) -> usize {
+        self.as_ref().map_or(0, |ctx| ctx.vcl.0 as usize)
+    }
+}
+
+impl VclOf for Ctx<'_> {
+    unsafe fn vcl_addr(&self) -> usize {
+        self.raw.vcl.0 as usize
+    }
+}
+
+#[cfg(feature = "leak-check")]
+mod registry {
+    use std::collections::BTreeMap;
+    use std::sync::{Mutex, MutexGuard};
+
+    /// Address of the value -> (address of its VCL, type name)
+    static LIVE: Mutex<BTreeMap<usize, (usize, &'static str)>> = Mutex::new(BTreeMap::new());
+
+    pub fn live() -> MutexGuard<'static, BTreeMap<usize, (usize, &'static str)>> {
+        // a panic while holding the lock does not corrupt the map
+        LIVE.lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Record that Varnish now owns `ptr`, created for the VCL of `ctx`
+///
+/// SAFETY: `ctx` must be valid
+#[doc(hidden)]
+#[cfg_attr(not(feature = "leak-check"), expect(unused_variables))]
+pub unsafe fn track<T>(ctx: &impl VclOf, ptr: *const T) {
+    #[cfg(feature = "leak-check")]
+    if !ptr.is_null() {
+        let vcl = ctx.vcl_addr();
+        registry::live().insert(ptr as usize, (vcl, std::any::type_name::<T>()));
+    }
+}
+
+/// Record that `ptr` is no longer owned by Varnish, i.e. it was freed or handed back to Rust
+#[doc(hidden)]
+#[cfg_attr(not(feature = "leak-check"), expect(unused_variables))]
+pub fn untrack<T>(ptr: *const T) {
+    #[cfg(feature = "leak-check")]
+    registry::live().remove(&(ptr as usize));
+}
+
+/// The types still owned by Varnish for the VCL of `ctx`, with their number of instances.
+/// `except` is left out, e.g. the `PRIV_VCL` value that Varnish has yet to free.
+///
+/// SAFETY: `ctx` must be valid
+#[cfg_attr(not(feature = "leak-check"), expect(unused_variables))]
+pub unsafe fn live(ctx: &impl VclOf, except: *const c_void) -> Vec<(&'static str, usize)> {
+    #[cfg(feature = "leak-check")]
+    {
+        let vcl = ctx.vcl_addr();
+        let mut types = std::collections::BTreeMap::<&str, usize>::new();
+        for (ptr, (owner, ty)) in registry::live().iter() {
+            if *owner == vcl && *ptr != except as usize {
+                *types.entry(ty).or_default() += 1;
+            }
+        }
+        types.into_iter().collect()
+    }
+    #[cfg(not(feature = "leak-check"))]
+    Vec::new()
+}
+
+/// Called by the generated event handler after the user function
+///
+/// SAFETY: `ctx` and `vp` must be valid
+#[doc(hidden)]
+pub unsafe fn on_event(ctx: &impl VclOf, vp: *const vmod_priv, event: VclEvent) {
+    if !cfg!(feature = "leak-check") || event != VclEvent::Discard {
+        return;
+    }
+    let own = vp.as_ref().map_or(std::ptr::null_mut(), |vp| vp.priv_);
+    if let Some(msg) = report(&live(ctx, own)) {
+        #[cfg(test)]
+        panic!("{msg}");
+        #[cfg(not(test))]
+        crate::vcl::log(crate::vcl::LogTag::VclError, msg);
+    }
+}
+
+/// The message listing the values still alive, if any
+fn report(leaks: &[(&'static str, usize)]) -> Option<String> {
+    if leaks.is_empty() {
+        return None;
+    }
+    let list = leaks
+        .iter()
+        .map(|(ty, count)| format!("{count} x {ty}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(format!(
+        "Values still alive when discarding the VCL: {list}"
+    ))
+}
+
+#[cfg(all(test, feature = "leak-check"))]
+mod tests {
+    use std::ptr::{from_mut, from_ref, null};
+
+    use super::*;
+    use crate::ffi::VCL_VCL;
+
+    #[test]
+    fn leaks() {
+        let mut ctx_a = vrt_ctx {
+            vcl: VCL_VCL(0x10 as _),
+            ..vrt_ctx::default()
+        };
+        let mut ctx_b = vrt_ctx {
+            vcl: VCL_VCL(0x20 as _),
+            ..vrt_ctx::default()
+        };
+        let (ctx_a, ctx_b) = (from_mut(&mut ctx_a), from_mut(&mut ctx_b));
+        let (one, two, three) = (Box::new(1_u8), Box::new(2_u8), Box::new(3_u16));
+        let (one, two, three) = (from_ref(&*one), from_ref(&*two), from_ref(&*three));
+        unsafe {
+            track(&ctx_a, one);
+            track(&ctx_a, two);
+            track(&ctx_a, three);
+            track(&ctx_b, three);
+            assert_eq!(live(&ctx_a, null()), [("u8", 2)]);
+            assert_eq!(live(&ctx_b, null()), [("u16", 1)]);
+            untrack(one);
+            assert_eq!(live(&ctx_a, two.cast()), []);
+            on_event(&ctx_a, null(), VclEvent::Cold);
+            untrack(two);
+            untrack(three);
+            on_event(&ctx_b, null(), VclEvent::Discard);
+        }
+        assert_eq!(
+            report(&[("u8", 2), ("u16", 1)]).unwrap(),
+            "Values still alive when discarding the VCL: 2 x u8, 1 x u16"
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! Limit the size of the bodies delivered to the clients
+//!
+//! A misconfigured backend can send a body far larger than expected, e.g. a log file instead of a
+//! page. A [`MaxSizeDeliver`] stops the delivery once the limit given by a [`MaxSize`] is reached,
+//! either truncating the body or aborting the delivery:
+//!
+//! ``` rust,no_run
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use std::ffi::CStr;
+//! use std::sync::OnceLock;
+//! use varnish::vcl::{Ctx, DeliveryFilters, Event, MaxSize, MaxSizeCounters, MaxSizeDeliver};
+//!
+//! static COUNTERS: OnceLock<MaxSizeCounters> = OnceLock::new();
+//!
+//! struct TenMegs;
+//!
+//! impl MaxSize for TenMegs {
+//!     fn name() -> &'static CStr {
+//!         c"maxsize"
+//!     }
+//!
+//!     fn limit(_ctx: &Ctx) -> Option<u64> {
+//!         Some(10 * 1024 * 1024)
+//!     }
+//!
+//!     fn counters() -> Option<&'static MaxSizeCounters> {
+//!         COUNTERS.get()
+//!     }
+//! }
+//!
+//! // e.g. the body of the `#[event]` function of a vmod, then use
+//! // `set resp.filters = resp.filters + " maxsize";` in `vcl_deliver`
+//! fn event(event: Event, vdp: &mut DeliveryFilters) {
+//!     if let Event::Load = event {
+//!         if let Ok(counters) = MaxSizeCounters::new("mymod", "maxsize") {
+//!             COUNTERS.get_or_init(|| counters);
+//!         }
+//!         vdp.register::<MaxSizeDeliver<TenMegs>>();
+//!     }
+//! }
+//! ```
+//!
+//! When the length of the body is known in advance, i.e. when the response has a
+//! `Content-Length`, an oversized body is detected before anything is sent: a truncated response
+//! gets the reduced `Content-Length`, and the header named by [`MaxSize::marker()`] carrying the
+//! original length, while an aborted delivery fails before the headers are sent. Otherwise, the
+//! body is cut when the limit is reached: a truncated body ends normally but without any marker,
+//! as the headers are already sent, while an aborted delivery closes the connection in the middle
+//! of the body, so that the client can tell that it is incomplete.
+
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ffi::VdpAction;
+use crate::vcl::{
+    Ctx, DeliveryProcCtx, DeliveryProcessor, InitResult, PushResult, VclError, VscField, VscSegment,
+};
+
+/// What a [`MaxSizeDeliver`] does with a body over the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Deliver the beginning of the body, up to the limit
+    Truncate,
+    /// Fail the delivery
+    Abort,
+}
+
+/// Describes the limit enforced by a [`MaxSizeDeliver`]
+pub trait MaxSize {
+    /// The name of the processor, as used in `resp.filters`.
+    fn name() -> &'static CStr;
+    /// The largest body delivered, in bytes. Returning `None` removes the processor from the
+    /// pipeline.
+    fn limit(ctx: &Ctx) -> Option<u64>;
+    /// What to do with the bodies over the limit, [`Overflow::Abort`] by default
+    fn overflow(_ctx: &Ctx) -> Overflow {
+        Overflow::Abort
+    }
+    /// The header set on the responses truncated before being sent, with their original length
+    fn marker() -> &'static str {
+        "X-Body-Truncated"
+    }
+    /// Where to count the deliveries, if anywhere
+    fn counters() -> Option<&'static MaxSizeCounters> {
+        None
+    }
+}
+
+/// The counters of a [`MaxSize`], published as `vmod.<vmod>.<instance>.limited`, `.truncated`,
+/// and `.aborted`
+#[derive(Debug)]
+pub struct MaxSizeCounters(VscSegment);
+
+impl MaxSizeCounters {
+    const LIMITED: usize = 0;
+    const TRUNCATED: usize = 1;
+    const ABORTED: usize = 2;
+
+    /// Publish the counters, see [`VscSegment::new()`]
+    pub fn new(vmod: &str, instance: &str) -> Result<Self, VclError> {
+        VscSegment::new(
+            vmod,
+            instance,
+            "Limit of the size of the delivered bodies",
+            vec![
+                VscField::counter("limited", "Deliveries checked against the limit"),
+                VscField::counter("truncated", "Bodies truncated to the limit"),
+                VscField::counter("aborted", "Deliveries aborted for exceeding the limit"),
+            ],
+        )
+        .map(Self)
+    }
+
+    fn value(&self, idx: usize) -> &AtomicU64 {
+        &self.0.values()[idx]
+    }
+
+    /// The number of deliveries checked against the limit
+    pub fn limited(&self) -> u64 {
+        self.value(Self::LIMITED).load(Ordering::Relaxed)
+    }
+
+    /// The number of bodies truncated
+    pub fn truncated(&self) -> u64 {
+        self.value(Self::TRUNCATED).load(Ordering::Relaxed)
+    }
+
+    /// The number of deliveries aborted
+    pub fn aborted(&self) -> u64 {
+        self.value(Self::ABORTED).load(Ordering::Relaxed)
+    }
+}
+
+fn count<M: MaxSize>(idx: usize) {
+    if let Some(counters) = M::counters() {
+        counters.value(idx).fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A ready-made [`DeliveryProcessor`] limiting the size of the body to the one given by a
+/// [`MaxSize`], see the [module documentation](self)
+#[derive(Debug)]
+pub struct MaxSizeDeliver<M: MaxSize> {
+    window: Window,
+    overflow: Overflow,
+    /// The body was already counted as truncated, its length being known
+    counted: bool,
+    /// The end of the body was already pushed
+    done: bool,
+    _max_size: PhantomData<M>,
+}
+
+impl<M: MaxSize> DeliveryProcessor for MaxSizeDeliver<M> {
+    fn name() -> &'static CStr {
+        M::name()
+    }
+
+    fn new(vrt_ctx: &mut Ctx, _: &mut DeliveryProcCtx) -> InitResult<Self> {
+        let Some(limit) = M::limit(vrt_ctx) else {
+            return InitResult::Pass;
+        };
+        let overflow = M::overflow(vrt_ctx);
+        count::<M>(MaxSizeCounters::LIMITED);
+
+        let Some(req) = (unsafe { vrt_ctx.raw.req.as_mut() }) else {
+            return InitResult::Pass;
+        };
+        let Ok(len) = u64::try_from(req.resp_len) else {
+            // unknown length, watch the body as it goes
+            return InitResult::Ok(Self {
+                window: Window::new(limit),
+                overflow,
+                counted: false,
+                done: false,
+                _max_size: PhantomData,
+            });
+        };
+        if len <= limit {
+            return InitResult::Pass;
+        }
+        if overflow == Overflow::Abort {
+            count::<M>(MaxSizeCounters::ABORTED);
+            return InitResult::Err(VclError::String(format!(
+                "{}: the body is {len} bytes long, over the limit of {limit}",
+                M::name().to_string_lossy()
+            )));
+        }
+        req.resp_len = limit as i64;
+        if let Some(resp) = vrt_ctx.resp_mut() {
+            resp.unset_header("Content-Length");
+            resp.unset_header(M::marker());
+            if let Err(e) = resp
+                .set_header("Content-Length", &limit.to_string())
+                .and_then(|()| resp.set_header(M::marker(), &len.to_string()))
+            {
+                return InitResult::Err(e);
+            }
+        }
+        count::<M>(MaxSizeCounters::TRUNCATED);
+        InitResult::Ok(Self {
+            window: Window::new(limit),
+            overflow,
+            counted: true,
+            done: false,
+            _max_size: PhantomData,
+        })
+    }
+
+    fn push(&mut self, ctx: &mut DeliveryProcCtx, act: VdpAction, buf: &[u8]) -> PushResult {
+        if self.done {
+            return PushResult::End;
+        }
+        let Some(fits) = self.window.overflow_at(buf.len()) else {
+            return ctx.push(act, buf);
+        };
+        match self.overflow {
+            Overflow::Abort => {
+                count::<M>(MaxSizeCounters::ABORTED);
+                PushResult::Err
+            }
+            Overflow::Truncate => {
+                if !self.counted {
+                    count::<M>(MaxSizeCounters::TRUNCATED);
+                }
+                self.done = true;
+                match ctx.push(VdpAction::End, &buf[..fits]) {
+                    PushResult::Err => PushResult::Err,
+                    PushResult::Ok | PushResult::End => PushResult::End,
+                }
+            }
+        }
+    }
+}
+
+/// Counts the bytes delivered against the limit
+#[derive(Debug)]
+struct Window {
+    limit: u64,
+    sent: u64,
+}
+
+impl Window {
+    fn new(limit: u64) -> Self {
+        Self { limit, sent: 0 }
+    }
+
+    /// Account for `len` more bytes: `None` if they all fit, or how many of them do
+    fn overflow_at(&mut self, len: usize) -> Option<usize> {
+        let room = self.limit - self.sent;
+        if len as u64 <= room {
+            self.sent += len as u64;
+            return None;
+        }
+        self.sent = self.limit;
+        Some(room as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window() {
+        let mut window = Window::new(10);
+        assert_eq!(window.overflow_at(4), None);
+        assert_eq!(window.overflow_at(0), None);
+        assert_eq!(window.overflow_at(6), None);
+        assert_eq!(window.overflow_at(0), None);
+        assert_eq!(window.overflow_at(1), Some(0));
+
+        let mut window = Window::new(10);
+        assert_eq!(window.overflow_at(8), None);
+        assert_eq!(window.overflow_at(5), Some(2));
+        assert_eq!(window.overflow_at(5), Some(0));
+
+        let mut window = Window::new(0);
+        assert_eq!(window.overflow_at(0), None);
+        assert_eq!(window.overflow_at(1), Some(0));
+    }
+}
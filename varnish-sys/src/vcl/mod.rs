@@ -1,25 +1,106 @@
+mod abi;
 #[cfg(not(varnishsys_6))]
 mod backend;
+mod backend_ref;
+mod ban;
+#[cfg(feature = "bench")]
+mod bench_log;
+mod budget;
+#[cfg(not(varnishsys_6))]
+mod capture;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+#[cfg(not(varnishsys_6))]
+mod client_cert;
 mod convert;
 mod ctx;
+mod ctx_view;
+mod enums;
 mod error;
-mod http;
+mod global;
+mod global_state;
+mod handling;
+#[cfg(not(varnishsys_6))]
+mod health;
+pub mod http;
+#[cfg(feature = "http")]
+mod http_interop;
+mod intern;
+pub mod leaks;
+#[cfg(not(varnishsys_6))]
+mod max_size;
+#[cfg(feature = "ipnet")]
+mod net;
+mod priv_task;
 mod probe;
 #[cfg(not(varnishsys_6))]
 mod processor;
+#[cfg(not(varnishsys_6))]
+mod protocol;
+mod record;
+mod singleflight;
+pub mod sniff;
+mod strands;
+mod string_table;
+mod task_local;
+mod task_memo;
+#[cfg(not(varnishsys_6))]
+mod template;
+pub mod time;
+mod vmod_info;
 mod vsb;
+#[cfg(not(varnishsys_6))]
+mod vsc_wrapper;
 mod ws;
+mod ws_map;
 
+pub use abi::*;
 #[cfg(not(varnishsys_6))]
 pub use backend::*;
+pub use backend_ref::*;
+pub use ban::*;
+#[cfg(feature = "bench")]
+pub use bench_log::*;
+pub use budget::*;
+#[cfg(not(varnishsys_6))]
+pub use capture::*;
+#[cfg(not(varnishsys_6))]
+pub use client_cert::*;
 pub use convert::*;
 pub use ctx::*;
+pub use ctx_view::*;
+pub use enums::*;
 pub use error::*;
+pub use global::*;
+pub use global_state::*;
+pub use handling::*;
+#[cfg(not(varnishsys_6))]
+pub use health::*;
 pub use http::*;
+pub use intern::*;
+#[cfg(not(varnishsys_6))]
+pub use max_size::*;
+#[cfg(feature = "ipnet")]
+pub use net::*;
+pub use priv_task::*;
 pub use probe::*;
 #[cfg(not(varnishsys_6))]
 pub use processor::*;
+#[cfg(not(varnishsys_6))]
+pub use protocol::*;
+pub use record::*;
+pub use singleflight::*;
+pub use strands::*;
+pub use string_table::*;
+pub use task_local::*;
+pub use task_memo::*;
+#[cfg(not(varnishsys_6))]
+pub use template::*;
+pub use vmod_info::*;
 pub use vsb::*;
+#[cfg(not(varnishsys_6))]
+pub use vsc_wrapper::*;
 pub use ws::*;
+pub use ws_map::*;
 
 pub use crate::ffi::{VclEvent as Event, VslTag as LogTag};
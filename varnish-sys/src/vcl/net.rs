@@ -0,0 +1,326 @@
+//! Match IP addresses against networks, without caring about ports
+//!
+//! VCL ACLs are compiled along with the VCL, [`IpNetList`] covers the networks that are only known
+//! at runtime, e.g. passed as a string to an object constructor. Functions taking an
+//! `Option<IpAddr>` argument receive `client.ip` and friends without their port:
+//!
+//! ``` rust
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use std::net::IpAddr;
+//! use varnish::vcl::IpNetList;
+//!
+//! let internal: IpNetList = "10.0.0.0/8, 192.168.0.0/16 ::1".parse().unwrap();
+//!
+//! // e.g. the body of `pub fn is_internal(&self, ip: Option<IpAddr>) -> bool` in a vmod object
+//! let ip: Option<IpAddr> = Some("192.168.1.20".parse().unwrap());
+//! assert!(ip.is_some_and(|ip| internal.contains(ip)));
+//! assert!(!internal.contains("8.8.8.8".parse().unwrap()));
+//! ```
+//!
+//! [`Acl`] follows the rules of VCL ACLs instead: entries may be negated, and the most specific
+//! entry matching an address decides, whatever their order.
+//!
+//! ``` rust
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::Acl;
+//!
+//! // e.g. read from a file by an object constructor
+//! let acl: Acl = "10.0.0.0/8 !10.1.0.0/16 10.1.2.3".parse().unwrap();
+//! assert!(acl.contains("10.2.0.1".parse().unwrap()));
+//! assert!(!acl.contains("10.1.0.1".parse().unwrap()));
+//! assert!(acl.contains("10.1.2.3".parse().unwrap()));
+//! ```
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+pub use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+
+use crate::vcl::{BanBuilder, StrOp, VclError};
+
+/// A list of networks, matched in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpNetList(Vec<IpNet>);
+
+impl IpNetList {
+    /// Create a list from networks
+    pub fn new(nets: Vec<IpNet>) -> Self {
+        Self(nets)
+    }
+
+    /// The networks of the list
+    pub fn nets(&self) -> &[IpNet] {
+        &self.0
+    }
+
+    /// Check if `ip` is part of any of the networks.
+    ///
+    /// IPv4-mapped IPv6 addresses, e.g. `::ffff:10.0.0.1`, match IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+}
+
+impl From<Vec<IpNet>> for IpNetList {
+    fn from(nets: Vec<IpNet>) -> Self {
+        Self(nets)
+    }
+}
+
+impl FromStr for IpNetList {
+    type Err = VclError;
+
+    /// Parse networks separated by commas or whitespace, like `10.0.0.0/8, ::1`. Addresses without
+    /// a prefix length are single hosts.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                v.parse::<IpNet>()
+                    .or_else(|_| v.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| VclError::String(format!("Invalid network: {v:?}")))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+    }
+}
+
+/// Parse a network, or a single host
+fn parse_net(v: &str) -> Result<IpNet, VclError> {
+    v.parse::<IpNet>()
+        .or_else(|_| v.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| VclError::String(format!("Invalid network: {v:?}")))
+}
+
+/// Build an [`Acl`] from allowed and denied networks
+///
+/// Host bits set in a network are ignored, e.g. `10.1.2.3/8` is `10.0.0.0/8`.
+#[derive(Debug, Clone, Default)]
+pub struct AclBuilder {
+    entries: Vec<(IpNet, bool)>,
+}
+
+impl AclBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a network matching the ACL, like `"10.0.0.0"/8;` in VCL
+    #[must_use]
+    pub fn allow(mut self, net: impl Into<IpNet>) -> Self {
+        self.entries.push((net.into().trunc(), true));
+        self
+    }
+
+    /// Add a network excluded from the ACL, like `! "10.0.0.0"/8;` in VCL
+    #[must_use]
+    pub fn deny(mut self, net: impl Into<IpNet>) -> Self {
+        self.entries.push((net.into().trunc(), false));
+        self
+    }
+
+    /// Build the ACL. Fails if a network is both allowed and denied.
+    pub fn build(self) -> Result<Acl, VclError> {
+        let mut groups: Vec<(u8, bool, HashMap<IpAddr, bool>)> = Vec::new();
+        let mut entries: Vec<(IpNet, bool)> = Vec::with_capacity(self.entries.len());
+        for (net, allow) in self.entries {
+            let is_v6 = net.addr().is_ipv6();
+            let idx = groups
+                .iter()
+                .position(|(len, v6, _)| *len == net.prefix_len() && *v6 == is_v6)
+                .unwrap_or_else(|| {
+                    groups.push((net.prefix_len(), is_v6, HashMap::new()));
+                    groups.len() - 1
+                });
+            let group = &mut groups[idx].2;
+            if let Some(prev) = group.insert(net.addr(), allow) {
+                if prev != allow {
+                    return Err(VclError::String(format!(
+                        "Network {net} is both allowed and denied"
+                    )));
+                }
+            } else {
+                entries.push((net, allow));
+            }
+        }
+        // the most specific networks are looked up first
+        groups.sort_by_key(|(len, _, _)| Reverse(*len));
+        Ok(Acl {
+            entries,
+            groups: groups
+                .into_iter()
+                .map(|(len, _, nets)| (len, nets))
+                .collect(),
+        })
+    }
+}
+
+/// An ACL built at runtime, see the [module documentation](self)
+///
+/// Matching an address costs one hash lookup per distinct prefix length of the ACL, however many
+/// entries it has.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    entries: Vec<(IpNet, bool)>,
+    groups: Vec<(u8, HashMap<IpAddr, bool>)>,
+}
+
+impl Acl {
+    /// The entries of the ACL, in insertion order, without duplicates. `true` entries are allowed.
+    pub fn entries(&self) -> &[(IpNet, bool)] {
+        &self.entries
+    }
+
+    /// The most specific entry containing `ip`, if any
+    ///
+    /// IPv4-mapped IPv6 addresses, e.g. `::ffff:10.0.0.1`, match IPv4 networks.
+    pub fn matching(&self, ip: IpAddr) -> Option<(IpNet, bool)> {
+        let ip = ip.to_canonical();
+        self.groups.iter().find_map(|(len, nets)| {
+            let net = IpNet::new(ip, *len).ok()?.trunc();
+            nets.get(&net.addr()).map(|allow| (net, *allow))
+        })
+    }
+
+    /// Check if `ip` is matched by the ACL, i.e. if its most specific entry is allowed
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.matching(ip).is_some_and(|(_, allow)| allow)
+    }
+
+    /// Build a ban matching the objects whose `obj.http.<header>` holds an address matched by the
+    /// ACL, e.g. a header storing `client.ip` set in `vcl_backend_response`.
+    ///
+    /// The header is matched with regular expressions, so this only works for IPv4 entries, and
+    /// for ACLs where no allowed network is inside a denied one.
+    pub fn to_ban(&self, header: &str) -> Result<BanBuilder, VclError> {
+        let mut allowed = Vec::new();
+        let mut denied = Vec::new();
+        for (net, allow) in &self.entries {
+            let IpNet::V4(net) = net else {
+                return Err(VclError::String(format!(
+                    "IPv6 network {net} cannot be used in a ban"
+                )));
+            };
+            if *allow {
+                allowed.push(*net);
+            } else {
+                denied.push(*net);
+            }
+        }
+        if allowed.is_empty() {
+            return Err(VclError::CStr(c"The ACL does not allow any network"));
+        }
+        for net in &allowed {
+            if let Some(outer) = denied.iter().find(|d| d.contains(net)) {
+                return Err(VclError::String(format!(
+                    "Allowed network {net} is inside denied network {outer}, it cannot be used in a ban"
+                )));
+            }
+        }
+        let ban = BanBuilder::new().obj_http(header, StrOp::Match, &ipv4_regex(&allowed));
+        Ok(if denied.is_empty() {
+            ban
+        } else {
+            ban.obj_http(header, StrOp::NoMatch, &ipv4_regex(&denied))
+        })
+    }
+}
+
+impl FromStr for Acl {
+    type Err = VclError;
+
+    /// Parse entries separated by commas or whitespace, like `10.0.0.0/8, !10.1.0.0/16`. Denied
+    /// entries start with `!`, and addresses without a prefix length are single hosts.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|v| !v.is_empty())
+            .try_fold(AclBuilder::new(), |acl, v| -> Result<_, VclError> {
+                Ok(match v.strip_prefix('!') {
+                    Some(v) => acl.deny(parse_net(v)?),
+                    None => acl.allow(parse_net(v)?),
+                })
+            })?
+            .build()
+    }
+}
+
+/// A regular expression matching the textual form of the addresses of any of the networks
+fn ipv4_regex(nets: &[Ipv4Net]) -> String {
+    let mut regex = String::from("^(?:");
+    for (idx, net) in nets.iter().enumerate() {
+        if idx > 0 {
+            regex.push('|');
+        }
+        let octets = net.addr().octets();
+        let len = usize::from(net.prefix_len());
+        for (i, octet) in octets.iter().enumerate() {
+            if i > 0 {
+                regex.push_str("\\.");
+            }
+            if (i + 1) * 8 <= len {
+                let _ = write!(regex, "{octet}");
+            } else if i * 8 >= len {
+                regex.push_str("[0-9]{1,3}");
+            } else {
+                let count = 1u16 << ((i + 1) * 8 - len);
+                let values: Vec<String> = (0..count)
+                    .map(|v| (u16::from(*octet) + v).to_string())
+                    .collect();
+                let _ = write!(regex, "(?:{})", values.join("|"));
+            }
+        }
+    }
+    regex.push_str(")$");
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acl() {
+        let acl: Acl = "10.0.0.0/8, !10.1.0.0/16 10.1.2.3 10.1.2.3/32 !::/0 2001:db8::/32"
+            .parse()
+            .unwrap();
+        assert_eq!(acl.entries().len(), 5);
+        for ip in ["10.0.0.1", "10.1.2.3", "::ffff:10.2.0.1", "2001:db8::1"] {
+            assert!(acl.contains(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["10.1.0.1", "11.0.0.1", "2001:db9::1"] {
+            assert!(!acl.contains(ip.parse().unwrap()), "{ip}");
+        }
+        assert_eq!(
+            acl.matching("10.1.9.9".parse().unwrap()),
+            Some(("10.1.0.0/16".parse().unwrap(), false))
+        );
+        assert!("10.0.0.0/8 !10.9.9.9/8".parse::<Acl>().is_err());
+        assert!(acl.to_ban("x-ip").is_err());
+
+        let acl: Acl = "192.168.0.0/23 10.0.0.0/8 !10.1.0.0/16".parse().unwrap();
+        assert_eq!(
+            acl.to_ban("x-ip").unwrap().build().unwrap(),
+            r#"obj.http.x-ip ~ "^(?:192\\.168\\.(?:0|1)\\.[0-9]{1,3}|10\\.[0-9]{1,3}\\.[0-9]{1,3}\\.[0-9]{1,3})$" && obj.http.x-ip !~ "^(?:10\\.1\\.[0-9]{1,3}\\.[0-9]{1,3})$""#
+        );
+        let acl: Acl = "!10.0.0.0/8 10.1.0.0/16".parse().unwrap();
+        assert!(acl.to_ban("x-ip").is_err());
+    }
+
+    #[test]
+    fn net_list() {
+        let list: IpNetList = "10.0.0.0/8,\t2001:db8::/32 127.0.0.1".parse().unwrap();
+        assert_eq!(list.nets().len(), 3);
+        for ip in ["10.1.2.3", "2001:db8::1", "127.0.0.1", "::ffff:10.0.0.1"] {
+            assert!(list.contains(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["11.0.0.1", "2001:db9::1", "127.0.0.2"] {
+            assert!(!list.contains(ip.parse().unwrap()), "{ip}");
+        }
+        assert!("10.0.0.0/33".parse::<IpNetList>().is_err());
+        assert_eq!("".parse::<IpNetList>().unwrap(), IpNetList::default());
+    }
+}
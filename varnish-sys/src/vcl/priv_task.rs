@@ -0,0 +1,169 @@
+//! Per-task state for libraries built on top of a vmod
+//!
+//! The `PRIV_TASK` of `#[shared_per_task]` belongs to the vmod. A library used by vmods, e.g. a
+//! tracing layer or a key-value store, needs its own per-task state, without knowing which vmod
+//! it runs in. [`Ctx::priv_task_for()`] gives it a separate slot, keyed by the address of one of
+//! its `static`s, so that it can't collide with the state of the vmod or of another library:
+//!
+//! ``` rust,no_run
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::{Ctx, VclError};
+//!
+//! #[derive(Default)]
+//! struct Spans(Vec<String>);
+//!
+//! static SPANS_KEY: u8 = 0;
+//!
+//! fn enter(ctx: &mut Ctx, name: &str) -> Result<(), VclError> {
+//!     let mut slot = ctx.priv_task_for(&SPANS_KEY)?;
+//!     slot.get_or_insert_with(Spans::default)?.0.push(name.to_string());
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The value is dropped by Varnish at the end of the task. The key should not be a zero-sized
+//! `static`, as these may share their address.
+
+use std::any::Any;
+use std::ffi::c_void;
+use std::ptr;
+
+use crate::ffi;
+use crate::ffi::vmod_priv;
+#[cfg(not(varnishsys_6))]
+use crate::ffi::{vmod_priv_methods, VMOD_PRIV_METHODS_MAGIC};
+use crate::vcl::{Ctx, VclError};
+
+/// The type of the values stored by [`PrivTask`]
+type AnyBox = Box<dyn Any + Send>;
+
+#[cfg(not(varnishsys_6))]
+static PRIV_METHODS: vmod_priv_methods = vmod_priv_methods {
+    magic: VMOD_PRIV_METHODS_MAGIC,
+    type_: c"PrivTask".as_ptr(),
+    fini: Some(vmod_priv::on_fini::<AnyBox>),
+};
+
+impl Ctx<'_> {
+    /// The task slot identified by the address of `key`, to keep state for a library rather than
+    /// for the vmod
+    ///
+    /// Fails outside of a task, or if the slot is already used by something else than a
+    /// [`PrivTask`], e.g. a [`TaskLocal`](crate::vcl::TaskLocal) using the same `static`.
+    pub fn priv_task_for<K: ?Sized>(&mut self, key: &'static K) -> Result<PrivTask<'_>, VclError> {
+        let key = ptr::from_ref(key).cast::<c_void>();
+        let priv_ = unsafe { ffi::VRT_priv_task(self.raw, key).as_mut() }.ok_or(VclError::CStr(
+            c"priv_task_for: unable to allocate the task storage",
+        ))?;
+        if !priv_.priv_.is_null() && !is_priv_task(priv_) {
+            return Err(VclError::CStr(
+                c"priv_task_for: the key is already used for another kind of task state",
+            ));
+        }
+        Ok(PrivTask { priv_ })
+    }
+}
+
+#[cfg(not(varnishsys_6))]
+fn is_priv_task(priv_: &vmod_priv) -> bool {
+    ptr::eq(priv_.methods, &raw const PRIV_METHODS)
+}
+
+#[cfg(varnishsys_6)]
+fn is_priv_task(priv_: &vmod_priv) -> bool {
+    priv_.free.map(|f| f as usize) == Some(vmod_priv::on_fini::<AnyBox> as usize)
+}
+
+/// A slot of the current task holding a value of any type, see the [module documentation](self)
+#[derive(Debug)]
+pub struct PrivTask<'c> {
+    priv_: &'c mut vmod_priv,
+}
+
+impl PrivTask<'_> {
+    fn value(&self) -> Option<&AnyBox> {
+        // `priv_task_for()` checked that the slot is empty or holds an `AnyBox`
+        unsafe { self.priv_.get_ref::<AnyBox>() }
+    }
+
+    /// Whether the slot holds a value, of any type
+    pub fn is_set(&self) -> bool {
+        self.value().is_some()
+    }
+
+    /// The value, if there is one of type `T`
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.value()?.downcast_ref()
+    }
+
+    /// The value, for modification, if there is one of type `T`
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        unsafe { self.priv_.as_box_mut::<AnyBox>() }
+            .as_mut()?
+            .downcast_mut()
+    }
+
+    /// Store `value`, dropping the previous value, whatever its type
+    pub fn insert<T: Any + Send>(&mut self, value: T) -> &mut T {
+        drop(unsafe { self.priv_.take::<AnyBox>() });
+        let value: Box<AnyBox> = Box::new(Box::new(value));
+        unsafe {
+            #[cfg(not(varnishsys_6))]
+            self.priv_.put(value, &PRIV_METHODS);
+            #[cfg(varnishsys_6)]
+            self.priv_.put(value, Some(vmod_priv::on_fini::<AnyBox>));
+        }
+        self.get_mut().unwrap()
+    }
+
+    /// The value, created with `init` if the slot is empty
+    ///
+    /// Fails if the slot holds a value of another type.
+    pub fn get_or_insert_with<T: Any + Send>(
+        &mut self,
+        init: impl FnOnce() -> T,
+    ) -> Result<&mut T, VclError> {
+        if self.get::<T>().is_some() {
+            return Ok(self.get_mut().unwrap());
+        }
+        if self.is_set() {
+            return Err(VclError::CStr(
+                c"priv_task_for: the slot holds a value of another type",
+            ));
+        }
+        Ok(self.insert(init()))
+    }
+
+    /// Remove the value and return it, if there is one of type `T`
+    pub fn take<T: Any + Send>(&mut self) -> Option<T> {
+        self.get::<T>()?;
+        let value = unsafe { self.priv_.take::<AnyBox>() }?;
+        value.downcast().ok().map(|v| *v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priv_task() {
+        let mut raw = vmod_priv::default();
+        let mut slot = PrivTask { priv_: &mut raw };
+        assert!(!slot.is_set());
+        assert_eq!(slot.get::<u32>(), None);
+
+        *slot.get_or_insert_with(|| 1_u32).unwrap() += 1;
+        assert_eq!(slot.get::<u32>(), Some(&2));
+        assert!(slot.get_or_insert_with(String::new).is_err());
+        assert_eq!(slot.take::<String>(), None);
+        assert_eq!(slot.take::<u32>(), Some(2));
+        assert!(!slot.is_set());
+
+        slot.insert(String::from("a"));
+        slot.insert(3_u64);
+        assert_eq!(slot.get::<String>(), None);
+        assert!(is_priv_task(slot.priv_));
+        assert_eq!(slot.take::<u64>(), Some(3));
+    }
+}
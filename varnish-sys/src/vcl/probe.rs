@@ -26,6 +26,118 @@ pub struct Probe<T = String> {
 
 pub type CowProbe<'a> = Probe<Cow<'a, str>>;
 
+/// Build the raw text of a probe request, for [`Request::Text`]
+///
+/// Varnish sends [`Request::Text`] verbatim, so a missing `\r\n` or a stray newline in a header
+/// value silently breaks the health check. The builder validates each part, and terminates the
+/// request properly. Unless set otherwise, the method is `GET` and a `Connection: close` header
+/// is added, as Varnish reads the response until the connection is closed. Note that Varnish only
+/// checks the status of the response, see [`Probe::exp_status`].
+///
+/// ```
+/// # mod varnish { pub use varnish_sys::vcl; }
+/// use varnish::vcl::{ProbeRequest, Request};
+///
+/// let request = ProbeRequest::new("/health")
+///     .method("HEAD")
+///     .header("Host", "example.com")
+///     .build()
+///     .unwrap();
+/// assert_eq!(
+///     request,
+///     "HEAD /health HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n"
+/// );
+/// assert!(ProbeRequest::new("/").header("x-bad", "a\r\nb").build().is_err());
+/// let _: Request<String> = ProbeRequest::new("/").to_request().unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl ProbeRequest {
+    /// A `GET` request for `url`
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            method: "GET".to_string(),
+            url: url.into(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Set the method of the request
+    #[must_use]
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.method = method.into();
+        self
+    }
+
+    /// Add a header. Headers are sent in the order they are added.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Validate the request, and serialize it
+    pub fn build(&self) -> Result<String, VclError> {
+        if !is_token(&self.method) {
+            return Err(VclError::new(format!(
+                "Invalid probe request method `{}`",
+                self.method.escape_debug()
+            )));
+        }
+        if self.url.is_empty() || self.url.bytes().any(|c| c <= b' ' || c == 0x7f) {
+            return Err(VclError::new(format!(
+                "Invalid probe request URL `{}`",
+                self.url.escape_debug()
+            )));
+        }
+        let mut text = format!("{} {} HTTP/1.1\r\n", self.method, self.url);
+        for (name, value) in &self.headers {
+            if !is_token(name) {
+                return Err(VclError::new(format!(
+                    "Invalid probe request header name `{}`",
+                    name.escape_debug()
+                )));
+            }
+            if value.bytes().any(|c| (c < b' ' && c != b'\t') || c == 0x7f) {
+                return Err(VclError::new(format!(
+                    "Invalid value for the probe request header `{name}`"
+                )));
+            }
+            text.push_str(name);
+            text.push_str(": ");
+            text.push_str(value.trim());
+            text.push_str("\r\n");
+        }
+        if !self
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        {
+            text.push_str("Connection: close\r\n");
+        }
+        text.push_str("\r\n");
+        Ok(text)
+    }
+
+    /// Validate the request, and turn it into a [`Request::Text`]
+    pub fn to_request(&self) -> Result<Request<String>, VclError> {
+        Ok(Request::Text(self.build()?))
+    }
+}
+
+/// Check that `value` is an HTTP token, as used for methods and header names
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+}
+
 impl CowProbe<'_> {
     pub fn to_owned(&self) -> Probe {
         Probe {
@@ -73,9 +185,10 @@ pub(crate) fn into_vcl_probe<T: AsRef<str>>(
 /// Helper to convert a VCL probe into a Rust probe wrapper
 pub(crate) fn from_vcl_probe<'a, T: From<Cow<'a, str>>>(value: VCL_PROBE) -> Option<Probe<T>> {
     let pr = unsafe { value.0.as_ref()? };
-    assert!(
-        (pr.url.is_null() && !pr.request.is_null()) || pr.request.is_null() && !pr.url.is_null()
-    );
+    // exactly one of them is set by VCL, anything else is not a usable probe
+    if pr.url.is_null() == pr.request.is_null() {
+        return None;
+    }
     Some(Probe {
         request: if pr.url.is_null() {
             Request::Text(from_str(pr.request).into())
@@ -100,3 +213,26 @@ fn from_str<'a>(value: *const c_char) -> Cow<'a, str> {
         unsafe { CStr::from_ptr(value).to_string_lossy() }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_request() {
+        let req = ProbeRequest::new("/status?full=1")
+            .header("Host", " example.com ")
+            .header("connection", "keep-alive");
+        assert_eq!(
+            req.build().unwrap(),
+            "GET /status?full=1 HTTP/1.1\r\nHost: example.com\r\nconnection: keep-alive\r\n\r\n"
+        );
+        assert!(ProbeRequest::new("/a b").build().is_err());
+        assert!(ProbeRequest::new("").build().is_err());
+        assert!(ProbeRequest::new("/").method("GET /").build().is_err());
+        assert!(ProbeRequest::new("/").header("", "x").build().is_err());
+        assert!(ProbeRequest::new("/").header("a:b", "x").build().is_err());
+        assert!(ProbeRequest::new("/").header("x", "a\nb").build().is_err());
+        assert!(ProbeRequest::new("/").header("x", "a\tb").build().is_ok());
+    }
+}
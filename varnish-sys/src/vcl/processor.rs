@@ -2,14 +2,28 @@
 //! processors, named `VDP` in the C API, and implemented here using the [`DeliveryProcessor`] trait.
 //! Processors are linked together and will read, modify and push data down the delivery pipeline.
 //!
+//! Fetch processors, named `VFP`, implemented with the [`FetchProcessor`] trait, work the same
+//! way on the body of backend responses. Registered with [`FetchFilters`], they can be named in
+//! `beresp.filters`. They can also read the body of client requests, e.g. to hash or scan an
+//! upload, when added from `vcl_recv` with [`Ctx::add_req_body_filter()`].
+//!
 //! *Note:* The rust wrapper here is pretty thin and the vmod writer will most probably need to have to
 //! deal with the raw Varnish internals.
 
+use std::any::TypeId;
+use std::collections::BTreeMap;
 use std::ffi::{c_int, c_void, CStr};
+use std::marker::PhantomData;
 use std::ptr;
-
-use crate::ffi::{vdp_ctx, vfp_ctx, vfp_entry, vrt_ctx, VdpAction, VfpStatus};
-use crate::vcl::{Ctx, VclError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::ffi::{
+    vdp_ctx, vfp_ctx, vfp_entry, vmod_priv, vmod_priv_methods, vrt_ctx, VdpAction, VfpStatus,
+    VMOD_PRIV_METHODS_MAGIC,
+};
+use crate::vcl::vsc_wrapper::is_valid_name;
+use crate::vcl::{intern_cstr, Ctx, HttpHeaders, VclError, VscField, VscFormat, VscSegment};
 use crate::{ffi, validate_vfp_ctx, validate_vfp_entry};
 
 /// The return type for [`DeliveryProcessor::push`]
@@ -43,9 +57,20 @@ pub enum InitResult<T> {
     Pass,
 }
 
+impl<T> InitResult<T> {
+    /// Maps the `Ok` value using `f`, leaving `Err` and `Pass` untouched
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> InitResult<U> {
+        match self {
+            InitResult::Err(e) => InitResult::Err(e),
+            InitResult::Ok(v) => InitResult::Ok(f(v)),
+            InitResult::Pass => InitResult::Pass,
+        }
+    }
+}
+
 /// Describes a Varnish Delivery Processor (VDP)
 pub trait DeliveryProcessor: Sized {
-    /// The name of the processor.
+    /// The name of the processor. It is interned, see [`intern_cstr()`](crate::vcl::intern_cstr).
     fn name() -> &'static CStr;
     /// Create a new processor, possibly using knowledge from the pipeline, or from the current
     /// request.
@@ -118,7 +143,7 @@ pub unsafe extern "C" fn gen_vdp_push<T: DeliveryProcessor>(
 /// Create a `ffi::vdp` that can be fed to `ffi::VRT_AddVDP`
 pub fn new_vdp<T: DeliveryProcessor>() -> ffi::vdp {
     ffi::vdp {
-        name: T::name().as_ptr(),
+        name: intern_cstr(T::name()).as_ptr(),
         init: Some(gen_vdp_init::<T>),
         bytes: Some(gen_vdp_push::<T>),
         fini: Some(gen_vdp_fini::<T>),
@@ -161,9 +186,204 @@ impl DeliveryProcCtx<'_> {
     }
 }
 
+/// Describes how a [`MeterDeliveryProcessor`] accounts for the bytes it delivers
+pub trait Meter {
+    /// The name of the processor, as used in `resp.filters`.
+    fn name() -> &'static CStr;
+    /// The request header carrying the accounting label.
+    fn header() -> &'static str;
+    /// Decide the accounting key from the label found in [`Meter::header`], if any. Returning
+    /// `None` removes the processor from the pipeline.
+    fn key(label: Option<&str>) -> Option<String> {
+        label.map(ToString::to_string)
+    }
+    /// Called once per delivery with its total, e.g. to bump a per-key counter.
+    fn record(_key: &str, _bytes: u64) {}
+    /// Where to publish the per-key counters, if anywhere
+    fn counters() -> Option<&'static MeterCounters> {
+        None
+    }
+}
+
+/// The per-key counters of a [`Meter`], each key getting its own `vmod.<vmod>.<key>.bytes` and
+/// `.deliveries` counters, created on its first delivery
+///
+/// As the keys usually come from a client header, at most `max_keys` of them get their own
+/// counters. The keys past that limit, and the ones that are not valid VSC names (see
+/// [`VscSegment::new()`]), are accounted to the [`MeterCounters::OTHER`] key. A key whose
+/// counters could not be created is not tried again, and still takes one of the `max_keys`.
+#[derive(Debug)]
+pub struct MeterCounters {
+    vmod: String,
+    max_keys: usize,
+    /// `None` for the keys whose segment could not be created
+    segments: Mutex<BTreeMap<String, Option<VscSegment>>>,
+}
+
+impl MeterCounters {
+    /// The key of the bytes not accounted to their own key
+    pub const OTHER: &'static str = "other";
+
+    const BYTES: usize = 0;
+    const DELIVERIES: usize = 1;
+
+    /// Publish the counters of the [`MeterCounters::OTHER`] key, the others are added as needed
+    pub fn new(vmod: &str, max_keys: usize) -> Result<Self, VclError> {
+        let other = Self::segment(vmod, Self::OTHER)?;
+        Ok(Self {
+            vmod: vmod.to_string(),
+            max_keys,
+            segments: Mutex::new(BTreeMap::from([(Self::OTHER.to_string(), Some(other))])),
+        })
+    }
+
+    fn segment(vmod: &str, key: &str) -> Result<VscSegment, VclError> {
+        let mut bytes = VscField::counter("bytes", "Bytes delivered");
+        bytes.format = VscFormat::Bytes;
+        VscSegment::new(
+            vmod,
+            key,
+            "Bytes delivered per key",
+            vec![bytes, VscField::counter("deliveries", "Deliveries")],
+        )
+    }
+
+    /// Account a delivery of `bytes` to `key`
+    pub fn add(&self, key: &str, bytes: u64) {
+        let mut segments = self.segments.lock().unwrap_or_else(PoisonError::into_inner);
+        if !segments.contains_key(key) && segments.len() <= self.max_keys && is_valid_name(key) {
+            let seg = Self::segment(&self.vmod, key).ok();
+            segments.insert(key.to_string(), seg);
+        }
+        let seg = match segments.get(key) {
+            Some(Some(seg)) => seg,
+            _ => segments[Self::OTHER]
+                .as_ref()
+                .expect("created with the counters"),
+        };
+        let values = seg.values();
+        values[Self::BYTES].fetch_add(bytes, Ordering::Relaxed);
+        values[Self::DELIVERIES].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The bytes delivered so far for `key`, `None` if it has no counters of its own
+    pub fn bytes(&self, key: &str) -> Option<u64> {
+        self.segments
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)?
+            .as_ref()
+            .map(|seg| seg.values()[Self::BYTES].load(Ordering::Relaxed))
+    }
+
+    /// The number of deliveries so far for `key`, `None` if it has no counters of its own
+    pub fn deliveries(&self, key: &str) -> Option<u64> {
+        self.segments
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)?
+            .as_ref()
+            .map(|seg| seg.values()[Self::DELIVERIES].load(Ordering::Relaxed))
+    }
+}
+
+/// The running byte count of a [`MeterDeliveryProcessor`], shared with the rest of the task
+#[derive(Debug)]
+pub struct MeterReading {
+    key: String,
+    bytes: AtomicU64,
+}
+
+impl MeterReading {
+    /// The key the bytes are accounted to
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// How many bytes were delivered so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+static METER_PRIV_METHODS: vmod_priv_methods = vmod_priv_methods {
+    magic: VMOD_PRIV_METHODS_MAGIC,
+    type_: c"MeterReading".as_ptr(),
+    fini: Some(vmod_priv::on_fini::<Arc<MeterReading>>),
+};
+
+/// A ready-made [`DeliveryProcessor`] counting the bytes delivered to the client
+///
+/// The body is pushed unchanged. The count is published as a [`MeterReading`] in the task, where
+/// [`MeterDeliveryProcessor::reading`] can find it, and handed to [`Meter::record`] and to the
+/// [`Meter::counters`] once the delivery is over.
+#[derive(Debug)]
+pub struct MeterDeliveryProcessor<M: Meter> {
+    reading: Arc<MeterReading>,
+    _meter: PhantomData<M>,
+}
+
+impl<M: Meter> MeterDeliveryProcessor<M> {
+    /// Get the reading of the current task, if this processor is part of its delivery
+    pub fn reading(ctx: &Ctx) -> Option<Arc<MeterReading>> {
+        unsafe {
+            ffi::VRT_priv_task_get(ctx.raw, M::name().as_ptr().cast::<c_void>())
+                .as_ref()?
+                .get_ref::<Arc<MeterReading>>()
+                .cloned()
+        }
+    }
+}
+
+impl<M: Meter> DeliveryProcessor for MeterDeliveryProcessor<M> {
+    fn name() -> &'static CStr {
+        M::name()
+    }
+
+    fn new(vrt_ctx: &mut Ctx, _: &mut DeliveryProcCtx) -> InitResult<Self> {
+        let label = vrt_ctx.req().and_then(|r| r.header(M::header()));
+        let Some(key) = M::key(label) else {
+            return InitResult::Pass;
+        };
+        let reading = Arc::new(MeterReading {
+            key,
+            bytes: AtomicU64::new(0),
+        });
+        let id = M::name().as_ptr().cast::<c_void>();
+        let Some(priv_) = (unsafe { ffi::VRT_priv_task(vrt_ctx.raw, id).as_mut() }) else {
+            return InitResult::Err("failed to allocate the meter task slot".into());
+        };
+        unsafe {
+            drop(priv_.take::<Arc<MeterReading>>());
+            priv_.put(Box::new(Arc::clone(&reading)), &METER_PRIV_METHODS);
+        }
+        InitResult::Ok(Self {
+            reading,
+            _meter: PhantomData,
+        })
+    }
+
+    fn push(&mut self, ctx: &mut DeliveryProcCtx, act: VdpAction, buf: &[u8]) -> PushResult {
+        self.reading
+            .bytes
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        ctx.push(act, buf)
+    }
+}
+
+impl<M: Meter> Drop for MeterDeliveryProcessor<M> {
+    fn drop(&mut self) {
+        let bytes = self.reading.bytes();
+        M::record(&self.reading.key, bytes);
+        if let Some(counters) = M::counters() {
+            counters.add(&self.reading.key, bytes);
+        }
+    }
+}
+
 /// Describes a Varnish Fetch Processor (VFP)
 pub trait FetchProcessor: Sized {
-    /// The name of the processor.
+    /// The name of the processor. It is interned, see [`intern_cstr()`](crate::vcl::intern_cstr).
     fn name() -> &'static CStr;
     /// Create a new processor, possibly using knowledge from the pipeline
     fn new(vrt_ctx: &mut Ctx, vfp_ctx: &mut FetchProcCtx) -> InitResult<Self>;
@@ -234,7 +454,7 @@ pub unsafe extern "C" fn wrap_vfp_fini<T: FetchProcessor>(
 /// Create a `ffi::vfp` that can be fed to `ffi::VRT_AddVFP`
 pub fn new_vfp<T: FetchProcessor>() -> ffi::vfp {
     ffi::vfp {
-        name: T::name().as_ptr(),
+        name: intern_cstr(T::name()).as_ptr(),
         init: Some(wrap_vfp_init::<T>),
         pull: Some(wrap_vfp_pull::<T>),
         fini: Some(wrap_vfp_fini::<T>),
@@ -242,6 +462,66 @@ pub fn new_vfp<T: FetchProcessor>() -> ffi::vfp {
     }
 }
 
+/// The processors added to request bodies, by type
+///
+/// Unlike the ones of `beresp.filters`, they are not registered with Varnish, which points to
+/// them until the body is read, so they are allocated once and never freed.
+static REQ_BODY_VFPS: Mutex<BTreeMap<TypeId, ReqBodyVfp>> = Mutex::new(BTreeMap::new());
+
+struct ReqBodyVfp(&'static ffi::vfp);
+
+// Varnish only reads the `vfp`, and the strings and functions it points to are static
+unsafe impl Send for ReqBodyVfp {}
+
+fn req_body_vfp<T: FetchProcessor + 'static>() -> &'static ffi::vfp {
+    REQ_BODY_VFPS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(TypeId::of::<T>())
+        .or_insert_with(|| ReqBodyVfp(Box::leak(Box::new(new_vfp::<T>()))))
+        .0
+}
+
+impl Ctx<'_> {
+    /// Filter the body of the client request with `T`, e.g. to hash, scan, or transform an upload
+    ///
+    /// This must be called from `vcl_recv`, before the body is read, e.g. by
+    /// `std.cache_req_body()`. The processor then sees the body when it is cached, or when it is
+    /// sent to the backend. It is not registered in `beresp.filters`, and doesn't need to be.
+    ///
+    /// Returns `false` if the request has no body.
+    pub fn add_req_body_filter<T: FetchProcessor + 'static>(&mut self) -> Result<bool, VclError> {
+        let req = unsafe { self.raw.req.as_mut() }.ok_or(VclError::CStr(
+            c"request body filters can only be added on the client side",
+        ))?;
+        let status = req.req_body_status;
+        unsafe {
+            if status == ffi::BS_NONE.as_ptr() {
+                return Ok(false);
+            }
+            if status == ffi::BS_TAKEN.as_ptr()
+                || status == ffi::BS_CACHED.as_ptr()
+                || status == ffi::BS_ERROR.as_ptr()
+            {
+                return Err(VclError::CStr(
+                    c"request body filters must be added before the body is read",
+                ));
+            }
+        }
+        let vfc = unsafe { req.vfc.as_mut() }
+            .filter(|vfc| !vfc.resp.is_null())
+            .ok_or(VclError::CStr(
+                c"the request body is not ready to be filtered",
+            ))?;
+        if unsafe { ffi::VFP_Push(vfc, req_body_vfp::<T>()) }.is_null() {
+            return Err(VclError::CStr(
+                c"not enough workspace to add a request body filter",
+            ));
+        }
+        Ok(true)
+    }
+}
+
 /// A thin wrapper around a `*mut ffi::vfp_ctx`
 #[derive(Debug)]
 pub struct FetchProcCtx<'a> {
@@ -284,6 +564,87 @@ impl FetchProcCtx<'_> {
             n => panic!("unknown VfpStatus {n:?}"),
         }
     }
+
+    /// The headers of the backend response, including the trailers of a backend body once it
+    /// is over, see [`Transfer::trailers()`](crate::vcl::Transfer::trailers)
+    pub fn beresp(&mut self) -> Option<HttpHeaders<'_>> {
+        HttpHeaders::from_ptr(ffi::VCL_HTTP(self.raw.resp))
+    }
+
+    /// Mark the fetch as failed, logging `msg` as a `FetchError`
+    pub fn fail(&mut self, msg: impl Into<VclError>) {
+        let msg = msg.into();
+        let msg = msg.as_str();
+        // `%.*s` takes an `int` precision, passing a `usize` through varargs is undefined
+        let len = c_int::try_from(msg.len()).unwrap_or(c_int::MAX);
+        unsafe {
+            ffi::VFP_Error(self.raw, c"%.*s".as_ptr(), len, msg.as_ptr());
+        }
+    }
+}
+
+/// A consumer of the body bytes going through a [`TeeFetchProcessor`]
+///
+/// The sink only gets a copy of the data (to hash it, archive it, send it over a channel...),
+/// the body itself is passed down the pipeline untouched.
+pub trait TeeSink: Sized {
+    /// The name of the processor, as used in `beresp.filters`.
+    fn name() -> &'static CStr;
+    /// Create a new sink for the current fetch, possibly using knowledge from the pipeline.
+    /// Returning [`InitResult::Pass`] removes the processor from the pipeline.
+    fn new(vrt_ctx: &mut Ctx, vfp_ctx: &mut FetchProcCtx) -> InitResult<Self>;
+    /// Receive a copy of the bytes that were just pulled. An error fails the fetch.
+    fn write(&mut self, buf: &[u8]) -> Result<(), VclError>;
+    /// Called once, after the last chunk of the body was written.
+    fn finish(&mut self) -> Result<(), VclError> {
+        Ok(())
+    }
+}
+
+/// A ready-made [`FetchProcessor`] that passes the body through unchanged while streaming a copy
+/// of it to a [`TeeSink`]
+///
+/// It is registered like any other processor, e.g. `vfp.register::<TeeFetchProcessor<MySink>>()`,
+/// and uses the name of the sink.
+#[derive(Debug)]
+pub struct TeeFetchProcessor<S: TeeSink> {
+    sink: S,
+}
+
+impl<S: TeeSink> TeeFetchProcessor<S> {
+    /// Access the underlying sink
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S: TeeSink> FetchProcessor for TeeFetchProcessor<S> {
+    fn name() -> &'static CStr {
+        S::name()
+    }
+
+    fn new(vrt_ctx: &mut Ctx, vfp_ctx: &mut FetchProcCtx) -> InitResult<Self> {
+        S::new(vrt_ctx, vfp_ctx).map(|sink| Self { sink })
+    }
+
+    fn pull(&mut self, ctx: &mut FetchProcCtx, buf: &mut [u8]) -> PullResult {
+        let res = ctx.pull(buf);
+        let written = match res {
+            PullResult::Err => return res,
+            PullResult::Ok(len) => self.sink.write(&buf[..len]),
+            PullResult::End(len) => self
+                .sink
+                .write(&buf[..len])
+                .and_then(|()| self.sink.finish()),
+        };
+        match written {
+            Ok(()) => res,
+            Err(e) => {
+                ctx.fail(e);
+                PullResult::Err
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -302,7 +663,7 @@ impl<'c, 'f> FetchFilters<'c, 'f> {
     }
 
     fn find_position<T: FetchProcessor>(&self) -> Option<usize> {
-        let name = T::name().as_ptr();
+        let name = intern_cstr(T::name()).as_ptr();
         self.filters.iter().position(|f| f.name == name)
     }
 
@@ -354,7 +715,7 @@ impl<'c, 'f> DeliveryFilters<'c, 'f> {
     }
 
     fn find_position<T: DeliveryProcessor>(&self) -> Option<usize> {
-        let name = T::name().as_ptr();
+        let name = intern_cstr(T::name()).as_ptr();
         self.filters.iter().position(|f| f.name == name)
     }
 
@@ -0,0 +1,171 @@
+//! Find out how the client is talking to Varnish
+//!
+//! The HTTP version comes from the request line as the client sent it, before VCL had a chance to
+//! change `req.proto`. Varnish does not terminate TLS, so whether the connection is encrypted, and
+//! the protocol negotiated with ALPN, are only known when the TLS proxy in front of Varnish passes
+//! them along with the PROXY protocol version 2, e.g. `hitch` with `write-proxy-v2`. The HTTP/2
+//! stream ids are private to the H2 transport, and cannot be read by the vmods.
+//!
+//! ```
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::{Ctx, HttpVersion};
+//!
+//! // e.g. in a function called from `vcl_recv`
+//! fn describe(ctx: &Ctx) -> &'static str {
+//!     match ctx.client_protocol() {
+//!         Some(p) if p.is_h2() => "h2",
+//!         Some(p) if p.is_h2c() => "h2c",
+//!         Some(p) if p.version == HttpVersion::Http10 => "http/1.0",
+//!         Some(_) => "http/1.1",
+//!         None => "none",
+//!     }
+//! }
+//! ```
+
+use std::ptr;
+
+use crate::ffi;
+use crate::vcl::{Ctx, HttpHeaders};
+
+/// `struct vpx_tlv` of `cache_proxy_proto.c`, where the PROXY protocol TLVs are kept
+#[repr(C)]
+struct VpxTlv {
+    magic: u32,
+    len: u32,
+    // followed by `len` bytes of TLVs
+}
+
+const VPX_TLV_MAGIC: u32 = 0xdeb9_a4a5;
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_CLIENT_SSL: u8 = 0x01;
+
+/// The HTTP version of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+    Http2,
+}
+
+impl HttpVersion {
+    /// Parse the protocol of a request line, e.g. `HTTP/1.1`
+    pub fn from_proto(proto: &str) -> Option<Self> {
+        match proto {
+            "HTTP/1.0" => Some(Self::Http10),
+            "HTTP/1.1" => Some(Self::Http11),
+            "HTTP/2.0" => Some(Self::Http2),
+            _ => None,
+        }
+    }
+}
+
+/// The protocol spoken by the client, as returned by [`Ctx::client_protocol()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientProtocol<'a> {
+    pub version: HttpVersion,
+    /// The connection uses TLS, according to the PROXY protocol
+    pub tls: bool,
+    /// The protocol negotiated with ALPN, e.g. `h2`, according to the PROXY protocol
+    pub alpn: Option<&'a [u8]>,
+}
+
+impl ClientProtocol<'_> {
+    /// HTTP/2 over TLS
+    pub fn is_h2(&self) -> bool {
+        self.version == HttpVersion::Http2 && (self.tls || self.alpn == Some(b"h2"))
+    }
+
+    /// HTTP/2 over cleartext TCP, with prior knowledge or after an upgrade
+    pub fn is_h2c(&self) -> bool {
+        self.version == HttpVersion::Http2 && !self.is_h2()
+    }
+}
+
+/// Iterate over the type and value of the PROXY protocol TLVs in `data`, stopping at the first
+/// truncated one
+pub(crate) fn tlvs(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let [typ, hi, lo, rest @ ..] = data else {
+            return None;
+        };
+        let len = usize::from(u16::from_be_bytes([*hi, *lo]));
+        let value = rest.get(..len)?;
+        data = &rest[len..];
+        Some((*typ, value))
+    })
+}
+
+/// The TLS flag and the ALPN value found in the PROXY protocol TLVs
+fn parse_tlvs(data: &[u8]) -> (bool, Option<&[u8]>) {
+    let mut tls = false;
+    let mut alpn = None;
+    for (typ, value) in tlvs(data) {
+        match typ {
+            PP2_TYPE_ALPN => alpn = Some(value),
+            // the client flags come first, followed by the verify result and the sub-TLVs
+            PP2_TYPE_SSL => tls = value.first().is_some_and(|c| c & PP2_CLIENT_SSL != 0),
+            _ => {}
+        }
+    }
+    (tls, alpn)
+}
+
+/// The PROXY protocol TLVs of a session, if the connection used PROXY version 2
+pub(crate) fn proxy_tlvs(sp: &ffi::sess) -> Option<&[u8]> {
+    let mut dst: *mut usize = ptr::null_mut();
+    if unsafe { ffi::SES_Get_proxy_tlv(sp, ptr::from_mut(&mut dst)) } != 0 {
+        return None;
+    }
+    // the session attribute holds the address of the `vpx_tlv` allocated in the session workspace
+    let tlv = unsafe { (*dst.as_ref()? as *const VpxTlv).as_ref()? };
+    assert_eq!(tlv.magic, VPX_TLV_MAGIC);
+    let data = unsafe { ptr::from_ref(tlv).add(1).cast::<u8>() };
+    Some(unsafe { std::slice::from_raw_parts(data, tlv.len as usize) })
+}
+
+impl Ctx<'_> {
+    /// The protocol of the client connection, `None` outside of a client task, or for a request
+    /// line Varnish would not accept. See the [module documentation](self) for what is known.
+    pub fn client_protocol(&self) -> Option<ClientProtocol<'_>> {
+        let req = unsafe { self.raw.req.as_ref()? };
+        let http0 = HttpHeaders::from_ptr(ffi::VCL_HTTP(req.http0))?;
+        let version = HttpVersion::from_proto(http0.proto()?)?;
+        let sp = unsafe { req.sp.as_ref()? };
+        let (tls, alpn) = proxy_tlvs(sp).map_or((false, None), parse_tlvs);
+        Some(ClientProtocol { version, tls, alpn })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_tlvs() {
+        let data = [
+            &[PP2_TYPE_ALPN, 0, 2][..],
+            b"h2",
+            &[0x04, 0, 0],
+            &[PP2_TYPE_SSL, 0, 8, PP2_CLIENT_SSL, 0, 0, 0, 0, 0x21, 0, 0],
+            // truncated
+            &[0x05, 0, 9, 1],
+        ]
+        .concat();
+        assert_eq!(tlvs(&data).count(), 3);
+        assert_eq!(parse_tlvs(&data), (true, Some(&b"h2"[..])));
+        assert_eq!(parse_tlvs(&data[..5]), (false, Some(&b"h2"[..])));
+        assert_eq!(parse_tlvs(&[]), (false, None));
+
+        let proto = |version, tls, alpn| ClientProtocol { version, tls, alpn };
+        assert!(proto(HttpVersion::Http2, false, Some(b"h2")).is_h2());
+        assert!(proto(HttpVersion::Http2, false, None).is_h2c());
+        let h1 = proto(HttpVersion::Http11, true, Some(b"http/1.1"));
+        assert!(!h1.is_h2() && !h1.is_h2c());
+        assert_eq!(
+            HttpVersion::from_proto("HTTP/2.0"),
+            Some(HttpVersion::Http2)
+        );
+        assert_eq!(HttpVersion::from_proto("HTTP/3"), None);
+    }
+}
@@ -0,0 +1,73 @@
+//! Support for functions returning several values at once.
+//!
+//! A struct tagged with `#[derive(VclRecord)]` inside a `#[vmod]` module can be returned by a
+//! vmod function. Because VCL has no structured types, such a function is exposed as an object:
+//! its `.call(...)` method runs the function and records the result for the current task, and one
+//! accessor method per field reads it back.
+
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::ptr;
+
+use crate::ffi;
+#[cfg(not(varnishsys_6))]
+use crate::ffi::{vmod_priv, vmod_priv_methods, VMOD_PRIV_METHODS_MAGIC};
+use crate::vcl::{Ctx, VclError};
+
+/// Marker trait for structs that can be returned from vmod functions, see the [module
+/// documentation](self). Implement it with `#[derive(VclRecord)]`.
+pub trait VclRecord: 'static {}
+
+/// Storage of the last record produced in the current task.
+/// It must be public because it is used by the macro-generated code.
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct RecordSlot<T: VclRecord> {
+    name: String,
+    _record: PhantomData<T>,
+}
+
+impl<T: VclRecord> RecordSlot<T> {
+    #[cfg(not(varnishsys_6))]
+    const PRIV_METHODS: vmod_priv_methods = vmod_priv_methods {
+        magic: VMOD_PRIV_METHODS_MAGIC,
+        type_: c"VclRecord".as_ptr(),
+        fini: Some(vmod_priv::on_fini::<T>),
+    };
+
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            _record: PhantomData,
+        }
+    }
+
+    /// Each slot is keyed by its own address, so that two VCL objects never share a record
+    fn key(&self) -> *const c_void {
+        ptr::from_ref(self).cast::<c_void>()
+    }
+
+    /// Save `record` for the rest of the task, replacing any previous one
+    pub fn store(&self, ctx: &Ctx, record: T) -> Result<(), VclError> {
+        let priv_ = unsafe { ffi::VRT_priv_task(ctx.raw, self.key()).as_mut() }
+            .ok_or_else(|| format!("{}: unable to allocate the task storage", self.name))?;
+        unsafe {
+            drop(priv_.take::<T>());
+            #[cfg(not(varnishsys_6))]
+            priv_.put(Box::new(record), &Self::PRIV_METHODS);
+            #[cfg(varnishsys_6)]
+            priv_.put(Box::new(record), Some(ffi::vmod_priv::on_fini::<T>));
+        }
+        Ok(())
+    }
+
+    /// Get the record saved in the current task
+    pub fn get<'c>(&self, ctx: &'c Ctx) -> Result<&'c T, VclError> {
+        unsafe {
+            ffi::VRT_priv_task(ctx.raw, self.key())
+                .as_ref()
+                .and_then(|p| p.get_ref::<T>())
+        }
+        .ok_or_else(|| format!("{}: .call() must be used first in this task", self.name).into())
+    }
+}
@@ -0,0 +1,214 @@
+//! Deduplicate identical lookups running concurrently in different tasks
+//!
+//! When many requests need the same expensive result at the same time, e.g. the introspection of
+//! an auth token, only the first one should do the work. [`Singleflight`] makes the others wait
+//! for it and share its result, instead of sending the same query to a remote service hundreds of
+//! times. Nothing is cached: once the lookup is done, the next call for that key starts a new one.
+//!
+//! A `Singleflight` is typically created when the VCL is loaded, and kept in a
+//! `#[shared_per_vcl]` state or in a VCL object, so that it lives as long as the VCL does.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::vcl::VclError;
+
+/// The outcome of a lookup, as seen by the callers that waited for it
+type Shared<V> = Result<V, String>;
+
+#[derive(Debug)]
+struct Call<V> {
+    result: Mutex<Option<Shared<V>>>,
+    done: Condvar,
+}
+
+/// Run at most one lookup at a time per key, sharing its result with concurrent callers
+#[derive(Debug)]
+pub struct Singleflight<K, V> {
+    calls: Mutex<HashMap<K, Arc<Call<V>>>>,
+    timeout: Duration,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Singleflight<K, V> {
+    /// Create a `Singleflight` where callers wait at most `timeout` for a lookup started by
+    /// another task
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            calls: Mutex::default(),
+            timeout,
+        }
+    }
+
+    /// Get the value for `key`, running `lookup` only if no other task is already doing it.
+    ///
+    /// If `lookup` fails, the tasks that waited for it get an error with the same message. Waiting
+    /// tasks give up after the timeout, but the lookup keeps going for the task that started it.
+    pub fn get<F>(&self, key: K, lookup: F) -> Result<V, VclError>
+    where
+        F: FnOnce() -> Result<V, VclError>,
+    {
+        let mut calls = self.calls.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(call) = calls.get(&key).cloned() {
+            drop(calls);
+            return self.wait(&call);
+        }
+        let call = Arc::new(Call {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        });
+        calls.insert(key.clone(), Arc::clone(&call));
+        drop(calls);
+
+        // Publish the result even if `lookup` panics, waiting tasks must not hang
+        let mut guard = Leader {
+            flight: self,
+            key,
+            call,
+            result: Some(Err("the lookup was interrupted".to_string())),
+        };
+        let result = lookup();
+        guard.result = Some(match &result {
+            Ok(v) => Ok(v.clone()),
+            Err(e) => Err(e.to_string()),
+        });
+        result
+    }
+
+    /// How many lookups are currently running
+    pub fn in_flight(&self) -> usize {
+        self.calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    fn wait(&self, call: &Call<V>) -> Result<V, VclError> {
+        let result = call.result.lock().unwrap_or_else(PoisonError::into_inner);
+        let (result, _) = call
+            .done
+            .wait_timeout_while(result, self.timeout, |r| r.is_none())
+            .unwrap_or_else(PoisonError::into_inner);
+        match result.as_ref() {
+            Some(Ok(v)) => Ok(v.clone()),
+            Some(Err(e)) => Err(VclError::new(e.clone())),
+            None => Err(VclError::Str("timed out waiting for a concurrent lookup")),
+        }
+    }
+}
+
+/// Held by the task running the lookup, publishes the result and wakes up the others when dropped
+struct Leader<'a, K: Eq + Hash, V> {
+    flight: &'a Singleflight<K, V>,
+    key: K,
+    call: Arc<Call<V>>,
+    result: Option<Shared<V>>,
+}
+
+impl<K: Eq + Hash, V> Drop for Leader<'_, K, V> {
+    fn drop(&mut self) {
+        self.flight
+            .calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+        *self
+            .call
+            .result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = self.result.take();
+        self.call.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::Barrier;
+    use std::thread;
+
+    use super::*;
+
+    /// Called by a lookup to block until `count` other callers are waiting for it
+    fn wait_for_followers<K: Eq + Hash, V>(flight: &Singleflight<K, V>, key: &K, count: usize) {
+        // the map and the leader hold a reference to the call, and each waiting caller another one
+        while flight
+            .calls
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, Arc::strong_count)
+            < count + 2
+        {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn singleflight() {
+        let flight = Singleflight::<&str, u64>::new(Duration::from_secs(5));
+        let runs = AtomicUsize::new(0);
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        flight
+                            .get("token", || {
+                                runs.fetch_add(1, Ordering::Relaxed);
+                                wait_for_followers(&flight, &"token", 7);
+                                Ok(42)
+                            })
+                            .map_err(|e| e.to_string())
+                    })
+                })
+                .collect();
+            for h in handles {
+                assert_eq!(h.join().unwrap().unwrap(), 42);
+            }
+        });
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert_eq!(flight.in_flight(), 0);
+
+        // errors are shared, and the next call runs a new lookup
+        let (started, leading) = channel();
+        thread::scope(|s| {
+            let leader = s.spawn(|| {
+                flight
+                    .get("token", || {
+                        started.send(()).unwrap();
+                        wait_for_followers(&flight, &"token", 1);
+                        Err("introspection failed".into())
+                    })
+                    .map_err(|e| e.to_string())
+            });
+            leading.recv().unwrap();
+            let err = flight.get("token", || Ok(0)).unwrap_err();
+            assert_eq!(err.to_string(), "introspection failed");
+            assert!(leader.join().unwrap().is_err());
+        });
+        assert_eq!(flight.get("token", || Ok(7)).unwrap(), 7);
+    }
+
+    #[test]
+    fn singleflight_timeout() {
+        let flight = Singleflight::<u8, ()>::new(Duration::from_millis(50));
+        let (started, released) = (Barrier::new(2), Barrier::new(2));
+        thread::scope(|s| {
+            s.spawn(|| {
+                flight
+                    .get(1, || {
+                        started.wait();
+                        released.wait();
+                        Ok(())
+                    })
+                    .is_ok()
+            });
+            started.wait();
+            assert!(flight.get(1, || Ok(())).is_err());
+            released.wait();
+        });
+        assert_eq!(flight.in_flight(), 0);
+    }
+}
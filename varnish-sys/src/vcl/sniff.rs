@@ -0,0 +1,359 @@
+//! Guess the media type of a body from its first bytes
+//!
+//! Backends sometimes send a wrong or missing `Content-Type`, e.g. `application/octet-stream` for
+//! every file of a bucket. [`detect()`] recognizes the common formats from their magic numbers,
+//! and tells text from binary data, so that the type can be fixed before the object is cached:
+//!
+//! ``` rust
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::sniff::{detect, Mime};
+//!
+//! assert_eq!(detect(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Mime::Png);
+//! assert_eq!(detect(b"  <!DOCTYPE html><html>"), Mime::Html);
+//! assert_eq!(detect(b"plain words"), Mime::Text);
+//! assert_eq!(detect(b"\0\x01\x02"), Mime::Unknown);
+//! assert_eq!(Mime::Woff2.as_str(), "font/woff2");
+//! ```
+//!
+//! A few hundred bytes are enough, only a `tar` archive needs 262. [`fix_content_type()`] writes
+//! the result into `beresp`. The headers are copied into the object when the body starts being
+//! fetched, so it has to be called from the `new()` of a
+//! [`FetchProcessor`](crate::vcl::FetchProcessor), after pulling the beginning of the body.
+
+use std::fmt::{self, Display};
+
+use crate::vcl::{HttpHeaders, VclResult};
+
+/// A media type recognized by [`detect()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Mime {
+    Avif,
+    Bmp,
+    Gif,
+    Ico,
+    Jpeg,
+    Png,
+    Webp,
+    Otf,
+    Ttf,
+    Woff,
+    Woff2,
+    Bzip2,
+    Gzip,
+    Rar,
+    SevenZip,
+    Tar,
+    Xz,
+    Zip,
+    Zstd,
+    Mp3,
+    Mp4,
+    Ogg,
+    Wav,
+    Webm,
+    Pdf,
+    Wasm,
+    Html,
+    Json,
+    Svg,
+    Xml,
+    Text,
+    /// Binary data of an unknown format
+    Unknown,
+}
+
+impl Mime {
+    /// The media type, e.g. `image/png`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Avif => "image/avif",
+            Self::Bmp => "image/bmp",
+            Self::Gif => "image/gif",
+            Self::Ico => "image/x-icon",
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Webp => "image/webp",
+            Self::Otf => "font/otf",
+            Self::Ttf => "font/ttf",
+            Self::Woff => "font/woff",
+            Self::Woff2 => "font/woff2",
+            Self::Bzip2 => "application/x-bzip2",
+            Self::Gzip => "application/gzip",
+            Self::Rar => "application/vnd.rar",
+            Self::SevenZip => "application/x-7z-compressed",
+            Self::Tar => "application/x-tar",
+            Self::Xz => "application/x-xz",
+            Self::Zip => "application/zip",
+            Self::Zstd => "application/zstd",
+            Self::Mp3 => "audio/mpeg",
+            Self::Mp4 => "video/mp4",
+            Self::Ogg => "application/ogg",
+            Self::Wav => "audio/wav",
+            Self::Webm => "video/webm",
+            Self::Pdf => "application/pdf",
+            Self::Wasm => "application/wasm",
+            Self::Html => "text/html",
+            Self::Json => "application/json",
+            Self::Svg => "image/svg+xml",
+            Self::Xml => "text/xml",
+            Self::Text => "text/plain",
+            Self::Unknown => "application/octet-stream",
+        }
+    }
+
+    /// Whether the type was guessed from the content rather than from a magic number, so that
+    /// another textual type, e.g. `text/css`, may be right as well
+    pub fn is_text(self) -> bool {
+        matches!(
+            self,
+            Self::Html | Self::Json | Self::Svg | Self::Xml | Self::Text
+        )
+    }
+}
+
+impl Display for Mime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Magic numbers found at the start of the body, `?` matching any byte
+const SIGNATURES: &[(&[u8], Mime)] = &[
+    (b"\x89PNG\r\n\x1a\n", Mime::Png),
+    (b"\xff\xd8\xff", Mime::Jpeg),
+    (b"GIF87a", Mime::Gif),
+    (b"GIF89a", Mime::Gif),
+    (b"RIFF????WEBPVP", Mime::Webp),
+    (b"RIFF????WAVE", Mime::Wav),
+    (b"????ftypavif", Mime::Avif),
+    (b"????ftypavis", Mime::Avif),
+    (b"????ftyp", Mime::Mp4),
+    (b"\0\0\x01\0", Mime::Ico),
+    // the reserved fields of the header are zero, so that text starting with "BM" isn't a bitmap
+    (b"BM????\0\0\0\0", Mime::Bmp),
+    (b"wOFF", Mime::Woff),
+    (b"wOF2", Mime::Woff2),
+    (b"OTTO", Mime::Otf),
+    (b"\0\x01\0\0", Mime::Ttf),
+    (b"PK\x03\x04", Mime::Zip),
+    (b"PK\x05\x06", Mime::Zip),
+    (b"\x1f\x8b\x08", Mime::Gzip),
+    (b"BZh", Mime::Bzip2),
+    (b"\xfd7zXZ\0", Mime::Xz),
+    (b"\x28\xb5\x2f\xfd", Mime::Zstd),
+    (b"7z\xbc\xaf\x27\x1c", Mime::SevenZip),
+    (b"Rar!\x1a\x07", Mime::Rar),
+    (b"ID3", Mime::Mp3),
+    (b"OggS\0", Mime::Ogg),
+    (b"\x1a\x45\xdf\xa3", Mime::Webm),
+    (b"%PDF-", Mime::Pdf),
+    (b"\0asm", Mime::Wasm),
+];
+
+/// The tags an HTML document may start with, followed by a space or `>`
+const HTML_TAGS: &[&[u8]] = &[
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<body",
+    b"<script",
+    b"<iframe",
+    b"<style",
+    b"<title",
+    b"<table",
+    b"<div",
+    b"<font",
+    b"<h1",
+    b"<br",
+    b"<a",
+    b"<b",
+    b"<p",
+    b"<!--",
+];
+
+fn matches_signature(buf: &[u8], signature: &[u8]) -> bool {
+    buf.len() >= signature.len()
+        && signature
+            .iter()
+            .zip(buf)
+            .all(|(&s, &b)| s == b'?' || s == b)
+}
+
+fn starts_with_ignore_case(buf: &[u8], prefix: &[u8]) -> bool {
+    buf.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+/// Guess the type of a body from its beginning, see the [module documentation](self)
+pub fn detect(buf: &[u8]) -> Mime {
+    if let Some(&(_, mime)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| matches_signature(buf, signature))
+    {
+        return mime;
+    }
+    if buf.get(257..262) == Some(b"ustar") {
+        return Mime::Tar;
+    }
+    detect_text(buf)
+}
+
+/// Recognize markup and JSON, or tell plain text from binary data
+fn detect_text(buf: &[u8]) -> Mime {
+    let (buf, has_bom) = if let Some(rest) = buf.strip_prefix(b"\xef\xbb\xbf") {
+        (rest, true)
+    } else if buf.starts_with(b"\xfe\xff") || buf.starts_with(b"\xff\xfe") {
+        // UTF-16 isn't inspected further
+        return Mime::Text;
+    } else {
+        (buf, false)
+    };
+    if !is_text(buf) {
+        return Mime::Unknown;
+    }
+    let start = buf.trim_ascii_start();
+    let is_html = HTML_TAGS.iter().any(|tag| {
+        starts_with_ignore_case(start, tag)
+            && start
+                .get(tag.len())
+                .is_some_and(|&b| b == b' ' || b == b'>' || tag.ends_with(b"--"))
+    });
+    if is_html {
+        Mime::Html
+    } else if starts_with_ignore_case(start, b"<svg") {
+        Mime::Svg
+    } else if start.starts_with(b"<?xml") {
+        if start.windows(4).any(|w| w == b"<svg") {
+            Mime::Svg
+        } else {
+            Mime::Xml
+        }
+    } else if looks_like_json(start) {
+        Mime::Json
+    } else if !start.is_empty() || has_bom {
+        Mime::Text
+    } else {
+        Mime::Unknown
+    }
+}
+
+/// Valid UTF-8, except maybe for a character cut at the end, without control characters other
+/// than whitespace and escape
+fn is_text(buf: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(buf) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && buf.len() - e.valid_up_to() < 4,
+    };
+    valid
+        && !buf
+            .iter()
+            .any(|&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | b'\x0c' | b'\x1b'))
+}
+
+fn looks_like_json(start: &[u8]) -> bool {
+    let (open, next) = match start.split_first() {
+        Some((&open @ (b'{' | b'['), rest)) => (open, rest.trim_ascii_start().first()),
+        _ => return false,
+    };
+    match (open, next) {
+        (b'{', Some(b'"' | b'}')) => true,
+        (b'[', Some(&b)) => matches!(b, b'"' | b'{' | b'[' | b']' | b'-' | b'0'..=b'9'),
+        _ => false,
+    }
+}
+
+/// Set the `Content-Type` of `beresp` to the type of the body, guessed from its beginning `buf`,
+/// if it is wrong. Returns the type written, if any.
+///
+/// The header is only replaced if it is missing or `application/octet-stream`, or if `buf`
+/// starts with the magic number of another type. A type guessed from the content, e.g.
+/// [`Mime::Text`], doesn't replace an existing one, and [`Mime::Unknown`] never does.
+pub fn fix_content_type(beresp: &mut HttpHeaders, buf: &[u8]) -> VclResult<Option<Mime>> {
+    let mime = detect(buf);
+    if mime == Mime::Unknown {
+        return Ok(None);
+    }
+    let current = beresp.header("Content-Type").map(|ct| {
+        ct.split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase()
+    });
+    let replace = match current.as_deref() {
+        None | Some("" | "application/octet-stream" | "binary/octet-stream") => true,
+        Some(current) => !mime.is_text() && current != mime.as_str(),
+    };
+    if !replace {
+        return Ok(None);
+    }
+    beresp.unset_header("Content-Type");
+    beresp.set_header("Content-Type", mime.as_str())?;
+    Ok(Some(mime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures() {
+        let mut tar = vec![0; 300];
+        tar[257..262].copy_from_slice(b"ustar");
+        let cases: &[(&[u8], Mime)] = &[
+            (b"\x89PNG\r\n\x1a\n\0\0", Mime::Png),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", Mime::Jpeg),
+            (b"GIF89a\x01\0", Mime::Gif),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", Mime::Webp),
+            (b"RIFF\x24\0\0\0WAVEfmt ", Mime::Wav),
+            (b"\0\0\0\x1cftypavif\0\0", Mime::Avif),
+            (b"\0\0\0\x18ftypmp42\0\0", Mime::Mp4),
+            (b"wOF2\0\x01\0\0", Mime::Woff2),
+            (b"BM\x36\x10\0\0\0\0\0\0\x36\0", Mime::Bmp),
+            (b"BMW cars", Mime::Text),
+            (b"\0\x01\0\0\0\x0e\0\x80", Mime::Ttf),
+            (b"PK\x03\x04\x14\0", Mime::Zip),
+            (b"\x1f\x8b\x08\0", Mime::Gzip),
+            (b"\x28\xb5\x2f\xfd\x24", Mime::Zstd),
+            (b"%PDF-1.7\n", Mime::Pdf),
+            (b"\0asm\x01\0\0\0", Mime::Wasm),
+            (&tar, Mime::Tar),
+            // too short to tell
+            (b"RIFF", Mime::Text),
+            (b"\x89PN", Mime::Unknown),
+        ];
+        for &(buf, mime) in cases {
+            assert_eq!(detect(buf), mime, "{buf:?}");
+        }
+    }
+
+    #[test]
+    fn text() {
+        let cases: &[(&[u8], Mime)] = &[
+            (b"\n  <!DOCTYPE HTML>\n<html>", Mime::Html),
+            (b"<html lang=\"en\">", Mime::Html),
+            (b"<!-- comment -->", Mime::Html),
+            (b"<p>text</p>", Mime::Html),
+            (b"<pre>", Mime::Text),
+            (b"<?xml version=\"1.0\"?><rss>", Mime::Xml),
+            (b"<?xml version=\"1.0\"?>\n<svg xmlns=", Mime::Svg),
+            (b"<svg width=\"10\">", Mime::Svg),
+            (b"{\"a\": 1}", Mime::Json),
+            (b"[ {\"a\": 1} ]", Mime::Json),
+            (b"[section]\nkey = 1", Mime::Text),
+            (b"body { color: red }", Mime::Text),
+            (b"\xef\xbb\xbf", Mime::Text),
+            (b"\xff\xfeh\0i\0", Mime::Text),
+            ("caf\u{e9}".as_bytes(), Mime::Text),
+            // a character cut at the end of the buffer
+            (&"caf\u{e9}".as_bytes()[..4], Mime::Text),
+            (b"caf\xe9 au lait", Mime::Unknown),
+            (b"text\0with nul", Mime::Unknown),
+            (b"", Mime::Unknown),
+        ];
+        for &(buf, mime) in cases {
+            assert_eq!(detect(buf), mime, "{buf:?}");
+        }
+    }
+}
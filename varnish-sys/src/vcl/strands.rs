@@ -0,0 +1,168 @@
+//! Read a `STRANDS` argument, the pieces of a string concatenation, without joining them first
+//!
+//! When VCL calls `mymod.f(req.url + "?" + req.http.query)` with a `STRING` argument, Varnish
+//! joins the pieces into its workspace before the call. With a `STRANDS` argument, the vmod gets
+//! the pieces themselves, and only pays for the concatenation if it actually needs one string.
+//!
+//! An argument declared as [`Strands`] is a `STRANDS` in VCL. A `&str` argument can also be
+//! declared with `#[arg(strands)]`, in which case the pieces are joined into a Rust buffer before
+//! the call, and not copied at all if there is only one piece.
+//!
+//! ``` rust,ignore
+//! #[varnish::vmod]
+//! mod example {
+//!     use varnish::vcl::Strands;
+//!
+//!     /// Nothing is joined, the pieces are hashed one by one
+//!     pub fn hash(s: Strands) -> i64 {
+//!         s.iter().fold(0, |h, piece| {
+//!             piece.to_bytes().iter().fold(h, |h, b| h.wrapping_mul(31) + i64::from(*b))
+//!         })
+//!     }
+//!
+//!     /// Joined, if needed, but never in the workspace
+//!     pub fn shout(#[arg(strands)] s: &str) -> String {
+//!         s.to_uppercase()
+//!     }
+//! }
+//! ```
+//!
+//! Like the other arguments, a `Strands` must not be kept after the function returns.
+
+use std::borrow::Cow;
+use std::ffi::{c_char, CStr};
+use std::fmt;
+use std::str::Utf8Error;
+
+use crate::ffi::VCL_STRANDS;
+
+/// The pieces of a `STRANDS` argument, see the [module documentation](self)
+#[derive(Clone, Copy, Default)]
+pub struct Strands<'a>(&'a [*const c_char]);
+
+impl<'a> Strands<'a> {
+    /// The pieces, in order. A `NULL` piece, e.g. an unset header, is an empty string.
+    pub fn iter(self) -> impl Iterator<Item = &'a CStr> + 'a {
+        self.0.iter().map(|&p| {
+            if p.is_null() {
+                c""
+            } else {
+                unsafe { CStr::from_ptr(p) }
+            }
+        })
+    }
+
+    /// The length of the joined string, in bytes
+    pub fn len(self) -> usize {
+        self.iter().map(CStr::count_bytes).sum()
+    }
+
+    /// The joined string is empty
+    pub fn is_empty(self) -> bool {
+        self.iter().all(CStr::is_empty)
+    }
+
+    /// The joined bytes, only copied if more than one piece is not empty
+    pub fn to_bytes(self) -> Cow<'a, [u8]> {
+        let mut pieces = self.iter().filter(|s| !s.is_empty());
+        let Some(first) = pieces.next() else {
+            return Cow::Borrowed(&[]);
+        };
+        let Some(second) = pieces.next() else {
+            return Cow::Borrowed(first.to_bytes());
+        };
+        let mut joined = Vec::with_capacity(self.len());
+        joined.extend_from_slice(first.to_bytes());
+        joined.extend_from_slice(second.to_bytes());
+        pieces.for_each(|s| joined.extend_from_slice(s.to_bytes()));
+        Cow::Owned(joined)
+    }
+
+    /// The joined string, only copied if more than one piece is not empty
+    pub fn to_str(self) -> Result<Cow<'a, str>, Utf8Error> {
+        Ok(match self.to_bytes() {
+            Cow::Borrowed(b) => Cow::Borrowed(std::str::from_utf8(b)?),
+            Cow::Owned(b) => Cow::Owned(String::from_utf8(b).map_err(|e| e.utf8_error())?),
+        })
+    }
+
+    /// The joined string, with invalid UTF-8 sequences replaced with `U+FFFD`
+    pub fn to_string_lossy(self) -> Cow<'a, str> {
+        match self.to_bytes() {
+            Cow::Borrowed(b) => String::from_utf8_lossy(b),
+            Cow::Owned(b) => Cow::Owned(
+                String::from_utf8(b)
+                    .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()),
+            ),
+        }
+    }
+}
+
+impl From<VCL_STRANDS> for Option<Strands<'_>> {
+    fn from(value: VCL_STRANDS) -> Self {
+        let s = unsafe { value.0.as_ref()? };
+        let n = usize::try_from(s.n).unwrap_or(0);
+        if n == 0 || s.p.is_null() {
+            return Some(Strands::default());
+        }
+        Some(Strands(unsafe { std::slice::from_raw_parts(s.p, n) }))
+    }
+}
+
+impl From<VCL_STRANDS> for Strands<'_> {
+    fn from(value: VCL_STRANDS) -> Self {
+        // Treat a null pointer as an empty string
+        <Option<Strands>>::from(value).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Strands<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.iter()
+            .try_for_each(|s| f.write_str(&String::from_utf8_lossy(s.to_bytes())))
+    }
+}
+
+impl fmt::Debug for Strands<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+    use crate::ffi::strands;
+
+    fn strands_of(p: &mut [*const c_char]) -> strands {
+        strands {
+            n: p.len().try_into().unwrap(),
+            p: p.as_mut_ptr(),
+        }
+    }
+
+    #[test]
+    fn strands() {
+        assert!(<Option<Strands>>::from(VCL_STRANDS(ptr::null())).is_none());
+
+        let mut p = [c"/path".as_ptr(), ptr::null(), c"".as_ptr()];
+        let raw = strands_of(&mut p);
+        let s = Strands::from(VCL_STRANDS(ptr::from_ref(&raw)));
+        assert_eq!(s.len(), 5);
+        assert!(matches!(s.to_str(), Ok(Cow::Borrowed("/path"))));
+
+        let mut p = [c"/path".as_ptr(), c"?".as_ptr(), c"q=\xff".as_ptr()];
+        let raw = strands_of(&mut p);
+        let s = Strands::from(VCL_STRANDS(ptr::from_ref(&raw)));
+        assert_eq!(s.to_bytes().as_ref(), b"/path?q=\xff");
+        assert!(s.to_str().is_err());
+        assert_eq!(s.to_string_lossy(), "/path?q=\u{fffd}");
+        assert_eq!(s.to_string(), "/path?q=\u{fffd}");
+
+        let mut p = [ptr::null()];
+        let raw = strands_of(&mut p);
+        assert!(Strands::from(VCL_STRANDS(ptr::from_ref(&raw))).is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+//! Return one of a few strings to VCL without copying it into the workspace
+//!
+//! Normalization vmods usually map each request to one of a handful of values, e.g. `gzip` or
+//! `br` for `Accept-Encoding`, or `mobile` and `desktop` for a device class. Returning a `String`
+//! copies the value into the workspace of every request. A [`StringTable`] kept in the
+//! `#[shared_per_vcl]` state stores each distinct value once, for as long as the VCL is loaded,
+//! and hands the same `VCL_STRING` pointer to every request.
+//!
+//! ``` rust,ignore
+//! #[varnish::vmod]
+//! mod normalize {
+//!     use varnish::ffi::VCL_STRING;
+//!     use varnish::vcl::{StringTable, VclResult, Workspace};
+//!
+//!     #[event]
+//!     pub fn event(#[shared_per_vcl] table: &mut Option<Box<StringTable>>) {
+//!         table.get_or_insert_with(|| Box::new(StringTable::new(64)));
+//!     }
+//!
+//!     pub fn encoding(
+//!         ws: &mut Workspace,
+//!         #[shared_per_vcl] table: Option<&StringTable>,
+//!         accept: &str,
+//!     ) -> VclResult<VCL_STRING> {
+//!         let value = match accept {
+//!             a if a.contains("br") => "br",
+//!             a if a.contains("gzip") => "gzip",
+//!             _ => "",
+//!         };
+//!         table.expect("created in the event").get(ws, value)
+//!     }
+//! }
+//! ```
+//!
+//! The pointers must not be used after the VCL is discarded, so a table must not be shared with
+//! other VCLs, e.g. from a `static`.
+
+use std::collections::HashMap;
+use std::ffi::{c_char, CString};
+use std::fmt;
+use std::sync::{PoisonError, RwLock};
+
+use crate::ffi::VCL_STRING;
+use crate::vcl::{IntoVCL, VclResult, Workspace};
+
+/// Interned strings of a VCL, see the [module documentation](self)
+pub struct StringTable {
+    strings: RwLock<HashMap<Box<str>, CString>>,
+    capacity: usize,
+}
+
+impl StringTable {
+    /// Create an empty table storing at most `capacity` distinct strings. Once it is full, the
+    /// other strings are copied into the workspace, so values coming from the client can't grow
+    /// it without bound.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            strings: RwLock::new(HashMap::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// The interned copy of `s`, or a copy in the workspace if the table is full or `s` contains
+    /// a NUL byte
+    pub fn get(&self, ws: &mut Workspace, s: &str) -> VclResult<VCL_STRING> {
+        match self.intern(s) {
+            Some(ptr) => Ok(VCL_STRING(ptr)),
+            None => s.into_vcl(ws),
+        }
+    }
+
+    /// The number of interned strings
+    pub fn len(&self) -> usize {
+        self.strings
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// The table has no interned string yet
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn intern(&self, s: &str) -> Option<*const c_char> {
+        let strings = self.strings.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(c) = strings.get(s) {
+            return Some(c.as_ptr());
+        }
+        drop(strings);
+
+        let mut strings = self.strings.write().unwrap_or_else(PoisonError::into_inner);
+        // another thread may have added it while the lock was released
+        if let Some(c) = strings.get(s) {
+            return Some(c.as_ptr());
+        }
+        if strings.len() >= self.capacity {
+            return None;
+        }
+        // the buffer of a CString does not move when the map grows
+        let c = CString::new(s).ok()?;
+        let ptr = c.as_ptr();
+        strings.insert(s.into(), c);
+        Some(ptr)
+    }
+}
+
+impl fmt::Debug for StringTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StringTable")
+            .field("len", &self.len())
+            .field("capacity", &self.capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_table() {
+        let table = StringTable::new(2);
+        assert!(table.is_empty());
+        let gzip = table.intern("gzip").unwrap();
+        let br = table.intern("br").unwrap();
+        assert_ne!(gzip, br);
+        assert_eq!(table.intern("gzip"), Some(gzip));
+
+        // full, or not a C string
+        assert_eq!(table.intern("deflate"), None);
+        assert_eq!(StringTable::new(2).intern("g\0zip"), None);
+        assert_eq!(table.len(), 2);
+    }
+}
@@ -0,0 +1,128 @@
+//! Scratch data shared by the functions of a vmod during a single task
+//!
+//! A `#[shared_per_task]` argument holds a single type for the whole vmod, and is moved out of
+//! the `PRIV_TASK` and back around each call. A [`TaskLocal`] is declared as a `static`, so a vmod
+//! can have as many of them as it needs, each with its own type, and any function with a [`Ctx`]
+//! can reach it. The value is created on first use in each task, and dropped by Varnish when the
+//! task ends:
+//!
+//! ``` rust,no_run
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::{Ctx, TaskLocal, VclError};
+//!
+//! static SEEN: TaskLocal<Vec<String>> = TaskLocal::new();
+//!
+//! // e.g. the body of `pub fn see(ctx: &Ctx, name: &str)` in a vmod
+//! fn see(ctx: &Ctx, name: &str) -> Result<(), VclError> {
+//!     SEEN.get_or_init(ctx, Vec::new)?.push(name.to_string());
+//!     Ok(())
+//! }
+//!
+//! // e.g. the body of `pub fn seen(ctx: &Ctx) -> String` in the same vmod
+//! fn seen(ctx: &Ctx) -> Result<String, VclError> {
+//!     Ok(SEEN.get(ctx)?.map(|v| v.join(",")).unwrap_or_default())
+//! }
+//! ```
+
+use std::cell::{RefCell, RefMut};
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::ptr;
+
+use crate::ffi;
+#[cfg(not(varnishsys_6))]
+use crate::ffi::{vmod_priv, vmod_priv_methods, VMOD_PRIV_METHODS_MAGIC};
+use crate::vcl::{Ctx, VclError};
+
+/// A value of type `T` for each task, see the [module documentation](self)
+#[derive(Debug)]
+pub struct TaskLocal<T: Send + 'static> {
+    // Statics of zero-sized types may share their address, which is used as the key
+    _key: u8,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T: Send + 'static> Default for TaskLocal<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send + 'static> TaskLocal<T> {
+    #[cfg(not(varnishsys_6))]
+    const PRIV_METHODS: vmod_priv_methods = vmod_priv_methods {
+        magic: VMOD_PRIV_METHODS_MAGIC,
+        type_: c"TaskLocal".as_ptr(),
+        fini: Some(vmod_priv::on_fini::<RefCell<T>>),
+    };
+
+    /// Create a task-local value, usually stored in a `static`
+    pub const fn new() -> Self {
+        Self {
+            _key: 0,
+            _value: PhantomData,
+        }
+    }
+
+    fn key(&self) -> *const c_void {
+        ptr::from_ref(self).cast::<c_void>()
+    }
+
+    fn cell<'c>(&self, ctx: &'c Ctx) -> Option<&'c RefCell<T>> {
+        unsafe {
+            ffi::VRT_priv_task(ctx.raw, self.key())
+                .as_ref()
+                .and_then(|p| p.get_ref::<RefCell<T>>())
+        }
+    }
+
+    /// Get the value of the current task, if it was created
+    ///
+    /// Fails if the value is already borrowed, i.e. from an outer call in the same task.
+    pub fn get<'c>(&self, ctx: &'c Ctx) -> Result<Option<RefMut<'c, T>>, VclError> {
+        self.cell(ctx).map(borrow).transpose()
+    }
+
+    /// Get the value of the current task, creating it with `init` if needed
+    ///
+    /// Fails if the value is already borrowed, or if the task storage cannot be allocated.
+    pub fn get_or_init<'c>(
+        &self,
+        ctx: &'c Ctx,
+        init: impl FnOnce() -> T,
+    ) -> Result<RefMut<'c, T>, VclError> {
+        if let Some(cell) = self.cell(ctx) {
+            return borrow(cell);
+        }
+        let priv_ = unsafe { ffi::VRT_priv_task(ctx.raw, self.key()).as_mut() }
+            .ok_or("TaskLocal: unable to allocate the task storage")?;
+        let cell = Box::new(RefCell::new(init()));
+        unsafe {
+            #[cfg(not(varnishsys_6))]
+            priv_.put(cell, &Self::PRIV_METHODS);
+            #[cfg(varnishsys_6)]
+            priv_.put(cell, Some(ffi::vmod_priv::on_fini::<RefCell<T>>));
+            borrow(priv_.get_ref::<RefCell<T>>().unwrap_unchecked())
+        }
+    }
+
+    /// Remove the value from the current task, returning it if it was created
+    ///
+    /// Fails if the value is borrowed.
+    pub fn take(&self, ctx: &Ctx) -> Result<Option<T>, VclError> {
+        if let Some(cell) = self.cell(ctx) {
+            drop(borrow(cell)?);
+        }
+        let value = unsafe {
+            ffi::VRT_priv_task(ctx.raw, self.key())
+                .as_mut()
+                .and_then(|p| p.take::<RefCell<T>>())
+        };
+        Ok(value.map(|v| v.into_inner()))
+    }
+}
+
+fn borrow<T>(cell: &RefCell<T>) -> Result<RefMut<'_, T>, VclError> {
+    cell.try_borrow_mut()
+        .map_err(|_| "TaskLocal: the value is already in use in this task".into())
+}
@@ -0,0 +1,206 @@
+//! Compute a value once per task, even if VCL asks for it many times
+//!
+//! A vmod function is often called with the same argument several times during one request, e.g.
+//! once per header, or from several subroutines. A [`TaskMemo`] remembers the last results of
+//! the current task, keyed by string, so that an expensive computation like matching the
+//! `User-Agent` against a list of regexes is only done once per request:
+//!
+//! ``` rust,no_run
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use varnish::vcl::{Ctx, TaskMemo, VclError};
+//!
+//! static DEVICES: TaskMemo<&'static str> = TaskMemo::new(4);
+//!
+//! // e.g. the body of `pub fn device(ctx: &Ctx, ua: &str) -> &'static str` in a vmod
+//! fn device(ctx: &Ctx, ua: &str) -> Result<&'static str, VclError> {
+//!     DEVICES.get_or_insert_with(ctx, ua, || {
+//!         if ua.contains("Mobile") {
+//!             "mobile"
+//!         } else {
+//!             "desktop"
+//!         }
+//!     })
+//! }
+//! ```
+//!
+//! The cache only holds `capacity` entries and evicts the least recently used one when it is full.
+//! Its table is a [`WsMap`] allocated from the workspace on first use in each task, and the keys
+//! are copied next to it, so the values must be `Copy` like those of any [`WsMap`]. The
+//! workspace can't reclaim the copy of an evicted key, so a request with many distinct keys uses
+//! a little more of it with each miss, until the workspace is wiped at the end of the task.
+
+use std::fmt::{self, Debug};
+use std::str::from_utf8_unchecked;
+
+use crate::vcl::{Ctx, TaskLocal, VclError, Workspace, WsMap};
+
+/// A small per-task cache of computed values, see the [module documentation](self)
+pub struct TaskMemo<V: Copy + Send + 'static> {
+    // The table lives in the workspace of the task, which `varnishd` only wipes after dropping
+    // the `PRIV_TASK` data, so it outlives the `TaskLocal` holding it
+    entries: TaskLocal<Lru<'static, V>>,
+    capacity: usize,
+}
+
+impl<V: Copy + Send + 'static> Debug for TaskMemo<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskMemo")
+            .field("capacity", &self.capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<V: Copy + Send + 'static> TaskMemo<V> {
+    /// Create a cache of up to `capacity` entries per task, usually stored in a `static`
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            entries: TaskLocal::new(),
+            capacity,
+        }
+    }
+
+    /// The value computed for `key` in the current task, if it is still cached
+    pub fn get(&self, ctx: &Ctx, key: &str) -> Result<Option<V>, VclError> {
+        Ok(self.entries.get(ctx)?.and_then(|mut lru| lru.get(key)))
+    }
+
+    /// The value of `key` for the current task, computed with `compute` if it isn't cached
+    ///
+    /// The cache isn't borrowed while `compute` runs, so it may use the same [`TaskMemo`].
+    /// Fails if the workspace is too small for the table or the copy of `key`.
+    pub fn get_or_insert_with(
+        &self,
+        ctx: &Ctx,
+        key: &str,
+        compute: impl FnOnce() -> V,
+    ) -> Result<V, VclError> {
+        self.try_get_or_insert_with(ctx, key, || Ok(compute()))
+    }
+
+    /// Like [`TaskMemo::get_or_insert_with()`], but nothing is cached if `compute` fails
+    pub fn try_get_or_insert_with(
+        &self,
+        ctx: &Ctx,
+        key: &str,
+        compute: impl FnOnce() -> Result<V, VclError>,
+    ) -> Result<V, VclError> {
+        if let Some(value) = self.get(ctx, key)? {
+            return Ok(value);
+        }
+        let value = compute()?;
+        let mut ws = Workspace::from_ptr(ctx.ws.raw);
+        let mut lru = match self.entries.get(ctx)? {
+            Some(lru) => lru,
+            None => {
+                let lru = Lru::new(&mut ws, self.capacity)?;
+                self.entries.get_or_init(ctx, || lru)?
+            }
+        };
+        lru.insert(&mut ws, key, value)?;
+        Ok(value)
+    }
+
+    /// Forget the values of the current task, the table stays in the workspace for the next ones
+    pub fn clear(&self, ctx: &Ctx) -> Result<(), VclError> {
+        if let Some(mut lru) = self.entries.get(ctx)? {
+            lru.entries.clear();
+        }
+        Ok(())
+    }
+}
+
+/// The entries, with the value of `clock` when each of them was last used
+///
+/// The capacity is meant to be small, so finding the least recently used entry with a linear
+/// search is cheaper than keeping the entries in order.
+struct Lru<'a, V> {
+    entries: WsMap<'a, &'a str, (V, u64)>,
+    clock: u64,
+}
+
+impl<'a, V: Copy> Lru<'a, V> {
+    fn new(ws: &mut Workspace<'a>, capacity: usize) -> Result<Self, VclError> {
+        Ok(Self {
+            entries: WsMap::new(ws, capacity)?,
+            clock: 0,
+        })
+    }
+
+    fn get(&mut self, key: &str) -> Option<V> {
+        self.clock += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        *used = self.clock;
+        Some(*value)
+    }
+
+    fn insert(&mut self, ws: &mut Workspace<'a>, key: &str, value: V) -> Result<(), VclError> {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            *entry = (value, self.clock);
+            return Ok(());
+        }
+        if self.entries.capacity() == 0 {
+            return Ok(());
+        }
+        let key = if key.is_empty() {
+            ""
+        } else {
+            // a copy of a `&str` is valid UTF-8
+            unsafe { from_utf8_unchecked(ws.copy_bytes(key)?) }
+        };
+        if self.entries.len() == self.entries.capacity() {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(k, _)| *k)
+                .expect("the table is full");
+            self.entries.remove(oldest);
+        }
+        self.entries.insert(key, (value, self.clock))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcl::TestWS;
+
+    #[test]
+    fn lru() {
+        let mut test_ws = TestWS::new(4096);
+        let mut ws = test_ws.workspace();
+        let mut lru = Lru::new(&mut ws, 2).unwrap();
+        lru.insert(&mut ws, "a", 1).unwrap();
+        lru.insert(&mut ws, "b", 2).unwrap();
+        assert_eq!(lru.get("a"), Some(1));
+        lru.insert(&mut ws, "c", 3).unwrap();
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a"), Some(1));
+        assert_eq!(lru.get("c"), Some(3));
+
+        let free = ws.free();
+        lru.insert(&mut ws, "c", 4).unwrap();
+        assert_eq!(ws.free(), free);
+        lru.insert(&mut ws, "d", 5).unwrap();
+        assert!(ws.free() < free);
+        assert_eq!(lru.get("a"), None);
+        assert_eq!(lru.get("c"), Some(4));
+        assert_eq!(lru.get("d"), Some(5));
+        assert_eq!(lru.entries.len(), 2);
+
+        lru.insert(&mut ws, "", 6).unwrap();
+        assert_eq!(lru.get(""), Some(6));
+
+        let mut lru = Lru::new(&mut ws, 0).unwrap();
+        lru.insert(&mut ws, "a", 1).unwrap();
+        assert_eq!(lru.get("a"), None);
+
+        let mut small_ws = TestWS::new(160);
+        let mut ws = small_ws.workspace();
+        let mut lru = Lru::new(&mut ws, 2).unwrap();
+        assert!(lru.insert(&mut ws, &"x".repeat(200), 1).is_err());
+        assert_eq!(lru.entries.len(), 0);
+    }
+}
@@ -0,0 +1,282 @@
+//! Fill `{{placeholder}}` tokens in the body while it is delivered
+//!
+//! ESI assembles a page from fragments, each one being a separate request. When the cached page
+//! only needs a few small personalized values, e.g. the user name from a cookie, or the current
+//! date, a [`TemplateDeliveryProcessor`] is a lighter alternative: it replaces the placeholders
+//! of the body with the values given by a [`Template`], computed once per delivery:
+//!
+//! ``` rust,no_run
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use std::ffi::CStr;
+//! use varnish::vcl::{Ctx, DeliveryFilters, Event, InitResult, Template, TemplateDeliveryProcessor};
+//!
+//! struct Greeting {
+//!     user: Option<String>,
+//! }
+//!
+//! impl Template for Greeting {
+//!     fn name() -> &'static CStr {
+//!         c"greeting"
+//!     }
+//!
+//!     fn new(ctx: &mut Ctx) -> InitResult<Self> {
+//!         let user = ctx.req().and_then(|req| req.header("X-User")).map(String::from);
+//!         InitResult::Ok(Self { user })
+//!     }
+//!
+//!     fn value(&mut self, key: &str) -> Option<String> {
+//!         match key {
+//!             "user" => Some(self.user.clone().unwrap_or_else(|| "guest".to_string())),
+//!             _ => None,
+//!         }
+//!     }
+//! }
+//!
+//! // e.g. the body of the `#[event]` function of a vmod, then use
+//! // `set resp.filters = "greeting";` in `vcl_deliver` for the templated pages
+//! fn event(event: Event, vdp: &mut DeliveryFilters) {
+//!     if let Event::Load = event {
+//!         vdp.register::<TemplateDeliveryProcessor<Greeting>>();
+//!     }
+//! }
+//! ```
+//!
+//! A placeholder name is made of ASCII letters, digits, `_`, `-` and `.`, and is at most
+//! [`MAX_PLACEHOLDER_LEN`] bytes long. Placeholders may be split across the chunks of the body.
+//! Anything else, as well as the placeholders without a value, is delivered unchanged. The values
+//! are inserted as they are, so they must be escaped by the [`Template`] if needed, e.g. for HTML.
+//!
+//! The body can't be encoded: the processor skips the responses with a `Content-Encoding`. As the
+//! length of the body changes, `Content-Length` is removed.
+
+use std::ffi::CStr;
+
+use crate::ffi::VdpAction;
+use crate::vcl::{Ctx, DeliveryProcCtx, DeliveryProcessor, InitResult, PushResult};
+
+/// The longest placeholder name that is replaced
+pub const MAX_PLACEHOLDER_LEN: usize = 64;
+
+/// Describes the values filled in by a [`TemplateDeliveryProcessor`]
+pub trait Template: Sized {
+    /// The name of the processor, as used in `resp.filters`.
+    fn name() -> &'static CStr;
+    /// Prepare the values of the current delivery, e.g. from the request headers. Returning
+    /// [`InitResult::Pass`] removes the processor from the pipeline.
+    fn new(ctx: &mut Ctx) -> InitResult<Self>;
+    /// The value replacing `{{key}}`, or `None` to leave the placeholder as is
+    fn value(&mut self, key: &str) -> Option<String>;
+}
+
+/// A ready-made [`DeliveryProcessor`] filling the placeholders of the body with the values of a
+/// [`Template`], see the [module documentation](self)
+#[derive(Debug)]
+pub struct TemplateDeliveryProcessor<T: Template> {
+    template: T,
+    matcher: Matcher,
+    out: Vec<u8>,
+}
+
+impl<T: Template> DeliveryProcessor for TemplateDeliveryProcessor<T> {
+    fn name() -> &'static CStr {
+        T::name()
+    }
+
+    fn new(vrt_ctx: &mut Ctx, _: &mut DeliveryProcCtx) -> InitResult<Self> {
+        let Some(resp) = vrt_ctx.resp_mut() else {
+            return InitResult::Pass;
+        };
+        if resp.header("Content-Encoding").is_some() {
+            return InitResult::Pass;
+        }
+        let template = match T::new(vrt_ctx) {
+            InitResult::Ok(template) => template,
+            InitResult::Err(e) => return InitResult::Err(e),
+            InitResult::Pass => return InitResult::Pass,
+        };
+        if let Some(resp) = vrt_ctx.resp_mut() {
+            resp.unset_header("Content-Length");
+        }
+        if let Some(req) = unsafe { vrt_ctx.raw.req.as_mut() } {
+            req.resp_len = -1;
+        }
+        InitResult::Ok(Self {
+            template,
+            matcher: Matcher::default(),
+            out: Vec::new(),
+        })
+    }
+
+    fn push(&mut self, ctx: &mut DeliveryProcCtx, act: VdpAction, buf: &[u8]) -> PushResult {
+        let template = &mut self.template;
+        self.matcher
+            .feed(buf, &mut self.out, |key| template.value(key));
+        if matches!(act, VdpAction::End) {
+            self.matcher.finish(&mut self.out);
+        }
+        if self.out.is_empty() && matches!(act, VdpAction::Null) {
+            return PushResult::Ok;
+        }
+        let res = ctx.push(act, &self.out);
+        self.out.clear();
+        res
+    }
+}
+
+/// Where the matcher is in a possible placeholder
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Outside of a placeholder
+    #[default]
+    Text,
+    /// After `{`
+    Open,
+    /// In the name, after `{{`
+    Name,
+    /// After `{{name}`
+    Close,
+}
+
+/// Finds the placeholders in a body fed in chunks, keeping the start of a placeholder that may
+/// continue in the next chunk
+#[derive(Debug, Default)]
+struct Matcher {
+    state: State,
+    /// The bytes of the possible placeholder, not yet written
+    pending: Vec<u8>,
+}
+
+impl Matcher {
+    fn is_name_byte(b: u8) -> bool {
+        b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.')
+    }
+
+    /// Write `buf` to `out`, replacing the complete placeholders with their value
+    fn feed(
+        &mut self,
+        mut buf: &[u8],
+        out: &mut Vec<u8>,
+        mut value: impl FnMut(&str) -> Option<String>,
+    ) {
+        while let Some((&b, rest)) = buf.split_first() {
+            match self.state {
+                State::Text => {
+                    // copy the text up to the next brace at once
+                    let end = buf.iter().position(|&b| b == b'{').unwrap_or(buf.len());
+                    out.extend_from_slice(&buf[..end]);
+                    if end < buf.len() {
+                        self.pending.push(b'{');
+                        self.state = State::Open;
+                        buf = &buf[end + 1..];
+                    } else {
+                        buf = &[];
+                    }
+                    continue;
+                }
+                State::Open if b == b'{' => {
+                    self.pending.push(b);
+                    self.state = State::Name;
+                }
+                // `{{{`: the first brace is text
+                State::Name if b == b'{' && self.pending.len() == 2 => out.push(b'{'),
+                State::Name if Self::is_name_byte(b) => {
+                    if self.pending.len() - 2 == MAX_PLACEHOLDER_LEN {
+                        self.reset(out);
+                        continue;
+                    }
+                    self.pending.push(b);
+                }
+                State::Name if b == b'}' && self.pending.len() > 2 => {
+                    self.pending.push(b);
+                    self.state = State::Close;
+                }
+                State::Close if b == b'}' => {
+                    // only name bytes were pushed, so this is ASCII
+                    let name = std::str::from_utf8(&self.pending[2..self.pending.len() - 1])
+                        .unwrap_or_default();
+                    if let Some(v) = value(name) {
+                        out.extend_from_slice(v.as_bytes());
+                    } else {
+                        out.extend_from_slice(&self.pending);
+                        out.push(b);
+                    }
+                    self.pending.clear();
+                    self.state = State::Text;
+                }
+                // not a placeholder after all, `b` may start another one
+                _ => {
+                    self.reset(out);
+                    continue;
+                }
+            }
+            buf = rest;
+        }
+    }
+
+    /// Write the pending bytes as text
+    fn reset(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.pending);
+        self.state = State::Text;
+    }
+
+    /// Write what remains at the end of the body
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        self.reset(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(chunks: &[&[u8]]) -> String {
+        let mut matcher = Matcher::default();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            matcher.feed(chunk, &mut out, |key| match key {
+                "user" => Some("Alice".to_string()),
+                "empty" => Some(String::new()),
+                _ => None,
+            });
+        }
+        matcher.finish(&mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn placeholders() {
+        let cases = [
+            ("Hello {{user}}!", "Hello Alice!"),
+            ("{{user}}{{empty}}{{user}}", "AliceAlice"),
+            ("{{unknown}} {{user}}", "{{unknown}} Alice"),
+            ("{{{user}}}", "{Alice}"),
+            ("{ {{user}} }", "{ Alice }"),
+            ("{{ user }}", "{{ user }}"),
+            ("{{}} {{user} }", "{{}} {{user} }"),
+            ("{{user", "{{user"),
+            ("a {", "a {"),
+            ("{{us{{user}}", "{{usAlice"),
+            ("{{user}{{user}}", "{{user}Alice"),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(render(&[body.as_bytes()]), expected, "{body}");
+            // split the body at every position
+            for i in 0..=body.len() {
+                let (a, b) = body.as_bytes().split_at(i);
+                assert_eq!(render(&[a, b]), expected, "{body} split at {i}");
+            }
+            // one byte at a time
+            let bytes: Vec<&[u8]> = body.as_bytes().chunks(1).collect();
+            assert_eq!(render(&bytes), expected, "{body} bytewise");
+        }
+    }
+
+    #[test]
+    fn long_name() {
+        let name = "a".repeat(MAX_PLACEHOLDER_LEN + 1);
+        let body = format!("{{{{{name}}}}} {{{{user}}}}");
+        assert_eq!(render(&[body.as_bytes()]), format!("{{{{{name}}}}} Alice"));
+        let body = format!("{{{{{}}}}}", &name[1..]);
+        assert_eq!(render(&[body.as_bytes()]), body);
+    }
+}
@@ -0,0 +1,429 @@
+//! Format and parse timestamps, like `strftime()` and `strptime()` in UTC
+//!
+//! Cache header math constantly goes back and forth between [`SystemTime`] and HTTP dates.
+//! [`format()`] writes straight into the workspace, without any heap allocation, and [`parse()`]
+//! reads a string according to the same format language. The HTTP date formats are available as
+//! constants, and [`parse_http_date()`] accepts all three of them, as required by RFC 9110:
+//!
+//! ``` rust
+//! # mod varnish { pub use varnish_sys::vcl; }
+//! use std::time::{Duration, SystemTime};
+//! use varnish::vcl::time::{parse, parse_http_date, IMF_FIXDATE};
+//!
+//! let t = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+//! assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap(), t);
+//! assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT").unwrap(), t);
+//! assert_eq!(parse("1994-11-06T08:49:37Z", "%FT%TZ").unwrap(), t);
+//!
+//! let mut s = String::new();
+//! varnish::vcl::time::format_to(&mut s, t, IMF_FIXDATE).unwrap();
+//! assert_eq!(s, "Sun, 06 Nov 1994 08:49:37 GMT");
+//! ```
+//!
+//! The supported conversions are `%a %A %b %B %C %d %D %e %F %h %H %I %j %m %M %n %p %R %s %S %t
+//! %T %u %w %y %Y %z %Z %%`. Times are always in UTC, `%z` and `%Z` are respectively formatted as
+//! `+0000` and `UTC`, but `%z` offsets are honored when parsing.
+
+use std::fmt::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::ffi::VCL_STRING;
+use crate::vcl::{VclError, Workspace};
+
+/// The preferred HTTP date format, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub const IMF_FIXDATE: &str = "%a, %d %b %Y %T GMT";
+/// The obsolete RFC 850 HTTP date format, e.g. `Sunday, 06-Nov-94 08:49:37 GMT`
+pub const RFC850_DATE: &str = "%A, %d-%b-%y %T GMT";
+/// The obsolete `asctime()` HTTP date format, e.g. `Sun Nov  6 08:49:37 1994`
+pub const ASCTIME_DATE: &str = "%a %b %e %T %Y";
+
+const DAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Format `t` into the workspace, and return it as a VCL string
+///
+/// Nothing is allocated on the heap, the string is written directly into the free space of the
+/// workspace and the unused part is given back.
+pub fn format(ws: &mut Workspace, t: SystemTime, fmt: &str) -> Result<VCL_STRING, VclError> {
+    let mut buf = ws.str_buffer();
+    if format_to(&mut buf, t, fmt).is_err() {
+        return Err(VclError::Str("Workspace overflow while formatting a time"));
+    }
+    buf.finish()
+}
+
+/// Format `t` into any [`fmt::Write`], e.g. a `String`
+pub fn format_to<W: Write>(out: &mut W, t: SystemTime, fmt: &str) -> fmt::Result {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => i64::try_from(d.as_secs()).unwrap_or(i64::MAX),
+        // round towards the past, like the positive case
+        Err(e) => {
+            let d = e.duration();
+            let secs = i64::try_from(d.as_secs()).unwrap_or(i64::MAX);
+            if d.subsec_nanos() == 0 {
+                -secs
+            } else {
+                -secs - 1
+            }
+        }
+    };
+    write_secs(out, secs, fmt)
+}
+
+fn write_secs<W: Write>(out: &mut W, secs: i64, fmt: &str) -> fmt::Result {
+    let days = secs.div_euclid(86400);
+    let tod = secs.rem_euclid(86400);
+    let (hour, min, sec) = (tod / 3600, tod / 60 % 60, tod % 60);
+    let (year, month, day) = civil_from_days(days);
+    let yday = days - days_from_civil(year, 1, 1) + 1;
+    let wday = (days + 4).rem_euclid(7) as usize;
+    let month_name = MONTHS[month as usize - 1];
+
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.write_char(c)?;
+            continue;
+        }
+        match chars.next() {
+            Some('a') => out.write_str(&DAYS[wday][..3]),
+            Some('A') => out.write_str(DAYS[wday]),
+            Some('b' | 'h') => out.write_str(&month_name[..3]),
+            Some('B') => out.write_str(month_name),
+            Some('C') => write!(out, "{:02}", year / 100),
+            Some('d') => write!(out, "{day:02}"),
+            Some('D') => write!(out, "{month:02}/{day:02}/{:02}", year % 100),
+            Some('e') => write!(out, "{day:2}"),
+            Some('F') => write!(out, "{year}-{month:02}-{day:02}"),
+            Some('H') => write!(out, "{hour:02}"),
+            Some('I') => write!(out, "{:02}", (hour + 11) % 12 + 1),
+            Some('j') => write!(out, "{yday:03}"),
+            Some('m') => write!(out, "{month:02}"),
+            Some('M') => write!(out, "{min:02}"),
+            Some('n') => out.write_char('\n'),
+            Some('p') => out.write_str(if hour < 12 { "AM" } else { "PM" }),
+            Some('R') => write!(out, "{hour:02}:{min:02}"),
+            Some('s') => write!(out, "{secs}"),
+            Some('S') => write!(out, "{sec:02}"),
+            Some('t') => out.write_char('\t'),
+            Some('T') => write!(out, "{hour:02}:{min:02}:{sec:02}"),
+            Some('u') => write!(out, "{}", if wday == 0 { 7 } else { wday }),
+            Some('w') => write!(out, "{wday}"),
+            Some('y') => write!(out, "{:02}", year % 100),
+            Some('Y') => write!(out, "{year}"),
+            Some('z') => out.write_str("+0000"),
+            Some('Z') => out.write_str("UTC"),
+            Some('%') | None => out.write_char('%'),
+            Some(c) => write!(out, "%{c}"),
+        }?;
+    }
+    Ok(())
+}
+
+/// Parse `s` according to `fmt`, which uses the same conversions as [`format()`]
+///
+/// Whitespace in `fmt` matches any amount of whitespace in `s`, and names are matched without
+/// regard to case. Fields that are not part of the format default to the UNIX epoch.
+pub fn parse(s: &str, fmt: &str) -> Result<SystemTime, VclError> {
+    let mut parser = Parser {
+        rest: s,
+        ..Parser::default()
+    };
+    parser
+        .run(fmt)
+        .and_then(|()| {
+            if parser.rest.trim().is_empty() {
+                parser.to_secs()
+            } else {
+                Err("unexpected trailing characters")
+            }
+        })
+        .map(|secs| {
+            let d = Duration::from_secs(secs.unsigned_abs());
+            if secs < 0 {
+                UNIX_EPOCH - d
+            } else {
+                UNIX_EPOCH + d
+            }
+        })
+        .map_err(|e| VclError::String(format!("Cannot parse {s:?} with {fmt:?}: {e}")))
+}
+
+/// Parse an HTTP date in any of the [`IMF_FIXDATE`], [`RFC850_DATE`] or [`ASCTIME_DATE`] formats
+pub fn parse_http_date(s: &str) -> Result<SystemTime, VclError> {
+    parse(s, IMF_FIXDATE)
+        .or_else(|_| parse(s, RFC850_DATE))
+        .or_else(|_| parse(s, ASCTIME_DATE))
+        .map_err(|_| VclError::String(format!("Invalid HTTP date: {s:?}")))
+}
+
+/// Days since the epoch from a civil date, see <https://howardhinnant.github.io/date_algorithms.html>
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Civil date from days since the epoch, the reverse of [`days_from_civil()`]
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    days_from_civil(year + month / 12, month % 12 + 1, 1) - days_from_civil(year, month, 1)
+}
+
+#[derive(Debug)]
+struct Parser<'a> {
+    rest: &'a str,
+    year: i64,
+    month: i64,
+    day: i64,
+    yday: Option<i64>,
+    hour: i64,
+    pm: Option<bool>,
+    min: i64,
+    sec: i64,
+    offset: i64,
+    epoch: Option<i64>,
+}
+
+impl Default for Parser<'_> {
+    fn default() -> Self {
+        Self {
+            rest: "",
+            year: 1970,
+            month: 1,
+            day: 1,
+            yday: None,
+            hour: 0,
+            pm: None,
+            min: 0,
+            sec: 0,
+            offset: 0,
+            epoch: None,
+        }
+    }
+}
+
+impl Parser<'_> {
+    fn run(&mut self, fmt: &str) -> Result<(), &'static str> {
+        let mut chars = fmt.chars();
+        while let Some(c) = chars.next() {
+            if c.is_whitespace() {
+                self.rest = self.rest.trim_start();
+                continue;
+            }
+            if c != '%' {
+                self.rest = self.rest.strip_prefix(c).ok_or("literal mismatch")?;
+                continue;
+            }
+            match chars.next() {
+                Some('a' | 'A') => {
+                    self.name(&DAYS)?;
+                }
+                Some('b' | 'B' | 'h') => self.month = self.name(&MONTHS)? + 1,
+                Some('C') => self.year = self.num(2, 0, 99)? * 100 + self.year % 100,
+                Some('d') => self.day = self.num(2, 1, 31)?,
+                Some('e') => {
+                    self.rest = self.rest.trim_start();
+                    self.day = self.num(2, 1, 31)?;
+                }
+                Some('D') => self.run("%m/%d/%y")?,
+                Some('F') => self.run("%Y-%m-%d")?,
+                Some('H') => self.hour = self.num(2, 0, 23)?,
+                Some('I') => self.hour = self.num(2, 1, 12)? % 12,
+                Some('j') => self.yday = Some(self.num(3, 1, 366)?),
+                Some('m') => self.month = self.num(2, 1, 12)?,
+                Some('M') => self.min = self.num(2, 0, 59)?,
+                Some('n' | 't') => self.rest = self.rest.trim_start(),
+                Some('p') => self.pm = Some(self.name(&["AM", "PM"])? == 1),
+                Some('R') => self.run("%H:%M")?,
+                Some('s') => self.epoch = Some(self.signed(19)?),
+                Some('S') => self.sec = self.num(2, 0, 60)?,
+                Some('T') => self.run("%H:%M:%S")?,
+                Some('u') => {
+                    self.num(1, 1, 7)?;
+                }
+                Some('w') => {
+                    self.num(1, 0, 6)?;
+                }
+                Some('y') => {
+                    let y = self.num(2, 0, 99)?;
+                    self.year = if y < 69 { 2000 + y } else { 1900 + y };
+                }
+                Some('Y') => self.year = self.signed(4)?,
+                Some('z') => self.offset = self.zone_offset()?,
+                Some('Z') => {
+                    self.name(&["UTC", "GMT", "Z"])?;
+                }
+                Some('%') | None => {
+                    self.rest = self.rest.strip_prefix('%').ok_or("expected '%'")?;
+                }
+                Some(_) => return Err("unsupported conversion"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read an unsigned number of at most `max_len` digits, within `min..=max`
+    fn num(&mut self, max_len: usize, min: i64, max: i64) -> Result<i64, &'static str> {
+        let len = self
+            .rest
+            .bytes()
+            .take(max_len)
+            .take_while(u8::is_ascii_digit)
+            .count();
+        let v = self.rest[..len].parse().map_err(|_| "expected a number")?;
+        if !(min..=max).contains(&v) {
+            return Err("number out of range");
+        }
+        self.rest = &self.rest[len..];
+        Ok(v)
+    }
+
+    /// Read a number of at most `max_len` digits, with an optional sign
+    fn signed(&mut self, max_len: usize) -> Result<i64, &'static str> {
+        let neg = if let Some(rest) = self.rest.strip_prefix('-') {
+            self.rest = rest;
+            true
+        } else {
+            self.rest = self.rest.strip_prefix('+').unwrap_or(self.rest);
+            false
+        };
+        let v = self.num(max_len, 0, i64::MAX)?;
+        Ok(if neg { -v } else { v })
+    }
+
+    /// Match a name, full or abbreviated to 3 characters, returning its index
+    fn name(&mut self, names: &[&str]) -> Result<i64, &'static str> {
+        for (idx, name) in names.iter().enumerate() {
+            for len in [name.len(), 3] {
+                let Some(candidate) = self.rest.get(..len.min(name.len())) else {
+                    continue;
+                };
+                if candidate.eq_ignore_ascii_case(&name[..candidate.len()]) {
+                    self.rest = &self.rest[candidate.len()..];
+                    return Ok(idx as i64);
+                }
+            }
+        }
+        Err("unknown name")
+    }
+
+    /// Read a `+hhmm`, `-hh:mm` or `Z` offset, in seconds
+    fn zone_offset(&mut self) -> Result<i64, &'static str> {
+        if let Some(rest) = self.rest.strip_prefix(['Z', 'z']) {
+            self.rest = rest;
+            return Ok(0);
+        }
+        let neg = match self.rest.as_bytes().first() {
+            Some(b'+') => false,
+            Some(b'-') => true,
+            _ => return Err("expected a time zone offset"),
+        };
+        self.rest = &self.rest[1..];
+        let hours = self.num(2, 0, 23)?;
+        self.rest = self.rest.strip_prefix(':').unwrap_or(self.rest);
+        let mins = self.num(2, 0, 59)?;
+        let offset = hours * 3600 + mins * 60;
+        Ok(if neg { -offset } else { offset })
+    }
+
+    fn to_secs(&self) -> Result<i64, &'static str> {
+        if let Some(epoch) = self.epoch {
+            return Ok(epoch);
+        }
+        let days = if let Some(yday) = self.yday {
+            days_from_civil(self.year, 1, 1) + yday - 1
+        } else {
+            if self.day > days_in_month(self.year, self.month) {
+                return Err("day out of range");
+            }
+            days_from_civil(self.year, self.month, self.day)
+        };
+        let hour = match self.pm {
+            Some(true) => self.hour % 12 + 12,
+            Some(false) => self.hour % 12,
+            None => self.hour,
+        };
+        Ok(days * 86400 + hour * 3600 + self.min * 60 + self.sec - self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcl::TestWS;
+
+    #[test]
+    fn format_parse() {
+        let t = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        for (fmt, s) in [
+            (IMF_FIXDATE, "Sun, 06 Nov 1994 08:49:37 GMT"),
+            (RFC850_DATE, "Sunday, 06-Nov-94 08:49:37 GMT"),
+            (ASCTIME_DATE, "Sun Nov  6 08:49:37 1994"),
+            ("%F %I:%M:%S %p, day %j", "1994-11-06 08:49:37 AM, day 310"),
+        ] {
+            let mut out = String::new();
+            format_to(&mut out, t, fmt).unwrap();
+            assert_eq!(out, s);
+            assert_eq!(parse(s, fmt).unwrap(), t);
+            assert_eq!(parse_http_date(s).is_ok(), fmt != "%F %I:%M:%S %p, day %j");
+        }
+        assert_eq!(parse("1994-11-06T09:49:37+01:00", "%FT%T%z").unwrap(), t);
+        assert_eq!(parse("784111777", "%s").unwrap(), t);
+        assert_eq!(
+            parse("1969-12-31", "%F").unwrap(),
+            UNIX_EPOCH - Duration::from_secs(86400)
+        );
+        assert!(parse("Sun, 31 Feb 1994 08:49:37 GMT", IMF_FIXDATE).is_err());
+        assert!(parse("Sun, 06 Nov 1994 08:49:37 GMT trailing", IMF_FIXDATE).is_err());
+        assert!(parse_http_date("yesterday").is_err());
+
+        let mut test_ws = TestWS::new(160);
+        let mut ws = test_ws.workspace();
+        let s = format(&mut ws, t, IMF_FIXDATE).unwrap();
+        assert_eq!(
+            <Option<&std::ffi::CStr>>::from(s)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        assert!(format(&mut ws, t, &"%Y".repeat(100)).is_err());
+    }
+}
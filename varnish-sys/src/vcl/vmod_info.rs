@@ -0,0 +1,102 @@
+//! Tell which build of a vmod is loaded
+//!
+//! The code generated by `#[vmod]` adds a private `vmod_info()` function to the module, returning
+//! the [`VmodInfo`] of the vmod. It can be exported to VCL, or logged when a VCL is loaded with
+//! [`Ctx::log_banner()`]:
+//!
+//! ``` rust,ignore
+//! #[varnish::vmod]
+//! mod example {
+//!     use varnish::vcl::{Ctx, Event};
+//!
+//!     /// e.g. `example.version()` returns `vmod_example 0.4.0 (git 1a2b3c4, Varnish 7.6.1 ...)`
+//!     pub fn version() -> String {
+//!         vmod_info().to_string()
+//!     }
+//!
+//!     #[event]
+//!     pub fn event(ctx: &mut Ctx, event: Event) {
+//!         if event == Event::Load {
+//!             ctx.log_banner(vmod_info());
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! The git hash is read from the `VMOD_GIT_HASH` environment variable when the vmod is built, e.g.
+//! set by the `build.rs` of the vmod:
+//!
+//! ``` rust,ignore
+//! fn main() {
+//!     let hash = std::process::Command::new("git")
+//!         .args(["rev-parse", "--short", "HEAD"])
+//!         .output()
+//!         .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+//!         .unwrap_or_default();
+//!     println!("cargo:rustc-env=VMOD_GIT_HASH={hash}");
+//! }
+//! ```
+
+use std::ffi::CStr;
+use std::fmt;
+
+use crate::vcl::{Buffer, Ctx, LogTag};
+
+/// The name and the build of a vmod, see the [module documentation](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmodInfo {
+    /// The name of the vmod, as imported in VCL
+    pub name: &'static str,
+    /// The version of the crate of the vmod, from its `Cargo.toml`
+    pub version: &'static str,
+    /// The git commit the vmod was built from, from the `VMOD_GIT_HASH` environment variable
+    pub git_hash: Option<&'static str>,
+    /// The `VMOD_ABI_Version` the vmod was built against, e.g. `Varnish 7.6.1 c3d5882...`
+    pub abi: &'static CStr,
+}
+
+impl fmt::Display for VmodInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vmod_{} {} (", self.name, self.version)?;
+        if let Some(hash) = self.git_hash.filter(|h| !h.is_empty()) {
+            write!(f, "git {hash}, ")?;
+        }
+        write!(f, "{})", self.abi.to_string_lossy())
+    }
+}
+
+impl Ctx<'_> {
+    /// Log the build of the vmod as a `Debug` record. In the `Load` event, it is also shown in the
+    /// output of the `vcl.load` CLI command.
+    pub fn log_banner(&mut self, info: &VmodInfo) {
+        let banner = info.to_string();
+        if !self.raw.msg.is_null() {
+            let _ = Buffer::from_ptr(self.raw.msg).write(&format!("{banner}\n"));
+        }
+        self.log(LogTag::Debug, banner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vmod_info_display() {
+        let mut info = VmodInfo {
+            name: "example",
+            version: "0.4.0",
+            git_hash: Some("1a2b3c4"),
+            abi: c"Varnish 7.6.1 c3d5882003eb87e5e93dc09fb9513ca96db3ca3c",
+        };
+        assert_eq!(
+            info.to_string(),
+            "vmod_example 0.4.0 (git 1a2b3c4, Varnish 7.6.1 c3d5882003eb87e5e93dc09fb9513ca96db3ca3c)"
+        );
+        info.git_hash = Some("");
+        assert_eq!(
+            info.to_string(),
+            "vmod_example 0.4.0 (Varnish 7.6.1 c3d5882003eb87e5e93dc09fb9513ca96db3ca3c)"
+        );
+    }
+}
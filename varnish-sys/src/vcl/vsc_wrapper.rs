@@ -0,0 +1,352 @@
+//! Export counters to `varnishstat` and the other VSC readers
+//!
+//! A [`VscSegment`] is a set of `u64` values published in the shared memory of `varnishd`, next
+//! to its own counters. Each field of a segment is named `vmod.<vmod>.<instance>.<field>`, so a
+//! reader can select the counters of a vmod with a glob, e.g. `varnishstat -f 'vmod.rate.*'`, or
+//! `varnish::vsc::StatsBuilder::include()` in Rust.
+//!
+//! The names may only contain ASCII letters, digits, `_` and `-`, so that they can't be mistaken
+//! for glob patterns or for another level of the dotted name, and two live segments can't have
+//! the same name.
+//!
+//! ``` rust,ignore
+//! use std::sync::atomic::Ordering::Relaxed;
+//! use varnish::vcl::{VscField, VscSegment};
+//!
+//! // in an object constructor, `vcl_name` being the name of the object in VCL
+//! let vsc = VscSegment::new(
+//!     "rate",
+//!     vcl_name,
+//!     "Rate limiter",
+//!     vec![
+//!         VscField::counter("allowed", "Requests allowed"),
+//!         VscField::counter("denied", "Requests denied"),
+//!         VscField::gauge("buckets", "Live buckets"),
+//!     ],
+//! )?;
+//! vsc.get("denied").unwrap().fetch_add(1, Relaxed);
+//! ```
+
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::fmt::{self, Write as _};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Mutex, PoisonError};
+
+use crate::ffi;
+use crate::vcl::{VclError, VclResult};
+
+/// The first part of the name of every segment, i.e. the VSC type in the JSON schema
+const VSC_TYPE: &std::ffi::CStr = c"vmod";
+
+/// The names of the live segments, without the `vmod.` prefix
+static LIVE: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// How a field varies, its `type` in the VSC schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VscKind {
+    /// Only goes up, readers compute rates from it
+    Counter,
+    /// Goes up and down
+    Gauge,
+    /// 64 flags packed together
+    Bitmap,
+}
+
+/// How a field is displayed, its `format` in the VSC schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VscFormat {
+    Integer,
+    Bytes,
+    Bitmap,
+    /// A number of seconds
+    Duration,
+}
+
+/// Who a field is meant for, its `level` in the VSC schema, `varnishstat` hides the debug ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VscLevel {
+    Info,
+    Diag,
+    Debug,
+}
+
+/// The description of a field of a [`VscSegment`]
+#[derive(Debug, Clone)]
+pub struct VscField {
+    /// The last part of the name, e.g. `hits`
+    pub name: String,
+    pub kind: VscKind,
+    pub format: VscFormat,
+    pub level: VscLevel,
+    /// A short description, shown by `varnishstat`
+    pub oneliner: String,
+    /// A longer description, may be empty
+    pub docs: String,
+}
+
+impl VscField {
+    fn new(name: &str, kind: VscKind, format: VscFormat, oneliner: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            format,
+            level: VscLevel::Info,
+            oneliner: oneliner.to_string(),
+            docs: String::new(),
+        }
+    }
+
+    /// An integer that only goes up
+    pub fn counter(name: &str, oneliner: &str) -> Self {
+        Self::new(name, VscKind::Counter, VscFormat::Integer, oneliner)
+    }
+
+    /// An integer that goes up and down
+    pub fn gauge(name: &str, oneliner: &str) -> Self {
+        Self::new(name, VscKind::Gauge, VscFormat::Integer, oneliner)
+    }
+
+    /// 64 flags
+    pub fn bitmap(name: &str, oneliner: &str) -> Self {
+        Self::new(name, VscKind::Bitmap, VscFormat::Bitmap, oneliner)
+    }
+}
+
+/// Counters published in the shared memory, see the [module documentation](self)
+///
+/// The segment is removed when it is dropped.
+pub struct VscSegment {
+    seg: *mut ffi::vsc_seg,
+    values: NonNull<AtomicU64>,
+    fields: Vec<VscField>,
+    /// `<vmod>.<instance>`
+    ident: String,
+}
+
+// The values are atomics, and the segment is only used again to destroy it
+unsafe impl Send for VscSegment {}
+unsafe impl Sync for VscSegment {}
+
+impl VscSegment {
+    /// Publish the `fields` of a segment named `vmod.<vmod>.<instance>`, all starting at 0.
+    ///
+    /// Fails if a name is invalid, if two fields have the same name, or if a segment with the
+    /// same name is still alive.
+    pub fn new(
+        vmod: &str,
+        instance: &str,
+        oneliner: &str,
+        fields: Vec<VscField>,
+    ) -> VclResult<Self> {
+        let json = schema(oneliner, &fields)?;
+        check_name("vmod", vmod)?;
+        check_name("instance", instance)?;
+        let ident = format!("{vmod}.{instance}");
+        let fmt = CString::new(ident.as_str()).map_err(|e| VclError::String(e.to_string()))?;
+        register(&ident)?;
+
+        let mut seg = ptr::null_mut();
+        let values = unsafe {
+            // `fmt` has no `%` directive, so the `va_list` is never read
+            ffi::VRT_VSC_Alloc(
+                ptr::null_mut(),
+                &raw mut seg,
+                VSC_TYPE.as_ptr(),
+                fields.len() * size_of::<u64>(),
+                json.as_ptr(),
+                json.len(),
+                fmt.as_ptr(),
+                std::mem::zeroed(),
+            )
+        };
+        let Some(values) = NonNull::new(values.cast::<AtomicU64>()) else {
+            unregister(&ident);
+            return Err(VclError::String(format!(
+                "could not allocate the vmod.{ident} counters"
+            )));
+        };
+        Ok(Self {
+            seg,
+            values,
+            fields,
+            ident,
+        })
+    }
+
+    /// The full name of the segment, e.g. `vmod.rate.limiter`
+    pub fn name(&self) -> String {
+        format!("vmod.{}", self.ident)
+    }
+
+    /// The fields, in the order of [`VscSegment::values()`]
+    pub fn fields(&self) -> &[VscField] {
+        &self.fields
+    }
+
+    /// The values of the fields, in the order of [`VscSegment::fields()`]
+    pub fn values(&self) -> &[AtomicU64] {
+        unsafe { std::slice::from_raw_parts(self.values.as_ptr(), self.fields.len()) }
+    }
+
+    /// The value of the field called `name`
+    pub fn get(&self, name: &str) -> Option<&AtomicU64> {
+        let idx = self.fields.iter().position(|f| f.name == name)?;
+        self.values().get(idx)
+    }
+}
+
+impl Drop for VscSegment {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::VRT_VSC_Destroy(VSC_TYPE.as_ptr(), self.seg);
+        }
+        unregister(&self.ident);
+    }
+}
+
+impl fmt::Debug for VscSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VscSegment")
+            .field("name", &self.name())
+            .field("fields", &self.fields)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `name` can be used as the vmod, instance, or field part of the name of a segment
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+}
+
+fn check_name(what: &str, name: &str) -> VclResult<()> {
+    if !is_valid_name(name) {
+        return Err(VclError::String(format!(
+            "invalid VSC {what} name {name:?}, only ASCII letters, digits, '_' and '-' are allowed"
+        )));
+    }
+    Ok(())
+}
+
+fn register(ident: &str) -> VclResult<()> {
+    let mut live = LIVE.lock().unwrap_or_else(PoisonError::into_inner);
+    if !live.insert(ident.to_string()) {
+        return Err(VclError::String(format!(
+            "the VSC segment vmod.{ident} already exists"
+        )));
+    }
+    Ok(())
+}
+
+fn unregister(ident: &str) {
+    LIVE.lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(ident);
+}
+
+/// The JSON description of a segment, as generated by `vsctool.py` for the counters of Varnish
+fn schema(oneliner: &str, fields: &[VscField]) -> VclResult<String> {
+    let mut names = BTreeSet::new();
+    let mut json = String::new();
+    write!(
+        json,
+        r#"{{"version":"1","name":"vmod","oneliner":{},"order":100,"docs":"","elements":{},"elem":{{"#,
+        json_str(oneliner),
+        fields.len()
+    )
+    .unwrap();
+    for (idx, field) in fields.iter().enumerate() {
+        check_name("field", &field.name)?;
+        if !names.insert(field.name.as_str()) {
+            return Err(VclError::String(format!(
+                "duplicate VSC field name {:?}",
+                field.name
+            )));
+        }
+        let kind = match field.kind {
+            VscKind::Counter => "counter",
+            VscKind::Gauge => "gauge",
+            VscKind::Bitmap => "bitmap",
+        };
+        let format = match field.format {
+            VscFormat::Integer => "integer",
+            VscFormat::Bytes => "bytes",
+            VscFormat::Bitmap => "bitmap",
+            VscFormat::Duration => "duration",
+        };
+        let level = match field.level {
+            VscLevel::Info => "info",
+            VscLevel::Diag => "diag",
+            VscLevel::Debug => "debug",
+        };
+        let name = json_str(&field.name);
+        write!(
+            json,
+            r#"{}{name}:{{"type":"{kind}","ctype":"uint64_t","level":"{level}","oneliner":{},"format":"{format}","index":{},"name":{name},"docs":{}}}"#,
+            if idx == 0 { "" } else { "," },
+            json_str(&field.oneliner),
+            idx * size_of::<u64>(),
+            json_str(&field.docs),
+        )
+        .unwrap();
+    }
+    json.push_str("}}");
+    Ok(json)
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => write!(out, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vsc_schema() {
+        let mut bytes = VscField::gauge("bytes", "Bytes \"in\" use");
+        bytes.format = VscFormat::Bytes;
+        assert_eq!(
+            schema("Test", &[VscField::counter("hits", "Hits"), bytes]).unwrap(),
+            concat!(
+                r#"{"version":"1","name":"vmod","oneliner":"Test","order":100,"docs":"","elements":2,"elem":{"#,
+                r#""hits":{"type":"counter","ctype":"uint64_t","level":"info","oneliner":"Hits","format":"integer","index":0,"name":"hits","docs":""},"#,
+                r#""bytes":{"type":"gauge","ctype":"uint64_t","level":"info","oneliner":"Bytes \"in\" use","format":"bytes","index":8,"name":"bytes","docs":""}}}"#,
+            )
+        );
+
+        let hits = VscField::counter("hits", "");
+        assert!(schema("", &[hits.clone(), hits]).is_err());
+        assert!(schema("", &[VscField::counter("a.b", "")]).is_err());
+    }
+
+    #[test]
+    fn vsc_names() {
+        assert!(check_name("vmod", "rate_limit-2").is_ok());
+        for name in ["", "a.b", "a*", "a b", "é"] {
+            assert!(check_name("vmod", name).is_err());
+        }
+
+        register("test.one").unwrap();
+        assert!(register("test.one").is_err());
+        unregister("test.one");
+        register("test.one").unwrap();
+        unregister("test.one");
+    }
+}
@@ -13,20 +13,26 @@
 
 use std::any::type_name;
 use std::ffi::{c_char, c_void, CStr};
+use std::fmt;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::mem::{align_of, size_of, transmute, MaybeUninit};
 use std::num::NonZeroUsize;
+use std::ops::{Deref, DerefMut};
 use std::ptr;
 use std::slice::from_raw_parts_mut;
 
 use memchr::memchr;
 
-#[cfg(varnishsys_6)]
+#[cfg(not(any(varnishsys_6, test, miri, feature = "bench")))]
+use crate::ffi::WS_Allocated;
+#[cfg(all(varnishsys_6, not(any(test, miri, feature = "bench"))))]
 use crate::ffi::WS_Inside;
 use crate::ffi::{txt, VCL_STRING};
 #[cfg(not(varnishsys_6))]
-use crate::ffi::{vrt_blob, WS_Allocated, VCL_BLOB};
+use crate::ffi::{vrt_blob, VCL_BLOB};
+#[cfg(feature = "chaos")]
+use crate::vcl::chaos::{self, Fault};
 use crate::vcl::VclError;
 use crate::{ffi, validate_ws};
 
@@ -59,33 +65,33 @@ impl<'a> Workspace<'a> {
     /// # Safety
     /// Allocated memory is not initialized.
     pub unsafe fn alloc(&mut self, size: NonZeroUsize) -> *mut c_void {
-        #[cfg(not(test))]
+        #[cfg(feature = "chaos")]
+        if chaos::fails(Fault::Workspace) {
+            return ptr::null_mut();
+        }
+
+        #[cfg(not(any(test, miri, feature = "bench")))]
         {
             ffi::WS_Alloc(validate_ws(self.raw), size.get() as u32)
         }
 
-        #[cfg(test)]
+        // `WS_Alloc` is a private part of `varnishd`, not the Varnish library,
+        // so it is only available if the output is a `cdylib`.
+        // When testing or benchmarking, VMOD is a lib or a bin,
+        // so we have to fake our own allocator.
+        #[cfg(any(test, miri, feature = "bench"))]
         {
-            // `WS_Alloc` is a private part of `varnishd`, not the Varnish library,
-            // so it is only available if the output is a `cdylib`.
-            // When testing, VMOD is a lib or a bin,
-            // so we have to fake our own allocator.
-            let ws = validate_ws(self.raw);
-            let align = align_of::<*const c_void>();
-            let aligned_sz = size.get().div_ceil(align) * align;
-            if ws.e.offset_from(ws.f) < aligned_sz as isize {
-                ptr::null_mut()
-            } else {
-                let p = ws.f.cast::<c_void>();
-                ws.f = ws.f.add(aligned_sz);
-                p
-            }
+            shadow::alloc(validate_ws(self.raw), size.get())
         }
     }
 
     /// Check if a pointer is part of the current workspace
     pub fn contains(&self, data: &[u8]) -> bool {
-        #[cfg(varnishsys_6)]
+        #[cfg(any(test, miri, feature = "bench"))]
+        {
+            shadow::allocated(unsafe { validate_ws(self.raw) }, data)
+        }
+        #[cfg(all(varnishsys_6, not(any(test, miri, feature = "bench"))))]
         {
             let last = match data.last() {
                 None => data.as_ptr(),
@@ -93,12 +99,33 @@ impl<'a> Workspace<'a> {
             };
             unsafe { WS_Inside(self.raw, data.as_ptr().cast(), last.cast()) == 1 }
         }
-        #[cfg(not(varnishsys_6))]
+        #[cfg(not(any(varnishsys_6, test, miri, feature = "bench")))]
         {
             unsafe { WS_Allocated(self.raw, data.as_ptr().cast(), data.len() as isize) == 1 }
         }
     }
 
+    /// Total size of the workspace, in bytes
+    ///
+    /// For the workspace of a backend task, this is roughly the `workspace_backend` parameter
+    /// minus what `varnishd` uses for its own bookkeeping, and likewise with `workspace_client`
+    /// for a client task. Use it to size buffers instead of hardcoding a guess, see also
+    /// [`Ctx::workspace_sizes()`](crate::vcl::Ctx::workspace_sizes) for the other workspaces.
+    pub fn capacity(&self) -> usize {
+        let ws = unsafe { validate_ws(self.raw) };
+        unsafe { ws.e.offset_from(ws.s) as usize }
+    }
+
+    /// How many bytes can still be allocated, zero if the workspace is currently reserved
+    pub fn free(&self) -> usize {
+        let ws = unsafe { validate_ws(self.raw) };
+        if ws.r.is_null() {
+            unsafe { ws.e.offset_from(ws.f) as usize }
+        } else {
+            0
+        }
+    }
+
     /// Allocate `[u8; size]` array on Workspace.
     /// Returns a reference to uninitialized buffer, or an out of memory error.
     pub fn allocate(&mut self, size: NonZeroUsize) -> Result<&'a mut [MaybeUninit<u8>], VclError> {
@@ -132,7 +159,7 @@ impl<'a> Workspace<'a> {
     }
 
     /// Copy any `AsRef<[u8]>` into the workspace
-    fn copy_bytes(&mut self, src: impl AsRef<[u8]>) -> Result<&'a [u8], VclError> {
+    pub(crate) fn copy_bytes(&mut self, src: impl AsRef<[u8]>) -> Result<&'a [u8], VclError> {
         // Re-implement unstable `maybe_uninit_write_slice` and `maybe_uninit_slice`
         // See https://github.com/rust-lang/rust/issues/79995
         // See https://github.com/rust-lang/rust/issues/63569
@@ -201,7 +228,17 @@ impl<'a> Workspace<'a> {
         let ws = unsafe { validate_ws(self.raw) };
 
         unsafe {
+            #[cfg(not(any(test, miri, feature = "bench")))]
             let sz = ffi::WS_ReserveAll(ws) as usize;
+            #[cfg(any(test, miri, feature = "bench"))]
+            let sz = shadow::reserve_all(ws);
+            // an exhausted workspace leaves nothing to reserve
+            #[cfg(feature = "chaos")]
+            let sz = if chaos::fails(Fault::Workspace) {
+                0
+            } else {
+                sz
+            };
             let buf = from_raw_parts_mut(ws.f.cast::<u8>(), sz);
             ReservedBuf {
                 buf,
@@ -211,6 +248,134 @@ impl<'a> Workspace<'a> {
             }
         }
     }
+
+    /// Reserve the free space of the workspace to write a string in it, in as many pieces as
+    /// needed, without knowing its length beforehand. Nothing else can be allocated from the
+    /// workspace until the [`WsStrBuffer`] is finished or dropped.
+    ///
+    /// ``` ignore
+    /// use std::fmt::Write;
+    /// use varnish::vcl::TestWS;
+    ///
+    /// let mut test_ws = TestWS::new(160);
+    /// let mut ws = test_ws.workspace();
+    /// let mut buf = ws.str_buffer();
+    /// for part in ["a", "b", "c"] {
+    ///     buf.push_str(part)?;
+    /// }
+    /// write!(buf, "-{}", 42).unwrap();
+    /// let value = buf.finish()?;
+    /// assert_eq!(<&str>::try_from(value)?, "abc-42");
+    /// # Ok::<(), varnish::vcl::VclError>(())
+    /// ```
+    pub fn str_buffer(&mut self) -> WsStrBuffer<'a> {
+        WsStrBuffer {
+            reserved: self.reserve(),
+            len: 0,
+            needed: 0,
+            has_null: false,
+        }
+    }
+
+    /// Run `f` with a [`WorkspaceScope`], rolling back everything it allocated when it returns,
+    /// unless [`WorkspaceScope::commit()`] was called. This makes it cheap to try building a large
+    /// value, and fall back to something else if it does not fit.
+    ///
+    /// The allocations cannot outlive the closure, but a committed value can be passed on as a
+    /// raw VCL type, e.g. a `VCL_STRING`.
+    ///
+    /// ``` ignore
+    /// use varnish::vcl::TestWS;
+    ///
+    /// let mut test_ws = TestWS::new(160);
+    /// let mut ws = test_ws.workspace();
+    /// let value = ws.scope(|ws| {
+    ///     // too big, nothing is kept
+    ///     ws.copy_bytes_with_null([b'x'; 100])?;
+    ///     ws.copy_bytes_with_null([b'x'; 100])
+    /// });
+    /// assert!(value.is_err());
+    /// assert_eq!(ws.free(), 160);
+    ///
+    /// let value = ws.scope(|ws| {
+    ///     let value = ws.copy_cstr(c"small")?;
+    ///     ws.commit();
+    ///     Ok::<_, varnish::vcl::VclError>(value)
+    /// });
+    /// assert!(value.is_ok());
+    /// assert!(ws.free() < 160);
+    /// ```
+    pub fn scope<R>(&mut self, f: impl for<'s> FnOnce(&mut WorkspaceScope<'s>) -> R) -> R {
+        let mut scope = WorkspaceScope {
+            snapshot: self.snapshot(),
+            ws: Workspace::from_ptr(self.raw),
+            committed: false,
+        };
+        f(&mut scope)
+    }
+
+    fn snapshot(&self) -> usize {
+        let ws = unsafe { validate_ws(self.raw) };
+        #[cfg(not(any(test, miri, feature = "bench")))]
+        unsafe {
+            ffi::WS_Snapshot(ws)
+        }
+        #[cfg(any(test, miri, feature = "bench"))]
+        {
+            shadow::snapshot(ws)
+        }
+    }
+
+    fn reset(&mut self, snapshot: usize) {
+        let ws = unsafe { validate_ws(self.raw) };
+        #[cfg(not(any(test, miri, feature = "bench")))]
+        unsafe {
+            ffi::WS_Reset(ws, snapshot);
+        }
+        #[cfg(any(test, miri, feature = "bench"))]
+        {
+            shadow::reset(ws, snapshot);
+        }
+    }
+}
+
+/// A [`Workspace`] whose allocations are rolled back when it is dropped, unless committed, see
+/// [`Workspace::scope()`]
+#[derive(Debug)]
+pub struct WorkspaceScope<'s> {
+    ws: Workspace<'s>,
+    snapshot: usize,
+    committed: bool,
+}
+
+impl WorkspaceScope<'_> {
+    /// Keep the allocations made in this scope
+    pub fn commit(&mut self) {
+        self.committed = true;
+    }
+}
+
+impl<'s> Deref for WorkspaceScope<'s> {
+    type Target = Workspace<'s>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ws
+    }
+}
+
+impl DerefMut for WorkspaceScope<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ws
+    }
+}
+
+impl Drop for WorkspaceScope<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let snapshot = self.snapshot;
+            self.ws.reset(snapshot);
+        }
+    }
 }
 
 /// Internal helper to convert a `&[u8]` to a `&[MaybeUninit<u8>]`
@@ -300,10 +465,16 @@ impl<'a> ReservedBuf<'a> {
     /// let r2 = ws.reserve();
     /// assert_eq!(&r2.buf[..9], b"IAmNotZero");
     /// ```
+    ///
+    /// Under Miri, the slice returned after writing through `Write` is reported as aliasing
+    /// `buf`, as it starts before it. Writing with indexes, as above, keeps the code checkable.
     pub fn release(mut self, sz: usize) -> &'a mut [u8] {
+        // `buf` is still borrowed, so the result is derived from it rather than from `b`, the
+        // bytes already written through `Write` being in front of it
         unsafe {
-            self.len = self.buf.as_ptr().add(sz).offset_from(self.b) as usize;
-            from_raw_parts_mut(self.b, self.len)
+            let written = self.buf.as_ptr().offset_from(self.b) as usize;
+            self.len = written + sz;
+            from_raw_parts_mut(self.buf.as_mut_ptr().sub(written), self.len)
         }
     }
 }
@@ -311,20 +482,107 @@ impl<'a> ReservedBuf<'a> {
 impl Drop for ReservedBuf<'_> {
     fn drop(&mut self) {
         unsafe {
+            #[cfg(not(any(test, miri, feature = "bench")))]
             ffi::WS_Release(validate_ws(self.wsp), self.len as u32);
+            #[cfg(any(test, miri, feature = "bench"))]
+            shadow::release(validate_ws(self.wsp), self.len);
+        }
+    }
+}
+
+/// A string being written into the workspace, see [`Workspace::str_buffer()`]
+///
+/// Writes past the end of the workspace are counted but dropped, and the first error is returned
+/// by [`WsStrBuffer::finish()`]. Dropping the buffer without finishing it gives the space back.
+#[derive(Debug)]
+pub struct WsStrBuffer<'a> {
+    reserved: ReservedBuf<'a>,
+    len: usize,
+    /// The length of the string, including what did not fit
+    needed: usize,
+    has_null: bool,
+}
+
+impl WsStrBuffer<'_> {
+    /// Append `value`
+    pub fn push_str(&mut self, value: &str) -> Result<(), VclError> {
+        self.push_bytes(value.as_bytes())
+    }
+
+    /// Append raw bytes, e.g. a string that is not valid UTF-8. They must not contain `NULL`
+    /// bytes.
+    pub fn push_bytes(&mut self, value: &[u8]) -> Result<(), VclError> {
+        self.check()?;
+        self.needed += value.len();
+        let end = self.len + value.len();
+        let Some(dst) = self.reserved.buf.get_mut(self.len..end) else {
+            return self.check();
+        };
+        // look for NULL bytes in the copy, while it is still in the cache
+        dst.copy_from_slice(value);
+        self.has_null = memchr(0, dst).is_some();
+        if !self.has_null {
+            self.len = end;
+        }
+        self.check()
+    }
+
+    /// The length of the string written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Terminate the string, and give the rest of the reserved space back to the workspace
+    pub fn finish(self) -> Result<VCL_STRING, VclError> {
+        self.check()?;
+        let Some(nul) = self.reserved.buf.get_mut(self.len) else {
+            return Err(VclError::WsOutOfMemory(
+                NonZeroUsize::MIN.saturating_add(self.needed),
+            ));
+        };
+        *nul = b'\0';
+        let buf = self.reserved.release(self.len + 1);
+        Ok(VCL_STRING(buf.as_ptr().cast::<c_char>()))
+    }
+
+    /// The error of the first failed write, if any
+    fn check(&self) -> Result<(), VclError> {
+        if self.has_null {
+            Err(VclError::CStr(c"NULL byte found in the source string"))
+        } else if self.needed > self.len {
+            Err(VclError::WsOutOfMemory(
+                NonZeroUsize::MIN.saturating_add(self.needed),
+            ))
+        } else {
+            Ok(())
         }
     }
 }
 
+impl fmt::Write for WsStrBuffer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
 /// A struct holding both a native ws struct and the space it points to.
 ///
 /// As the name implies, this struct mainly exist to facilitate testing and should probably not be
 /// used elsewhere.
+///
+/// Its allocations are done by a Rust allocator working on offsets into the space, rather than by
+/// `varnishd`, so that the [`Workspace`] wrappers can run under Miri, e.g. with
+/// `cargo +nightly miri test`.
 #[derive(Debug)]
 pub struct TestWS {
     c_ws: ffi::ws,
+    /// Made of pointers, so that the space is aligned like the one of `varnishd`
     #[expect(dead_code)]
-    space: Vec<c_char>,
+    space: Vec<*mut c_void>,
 }
 
 impl TestWS {
@@ -332,8 +590,9 @@ impl TestWS {
     pub fn new(sz: usize) -> Self {
         let al = align_of::<*const c_void>();
         let aligned_sz = (sz / al) * al;
-        let mut v: Vec<c_char> = vec![0; sz];
-        let s = v.as_mut_ptr();
+        let mut v: Vec<*mut c_void> = vec![ptr::null_mut(); sz.div_ceil(al)];
+        // the only pointer to the space, all the others are derived from it
+        let s = v.as_mut_ptr().cast::<c_char>();
         Self {
             c_ws: ffi::ws {
                 magic: ffi::WS_MAGIC,
@@ -359,6 +618,75 @@ impl TestWS {
     }
 }
 
+/// The allocator of the workspaces when `varnishd` is not there, i.e. in tests, benchmarks, and
+/// under Miri
+///
+/// The state is kept in the `ws` struct as `varnishd` would, but every pointer handed out is
+/// computed from an offset to `ws.s`, so that they all share the provenance of the space.
+#[cfg(any(test, miri, feature = "bench"))]
+mod shadow {
+    use std::ffi::c_void;
+    use std::mem::align_of;
+    use std::ptr;
+
+    use crate::ffi;
+
+    const ALIGN: usize = align_of::<*const c_void>();
+
+    fn offset(ws: &ffi::ws, p: *const std::ffi::c_char) -> usize {
+        unsafe { p.offset_from(ws.s) as usize }
+    }
+
+    /// Aligned allocation of `size` bytes, or `NULL` if they don't fit
+    pub(super) fn alloc(ws: &mut ffi::ws, size: usize) -> *mut c_void {
+        assert!(ws.r.is_null(), "cannot allocate from a reserved workspace");
+        let (f, e) = (offset(ws, ws.f), offset(ws, ws.e));
+        let aligned_sz = size.div_ceil(ALIGN) * ALIGN;
+        if e - f < aligned_sz {
+            return ptr::null_mut();
+        }
+        ws.f = unsafe { ws.s.add(f + aligned_sz) };
+        unsafe { ws.s.add(f).cast::<c_void>() }
+    }
+
+    /// Reserve all the free space, returning its length
+    pub(super) fn reserve_all(ws: &mut ffi::ws) -> usize {
+        assert!(ws.r.is_null(), "the workspace is already reserved");
+        ws.r = ws.e;
+        offset(ws, ws.e) - offset(ws, ws.f)
+    }
+
+    /// Keep `len` bytes of the reservation
+    pub(super) fn release(ws: &mut ffi::ws, len: usize) {
+        assert!(!ws.r.is_null(), "the workspace is not reserved");
+        let f = offset(ws, ws.f) + len.div_ceil(ALIGN) * ALIGN;
+        assert!(f <= offset(ws, ws.e), "released more than was reserved");
+        ws.f = unsafe { ws.s.add(f) };
+        ws.r = ptr::null_mut();
+    }
+
+    pub(super) fn snapshot(ws: &ffi::ws) -> usize {
+        assert!(
+            ws.r.is_null(),
+            "cannot take a snapshot of a reserved workspace"
+        );
+        offset(ws, ws.f)
+    }
+
+    pub(super) fn reset(ws: &mut ffi::ws, snapshot: usize) {
+        assert!(snapshot <= offset(ws, ws.e), "invalid workspace snapshot");
+        ws.f = unsafe { ws.s.add(snapshot) };
+        ws.r = ptr::null_mut();
+    }
+
+    /// Whether `data` is in the allocated part of the workspace
+    pub(super) fn allocated(ws: &ffi::ws, data: &[u8]) -> bool {
+        // compare addresses, `data` may come from another allocation
+        let start = data.as_ptr() as usize;
+        start >= ws.s as usize && start + data.len() <= ws.f as usize
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::num::NonZero;
@@ -377,5 +705,92 @@ mod tests {
         unsafe {
             assert!(ws.alloc(NonZero::new(1).unwrap()).is_null());
         }
+        assert_eq!((ws.capacity(), ws.free()), (160, 0));
+    }
+
+    #[test]
+    fn ws_scope() {
+        let mut test_ws = TestWS::new(160);
+        let mut ws = test_ws.workspace();
+        ws.copy_bytes_with_null(b"before").unwrap();
+        let free = ws.free();
+
+        let res = ws.scope(|ws| {
+            ws.copy_bytes_with_null([b'x'; 64])?;
+            ws.copy_bytes_with_null([b'x'; 128])
+        });
+        assert!(res.is_err());
+        assert_eq!(ws.free(), free);
+
+        // the reservation is rolled back too
+        ws.scope(|ws| ws.reserve().release(10).len());
+        assert_eq!(ws.free(), free);
+
+        ws.scope(|ws| {
+            ws.copy_bytes_with_null(b"kept").unwrap();
+            ws.commit();
+        });
+        assert!(ws.free() < free);
+    }
+
+    #[test]
+    fn ws_reserve() {
+        let mut test_ws = TestWS::new(160);
+        let mut ws = test_ws.workspace();
+
+        let r = ws.reserve();
+        assert_eq!(r.buf.len(), 160);
+        r.buf[..12].copy_from_slice(b"0123456789ab");
+        let released = r.release(12);
+        assert_eq!(released, b"0123456789ab");
+        assert!(ws.contains(released));
+        assert!(!ws.contains(b"0123456789ab"));
+        assert_eq!(ws.free(), 144);
+
+        // dropped without a release, the space is given back
+        ws.reserve().buf[0] = b'x';
+        assert_eq!(ws.free(), 144);
+
+        let value = ws.allocate_zeroed(NonZero::new(3).unwrap()).unwrap();
+        assert!(ws.contains(value));
+        assert_eq!(ws.free(), 136);
+    }
+
+    #[test]
+    fn ws_str_buffer() {
+        use std::fmt::Write as _;
+
+        let mut test_ws = TestWS::new(160);
+        let mut ws = test_ws.workspace();
+        let free = ws.free();
+
+        let mut buf = ws.str_buffer();
+        buf.push_str("foo").unwrap();
+        buf.push_bytes(b"bar").unwrap();
+        write!(buf, "-{}", 42).unwrap();
+        assert_eq!(buf.len(), 9);
+        let value = buf.finish().unwrap();
+        assert_eq!(<&str>::try_from(value).unwrap(), "foobar-42");
+        // the test workspace aligns the allocations
+        assert_eq!(ws.free(), free - 16);
+
+        let free = ws.free();
+        let mut buf = ws.str_buffer();
+        assert!(buf.push_bytes(b"a\0b").is_err());
+        assert!(buf.push_str("c").is_err());
+        assert!(buf.finish().is_err());
+        assert_eq!(ws.free(), free);
+
+        let mut buf = ws.str_buffer();
+        buf.push_bytes(&[b'x'; 100]).unwrap();
+        assert!(buf.push_bytes(&[b'x'; 100]).is_err());
+        assert!(matches!(buf.finish(), Err(VclError::WsOutOfMemory(n)) if n.get() == 201));
+        assert_eq!(ws.free(), free);
+
+        // no room left for the NULL byte
+        let mut buf = ws.str_buffer();
+        buf.push_bytes(&vec![b'x'; free]).unwrap();
+        assert!(buf.finish().is_err());
+        assert_eq!(ws.free(), free);
     }
 }
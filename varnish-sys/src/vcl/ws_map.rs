@@ -0,0 +1,236 @@
+//! A hash map stored in the workspace
+//!
+//! [`WsMap`] is a small open-addressing table for per-task lookups, e.g. parsed query parameters
+//! or a set of already seen values. Its table is allocated from the [`Workspace`] once, with a
+//! fixed capacity, so it never touches the heap and is reclaimed with the rest of the workspace at
+//! the end of the task. Nothing stored in the workspace is ever dropped, so keys and values must
+//! be `Copy`, e.g. integers or `&str` borrowed from the request.
+
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter};
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::mem::{align_of, size_of, MaybeUninit};
+use std::num::NonZeroUsize;
+use std::slice::from_raw_parts_mut;
+
+use crate::vcl::{VclError, Workspace};
+
+/// A fixed-capacity hash map allocated from the workspace, see the [module documentation](self)
+pub struct WsMap<'a, K, V> {
+    slots: &'a mut [Option<(K, V)>],
+    len: usize,
+    capacity: usize,
+    hasher: RandomState,
+}
+
+impl<K: Debug, V: Debug> Debug for WsMap<'_, K, V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K: Hash + Eq + Copy, V: Copy> WsMap<'a, K, V> {
+    /// Allocate a map holding up to `capacity` entries.
+    ///
+    /// The table has room for at least a third more entries than `capacity`, to keep the lookups
+    /// short, so it uses about `capacity * 1.5 * size_of::<Option<(K, V)>>()` bytes.
+    pub fn new(ws: &mut Workspace<'a>, capacity: usize) -> Result<Self, VclError> {
+        if align_of::<Option<(K, V)>>() > align_of::<*const ()>() {
+            return Err(VclError::CStr(
+                c"WsMap entries cannot be aligned more than pointers",
+            ));
+        }
+        let count = (capacity + capacity / 3 + 1).next_power_of_two();
+        let size = count
+            .checked_mul(size_of::<Option<(K, V)>>())
+            .and_then(NonZeroUsize::new)
+            .ok_or(VclError::CStr(c"Invalid WsMap capacity"))?;
+        let buf = ws.allocate(size)?;
+        let slots = unsafe {
+            from_raw_parts_mut(
+                buf.as_mut_ptr().cast::<MaybeUninit<Option<(K, V)>>>(),
+                count,
+            )
+        };
+        for slot in &mut *slots {
+            slot.write(None);
+        }
+        Ok(Self {
+            // all the slots were initialized above
+            slots: unsafe { &mut *(std::ptr::from_mut(slots) as *mut [Option<(K, V)>]) },
+            len: 0,
+            capacity,
+            hasher: RandomState::new(),
+        })
+    }
+
+    /// The index where the search for `key` starts
+    fn home<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        // the number of slots is a power of two
+        (self.hasher.hash_one(key) as usize) & (self.slots.len() - 1)
+    }
+
+    /// The index of `key`, or of the empty slot where it would be inserted
+    fn find<Q>(&self, key: &Q) -> (usize, bool)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mask = self.slots.len() - 1;
+        let mut idx = self.home(key);
+        loop {
+            match &self.slots[idx] {
+                None => return (idx, false),
+                Some((k, _)) if k.borrow() == key => return (idx, true),
+                Some(_) => idx = (idx + 1) & mask,
+            }
+        }
+    }
+
+    /// Insert a value, returning the previous value of `key` if any. Fails if the map is full
+    /// and `key` is not in it yet.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, VclError> {
+        let (idx, found) = self.find(&key);
+        if found {
+            let (_, old) = self.slots[idx].as_mut().expect("slot was found");
+            return Ok(Some(std::mem::replace(old, value)));
+        }
+        if self.len == self.capacity {
+            return Err(VclError::CStr(c"WsMap is full"));
+        }
+        self.slots[idx] = Some((key, value));
+        self.len += 1;
+        Ok(None)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.find(key) {
+            (idx, true) => self.slots[idx].as_ref().map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.find(key) {
+            (idx, true) => self.slots[idx].as_mut().map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key).1
+    }
+
+    /// Remove `key`, returning its value if it was in the map
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (mut hole, true) = self.find(key) else {
+            return None;
+        };
+        let (_, value) = self.slots[hole].take().expect("slot was found");
+        self.len -= 1;
+        // shift back the following entries of the cluster, so that no lookup stops at the hole
+        let mask = self.slots.len() - 1;
+        let mut idx = hole;
+        loop {
+            idx = (idx + 1) & mask;
+            let Some((k, _)) = &self.slots[idx] else {
+                break;
+            };
+            let home = self.home(k);
+            // move the entry unless its home lies cyclically in (hole, idx]
+            if (idx.wrapping_sub(home) & mask) >= (idx.wrapping_sub(hole) & mask) {
+                self.slots[hole] = self.slots[idx].take();
+                hole = idx;
+            }
+        }
+        Some(value)
+    }
+
+    /// Remove all the entries, the table itself stays in the workspace
+    pub fn clear(&mut self) {
+        self.slots.fill(None);
+        self.len = 0;
+    }
+}
+
+impl<K, V> WsMap<'_, K, V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of entries the map can hold
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Iterate over the entries, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.slots
+            .iter()
+            .filter_map(|s| s.as_ref().map(|(k, v)| (k, v)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcl::TestWS;
+
+    #[test]
+    fn ws_map() {
+        let mut test_ws = TestWS::new(4096);
+        let mut ws = test_ws.workspace();
+        let free = ws.free();
+        let mut map = WsMap::<&str, i64>::new(&mut ws, 12).unwrap();
+        assert!(free - ws.free() >= 16 * size_of::<Option<(&str, i64)>>());
+
+        let keys: Vec<String> = (0..12).map(|i| format!("k{i}")).collect();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(map.insert(k, i as i64).unwrap(), None);
+        }
+        assert!(map.insert("extra", 0).is_err());
+        assert_eq!(map.insert("k3", 33).unwrap(), Some(3));
+        assert_eq!((map.len(), map.capacity()), (12, 12));
+
+        for k in keys.iter().step_by(2) {
+            assert!(map.remove(k.as_str()).is_some());
+        }
+        assert_eq!(map.remove("k0"), None);
+        assert_eq!(map.len(), 6);
+        for (i, k) in keys.iter().enumerate() {
+            let expected = match i {
+                3 => Some(33),
+                _ if i % 2 == 1 => Some(i as i64),
+                _ => None,
+            };
+            assert_eq!(map.get(k.as_str()).copied(), expected, "{k}");
+        }
+        *map.get_mut("k1").unwrap() += 10;
+        assert_eq!(map.get("k1"), Some(&11));
+        assert_eq!(map.iter().count(), 6);
+
+        map.clear();
+        assert!(map.is_empty() && !map.contains_key("k1"));
+        assert!(WsMap::<u64, u64>::new(&mut ws, 1000).is_err());
+    }
+}
@@ -8,11 +8,13 @@
 #include "cache/cache.h"
 #include "cache/cache_director.h"
 #include "cache/cache_filter.h"
+#include "vcl.h"
 #include "vmod_abi.h"
 #include "vsb.h"
 #include "vsa.h"
 #include "vapi/vsm.h"
 #include "vapi/vsc.h"
+#include "vapi/vsl.h"
 
 #ifndef VARNISH_RS_6_0
 struct http_conn {
@@ -36,3 +38,16 @@ struct http_conn {
 #endif
 
 struct vfp_entry *VFP_Push(struct vfp_ctx *, const struct vfp *);
+
+/* from vrt_obj.h, which declares all the VCL variables */
+VCL_STRING VRT_r_local_endpoint(VRT_CTX);
+VCL_STRING VRT_r_local_socket(VRT_CTX);
+VCL_DURATION VRT_r_bereq_connect_timeout(VRT_CTX);
+VCL_DURATION VRT_r_bereq_first_byte_timeout(VRT_CTX);
+VCL_DURATION VRT_r_bereq_between_bytes_timeout(VRT_CTX);
+VCL_DURATION VRT_r_sess_timeout_idle(VRT_CTX);
+#ifndef VARNISH_RS_6_0
+VCL_DURATION VRT_r_sess_timeout_linger(VRT_CTX);
+VCL_DURATION VRT_r_sess_send_timeout(VRT_CTX);
+VCL_DURATION VRT_r_sess_idle_send_timeout(VRT_CTX);
+#endif
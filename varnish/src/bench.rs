@@ -0,0 +1,492 @@
+//! Benchmark vmod functions outside of Varnish
+//!
+//! [`BenchCtx`] builds a synthetic [`Ctx`] with a workspace and the HTTP objects of your choice.
+//! [`bench_vmod!`](crate::bench_vmod) finds the C functions generated by `#[vmod]` in the
+//! `Vmod_<name>_Data` table of the vmod, and [`bench_vmod_fn!`](crate::bench_vmod_fn) calls one of
+//! them against the context, like Varnish would: the arguments go through the conversion from
+//! their VCL types, the errors through [`Ctx::fail()`], and the returned value through its
+//! conversion into its VCL type.
+//!
+//! This module requires the `bench` feature, which replaces the workspace allocator and the
+//! logging functions of `varnishd` with Rust implementations. The log records are copied into a
+//! per-thread buffer, see [`take_bench_log()`](varnish_sys::vcl::take_bench_log), and
+//! [`Ctx::fail()`] marks the task as failed, see [`BenchCtx::failed()`]. Only enable it as a
+//! dev-dependency, never in the `cdylib` loaded by Varnish:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! criterion = "0.5"
+//! varnish = { version = "0.4", features = ["bench"] }
+//! ```
+//!
+//! The vmod crate must also be built as an `rlib` (in addition to the `cdylib`) for the benchmarks
+//! to link it. With `criterion`, `benches/my_bench.rs` may look like this, see also
+//! `examples/vmod_example/benches`:
+//!
+//! ```ignore
+//! use criterion::{criterion_group, criterion_main, Criterion};
+//! use varnish::bench::{BenchCtx, BenchHttp};
+//! use varnish::ffi::{VCL_BOOL, VCL_INT, VCL_STRING};
+//!
+//! fn benchmarks(c: &mut Criterion) {
+//!     let vmod = varnish::bench_vmod!(my_vmod, Vmod_my_vmod_Data);
+//!     let req = BenchHttp::request("GET", "/", &[("host", "example.com")]);
+//!     let mut bench_ctx = BenchCtx::new(16 * 1024).with_req(req);
+//!
+//!     varnish::bench_vmod_fn!(c, "is_even", bench_ctx, vmod.is_even(VCL_INT(8)) => VCL_BOOL);
+//!     assert!(!bench_ctx.failed());
+//!     // methods are called with the pointer to the object as first argument
+//!     varnish::bench_vmod_fn!(c, "counter.get", bench_ctx, vmod.counter.get(obj) => VCL_INT);
+//! }
+//!
+//! criterion_group!(benches, benchmarks);
+//! criterion_main!(benches);
+//! ```
+//!
+//! The same contexts can be used in unit tests. [`TestFetchCtx`] adds the backend side state that
+//! [`Serve`](varnish_sys::vcl::Serve) and
+//! [`FetchProcessor`](varnish_sys::vcl::FetchProcessor) implementations expect.
+
+use std::ffi::{c_uchar, c_uint, CString};
+use std::ptr;
+
+#[cfg(not(varnishsys_6))]
+use varnish_sys::ffi::vmod_data;
+use varnish_sys::ffi::{self, http, txt, vrt_ctx, VslTag, HTTP_MAGIC, VRT_CTX_MAGIC};
+#[cfg(not(varnishsys_6))]
+use varnish_sys::ffi::{busyobj, vfp_ctx, BUSYOBJ_MAGIC, VFP_CTX_MAGIC};
+#[cfg(not(varnishsys_6))]
+use varnish_sys::vcl::FetchProcCtx;
+use varnish_sys::vcl::{clear_bench_log, Ctx, TestWS};
+
+#[cfg(not(varnishsys_6))]
+use crate::func_table::FuncTable;
+
+const HDR_FIRST: usize = ffi::HTTP_HDR_FIRST as usize;
+
+/// A synthetic HTTP object, used as `req`, `resp`, `bereq`, or `beresp` of a [`BenchCtx`]
+#[derive(Debug)]
+pub struct BenchHttp {
+    raw: http,
+    hd: Vec<txt>,
+    hdf: Vec<c_uchar>,
+    /// Initial state, restored by [`BenchCtx::reset()`]
+    initial_hd: Vec<txt>,
+    initial_nhd: u16,
+    /// Storage of the strings `hd` points to
+    _strings: Vec<Option<CString>>,
+}
+
+impl BenchHttp {
+    /// Number of header slots left free for the benchmarked functions, by default
+    pub const SPARE_SLOTS: u16 = 16;
+
+    /// Create a request with the given method, URL, and headers
+    pub fn request(method: &str, url: &str, headers: &[(&str, &str)]) -> Self {
+        let first_line = [Some(method), Some(url), Some("HTTP/1.1"), None, None];
+        Self::new(first_line, headers, 0, VslTag::ReqMethod)
+    }
+
+    /// Create a response with the given status and headers
+    pub fn response(status: u16, headers: &[(&str, &str)]) -> Self {
+        let status_str = status.to_string();
+        let first_line = [None, None, Some("HTTP/1.1"), Some(&status_str), Some("OK")];
+        Self::new(first_line, headers, status, VslTag::RespMethod)
+    }
+
+    /// Change the number of free header slots, which is [`Self::SPARE_SLOTS`] by default
+    #[must_use]
+    pub fn with_spare_slots(mut self, spare: u16) -> Self {
+        let len = usize::from(self.initial_nhd + spare);
+        self.hd.resize(len, txt::default());
+        self.hdf.resize(len, 0);
+        self.raw.hd = self.hd.as_mut_ptr();
+        self.raw.hdf = self.hdf.as_mut_ptr();
+        self.raw.shd = self.initial_nhd + spare;
+        self
+    }
+
+    fn new(
+        first_line: [Option<&str>; HDR_FIRST],
+        headers: &[(&str, &str)],
+        status: u16,
+        logtag: VslTag,
+    ) -> Self {
+        let first_line =
+            first_line.map(|v| v.map(|v| CString::new(v).expect("NULL byte in the first line")));
+        let headers = headers.iter().map(|(name, value)| {
+            Some(CString::new(format!("{name}: {value}")).expect("NULL byte in a header"))
+        });
+        let strings: Vec<Option<CString>> = first_line.into_iter().chain(headers).collect();
+        let hd: Vec<txt> = strings
+            .iter()
+            .map(|s| s.as_deref().map_or_else(txt::default, txt::from_cstr))
+            .collect();
+        let nhd = u16::try_from(hd.len()).expect("too many headers");
+        Self {
+            raw: http {
+                magic: HTTP_MAGIC,
+                nhd,
+                logtag,
+                status,
+                protover: 11,
+                ..Default::default()
+            },
+            initial_hd: hd.clone(),
+            hdf: vec![0; hd.len()],
+            hd,
+            initial_nhd: nhd,
+            _strings: strings,
+        }
+        .with_spare_slots(Self::SPARE_SLOTS)
+    }
+
+    fn reset(&mut self) {
+        let nhd = usize::from(self.initial_nhd);
+        self.hd[..nhd].copy_from_slice(&self.initial_hd);
+        self.hdf[..nhd].fill(0);
+        self.raw.nhd = self.initial_nhd;
+    }
+}
+
+/// A synthetic VCL context to run vmod functions outside of Varnish
+#[derive(Debug)]
+pub struct BenchCtx {
+    vrt_ctx: vrt_ctx,
+    handling: c_uint,
+    test_ws: TestWS,
+    req: Option<Box<BenchHttp>>,
+    resp: Option<Box<BenchHttp>>,
+    bereq: Option<Box<BenchHttp>>,
+    beresp: Option<Box<BenchHttp>>,
+}
+
+impl BenchCtx {
+    /// Create a context with a workspace of `ws_size` bytes, and no HTTP objects
+    pub fn new(ws_size: usize) -> Self {
+        Self {
+            vrt_ctx: vrt_ctx {
+                magic: VRT_CTX_MAGIC,
+                ..vrt_ctx::default()
+            },
+            handling: 0,
+            test_ws: TestWS::new(ws_size),
+            req: None,
+            resp: None,
+            bereq: None,
+            beresp: None,
+        }
+    }
+
+    /// Make `req` available, as on the client side
+    #[must_use]
+    pub fn with_req(mut self, req: BenchHttp) -> Self {
+        self.req = Some(Box::new(req));
+        self
+    }
+
+    /// Make `resp` available, as in `vcl_deliver`
+    #[must_use]
+    pub fn with_resp(mut self, resp: BenchHttp) -> Self {
+        self.resp = Some(Box::new(resp));
+        self
+    }
+
+    /// Make `bereq` available, as on the backend side
+    #[must_use]
+    pub fn with_bereq(mut self, bereq: BenchHttp) -> Self {
+        self.bereq = Some(Box::new(bereq));
+        self
+    }
+
+    /// Make `beresp` available, as in `vcl_backend_response`
+    #[must_use]
+    pub fn with_beresp(mut self, beresp: BenchHttp) -> Self {
+        self.beresp = Some(Box::new(beresp));
+        self
+    }
+
+    /// Empty the workspace and the log, clear the failure, and restore the HTTP objects to their
+    /// initial state. This should be called before each iteration, like Varnish does for each
+    /// task.
+    pub fn reset(&mut self) {
+        self.handling = 0;
+        clear_bench_log();
+        let ws = self.test_ws.as_ptr();
+        unsafe {
+            (*ws).f = (*ws).s;
+            (*ws).r = ptr::null_mut();
+        }
+        for http in [
+            &mut self.req,
+            &mut self.resp,
+            &mut self.bereq,
+            &mut self.beresp,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            http.reset();
+        }
+    }
+
+    /// Get the [`Ctx`] to pass to the benchmarked function
+    pub fn ctx(&mut self) -> Ctx<'_> {
+        self.link();
+        Ctx::from_ref(&mut self.vrt_ctx)
+    }
+
+    /// Get the raw context to pass to the C functions of a vmod, valid until `self` moves
+    pub fn as_ptr(&mut self) -> *mut vrt_ctx {
+        self.link();
+        ptr::from_mut(&mut self.vrt_ctx)
+    }
+
+    /// Whether [`Ctx::fail()`] was called since the last [`reset()`](Self::reset)
+    pub fn failed(&self) -> bool {
+        self.handling == ffi::VCL_RET_FAIL
+    }
+
+    #[cfg_attr(varnishsys_6, expect(clippy::useless_conversion))] // Varnish v6 uses raw pointers
+    fn link(&mut self) {
+        // Pointers are only set now because `self` may have moved since the last call
+        let ws = self.test_ws.as_ptr();
+        let http_ptr = |http: &mut Option<Box<BenchHttp>>| {
+            http.as_mut().map_or(ptr::null_mut(), |http| {
+                http.raw.ws = ws;
+                ptr::from_mut(&mut http.raw)
+            })
+        };
+        self.vrt_ctx.ws = ws;
+        self.vrt_ctx.handling = ptr::from_mut(&mut self.handling);
+        self.vrt_ctx.http_req = http_ptr(&mut self.req).into();
+        self.vrt_ctx.http_req_top = self.vrt_ctx.http_req;
+        self.vrt_ctx.http_resp = http_ptr(&mut self.resp).into();
+        self.vrt_ctx.http_bereq = http_ptr(&mut self.bereq).into();
+        self.vrt_ctx.http_beresp = http_ptr(&mut self.beresp).into();
+    }
+}
+
+/// A synthetic backend side context, with a `busyobj` and a VFP context, to unit test
+/// [`Serve::get_headers()`](varnish_sys::vcl::Serve::get_headers) and
+/// [`FetchProcessor::new()`](varnish_sys::vcl::FetchProcessor::new) without `varnishtest`
+///
+/// `bereq` is given, and `beresp` defaults to an empty `200` response.
+///
+/// ```
+/// use varnish::bench::{BenchHttp, TestFetchCtx};
+///
+/// let bereq = BenchHttp::request("GET", "/a", &[("host", "example.com")]);
+/// let mut test_ctx = TestFetchCtx::new(16 * 1024, bereq);
+///
+/// // e.g. a call to `backend.get_headers(&mut ctx)`
+/// let mut ctx = test_ctx.ctx();
+/// let host = ctx.bereq().unwrap().header("host").unwrap();
+/// assert_eq!(host, "example.com");
+/// let beresp = ctx.beresp_mut().unwrap();
+/// beresp.set_header("x-url", "/a").unwrap();
+///
+/// // e.g. a call to `MyProcessor::new(&mut ctx, &mut vfp_ctx)`
+/// let (ctx, vfp_ctx) = test_ctx.fetch_ctx();
+/// assert!(!ctx.raw.bo.is_null());
+/// assert_eq!(vfp_ctx.raw.resp, ctx.beresp().unwrap().raw as *const _ as *mut _);
+/// assert_eq!(ctx.beresp().unwrap().header("x-url"), Some("/a"));
+/// ```
+#[cfg(not(varnishsys_6))]
+#[derive(Debug)]
+pub struct TestFetchCtx {
+    bench_ctx: BenchCtx,
+    bo: busyobj,
+    vfp_ctx: vfp_ctx,
+}
+
+#[cfg(not(varnishsys_6))]
+impl TestFetchCtx {
+    /// Create a context with a workspace of `ws_size` bytes, fetching `bereq`
+    pub fn new(ws_size: usize, bereq: BenchHttp) -> Self {
+        Self {
+            bench_ctx: BenchCtx::new(ws_size)
+                .with_bereq(bereq)
+                .with_beresp(BenchHttp::response(200, &[])),
+            bo: busyobj {
+                magic: BUSYOBJ_MAGIC,
+                ..Default::default()
+            },
+            vfp_ctx: vfp_ctx {
+                magic: VFP_CTX_MAGIC,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Replace the default `beresp`, e.g. to test a fetch processor against given headers
+    #[must_use]
+    pub fn with_beresp(mut self, beresp: BenchHttp) -> Self {
+        self.bench_ctx = self.bench_ctx.with_beresp(beresp);
+        self
+    }
+
+    /// Empty the workspace, and restore `bereq` and `beresp` to their initial state
+    pub fn reset(&mut self) {
+        self.bench_ctx.reset();
+    }
+
+    /// Get the [`Ctx`] of the fetch, as passed to `Serve::get_headers()`
+    pub fn ctx(&mut self) -> Ctx<'_> {
+        self.fetch_ctx().0
+    }
+
+    /// Get the [`Ctx`] and the [`FetchProcCtx`] of the fetch, as passed to
+    /// [`FetchProcessor::new()`](varnish_sys::vcl::FetchProcessor::new)
+    pub fn fetch_ctx(&mut self) -> (Ctx<'_>, FetchProcCtx<'_>) {
+        // Pointers are only set now because `self` may have moved since the last call
+        self.bench_ctx.link();
+        let vrt_ctx = &mut self.bench_ctx.vrt_ctx;
+        self.bo.bereq = vrt_ctx.http_bereq.0;
+        self.bo.beresp = vrt_ctx.http_beresp.0;
+        self.bo.vfc = ptr::from_mut(&mut self.vfp_ctx);
+        self.vfp_ctx.req = vrt_ctx.http_bereq.0;
+        self.vfp_ctx.resp = vrt_ctx.http_beresp.0;
+        vrt_ctx.bo = ptr::from_mut(&mut self.bo);
+        (
+            Ctx::from_ref(vrt_ctx),
+            FetchProcCtx {
+                raw: &mut self.vfp_ctx,
+            },
+        )
+    }
+}
+
+/// The C functions of a vmod, found in its `Vmod_<name>_Data` table, see
+/// [`bench_vmod!`](crate::bench_vmod)
+#[cfg(not(varnishsys_6))]
+#[derive(Debug)]
+pub struct BenchVmod {
+    table: FuncTable,
+}
+
+#[cfg(not(varnishsys_6))]
+impl BenchVmod {
+    /// Use the function table of `data`, which must be the `Vmod_<name>_Data` of a vmod
+    ///
+    /// # Panics
+    ///
+    /// If `data` has no function table, or its JSON description can't be parsed.
+    pub unsafe fn new(data: &'static vmod_data) -> Self {
+        match FuncTable::new(data) {
+            Ok(table) => Self { table },
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Get the C function generated for `slot`, e.g. `is_even`, `counter.__init`, or
+    /// `counter.get`, taking `args`
+    ///
+    /// # Safety
+    ///
+    /// `args` must have the VCL types of the arguments of the function, and `R` its return type.
+    ///
+    /// # Panics
+    ///
+    /// If the vmod has no such function.
+    pub unsafe fn wrapper<A: WrapperArgs, R>(&self, slot: &str, _args: &A) -> A::Fn<R> {
+        match self.table.get(slot) {
+            Ok(func) => func,
+            Err(e) => panic!("{e}"),
+        }
+    }
+}
+
+/// The arguments of a C function of a vmod, as a tuple of VCL types, after the context
+pub trait WrapperArgs: Copy {
+    /// `unsafe extern "C" fn(*mut vrt_ctx, args...) -> R`
+    type Fn<R>: Copy;
+
+    /// Call `func` with `ctx` and these arguments
+    unsafe fn call<R>(self, func: Self::Fn<R>, ctx: *mut vrt_ctx) -> R;
+}
+
+macro_rules! impl_wrapper_args {
+    ($($ty:ident $arg:ident),*) => {
+        impl<$($ty: Copy),*> WrapperArgs for ($($ty,)*) {
+            type Fn<R> = unsafe extern "C" fn(*mut vrt_ctx $(, $ty)*) -> R;
+
+            unsafe fn call<R>(self, func: Self::Fn<R>, ctx: *mut vrt_ctx) -> R {
+                let ($($arg,)*) = self;
+                func(ctx $(, $arg)*)
+            }
+        }
+    };
+}
+
+impl_wrapper_args!();
+impl_wrapper_args!(A a);
+impl_wrapper_args!(A a, B b);
+impl_wrapper_args!(A a, B b, C c);
+impl_wrapper_args!(A a, B b, C c, D d);
+impl_wrapper_args!(A a, B b, C c, D d, E e);
+impl_wrapper_args!(A a, B b, C c, D d, E e, F f);
+
+/// Get the [`BenchVmod`](crate::bench::BenchVmod) of a vmod crate, from the name of the crate and
+/// the name of its `Vmod_<name>_Data` symbol, see the [module documentation](crate::bench).
+///
+/// ```ignore
+/// let vmod = varnish::bench_vmod!(vmod_example, Vmod_example_Data);
+/// ```
+#[cfg(not(varnishsys_6))]
+#[macro_export]
+macro_rules! bench_vmod {
+    ($vmod_crate:ident, $data:ident) => {{
+        // make sure the crate is linked
+        use $vmod_crate as _;
+        extern "C" {
+            static $data: $crate::ffi::vmod_data;
+        }
+        unsafe { $crate::bench::BenchVmod::new(&*::std::ptr::addr_of!($data)) }
+    }};
+}
+
+/// Benchmark a C function of a vmod with `criterion`, see the [module documentation](crate::bench).
+///
+/// The arguments are the `Criterion` instance (or a benchmark group), the name of the benchmark,
+/// the [`BenchCtx`](crate::bench::BenchCtx) to use, and the call to benchmark on a
+/// [`BenchVmod`](crate::bench::BenchVmod): `vmod.function(args...)` or
+/// `vmod.object.method(obj, args...)`. The arguments are given with their VCL types, e.g.
+/// `VCL_INT(8)`, and are converted by the function generated by `#[vmod]`, like they would be
+/// when called from VCL. If the function returns a value, add `=> VCL_TYPE`.
+///
+/// The context is reset before each iteration, and is left as the last iteration left it, e.g.
+/// to check [`BenchCtx::failed()`](crate::bench::BenchCtx::failed).
+#[cfg(not(varnishsys_6))]
+#[macro_export]
+macro_rules! bench_vmod_fn {
+    ($c:expr, $name:expr, $bench_ctx:expr, $vmod:ident $(. $path:ident)+ ($($arg:expr),* $(,)?) $(=> $vcl_ty:ty)?) => {{
+        let __args = ($($arg,)*);
+        let __func = unsafe {
+            $vmod.wrapper::<_, $crate::__bench_ret_ty!($($vcl_ty)?)>(
+                &[$(stringify!($path)),+].join("."),
+                &__args,
+            )
+        };
+        $c.bench_function($name, |b| {
+            b.iter(|| {
+                $bench_ctx.reset();
+                let __ctx = $bench_ctx.as_ptr();
+                ::std::hint::black_box(unsafe {
+                    $crate::bench::WrapperArgs::call(::std::hint::black_box(__args), __func, __ctx)
+                });
+            });
+        });
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __bench_ret_ty {
+    () => {
+        ()
+    };
+    ($vcl_ty:ty) => {
+        $vcl_ty
+    };
+}
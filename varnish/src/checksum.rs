@@ -0,0 +1,387 @@
+//! Verify the fetched bodies against the checksums sent by the backend
+//!
+//! Object stores and some origins send a checksum of the body along with it, in a
+//! `Content-MD5`, `Digest`, `Repr-Digest` or `x-amz-checksum-*` header. A
+//! [`ChecksumFetchProcessor`] hashes the body while it is fetched, and fails the fetch if the
+//! result differs, so that a corrupted body never makes it into the cache. The reason is logged
+//! as a `FetchError` record, e.g.
+//! `checksum: sha-256 mismatch (x-amz-checksum-sha256), expected n4bQ...=, got LCa0...=`.
+//!
+//! ``` rust,no_run
+//! use std::ffi::CStr;
+//! use varnish::checksum::{Algorithm, ChecksumFetchProcessor, ChecksumSpec};
+//! use varnish::vcl::{Event, FetchFilters};
+//!
+//! struct Verify;
+//!
+//! impl ChecksumSpec for Verify {
+//!     fn name() -> &'static CStr {
+//!         c"checksum"
+//!     }
+//!     // MD5 is too weak for this origin
+//!     fn algorithms() -> &'static [Algorithm] {
+//!         &[Algorithm::Sha256, Algorithm::Crc32]
+//!     }
+//! }
+//!
+//! // e.g. the body of the `#[event]` function of a vmod, then use
+//! // `set beresp.filters = "checksum " + beresp.filters;` in `vcl_backend_response`, so that
+//! // the body is checked before any other processor modifies it
+//! fn event(event: Event, vfp: &mut FetchFilters) {
+//!     if let Event::Load = event {
+//!         vfp.register::<ChecksumFetchProcessor<Verify>>();
+//!     }
+//! }
+//! ```
+
+use std::ffi::CStr;
+use std::fmt::{self, Display};
+use std::marker::PhantomData;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use md5::Md5;
+use sha2::digest::Digest as _;
+use sha2::{Sha256, Sha512};
+use varnish_sys::vcl::{
+    Ctx, FetchProcCtx, InitResult, LogTag, TeeFetchProcessor, TeeSink, VclError,
+};
+
+/// A checksum algorithm supported by [`ChecksumFetchProcessor`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// `Content-MD5`, or `md5` in `Digest`
+    Md5,
+    /// `sha-256` in `Digest` and `Repr-Digest`, or `x-amz-checksum-sha256`
+    Sha256,
+    /// `sha-512` in `Digest` and `Repr-Digest`
+    Sha512,
+    /// `x-amz-checksum-crc32`
+    Crc32,
+}
+
+impl Algorithm {
+    /// The name of the algorithm, as used in the `Digest` header
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha-256",
+            Algorithm::Sha512 => "sha-512",
+            Algorithm::Crc32 => "crc32",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Self::Md5, Self::Sha256, Self::Sha512, Self::Crc32]
+            .into_iter()
+            .find(|a| a.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The size of the checksum, in bytes
+    fn len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha512 => 64,
+            Algorithm::Crc32 => 4,
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            Algorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Which checksums are verified, and how the processor is named
+pub trait ChecksumSpec: 'static {
+    /// The name of the processor, as used in `beresp.filters`
+    fn name() -> &'static CStr;
+    /// The accepted algorithms, by order of preference. When the backend sends several
+    /// checksums, only the first one in this list is verified.
+    fn algorithms() -> &'static [Algorithm] {
+        &[
+            Algorithm::Sha512,
+            Algorithm::Sha256,
+            Algorithm::Md5,
+            Algorithm::Crc32,
+        ]
+    }
+    /// Fail the fetches without any accepted checksum, `false` by default
+    fn required() -> bool {
+        false
+    }
+}
+
+/// A checksum sent by the backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Expected {
+    algorithm: Algorithm,
+    header: &'static str,
+    value: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    fn update(&mut self, buf: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(buf),
+            Hasher::Sha256(h) => h.update(buf),
+            Hasher::Sha512(h) => h.update(buf),
+            Hasher::Crc32(h) => h.update(buf),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Md5(h) => h.finalize().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+            Hasher::Crc32(h) => h.finalize().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// Decode a checksum, in base64 as required, or in hexadecimal as some servers do
+fn decode(algorithm: Algorithm, value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    if let Ok(bytes) = STANDARD.decode(value) {
+        if bytes.len() == algorithm.len() {
+            return Some(bytes);
+        }
+    }
+    if value.len() != algorithm.len() * 2 || !value.is_ascii() {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Collect the checksums listed in the headers, failing on malformed values
+fn parse<'a>(
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<Vec<Expected>, String> {
+    let mut found = Vec::new();
+    let mut add = |algorithm, header, value: &str| match decode(algorithm, value) {
+        Some(value) => {
+            found.push(Expected {
+                algorithm,
+                header,
+                value,
+            });
+            Ok(())
+        }
+        None => Err(format!(
+            "invalid {algorithm} checksum in {header}: {value:?}"
+        )),
+    };
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("Content-MD5") {
+            add(Algorithm::Md5, "Content-MD5", value)?;
+        } else if name.eq_ignore_ascii_case("x-amz-checksum-sha256") {
+            add(Algorithm::Sha256, "x-amz-checksum-sha256", value)?;
+        } else if name.eq_ignore_ascii_case("x-amz-checksum-crc32") {
+            add(Algorithm::Crc32, "x-amz-checksum-crc32", value)?;
+        } else if name.eq_ignore_ascii_case("Digest") || name.eq_ignore_ascii_case("Repr-Digest") {
+            let header = if name.eq_ignore_ascii_case("Digest") {
+                "Digest"
+            } else {
+                "Repr-Digest"
+            };
+            for element in value.split(',') {
+                let Some((algo, v)) = element.split_once('=') else {
+                    continue;
+                };
+                // unknown algorithms are not an error, the backend may offer several
+                if let Some(algorithm) = Algorithm::from_name(algo.trim()) {
+                    // `Repr-Digest` is a structured field, with the value between colons
+                    add(algorithm, header, v.trim().trim_matches(':'))?;
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// The checksum to verify, according to [`ChecksumSpec::algorithms()`]
+fn preferred<S: ChecksumSpec>(found: &[Expected]) -> Option<&Expected> {
+    S::algorithms()
+        .iter()
+        .find_map(|a| found.iter().find(|e| e.algorithm == *a))
+}
+
+/// The [`TeeSink`] behind [`ChecksumFetchProcessor`]
+#[derive(Debug)]
+pub struct ChecksumSink<S: ChecksumSpec> {
+    expected: Expected,
+    hasher: Option<Hasher>,
+    _spec: PhantomData<S>,
+}
+
+impl<S: ChecksumSpec> ChecksumSink<S> {
+    /// The algorithm used to verify the body
+    pub fn algorithm(&self) -> Algorithm {
+        self.expected.algorithm
+    }
+}
+
+impl<S: ChecksumSpec> TeeSink for ChecksumSink<S> {
+    fn name() -> &'static CStr {
+        S::name()
+    }
+
+    fn new(vrt_ctx: &mut Ctx, vfp_ctx: &mut FetchProcCtx) -> InitResult<Self> {
+        let Some(beresp) = vrt_ctx.beresp() else {
+            return InitResult::Pass;
+        };
+        let name = S::name().to_string_lossy();
+        let found = match parse(beresp.iter()) {
+            Ok(found) => found,
+            Err(e) => {
+                let msg = format!("{name}: {e}");
+                vfp_ctx.fail(msg.clone());
+                return InitResult::Err(VclError::String(msg));
+            }
+        };
+        let Some(expected) = preferred::<S>(&found) else {
+            if S::required() {
+                let msg = format!("{name}: no accepted checksum in the response");
+                vfp_ctx.fail(msg.clone());
+                return InitResult::Err(VclError::String(msg));
+            }
+            return InitResult::Pass;
+        };
+        vrt_ctx.log(
+            LogTag::Debug,
+            format!(
+                "{name}: verifying {} from {}",
+                expected.algorithm, expected.header
+            ),
+        );
+        InitResult::Ok(Self {
+            hasher: Some(expected.algorithm.hasher()),
+            expected: expected.clone(),
+            _spec: PhantomData,
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), VclError> {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(buf);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), VclError> {
+        let Some(hasher) = self.hasher.take() else {
+            return Ok(());
+        };
+        let actual = hasher.finalize();
+        if actual == self.expected.value {
+            return Ok(());
+        }
+        Err(VclError::String(format!(
+            "{}: {} mismatch ({}), expected {}, got {}",
+            S::name().to_string_lossy(),
+            self.expected.algorithm,
+            self.expected.header,
+            STANDARD.encode(&self.expected.value),
+            STANDARD.encode(actual),
+        )))
+    }
+}
+
+/// A [`FetchProcessor`](varnish_sys::vcl::FetchProcessor) verifying the body against the
+/// checksum sent by the backend, see the [module documentation](self)
+///
+/// The body is passed through unchanged. Responses without an accepted checksum are not checked,
+/// unless [`ChecksumSpec::required()`] says otherwise.
+pub type ChecksumFetchProcessor<S> = TeeFetchProcessor<ChecksumSink<S>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sink(headers: &[(&str, &str)]) -> Result<ChecksumSink<Any>, String> {
+        let found = parse(headers.iter().copied())?;
+        let expected = preferred::<Any>(&found).ok_or("none")?;
+        Ok(ChecksumSink {
+            hasher: Some(expected.algorithm.hasher()),
+            expected: expected.clone(),
+            _spec: PhantomData,
+        })
+    }
+
+    fn check(headers: &[(&str, &str)]) -> Result<Algorithm, String> {
+        let mut sink = sink(headers)?;
+        sink.write(b"hello ").unwrap();
+        sink.write(b"world").unwrap();
+        sink.finish().map_err(|e| e.to_string())?;
+        Ok(sink.algorithm())
+    }
+
+    struct Any;
+
+    impl ChecksumSpec for Any {
+        fn name() -> &'static CStr {
+            c"checksum"
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        let md5 = ("Content-MD5", "XrY7u+Ae7tCTyyK7j1rNww==");
+        assert_eq!(check(&[md5]), Ok(Algorithm::Md5));
+        let hex = ("content-md5", "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        assert_eq!(check(&[hex]), Ok(Algorithm::Md5));
+        let crc = ("x-amz-checksum-crc32", "DUoRhQ==");
+        assert_eq!(check(&[crc]), Ok(Algorithm::Crc32));
+        let sha = (
+            "x-amz-checksum-sha256",
+            "uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=",
+        );
+        assert_eq!(check(&[crc, sha, md5]), Ok(Algorithm::Sha256));
+        let digest = (
+            "Digest",
+            "unixsum=30637, SHA-256=uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=",
+        );
+        assert_eq!(check(&[digest]), Ok(Algorithm::Sha256));
+        let repr = (
+            "Repr-Digest",
+            "sha-256=:uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=:",
+        );
+        assert_eq!(check(&[repr]), Ok(Algorithm::Sha256));
+
+        assert_eq!(
+            check(&[("Content-MD5", "AAAAAAAAAAAAAAAAAAAAAA==")]),
+            Err("checksum: md5 mismatch (Content-MD5), expected AAAAAAAAAAAAAAAAAAAAAA==, got XrY7u+Ae7tCTyyK7j1rNww==".to_string())
+        );
+        assert_eq!(
+            check(&[("x-amz-checksum-crc32", "nope")]),
+            Err("invalid crc32 checksum in x-amz-checksum-crc32: \"nope\"".to_string())
+        );
+        assert_eq!(
+            check(&[("Digest", "adler32=AAAA")]),
+            Err("none".to_string())
+        );
+    }
+}
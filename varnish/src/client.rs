@@ -0,0 +1,337 @@
+//! Call HTTP services from vmod functions
+//!
+//! Outbound calls, e.g. to an authentication service or a webhook, need an async HTTP client and
+//! a runtime to drive it. [`HttpClient`] wraps a `reqwest` client, and runs its requests on a
+//! small `tokio` runtime managed by this crate and shared by all its users, which only exists
+//! while at least one of them is alive. The calls block the Varnish worker thread until the
+//! response is received, or until the timeout budget of the call runs out.
+//!
+//! A client is usually created when the VCL is loaded, and kept in the `#[shared_per_vcl]` state,
+//! so that each VCL has its own configuration, and the runtime stops once all of them are
+//! discarded.
+//!
+//! ``` rust
+//! use std::time::Duration;
+//! use varnish::client::HttpClient;
+//! use varnish::vcl::VclError;
+//!
+//! let client = HttpClient::builder()
+//!     .timeout(Duration::from_millis(200))
+//!     .user_agent("my-vmod/1.0")
+//!     .build()?;
+//!
+//! // e.g. in a function called from `vcl_recv`
+//! fn is_allowed(client: &HttpClient, token: &str) -> bool {
+//!     let url = format!("http://auth.internal/check?token={token}");
+//!     client.get(&url).is_ok_and(|resp| resp.status().is_success())
+//! }
+//! # Ok::<(), VclError>(())
+//! ```
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use http::{Method, Request, Response};
+use varnish_sys::vcl::{VclError, VclResult};
+#[cfg(not(varnishsys_6))]
+use varnish_sys::vcl::{VscField, VscSegment};
+
+use crate::runtime::RuntimeRef;
+
+/// Counters of an [`HttpClient`] since its creation, see also [`HttpClient::publish()`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    /// Requests sent
+    pub requests: u64,
+    /// Responses received, whatever their status
+    pub responses: u64,
+    /// Requests that failed before a response was received, e.g. connection errors
+    pub errors: u64,
+    /// Requests abandoned because their timeout budget ran out
+    pub timeouts: u64,
+}
+
+const REQUESTS: usize = 0;
+const RESPONSES: usize = 1;
+const ERRORS: usize = 2;
+const TIMEOUTS: usize = 3;
+
+/// The counters of an [`HttpClient`], in the order of the fields of [`ClientStats`]
+///
+/// A trait object, so that the drop code of [`VscSegment`], which needs `varnishd`, is only
+/// linked in when the counters are published.
+trait Counters: Debug + Send + Sync {
+    fn values(&self) -> &[AtomicU64];
+
+    fn inc(&self, idx: usize) {
+        self.values()[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self, idx: usize) -> u64 {
+        self.values()[idx].load(Ordering::Relaxed)
+    }
+}
+
+impl Counters for Vec<AtomicU64> {
+    fn values(&self) -> &[AtomicU64] {
+        self
+    }
+}
+
+#[cfg(not(varnishsys_6))]
+impl Counters for VscSegment {
+    fn values(&self) -> &[AtomicU64] {
+        VscSegment::values(self)
+    }
+}
+
+/// Counters that only live in the process, until they are published
+fn local_counters() -> Box<dyn Counters> {
+    Box::new((0..4).map(|_| AtomicU64::new(0)).collect::<Vec<_>>())
+}
+
+/// Move the counts of `from` to `to`
+fn carry_over(from: &dyn Counters, to: Box<dyn Counters>) -> Box<dyn Counters> {
+    for (new, old) in to.values().iter().zip(from.values()) {
+        new.store(old.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+    to
+}
+
+/// Configure an [`HttpClient`]
+#[derive(Debug)]
+pub struct HttpClientBuilder {
+    timeout: Duration,
+    connect_timeout: Option<Duration>,
+    user_agent: Option<String>,
+    max_redirects: usize,
+}
+
+impl HttpClientBuilder {
+    /// The default timeout budget of a call, one second by default
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The timeout to establish a connection, only bound by the budget of the call by default
+    #[must_use]
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// The `User-Agent` header of the requests
+    #[must_use]
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// How many redirects to follow, none by default
+    #[must_use]
+    pub fn max_redirects(mut self, max: usize) -> Self {
+        self.max_redirects = max;
+        self
+    }
+
+    pub fn build(self) -> VclResult<HttpClient> {
+        let policy = if self.max_redirects == 0 {
+            reqwest::redirect::Policy::none()
+        } else {
+            reqwest::redirect::Policy::limited(self.max_redirects)
+        };
+        let mut builder = reqwest::Client::builder().redirect(policy);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(user_agent) = self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| VclError::String(format!("Unable to create the HTTP client: {e}")))?;
+        Ok(HttpClient {
+            client,
+            timeout: self.timeout,
+            counters: local_counters(),
+            runtime: RuntimeRef::acquire()?,
+        })
+    }
+}
+
+/// A blocking HTTP client, see the [module documentation](self)
+#[derive(Debug)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    timeout: Duration,
+    counters: Box<dyn Counters>,
+    // dropped last, after the client and its connection pool
+    runtime: RuntimeRef,
+}
+
+impl HttpClient {
+    pub fn builder() -> HttpClientBuilder {
+        HttpClientBuilder {
+            timeout: Duration::from_secs(1),
+            connect_timeout: None,
+            user_agent: None,
+            max_redirects: 0,
+        }
+    }
+
+    /// Send a `GET` request
+    pub fn get(&self, url: &str) -> VclResult<Response<Vec<u8>>> {
+        self.send(Self::request(Method::GET, url, Vec::new())?)
+    }
+
+    /// Send a `POST` request with `body`
+    pub fn post(&self, url: &str, body: impl Into<Vec<u8>>) -> VclResult<Response<Vec<u8>>> {
+        self.send(Self::request(Method::POST, url, body.into())?)
+    }
+
+    /// Send `request` with the default timeout budget
+    pub fn send(&self, request: Request<Vec<u8>>) -> VclResult<Response<Vec<u8>>> {
+        self.send_with_budget(request, self.timeout)
+    }
+
+    /// Send `request`, giving up if the whole exchange, including reading the body of the
+    /// response, takes longer than `budget`, e.g. what is left of the time allowed for the task
+    pub fn send_with_budget(
+        &self,
+        request: Request<Vec<u8>>,
+        budget: Duration,
+    ) -> VclResult<Response<Vec<u8>>> {
+        let desc = format!("{} {}", request.method(), request.uri());
+        let request = reqwest::Request::try_from(request)
+            .map_err(|e| VclError::String(format!("{desc}: {e}")))?;
+        self.counters.inc(REQUESTS);
+        let exchange = async {
+            let response = self.client.execute(request).await?;
+            let mut builder = Response::builder()
+                .status(response.status())
+                .version(response.version());
+            if let Some(headers) = builder.headers_mut() {
+                headers.extend(response.headers().clone());
+            }
+            let body = response.bytes().await?;
+            Ok::<_, reqwest::Error>(builder.body(body.to_vec()).expect("valid parts"))
+        };
+        let (counter, result) = match self
+            .runtime
+            .block_on(async { tokio::time::timeout(budget, exchange).await })
+        {
+            Ok(Ok(response)) => (RESPONSES, Ok(response)),
+            Ok(Err(e)) => (ERRORS, Err(VclError::String(format!("{desc}: {e}")))),
+            Err(_) => (
+                TIMEOUTS,
+                Err(VclError::String(format!(
+                    "{desc}: timed out after {budget:?}"
+                ))),
+            ),
+        };
+        self.counters.inc(counter);
+        result
+    }
+
+    /// The counters of the client
+    pub fn stats(&self) -> ClientStats {
+        ClientStats {
+            requests: self.counters.get(REQUESTS),
+            responses: self.counters.get(RESPONSES),
+            errors: self.counters.get(ERRORS),
+            timeouts: self.counters.get(TIMEOUTS),
+        }
+    }
+
+    /// Publish the counters to `varnishstat` as `vmod.<vmod>.<instance>.requests`,
+    /// `.responses`, `.errors` and `.timeouts`, e.g. right after building the client when the VCL
+    /// is loaded. The counts so far are carried over.
+    ///
+    /// The segment is removed by [`HttpClient::unpublish()`], or when the client is dropped.
+    #[cfg(not(varnishsys_6))]
+    pub fn publish(&mut self, vmod: &str, instance: &str) -> VclResult<()> {
+        let segment = VscSegment::new(
+            vmod,
+            instance,
+            "HTTP client",
+            vec![
+                VscField::counter("requests", "Requests sent"),
+                VscField::counter("responses", "Responses received, whatever their status"),
+                VscField::counter("errors", "Requests that failed without a response"),
+                VscField::counter("timeouts", "Requests abandoned when their budget ran out"),
+            ],
+        )?;
+        self.counters = carry_over(&*self.counters, Box::new(segment));
+        Ok(())
+    }
+
+    /// Remove the counters published by [`HttpClient::publish()`], e.g. when the VCL goes cold.
+    /// The client keeps counting in the process.
+    pub fn unpublish(&mut self) {
+        self.counters = carry_over(&*self.counters, local_counters());
+    }
+
+    fn request(method: Method, url: &str, body: Vec<u8>) -> VclResult<Request<Vec<u8>>> {
+        Request::builder()
+            .method(method)
+            .uri(url)
+            .body(body)
+            .map_err(|e| VclError::String(format!("Invalid URL {url}: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    /// Answer the first connection with `response`, or never answer if it is `None`
+    fn serve_once(response: Option<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            match response {
+                Some(response) => stream.write_all(response.as_bytes()).unwrap(),
+                None => thread::sleep(Duration::from_secs(2)),
+            }
+        });
+        format!("http://{addr}/")
+    }
+
+    #[test]
+    fn http_client() {
+        let client = HttpClient::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+
+        let url = serve_once(Some(
+            "HTTP/1.1 201 Created\r\nX-Foo: bar\r\nContent-Length: 2\r\n\r\nok",
+        ));
+        let resp = client.post(&url, "data").unwrap();
+        assert_eq!(resp.status(), 201);
+        assert_eq!(resp.headers()["x-foo"], "bar");
+        assert_eq!(resp.body(), b"ok");
+
+        let url = serve_once(None);
+        let err = client.get(&url).unwrap_err().to_string();
+        assert!(err.contains("timed out"), "{err}");
+        assert!(client.get("not a url").is_err());
+
+        let stats = client.stats();
+        assert_eq!((stats.requests, stats.responses, stats.timeouts), (2, 1, 1));
+
+        let mut client = client;
+        client.unpublish();
+        assert_eq!(client.stats(), stats);
+    }
+}
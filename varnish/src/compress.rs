@@ -0,0 +1,372 @@
+//! Compress the responses with brotli or zstd while they are delivered
+//!
+//! Varnish only knows gzip. [`BrotliDeliver`] and [`ZstdDeliver`] are delivery processors that
+//! compress the body on the fly, if the client lists the encoding in its `Accept-Encoding` and the
+//! response isn't already encoded. They update the headers to match: `Content-Encoding` is set,
+//! `Content-Length` removed since the size isn't known in advance, a strong `ETag` is weakened,
+//! and `Accept-Encoding` is added to `Vary`, even if the client doesn't support the encoding, as
+//! the response depends on it.
+//!
+//! ``` rust,no_run
+//! use varnish::compress::{BrotliDeliver, ZstdDeliver};
+//! use varnish::vcl::{DeliveryFilters, Event};
+//!
+//! // e.g. the body of the `#[event]` function of a vmod, then use
+//! // `set resp.filters = resp.filters + " brotli";` in `vcl_deliver`, for the content types
+//! // worth compressing
+//! fn event(event: Event, vdp: &mut DeliveryFilters) {
+//!     if let Event::Load = event {
+//!         vdp.register::<BrotliDeliver>();
+//!         vdp.register::<ZstdDeliver>();
+//!     }
+//! }
+//! ```
+//!
+//! Only one of them should be used per response. The compression levels favor speed, as the body
+//! is compressed for every delivery: 5 for brotli, and 3 for zstd. To compress once and cache the
+//! result, use `beresp.do_gzip` instead.
+//!
+//! These processors are available with the `brotli` and `zstd` features.
+
+use std::ffi::CStr;
+use std::io::{self, Write};
+
+use varnish_sys::ffi::VdpAction;
+use varnish_sys::vcl::{
+    Ctx, DeliveryProcCtx, DeliveryProcessor, HttpHeaders, InitResult, PushResult, VclError,
+};
+
+/// A streaming compressor, writing into an internal buffer
+trait Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    /// End the stream, nothing can be written afterwards
+    fn finish(&mut self) -> io::Result<()>;
+    /// The compressed data not yet pushed
+    fn output(&mut self) -> &mut Vec<u8>;
+}
+
+/// Compress `buf`, and push what is ready to the next processor
+fn push<E: Encoder>(
+    encoder: &mut E,
+    ctx: &mut DeliveryProcCtx,
+    act: VdpAction,
+    buf: &[u8],
+) -> PushResult {
+    let res = encoder.write(buf).and_then(|()| match act {
+        VdpAction::Flush => encoder.flush(),
+        VdpAction::End => encoder.finish(),
+        _ => Ok(()),
+    });
+    if res.is_err() {
+        return PushResult::Err;
+    }
+    let out = encoder.output();
+    if out.is_empty() && matches!(act, VdpAction::Null) {
+        return PushResult::Ok;
+    }
+    let res = ctx.push(act, out);
+    out.clear();
+    res
+}
+
+/// Update the response headers if it will be compressed with `coding`, or tell to skip the
+/// processor
+fn prepare(ctx: &mut Ctx, coding: &str) -> InitResult<()> {
+    let accepted = ctx
+        .req()
+        .and_then(|req| req.header("Accept-Encoding").map(|ae| accepts(ae, coding)))
+        .unwrap_or(false);
+    let Some(resp) = ctx.resp_mut() else {
+        return InitResult::Pass;
+    };
+    if !is_compressible(resp) {
+        return InitResult::Pass;
+    }
+    if let Err(e) = update_headers(resp, coding, accepted) {
+        return InitResult::Err(e);
+    }
+    if !accepted {
+        return InitResult::Pass;
+    }
+    // the length is unknown until the whole body is compressed
+    if let Some(req) = unsafe { ctx.raw.req.as_mut() } {
+        req.resp_len = -1;
+    }
+    InitResult::Ok(())
+}
+
+/// The response has a body that isn't encoded yet, and that we are allowed to transform
+fn is_compressible(resp: &HttpHeaders) -> bool {
+    let status = resp.status().and_then(|s| s.parse::<u16>().ok());
+    !matches!(status, None | Some(100..=199 | 204 | 206 | 304))
+        && resp
+            .header("Content-Encoding")
+            .is_none_or(|ce| ce.trim().eq_ignore_ascii_case("identity"))
+        && resp.header("Content-Range").is_none()
+        && !resp.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("Cache-Control") && has_token(value, "no-transform")
+        })
+}
+
+fn update_headers(resp: &mut HttpHeaders, coding: &str, accepted: bool) -> Result<(), VclError> {
+    let vary = resp
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+        .map(|(_, value)| value)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Some(vary) = vary_with_accept_encoding(&vary) {
+        resp.unset_header("Vary");
+        resp.set_header("Vary", &vary)?;
+    }
+    if !accepted {
+        return Ok(());
+    }
+    if let Some(etag) = resp.header("ETag").filter(|etag| etag.starts_with('"')) {
+        let weak = format!("W/{etag}");
+        resp.unset_header("ETag");
+        resp.set_header("ETag", &weak)?;
+    }
+    resp.unset_header("Content-Length");
+    resp.unset_header("Content-Encoding");
+    resp.set_header("Content-Encoding", coding)
+}
+
+/// The new value of `Vary`, if `Accept-Encoding` has to be added to `vary`
+fn vary_with_accept_encoding(vary: &str) -> Option<String> {
+    if has_token(vary, "*") || has_token(vary, "Accept-Encoding") {
+        None
+    } else if vary.trim().is_empty() {
+        Some("Accept-Encoding".to_string())
+    } else {
+        Some(format!("{}, Accept-Encoding", vary.trim()))
+    }
+}
+
+fn has_token(list: &str, token: &str) -> bool {
+    list.split(',')
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+/// `coding` is listed in `accept_encoding` with a non-zero weight, or `*` is if it isn't
+fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok());
+        let accepted = q.is_some_and(|q| q > 0.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return accepted;
+        }
+        if name == "*" {
+            wildcard = Some(accepted);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+#[cfg(feature = "brotli")]
+struct Brotli {
+    writer: Option<brotli::CompressorWriter<Vec<u8>>>,
+    tail: Vec<u8>,
+}
+
+#[cfg(feature = "brotli")]
+impl Brotli {
+    const QUALITY: u32 = 5;
+    const LG_WINDOW: u32 = 22;
+
+    fn new() -> Self {
+        Self {
+            writer: Some(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                Self::QUALITY,
+                Self::LG_WINDOW,
+            )),
+            tail: Vec::new(),
+        }
+    }
+
+    fn writer(&mut self) -> io::Result<&mut brotli::CompressorWriter<Vec<u8>>> {
+        self.writer
+            .as_mut()
+            .ok_or_else(|| io::Error::other("brotli: the stream is already finished"))
+    }
+}
+
+#[cfg(feature = "brotli")]
+impl Encoder for Brotli {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.writer()?.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer()?.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let writer = self.writer.take();
+        self.tail = writer
+            .ok_or_else(|| io::Error::other("brotli: the stream is already finished"))?
+            .into_inner();
+        Ok(())
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match &mut self.writer {
+            Some(writer) => writer.get_mut(),
+            None => &mut self.tail,
+        }
+    }
+}
+
+/// A delivery processor named `brotli`, compressing the body with brotli, see the
+/// [module documentation](self)
+#[cfg(feature = "brotli")]
+pub struct BrotliDeliver(Brotli);
+
+#[cfg(feature = "brotli")]
+impl DeliveryProcessor for BrotliDeliver {
+    fn name() -> &'static CStr {
+        c"brotli"
+    }
+
+    fn new(vrt_ctx: &mut Ctx, _vdp_ctx: &mut DeliveryProcCtx) -> InitResult<Self> {
+        prepare(vrt_ctx, "br").map(|()| Self(Brotli::new()))
+    }
+
+    fn push(&mut self, ctx: &mut DeliveryProcCtx, act: VdpAction, buf: &[u8]) -> PushResult {
+        push(&mut self.0, ctx, act, buf)
+    }
+}
+
+#[cfg(feature = "zstd")]
+struct Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>);
+
+#[cfg(feature = "zstd")]
+impl Zstd {
+    const LEVEL: i32 = 3;
+
+    fn new() -> io::Result<Self> {
+        zstd::stream::write::Encoder::new(Vec::new(), Self::LEVEL).map(Self)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Encoder for Zstd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.0.do_finish()
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        self.0.get_mut()
+    }
+}
+
+/// A delivery processor named `zstd`, compressing the body with zstd, see the
+/// [module documentation](self)
+#[cfg(feature = "zstd")]
+pub struct ZstdDeliver(Zstd);
+
+#[cfg(feature = "zstd")]
+impl DeliveryProcessor for ZstdDeliver {
+    fn name() -> &'static CStr {
+        c"zstd"
+    }
+
+    fn new(vrt_ctx: &mut Ctx, _vdp_ctx: &mut DeliveryProcCtx) -> InitResult<Self> {
+        match prepare(vrt_ctx, "zstd") {
+            InitResult::Ok(()) => match Zstd::new() {
+                Ok(encoder) => InitResult::Ok(Self(encoder)),
+                Err(e) => InitResult::Err(VclError::String(format!("zstd: {e}"))),
+            },
+            InitResult::Err(e) => InitResult::Err(e),
+            InitResult::Pass => InitResult::Pass,
+        }
+    }
+
+    fn push(&mut self, ctx: &mut DeliveryProcCtx, act: VdpAction, buf: &[u8]) -> PushResult {
+        push(&mut self.0, ctx, act, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_encoding() {
+        assert!(accepts("gzip, deflate, br, zstd", "br"));
+        assert!(accepts("gzip, BR;q=0.5", "br"));
+        assert!(!accepts("gzip, br;q=0", "br"));
+        assert!(!accepts("gzip, br;q=0.000", "br"));
+        assert!(!accepts("gzip", "zstd"));
+        assert!(accepts("*", "zstd"));
+        assert!(!accepts("*;q=0", "zstd"));
+        assert!(accepts("*;q=0, zstd", "zstd"));
+        assert!(!accepts("zstd;q=0, *", "zstd"));
+        assert!(!accepts("br;q=abc", "br"));
+        assert!(!accepts("", "br"));
+    }
+
+    #[test]
+    fn vary() {
+        let vary = |v: &str| vary_with_accept_encoding(v);
+        assert_eq!(vary("").as_deref(), Some("Accept-Encoding"));
+        assert_eq!(vary("Origin").as_deref(), Some("Origin, Accept-Encoding"));
+        assert_eq!(vary("Origin, accept-encoding"), None);
+        assert_eq!(vary("*"), None);
+    }
+
+    #[cfg(any(feature = "brotli", feature = "zstd"))]
+    fn compress(encoder: &mut impl Encoder, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for chunk in chunks {
+            encoder.write(chunk).unwrap();
+            encoder.flush().unwrap();
+            body.append(encoder.output());
+        }
+        encoder.finish().unwrap();
+        body.append(encoder.output());
+        body
+    }
+
+    #[cfg(any(feature = "brotli", feature = "zstd"))]
+    const CHUNKS: [&[u8]; 3] = [b"hello ", b"", b"hello hello hello world"];
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn brotli() {
+        let mut encoder = Brotli::new();
+        let body = compress(&mut encoder, &CHUNKS);
+        assert!(encoder.write(b"more").is_err());
+
+        let mut plain = Vec::new();
+        io::copy(&mut brotli::Decompressor::new(&body[..], 4096), &mut plain).unwrap();
+        assert_eq!(plain, CHUNKS.concat());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() {
+        let body = compress(&mut Zstd::new().unwrap(), &CHUNKS);
+        assert_eq!(zstd::decode_all(&body[..]).unwrap(), CHUNKS.concat());
+    }
+}
@@ -0,0 +1,125 @@
+//! Parse the configuration of a VMOD object from TOML or YAML
+//!
+//! Object constructors taking a complex configuration all end up inventing their own string
+//! format. With `#[arg(config)]`, the argument is a `STRING` in VCL, holding either inline TOML,
+//! inline YAML after a `yaml:` prefix, or a `file://` path to a TOML file, or to a YAML file if
+//! its name ends with `.yaml` or `.yml`. The generated code deserializes it into the Rust type of
+//! the argument. Any error fails the VCL load, with the line and column of the faulty value.
+//!
+//! ``` rust
+//! use serde::Deserialize;
+//! use varnish::vcl::VclError;
+//!
+//! #[derive(Deserialize)]
+//! pub struct Config {
+//!     hosts: Vec<String>,
+//!     #[serde(default)]
+//!     retries: u32,
+//! }
+//!
+//! // In the `#[vmod]` module, the constructor of the object gets the parsed value:
+//! //     pub fn new(#[arg(config)] config: Config) -> Self
+//! // and VCL passes inline TOML or YAML, or a file:
+//! //     new pool = example.pool({"hosts = ["a", "b"]"});
+//! //     new pool = example.pool({"yaml: { hosts: [a, b] }"});
+//! //     new pool = example.pool("file:///etc/varnish/pool.toml");
+//! //     new pool = example.pool("file:///etc/varnish/pool.yaml");
+//! let config: Config = varnish::config::parse(r#"hosts = ["a", "b"]"#)?;
+//! assert_eq!(config.hosts.len(), 2);
+//! let config: Config = varnish::config::parse("yaml: { hosts: [a, b], retries: 2 }")?;
+//! assert_eq!(config.retries, 2);
+//! # Ok::<(), VclError>(())
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use varnish_sys::ffi::VCL_STRING;
+use varnish_sys::vcl::VclError;
+
+/// Deserialize a configuration from inline TOML, inline YAML if `value` starts with `yaml:`, or
+/// from a file if `value` starts with `file://`. Files are read as YAML if their name ends with
+/// `.yaml` or `.yml`, and as TOML otherwise.
+pub fn parse<T: DeserializeOwned>(value: &str) -> Result<T, VclError> {
+    if let Some(path) = value.strip_prefix("file://") {
+        let text = fs::read_to_string(path)
+            .map_err(|e| VclError::String(format!("Unable to read {path}: {e}")))?;
+        let yaml = Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"));
+        from_str(&text, yaml).map_err(|e| VclError::String(format!("{path}: {e}")))
+    } else if let Some(yaml) = value.strip_prefix("yaml:") {
+        from_str(yaml, true).map_err(VclError::String)
+    } else {
+        from_str(value, false).map_err(VclError::String)
+    }
+}
+
+fn from_str<T: DeserializeOwned>(text: &str, yaml: bool) -> Result<T, String> {
+    if yaml {
+        serde_yaml_ng::from_str(text).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+}
+
+/// Convert a `VCL_STRING` argument declared with `#[arg(config)]`.
+/// It must be public because it is used by the macro-generated code.
+#[doc(hidden)]
+pub fn from_arg<T: DeserializeOwned>(value: VCL_STRING, arg: &str) -> Result<T, VclError> {
+    let value: &str = value.try_into()?;
+    parse(value).map_err(|e| VclError::String(format!("Invalid configuration in `{arg}`: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Config {
+        hosts: Vec<String>,
+        #[serde(default)]
+        retries: u32,
+    }
+
+    #[test]
+    fn parse_config() {
+        let cfg: Config = parse(r#"hosts = ["a", "b"]"#).unwrap();
+        assert_eq!(cfg.hosts, ["a", "b"]);
+        assert_eq!(cfg.retries, 0);
+
+        let err = parse::<Config>("hosts = []\nretries = \"x\"")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 2"), "{err}");
+
+        let path = env::temp_dir().join(format!("varnish-config-{}.toml", std::process::id()));
+        fs::write(&path, "hosts = [\"c\"]\nretries = 3\n").unwrap();
+        let cfg: Config = parse(&format!("file://{}", path.display())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cfg.retries, 3);
+
+        let cfg: Config = parse("yaml:\nhosts:\n  - d\n  - e\n").unwrap();
+        assert_eq!(cfg.hosts, ["d", "e"]);
+        let err = parse::<Config>("yaml:\nhosts: []\nretries: x\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 3"), "{err}");
+
+        let path = env::temp_dir().join(format!("varnish-config-{}.yaml", std::process::id()));
+        fs::write(&path, "hosts: [f]\nretries: 4\n").unwrap();
+        let cfg: Config = parse(&format!("file://{}", path.display())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((cfg.hosts, cfg.retries), (vec!["f".to_string()], 4));
+
+        let err = parse::<Config>("file:///nonexistent.toml").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Unable to read /nonexistent.toml"));
+    }
+}
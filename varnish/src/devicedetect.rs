@@ -0,0 +1,357 @@
+//! Classify the clients by device, like the `devicedetect.vcl` include does
+//!
+//! A [`DeviceDetect`] holds an ordered list of rules, each giving a class, e.g. `mobile-iphone`,
+//! to the requests whose headers match all its conditions. The first matching rule wins, and the
+//! requests matching no rule get the default class, `pc` unless changed. The rules are written
+//! with the same conditions as the `if` statements of `devicedetect.vcl`, one rule per line:
+//!
+//! ```text
+//! # class          conditions, joined by &&
+//! tablet-ipad      User-Agent ~ "(?i)ipad"
+//! mobile-android   User-Agent ~ "(?i)android" && User-Agent ~ "(?i)mobile"
+//! tablet-android   User-Agent ~ "(?i)android" && User-Agent !~ "(?i)mobile"
+//! # the client hints can be used as well
+//! mobile-generic   Sec-CH-UA-Mobile ~ "^\?1$"
+//! ```
+//!
+//! Header names are case-insensitive, and a missing header matches no `~` condition and every
+//! `!~` one, like in VCL. An `||` becomes several rules with the same class.
+//! [`DeviceDetect::standard()`] gives the rules of `devicedetect.vcl`, so migrating from the
+//! include only takes replacing `call devicedetect;` by a call to the vmod.
+//!
+//! The regexes are compiled when the VCL warms up, and freed when it goes cold, by forwarding
+//! the VCL events to [`DeviceDetect::on_event()`], e.g. with a
+//! [`Lifecycle`](crate::lifecycle::Lifecycle). They are also checked, and compiled, when the
+//! rules are parsed, so an invalid regex fails the `vcl.load`.
+//!
+//! ``` rust
+//! use varnish::devicedetect::DeviceDetect;
+//!
+//! let dd = DeviceDetect::standard();
+//! let ua = "Mozilla/5.0 (iPad; CPU OS 17_0 like Mac OS X) AppleWebKit/605.1.15";
+//! let class = dd.classify_with(|name| name.eq_ignore_ascii_case("user-agent").then_some(ua));
+//! assert_eq!(class, "tablet-ipad");
+//!
+//! let dd: DeviceDetect = r#"
+//!     tv  User-Agent ~ "(?i)smart-?tv"
+//! "#.parse()?;
+//! assert_eq!(dd.with_default("other").classify_with(|_| None), "other");
+//! # Ok::<(), varnish::vcl::VclError>(())
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use regex::Regex;
+use varnish_sys::vcl::{Ctx, Event, VclError};
+
+use crate::lifecycle::Listener;
+
+/// The rules of `devicedetect.vcl`, as returned by [`DeviceDetect::standard()`]
+pub const STANDARD_RULES: &str = r#"
+mobile-bot         User-Agent ~ "\(compatible; Googlebot-Mobile/2.1; \+http://www.google.com/bot.html\)"
+mobile-bot         User-Agent ~ "(Android|iPhone)" && User-Agent ~ "\(compatible.?; Googlebot/2.1.?; \+http://www.google.com/bot.html"
+mobile-bot         User-Agent ~ "(iPhone|Windows Phone)" && User-Agent ~ "\(compatible; bingbot/2.0; \+http://www.bing.com/bingbot.htm"
+bot                User-Agent ~ "(?i)(ads|google|bing|msn|yandex|baidu|ro|career|seznam|)bot"
+bot                User-Agent ~ "(?i)(baidu|jike|symantec)spider"
+bot                User-Agent ~ "(?i)(pingdom|facebookexternalhit|scanner|slurp|(web)crawler)"
+tablet-ipad        User-Agent ~ "(?i)ipad"
+mobile-iphone      User-Agent ~ "(?i)ip(hone|od)"
+mobile-android     User-Agent ~ "(?i)android.*(mobile|mini)"
+tablet-android     User-Agent ~ "(?i)android 3"
+mobile-smartphone  User-Agent ~ "Opera Mobi"
+tablet-android     User-Agent ~ "(?i)android"
+tablet-rim         User-Agent ~ "PlayBook; U; RIM Tablet"
+tablet-hp          User-Agent ~ "hp-tablet.*TouchPad"
+mobile-kindle      User-Agent ~ "Kindle/3"
+mobile-firefoxos   User-Agent ~ "Mobile.+Firefox"
+mobile-smartphone  User-Agent ~ "^HTC|Fennec|IEMobile|BlackBerry|BB10.*Mobile|GT-.*Build/GINGERBREAD|SymbianOS.*AppleWebKit"
+mobile-generic     User-Agent ~ "(?i)(symbian|^sonyericsson|^nokia|^samsung|^lg|bada|blazer|cellphone|iemobile|midp-2.0|u990|netfront|opera mini|palm|nintendo wii|playstation portable|portalmmm|proxinet|sonyericsson|windows ?ce|winwap|eudoraweb|htc|240x320|avantgo)"
+"#;
+
+/// A condition of a rule, e.g. `User-Agent ~ "(?i)ipad"`
+#[derive(Debug, Clone)]
+struct Condition {
+    header: String,
+    pattern: String,
+    negated: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    class: usize,
+    conditions: Vec<Condition>,
+}
+
+/// The compiled regexes of each rule, in the order of their conditions
+type Compiled = Vec<Vec<Regex>>;
+
+/// Device classification rules, see the [module documentation](self)
+pub struct DeviceDetect {
+    classes: Vec<String>,
+    rules: Vec<Rule>,
+    default: String,
+    compiled: RwLock<Option<Arc<Compiled>>>,
+}
+
+impl DeviceDetect {
+    /// The rules of `devicedetect.vcl`
+    pub fn standard() -> Self {
+        STANDARD_RULES
+            .parse()
+            .expect("the standard rules are valid")
+    }
+
+    /// Use `class` for the requests matching no rule, instead of `pc`
+    #[must_use]
+    pub fn with_default(mut self, class: impl Into<String>) -> Self {
+        self.default = class.into();
+        self
+    }
+
+    /// The class of the current request, from the headers of `req`, or of `bereq` on the backend
+    /// side
+    pub fn classify(&self, ctx: &Ctx) -> &str {
+        ctx.req()
+            .or_else(|| ctx.bereq())
+            .map_or(&self.default, |http| {
+                self.classify_with(|name| http.header(name))
+            })
+    }
+
+    /// The class for the headers given by `header`, which returns the value of a header from its
+    /// name, e.g. to classify a request outside of a VCL task
+    pub fn classify_with<'h>(&self, header: impl Fn(&str) -> Option<&'h str>) -> &str {
+        let Some(compiled) = self.compiled() else {
+            return &self.default;
+        };
+        let matches = |rule: &Rule, regexes: &[Regex]| {
+            rule.conditions.iter().zip(regexes).all(|(cond, re)| {
+                header(&cond.header).is_some_and(|value| re.is_match(value)) != cond.negated
+            })
+        };
+        self.rules
+            .iter()
+            .zip(compiled.iter())
+            .find(|(rule, regexes)| matches(rule, regexes))
+            .map_or(&self.default, |(rule, _)| &self.classes[rule.class])
+    }
+
+    /// All the classes a request can get, in the order of their first rule, then the default one
+    pub fn classes(&self) -> impl Iterator<Item = &str> {
+        self.classes
+            .iter()
+            .chain([&self.default])
+            .map(String::as_str)
+    }
+
+    /// Compile the regexes on `Load` and `Warm`, and free them on `Cold`. The requests are
+    /// given the default class while they are not compiled.
+    pub fn on_event(&self, event: Event) {
+        let mut compiled = self
+            .compiled
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        match event {
+            Event::Load | Event::Warm if compiled.is_none() => {
+                // the patterns were checked when parsing the rules
+                *compiled = compile(&self.rules).ok().map(Arc::new);
+            }
+            Event::Cold | Event::Discard => *compiled = None,
+            _ => {}
+        }
+    }
+
+    fn compiled(&self) -> Option<Arc<Compiled>> {
+        self.compiled
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+fn compile(rules: &[Rule]) -> Result<Compiled, VclError> {
+    rules
+        .iter()
+        .map(|rule| {
+            rule.conditions
+                .iter()
+                .map(|cond| {
+                    Regex::new(&cond.pattern).map_err(|e| {
+                        VclError::String(format!("Invalid pattern for {}: {e}", cond.header))
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Parse `Header ~ "regex"` or `Header !~ "regex"`
+fn parse_condition(s: &str) -> Result<(Condition, &str), String> {
+    let s = s.trim_start();
+    let end = s
+        .find(|c: char| c.is_whitespace() || c == '~' || c == '!')
+        .unwrap_or(s.len());
+    let (header, rest) = s.split_at(end);
+    if header.is_empty() {
+        return Err("missing header name".to_string());
+    }
+    let rest = rest.trim_start();
+    let (negated, rest) = if let Some(rest) = rest.strip_prefix("!~") {
+        (true, rest)
+    } else if let Some(rest) = rest.strip_prefix('~') {
+        (false, rest)
+    } else {
+        return Err(format!("expected ~ or !~ after {header}"));
+    };
+    // like in VCL, the strings have no escapes and can't contain a double quote
+    let rest = rest
+        .trim_start()
+        .strip_prefix('"')
+        .ok_or_else(|| format!("expected a quoted pattern for {header}"))?;
+    let (pattern, rest) = rest
+        .split_once('"')
+        .ok_or_else(|| format!("unterminated pattern for {header}"))?;
+    let cond = Condition {
+        header: header.to_string(),
+        pattern: pattern.to_string(),
+        negated,
+    };
+    Ok((cond, rest))
+}
+
+fn parse_rule(line: &str) -> Result<(&str, Vec<Condition>), String> {
+    let (class, mut rest) = line
+        .split_once(char::is_whitespace)
+        .ok_or("missing conditions")?;
+    let mut conditions = Vec::new();
+    loop {
+        let (cond, tail) = parse_condition(rest)?;
+        conditions.push(cond);
+        let tail = tail.trim_start();
+        if tail.is_empty() {
+            return Ok((class, conditions));
+        }
+        rest = tail
+            .strip_prefix("&&")
+            .ok_or_else(|| format!("expected && instead of {tail:?}"))?;
+    }
+}
+
+impl FromStr for DeviceDetect {
+    type Err = VclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut classes: Vec<String> = Vec::new();
+        let mut rules = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (class, conditions) = parse_rule(line).map_err(|e| {
+                VclError::String(format!("Invalid device rule on line {}: {e}", i + 1))
+            })?;
+            let class = classes.iter().position(|c| c == class).unwrap_or_else(|| {
+                classes.push(class.to_string());
+                classes.len() - 1
+            });
+            rules.push(Rule { class, conditions });
+        }
+        let compiled = compile(&rules)?;
+        Ok(Self {
+            classes,
+            rules,
+            default: "pc".to_string(),
+            compiled: RwLock::new(Some(Arc::new(compiled))),
+        })
+    }
+}
+
+impl Listener for DeviceDetect {
+    fn on_event(&self, event: Event) {
+        Self::on_event(self, event);
+    }
+}
+
+impl fmt::Debug for DeviceDetect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceDetect")
+            .field("classes", &self.classes)
+            .field("rules", &self.rules.len())
+            .field("default", &self.default)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(dd: &DeviceDetect, ua: &str) -> String {
+        dd.classify_with(|name| name.eq_ignore_ascii_case("User-Agent").then_some(ua))
+            .to_string()
+    }
+
+    #[test]
+    fn standard_rules() {
+        let dd = DeviceDetect::standard();
+        for (ua, class) in [
+            (
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+                "bot",
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+                "mobile-bot",
+            ),
+            ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X)", "mobile-iphone"),
+            ("Mozilla/5.0 (Linux; Android 14; Pixel 8) Mobile Safari/537.36", "mobile-android"),
+            ("Mozilla/5.0 (Linux; Android 14; SM-X710) Safari/537.36", "tablet-android"),
+            ("Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0", "pc"),
+        ] {
+            assert_eq!(classify(&dd, ua), class, "{ua}");
+        }
+        assert_eq!(dd.classify_with(|_| None), "pc");
+        assert_eq!(dd.classes().last(), Some("pc"));
+    }
+
+    #[test]
+    fn custom_rules() {
+        let dd: DeviceDetect = r#"
+            # client hints first
+            mobile  sec-ch-ua-mobile ~ "^\?1$"
+            tablet  User-Agent ~ "(?i)android" && User-Agent !~ "(?i)mobile"
+        "#
+        .parse::<DeviceDetect>()
+        .unwrap()
+        .with_default("desktop");
+        assert_eq!(classify(&dd, "Android 14"), "tablet");
+        assert_eq!(classify(&dd, "Android 14 Mobile"), "desktop");
+        let hints = |name: &str| {
+            name.eq_ignore_ascii_case("Sec-CH-UA-Mobile")
+                .then_some("?1")
+        };
+        assert_eq!(dd.classify_with(hints), "mobile");
+        assert_eq!(
+            dd.classes().collect::<Vec<_>>(),
+            ["mobile", "tablet", "desktop"]
+        );
+
+        dd.on_event(Event::Cold);
+        assert_eq!(classify(&dd, "Android 14"), "desktop");
+        dd.on_event(Event::Warm);
+        assert_eq!(classify(&dd, "Android 14"), "tablet");
+
+        for bad in [
+            "mobile",
+            r#"mobile User-Agent = "x""#,
+            r#"mobile User-Agent ~ "x"#,
+            r#"mobile User-Agent ~ "x" || User-Agent ~ "y""#,
+            r#"mobile User-Agent ~ "(""#,
+        ] {
+            assert!(bad.parse::<DeviceDetect>().is_err(), "{bad}");
+        }
+    }
+}
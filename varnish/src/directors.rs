@@ -0,0 +1,453 @@
+//! Building blocks for custom directors
+//!
+//! A [`HashRing`] maps request keys to members, e.g. backends, with consistent hashing: each
+//! member owns many points of a ring, proportionally to its weight, and a key goes to the member
+//! owning the first point after the hash of the key. Adding or removing a member only moves the
+//! keys it takes or gave up, all the others stay on the same member, which keeps the caches of
+//! the backends warm. This is what the shard director does, in a form that can be customized.
+//!
+//! The key is taken from the request with a [`KeySource`], and the hashes do not depend on the
+//! process, so that several Varnish servers with the same members agree on where each key goes.
+//!
+//! When requests do not need to stick to a member, a [`WeightedRoundRobin`] spreads them in
+//! proportion to the weights, and only uses the members of a lower priority when all the members
+//! of the best one are unhealthy, like the priorities and weights of DNS `SRV` records.
+//!
+//! ``` rust
+//! use std::sync::RwLock;
+//! use varnish::directors::{HashRing, KeySource};
+//! use varnish::vcl::Ctx;
+//!
+//! struct Shard {
+//!     // the backends, identified by name
+//!     ring: RwLock<HashRing<String>>,
+//!     key: KeySource,
+//! }
+//!
+//! impl Shard {
+//!     // e.g. a method called as `set req.backend_hint = shard.backend();`,
+//!     // returning the `VCL_BACKEND` of the chosen member
+//!     fn pick(&self, ctx: &Ctx, healthy: impl Fn(&String) -> bool) -> Option<String> {
+//!         let req = ctx.req()?;
+//!         let key = self.key.key(req)?;
+//!         let ring = self.ring.read().unwrap();
+//!         ring.get_where(key, healthy).cloned()
+//!     }
+//! }
+//!
+//! let mut ring = HashRing::new(100);
+//! ring.add("s1", 1, "s1".to_string());
+//! ring.add("s2", 2, "s2".to_string());
+//! let shard = Shard {
+//!     ring: RwLock::new(ring),
+//!     key: KeySource::Cookie("session".to_string()),
+//! };
+//! ```
+
+use std::fmt::{Debug, Formatter};
+
+use varnish_sys::vcl::HttpHeaders;
+
+/// Where to find the key of a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// The URL, including the query string
+    Url,
+    /// The value of the first header with this name
+    Header(String),
+    /// The value of the cookie with this name
+    Cookie(String),
+}
+
+impl KeySource {
+    /// Get the key from the request, `None` if it is not there
+    pub fn key<'a>(&self, req: &'a HttpHeaders) -> Option<&'a str> {
+        match self {
+            Self::Url => req.url(),
+            Self::Header(name) => req.header(name),
+            Self::Cookie(name) => req
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case("cookie"))
+                .find_map(|(_, v)| cookie_value(v, name)),
+        }
+    }
+}
+
+/// Find the value of the `name` cookie in a `Cookie` header
+fn cookie_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header
+        .split(';')
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| v.trim_matches('"'))
+}
+
+/// A 64-bit FNV-1a hash, followed by a finalizer spreading the bits of similar inputs
+///
+/// Unlike the std hashers, its results are stable across processes and Rust versions.
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+struct Member<T> {
+    id: String,
+    weight: u32,
+    value: T,
+}
+
+/// A consistent hashing ring with weighted virtual nodes, see the [module documentation](self)
+///
+/// The ring is rebuilt whenever its members change. Lookups only need `&self`, so a director
+/// shared by many requests can keep it in an `RwLock`, or build a new ring and swap it.
+pub struct HashRing<T> {
+    /// Virtual nodes per unit of weight
+    replicas: u32,
+    members: Vec<Member<T>>,
+    /// The points of the ring and the index of their member, sorted by hash
+    ring: Vec<(u64, usize)>,
+}
+
+impl<T> Debug for HashRing<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HashRing")
+            .field("replicas", &self.replicas)
+            .field(
+                "members",
+                &self
+                    .members
+                    .iter()
+                    .map(|m| (&m.id, m.weight))
+                    .collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> HashRing<T> {
+    /// Create an empty ring, where each member gets `replicas` virtual nodes per unit of weight.
+    /// A few hundred nodes per member keep the load within about ten percent of the weights.
+    pub fn new(replicas: u32) -> Self {
+        Self {
+            replicas: replicas.max(1),
+            members: Vec::new(),
+            ring: Vec::new(),
+        }
+    }
+
+    /// Add a member, or replace the one with the same `id`. The `id` decides where its virtual
+    /// nodes are, so it must not change with the order in which the members are added. A member
+    /// with a zero weight gets no keys.
+    pub fn add(&mut self, id: impl Into<String>, weight: u32, value: T) {
+        let id = id.into();
+        if let Some(member) = self.members.iter_mut().find(|m| m.id == id) {
+            member.weight = weight;
+            member.value = value;
+        } else {
+            self.members.push(Member { id, weight, value });
+        }
+        self.rebuild();
+    }
+
+    /// Remove a member, returning its value
+    pub fn remove(&mut self, id: &str) -> Option<T> {
+        let idx = self.members.iter().position(|m| m.id == id)?;
+        let member = self.members.remove(idx);
+        self.rebuild();
+        Some(member.value)
+    }
+
+    /// Change the weight of a member, returning `false` if there is no such member
+    pub fn set_weight(&mut self, id: &str, weight: u32) -> bool {
+        let Some(member) = self.members.iter_mut().find(|m| m.id == id) else {
+            return false;
+        };
+        if member.weight != weight {
+            member.weight = weight;
+            self.rebuild();
+        }
+        true
+    }
+
+    fn rebuild(&mut self) {
+        self.ring.clear();
+        for (idx, member) in self.members.iter().enumerate() {
+            let nodes = member.weight.saturating_mul(self.replicas);
+            self.ring
+                .extend((0..nodes).map(|n| (hash(format!("{}-{n}", member.id).as_bytes()), idx)));
+        }
+        // ties are broken by id, not by insertion order
+        let members = &self.members;
+        self.ring
+            .sort_unstable_by(|a, b| (a.0, &members[a.1].id).cmp(&(b.0, &members[b.1].id)));
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Iterate over the ids, weights, and values of the members, in the order they were added
+    pub fn members(&self) -> impl Iterator<Item = (&str, u32, &T)> {
+        self.members
+            .iter()
+            .map(|m| (m.id.as_str(), m.weight, &m.value))
+    }
+
+    /// Iterate over the distinct members, starting with the one owning `key`, then in the order
+    /// of the ring. This is the order in which to fall back when members are unhealthy.
+    pub fn candidates(&self, key: impl AsRef<[u8]>) -> impl Iterator<Item = &T> {
+        let start = self.ring.partition_point(|(h, _)| *h < hash(key.as_ref()));
+        let mut seen = vec![false; self.members.len()];
+        self.ring[start..]
+            .iter()
+            .chain(&self.ring[..start])
+            .filter(move |(_, idx)| !std::mem::replace(&mut seen[*idx], true))
+            .map(|(_, idx)| &self.members[*idx].value)
+    }
+
+    /// Get the member owning `key`, `None` if the ring is empty
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&T> {
+        self.candidates(key).next()
+    }
+
+    /// Get the first member for `key` matching `pred`, e.g. the first healthy backend
+    pub fn get_where(&self, key: impl AsRef<[u8]>, mut pred: impl FnMut(&T) -> bool) -> Option<&T> {
+        self.candidates(key).find(|m| pred(m))
+    }
+}
+
+struct Weighted<T> {
+    id: String,
+    priority: u16,
+    weight: u32,
+    /// The credit of the member, see [`WeightedRoundRobin::pick_where()`]
+    current: i64,
+    value: T,
+}
+
+/// A smooth weighted round-robin with priorities, see the [module documentation](self)
+///
+/// The members with the lowest priority value are used first, as long as one of them is
+/// eligible. Among them, each member is picked in proportion to its weight, and the picks are
+/// interleaved rather than sent in bursts to the same member. A member with a zero weight is only
+/// picked if no eligible member of the same priority has a weight.
+pub struct WeightedRoundRobin<T> {
+    members: Vec<Weighted<T>>,
+}
+
+impl<T> Debug for WeightedRoundRobin<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeightedRoundRobin")
+            .field(
+                "members",
+                &self
+                    .members
+                    .iter()
+                    .map(|m| (&m.id, m.priority, m.weight))
+                    .collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Default for WeightedRoundRobin<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> WeightedRoundRobin<T> {
+    pub fn new() -> Self {
+        Self {
+            members: Vec::new(),
+        }
+    }
+
+    /// Add a member, or replace the one with the same `id`
+    pub fn add(&mut self, id: impl Into<String>, priority: u16, weight: u32, value: T) {
+        let id = id.into();
+        if let Some(member) = self.members.iter_mut().find(|m| m.id == id) {
+            member.priority = priority;
+            member.weight = weight;
+            member.value = value;
+        } else {
+            self.members.push(Weighted {
+                id,
+                priority,
+                weight,
+                current: 0,
+                value,
+            });
+        }
+    }
+
+    /// Remove a member, returning its value
+    pub fn remove(&mut self, id: &str) -> Option<T> {
+        let idx = self.members.iter().position(|m| m.id == id)?;
+        Some(self.members.remove(idx).value)
+    }
+
+    /// Change the priority and the weight of a member, returning `false` if there is no such
+    /// member
+    pub fn set(&mut self, id: &str, priority: u16, weight: u32) -> bool {
+        let Some(member) = self.members.iter_mut().find(|m| m.id == id) else {
+            return false;
+        };
+        member.priority = priority;
+        member.weight = weight;
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Iterate over the ids, priorities, weights, and values of the members, in the order they
+    /// were added
+    pub fn members(&self) -> impl Iterator<Item = (&str, u16, u32, &T)> {
+        self.members
+            .iter()
+            .map(|m| (m.id.as_str(), m.priority, m.weight, &m.value))
+    }
+
+    /// Pick the next member, `None` if there is none
+    pub fn pick(&mut self) -> Option<&T> {
+        self.pick_where(|_| true)
+    }
+
+    /// Pick the next member matching `pred`, e.g. the next healthy backend
+    pub fn pick_where(&mut self, mut pred: impl FnMut(&T) -> bool) -> Option<&T> {
+        let eligible: Vec<usize> = (0..self.members.len())
+            .filter(|idx| pred(&self.members[*idx].value))
+            .collect();
+        let priority = eligible
+            .iter()
+            .map(|idx| self.members[*idx].priority)
+            .min()?;
+        let tier: Vec<usize> = eligible
+            .into_iter()
+            .filter(|idx| self.members[*idx].priority == priority)
+            .collect();
+        let weighted = tier.iter().any(|idx| self.members[*idx].weight > 0);
+        let weight = |m: &Weighted<T>| match (weighted, m.weight) {
+            (false, _) => 1,
+            (true, w) => i64::from(w),
+        };
+        // each member earns its weight, and the richest one pays the total for being picked
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for idx in tier {
+            let w = weight(&self.members[idx]);
+            self.members[idx].current += w;
+            total += w;
+            let current = self.members[idx].current;
+            if w > 0 && best.is_none_or(|b| current > self.members[b].current) {
+                best = Some(idx);
+            }
+        }
+        let best = best?;
+        self.members[best].current -= total;
+        Some(&self.members[best].value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(ring: &HashRing<&'static str>) -> Vec<&'static str> {
+        (0..10_000)
+            .map(|i| *ring.get(format!("/url/{i}")).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn hash_ring() {
+        let mut ring = HashRing::new(200);
+        assert_eq!(ring.get("/"), None);
+        ring.add("a", 1, "a");
+        ring.add("b", 1, "b");
+        ring.add("c", 2, "c");
+        assert_eq!(ring.len(), 3);
+
+        let before = owners(&ring);
+        let count = |o: &[&str], m| o.iter().filter(|v| **v == m).count();
+        // weighted, within 25%
+        assert!((1875..3125).contains(&count(&before, "a")));
+        assert!((3750..6250).contains(&count(&before, "c")));
+
+        // only the keys of the removed member move
+        assert_eq!(ring.remove("b"), Some("b"));
+        let after = owners(&ring);
+        for (b, a) in before.iter().zip(&after) {
+            assert!(b == a || *b == "b");
+        }
+
+        // the order of the additions does not matter
+        let mut other = HashRing::new(200);
+        other.add("c", 2, "c");
+        other.add("a", 1, "a");
+        assert_eq!(owners(&other), after);
+
+        // fallback to the next distinct members
+        let all: Vec<_> = ring.candidates("key").copied().collect();
+        assert_eq!(all.len(), 2);
+        assert_eq!(ring.get_where("key", |m| *m != all[0]), Some(&all[1]));
+        assert!(ring.set_weight("a", 0));
+        assert!(owners(&ring).iter().all(|m| *m == "c"));
+        assert!(!ring.set_weight("b", 1));
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let mut wrr = WeightedRoundRobin::new();
+        assert_eq!(wrr.pick(), None);
+        wrr.add("a", 10, 5, "a");
+        wrr.add("b", 10, 1, "b");
+        wrr.add("c", 10, 1, "c");
+        wrr.add("backup", 20, 1, "backup");
+        let picks: String = (0..7).map(|_| *wrr.pick().unwrap()).collect();
+        // smooth: the heavy member is not picked five times in a row
+        assert_eq!(picks, "aabacaa");
+
+        // the next priority is only used when the best one has no eligible member
+        assert_eq!(wrr.pick_where(|m| *m != "a"), Some(&"b"));
+        assert_eq!(wrr.pick_where(|m| m.len() > 1), Some(&"backup"));
+
+        // zero weights are only picked when the whole priority has none
+        assert!(wrr.set("a", 10, 0));
+        assert!((0..10).all(|_| *wrr.pick().unwrap() != "a"));
+        assert!(wrr.set("b", 10, 0) && wrr.set("c", 10, 0));
+        let picks: String = (0..3).map(|_| *wrr.pick().unwrap()).collect();
+        assert_eq!(picks.len(), 3);
+        assert!(picks.contains('a') && picks.contains('b') && picks.contains('c'));
+
+        assert_eq!(wrr.remove("backup"), Some("backup"));
+        assert!(!wrr.set("backup", 1, 1));
+        assert_eq!(wrr.len(), 3);
+    }
+
+    #[test]
+    fn cookie() {
+        let header = "a=1; session=\"xyz\";b=2";
+        assert_eq!(cookie_value(header, "session"), Some("xyz"));
+        assert_eq!(cookie_value(header, "b"), Some("2"));
+        assert_eq!(cookie_value(header, "sess"), None);
+    }
+}
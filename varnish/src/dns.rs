@@ -0,0 +1,533 @@
+//! Resolve host names, caching the addresses for as long as the DNS allows
+//!
+//! Dynamic backends need the current addresses of a host name without paying for a DNS query on
+//! every request. A [`Resolver`] caches the addresses of each host it was asked about, and keeps
+//! them fresh with a background refresh while the VCL is warm, so that [`Resolver::resolve()`]
+//! rarely has to wait for the network. When a refresh fails, the previous addresses keep being
+//! served until a refresh succeeds.
+//!
+//! The queries are made by a [`Lookup`]: [`SystemLookup`] uses the system resolver, which does not
+//! tell how long the addresses are valid, and `HickoryLookup`, with the `dns-hickory` feature,
+//! talks DNS directly and honors the TTL of the records. The latter can also query `SRV` records,
+//! to find the hosts, ports, priorities, and weights of the instances of a service, and `TXT`
+//! records, e.g. to publish routing hints next to the service.
+//!
+//! ``` rust
+//! use std::time::Duration;
+//! use varnish::dns::Resolver;
+//! use varnish::vcl::{Event, VclError};
+//!
+//! // e.g. the body of `#[event] pub fn event(event: Event, #[shared_per_vcl] ...)`
+//! fn event(event: Event, vcl: &mut Option<Box<Resolver>>) {
+//!     let resolver = vcl.get_or_insert_with(|| Box::new(Resolver::system()));
+//!     resolver.on_event(event);
+//! }
+//!
+//! // e.g. while picking the address of a dynamic backend
+//! fn addresses(resolver: &Resolver) -> Result<(), VclError> {
+//!     for addr in resolver.resolve("localhost", 8080)? {
+//!         assert_eq!(addr.port(), 8080);
+//!     }
+//!     Ok(())
+//! }
+//! # let mut vcl = None;
+//! # event(Event::Load, &mut vcl);
+//! # addresses(vcl.as_ref().unwrap()).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use varnish_sys::vcl::{Event, VclError, VclResult};
+
+use crate::timer::Timers;
+
+/// Query the addresses of a host name
+pub trait Lookup: Send + Sync + 'static {
+    /// The addresses of `host`, and how long they are valid
+    fn lookup(&self, host: &str) -> VclResult<(Vec<IpAddr>, Duration)>;
+
+    /// The targets of the `SRV` records of `name`, and how long they are valid. Not supported by
+    /// default.
+    fn lookup_srv(&self, name: &str) -> VclResult<(Vec<SrvTarget>, Duration)> {
+        Err(VclError::String(format!(
+            "Unable to resolve {name}: SRV records are not supported by this lookup"
+        )))
+    }
+
+    /// The text of the `TXT` records of `name`, and how long they are valid. Not supported by
+    /// default.
+    fn lookup_txt(&self, name: &str) -> VclResult<(Vec<String>, Duration)> {
+        Err(VclError::String(format!(
+            "Unable to resolve {name}: TXT records are not supported by this lookup"
+        )))
+    }
+}
+
+/// An instance of a service, from an `SRV` record
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SrvTarget {
+    /// The targets with the lowest value are used first
+    pub priority: u16,
+    /// How often the target is used, relatively to the others of the same priority
+    pub weight: u16,
+    pub port: u16,
+    /// The host name of the instance
+    pub target: String,
+}
+
+/// A [`Lookup`] using the system resolver, i.e. `getaddrinfo()`, with a fixed TTL
+#[derive(Debug, Clone)]
+pub struct SystemLookup {
+    ttl: Duration,
+}
+
+impl SystemLookup {
+    /// Consider the addresses valid for `ttl`, as the system resolver does not tell
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl }
+    }
+}
+
+impl Default for SystemLookup {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30))
+    }
+}
+
+impl Lookup for SystemLookup {
+    fn lookup(&self, host: &str) -> VclResult<(Vec<IpAddr>, Duration)> {
+        let addrs = (host, 0)
+            .to_socket_addrs()
+            .map_err(|e| VclError::String(format!("Unable to resolve {host}: {e}")))?;
+        Ok((addrs.map(|addr| addr.ip()).collect(), self.ttl))
+    }
+}
+
+/// A [`Lookup`] sending DNS queries with `hickory-resolver`, honoring the TTL of the records
+#[cfg(feature = "dns-hickory")]
+#[derive(Debug)]
+pub struct HickoryLookup {
+    resolver: hickory_resolver::TokioResolver,
+    runtime: crate::runtime::RuntimeRef,
+}
+
+#[cfg(feature = "dns-hickory")]
+impl HickoryLookup {
+    /// Use the name servers of the system configuration, i.e. `/etc/resolv.conf`
+    pub fn from_system_conf() -> VclResult<Self> {
+        let runtime = crate::runtime::RuntimeRef::acquire()?;
+        // the resolver is bound to the runtime it is created in
+        let resolver = runtime
+            .block_on(async {
+                hickory_resolver::TokioResolver::builder_tokio()
+                    .and_then(hickory_resolver::ResolverBuilder::build)
+            })
+            .map_err(|e| VclError::String(format!("Unable to create the DNS resolver: {e}")))?;
+        Ok(Self { resolver, runtime })
+    }
+}
+
+#[cfg(feature = "dns-hickory")]
+impl Lookup for HickoryLookup {
+    fn lookup(&self, host: &str) -> VclResult<(Vec<IpAddr>, Duration)> {
+        let lookup = self
+            .runtime
+            .block_on(self.resolver.lookup_ip(host))
+            .map_err(|e| VclError::String(format!("Unable to resolve {host}: {e}")))?;
+        let ttl = lookup
+            .valid_until()
+            .saturating_duration_since(Instant::now());
+        Ok((lookup.iter().collect(), ttl))
+    }
+
+    fn lookup_srv(&self, name: &str) -> VclResult<(Vec<SrvTarget>, Duration)> {
+        use hickory_resolver::proto::rr::RData;
+
+        let lookup = self
+            .runtime
+            .block_on(self.resolver.srv_lookup(name))
+            .map_err(|e| VclError::String(format!("Unable to resolve {name}: {e}")))?;
+        let targets = lookup
+            .answers()
+            .iter()
+            .filter_map(|record| match &record.data {
+                // a target of "." means that the service is not available
+                RData::SRV(srv) if !srv.target.is_root() => Some(SrvTarget {
+                    priority: srv.priority,
+                    weight: srv.weight,
+                    port: srv.port,
+                    target: srv.target.to_utf8(),
+                }),
+                _ => None,
+            })
+            .collect();
+        let ttl = lookup
+            .valid_until()
+            .saturating_duration_since(Instant::now());
+        Ok((targets, ttl))
+    }
+
+    fn lookup_txt(&self, name: &str) -> VclResult<(Vec<String>, Duration)> {
+        use hickory_resolver::proto::rr::RData;
+
+        let lookup = self
+            .runtime
+            .block_on(self.resolver.txt_lookup(name))
+            .map_err(|e| VclError::String(format!("Unable to resolve {name}: {e}")))?;
+        // the strings of a record are parts of the same text
+        let texts = lookup
+            .answers()
+            .iter()
+            .filter_map(|record| match &record.data {
+                RData::TXT(txt) => Some(
+                    txt.txt_data
+                        .iter()
+                        .map(|part| String::from_utf8_lossy(part))
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect();
+        let ttl = lookup
+            .valid_until()
+            .saturating_duration_since(Instant::now());
+        Ok((texts, ttl))
+    }
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    answer: Arc<[V]>,
+    expires: Instant,
+    /// The error of the last lookup, if it failed
+    error: Option<String>,
+}
+
+/// The answers of one kind of query, by name
+struct Cache<V> {
+    entries: Mutex<HashMap<String, Entry<V>>>,
+}
+
+impl<V> Cache<V> {
+    fn new() -> Self {
+        Self {
+            entries: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Entry<V>>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The answer for `name`, if it is still valid
+    fn get(&self, name: &str, now: Instant) -> Option<Arc<[V]>> {
+        self.lock()
+            .get(name)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| Arc::clone(&entry.answer))
+    }
+
+    /// Store the result of a lookup of `name`, keeping the previous answer if the lookup failed
+    fn store(
+        &self,
+        name: &str,
+        now: Instant,
+        result: VclResult<(Vec<V>, Duration)>,
+    ) -> VclResult<Arc<[V]>> {
+        let mut entries = self.lock();
+        match result {
+            Ok((answer, ttl)) => {
+                let answer: Arc<[V]> = answer.into();
+                entries.insert(
+                    name.to_string(),
+                    Entry {
+                        answer: Arc::clone(&answer),
+                        expires: now + ttl,
+                        error: None,
+                    },
+                );
+                Ok(answer)
+            }
+            Err(err) => match entries.get_mut(name) {
+                Some(entry) if !entry.answer.is_empty() => {
+                    // retry at the next refresh, but keep serving the stale answer
+                    entry.error = Some(err.to_string());
+                    Ok(Arc::clone(&entry.answer))
+                }
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// The names whose answer expires before `deadline`, or whose last lookup failed
+    fn expiring(&self, deadline: Instant) -> Vec<String> {
+        self.lock()
+            .iter()
+            .filter(|(_, entry)| entry.expires <= deadline || entry.error.is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn error(&self, name: &str) -> Option<String> {
+        self.lock().get(name)?.error.clone()
+    }
+
+    fn remove(&self, name: &str) {
+        self.lock().remove(name);
+    }
+}
+
+/// One of the methods of [`Lookup`]
+type Query<V> = fn(&dyn Lookup, &str) -> VclResult<(Vec<V>, Duration)>;
+
+struct Inner {
+    lookup: Box<dyn Lookup>,
+    addrs: Cache<IpAddr>,
+    srv: Cache<SrvTarget>,
+    txt: Cache<String>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+}
+
+impl Inner {
+    /// Query `name` and update its entry in `cache`, keeping the previous answer if the lookup
+    /// fails
+    fn refresh<V>(
+        &self,
+        cache: &Cache<V>,
+        query: Query<V>,
+        name: &str,
+        now: Instant,
+    ) -> VclResult<Arc<[V]>> {
+        let result = query(&*self.lookup, name).and_then(|(answer, ttl)| {
+            if answer.is_empty() {
+                Err(VclError::String(format!("No record found for {name}")))
+            } else {
+                Ok((answer, ttl.clamp(self.min_ttl, self.max_ttl)))
+            }
+        });
+        cache.store(name, now, result)
+    }
+
+    /// The answer for `name`, from `cache` if it is still valid
+    fn get<V>(&self, cache: &Cache<V>, query: Query<V>, name: &str) -> VclResult<Arc<[V]>> {
+        let now = Instant::now();
+        match cache.get(name, now) {
+            Some(answer) => Ok(answer),
+            None => self.refresh(cache, query, name, now),
+        }
+    }
+
+    /// Refresh the entries expiring within `horizon`
+    fn refresh_expiring(&self, horizon: Duration) {
+        let now = Instant::now();
+        for host in self.addrs.expiring(now + horizon) {
+            let _ = self.refresh(&self.addrs, Lookup::lookup, &host, now);
+        }
+        for name in self.srv.expiring(now + horizon) {
+            let _ = self.refresh(&self.srv, Lookup::lookup_srv, &name, now);
+        }
+        for name in self.txt.expiring(now + horizon) {
+            let _ = self.refresh(&self.txt, Lookup::lookup_txt, &name, now);
+        }
+    }
+}
+
+/// A caching resolver, see the [module documentation](self)
+pub struct Resolver {
+    inner: Arc<Inner>,
+    timers: Timers,
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolver")
+            .field("hosts", &self.inner.addrs.lock().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Resolver {
+    /// A resolver refreshing the addresses every `interval` while started, bounding the TTL of
+    /// the records between `min_ttl` and `max_ttl`
+    pub fn new(
+        lookup: impl Lookup,
+        interval: Duration,
+        min_ttl: Duration,
+        max_ttl: Duration,
+    ) -> Self {
+        let inner = Arc::new(Inner {
+            lookup: Box::new(lookup),
+            addrs: Cache::new(),
+            srv: Cache::new(),
+            txt: Cache::new(),
+            min_ttl,
+            max_ttl,
+        });
+        let mut timers = Timers::new();
+        let refreshed = Arc::clone(&inner);
+        timers.every(interval, move || refreshed.refresh_expiring(interval));
+        Self { inner, timers }
+    }
+
+    /// A resolver using the system resolver, with a 30 second TTL, refreshed every second
+    pub fn system() -> Self {
+        Self::new(
+            SystemLookup::default(),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            Duration::from_secs(3600),
+        )
+    }
+
+    /// A resolver sending its own DNS queries, honoring the TTL of the records, refreshed every
+    /// second
+    #[cfg(feature = "dns-hickory")]
+    pub fn hickory() -> VclResult<Self> {
+        Ok(Self::new(
+            HickoryLookup::from_system_conf()?,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            Duration::from_secs(3600),
+        ))
+    }
+
+    /// The addresses of `host` with `port`, from the cache if they are still valid. The host is
+    /// then kept fresh by the background refresh, until [`Resolver::forget()`] is called.
+    pub fn resolve(&self, host: &str, port: u16) -> VclResult<Vec<SocketAddr>> {
+        let addrs = self.inner.get(&self.inner.addrs, Lookup::lookup, host)?;
+        Ok(addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+    }
+
+    /// The targets of the `SRV` records of `name`, e.g. `_http._tcp.example.com`, sorted by
+    /// priority. They are cached and kept fresh like the addresses.
+    pub fn resolve_srv(&self, name: &str) -> VclResult<Vec<SrvTarget>> {
+        let targets = self.inner.get(&self.inner.srv, Lookup::lookup_srv, name)?;
+        let mut targets = targets.to_vec();
+        targets.sort_by_key(|t| t.priority);
+        Ok(targets)
+    }
+
+    /// The text of the `TXT` records of `name`. They are cached and kept fresh like the
+    /// addresses.
+    pub fn resolve_txt(&self, name: &str) -> VclResult<Vec<String>> {
+        Ok(self
+            .inner
+            .get(&self.inner.txt, Lookup::lookup_txt, name)?
+            .to_vec())
+    }
+
+    /// Stop keeping `name` fresh, and drop all its records
+    pub fn forget(&self, name: &str) {
+        self.inner.addrs.remove(name);
+        self.inner.srv.remove(name);
+        self.inner.txt.remove(name);
+    }
+
+    /// The error of the last refresh of `name`, while its previous records are still served
+    pub fn last_error(&self, name: &str) -> Option<String> {
+        self.inner
+            .addrs
+            .error(name)
+            .or_else(|| self.inner.srv.error(name))
+            .or_else(|| self.inner.txt.error(name))
+    }
+
+    /// Refresh in the background while the VCL is warm, see [`Timers::on_event()`]
+    pub fn on_event(&mut self, event: Event) {
+        self.timers.on_event(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    /// Resolves to 10.0.0.<n>, n being the number of queries, and fails from the third one
+    #[derive(Default)]
+    struct Counting(Arc<AtomicU32>);
+
+    impl Lookup for Counting {
+        fn lookup(&self, host: &str) -> VclResult<(Vec<IpAddr>, Duration)> {
+            let n = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+            if n >= 3 {
+                return Err(VclError::String(format!("{host}: down")));
+            }
+            let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, n as u8));
+            Ok((vec![ip], Duration::ZERO))
+        }
+    }
+
+    #[test]
+    fn resolver() {
+        let queries = Arc::new(AtomicU32::new(0));
+        let ttl = Duration::from_millis(50);
+        let lookup = Counting(Arc::clone(&queries));
+        let resolver = Resolver::new(lookup, Duration::from_secs(60), ttl, ttl);
+
+        let addrs = resolver.resolve("example", 80).unwrap();
+        assert_eq!(addrs, ["10.0.0.1:80".parse().unwrap()]);
+        // cached, the TTL is raised to the minimum
+        resolver.resolve("example", 81).unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+
+        std::thread::sleep(ttl);
+        assert_eq!(
+            resolver.resolve("example", 80).unwrap()[0].ip().to_string(),
+            "10.0.0.2"
+        );
+
+        // failures keep the stale addresses
+        resolver.inner.refresh_expiring(ttl);
+        assert_eq!(
+            resolver.resolve("example", 80).unwrap()[0].ip().to_string(),
+            "10.0.0.2"
+        );
+        assert!(resolver.last_error("example").unwrap().contains("down"));
+
+        resolver.forget("example");
+        assert!(resolver.resolve("example", 80).is_err());
+        assert!(resolver.resolve_srv("example").is_err());
+    }
+
+    struct Service;
+
+    impl Lookup for Service {
+        fn lookup(&self, host: &str) -> VclResult<(Vec<IpAddr>, Duration)> {
+            Err(VclError::String(format!("{host}: unknown")))
+        }
+
+        fn lookup_srv(&self, _name: &str) -> VclResult<(Vec<SrvTarget>, Duration)> {
+            let target = |priority, target: &str| SrvTarget {
+                priority,
+                weight: 1,
+                port: 8080,
+                target: target.to_string(),
+            };
+            let targets = vec![target(20, "backup."), target(10, "a."), target(10, "b.")];
+            Ok((targets, Duration::from_secs(60)))
+        }
+    }
+
+    #[test]
+    fn resolver_srv() {
+        let resolver = Resolver::new(
+            Service,
+            Duration::from_secs(60),
+            Duration::ZERO,
+            Duration::MAX,
+        );
+        let targets = resolver.resolve_srv("_http._tcp.example").unwrap();
+        let names: Vec<_> = targets.iter().map(|t| t.target.as_str()).collect();
+        assert_eq!(names, ["a.", "b.", "backup."]);
+
+        let err = resolver.resolve_txt("example").unwrap_err().to_string();
+        assert!(err.contains("not supported"), "{err}");
+    }
+}
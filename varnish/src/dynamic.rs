@@ -0,0 +1,381 @@
+//! Backends following the DNS answers of a host name
+//!
+//! A [`DynamicBackendGroup`] keeps one [`Backend`] per address of a host name: when the
+//! [`Resolver`] sees new addresses, a backend is created for each of them, and the backends of the
+//! addresses that went away are retired. [`DynamicBackendGroup::backend()`] then picks one of the
+//! healthy backends, round-robin, to be returned to VCL, e.g. as `req.backend_hint`.
+//!
+//! Creating a backend needs a [`Ctx`], so the group is brought in sync with the resolver when
+//! a backend is asked for, the DNS queries themselves being made in the background. A retired
+//! backend is kept until no fetch uses it anymore, as Varnish may still be talking to it.
+//!
+//! Each backend wraps the [`Serve`] implementation built for its address by the factory of the
+//! group. When the backends check their own health, e.g. by polling the address, the group is
+//! told with [`DynamicBackendGroup::has_probe()`], and only picks the healthy ones.
+//!
+//! A group created with [`DynamicBackendGroup::srv()`] follows the `SRV` records of a service
+//! instead, which needs a [`Lookup`](crate::dns::Lookup) supporting them, e.g. `HickoryLookup`.
+//! The backends of the targets with the best priority are picked in proportion to their weights,
+//! with a [`WeightedRoundRobin`], and the other priorities are only used when none of them is
+//! healthy. The `TXT` records of the service, e.g. routing hints, are available with
+//! [`DynamicBackendGroup::hints()`].
+//!
+//! ``` rust
+//! use std::net::SocketAddr;
+//! use varnish::dns::Resolver;
+//! use varnish::dynamic::DynamicBackendGroup;
+//! use varnish::ffi::VCL_BACKEND;
+//! use varnish::vcl::{Ctx, Event, Serve, VclError};
+//!
+//! struct Origin {
+//!     addr: SocketAddr,
+//! }
+//!
+//! impl Serve<()> for Origin {
+//!     fn get_type(&self) -> &str {
+//!         "origin"
+//!     }
+//!
+//!     fn get_headers(&self, ctx: &mut Ctx) -> Result<Option<()>, VclError> {
+//!         // fetch from `self.addr`
+//!         Ok(None)
+//!     }
+//! }
+//!
+//! // e.g. the constructor of a VCL object: `new origin = example.origin("api.internal", 8080);`
+//! fn new(name: &str, host: &str, port: u16) -> DynamicBackendGroup<Origin, ()> {
+//!     DynamicBackendGroup::new(name, host, port, Resolver::system(), |addr| Origin { addr })
+//! }
+//!
+//! // e.g. the `#[event]` function, with the group in the `#[shared_per_vcl]` state
+//! fn event(group: &mut DynamicBackendGroup<Origin, ()>, event: Event) {
+//!     group.on_event(event);
+//! }
+//!
+//! // e.g. `set req.backend_hint = origin.backend();`
+//! fn backend(group: &DynamicBackendGroup<Origin, ()>, ctx: &mut Ctx) -> VCL_BACKEND {
+//!     group.backend(ctx)
+//! }
+//! ```
+
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use varnish_sys::ffi::VCL_BACKEND;
+use varnish_sys::vcl::{Backend, Ctx, Event, LogTag, Serve, Transfer, VclResult};
+
+use crate::directors::WeightedRoundRobin;
+use crate::dns::Resolver;
+
+/// How long a retired backend is kept at least, even if it looks idle
+const RETIRE_GRACE: Duration = Duration::from_secs(10);
+
+/// What the addresses of a group come from
+#[derive(Debug)]
+enum Source {
+    Host { host: String, port: u16 },
+    Srv { name: String },
+}
+
+/// An address to have a backend for, and how to pick it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Endpoint {
+    addr: SocketAddr,
+    priority: u16,
+    weight: u32,
+}
+
+struct Members<S: Serve<T>, T: Transfer> {
+    /// The backends, by address
+    active: WeightedRoundRobin<(SocketAddr, Backend<S, T>)>,
+    retired: Vec<(Instant, Backend<S, T>)>,
+}
+
+/// A set of backends for the addresses of a host name or of a service, see the
+/// [module documentation](self)
+pub struct DynamicBackendGroup<S: Serve<T>, T: Transfer> {
+    name: String,
+    source: Source,
+    has_probe: bool,
+    resolver: Resolver,
+    factory: Box<dyn Fn(SocketAddr) -> S + Send + Sync>,
+    members: Mutex<Members<S, T>>,
+}
+
+impl<S: Serve<T>, T: Transfer> Debug for DynamicBackendGroup<S, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DynamicBackendGroup")
+            .field("name", &self.name)
+            .field("source", &self.source)
+            .field("addresses", &self.addresses())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: Serve<T>, T: Transfer> DynamicBackendGroup<S, T> {
+    /// A group named `name`, for `host` and `port`, using `resolver` to follow the addresses of
+    /// `host`, and `factory` to build the backend of each address. The group is empty until it
+    /// is first synced.
+    pub fn new(
+        name: &str,
+        host: &str,
+        port: u16,
+        resolver: Resolver,
+        factory: impl Fn(SocketAddr) -> S + Send + Sync + 'static,
+    ) -> Self {
+        let source = Source::Host {
+            host: host.to_string(),
+            port,
+        };
+        Self::with_source(name, source, resolver, factory)
+    }
+
+    /// A group named `name`, following the targets of the `SRV` records of `service`, e.g.
+    /// `_http._tcp.example.com`, and their addresses. See [`DynamicBackendGroup::new()`].
+    pub fn srv(
+        name: &str,
+        service: &str,
+        resolver: Resolver,
+        factory: impl Fn(SocketAddr) -> S + Send + Sync + 'static,
+    ) -> Self {
+        let source = Source::Srv {
+            name: service.to_string(),
+        };
+        Self::with_source(name, source, resolver, factory)
+    }
+
+    fn with_source(
+        name: &str,
+        source: Source,
+        resolver: Resolver,
+        factory: impl Fn(SocketAddr) -> S + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            source,
+            has_probe: false,
+            resolver,
+            factory: Box::new(factory),
+            members: Mutex::new(Members {
+                active: WeightedRoundRobin::new(),
+                retired: Vec::new(),
+            }),
+        }
+    }
+
+    /// Whether the backends check their own health with [`Serve::healthy()`], false by default.
+    /// Only the healthy backends are then picked.
+    #[must_use]
+    pub fn has_probe(mut self, has_probe: bool) -> Self {
+        self.has_probe = has_probe;
+        self
+    }
+
+    /// The addresses of the current backends
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.lock()
+            .active
+            .members()
+            .map(|(_, _, _, (addr, _))| *addr)
+            .collect()
+    }
+
+    /// The text of the `TXT` records of the host or the service, e.g. routing hints. They are
+    /// cached and kept fresh by the resolver.
+    pub fn hints(&self) -> VclResult<Vec<String>> {
+        match &self.source {
+            Source::Host { host, .. } => self.resolver.resolve_txt(host),
+            Source::Srv { name } => self.resolver.resolve_txt(name),
+        }
+    }
+
+    /// The addresses to have backends for, with their priorities and weights
+    fn endpoints(&self) -> VclResult<Vec<Endpoint>> {
+        match &self.source {
+            Source::Host { host, port } => Ok(self
+                .resolver
+                .resolve(host, *port)?
+                .into_iter()
+                .map(|addr| Endpoint {
+                    addr,
+                    priority: 0,
+                    weight: 1,
+                })
+                .collect()),
+            Source::Srv { name } => {
+                let mut endpoints = Vec::new();
+                let mut error = None;
+                for target in self.resolver.resolve_srv(name)? {
+                    // a target that cannot be resolved is skipped, unless all of them fail
+                    match self.resolver.resolve(&target.target, target.port) {
+                        Ok(addrs) => endpoints.extend(addrs.into_iter().map(|addr| Endpoint {
+                            addr,
+                            priority: target.priority,
+                            weight: u32::from(target.weight),
+                        })),
+                        Err(e) => error = Some(e),
+                    }
+                }
+                match error {
+                    Some(e) if endpoints.is_empty() => Err(e),
+                    _ => Ok(endpoints),
+                }
+            }
+        }
+    }
+
+    /// Create and retire backends to match the addresses of the host, and drop the retired
+    /// backends that are no longer used. Returns whether the set of backends changed.
+    ///
+    /// If the host cannot be resolved, the current backends are kept.
+    pub fn sync(&self, ctx: &mut Ctx) -> VclResult<bool> {
+        let resolved = self.endpoints()?;
+        let mut members = self.lock();
+        let now = Instant::now();
+        members
+            .retired
+            .retain(|(since, be)| now < *since + RETIRE_GRACE || be.load().in_flight() > 0);
+
+        let current: Vec<Endpoint> = members
+            .active
+            .members()
+            .map(|(_, priority, weight, (addr, _))| Endpoint {
+                addr: *addr,
+                priority,
+                weight,
+            })
+            .collect();
+        let (added, removed, changed) = diff(&current, &resolved);
+        if added.is_empty() && removed.is_empty() && changed.is_empty() {
+            return Ok(false);
+        }
+        // create the new backends first, so that a failure leaves the group untouched
+        let mut created = Vec::with_capacity(added.len());
+        for endpoint in added {
+            let addr = endpoint.addr;
+            let name = format!("{}({addr})", self.name);
+            let be = Backend::new(ctx, &name, (self.factory)(addr), self.has_probe)?;
+            created.push((endpoint, be));
+        }
+        for addr in removed {
+            if let Some((_, be)) = members.active.remove(&addr.to_string()) {
+                members.retired.push((now, be));
+            }
+        }
+        for endpoint in changed {
+            let id = endpoint.addr.to_string();
+            members.active.set(&id, endpoint.priority, endpoint.weight);
+        }
+        for (endpoint, be) in created {
+            let Endpoint {
+                addr,
+                priority,
+                weight,
+            } = endpoint;
+            members
+                .active
+                .add(addr.to_string(), priority, weight, (addr, be));
+        }
+        Ok(true)
+    }
+
+    /// Pick a backend among the healthy ones, after syncing the group: round-robin for a host,
+    /// by priority and weight for a service. Returns a null backend if there is none, which
+    /// fails the fetch.
+    ///
+    /// Sync errors are logged, and the current backends are used.
+    pub fn backend(&self, ctx: &mut Ctx) -> VCL_BACKEND {
+        if let Err(e) = self.sync(ctx) {
+            ctx.log(LogTag::Error, format!("{}: {e}", self.name));
+        }
+        let has_probe = self.has_probe;
+        self.lock()
+            .active
+            .pick_where(|(_, be)| !has_probe || be.get_inner().healthy(ctx).0)
+            .map_or_else(VCL_BACKEND::default, |(_, be)| be.vcl_ptr())
+    }
+
+    /// Refresh the addresses in the background while the VCL is warm, see
+    /// [`Resolver::on_event()`]
+    pub fn on_event(&mut self, event: Event) {
+        self.resolver.on_event(event);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Members<S, T>> {
+        self.members.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The endpoints to add to `current`, the addresses to remove from it, and the endpoints whose
+/// priority or weight changed, to get `resolved`. An address resolved twice, e.g. from two `SRV`
+/// targets, keeps its first endpoint.
+fn diff(
+    current: &[Endpoint],
+    resolved: &[Endpoint],
+) -> (Vec<Endpoint>, Vec<SocketAddr>, Vec<Endpoint>) {
+    let mut wanted: Vec<Endpoint> = Vec::new();
+    for endpoint in resolved {
+        if !wanted.iter().any(|e| e.addr == endpoint.addr) {
+            wanted.push(*endpoint);
+        }
+    }
+    let find = |list: &[Endpoint], addr| list.iter().find(|e| e.addr == addr).copied();
+    let added = wanted
+        .iter()
+        .filter(|e| find(current, e.addr).is_none())
+        .copied()
+        .collect();
+    let removed = current
+        .iter()
+        .filter(|e| find(&wanted, e.addr).is_none())
+        .map(|e| e.addr)
+        .collect();
+    let changed = wanted
+        .iter()
+        .filter(|e| find(current, e.addr).is_some_and(|c| c != **e))
+        .copied()
+        .collect();
+    (added, removed, changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoints(list: &[(&str, u16, u32)]) -> Vec<Endpoint> {
+        list.iter()
+            .map(|(addr, priority, weight)| Endpoint {
+                addr: addr.parse().unwrap(),
+                priority: *priority,
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dynamic_diff() {
+        let current = endpoints(&[("10.0.0.1:80", 0, 1), ("10.0.0.2:80", 0, 1)]);
+        let resolved = endpoints(&[
+            ("10.0.0.2:80", 0, 1),
+            ("10.0.0.3:80", 0, 1),
+            ("10.0.0.3:80", 5, 1),
+        ]);
+        let (added, removed, changed) = diff(&current, &resolved);
+        assert_eq!(added, endpoints(&[("10.0.0.3:80", 0, 1)]));
+        assert_eq!(removed, ["10.0.0.1:80".parse().unwrap()]);
+        assert!(changed.is_empty());
+
+        let (added, removed, changed) = diff(&current, &current);
+        assert!(added.is_empty() && removed.is_empty() && changed.is_empty());
+        let (added, removed, _) = diff(&[], &current);
+        assert_eq!((added, removed.len()), (current.clone(), 0));
+
+        // SRV targets changing their priority or weight keep their backend
+        let resolved = endpoints(&[("10.0.0.1:80", 10, 3), ("10.0.0.2:80", 0, 1)]);
+        let (added, removed, changed) = diff(&current, &resolved);
+        assert!(added.is_empty() && removed.is_empty());
+        assert_eq!(changed, endpoints(&[("10.0.0.1:80", 10, 3)]));
+    }
+}
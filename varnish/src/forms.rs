@@ -0,0 +1,613 @@
+//! Read the fields of HTML form bodies, without a web framework
+//!
+//! A vmod inspecting submitted forms, e.g. to check a CSRF token or to reject suspicious values,
+//! needs the fields of `application/x-www-form-urlencoded` and `multipart/form-data` bodies. The
+//! parsers of this module are fed the body one chunk at a time, as returned by
+//! [`Ctx::cached_req_body()`](crate::vcl::Ctx::cached_req_body), and copy each field into the
+//! workspace, so the fields live until the end of the task without any allocation to free.
+//!
+//! The size of the work is bounded by [`FormLimits`]: a body with too many fields, or with a
+//! field too large, is rejected instead of being parsed.
+//!
+//! ``` rust,ignore
+//! use varnish::forms::{multipart_boundary, FormLimits, MultipartParser};
+//! use varnish::vcl::{Ctx, VclError};
+//!
+//! // e.g. in a function called from `vcl_recv`, after `std.cache_req_body()`
+//! fn csrf_token<'a>(ctx: &mut Ctx<'a>) -> Result<Option<&'a str>, VclError> {
+//!     let content_type = ctx.req().and_then(|r| r.header("content-type")).unwrap_or_default();
+//!     let boundary = multipart_boundary(content_type).ok_or("not a multipart form")?.to_string();
+//!     let mut parser = MultipartParser::new(&boundary, FormLimits::default());
+//!     for chunk in ctx.cached_req_body()? {
+//!         parser.feed(&mut ctx.ws, chunk)?;
+//!     }
+//!     let fields = parser.finish()?;
+//!     Ok(fields.iter().find(|f| f.name == "csrf").and_then(|f| f.value_str()))
+//! }
+//! ```
+
+use std::num::NonZeroUsize;
+
+use varnish_sys::vcl::{VclError, VclResult, Workspace};
+
+/// The largest header block of a multipart part
+const MAX_PART_HEADERS: usize = 8 * 1024;
+
+/// The bounds of the work done by a parser
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormLimits {
+    /// The maximum number of fields, 64 by default
+    pub fields: usize,
+    /// The maximum length of a field name, in bytes once decoded, 256 by default
+    pub name_len: usize,
+    /// The maximum length of a field value, in bytes once decoded, 64 KiB by default
+    pub value_len: usize,
+}
+
+impl Default for FormLimits {
+    fn default() -> Self {
+        Self {
+            fields: 64,
+            name_len: 256,
+            value_len: 64 * 1024,
+        }
+    }
+}
+
+/// A field of a form, copied into the workspace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormField<'a> {
+    /// The name, with invalid UTF-8 sequences replaced with `U+FFFD`
+    pub name: &'a str,
+    /// The decoded value
+    pub value: &'a [u8],
+    /// The name of the uploaded file, only for the file parts of multipart forms
+    pub filename: Option<&'a str>,
+    /// The `Content-Type` of the part, only for multipart forms
+    pub content_type: Option<&'a str>,
+}
+
+impl<'a> FormField<'a> {
+    /// The value, if it is valid UTF-8
+    pub fn value_str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.value).ok()
+    }
+}
+
+/// The fields found so far, with the limits they are checked against
+#[derive(Debug)]
+struct Fields<'a> {
+    limits: FormLimits,
+    fields: Vec<FormField<'a>>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(limits: FormLimits) -> Self {
+        Self {
+            limits,
+            fields: Vec::new(),
+        }
+    }
+
+    fn check_name(&self, len: usize) -> VclResult<()> {
+        if len > self.limits.name_len {
+            return Err(VclError::String(format!(
+                "form field name longer than {} bytes",
+                self.limits.name_len
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_value(&self, len: usize) -> VclResult<()> {
+        if len > self.limits.value_len {
+            return Err(VclError::String(format!(
+                "form field value longer than {} bytes",
+                self.limits.value_len
+            )));
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        ws: &mut Workspace<'a>,
+        name: &[u8],
+        value: &[u8],
+        filename: Option<&str>,
+        content_type: Option<&str>,
+    ) -> VclResult<()> {
+        if self.fields.len() >= self.limits.fields {
+            return Err(VclError::String(format!(
+                "more than {} form fields",
+                self.limits.fields
+            )));
+        }
+        let field = FormField {
+            name: ws_str(ws, &String::from_utf8_lossy(name))?,
+            value: ws_bytes(ws, value)?,
+            filename: filename.map(|s| ws_str(ws, s)).transpose()?,
+            content_type: content_type.map(|s| ws_str(ws, s)).transpose()?,
+        };
+        self.fields.push(field);
+        Ok(())
+    }
+}
+
+fn ws_bytes<'a>(ws: &mut Workspace<'a>, src: &[u8]) -> VclResult<&'a [u8]> {
+    let Some(len) = NonZeroUsize::new(src.len()) else {
+        return Ok(&[]);
+    };
+    let dest = ws.allocate(len)?;
+    for (d, s) in dest.iter_mut().zip(src) {
+        d.write(*s);
+    }
+    // every byte was initialized above
+    Ok(unsafe { std::slice::from_raw_parts(dest.as_ptr().cast::<u8>(), len.get()) })
+}
+
+fn ws_str<'a>(ws: &mut Workspace<'a>, src: &str) -> VclResult<&'a str> {
+    // a copy of a `str` is valid UTF-8
+    Ok(unsafe { std::str::from_utf8_unchecked(ws_bytes(ws, src.as_bytes())?) })
+}
+
+/// Parse an `application/x-www-form-urlencoded` body
+///
+/// `+` is decoded as a space, and invalid `%` escapes are kept as they are.
+#[derive(Debug)]
+pub struct UrlEncodedParser<'a> {
+    fields: Fields<'a>,
+    name: Vec<u8>,
+    value: Vec<u8>,
+    in_value: bool,
+    /// The `%` escape being read
+    pct: Vec<u8>,
+}
+
+impl<'a> UrlEncodedParser<'a> {
+    pub fn new(limits: FormLimits) -> Self {
+        Self {
+            fields: Fields::new(limits),
+            name: Vec::new(),
+            value: Vec::new(),
+            in_value: false,
+            pct: Vec::with_capacity(3),
+        }
+    }
+
+    /// Parse the next chunk of the body
+    pub fn feed(&mut self, ws: &mut Workspace<'a>, chunk: &[u8]) -> VclResult<()> {
+        for &b in chunk {
+            match b {
+                b'&' => self.end_field(ws)?,
+                b'=' if !self.in_value => {
+                    self.flush_pct()?;
+                    self.in_value = true;
+                }
+                _ => self.push(b)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The fields, once the whole body was fed
+    pub fn finish(mut self, ws: &mut Workspace<'a>) -> VclResult<Vec<FormField<'a>>> {
+        self.end_field(ws)?;
+        Ok(self.fields.fields)
+    }
+
+    fn push(&mut self, b: u8) -> VclResult<()> {
+        if !self.pct.is_empty() {
+            if b.is_ascii_hexdigit() {
+                self.pct.push(b);
+                if self.pct.len() == 3 {
+                    let decoded = (hex(self.pct[1]) << 4) | hex(self.pct[2]);
+                    self.pct.clear();
+                    return self.out(decoded);
+                }
+                return Ok(());
+            }
+            self.flush_pct()?;
+        }
+        match b {
+            b'%' => {
+                self.pct.push(b);
+                Ok(())
+            }
+            b'+' => self.out(b' '),
+            _ => self.out(b),
+        }
+    }
+
+    /// Output an incomplete escape as it is
+    fn flush_pct(&mut self) -> VclResult<()> {
+        for i in 0..self.pct.len() {
+            self.out(self.pct[i])?;
+        }
+        self.pct.clear();
+        Ok(())
+    }
+
+    fn out(&mut self, b: u8) -> VclResult<()> {
+        if self.in_value {
+            self.fields.check_value(self.value.len() + 1)?;
+            self.value.push(b);
+        } else {
+            self.fields.check_name(self.name.len() + 1)?;
+            self.name.push(b);
+        }
+        Ok(())
+    }
+
+    fn end_field(&mut self, ws: &mut Workspace<'a>) -> VclResult<()> {
+        self.flush_pct()?;
+        // skip the empty pairs of `a=1&&b=2`
+        if self.in_value || !self.name.is_empty() {
+            self.fields.push(ws, &self.name, &self.value, None, None)?;
+        }
+        self.name.clear();
+        self.value.clear();
+        self.in_value = false;
+        Ok(())
+    }
+}
+
+fn hex(b: u8) -> u8 {
+    match b {
+        b'0'..=b'9' => b - b'0',
+        b'a'..=b'f' => b - b'a' + 10,
+        _ => b - b'A' + 10,
+    }
+}
+
+/// Parse all the chunks of an `application/x-www-form-urlencoded` body at once
+pub fn parse_urlencoded<'a, B: AsRef<[u8]>>(
+    ws: &mut Workspace<'a>,
+    body: impl IntoIterator<Item = B>,
+    limits: FormLimits,
+) -> VclResult<Vec<FormField<'a>>> {
+    let mut parser = UrlEncodedParser::new(limits);
+    for chunk in body {
+        parser.feed(ws, chunk.as_ref())?;
+    }
+    parser.finish(ws)
+}
+
+/// The `boundary` parameter of a `multipart/form-data` content type
+pub fn multipart_boundary(content_type: &str) -> Option<&str> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v.trim().trim_matches('"'))
+        .filter(|b| !b.is_empty() && b.len() <= 70)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MultipartState {
+    /// Before the first boundary
+    Preamble,
+    /// After a boundary, waiting for `\r\n` or the final `--`
+    Boundary,
+    Headers,
+    Body,
+    Done,
+}
+
+/// Parse a `multipart/form-data` body
+///
+/// File parts are read like the others, their content being the value of the field, so
+/// [`FormLimits::value_len`] also bounds the size of the uploaded files.
+#[derive(Debug)]
+pub struct MultipartParser<'a> {
+    fields: Fields<'a>,
+    /// `\r\n--` followed by the boundary
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: MultipartState,
+    headers_len: usize,
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+    value: Vec<u8>,
+}
+
+impl<'a> MultipartParser<'a> {
+    /// A parser for the body of a form, `boundary` coming from [`multipart_boundary()`]
+    pub fn new(boundary: &str, limits: FormLimits) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Self {
+            fields: Fields::new(limits),
+            delimiter,
+            // so that a boundary at the very start of the body is found like the others
+            buf: b"\r\n".to_vec(),
+            state: MultipartState::Preamble,
+            headers_len: 0,
+            name: None,
+            filename: None,
+            content_type: None,
+            value: Vec::new(),
+        }
+    }
+
+    /// Parse the next chunk of the body
+    pub fn feed(&mut self, ws: &mut Workspace<'a>, chunk: &[u8]) -> VclResult<()> {
+        self.buf.extend_from_slice(chunk);
+        while self.step(ws)? {}
+        Ok(())
+    }
+
+    /// The fields, once the whole body was fed
+    pub fn finish(self) -> VclResult<Vec<FormField<'a>>> {
+        if self.state != MultipartState::Done {
+            return Err(VclError::CStr(c"truncated multipart body"));
+        }
+        Ok(self.fields.fields)
+    }
+
+    /// Make progress with the buffered data, `false` if more data is needed
+    fn step(&mut self, ws: &mut Workspace<'a>) -> VclResult<bool> {
+        match self.state {
+            MultipartState::Preamble => {
+                if let Some(pos) = find(&self.buf, &self.delimiter) {
+                    self.buf.drain(..pos + self.delimiter.len());
+                    self.state = MultipartState::Boundary;
+                    return Ok(true);
+                }
+                let keep = self.delimiter.len() - 1;
+                if self.buf.len() > keep {
+                    self.buf.drain(..self.buf.len() - keep);
+                }
+                Ok(false)
+            }
+            MultipartState::Boundary => {
+                if self.buf.len() < 2 {
+                    return Ok(false);
+                }
+                match &self.buf[..2] {
+                    b"--" => {
+                        // the epilogue is ignored
+                        self.buf.clear();
+                        self.state = MultipartState::Done;
+                        Ok(false)
+                    }
+                    b"\r\n" => {
+                        self.buf.drain(..2);
+                        self.headers_len = 0;
+                        self.state = MultipartState::Headers;
+                        Ok(true)
+                    }
+                    _ => Err(VclError::CStr(c"malformed multipart boundary")),
+                }
+            }
+            MultipartState::Headers => {
+                let Some(end) = find(&self.buf, b"\r\n") else {
+                    if self.headers_len + self.buf.len() > MAX_PART_HEADERS {
+                        return Err(VclError::CStr(c"multipart part headers too long"));
+                    }
+                    return Ok(false);
+                };
+                self.headers_len += end + 2;
+                if self.headers_len > MAX_PART_HEADERS {
+                    return Err(VclError::CStr(c"multipart part headers too long"));
+                }
+                let line = self.buf.drain(..end + 2).collect::<Vec<u8>>();
+                let line = &line[..end];
+                if line.is_empty() {
+                    if self.name.is_none() {
+                        return Err(VclError::CStr(c"multipart part without a field name"));
+                    }
+                    self.state = MultipartState::Body;
+                } else {
+                    self.header(line)?;
+                }
+                Ok(true)
+            }
+            MultipartState::Body => {
+                if let Some(pos) = find(&self.buf, &self.delimiter) {
+                    self.fields.check_value(self.value.len() + pos)?;
+                    self.value.extend_from_slice(&self.buf[..pos]);
+                    self.buf.drain(..pos + self.delimiter.len());
+                    self.end_part(ws)?;
+                    self.state = MultipartState::Boundary;
+                    return Ok(true);
+                }
+                let keep = self.delimiter.len() - 1;
+                if self.buf.len() > keep {
+                    let len = self.buf.len() - keep;
+                    self.fields.check_value(self.value.len() + len)?;
+                    self.value.extend(self.buf.drain(..len));
+                }
+                Ok(false)
+            }
+            MultipartState::Done => {
+                self.buf.clear();
+                Ok(false)
+            }
+        }
+    }
+
+    fn header(&mut self, line: &[u8]) -> VclResult<()> {
+        let line = String::from_utf8_lossy(line);
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(VclError::CStr(c"malformed multipart part header"));
+        };
+        let value = value.trim();
+        if name.trim().eq_ignore_ascii_case("content-disposition") {
+            for (k, v) in disposition_params(value) {
+                if k.eq_ignore_ascii_case("name") {
+                    self.fields.check_name(v.len())?;
+                    self.name = Some(v);
+                } else if k.eq_ignore_ascii_case("filename") {
+                    self.filename = Some(v);
+                }
+            }
+        } else if name.trim().eq_ignore_ascii_case("content-type") {
+            self.content_type = Some(value.to_string());
+        }
+        Ok(())
+    }
+
+    fn end_part(&mut self, ws: &mut Workspace<'a>) -> VclResult<()> {
+        let name = self.name.take().unwrap_or_default();
+        let filename = self.filename.take();
+        let content_type = self.content_type.take();
+        self.fields.push(
+            ws,
+            name.as_bytes(),
+            &self.value,
+            filename.as_deref(),
+            content_type.as_deref(),
+        )?;
+        self.value.clear();
+        Ok(())
+    }
+}
+
+/// The `key=value` parameters of a `Content-Disposition` header, unquoted
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = value.split_once(';').map_or("", |(_, r)| r);
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ';']);
+        let Some((key, after)) = rest.split_once('=') else {
+            return params;
+        };
+        let key = key.trim().to_string();
+        let after = after.trim_start();
+        let (val, next) = if let Some(quoted) = after.strip_prefix('"') {
+            let mut val = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => val.extend(chars.next().map(|(_, c)| c)),
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => val.push(c),
+                }
+            }
+            (val, &quoted[end..])
+        } else {
+            let end = after.find(';').unwrap_or(after.len());
+            (after[..end].trim().to_string(), &after[end..])
+        };
+        params.push((key, val));
+        rest = next;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Parse all the chunks of a `multipart/form-data` body at once
+pub fn parse_multipart<'a, B: AsRef<[u8]>>(
+    ws: &mut Workspace<'a>,
+    boundary: &str,
+    body: impl IntoIterator<Item = B>,
+    limits: FormLimits,
+) -> VclResult<Vec<FormField<'a>>> {
+    let mut parser = MultipartParser::new(boundary, limits);
+    for chunk in body {
+        parser.feed(ws, chunk.as_ref())?;
+    }
+    parser.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use varnish_sys::vcl::TestWS;
+
+    use super::*;
+
+    fn pairs<'a>(fields: &[FormField<'a>]) -> Vec<(&'a str, &'a [u8])> {
+        fields.iter().map(|f| (f.name, f.value)).collect()
+    }
+
+    #[test]
+    fn urlencoded() {
+        let mut test_ws = TestWS::new(4096);
+        let mut ws = test_ws.workspace();
+        let body = ["a=1&b=hello+wor", "ld%2", "1&&c&d=%zz%4", "1%"];
+        let fields = parse_urlencoded(&mut ws, body, FormLimits::default()).unwrap();
+        assert_eq!(
+            pairs(&fields),
+            [
+                ("a", &b"1"[..]),
+                ("b", b"hello world!"),
+                ("c", b""),
+                ("d", b"%zzA%"),
+            ]
+        );
+
+        let limits = FormLimits {
+            fields: 2,
+            name_len: 3,
+            value_len: 4,
+        };
+        assert!(parse_urlencoded(&mut ws, ["a=1&b=2"], limits).is_ok());
+        assert!(parse_urlencoded(&mut ws, ["a=1&b=2&c=3"], limits).is_err());
+        assert!(parse_urlencoded(&mut ws, ["long=1"], limits).is_err());
+        assert!(parse_urlencoded(&mut ws, ["a=%41%41%41%41%41"], limits).is_err());
+    }
+
+    #[test]
+    fn multipart() {
+        assert_eq!(
+            multipart_boundary("Multipart/Form-Data; charset=utf-8; boundary=\"xyz\""),
+            Some("xyz")
+        );
+        assert_eq!(multipart_boundary("text/plain; boundary=xyz"), None);
+
+        let body = concat!(
+            "preamble\r\n",
+            "--xyz\r\n",
+            "Content-Disposition: form-data; name=\"csrf\"\r\n",
+            "\r\n",
+            "t0k3n\r\n",
+            "--xyz\r\n",
+            "content-disposition: form-data; name=\"file\"; filename=\"a \\\"b\\\";.txt\"\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "line 1\r\n-- not the end\r\n",
+            "--xyz--\r\n",
+            "epilogue",
+        );
+        // feed the body in every possible pair of chunks
+        for split in 0..body.len() {
+            let mut test_ws = TestWS::new(1024);
+            let mut ws = test_ws.workspace();
+            let (a, b) = body.as_bytes().split_at(split);
+            let fields = parse_multipart(&mut ws, "xyz", [a, b], FormLimits::default()).unwrap();
+            assert_eq!(
+                pairs(&fields),
+                [
+                    ("csrf", &b"t0k3n"[..]),
+                    ("file", b"line 1\r\n-- not the end")
+                ]
+            );
+            assert_eq!(fields[0].filename, None);
+            assert_eq!(fields[1].filename, Some("a \"b\";.txt"));
+            assert_eq!(fields[1].content_type, Some("text/plain"));
+        }
+
+        let mut test_ws = TestWS::new(1024);
+        let mut ws = test_ws.workspace();
+        let limits = FormLimits {
+            value_len: 8,
+            ..FormLimits::default()
+        };
+        assert!(parse_multipart(&mut ws, "xyz", [body], limits).is_err());
+        assert!(parse_multipart(&mut ws, "xyz", [&body[..60]], FormLimits::default()).is_err());
+        let no_name = "--xyz\r\nContent-Type: text/plain\r\n\r\nx\r\n--xyz--";
+        assert!(parse_multipart(&mut ws, "xyz", [no_name], FormLimits::default()).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Find the C functions of a vmod in the function table of its `Vmod_<name>_Data`
+//!
+//! Only the `Vmod_<name>_Data` symbol of a vmod is exported, its functions are reached through
+//! the `func` table, a C struct of function pointers. The JSON description of the vmod lists the
+//! `$FUNC`, `$OBJ` and `$EVENT` entries in the order of the fields of that struct, which gives the
+//! slot of each function.
+
+use std::ffi::{c_void, CStr};
+use std::mem::size_of;
+
+use serde_json::Value;
+use varnish_sys::ffi::vmod_data;
+use varnish_sys::vcl::VclError;
+
+/// The function table of a vmod, with the slots named after the VCL functions and methods:
+/// `duration` for `std.duration()`, `round_robin.__init` and `round_robin.__fini` for the
+/// constructor and destructor of `directors.round_robin`, `round_robin.add_backend` for its
+/// `.add_backend()` method, and `$EVENT` for the event function.
+#[derive(Debug)]
+pub(crate) struct FuncTable {
+    name: String,
+    func: *const *const c_void,
+    slots: Vec<String>,
+}
+
+// The table is static data of the vmod library, which outlives it
+unsafe impl Send for FuncTable {}
+unsafe impl Sync for FuncTable {}
+
+impl FuncTable {
+    /// # Safety
+    ///
+    /// `data` must be the `Vmod_<name>_Data` of a vmod, and the table must not be used once the
+    /// library of the vmod is unloaded.
+    pub(crate) unsafe fn new(data: &vmod_data) -> Result<Self, VclError> {
+        let name = if data.name.is_null() {
+            String::from("?")
+        } else {
+            CStr::from_ptr(data.name).to_string_lossy().into_owned()
+        };
+        if data.json.is_null() || data.func.is_null() {
+            return Err(VclError::String(format!(
+                "vmod {name} has no function table"
+            )));
+        }
+        let json = CStr::from_ptr(data.json).to_string_lossy();
+        let slots = slots(&json).map_err(|e| VclError::String(format!("vmod {name}: {e}")))?;
+        let len = usize::try_from(data.func_len).unwrap_or(0) / size_of::<*const c_void>();
+        if slots.len() > len {
+            return Err(VclError::String(format!(
+                "vmod {name} describes {} functions, but its table only has {len} slots",
+                slots.len()
+            )));
+        }
+        Ok(Self {
+            name,
+            func: data.func.cast(),
+            slots,
+        })
+    }
+
+    /// Get the function in the slot called `slot`
+    ///
+    /// # Safety
+    ///
+    /// `F` must be the `unsafe extern "C" fn` type of the function.
+    pub(crate) unsafe fn get<F: Copy>(&self, slot: &str) -> Result<F, VclError> {
+        assert_eq!(size_of::<F>(), size_of::<*const c_void>());
+        let idx = self
+            .slots
+            .iter()
+            .position(|s| s == slot)
+            .ok_or_else(|| VclError::String(format!("vmod {} has no {slot}", self.name)))?;
+        let ptr = *self.func.add(idx);
+        if ptr.is_null() {
+            return Err(VclError::String(format!(
+                "vmod {}: {slot} is not set",
+                self.name
+            )));
+        }
+        Ok(std::mem::transmute_copy::<*const c_void, F>(&ptr))
+    }
+}
+
+/// The names of the slots of the function table, in order, see [`FuncTable`]
+fn slots(json: &str) -> Result<Vec<String>, String> {
+    let json = json.strip_prefix("VMOD_JSON_SPEC\u{2}").unwrap_or(json);
+    let json = json.split('\u{3}').next().unwrap_or_default();
+    let spec: Value =
+        serde_json::from_str(json).map_err(|e| format!("invalid JSON description: {e}"))?;
+    let entries = spec
+        .as_array()
+        .ok_or("the JSON description is not an array")?;
+
+    let mut slots = Vec::new();
+    let mut table = None;
+    let mut add = |name: String, cfunc: Option<&Value>| -> Result<(), String> {
+        // e.g. `Vmod_vmod_std_Func.f_duration`, all of them being fields of the same struct
+        let prefix = cfunc
+            .and_then(Value::as_str)
+            .and_then(|cfunc| cfunc.split_once('.'))
+            .map(|(prefix, _)| prefix)
+            .ok_or_else(|| format!("no C function for {name}"))?;
+        if *table.get_or_insert_with(|| prefix.to_string()) != prefix {
+            return Err(format!(
+                "{name} is not in the {} table",
+                table.as_deref().unwrap_or_default()
+            ));
+        }
+        slots.push(name);
+        Ok(())
+    };
+    for entry in entries.iter().filter_map(Value::as_array) {
+        match entry.first().and_then(Value::as_str) {
+            // ["$FUNC", name, [ret, cfunc, args...]]
+            Some("$FUNC") => {
+                let name = str_at(entry, 1)?;
+                add(name.to_string(), entry.get(2).and_then(|p| p.get(1)))?;
+            }
+            // ["$OBJ", name, flags, struct, ["$INIT", proto], ["$FINI", proto],
+            //  ["$METHOD", name, proto]...]
+            Some("$OBJ") => {
+                let obj = str_at(entry, 1)?;
+                for member in entry.iter().skip(4).filter_map(Value::as_array) {
+                    let (name, proto) = match member.first().and_then(Value::as_str) {
+                        Some("$INIT") => (format!("{obj}.__init"), member.get(1)),
+                        Some("$FINI") => (format!("{obj}.__fini"), member.get(1)),
+                        Some("$METHOD") => (format!("{obj}.{}", str_at(member, 1)?), member.get(2)),
+                        _ => continue,
+                    };
+                    add(name, proto.and_then(|p| p.get(1)))?;
+                }
+            }
+            // ["$EVENT", cfunc]
+            Some("$EVENT") => add("$EVENT".to_string(), entry.get(1))?,
+            _ => {}
+        }
+    }
+    Ok(slots)
+}
+
+fn str_at(entry: &[Value], idx: usize) -> Result<&str, String> {
+    entry
+        .get(idx)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("invalid entry {}", Value::from(entry.to_vec())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_slots() {
+        // trimmed down from the description of vmod_directors
+        let json = concat!(
+            "VMOD_JSON_SPEC\u{2}",
+            r#"
+[
+  ["$VMOD", "1.0", "directors", "Vmod_vmod_directors_Func", "abc", "Varnish 7.6.1", "20", "0"],
+  ["$CPROTO", "struct Vmod_vmod_directors_Func {...};"],
+  ["$EVENT", "Vmod_vmod_directors_Func._event"],
+  ["$OBJ", "round_robin", {"NULL_OK": false}, "struct vmod_directors_round_robin",
+    ["$INIT", [["VOID"], "Vmod_vmod_directors_Func.f_round_robin__init", ""]],
+    ["$FINI", [["VOID"], "Vmod_vmod_directors_Func.f_round_robin__fini", ""]],
+    ["$METHOD", "add_backend",
+      [["VOID"], "Vmod_vmod_directors_Func.f_round_robin_add_backend", "", ["BACKEND"]]],
+    ["$METHOD", "backend",
+      [["BACKEND"], "Vmod_vmod_directors_Func.f_round_robin_backend", ""],
+      ["$RESTRICT", ["vcl_recv"]]]
+  ],
+  ["$FUNC", "lookup",
+    [["BACKEND"], "Vmod_vmod_directors_Func.f_lookup", "", ["STRING"]],
+    ["$RESTRICT", ["vcl_init"]]],
+  ["$ALIAS", "alias", "lookup"]
+]
+"#,
+            "\u{3}"
+        );
+        assert_eq!(
+            slots(json).unwrap(),
+            [
+                "$EVENT",
+                "round_robin.__init",
+                "round_robin.__fini",
+                "round_robin.add_backend",
+                "round_robin.backend",
+                "lookup",
+            ]
+        );
+
+        assert!(slots("VMOD_JSON_SPEC\u{2}[").is_err());
+        let two_tables =
+            r#"[["$FUNC", "f", [["VOID"], "A.f_f"]], ["$FUNC", "g", [["VOID"], "B.f_g"]]]"#;
+        assert!(slots(two_tables).is_err());
+        assert!(slots(r#"[["$FUNC", "f", [["VOID"]]]]"#).is_err());
+    }
+}
@@ -0,0 +1,3 @@
+//! Helpers working on the [`HttpHeaders`](crate::vcl::HttpHeaders) of the requests and responses
+
+pub mod policy;
@@ -0,0 +1,404 @@
+//! Enforce a set of rules on the headers of a response
+//!
+//! Most security-header vmods boil down to the same checks: some headers must be present (e.g.
+//! `Strict-Transport-Security` or `Content-Security-Policy`), some must never leave the cache
+//! (e.g. `Server` or `X-Powered-By`), and some must have a well-known form. A [`HeaderPolicy`]
+//! declares these rules, in Rust or in TOML, and [`HeaderPolicy::apply()`] enforces them in one
+//! pass:
+//! - a missing required header is added, with its default value,
+//! - a forbidden header is removed,
+//! - a header whose value does not match its pattern is removed, then added back with its default
+//!   value if it is also required.
+//!
+//! Each of these is a [`Violation`], returned in a [`Report`] that can be logged to VSL as
+//! `VCL_Log` records, e.g. `header policy: forbidden header Server, removed`.
+//!
+//! ``` rust
+//! use varnish::headers::policy::HeaderPolicy;
+//! use varnish::vcl::{Ctx, VclError};
+//!
+//! let policy: HeaderPolicy = r#"
+//!     forbidden = ["Server", "X-Powered-By"]
+//!
+//!     [required]
+//!     Strict-Transport-Security = "max-age=63072000"
+//!     X-Frame-Options = "DENY"
+//!
+//!     [patterns]
+//!     X-Frame-Options = "^(?i)(DENY|SAMEORIGIN)$"
+//! "#
+//! .parse()?;
+//!
+//! // e.g. in a function called from `vcl_deliver`
+//! fn secure(ctx: &mut Ctx, policy: &HeaderPolicy) -> Result<(), VclError> {
+//!     if let Some(resp) = ctx.resp_mut() {
+//!         policy.apply(resp)?.log(ctx);
+//!     }
+//!     Ok(())
+//! }
+//! # Ok::<(), VclError>(())
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use regex::Regex;
+use serde::Deserialize;
+use varnish_sys::vcl::{Ctx, HttpHeaders, LogTag, VclError};
+
+/// Check that `name` can be used as a header name
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Build a [`HeaderPolicy`] from its rules
+///
+/// Header names are case-insensitive, and each of them can only be declared once per kind of
+/// rule, e.g. a header can be required and have a pattern, but not be required twice.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderPolicyBuilder {
+    required: Vec<(String, String)>,
+    forbidden: Vec<String>,
+    patterns: Vec<(String, String)>,
+}
+
+impl HeaderPolicyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require the `name` header, adding it with `value` when it is missing
+    #[must_use]
+    pub fn require(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.required.push((name.into(), value.into()));
+        self
+    }
+
+    /// Remove the `name` header, if present
+    #[must_use]
+    pub fn forbid(mut self, name: impl Into<String>) -> Self {
+        self.forbidden.push(name.into());
+        self
+    }
+
+    /// Remove the `name` header when any of its values does not match the `pattern` regex. The
+    /// pattern is not anchored, use `^...$` to match the whole value.
+    #[must_use]
+    pub fn pattern(mut self, name: impl Into<String>, pattern: impl Into<String>) -> Self {
+        self.patterns.push((name.into(), pattern.into()));
+        self
+    }
+
+    /// Build the policy. Fails on invalid header names or patterns, on headers declared more
+    /// than once, on headers both required and forbidden, and on required values not matching
+    /// their own pattern.
+    pub fn build(self) -> Result<HeaderPolicy, VclError> {
+        fn check_names<'a>(
+            kind: &str,
+            names: impl Iterator<Item = &'a String>,
+        ) -> Result<(), VclError> {
+            let mut seen: Vec<&str> = Vec::new();
+            for name in names {
+                if !is_token(name) {
+                    return Err(VclError::String(format!("Invalid header name: {name:?}")));
+                }
+                if seen.iter().any(|s| s.eq_ignore_ascii_case(name)) {
+                    return Err(VclError::String(format!(
+                        "Header {name} is {kind} more than once"
+                    )));
+                }
+                seen.push(name);
+            }
+            Ok(())
+        }
+
+        check_names("required", self.required.iter().map(|(n, _)| n))?;
+        check_names("forbidden", self.forbidden.iter())?;
+        check_names("matched", self.patterns.iter().map(|(n, _)| n))?;
+
+        let patterns = self
+            .patterns
+            .into_iter()
+            .map(|(name, pattern)| match Regex::new(&pattern) {
+                Ok(re) => Ok((name, re)),
+                Err(e) => Err(VclError::String(format!("Invalid pattern for {name}: {e}"))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let policy = HeaderPolicy {
+            required: self.required,
+            forbidden: self.forbidden,
+            patterns,
+        };
+        for (name, value) in &policy.required {
+            if value.contains(['\r', '\n']) {
+                return Err(VclError::String(format!(
+                    "Invalid value for required header {name}: {value:?}"
+                )));
+            }
+            if policy.is_forbidden(name) {
+                return Err(VclError::String(format!(
+                    "Header {name} is both required and forbidden"
+                )));
+            }
+            if !policy.is_valid(name, value) {
+                return Err(VclError::String(format!(
+                    "The value of required header {name} does not match its pattern: {value:?}"
+                )));
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// The TOML form of a policy
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    required: BTreeMap<String, String>,
+    #[serde(default)]
+    forbidden: Vec<String>,
+    #[serde(default)]
+    patterns: BTreeMap<String, String>,
+}
+
+/// Rules enforced on the headers of a response, see the [module documentation](self)
+///
+/// It can be parsed from TOML, with a `forbidden` list of header names, a `[required]` table of
+/// header names and default values, and a `[patterns]` table of header names and regexes.
+#[derive(Debug, Clone)]
+pub struct HeaderPolicy {
+    required: Vec<(String, String)>,
+    forbidden: Vec<String>,
+    patterns: Vec<(String, Regex)>,
+}
+
+impl FromStr for HeaderPolicy {
+    type Err = VclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file: PolicyFile = toml::from_str(s)
+            .map_err(|e| VclError::String(format!("Invalid header policy: {e}")))?;
+        let mut builder = HeaderPolicyBuilder::new();
+        builder.required.extend(file.required);
+        builder.forbidden.extend(file.forbidden);
+        builder.patterns.extend(file.patterns);
+        builder.build()
+    }
+}
+
+impl HeaderPolicy {
+    pub fn builder() -> HeaderPolicyBuilder {
+        HeaderPolicyBuilder::new()
+    }
+
+    fn is_forbidden(&self, name: &str) -> bool {
+        self.forbidden.iter().any(|f| f.eq_ignore_ascii_case(name))
+    }
+
+    fn is_valid(&self, name: &str, value: &str) -> bool {
+        self.patterns
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .all(|(_, re)| re.is_match(value))
+    }
+
+    /// Find the violations of the policy in `headers`, in the order of the headers, followed by
+    /// the missing ones. A header is reported once, even if it appears several times.
+    fn check<'h>(&self, headers: impl IntoIterator<Item = (&'h str, &'h str)>) -> Vec<Violation> {
+        let mut violations: Vec<Violation> = Vec::new();
+        let mut present: Vec<&str> = Vec::new();
+        for (name, value) in headers {
+            let kind = if self.is_forbidden(name) {
+                ViolationKind::Forbidden
+            } else if !self.is_valid(name, value) {
+                ViolationKind::Invalid
+            } else {
+                present.push(name);
+                continue;
+            };
+            if !violations
+                .iter()
+                .any(|v| v.header.eq_ignore_ascii_case(name))
+            {
+                violations.push(Violation {
+                    header: name.to_string(),
+                    kind,
+                    value: value.to_string(),
+                });
+            }
+        }
+        for (name, value) in &self.required {
+            let replaced = violations
+                .iter()
+                .any(|v| v.header.eq_ignore_ascii_case(name));
+            if !replaced && !present.iter().any(|p| p.eq_ignore_ascii_case(name)) {
+                violations.push(Violation {
+                    header: name.clone(),
+                    kind: ViolationKind::Missing,
+                    value: value.clone(),
+                });
+            }
+        }
+        violations
+    }
+
+    /// The default value of a required header
+    fn default_value(&self, name: &str) -> Option<&str> {
+        self.required
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Enforce the policy on `headers`, usually `resp` or `beresp`, removing and adding headers
+    /// as needed. Only fails if a required header cannot be added, e.g. when the workspace is
+    /// exhausted.
+    pub fn apply(&self, headers: &mut HttpHeaders) -> Result<Report, VclError> {
+        let violations = self.check(headers.iter());
+        for violation in &violations {
+            let name = violation.header.as_str();
+            if violation.kind != ViolationKind::Missing {
+                headers.unset_header(name);
+            }
+            if let Some(value) = self.default_value(name) {
+                headers.set_header(name, value)?;
+            }
+        }
+        Ok(Report { violations })
+    }
+}
+
+/// How a header broke a [`HeaderPolicy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// A required header was missing, and was added
+    Missing,
+    /// A forbidden header was removed
+    Forbidden,
+    /// A header did not match its pattern, and was removed, or replaced if it is required
+    Invalid,
+}
+
+/// A header that broke a [`HeaderPolicy`], and was fixed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// The name of the header, as found in the object or as declared in the policy
+    pub header: String,
+    pub kind: ViolationKind,
+    /// The offending value, or the value added for a missing header
+    pub value: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = &self.header;
+        match self.kind {
+            ViolationKind::Missing => write!(f, "missing header {header}, added"),
+            ViolationKind::Forbidden => write!(f, "forbidden header {header}, removed"),
+            ViolationKind::Invalid => {
+                write!(f, "invalid header {header}: {:?}, removed", self.value)
+            }
+        }
+    }
+}
+
+/// The violations fixed by [`HeaderPolicy::apply()`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    violations: Vec<Violation>,
+}
+
+impl Report {
+    /// `true` if the headers already followed the policy
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Log each violation as a `VCL_Log` record
+    pub fn log(&self, ctx: &mut Ctx) {
+        for violation in &self.violations {
+            ctx.log(LogTag::VclLog, format!("header policy: {violation}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(policy: &HeaderPolicy, headers: &[(&str, &str)]) -> Vec<(String, ViolationKind)> {
+        policy
+            .check(headers.iter().copied())
+            .into_iter()
+            .map(|v| (v.header, v.kind))
+            .collect()
+    }
+
+    #[test]
+    fn header_policy() {
+        let policy: HeaderPolicy = r#"
+            forbidden = ["Server", "x-powered-by"]
+            [required]
+            Strict-Transport-Security = "max-age=600"
+            X-Frame-Options = "DENY"
+            [patterns]
+            x-frame-options = "^(DENY|SAMEORIGIN)$"
+            Cache-Control = "^public"
+        "#
+        .parse()
+        .unwrap();
+
+        let clean = [
+            ("strict-transport-security", "max-age=1"),
+            ("X-Frame-Options", "SAMEORIGIN"),
+            ("Cache-Control", "public, max-age=60"),
+        ];
+        assert!(policy.check(clean).is_empty());
+
+        let headers = [
+            ("X-Powered-By", "php"),
+            ("X-Frame-Options", "ALLOW-FROM x"),
+            ("Cache-Control", "private"),
+            ("X-Powered-By", "php"),
+            ("server", "nginx"),
+        ];
+        assert_eq!(
+            kinds(&policy, &headers),
+            [
+                ("X-Powered-By".into(), ViolationKind::Forbidden),
+                ("X-Frame-Options".into(), ViolationKind::Invalid),
+                ("Cache-Control".into(), ViolationKind::Invalid),
+                ("server".into(), ViolationKind::Forbidden),
+                ("Strict-Transport-Security".into(), ViolationKind::Missing),
+            ]
+        );
+        assert_eq!(policy.default_value("x-frame-options"), Some("DENY"));
+        assert_eq!(
+            policy.check([("Server", "x")])[0].to_string(),
+            "forbidden header Server, removed"
+        );
+
+        let build = |b: HeaderPolicyBuilder| b.build().map_err(|e| e.to_string());
+        assert!(build(HeaderPolicy::builder().forbid("a").require("A", "x")).is_err());
+        assert!(build(HeaderPolicy::builder().forbid("a").forbid("A")).is_err());
+        assert!(build(HeaderPolicy::builder().forbid("a b")).is_err());
+        assert!(build(HeaderPolicy::builder().pattern("a", "(")).is_err());
+        assert!(build(
+            HeaderPolicy::builder()
+                .require("a", "x")
+                .pattern("a", "^y$")
+        )
+        .is_err());
+        assert!("unknown = 1".parse::<HeaderPolicy>().is_err());
+    }
+}
@@ -0,0 +1,670 @@
+//! Inspect requests with a small set of rules, like a lightweight web application firewall
+//!
+//! A [`Ruleset`] is a list of rules, each matching requests on their method, URL, headers, and
+//! body, and taking an [`Action`] when all its conditions match: logging the request, denying it,
+//! or adding to its score, the request being denied once its score reaches a threshold. The rules
+//! are compiled once, e.g. from TOML when the VCL is warmed, and [`Ruleset::evaluate()`] checks a
+//! request against all of them:
+//!
+//! ``` rust
+//! use varnish::inspect::Ruleset;
+//! use varnish::vcl::{Ctx, VclError};
+//!
+//! let rules: Ruleset = r#"
+//!     threshold = 10
+//!
+//!     [[rule]]
+//!     id = "sqli"
+//!     url = '(?i)union(\s|%20|\+)+select'
+//!     action = "deny"
+//!
+//!     [[rule]]
+//!     id = "scanner"
+//!     headers = { User-Agent = "(?i)sqlmap|nikto" }
+//!     action = "score"
+//!     score = 5
+//!
+//!     [[rule]]
+//!     id = "upload-script"
+//!     methods = ["POST", "PUT"]
+//!     body = "(?i)<script"
+//!     action = "log"
+//! "#
+//! .parse()?;
+//!
+//! // e.g. in a function called from `vcl_recv`, returning `true` to send a 403
+//! fn inspect(ctx: &mut Ctx, rules: &Ruleset) -> bool {
+//!     let decision = rules.evaluate(ctx);
+//!     decision.log(ctx);
+//!     decision.is_denied()
+//! }
+//! # Ok::<(), VclError>(())
+//! ```
+//!
+//! The cost of an evaluation is bounded: the regexes run in linear time, the evaluation stops at
+//! the first denial, and only the first [`Ruleset::max_body()`] bytes of the body are inspected,
+//! if it was cached with `std.cache_req_body()`.
+//!
+//! The number of requests, of denials, and of matches of each rule are counted, and can be
+//! published to `varnishstat` with [`Ruleset::export_counters()`].
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use regex::{bytes, Regex};
+use serde::Deserialize;
+use varnish_sys::vcl::{Ctx, LogTag, VclError};
+#[cfg(not(varnishsys_6))]
+use varnish_sys::vcl::{VscField, VscSegment};
+
+/// What a rule does when it matches a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Only report the match
+    Log,
+    /// Deny the request
+    Deny,
+    /// Add to the score of the request
+    Score(u32),
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Log => f.write_str("log"),
+            Self::Deny => f.write_str("deny"),
+            Self::Score(n) => write!(f, "score {n}"),
+        }
+    }
+}
+
+/// A rule of a [`Ruleset`], matching a request if all its conditions do
+///
+/// The patterns are regexes, not anchored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    id: String,
+    action: Action,
+    methods: Vec<String>,
+    url: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+}
+
+impl Rule {
+    /// A rule without any condition yet. The `id` names its counter, so it may only contain
+    /// ASCII letters, digits, `_` and `-`.
+    pub fn new(id: impl Into<String>, action: Action) -> Self {
+        Self {
+            id: id.into(),
+            action,
+            methods: Vec::new(),
+            url: None,
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    /// Match requests using one of these methods
+    #[must_use]
+    pub fn methods<S: Into<String>>(mut self, methods: impl IntoIterator<Item = S>) -> Self {
+        self.methods.extend(methods.into_iter().map(Into::into));
+        self
+    }
+
+    /// Match requests whose URL matches `pattern`
+    #[must_use]
+    pub fn url(mut self, pattern: impl Into<String>) -> Self {
+        self.url = Some(pattern.into());
+        self
+    }
+
+    /// Match requests with a `name` header matching `pattern`
+    #[must_use]
+    pub fn header(mut self, name: impl Into<String>, pattern: impl Into<String>) -> Self {
+        self.headers.push((name.into(), pattern.into()));
+        self
+    }
+
+    /// Match requests whose cached body matches `pattern`, as bytes
+    #[must_use]
+    pub fn body(mut self, pattern: impl Into<String>) -> Self {
+        self.body = Some(pattern.into());
+        self
+    }
+
+    fn compile(self) -> Result<CompiledRule, VclError> {
+        let id = self.id;
+        if id.is_empty()
+            || !id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+        {
+            return Err(VclError::String(format!("Invalid rule id: {id:?}")));
+        }
+        if self.methods.is_empty()
+            && self.url.is_none()
+            && self.headers.is_empty()
+            && self.body.is_none()
+        {
+            return Err(VclError::String(format!("Rule {id} has no condition")));
+        }
+        let invalid =
+            |e: regex::Error| VclError::String(format!("Invalid pattern in rule {id}: {e}"));
+        let url = self
+            .url
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(invalid)?;
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, pattern)| Ok((name.clone(), Regex::new(pattern).map_err(invalid)?)))
+            .collect::<Result<_, VclError>>()?;
+        let body = self
+            .body
+            .as_deref()
+            .map(bytes::Regex::new)
+            .transpose()
+            .map_err(invalid)?;
+        Ok(CompiledRule {
+            action: self.action,
+            methods: self.methods,
+            url,
+            headers,
+            body,
+            id,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    id: String,
+    action: Action,
+    methods: Vec<String>,
+    url: Option<Regex>,
+    headers: Vec<(String, Regex)>,
+    body: Option<bytes::Regex>,
+}
+
+impl CompiledRule {
+    fn matches(&self, req: &Inspected) -> bool {
+        (self.methods.is_empty() || self.methods.iter().any(|m| m == req.method))
+            && self.url.as_ref().is_none_or(|re| re.is_match(req.url))
+            && self.headers.iter().all(|(name, re)| {
+                req.headers
+                    .iter()
+                    .any(|(n, v)| n.eq_ignore_ascii_case(name) && re.is_match(v))
+            })
+            && self.body.as_ref().is_none_or(|re| re.is_match(req.body))
+    }
+}
+
+/// Build a [`Ruleset`] from its rules
+#[derive(Debug, Clone)]
+pub struct RulesetBuilder {
+    rules: Vec<Rule>,
+    threshold: u32,
+    max_body: usize,
+}
+
+impl Default for RulesetBuilder {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            threshold: 0,
+            max_body: 8 * 1024,
+        }
+    }
+}
+
+impl RulesetBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule, evaluated after the ones already added
+    #[must_use]
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Deny the requests whose score reaches `threshold`, never if 0, the default
+    #[must_use]
+    pub fn threshold(mut self, threshold: u32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Only inspect the first `max_body` bytes of the body, 8 KiB by default
+    #[must_use]
+    pub fn max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// Compile the rules. Fails on invalid ids or patterns, on ids used twice, and on rules
+    /// without any condition.
+    pub fn build(self) -> Result<Ruleset, VclError> {
+        let mut rules: Vec<CompiledRule> = Vec::with_capacity(self.rules.len());
+        for rule in self.rules {
+            let rule = rule.compile()?;
+            if rules.iter().any(|r| r.id == rule.id) {
+                return Err(VclError::String(format!(
+                    "Rule {} is declared more than once",
+                    rule.id
+                )));
+            }
+            rules.push(rule);
+        }
+        let counters: Vec<AtomicU64> = (0..rules.len() + 2).map(|_| AtomicU64::new(0)).collect();
+        Ok(Ruleset {
+            rules,
+            threshold: self.threshold,
+            max_body: self.max_body,
+            counters: Box::new(counters),
+        })
+    }
+}
+
+/// The TOML form of a rule
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    id: String,
+    action: String,
+    score: Option<u32>,
+    #[serde(default)]
+    methods: Vec<String>,
+    url: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: Option<String>,
+}
+
+/// The TOML form of a ruleset
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesetFile {
+    threshold: Option<u32>,
+    max_body: Option<usize>,
+    #[serde(default)]
+    rule: Vec<RuleFile>,
+}
+
+impl TryFrom<RuleFile> for Rule {
+    type Error = VclError;
+
+    fn try_from(file: RuleFile) -> Result<Self, Self::Error> {
+        let action = match (file.action.as_str(), file.score) {
+            ("log", None) => Action::Log,
+            ("deny", None) => Action::Deny,
+            ("score", Some(n)) => Action::Score(n),
+            ("score", None) => {
+                return Err(VclError::String(format!("Rule {} has no score", file.id)))
+            }
+            (action, _) => {
+                return Err(VclError::String(format!(
+                    "Invalid action for rule {}: {action:?}",
+                    file.id
+                )))
+            }
+        };
+        let mut rule = Rule::new(file.id, action).methods(file.methods);
+        rule.url = file.url;
+        rule.headers.extend(file.headers);
+        rule.body = file.body;
+        Ok(rule)
+    }
+}
+
+/// The counters of a [`Ruleset`]: the requests, the denials, then the matches of each rule
+///
+/// A trait object rather than an enum, so that the drop code of [`VscSegment`], which needs
+/// `varnishd`, is only linked in when the counters are exported.
+trait Counters: Debug + Send + Sync {
+    fn values(&self) -> &[AtomicU64];
+
+    fn inc(&self, idx: usize) {
+        self.values()[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self, idx: usize) -> u64 {
+        self.values()[idx].load(Ordering::Relaxed)
+    }
+}
+
+impl Counters for Vec<AtomicU64> {
+    fn values(&self) -> &[AtomicU64] {
+        self
+    }
+}
+
+#[cfg(not(varnishsys_6))]
+impl Counters for VscSegment {
+    fn values(&self) -> &[AtomicU64] {
+        VscSegment::values(self)
+    }
+}
+
+/// The parts of a request checked by the rules
+struct Inspected<'r> {
+    method: &'r str,
+    url: &'r str,
+    headers: Vec<(&'r str, &'r str)>,
+    body: &'r [u8],
+}
+
+/// Compiled rules checked against requests, see the [module documentation](self)
+///
+/// It can be parsed from TOML, with an optional `threshold` and `max_body`, and a `[[rule]]`
+/// array of tables, each with an `id`, an `action` (`"log"`, `"deny"`, or `"score"` with a
+/// `score`), and at least one condition: a `methods` list, a `url` pattern, a `headers` table of
+/// header names and patterns, or a `body` pattern.
+#[derive(Debug)]
+pub struct Ruleset {
+    rules: Vec<CompiledRule>,
+    threshold: u32,
+    max_body: usize,
+    counters: Box<dyn Counters>,
+}
+
+impl FromStr for Ruleset {
+    type Err = VclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file: RulesetFile =
+            toml::from_str(s).map_err(|e| VclError::String(format!("Invalid ruleset: {e}")))?;
+        let mut builder = RulesetBuilder::new();
+        if let Some(threshold) = file.threshold {
+            builder = builder.threshold(threshold);
+        }
+        if let Some(max_body) = file.max_body {
+            builder = builder.max_body(max_body);
+        }
+        for rule in file.rule {
+            builder = builder.rule(rule.try_into()?);
+        }
+        builder.build()
+    }
+}
+
+impl Ruleset {
+    pub fn builder() -> RulesetBuilder {
+        RulesetBuilder::new()
+    }
+
+    /// The number of body bytes inspected
+    pub fn max_body(&self) -> usize {
+        self.max_body
+    }
+
+    /// Publish the counters as `vmod.<vmod>.<instance>.requests`, `.denied`, and
+    /// `.rule_<id>` for each rule, e.g. from the constructor of a vmod object. The counts so far
+    /// are carried over.
+    #[cfg(not(varnishsys_6))]
+    pub fn export_counters(&mut self, vmod: &str, instance: &str) -> Result<(), VclError> {
+        let mut fields = vec![
+            VscField::counter("requests", "Requests inspected"),
+            VscField::counter("denied", "Requests denied"),
+        ];
+        fields.extend(
+            self.rules
+                .iter()
+                .map(|r| VscField::counter(&format!("rule_{}", r.id), "Matches of the rule")),
+        );
+        let segment = VscSegment::new(vmod, instance, "Request inspection", fields)?;
+        for (new, old) in segment.values().iter().zip(self.counters.values()) {
+            new.store(old.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        self.counters = Box::new(segment);
+        Ok(())
+    }
+
+    /// The number of requests evaluated
+    pub fn requests(&self) -> u64 {
+        self.counters.get(0)
+    }
+
+    /// The number of requests denied
+    pub fn denied(&self) -> u64 {
+        self.counters.get(1)
+    }
+
+    /// The number of requests matched by the rule `id`
+    pub fn hits(&self, id: &str) -> Option<u64> {
+        let idx = self.rules.iter().position(|r| r.id == id)?;
+        Some(self.counters.get(idx + 2))
+    }
+
+    /// Check the current request against the rules, see the [module documentation](self)
+    ///
+    /// Outside of the client side, no rule matches.
+    pub fn evaluate(&self, ctx: &mut Ctx) -> Decision {
+        let body = if self.rules.iter().any(|r| r.body.is_some()) {
+            self.body(ctx)
+        } else {
+            Vec::new()
+        };
+        let Some(req) = ctx.req() else {
+            return Decision::default();
+        };
+        self.evaluate_request(&Inspected {
+            method: req.method().unwrap_or_default(),
+            url: req.url().unwrap_or_default(),
+            headers: req.iter().collect(),
+            body: &body,
+        })
+    }
+
+    /// The beginning of the cached body, empty if it wasn't cached
+    #[cfg(not(varnishsys_6))]
+    fn body(&self, ctx: &mut Ctx) -> Vec<u8> {
+        let mut body = Vec::new();
+        for chunk in ctx.cached_req_body().unwrap_or_default() {
+            let len = chunk.len().min(self.max_body - body.len());
+            body.extend_from_slice(&chunk[..len]);
+            if body.len() == self.max_body {
+                break;
+            }
+        }
+        body
+    }
+
+    #[cfg(varnishsys_6)]
+    fn body(&self, _ctx: &mut Ctx) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn evaluate_request(&self, req: &Inspected) -> Decision {
+        self.counters.inc(0);
+        let mut decision = Decision::default();
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(req) {
+                continue;
+            }
+            self.counters.inc(idx + 2);
+            decision.hits.push(Hit {
+                rule: rule.id.clone(),
+                action: rule.action,
+            });
+            match rule.action {
+                Action::Log => {}
+                Action::Deny => decision.denied = true,
+                Action::Score(n) => {
+                    decision.score = decision.score.saturating_add(n);
+                    decision.denied = self.threshold > 0 && decision.score >= self.threshold;
+                }
+            }
+            if decision.denied {
+                self.counters.inc(1);
+                break;
+            }
+        }
+        decision
+    }
+}
+
+/// A rule that matched a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    /// The id of the rule
+    pub rule: String,
+    pub action: Action,
+}
+
+/// The result of [`Ruleset::evaluate()`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Decision {
+    denied: bool,
+    score: u32,
+    hits: Vec<Hit>,
+}
+
+impl Decision {
+    /// The request should be denied, by a rule or by its score
+    pub fn is_denied(&self) -> bool {
+        self.denied
+    }
+
+    /// The sum of the scores of the matching rules
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+
+    /// The rules that matched, in order, the last one denying the request if it was denied
+    pub fn hits(&self) -> &[Hit] {
+        &self.hits
+    }
+
+    /// Log each match as a `VCL_Log` record, e.g. `inspect: rule sqli matched, deny`, and the
+    /// outcome if the request is denied
+    pub fn log(&self, ctx: &mut Ctx) {
+        for hit in &self.hits {
+            ctx.log(
+                LogTag::VclLog,
+                format!("inspect: rule {} matched, {}", hit.rule, hit.action),
+            );
+        }
+        if self.denied {
+            ctx.log(
+                LogTag::VclLog,
+                format!("inspect: denied, score {}", self.score),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request<'r>(method: &'r str, url: &'r str, headers: &[(&'r str, &'r str)]) -> Inspected<'r> {
+        Inspected {
+            method,
+            url,
+            headers: headers.to_vec(),
+            body: b"",
+        }
+    }
+
+    fn hits(decision: &Decision) -> Vec<&str> {
+        decision.hits().iter().map(|h| h.rule.as_str()).collect()
+    }
+
+    #[test]
+    fn ruleset() {
+        let rules: Ruleset = r#"
+            threshold = 10
+            max_body = 16
+
+            [[rule]]
+            id = "admin"
+            url = "^/admin"
+            action = "log"
+
+            [[rule]]
+            id = "sqli"
+            url = '(?i)union(\s|%20|\+)+select'
+            action = "deny"
+
+            [[rule]]
+            id = "scanner"
+            headers = { user-agent = "(?i)sqlmap" }
+            action = "score"
+            score = 5
+
+            [[rule]]
+            id = "no-referer"
+            methods = ["POST"]
+            headers = { Referer = "^$" }
+            action = "score"
+            score = 5
+
+            [[rule]]
+            id = "script"
+            body = "(?i)<script"
+            action = "deny"
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(rules.max_body(), 16);
+
+        let ok = rules.evaluate_request(&request("GET", "/", &[("User-Agent", "curl")]));
+        assert!(!ok.is_denied() && ok.hits().is_empty());
+
+        let d = rules.evaluate_request(&request("GET", "/admin?q=1 UNION  select", &[]));
+        assert!(d.is_denied());
+        assert_eq!(hits(&d), ["admin", "sqli"]);
+
+        let d = rules.evaluate_request(&request("GET", "/", &[("USER-AGENT", "SQLMap/1.0")]));
+        assert!(!d.is_denied());
+        assert_eq!((d.score(), hits(&d)), (5, vec!["scanner"]));
+
+        let headers = [("User-Agent", "sqlmap"), ("Referer", "")];
+        let d = rules.evaluate_request(&request("POST", "/", &headers));
+        assert!(d.is_denied());
+        assert_eq!(d.score(), 10);
+        assert!(!rules
+            .evaluate_request(&request("GET", "/", &headers))
+            .is_denied());
+
+        let mut req = request("POST", "/upload", &[]);
+        req.body = b"<p><SCRIPT>";
+        assert_eq!(hits(&rules.evaluate_request(&req)), ["script"]);
+
+        assert_eq!(rules.requests(), 6);
+        assert_eq!(rules.denied(), 3);
+        assert_eq!(rules.hits("scanner"), Some(3));
+        assert_eq!(rules.hits("unknown"), None);
+    }
+
+    #[test]
+    fn ruleset_errors() {
+        let build = |b: RulesetBuilder| b.build().map_err(|e| e.to_string());
+        assert!(build(Ruleset::builder().rule(Rule::new("a", Action::Log))).is_err());
+        assert!(build(Ruleset::builder().rule(Rule::new("a.b", Action::Log).url("x"))).is_err());
+        assert!(build(Ruleset::builder().rule(Rule::new("a", Action::Log).url("("))).is_err());
+        assert!(build(
+            Ruleset::builder()
+                .rule(Rule::new("a", Action::Log).url("x"))
+                .rule(Rule::new("a", Action::Deny).body("y"))
+        )
+        .is_err());
+        assert!(build(Ruleset::builder().rule(Rule::new("a", Action::Score(1)).url("x"))).is_ok());
+
+        for toml in [
+            "unknown = 1",
+            "[[rule]]\nid = \"a\"\nurl = \"x\"\naction = \"score\"",
+            "[[rule]]\nid = \"a\"\nurl = \"x\"\naction = \"block\"",
+            "[[rule]]\nid = \"a\"\nurl = \"x\"\naction = \"log\"\nscore = 1",
+        ] {
+            assert!(toml.parse::<Ruleset>().is_err(), "{toml}");
+        }
+    }
+}
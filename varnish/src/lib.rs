@@ -89,7 +89,8 @@ pub mod ffi {
     pub use varnish_sys::ffi::vmod_priv_free_f;
     pub use varnish_sys::ffi::{
         vmod_data, vmod_priv, vrt_ctx, VMOD_ABI_Version, VclEvent, VCL_BACKEND, VCL_BOOL,
-        VCL_DURATION, VCL_INT, VCL_IP, VCL_PROBE, VCL_REAL, VCL_STRING, VCL_VOID,
+        VCL_DURATION, VCL_ENUM, VCL_INT, VCL_IP, VCL_PROBE, VCL_REAL, VCL_STRANDS, VCL_STRING,
+        VCL_VOID,
     };
     #[cfg(not(varnishsys_6_priv_free_f))]
     pub use varnish_sys::ffi::{vmod_priv_methods, VMOD_PRIV_METHODS_MAGIC};
@@ -98,12 +99,61 @@ pub mod ffi {
 #[cfg(feature = "ffi")]
 pub use varnish_sys::ffi;
 
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(feature = "checksum")]
+pub mod checksum;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(any(feature = "brotli", feature = "zstd"))]
+pub mod compress;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "devicedetect")]
+pub mod devicedetect;
+#[cfg(feature = "dns")]
+pub mod dns;
+#[cfg(feature = "dns")]
+pub mod dynamic;
+#[cfg(feature = "forms")]
+pub mod forms;
+#[cfg(all(any(feature = "bench", feature = "vmods"), not(varnishsys_6)))]
+mod func_table;
+#[cfg(feature = "header-policy")]
+pub mod headers;
+#[cfg(feature = "inspect")]
+pub mod inspect;
+
+pub mod directors;
+pub mod lifecycle;
+#[cfg(feature = "packaging")]
+pub mod packaging;
+pub mod pool;
+#[cfg(not(varnishsys_6))]
+pub mod problem;
+pub mod resilience;
+#[cfg(feature = "rewrite")]
+pub mod rewrite;
+#[cfg(any(feature = "client", feature = "dns-hickory"))]
+mod runtime;
+pub mod sampling;
+pub mod session;
+#[cfg(not(varnishsys_6))]
+pub mod tags;
+pub mod timer;
 pub mod varnishtest;
+#[cfg(all(feature = "vmods", not(varnishsys_6)))]
+pub mod vmods;
 
 #[cfg(feature = "vsc")]
 pub mod vsc;
 
-pub use varnish_macros::vmod;
+#[cfg(feature = "vsl")]
+pub mod vsl;
+
+#[doc(hidden)]
+pub use varnish_macros::__vmod_parts;
+pub use varnish_macros::{vmod, vmod_part, Stats, VclEnum, VclRecord};
 
 /// Run all VTC tests using `varnishtest` utility.
 ///
@@ -123,6 +173,10 @@ pub use varnish_macros::vmod;
 ///
 /// Tests will automatically time out after 5s. To override, set `VARNISHTEST_DURATION` env var.
 ///
+/// To get the results as JSON or as `JUnit` XML, e.g. for a CI system, set the `VARNISHTEST_JSON` or
+/// `VARNISHTEST_JUNIT` env var to the path of the file to write, see
+/// [`VtcReport`](varnishtest::VtcReport).
+///
 /// To debug the tests, pass `true` as the second argument:
 /// ```rust
 /// varnish::run_vtc_tests!("tests/*.vtc", true);
@@ -148,3 +202,41 @@ macro_rules! run_vtc_tests {
         }
     };
 }
+
+/// Run a single VTC test with `varnishtest -v`, and return its
+/// [`VtcOutput`](varnishtest::VtcOutput) to check the VSL records it produced with
+/// [`expect_vsl!`]. Like [`run_vtc_tests!`], the vmod must be built first.
+///
+/// ```rust,no_run
+/// #[test]
+/// fn logs_hello() {
+///     let output = varnish::vtc_output!("tests/test01.vtc");
+///     varnish::expect_vsl!(output, "VCL_Log", "hello", 2);
+/// }
+/// ```
+#[macro_export]
+macro_rules! vtc_output {
+    ( $path:expr ) => {
+        match $crate::varnishtest::run_varnish_test_output(
+            env!("LD_LIBRARY_PATH"),
+            env!("CARGO_PKG_NAME"),
+            $path,
+            option_env!("VARNISHTEST_DURATION").unwrap_or("5s"),
+        ) {
+            Ok(output) => output,
+            Err(err) => panic!("{err}"),
+        }
+    };
+}
+
+/// Assert that a [`VtcOutput`](varnishtest::VtcOutput) has exactly `count` VSL records with
+/// `tag` whose value contains `value`, e.g. the `VCL_Log` or `Debug` lines logged by the vmod.
+/// See [`vtc_output!`].
+#[macro_export]
+macro_rules! expect_vsl {
+    ( $output:expr, $tag:expr, $value:expr, $count:expr ) => {
+        if let Err(err) = $output.expect_vsl($tag, $value, $count) {
+            panic!("{err}");
+        }
+    };
+}
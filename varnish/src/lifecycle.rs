@@ -0,0 +1,192 @@
+//! Broadcast the VCL events to the objects that need them
+//!
+//! Only the `#[event]` function of a vmod is told when its VCL is loaded, warmed, cooled or
+//! discarded. A [`Lifecycle`] stored in the `#[shared_per_vcl]` state forwards these events to
+//! any number of subscribers, e.g. the connection pools or caches deep inside the vmod objects,
+//! without plumbing each of them back to the event function.
+//!
+//! ``` rust
+//! use std::sync::atomic::{AtomicBool, Ordering};
+//! use std::sync::Arc;
+//! use varnish::lifecycle::{Lifecycle, Listener};
+//! use varnish::vcl::Event;
+//!
+//! #[derive(Default)]
+//! struct PerVcl {
+//!     lifecycle: Lifecycle,
+//! }
+//!
+//! #[derive(Default)]
+//! struct Pool {
+//!     active: AtomicBool,
+//! }
+//!
+//! impl Listener for Pool {
+//!     fn on_event(&self, event: Event) {
+//!         match event {
+//!             Event::Warm => self.active.store(true, Ordering::Relaxed),
+//!             Event::Cold => self.active.store(false, Ordering::Relaxed),
+//!             _ => {}
+//!         }
+//!     }
+//! }
+//!
+//! // e.g. the body of `#[event] pub fn event(event: Event, #[shared_per_vcl] ...)`
+//! fn event(event: Event, vcl: &mut Option<Box<PerVcl>>) {
+//!     vcl.get_or_insert_with(Box::default).lifecycle.notify(event);
+//! }
+//!
+//! // e.g. in an object constructor, also getting the `#[shared_per_vcl]` state
+//! fn new_pool(vcl: &mut Option<Box<PerVcl>>) -> Arc<Pool> {
+//!     let pool = Arc::new(Pool::default());
+//!     vcl.get_or_insert_with(Box::default).lifecycle.subscribe(&pool);
+//!     pool
+//! }
+//! # let mut vcl = None;
+//! # let pool = new_pool(&mut vcl);
+//! # event(Event::Warm, &mut vcl);
+//! # assert!(pool.active.load(Ordering::Relaxed));
+//! ```
+
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+
+use varnish_sys::vcl::Event;
+
+/// A subscriber of a [`Lifecycle`]
+///
+/// It is implemented for closures taking an [`Event`].
+pub trait Listener: Send + Sync + 'static {
+    fn on_event(&self, event: Event);
+}
+
+impl<F: Fn(Event) + Send + Sync + 'static> Listener for F {
+    fn on_event(&self, event: Event) {
+        self(event);
+    }
+}
+
+/// Keeps a closure subscribed to a [`Lifecycle`], until it is dropped
+pub struct Subscription(#[expect(dead_code)] Arc<dyn Listener>);
+
+impl Debug for Subscription {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscription").finish_non_exhaustive()
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    listeners: Vec<Weak<dyn Listener>>,
+    last: Option<Event>,
+}
+
+/// Forwards the VCL events to its subscribers, see the [module documentation](self)
+///
+/// Subscribers are held weakly: they stop receiving events once dropped, and do not need to
+/// unsubscribe. Events are delivered without holding any lock, so a listener may subscribe other
+/// listeners, or drop itself, while it is notified.
+#[derive(Default)]
+pub struct Lifecycle {
+    inner: Mutex<Inner>,
+}
+
+impl Debug for Lifecycle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let inner = self.lock();
+        f.debug_struct("Lifecycle")
+            .field("listeners", &inner.listeners.len())
+            .field("last", &inner.last)
+            .finish()
+    }
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Forward `event` to the subscribers, in the order they subscribed
+    pub fn notify(&self, event: Event) {
+        let listeners: Vec<_> = {
+            let mut inner = self.lock();
+            inner.last = Some(event);
+            inner.listeners.retain(|l| l.strong_count() > 0);
+            inner.listeners.iter().filter_map(Weak::upgrade).collect()
+        };
+        for listener in listeners {
+            listener.on_event(event);
+        }
+    }
+
+    /// Subscribe `listener` for as long as it is alive.
+    ///
+    /// If the VCL is already warm, the listener gets [`Event::Warm`] right away.
+    pub fn subscribe<L: Listener>(&self, listener: &Arc<L>) {
+        let weak: Weak<L> = Arc::downgrade(listener);
+        let warm = {
+            let mut inner = self.lock();
+            inner.listeners.push(weak);
+            inner.last == Some(Event::Warm)
+        };
+        if warm {
+            listener.on_event(Event::Warm);
+        }
+    }
+
+    /// Subscribe a closure until the returned [`Subscription`] is dropped
+    pub fn subscribe_fn(&self, listener: impl Fn(Event) + Send + Sync + 'static) -> Subscription {
+        let listener = Arc::new(listener);
+        self.subscribe(&listener);
+        Subscription(listener)
+    }
+
+    /// The last event forwarded by [`Lifecycle::notify()`], if any
+    pub fn last_event(&self) -> Option<Event> {
+        self.lock().last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn lifecycle() {
+        let lifecycle = Arc::new(Lifecycle::new());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let events = Arc::clone(&seen);
+        let sub = lifecycle.subscribe_fn(move |e| events.lock().unwrap().push(e));
+        lifecycle.notify(Event::Load);
+
+        // a listener subscribing another one while notified
+        let count = Arc::new(AtomicUsize::new(0));
+        let (nested, counter) = (Arc::clone(&lifecycle), Arc::clone(&count));
+        let _outer = lifecycle.subscribe_fn(move |e| {
+            if e == Event::Warm {
+                let counter = Arc::clone(&counter);
+                let late = Arc::new(move |_: Event| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                });
+                nested.subscribe(&late);
+            }
+        });
+        lifecycle.notify(Event::Warm);
+        assert_eq!(lifecycle.last_event(), Some(Event::Warm));
+        // the late listener got the current state, and was dropped right after
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        drop(sub);
+        lifecycle.notify(Event::Cold);
+        assert_eq!(*seen.lock().unwrap(), [Event::Load, Event::Warm]);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert_eq!(lifecycle.lock().listeners.len(), 1);
+    }
+}
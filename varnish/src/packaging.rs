@@ -0,0 +1,363 @@
+//! Check and package a built vmod library
+//!
+//! Cargo builds a vmod as `target/release/lib<crate>.so`, but Varnish only imports
+//! `libvmod_<name>.so`, built against its exact version, and loaded on a host whose glibc is at
+//! least as recent as the one of the build host. [`BuiltVmod`] reads the library produced by cargo
+//! to check all of that before it is shipped, and [`BuiltVmod::package()`] copies it under its
+//! proper name, along with a metadata file. It is meant to be called once the library is built,
+//! e.g. from an `xtask` crate, or from the build script of a crate depending on the vmod.
+//!
+//! ``` rust,no_run
+//! use varnish::packaging::BuiltVmod;
+//!
+//! fn main() -> Result<(), String> {
+//!     let vmod = BuiltVmod::read("target/release/libvmod_example.so")?;
+//!     // e.g. Debian 11 ships glibc 2.31
+//!     vmod.require_max_glibc(2, 31)?;
+//!     vmod.require_abi(&varnish::packaging::build_abi())?;
+//!     // writes dist/vmod_example-0.1.0/libvmod_example.so and vmod_example.json
+//!     let dir = vmod.package("0.1.0", "dist")?;
+//!     println!("packaged in {}", dir.display());
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Only 64-bit little-endian ELF libraries, e.g. for `x86_64` or `aarch64` Linux, can be read.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+const SHT_DYNAMIC: u32 = 6;
+const SHT_DYNSYM: u32 = 11;
+const SHT_GNU_VERNEED: u32 = 0x6fff_fffe;
+const DT_NEEDED: usize = 1;
+
+/// A vmod library built by cargo, see the [module documentation](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuiltVmod {
+    pub path: PathBuf,
+    /// The name of the vmod, from its `Vmod_<name>_Data` symbol
+    pub name: String,
+    /// The `VMOD_ABI_Version` it was built against, e.g. `Varnish 7.6.1 c3d5882...`, `None` for
+    /// Varnish 6
+    pub abi: Option<String>,
+    /// The shared libraries it links to, e.g. `libc.so.6`
+    pub needed: Vec<String>,
+    /// The most recent glibc version it requires, e.g. `(2, 34)`
+    pub glibc: Option<(u32, u32)>,
+}
+
+/// The `VMOD_ABI_Version` of the Varnish headers used to build this crate, i.e. the one the vmods
+/// built along with it are bound to
+pub fn build_abi() -> String {
+    varnish_sys::ffi::VMOD_ABI_Version
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl BuiltVmod {
+    /// Read the library at `path`, failing if it is not a vmod
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|e| format!("Unable to read {}: {e}", path.display()))?;
+        let elf = Elf::parse(&data).map_err(|e| format!("{}: {e}", path.display()))?;
+        let names: Vec<_> = elf
+            .dynamic_symbols()?
+            .into_iter()
+            .filter_map(|s| Some(s.strip_prefix("Vmod_")?.strip_suffix("_Data")?.to_string()))
+            .collect();
+        let name = match names.as_slice() {
+            [name] => name.clone(),
+            [] => {
+                return Err(format!(
+                    "{} does not export a Vmod_<name>_Data symbol, is it a `cdylib` crate with a #[vmod] module?",
+                    path.display()
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "{} exports several Vmod_<name>_Data symbols: {}",
+                    path.display(),
+                    names.join(", ")
+                ))
+            }
+        };
+        let abi =
+            vmod_json_header(&data).and_then(|header| Some(header.get(5)?.as_str()?.to_string()));
+        Ok(Self {
+            path: path.to_path_buf(),
+            name,
+            abi,
+            needed: elf.needed()?,
+            glibc: elf.glibc_version()?,
+        })
+    }
+
+    /// The name Varnish looks for when importing the vmod, i.e. `libvmod_<name>.so`
+    pub fn file_name(&self) -> String {
+        format!("libvmod_{}.so", self.name)
+    }
+
+    /// Fail if the library requires a glibc more recent than `major.minor`, e.g. the one of the
+    /// oldest distribution it must run on
+    pub fn require_max_glibc(&self, major: u32, minor: u32) -> Result<(), String> {
+        match self.glibc {
+            Some((maj, min)) if (maj, min) > (major, minor) => Err(format!(
+                "{} requires glibc {maj}.{min}, more recent than {major}.{minor}: build it on an \
+                older distribution, e.g. in a container",
+                self.path.display()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail unless the library was built against `abi`, e.g. [`build_abi()`] or the version of
+    /// the Varnish it will be installed with
+    pub fn require_abi(&self, abi: &str) -> Result<(), String> {
+        match &self.abi {
+            Some(built) if built != abi => Err(format!(
+                "{} was built against {built}, not {abi}: build it with the development files of \
+                the right Varnish version",
+                self.path.display()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The metadata written next to the packaged library
+    pub fn metadata(&self, version: &str) -> Value {
+        json!({
+            "name": self.name,
+            "version": version,
+            "file": self.file_name(),
+            "varnish_abi": self.abi,
+            "glibc": self.glibc.map(|(major, minor)| format!("{major}.{minor}")),
+            "needed": self.needed,
+        })
+    }
+
+    /// Copy the library to `<dir>/vmod_<name>-<version>/libvmod_<name>.so`, along with its
+    /// metadata as `vmod_<name>.json`, and return the directory
+    pub fn package(&self, version: &str, dir: impl AsRef<Path>) -> Result<PathBuf, String> {
+        let dir = dir.as_ref().join(format!("vmod_{}-{version}", self.name));
+        fs::create_dir_all(&dir).map_err(|e| format!("Unable to create {}: {e}", dir.display()))?;
+        let lib = dir.join(self.file_name());
+        fs::copy(&self.path, &lib).map_err(|e| {
+            format!(
+                "Unable to copy {} to {}: {e}",
+                self.path.display(),
+                lib.display()
+            )
+        })?;
+        let meta = dir.join(format!("vmod_{}.json", self.name));
+        let json =
+            serde_json::to_string_pretty(&self.metadata(version)).map_err(|e| e.to_string())?;
+        fs::write(&meta, json + "\n")
+            .map_err(|e| format!("Unable to write {}: {e}", meta.display()))?;
+        Ok(dir)
+    }
+}
+
+/// The `$VMOD` line of the JSON description embedded by `#[vmod]`, i.e.
+/// `["$VMOD", "1.0", name, func struct, file id, abi, "0", "0"]`
+fn vmod_json_header(data: &[u8]) -> Option<Vec<Value>> {
+    const START: &[u8] = b"VMOD_JSON_SPEC\x02";
+    let start = data.windows(START.len()).position(|w| w == START)? + START.len();
+    let len = data[start..].iter().position(|&b| b == 0x03)?;
+    let spec: Value = serde_json::from_slice(&data[start..start + len]).ok()?;
+    spec.get(0)?.as_array().cloned()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Section {
+    typ: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    entsize: usize,
+}
+
+/// Just enough of a 64-bit little-endian ELF file to read its dynamic symbols and dependencies
+struct Elf<'a> {
+    data: &'a [u8],
+    sections: Vec<Section>,
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, String> {
+        if data.get(..4) != Some(b"\x7fELF") {
+            return Err("not an ELF file".to_string());
+        }
+        if data.get(4..6) != Some(&[2, 1]) {
+            return Err("only 64-bit little-endian ELF files are supported".to_string());
+        }
+        let mut elf = Self {
+            data,
+            sections: Vec::new(),
+        };
+        let shoff = elf.u64(0x28)?;
+        let shentsize = elf.u16(0x3a)?;
+        for i in 0..elf.u16(0x3c)? {
+            let sh = shoff + i * shentsize;
+            elf.sections.push(Section {
+                typ: elf.u32(sh + 4)?,
+                offset: elf.u64(sh + 24)?,
+                size: elf.u64(sh + 32)?,
+                link: elf.u32(sh + 40)?,
+                info: elf.u32(sh + 44)?,
+                entsize: elf.u64(sh + 56)?,
+            });
+        }
+        Ok(elf)
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], String> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| "truncated ELF file".to_string())
+    }
+
+    fn u16(&self, offset: usize) -> Result<usize, String> {
+        let b = self.bytes(offset, 2)?;
+        Ok(usize::from(u16::from_le_bytes([b[0], b[1]])))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, String> {
+        let b = self.bytes(offset, 4)?;
+        Ok(u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&self, offset: usize) -> Result<usize, String> {
+        let b = self.bytes(offset, 8)?;
+        usize::try_from(u64::from_le_bytes(b.try_into().unwrap()))
+            .map_err(|_| "invalid ELF offset".to_string())
+    }
+
+    fn sections(&self, typ: u32) -> impl Iterator<Item = &Section> {
+        self.sections.iter().filter(move |s| s.typ == typ)
+    }
+
+    /// The string at `offset` in the string table section `link`
+    fn string(&self, link: u32, offset: usize) -> Result<String, String> {
+        let table = usize::try_from(link)
+            .ok()
+            .and_then(|idx| self.sections.get(idx))
+            .ok_or_else(|| "invalid ELF string table".to_string())?;
+        let table = self.bytes(table.offset, table.size)?;
+        let s = table
+            .get(offset..)
+            .and_then(|s| s.split(|&b| b == 0).next())
+            .ok_or_else(|| "invalid ELF string".to_string())?;
+        Ok(String::from_utf8_lossy(s).into_owned())
+    }
+
+    /// The names of the symbols defined and exported by the library
+    fn dynamic_symbols(&self) -> Result<Vec<String>, String> {
+        let mut names = Vec::new();
+        for sec in self.sections(SHT_DYNSYM) {
+            let entsize = if sec.entsize == 0 { 24 } else { sec.entsize };
+            for sym in (sec.offset..sec.offset + sec.size).step_by(entsize) {
+                let shndx = self.u16(sym + 6)?;
+                let binding = self.bytes(sym + 4, 1)?[0] >> 4;
+                // defined, and global or weak
+                if shndx != 0 && (binding == 1 || binding == 2) {
+                    names.push(self.string(sec.link, self.u32(sym)? as usize)?);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// The `DT_NEEDED` entries, i.e. the libraries to load along with this one
+    fn needed(&self) -> Result<Vec<String>, String> {
+        let mut needed = Vec::new();
+        for sec in self.sections(SHT_DYNAMIC) {
+            for entry in (sec.offset..sec.offset + sec.size).step_by(16) {
+                if self.u64(entry)? == DT_NEEDED {
+                    needed.push(self.string(sec.link, self.u64(entry + 8)?)?);
+                }
+            }
+        }
+        Ok(needed)
+    }
+
+    /// The most recent `GLIBC_x.y` version needed by the symbols imported from the glibc
+    fn glibc_version(&self) -> Result<Option<(u32, u32)>, String> {
+        let mut max = None;
+        for sec in self.sections(SHT_GNU_VERNEED) {
+            let mut need = sec.offset;
+            for _ in 0..sec.info {
+                let mut aux = need + self.u32(need + 8)? as usize;
+                for _ in 0..self.u16(need + 2)? {
+                    let name = self.string(sec.link, self.u32(aux + 8)? as usize)?;
+                    if let Some((major, minor)) =
+                        name.strip_prefix("GLIBC_").and_then(|v| v.split_once('.'))
+                    {
+                        let minor = minor.split('.').next().unwrap_or_default();
+                        if let (Ok(major), Ok(minor)) = (major.parse(), minor.parse()) {
+                            max = max.max(Some((major, minor)));
+                        }
+                    }
+                    aux += self.u32(aux + 12)? as usize;
+                }
+                let next = self.u32(need + 12)? as usize;
+                if next == 0 {
+                    break;
+                }
+                need += next;
+            }
+        }
+        Ok(max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vmod_json() {
+        let data = b"..VMOD_JSON_SPEC\x02[[\"$VMOD\",\"1.0\",\"example\",\"Vmod_example_Func\",\"abc\",\"Varnish 7.6.1 c3d5882\",\"0\",\"0\"]]\x03..";
+        let header = vmod_json_header(data).unwrap();
+        assert_eq!(header[5], "Varnish 7.6.1 c3d5882");
+        assert!(vmod_json_header(b"VMOD_JSON_SPEC\x02[[").is_none());
+
+        let vmod = BuiltVmod {
+            path: PathBuf::from("target/release/libvmod_example.so"),
+            name: "example".to_string(),
+            abi: Some("Varnish 7.6.1 c3d5882".to_string()),
+            needed: vec!["libc.so.6".to_string()],
+            glibc: Some((2, 34)),
+        };
+        assert_eq!(vmod.file_name(), "libvmod_example.so");
+        assert!(vmod.require_max_glibc(2, 34).is_ok());
+        assert!(vmod.require_max_glibc(2, 31).is_err());
+        assert!(vmod.require_abi("Varnish 7.6.1 c3d5882").is_ok());
+        assert!(vmod.require_abi("Varnish 7.5.0 eef2526").is_err());
+        assert_eq!(vmod.metadata("1.0.0")["glibc"], "2.34");
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        target_pointer_width = "64",
+        target_endian = "little"
+    ))]
+    #[test]
+    fn elf_dependencies() {
+        // the test binary itself is a dynamically linked ELF file
+        let data = fs::read(std::env::current_exe().unwrap()).unwrap();
+        let elf = Elf::parse(&data).unwrap();
+        assert!(elf
+            .needed()
+            .unwrap()
+            .iter()
+            .any(|n| n.starts_with("libc.so")));
+        assert!(elf.glibc_version().unwrap().is_some_and(|v| v >= (2, 2)));
+        assert!(Elf::parse(b"\x7fELF\x01\x01").is_err());
+        assert!(BuiltVmod::read(std::env::current_exe().unwrap()).is_err());
+    }
+}
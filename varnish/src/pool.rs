@@ -0,0 +1,282 @@
+//! Reuse the allocations of objects created and dropped at a high rate
+//!
+//! Some vmods create an object for every request, e.g. a parser or a session state with a few
+//! buffers, and drop it at the end of the task. An [`ObjectPool`] keeps the dropped values, with
+//! their inner allocations, to hand them out again, so that neither the `Box` nor the buffers are
+//! allocated and freed on the hot path. Values are [recycled](Recycle) before they are reused.
+//!
+//! ``` rust
+//! use std::sync::{Arc, LazyLock};
+//! use varnish::pool::{ObjectPool, Pooled, Recycle};
+//!
+//! #[derive(Default)]
+//! struct Scratch {
+//!     headers: Vec<(String, String)>,
+//!     body: Vec<u8>,
+//! }
+//!
+//! impl Recycle for Scratch {
+//!     fn recycle(&mut self) {
+//!         // keep the capacity, drop the content
+//!         self.headers.clear();
+//!         self.body.clear();
+//!     }
+//! }
+//!
+//! static POOL: LazyLock<Arc<ObjectPool<Scratch>>> = LazyLock::new(|| ObjectPool::new(1024));
+//!
+//! // e.g. the object created by `new` in the vmod, once per request
+//! struct Session {
+//!     scratch: Pooled<Scratch>,
+//! }
+//!
+//! let session = Session { scratch: POOL.acquire() };
+//! drop(session);
+//! let session = Session { scratch: POOL.acquire() };
+//! assert!(session.scratch.body.is_empty());
+//! assert_eq!(POOL.stats().hits, 1);
+//! ```
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// A value that can be reset to be reused by another user
+pub trait Recycle: Default + Send {
+    /// Forget the state left by the previous user, ideally keeping the allocations, e.g. by
+    /// calling `clear()` on the collections
+    fn recycle(&mut self);
+}
+
+/// Counters of an [`ObjectPool`] since its creation, as returned by [`ObjectPool::stats()`]
+///
+/// A vmod can report them from one of its functions, e.g. the [`hit_rate()`](Self::hit_rate)
+/// to size the pool from a VTC test or a debug endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Values taken from the pool
+    pub hits: u64,
+    /// Values allocated because the pool was empty
+    pub misses: u64,
+    /// Values given back to the pool
+    pub returned: u64,
+    /// Values freed because the pool was full
+    pub discarded: u64,
+    /// Values currently waiting in the pool
+    pub idle: usize,
+}
+
+impl PoolStats {
+    /// The share of the acquisitions served from the pool, between 0 and 1
+    #[expect(clippy::cast_precision_loss)]
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// A pool of boxed values, see the [module documentation](self)
+pub struct ObjectPool<T: Recycle> {
+    idle: Mutex<Vec<Box<T>>>,
+    capacity: usize,
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    returned: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl<T: Recycle> ObjectPool<T> {
+    /// Create an empty pool keeping at most `capacity` idle values
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            idle: Mutex::new(Vec::with_capacity(capacity)),
+            capacity,
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            returned: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        })
+    }
+
+    /// Allocate `count` values in advance, e.g. in the `vcl_init` event, up to the capacity
+    pub fn prefill(&self, count: usize) {
+        let mut idle = self.lock();
+        let count = count.min(self.capacity.saturating_sub(idle.len()));
+        idle.extend((0..count).map(|_| Box::default()));
+    }
+
+    /// Take a value from the pool, or allocate a new one if the pool is empty. The value goes
+    /// back to the pool when the returned [`Pooled`] is dropped.
+    pub fn acquire(self: &Arc<Self>) -> Pooled<T> {
+        let value = self.lock().pop();
+        let value = if let Some(value) = value {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            value
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            Box::default()
+        };
+        Pooled {
+            value: Some(value),
+            generation: self.generation.fetch_add(1, Ordering::Relaxed),
+            pool: Arc::clone(self),
+        }
+    }
+
+    /// Free all the idle values, e.g. when the VCL goes cold
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// The counters of the pool
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            returned: self.returned.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            idle: self.lock().len(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Box<T>>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn release(&self, mut value: Box<T>) {
+        value.recycle();
+        let mut idle = self.lock();
+        if idle.len() < self.capacity {
+            idle.push(value);
+            drop(idle);
+            self.returned.fetch_add(1, Ordering::Relaxed);
+        } else {
+            // free the value outside of the lock
+            drop(idle);
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<T: Recycle> fmt::Debug for ObjectPool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObjectPool")
+            .field("capacity", &self.capacity)
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A value leased from an [`ObjectPool`], given back to it when dropped
+pub struct Pooled<T: Recycle> {
+    value: Option<Box<T>>,
+    generation: u64,
+    pool: Arc<ObjectPool<T>>,
+}
+
+impl<T: Recycle> Pooled<T> {
+    /// The generation tag of this lease, unique within the pool. A recycled value gets a new
+    /// one, which tells it apart from its previous use, e.g. when it is the key of a cache.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Keep the value for good, it will not go back to the pool
+    pub fn detach(mut self) -> T {
+        *self.value.take().expect("the value is only taken once")
+    }
+}
+
+impl<T: Recycle> Deref for Pooled<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_deref().expect("the value is only taken once")
+    }
+}
+
+impl<T: Recycle> DerefMut for Pooled<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+            .as_deref_mut()
+            .expect("the value is only taken once")
+    }
+}
+
+impl<T: Recycle> Drop for Pooled<T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            self.pool.release(value);
+        }
+    }
+}
+
+impl<T: Recycle + fmt::Debug> fmt::Debug for Pooled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pooled")
+            .field("value", &self.value)
+            .field("generation", &self.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Buf(Vec<u8>);
+
+    impl Recycle for Buf {
+        fn recycle(&mut self) {
+            self.0.clear();
+        }
+    }
+
+    #[test]
+    fn object_pool() {
+        let pool = ObjectPool::<Buf>::new(1);
+        let mut a = pool.acquire();
+        a.0.extend_from_slice(b"hello");
+        let addr = std::ptr::from_ref::<Buf>(&a);
+        let generation = a.generation();
+        drop(a);
+
+        let b = pool.acquire();
+        assert_eq!(std::ptr::from_ref::<Buf>(&b), addr);
+        assert!(b.0.is_empty() && b.0.capacity() >= 5);
+        assert_ne!(b.generation(), generation);
+
+        // The pool is full, the second value is freed
+        let c = pool.acquire();
+        drop(b);
+        drop(c);
+        let detached = pool.acquire().detach();
+        drop(detached);
+
+        let stats = pool.stats();
+        assert_eq!(
+            (
+                stats.hits,
+                stats.misses,
+                stats.returned,
+                stats.discarded,
+                stats.idle
+            ),
+            (2, 2, 2, 1, 0)
+        );
+        assert!((stats.hit_rate() - 0.5).abs() < f64::EPSILON);
+
+        pool.prefill(5);
+        assert_eq!(pool.stats().idle, 1);
+        pool.clear();
+        assert_eq!(pool.stats().idle, 0);
+    }
+}
@@ -0,0 +1,175 @@
+//! Answer errors with `application/problem+json` bodies
+//!
+//! API gateways are expected to report errors in a machine-readable form, such as the "problem
+//! details" of RFC 9457. A vmod function cannot answer the client by itself: it can only fail the
+//! task, which sends it to `vcl_synth` on the client side, or to `vcl_backend_error` on the
+//! backend side, with a generic `503` error. [`respond()`] remembers the problem for the task
+//! before failing it, and [`deliver()`], called from those subroutines, turns it into the
+//! response.
+//!
+//! ```vcl
+//! sub vcl_recv {
+//!     # calls `varnish::problem::respond()` when the token is missing
+//!     auth.check();
+//! }
+//!
+//! sub vcl_synth {
+//!     # calls `varnish::problem::deliver()`, and delivers the problem if there is one
+//!     if (auth.problem()) {
+//!         return (deliver);
+//!     }
+//! }
+//! ```
+//!
+//! ``` rust
+//! use varnish::problem;
+//! use varnish::vcl::{Ctx, VclError};
+//!
+//! // e.g. the body of `pub fn check(ctx: &mut Ctx)` in the vmod
+//! fn check(ctx: &mut Ctx) {
+//!     let token = ctx.req().and_then(|req| req.header("authorization"));
+//!     if token.is_none() {
+//!         problem::respond(
+//!             ctx,
+//!             401,
+//!             "https://example.com/problems/unauthorized",
+//!             "Unauthorized",
+//!             "The request has no Authorization header",
+//!         );
+//!     }
+//! }
+//!
+//! // e.g. the body of `pub fn problem(ctx: &mut Ctx) -> Result<bool, VclError>`
+//! fn deliver(ctx: &mut Ctx) -> Result<bool, VclError> {
+//!     problem::deliver(ctx)
+//! }
+//! ```
+
+use std::fmt::Write;
+
+use varnish_sys::ffi;
+use varnish_sys::vcl::{Buffer, Ctx, TaskLocal, VclError, VclResult};
+
+/// The problem of the current task, if any
+static PENDING: TaskLocal<Option<Problem>> = TaskLocal::new();
+
+/// The details of an error, as defined by RFC 9457
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// The HTTP status of the response
+    pub status: u16,
+    /// A URI identifying the kind of problem, `about:blank` if there is nothing more specific
+    pub type_: String,
+    /// A short summary of the kind of problem, also used as the reason of the response
+    pub title: String,
+    /// An explanation of this occurrence of the problem
+    pub detail: String,
+}
+
+impl Problem {
+    /// The JSON body of the response
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"type\":");
+        push_json_string(&mut json, &self.type_);
+        json.push_str(",\"title\":");
+        push_json_string(&mut json, &self.title);
+        let _ = write!(json, ",\"status\":{}", self.status);
+        if !self.detail.is_empty() {
+            json.push_str(",\"detail\":");
+            push_json_string(&mut json, &self.detail);
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Fail the task, and keep the problem for [`deliver()`]. The failure is logged with the title
+/// and the detail of the problem.
+pub fn respond(ctx: &mut Ctx, status: u16, type_: &str, title: &str, detail: &str) {
+    let problem = Problem {
+        status,
+        type_: type_.to_string(),
+        title: title.to_string(),
+        detail: detail.to_string(),
+    };
+    let stored = PENDING
+        .get_or_init(ctx, || None)
+        .map(|mut pending| *pending = Some(problem));
+    match stored {
+        Ok(()) => ctx.fail(format!("{status} {title}: {detail}")),
+        Err(e) => ctx.fail(e),
+    }
+}
+
+/// Write the problem passed to [`respond()`] in this task as the response, i.e. `resp` in
+/// `vcl_synth` or `beresp` in `vcl_backend_error`: its status and reason, its `Content-Type`
+/// header, and its body. Returns whether there was a problem to deliver.
+pub fn deliver(ctx: &mut Ctx) -> VclResult<bool> {
+    let Some(problem) = PENDING.take(ctx)?.flatten() else {
+        return Ok(false);
+    };
+    let vsb = ctx.raw.specific.cast::<ffi::vsb>();
+    if vsb.is_null() || unsafe { (*vsb).magic } != ffi::VSB_MAGIC {
+        return Err(VclError::CStr(
+            c"deliver() can only be used in vcl_synth or vcl_backend_error",
+        ));
+    }
+    let resp = if ctx.resp().is_some() {
+        ctx.resp_mut()
+    } else {
+        ctx.beresp_mut()
+    };
+    let Some(resp) = resp else {
+        return Err(VclError::CStr(c"deliver(): there is no response"));
+    };
+    resp.set_status(problem.status);
+    resp.set_reason(&problem.title)?;
+    resp.set_header("Content-Type", "application/problem+json")?;
+    Buffer::from_ptr(vsb)
+        .write(&problem.to_json())
+        .map_err(|()| VclError::CStr(c"deliver(): unable to write the body"))?;
+    Ok(true)
+}
+
+/// Append `value` to `json` as a JSON string
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", u32::from(c));
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn problem_json() {
+        let mut problem = Problem {
+            status: 403,
+            type_: "about:blank".to_string(),
+            title: "Forbidden".to_string(),
+            detail: "token \"abc\" expired\n\u{1}é".to_string(),
+        };
+        assert_eq!(
+            problem.to_json(),
+            r#"{"type":"about:blank","title":"Forbidden","status":403,"detail":"token \"abc\" expired\n\u0001é"}"#
+        );
+        problem.detail.clear();
+        assert_eq!(
+            problem.to_json(),
+            r#"{"type":"about:blank","title":"Forbidden","status":403}"#
+        );
+    }
+}
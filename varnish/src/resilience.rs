@@ -0,0 +1,856 @@
+//! Protect backends and remote services from being hammered while they are failing
+//!
+//! A [`CircuitBreaker`] counts the successes and failures of the calls it wraps over a rolling
+//! window. When too many of them fail, it "opens" and rejects calls right away for a while, then
+//! lets a few probe calls through ("half-open") to decide whether to close again. It fits
+//! naturally in a [`Serve`](crate::vcl::Serve) implementation:
+//!
+//! ``` rust
+//! use std::time::{Duration, SystemTime};
+//! use varnish::resilience::CircuitBreaker;
+//! use varnish::vcl::{Ctx, VclError};
+//!
+//! struct Origin {
+//!     breaker: CircuitBreaker,
+//! }
+//!
+//! impl Origin {
+//!     fn get_headers(&self, ctx: &mut Ctx) -> Result<Option<()>, VclError> {
+//!         self.breaker.call(|| self.fetch(ctx))
+//!     }
+//!
+//!     fn healthy(&self, _ctx: &mut Ctx) -> (bool, SystemTime) {
+//!         self.breaker.healthy()
+//!     }
+//!
+//!     fn fetch(&self, _ctx: &mut Ctx) -> Result<Option<()>, VclError> {
+//!         // ... talk to the origin
+//!         Ok(None)
+//!     }
+//! }
+//!
+//! let origin = Origin {
+//!     breaker: CircuitBreaker::new("origin", 0.5, Duration::from_secs(10), Duration::from_secs(5)),
+//! };
+//! ```
+//!
+//! A [`SaintMode`] applies the same idea to a set of backends, blacklisting for a while the ones
+//! that return too many errors, so that directors can pick another one.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+#[cfg(not(varnishsys_6))]
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(varnishsys_6))]
+use std::sync::Arc;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use varnish_sys::vcl::VclError;
+#[cfg(not(varnishsys_6))]
+use varnish_sys::vcl::{
+    Buffer, Ctx, Event as VclEvent, Serve, StreamClose, Transfer, VscField, VscSegment,
+};
+
+/// Number of buckets of the rolling window
+const BUCKETS: usize = 10;
+
+/// The state of a [`CircuitBreaker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls go through, and their outcome is tracked
+    Closed,
+    /// Calls are rejected without being run
+    Open,
+    /// A limited number of probe calls go through to test the waters
+    HalfOpen,
+}
+
+/// Counters of a [`CircuitBreaker`] since its creation, see also
+/// [`CircuitBreaker::export_counters()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerStats {
+    /// The current state
+    pub state: BreakerState,
+    /// Calls that succeeded
+    pub successes: u64,
+    /// Calls that failed
+    pub failures: u64,
+    /// Calls rejected because the breaker was open
+    pub rejected: u64,
+    /// How many times the breaker opened
+    pub opened: u64,
+}
+
+impl BreakerState {
+    /// The value of the `state` counter
+    fn gauge(self) -> u64 {
+        match self {
+            Self::Closed => 0,
+            Self::Open => 1,
+            Self::HalfOpen => 2,
+        }
+    }
+}
+
+/// The counters of a [`CircuitBreaker`] in `varnishstat`, in the order of
+/// [`CircuitBreaker::export_counters()`]
+///
+/// A trait object, so that the drop code of [`VscSegment`], which needs `varnishd`, is only
+/// linked in when the counters are exported.
+trait Counters: Debug + Send + Sync {
+    fn values(&self) -> &[AtomicU64];
+}
+
+#[cfg(not(varnishsys_6))]
+impl Counters for VscSegment {
+    fn values(&self) -> &[AtomicU64] {
+        VscSegment::values(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    successes: u32,
+    failures: u32,
+}
+
+/// Move the `current` bucket forward for each `window / BUCKETS` elapsed since `start`, emptying
+/// the buckets it moves to
+fn rotate(
+    buckets: &mut [Bucket; BUCKETS],
+    current: &mut usize,
+    start: &mut Instant,
+    window: Duration,
+    now: Instant,
+) {
+    let bucket_len = window / BUCKETS as u32;
+    if !bucket_len.is_zero() {
+        let elapsed = now.duration_since(*start);
+        let rotations = (elapsed.as_nanos() / bucket_len.as_nanos()) as usize;
+        for _ in 0..rotations.min(BUCKETS) {
+            *current = (*current + 1) % BUCKETS;
+            buckets[*current] = Bucket::default();
+        }
+        *start += bucket_len * rotations as u32;
+    }
+}
+
+/// The sums of the buckets, as `(successes, failures)`
+fn totals(buckets: &[Bucket; BUCKETS]) -> (u32, u32) {
+    buckets.iter().fold((0_u32, 0_u32), |(s, f), b| {
+        (s.saturating_add(b.successes), f.saturating_add(b.failures))
+    })
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    changed: Instant,
+    changed_wall: SystemTime,
+    buckets: [Bucket; BUCKETS],
+    /// Index of the bucket covering `bucket_start`
+    current: usize,
+    bucket_start: Instant,
+    /// Probes currently running, and probes that succeeded, in the half-open state
+    probes: u32,
+    probe_successes: u32,
+    stats: BreakerStats,
+}
+
+/// A circuit breaker with a rolling window of outcomes, see the [module documentation](self)
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    failure_ratio: f64,
+    window: Duration,
+    open_for: Duration,
+    min_calls: u32,
+    probes: u32,
+    inner: Mutex<Inner>,
+    counters: Option<Box<dyn Counters>>,
+}
+
+impl CircuitBreaker {
+    /// Create a closed breaker that opens when at least `failure_ratio` (between 0 and 1) of the
+    /// calls failed during the last `window`, and stays open for `open_for`.
+    ///
+    /// `name` is used in the error returned when a call is rejected.
+    pub fn new(name: &str, failure_ratio: f64, window: Duration, open_for: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            name: name.to_string(),
+            failure_ratio,
+            window,
+            open_for,
+            min_calls: 10,
+            probes: 1,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                changed: now,
+                changed_wall: SystemTime::now(),
+                buckets: [Bucket::default(); BUCKETS],
+                current: 0,
+                bucket_start: now,
+                probes: 0,
+                probe_successes: 0,
+                stats: BreakerStats {
+                    state: BreakerState::Closed,
+                    successes: 0,
+                    failures: 0,
+                    rejected: 0,
+                    opened: 0,
+                },
+            }),
+            counters: None,
+        }
+    }
+
+    /// Don't open before the window contains at least `calls` calls, 10 by default
+    #[must_use]
+    pub fn min_calls(mut self, calls: u32) -> Self {
+        self.min_calls = calls;
+        self
+    }
+
+    /// How many probe calls must succeed in the half-open state to close the breaker, 1 by
+    /// default. Probes run one at a time.
+    #[must_use]
+    pub fn probes(mut self, probes: u32) -> Self {
+        self.probes = probes.max(1);
+        self
+    }
+
+    /// Run `f` if the breaker allows it, and record its outcome.
+    ///
+    /// Rejected calls get an error without `f` being run.
+    pub fn call<T>(&self, f: impl FnOnce() -> Result<T, VclError>) -> Result<T, VclError> {
+        let probe = self.acquire()?;
+        let result = f();
+        self.record(probe, result.is_ok());
+        result
+    }
+
+    /// The current state
+    pub fn state(&self) -> BreakerState {
+        let mut inner = self.lock();
+        self.refresh(&mut inner, Instant::now());
+        inner.state
+    }
+
+    /// The breaker as a backend health, e.g. to implement
+    /// [`Serve::healthy()`](crate::vcl::Serve::healthy): sick while open, along with the time of
+    /// the last state change
+    pub fn healthy(&self) -> (bool, SystemTime) {
+        let mut inner = self.lock();
+        self.refresh(&mut inner, Instant::now());
+        (inner.state != BreakerState::Open, inner.changed_wall)
+    }
+
+    /// The counters of the breaker
+    pub fn stats(&self) -> BreakerStats {
+        let mut inner = self.lock();
+        self.refresh(&mut inner, Instant::now());
+        inner.stats
+    }
+
+    /// Publish the counters as `vmod.<vmod>.<instance>.state` (0 when closed, 1 when open, 2
+    /// when half-open), `.successes`, `.failures`, `.rejected` and `.opened`, e.g. from the
+    /// constructor of a vmod object. The counts so far are carried over.
+    #[cfg(not(varnishsys_6))]
+    pub fn export_counters(&mut self, vmod: &str, instance: &str) -> Result<(), VclError> {
+        let segment = VscSegment::new(
+            vmod,
+            instance,
+            "Circuit breaker",
+            vec![
+                VscField::gauge("state", "State, 0: closed, 1: open, 2: half-open"),
+                VscField::counter("successes", "Calls that succeeded"),
+                VscField::counter("failures", "Calls that failed"),
+                VscField::counter("rejected", "Calls rejected while open"),
+                VscField::counter("opened", "Times the breaker opened"),
+            ],
+        )?;
+        self.counters = Some(Box::new(segment));
+        self.sync(&self.lock());
+        Ok(())
+    }
+
+    /// Close the breaker and forget the past outcomes, e.g. when the VCL becomes warm again
+    pub fn reset(&self) {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        inner.buckets = [Bucket::default(); BUCKETS];
+        inner.bucket_start = now;
+        Self::set_state(&mut inner, BreakerState::Closed, now);
+        self.sync(&inner);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Check if a call may run, returning whether it is a half-open probe
+    fn acquire(&self) -> Result<bool, VclError> {
+        let mut inner = self.lock();
+        self.refresh(&mut inner, Instant::now());
+        match inner.state {
+            BreakerState::Closed => Ok(false),
+            BreakerState::HalfOpen if inner.probes == 0 => {
+                inner.probes += 1;
+                Ok(true)
+            }
+            BreakerState::Open | BreakerState::HalfOpen => {
+                inner.stats.rejected += 1;
+                self.sync(&inner);
+                Err(VclError::new(format!(
+                    "{}: circuit breaker is open",
+                    self.name
+                )))
+            }
+        }
+    }
+
+    fn record(&self, probe: bool, success: bool) {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        self.refresh(&mut inner, now);
+        self.update(&mut inner, probe, success, now);
+        self.sync(&inner);
+    }
+
+    fn update(&self, inner: &mut Inner, probe: bool, success: bool, now: Instant) {
+        if success {
+            inner.stats.successes += 1;
+        } else {
+            inner.stats.failures += 1;
+        }
+        if probe {
+            inner.probes -= 1;
+            // The state may have changed if reset() was called while the probe was running
+            if inner.state == BreakerState::HalfOpen {
+                if success {
+                    inner.probe_successes += 1;
+                    if inner.probe_successes >= self.probes {
+                        inner.buckets = [Bucket::default(); BUCKETS];
+                        inner.bucket_start = now;
+                        Self::set_state(inner, BreakerState::Closed, now);
+                    }
+                } else {
+                    Self::set_state(inner, BreakerState::Open, now);
+                }
+            }
+            return;
+        }
+        if inner.state != BreakerState::Closed {
+            return;
+        }
+        let current = inner.current;
+        let bucket = &mut inner.buckets[current];
+        if success {
+            bucket.successes += 1;
+        } else {
+            bucket.failures += 1;
+        }
+        let (successes, failures) = totals(&inner.buckets);
+        let total = successes.saturating_add(failures);
+        if !success
+            && total >= self.min_calls
+            && f64::from(failures) >= self.failure_ratio * f64::from(total)
+        {
+            Self::set_state(inner, BreakerState::Open, now);
+        }
+    }
+
+    /// Rotate the window buckets, and move from open to half-open once `open_for` has elapsed
+    fn refresh(&self, inner: &mut Inner, now: Instant) {
+        rotate(
+            &mut inner.buckets,
+            &mut inner.current,
+            &mut inner.bucket_start,
+            self.window,
+            now,
+        );
+        if inner.state == BreakerState::Open && now.duration_since(inner.changed) >= self.open_for {
+            Self::set_state(inner, BreakerState::HalfOpen, now);
+            self.sync(inner);
+        }
+    }
+
+    /// Copy the stats to the exported counters, if any
+    fn sync(&self, inner: &Inner) {
+        let Some(counters) = &self.counters else {
+            return;
+        };
+        let stats = &inner.stats;
+        let values = [
+            stats.state.gauge(),
+            stats.successes,
+            stats.failures,
+            stats.rejected,
+            stats.opened,
+        ];
+        for (counter, value) in counters.values().iter().zip(values) {
+            counter.store(value, Ordering::Relaxed);
+        }
+    }
+
+    fn set_state(inner: &mut Inner, state: BreakerState, now: Instant) {
+        if state == BreakerState::Open {
+            inner.stats.opened += 1;
+        }
+        inner.state = state;
+        inner.stats.state = state;
+        inner.changed = now;
+        inner.changed_wall = SystemTime::now();
+        inner.probe_successes = 0;
+    }
+}
+
+/// The outcome of a backend fetch, as recorded by a [`SaintMode`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchOutcome {
+    /// The backend answered with a status below 500
+    Success,
+    /// The backend answered with a 5xx status
+    ServerError,
+    /// The fetch failed without a response, e.g. on a timeout or a refused connection
+    Failed,
+}
+
+impl FetchOutcome {
+    /// Classify a response status
+    pub fn from_status(status: u16) -> Self {
+        if status >= 500 {
+            Self::ServerError
+        } else {
+            Self::Success
+        }
+    }
+}
+
+/// The state of one backend in a [`SaintMode`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaintStats {
+    /// Successful fetches in the current window
+    pub successes: u32,
+    /// 5xx responses and failed fetches in the current window
+    pub errors: u32,
+    /// The backend is currently blacklisted
+    pub blacklisted: bool,
+    /// How many times the backend was blacklisted
+    pub blacklistings: u64,
+}
+
+#[derive(Debug)]
+struct Track {
+    buckets: [Bucket; BUCKETS],
+    current: usize,
+    bucket_start: Instant,
+    blacklisted_until: Option<Instant>,
+    changed_wall: SystemTime,
+    blacklistings: u64,
+}
+
+/// Per-backend error budgets, with a temporary blacklisting of the backends exceeding theirs
+///
+/// Like the saintmode vmod, it tracks the 5xx responses and the failed fetches of each backend,
+/// identified by its name, over a rolling window. A backend reaching `max_errors` errors in the
+/// window is blacklisted for a while, and considered sick by [`SaintMode::healthy()`]. Custom
+/// directors can also skip the [degraded](SaintMode::is_degraded) backends before they get
+/// blacklisted. Outcomes are either recorded by hand, or by wrapping the backend in a
+/// [`SaintServe`].
+///
+/// ``` rust
+/// use std::time::Duration;
+/// use varnish::resilience::{FetchOutcome, SaintMode};
+///
+/// let saint = SaintMode::new(Duration::from_secs(10), 4, Duration::from_secs(30));
+/// saint.record("origin1", FetchOutcome::from_status(503));
+/// saint.record("origin1", FetchOutcome::Failed);
+/// assert!(saint.is_degraded("origin1"));
+/// saint.record("origin1", FetchOutcome::ServerError);
+/// saint.record("origin1", FetchOutcome::ServerError);
+/// assert!(saint.is_blacklisted("origin1"));
+///
+/// // e.g. in a director, pick the first usable backend
+/// let backends = ["origin1", "origin2"];
+/// let pick = backends.iter().find(|b| !saint.is_degraded(b));
+/// assert_eq!(pick, Some(&"origin2"));
+/// ```
+#[derive(Debug)]
+pub struct SaintMode {
+    window: Duration,
+    max_errors: u32,
+    degraded_after: u32,
+    blacklist_for: Duration,
+    backends: Mutex<HashMap<String, Track>>,
+}
+
+impl SaintMode {
+    /// Blacklist a backend for `blacklist_for` once it reaches `max_errors` errors during the
+    /// last `window`
+    pub fn new(window: Duration, max_errors: u32, blacklist_for: Duration) -> Self {
+        let max_errors = max_errors.max(1);
+        Self {
+            window,
+            max_errors,
+            degraded_after: max_errors.div_ceil(2),
+            blacklist_for,
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Consider a backend degraded from `errors` errors in the window, half of `max_errors` by
+    /// default
+    #[must_use]
+    pub fn degraded_after(mut self, errors: u32) -> Self {
+        self.degraded_after = errors.clamp(1, self.max_errors);
+        self
+    }
+
+    /// Record the outcome of a fetch from `backend`, blacklisting it if it runs out of budget
+    pub fn record(&self, backend: &str, outcome: FetchOutcome) {
+        let now = Instant::now();
+        let mut backends = self.lock();
+        let track = self.track(&mut backends, backend, now);
+        if track.blacklisted_until.is_some() {
+            // the fetches started before the blacklisting don't extend it
+            return;
+        }
+        let bucket = &mut track.buckets[track.current];
+        if outcome == FetchOutcome::Success {
+            bucket.successes += 1;
+            return;
+        }
+        bucket.failures += 1;
+        if totals(&track.buckets).1 >= self.max_errors {
+            Self::set_blacklist(track, Some(now + self.blacklist_for));
+        }
+    }
+
+    /// Blacklist `backend` for `duration`, whatever its errors
+    pub fn blacklist(&self, backend: &str, duration: Duration) {
+        let now = Instant::now();
+        let mut backends = self.lock();
+        let track = self.track(&mut backends, backend, now);
+        Self::set_blacklist(track, Some(now + duration));
+    }
+
+    /// Lift the blacklisting of `backend`, and forget its errors
+    pub fn clear(&self, backend: &str) {
+        if let Some(track) = self.lock().get_mut(backend) {
+            if track.blacklisted_until.is_some() {
+                Self::set_blacklist(track, None);
+            }
+            track.buckets = [Bucket::default(); BUCKETS];
+        }
+    }
+
+    /// `backend` is blacklisted
+    pub fn is_blacklisted(&self, backend: &str) -> bool {
+        self.stats(backend).is_some_and(|s| s.blacklisted)
+    }
+
+    /// `backend` is blacklisted, or has at least the [`degraded_after`](Self::degraded_after)
+    /// number of errors in the window
+    pub fn is_degraded(&self, backend: &str) -> bool {
+        self.stats(backend)
+            .is_some_and(|s| s.blacklisted || s.errors >= self.degraded_after)
+    }
+
+    /// The blacklisting as a backend health, e.g. to implement
+    /// [`Serve::healthy()`](crate::vcl::Serve::healthy): sick while blacklisted, along with the
+    /// time the blacklisting started or ended
+    pub fn healthy(&self, backend: &str) -> (bool, SystemTime) {
+        let now = Instant::now();
+        let mut backends = self.lock();
+        match backends.get_mut(backend) {
+            Some(track) => {
+                self.refresh(track, now);
+                (track.blacklisted_until.is_none(), track.changed_wall)
+            }
+            None => (true, SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    /// The state of `backend`, `None` if nothing was recorded for it
+    pub fn stats(&self, backend: &str) -> Option<SaintStats> {
+        let now = Instant::now();
+        let mut backends = self.lock();
+        let track = backends.get_mut(backend)?;
+        self.refresh(track, now);
+        let (successes, errors) = totals(&track.buckets);
+        Some(SaintStats {
+            successes,
+            errors,
+            blacklisted: track.blacklisted_until.is_some(),
+            blacklistings: track.blacklistings,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Track>> {
+        self.backends.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn track<'a>(
+        &self,
+        backends: &'a mut HashMap<String, Track>,
+        backend: &str,
+        now: Instant,
+    ) -> &'a mut Track {
+        if !backends.contains_key(backend) {
+            backends.insert(
+                backend.to_string(),
+                Track {
+                    buckets: [Bucket::default(); BUCKETS],
+                    current: 0,
+                    bucket_start: now,
+                    blacklisted_until: None,
+                    changed_wall: SystemTime::now(),
+                    blacklistings: 0,
+                },
+            );
+        }
+        let track = backends.get_mut(backend).expect("inserted above");
+        self.refresh(track, now);
+        track
+    }
+
+    /// Rotate the window, and end the blacklisting once it expired
+    fn refresh(&self, track: &mut Track, now: Instant) {
+        rotate(
+            &mut track.buckets,
+            &mut track.current,
+            &mut track.bucket_start,
+            self.window,
+            now,
+        );
+        if track.blacklisted_until.is_some_and(|until| now >= until) {
+            Self::set_blacklist(track, None);
+        }
+    }
+
+    fn set_blacklist(track: &mut Track, until: Option<Instant>) {
+        if until.is_some() && track.blacklisted_until.is_none() {
+            track.blacklistings += 1;
+        }
+        // start afresh, a backend coming back gets its whole budget
+        track.buckets = [Bucket::default(); BUCKETS];
+        track.blacklisted_until = until;
+        track.changed_wall = SystemTime::now();
+    }
+}
+
+/// A backend whose fetches are recorded by a [`SaintMode`], and refused while it is blacklisted
+///
+/// It wraps a [`Serve`] implementation: errors returned by `get_headers()` count as
+/// [`FetchOutcome::Failed`], and the other fetches are classified by the status they set in
+/// `beresp`. The backend is reported sick while blacklisted.
+#[cfg(not(varnishsys_6))]
+#[derive(Debug)]
+pub struct SaintServe<S> {
+    inner: S,
+    name: String,
+    saint: Arc<SaintMode>,
+}
+
+#[cfg(not(varnishsys_6))]
+impl<S> SaintServe<S> {
+    /// Track `inner` under `name` in `saint`
+    pub fn new(inner: S, name: impl Into<String>, saint: Arc<SaintMode>) -> Self {
+        Self {
+            inner,
+            name: name.into(),
+            saint,
+        }
+    }
+
+    /// The wrapped backend
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+#[cfg(not(varnishsys_6))]
+impl<T: Transfer, S: Serve<T>> Serve<T> for SaintServe<S> {
+    fn get_type(&self) -> &str {
+        self.inner.get_type()
+    }
+
+    fn get_headers(&self, ctx: &mut Ctx) -> Result<Option<T>, VclError> {
+        if self.saint.is_blacklisted(&self.name) {
+            return Err(VclError::new(format!(
+                "{}: backend is blacklisted",
+                self.name
+            )));
+        }
+        let result = self.inner.get_headers(ctx);
+        let outcome = match &result {
+            Err(_) => FetchOutcome::Failed,
+            Ok(_) => ctx
+                .beresp()
+                .and_then(|beresp| beresp.status()?.parse().ok())
+                .map_or(FetchOutcome::Success, FetchOutcome::from_status),
+        };
+        self.saint.record(&self.name, outcome);
+        result
+    }
+
+    fn finish(&self, ctx: &mut Ctx) {
+        self.inner.finish(ctx);
+    }
+
+    fn healthy(&self, ctx: &mut Ctx) -> (bool, SystemTime) {
+        let (healthy, changed) = self.inner.healthy(ctx);
+        let (saint_healthy, saint_changed) = self.saint.healthy(&self.name);
+        (healthy && saint_healthy, changed.max(saint_changed))
+    }
+
+    fn pipe(&self, ctx: &mut Ctx, tcp_stream: TcpStream) -> StreamClose {
+        self.inner.pipe(ctx, tcp_stream)
+    }
+
+    fn event(&self, event: VclEvent) {
+        self.inner.event(event);
+    }
+
+    fn panic(&self, vsb: &mut Buffer) {
+        self.inner.panic(vsb);
+    }
+
+    fn list(&self, ctx: &mut Ctx, vsb: &mut Buffer, detailed: bool, json: bool) {
+        self.inner.list(ctx, vsb, detailed, json);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn fail() -> Result<(), VclError> {
+        Err("boom".into())
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        let breaker = CircuitBreaker::new(
+            "test",
+            0.5,
+            Duration::from_secs(60),
+            Duration::from_millis(50),
+        )
+        .min_calls(4)
+        .probes(2);
+
+        // Not enough calls to open
+        assert!(breaker.call(fail).is_err());
+        assert!(breaker.call(|| Ok(())).is_ok());
+        assert!(breaker.call(fail).is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        // 3 failures out of 4
+        assert!(breaker.call(fail).is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.healthy().0);
+        let err = breaker.call(|| Ok(())).unwrap_err();
+        assert_eq!(err.to_string(), "test: circuit breaker is open");
+
+        // A failed probe reopens it
+        sleep(Duration::from_millis(60));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.call(fail).is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // Two successful probes close it
+        sleep(Duration::from_millis(60));
+        assert!(breaker.call(|| Ok(())).is_ok());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.call(|| Ok(())).is_ok());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        let stats = breaker.stats();
+        assert_eq!(
+            (
+                stats.successes,
+                stats.failures,
+                stats.rejected,
+                stats.opened
+            ),
+            (3, 4, 1, 2)
+        );
+    }
+
+    #[derive(Debug)]
+    struct TestCounters(Vec<AtomicU64>);
+
+    impl Counters for TestCounters {
+        fn values(&self) -> &[AtomicU64] {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn breaker_counters() {
+        let mut breaker = CircuitBreaker::new(
+            "test",
+            0.5,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        )
+        .min_calls(2);
+        breaker.counters = Some(Box::new(TestCounters(
+            (0..5).map(|_| AtomicU64::new(0)).collect(),
+        )));
+        let counters = || -> Vec<u64> {
+            let values = breaker.counters.as_ref().unwrap().values();
+            values.iter().map(|v| v.load(Ordering::Relaxed)).collect()
+        };
+
+        assert!(breaker.call(|| Ok(())).is_ok());
+        assert_eq!(counters(), [0, 1, 0, 0, 0]);
+        assert!(breaker.call(fail).is_err());
+        assert_eq!(counters(), [1, 1, 1, 0, 1]);
+        assert!(breaker.call(|| Ok(())).is_err());
+        assert_eq!(counters(), [1, 1, 1, 1, 1]);
+        breaker.reset();
+        assert_eq!(counters(), [0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn saint_mode() {
+        let saint = SaintMode::new(Duration::from_secs(60), 3, Duration::from_millis(50));
+        assert!(!saint.is_degraded("b1"));
+        assert_eq!(saint.healthy("b1"), (true, SystemTime::UNIX_EPOCH));
+
+        saint.record("b1", FetchOutcome::from_status(200));
+        saint.record("b1", FetchOutcome::from_status(502));
+        assert!(!saint.is_degraded("b1"));
+        saint.record("b1", FetchOutcome::Failed);
+        assert!(saint.is_degraded("b1") && !saint.is_blacklisted("b1"));
+        saint.record("b1", FetchOutcome::ServerError);
+        assert!(saint.is_blacklisted("b1"));
+        assert!(!saint.healthy("b1").0);
+        assert!(!saint.is_degraded("b2"));
+
+        sleep(Duration::from_millis(60));
+        let stats = saint.stats("b1").unwrap();
+        assert_eq!(
+            (
+                stats.successes,
+                stats.errors,
+                stats.blacklisted,
+                stats.blacklistings
+            ),
+            (0, 0, false, 1)
+        );
+
+        saint.blacklist("b2", Duration::from_secs(60));
+        assert!(saint.is_degraded("b2"));
+        saint.clear("b2");
+        assert!(saint.healthy("b2").0);
+    }
+}
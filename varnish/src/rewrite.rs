@@ -0,0 +1,457 @@
+//! Rewrite the URL or a header of a request with rules loaded from a file
+//!
+//! A [`RewriteRules`] is an ordered list of rules, each either replacing a `prefix` of the value,
+//! or replacing the matches of a `regex`, with `$1` or `${name}` in the replacement standing for
+//! the capture groups. The first rule that matches wins, and a value matching no rule is left
+//! untouched. The rules are written in TOML:
+//!
+//! ```toml
+//! [[rules]]
+//! prefix = "/old-shop/"
+//! to = "/shop/"
+//!
+//! [[rules]]
+//! regex = '^/product/(\d+)$'
+//! to = "/catalog?id=$1"
+//! ```
+//!
+//! or in CSV, with a `type,from,to` line per rule, and `"` to quote the fields holding commas:
+//!
+//! ```text
+//! prefix,/old-shop/,/shop/
+//! regex,"^/product/(\d{1,9})$",/catalog?id=$1
+//! ```
+//!
+//! A [`Rewriter`] applies the rules to the current request, copying the new value into the
+//! workspace. When its rules come from a file, it can reload them when the file changes, e.g.
+//! from a [`Timers`] kept in the `#[shared_per_vcl]` state. A file that fails to load keeps the
+//! previous rules in use, and the error is available from [`Rewriter::last_error()`].
+//!
+//! ``` rust
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use varnish::rewrite::{Rewriter, Target};
+//! use varnish::timer::Timers;
+//! use varnish::vcl::{Ctx, VclError};
+//!
+//! // e.g. in the constructor of a vmod object, with the source given in VCL
+//! fn new_rewriter(timers: &mut Timers) -> Result<Arc<Rewriter>, VclError> {
+//!     let rewriter = Arc::new(Rewriter::new(
+//!         r#"rules = [{ prefix = "/old-shop/", to = "/shop/" }]"#,
+//!     )?);
+//!     // only files are reloaded, this is a no-op for inline rules
+//!     rewriter.watch(timers, Duration::from_secs(5));
+//!     Ok(rewriter)
+//! }
+//!
+//! // e.g. a method of the object, called from `vcl_recv`
+//! fn rewrite_url(ctx: &mut Ctx, rewriter: &Rewriter) -> Result<bool, VclError> {
+//!     rewriter.rewrite(ctx, Target::Url)
+//! }
+//!
+//! let rewriter = new_rewriter(&mut Timers::new())?;
+//! assert_eq!(rewriter.rules().apply("/old-shop/hats").as_deref(), Some("/shop/hats"));
+//! # Ok::<(), VclError>(())
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use regex::Regex;
+use serde::Deserialize;
+use varnish_sys::vcl::{Ctx, VclError};
+
+use crate::timer::{TimerHandle, Timers};
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Prefix(String),
+    Regex(Regex),
+}
+
+/// A rewrite rule, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct Rule {
+    matcher: Matcher,
+    to: String,
+}
+
+impl Rule {
+    /// Replace the `from` prefix of the value with `to`
+    pub fn prefix(from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            matcher: Matcher::Prefix(from.into()),
+            to: to.into(),
+        }
+    }
+
+    /// Replace the matches of `pattern` with `to`, where `$1` or `${name}` stand for the capture
+    /// groups
+    pub fn regex(pattern: &str, to: impl Into<String>) -> Result<Self, VclError> {
+        let re = Regex::new(pattern)
+            .map_err(|e| VclError::String(format!("Invalid rewrite pattern {pattern:?}: {e}")))?;
+        Ok(Self {
+            matcher: Matcher::Regex(re),
+            to: to.into(),
+        })
+    }
+
+    /// The rewritten value, `None` if the rule does not match
+    pub fn apply(&self, value: &str) -> Option<String> {
+        match &self.matcher {
+            Matcher::Prefix(from) => value
+                .strip_prefix(from.as_str())
+                .map(|rest| format!("{}{rest}", self.to)),
+            Matcher::Regex(re) => re
+                .is_match(value)
+                .then(|| re.replace_all(value, self.to.as_str()).into_owned()),
+        }
+    }
+}
+
+/// The TOML form of a rule
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    prefix: Option<String>,
+    regex: Option<String>,
+    to: String,
+}
+
+/// The TOML form of the rules
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    rules: Vec<RuleSpec>,
+}
+
+/// An ordered list of rewrite rules, see the [module documentation](self)
+#[derive(Debug, Clone, Default)]
+pub struct RewriteRules {
+    rules: Vec<Rule>,
+}
+
+impl RewriteRules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules }
+    }
+
+    /// Parse the rules from TOML
+    pub fn from_toml(s: &str) -> Result<Self, VclError> {
+        let file: RulesFile = crate::config::parse(s)?;
+        Self::from_specs(file.rules)
+    }
+
+    /// Parse the rules from CSV, with one `type,from,to` line per rule, `type` being `prefix` or
+    /// `regex`. Empty lines, lines starting with `#`, and a `type,from,to` header are ignored.
+    pub fn from_csv(s: &str) -> Result<Self, VclError> {
+        let mut rules = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line == "type,from,to" {
+                continue;
+            }
+            let err =
+                |e: &str| VclError::String(format!("Invalid rewrite rule on line {}: {e}", i + 1));
+            let fields = csv_fields(line).map_err(err)?;
+            let [kind, from, to] = <[String; 3]>::try_from(fields)
+                .map_err(|f| err(&format!("expected 3 fields, found {}", f.len())))?;
+            rules.push(match kind.as_str() {
+                "prefix" => Rule::prefix(from, to),
+                "regex" => Rule::regex(&from, to)?,
+                _ => return Err(err(&format!("unknown rule type {kind:?}"))),
+            });
+        }
+        Ok(Self::new(rules))
+    }
+
+    /// Load the rules from `source`: a `file://` path to a CSV file if it ends with `.csv`, or
+    /// to a TOML file otherwise, or inline TOML
+    pub fn load(source: &str) -> Result<Self, VclError> {
+        match source.strip_prefix("file://") {
+            Some(path)
+                if Path::new(path)
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) =>
+            {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| VclError::String(format!("Unable to read {path}: {e}")))?;
+                Self::from_csv(&text).map_err(|e| VclError::String(format!("{path}: {e}")))
+            }
+            _ => Self::from_toml(source),
+        }
+    }
+
+    fn from_specs(specs: Vec<RuleSpec>) -> Result<Self, VclError> {
+        specs
+            .into_iter()
+            .map(|spec| match (spec.prefix, spec.regex) {
+                (Some(prefix), None) => Ok(Rule::prefix(prefix, spec.to)),
+                (None, Some(regex)) => Rule::regex(&regex, spec.to),
+                _ => Err(VclError::String(
+                    "A rewrite rule needs either a `prefix` or a `regex`".to_string(),
+                )),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self::new)
+    }
+
+    /// The value rewritten by the first matching rule, `None` if no rule matches
+    pub fn apply(&self, value: &str) -> Option<String> {
+        self.rules.iter().find_map(|rule| rule.apply(value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+/// Split a CSV line into its fields, unquoting the quoted ones
+fn csv_fields(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err("unterminated quoted field"),
+                }
+            }
+            if !matches!(chars.peek(), None | Some(',')) {
+                return Err("unexpected character after a quoted field");
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                field.push(c);
+            }
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+/// The value to rewrite in the current request
+#[derive(Debug, Clone, Copy)]
+pub enum Target<'a> {
+    /// `req.url`, or `bereq.url` on the backend side
+    Url,
+    /// A header of `req`, or of `bereq` on the backend side
+    Header(&'a str),
+}
+
+struct Loaded {
+    rules: Arc<RewriteRules>,
+    modified: Option<SystemTime>,
+    error: Option<String>,
+}
+
+/// Rewrite rules applied to requests, and reloaded when their file changes, see the
+/// [module documentation](self)
+pub struct Rewriter {
+    source: String,
+    path: Option<PathBuf>,
+    loaded: Mutex<Loaded>,
+}
+
+impl Rewriter {
+    /// Load the rules from `source`, see [`RewriteRules::load()`]
+    pub fn new(source: impl Into<String>) -> Result<Self, VclError> {
+        let source = source.into();
+        let path = source.strip_prefix("file://").map(PathBuf::from);
+        let modified = path.as_deref().and_then(modified);
+        let rules = Arc::new(RewriteRules::load(&source)?);
+        Ok(Self {
+            source,
+            path,
+            loaded: Mutex::new(Loaded {
+                rules,
+                modified,
+                error: None,
+            }),
+        })
+    }
+
+    /// The rules in use
+    pub fn rules(&self) -> Arc<RewriteRules> {
+        Arc::clone(&self.lock().rules)
+    }
+
+    /// Reload the rules if they come from a file modified since they were loaded. Returns
+    /// whether new rules are in use; on error, the previous rules are kept.
+    pub fn reload_if_changed(&self) -> Result<bool, VclError> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let modified = modified(path);
+        if modified.is_none() || modified == self.lock().modified {
+            return Ok(false);
+        }
+        // parse outside of the lock, the requests keep using the current rules meanwhile
+        let result = RewriteRules::load(&self.source);
+        let mut loaded = self.lock();
+        loaded.modified = modified;
+        match result {
+            Ok(rules) => {
+                loaded.rules = Arc::new(rules);
+                loaded.error = None;
+                Ok(true)
+            }
+            Err(e) => {
+                loaded.error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Check the file for changes every `interval`, while the `timers` are started
+    pub fn watch(self: &Arc<Self>, timers: &mut Timers, interval: Duration) -> TimerHandle {
+        let rewriter = Arc::clone(self);
+        timers.every(interval, move || {
+            // the error is kept for last_error()
+            let _ = rewriter.reload_if_changed();
+        })
+    }
+
+    /// The error of the last reload, if it failed
+    pub fn last_error(&self) -> Option<String> {
+        self.lock().error.clone()
+    }
+
+    /// Rewrite `target` in the request of `ctx`. Returns whether a rule matched.
+    pub fn rewrite(&self, ctx: &mut Ctx, target: Target) -> Result<bool, VclError> {
+        let rules = self.rules();
+        let Some(http) = (if ctx.raw.req.is_null() {
+            ctx.bereq_mut()
+        } else {
+            ctx.req_mut()
+        }) else {
+            return Err(VclError::CStr(c"rewrite needs a request"));
+        };
+        let value = match target {
+            Target::Url => http.url(),
+            Target::Header(name) => http.header(name),
+        };
+        let Some(new) = value.and_then(|v| rules.apply(v)) else {
+            return Ok(false);
+        };
+        match target {
+            Target::Url => http.set_url(&new)?,
+            Target::Header(name) => {
+                http.unset_header(name);
+                http.set_header(name, &new)?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Loaded> {
+        self.loaded.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl fmt::Debug for Rewriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rewriter")
+            .field("source", &self.source)
+            .field("rules", &self.rules().len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn rewrite_rules() {
+        let rules = RewriteRules::from_toml(
+            r#"
+            [[rules]]
+            prefix = "/old/"
+            to = "/new/"
+
+            [[rules]]
+            regex = '^/product/(?<id>\d+)$'
+            to = "/catalog?id=${id}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(rules.apply("/old/a").as_deref(), Some("/new/a"));
+        assert_eq!(
+            rules.apply("/product/42").as_deref(),
+            Some("/catalog?id=42")
+        );
+        assert_eq!(rules.apply("/product/x"), None);
+
+        assert!(RewriteRules::from_toml("[[rules]]\nto = \"x\"").is_err());
+        assert!(RewriteRules::from_toml("[[rules]]\nregex = \"(\"\nto = \"x\"").is_err());
+
+        let csv = RewriteRules::from_csv(
+            "type,from,to\n# comment\nregex,\"^/p/(\\d{1,3})$\",\"/q?a=$1,b\"\nprefix,/a,/b\n",
+        )
+        .unwrap();
+        assert_eq!(csv.len(), 2);
+        assert_eq!(csv.apply("/p/7").as_deref(), Some("/q?a=7,b"));
+        assert_eq!(csv.apply("/a/x").as_deref(), Some("/b/x"));
+        assert!(RewriteRules::from_csv("prefix,/a").is_err());
+        assert!(RewriteRules::from_csv("glob,/a,/b").is_err());
+        assert!(RewriteRules::from_csv("prefix,\"/a,/b").is_err());
+    }
+
+    #[test]
+    fn reload() {
+        let path = env::temp_dir().join(format!("varnish-rewrite-{}.csv", std::process::id()));
+        fs::write(&path, "prefix,/a,/b\n").unwrap();
+        let rewriter = Rewriter::new(format!("file://{}", path.display())).unwrap();
+        assert_eq!(rewriter.rules().apply("/a").as_deref(), Some("/b"));
+        assert!(!rewriter.reload_if_changed().unwrap());
+
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::write(&path, "prefix,/a,/c\nbad").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(rewriter.reload_if_changed().is_err());
+        assert!(rewriter.last_error().is_some());
+        assert_eq!(rewriter.rules().apply("/a").as_deref(), Some("/b"));
+
+        fs::write(&path, "prefix,/a,/c\n").unwrap();
+        let later = later + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(rewriter.reload_if_changed().unwrap());
+        assert_eq!(rewriter.rules().apply("/a").as_deref(), Some("/c"));
+        assert_eq!(rewriter.last_error(), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
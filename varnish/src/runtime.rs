@@ -0,0 +1,61 @@
+//! The `tokio` runtime shared by the features running async code in the background
+//!
+//! It is started by the first user, and stopped once the last one is dropped, e.g. when the last
+//! VCL using the vmod is discarded.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::Duration;
+
+use tokio::runtime::Runtime;
+use varnish_sys::vcl::{VclError, VclResult};
+
+/// Worker threads of the shared runtime
+const WORKERS: usize = 2;
+
+/// How long to wait for the runtime to stop once the last user is gone
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+static RUNTIME: Mutex<Weak<Runtime>> = Mutex::new(Weak::new());
+
+/// A reference to the shared runtime, stopping it when the last one is dropped
+#[derive(Debug)]
+pub(crate) struct RuntimeRef(Option<Arc<Runtime>>);
+
+impl RuntimeRef {
+    pub(crate) fn acquire() -> VclResult<Self> {
+        let mut weak = RUNTIME.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(runtime) = weak.upgrade() {
+            return Ok(Self(Some(runtime)));
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(WORKERS)
+            .thread_name("varnish-runtime")
+            .enable_all()
+            .build()
+            .map_err(|e| {
+                VclError::String(format!("Unable to start the background runtime: {e}"))
+            })?;
+        let runtime = Arc::new(runtime);
+        *weak = Arc::downgrade(&runtime);
+        Ok(Self(Some(runtime)))
+    }
+
+    pub(crate) fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.0
+            .as_ref()
+            .expect("runtime is set until drop")
+            .block_on(future)
+    }
+}
+
+impl Drop for RuntimeRef {
+    /// Stop the threads with the last user, so that no code of the vmod runs after it is
+    /// unloaded. The lock keeps a new user from starting another runtime in the meantime.
+    fn drop(&mut self) {
+        let _guard = RUNTIME.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(runtime) = self.0.take().and_then(Arc::into_inner) {
+            runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        }
+    }
+}
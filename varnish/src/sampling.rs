@@ -0,0 +1,216 @@
+//! Only pay for verbose logging or heavy metrics on a fraction of the requests
+//!
+//! A debug-style vmod that logs every header, or times every step of a request, is too expensive
+//! to leave on in production. A [`Sampler`] decides, for each request, whether that work should
+//! be done, so that the overhead stays bounded by the sampling rate:
+//!
+//! - [`Sampler::by_vxid()`] hashes the id of the task, so every call made for the same request
+//!   gives the same answer, in every vmod using the same rate, and across restarts. Either all the
+//!   verbose records of a request are logged, or none are.
+//! - [`Sampler::random()`] draws a new number at each call, for independent events, e.g. timing
+//!   one cache lookup in a hundred.
+//!
+//! ``` rust
+//! use varnish::sampling::Sampler;
+//! use varnish::vcl::{Ctx, LogTag};
+//!
+//! // one request in a thousand
+//! const VERBOSE: Sampler = Sampler::by_vxid(0.001);
+//!
+//! fn log_headers(ctx: &mut Ctx) {
+//!     if !VERBOSE.sample(ctx) {
+//!         return;
+//!     }
+//!     let lines: Vec<String> = ctx
+//!         .req()
+//!         .into_iter()
+//!         .flatten()
+//!         .map(|(name, value)| format!("{name}: {value:?}"))
+//!         .collect();
+//!     for line in lines {
+//!         ctx.log(LogTag::Debug, line);
+//!     }
+//! }
+//! ```
+//!
+//! The client and backend sides of a request are different tasks with different ids, so
+//! [`Sampler::by_vxid()`] samples them independently.
+
+use std::cell::Cell;
+use std::hash::{BuildHasher, RandomState};
+
+use varnish_sys::ffi;
+use varnish_sys::vcl::{Ctx, LogTag};
+
+/// Decides which requests are sampled, see the [module documentation](self)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampler {
+    rate: f64,
+    mode: Mode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Vxid,
+    Random,
+}
+
+impl Sampler {
+    /// Sample a `rate` fraction of the tasks, the same ones at every call
+    ///
+    /// `rate` is clamped to `0.0..=1.0`, and `NaN` samples nothing.
+    pub const fn by_vxid(rate: f64) -> Self {
+        Self {
+            rate: clamp(rate),
+            mode: Mode::Vxid,
+        }
+    }
+
+    /// Sample a `rate` fraction of the calls, each call being decided on its own
+    ///
+    /// `rate` is clamped to `0.0..=1.0`, and `NaN` samples nothing.
+    pub const fn random(rate: f64) -> Self {
+        Self {
+            rate: clamp(rate),
+            mode: Mode::Random,
+        }
+    }
+
+    /// The fraction of tasks or calls that are sampled
+    pub fn rate(self) -> f64 {
+        self.rate
+    }
+
+    /// Whether the current task should do the sampled work
+    ///
+    /// With [`Sampler::by_vxid()`], a context without a log, e.g. in `vcl_init`, is treated as
+    /// the task `0`.
+    pub fn sample(self, ctx: &Ctx) -> bool {
+        match self.mode {
+            Mode::Vxid => self.sample_vxid(task_vxid(ctx)),
+            Mode::Random => self.hit(next_random()),
+        }
+    }
+
+    /// Whether the task `vxid` should do the sampled work, as decided by [`Sampler::sample()`]
+    ///
+    /// With [`Sampler::random()`], `vxid` is ignored.
+    pub fn sample_vxid(self, vxid: u64) -> bool {
+        match self.mode {
+            Mode::Vxid => self.hit(mix(vxid)),
+            Mode::Random => self.hit(next_random()),
+        }
+    }
+
+    /// Log `msg` if the current task is sampled, returns whether it was
+    pub fn log(self, ctx: &mut Ctx, tag: LogTag, msg: impl AsRef<str>) -> bool {
+        self.log_with(ctx, tag, || msg)
+    }
+
+    /// Like [`Sampler::log()`], but the message is only built if the task is sampled
+    pub fn log_with<S: AsRef<str>>(
+        self,
+        ctx: &mut Ctx,
+        tag: LogTag,
+        msg: impl FnOnce() -> S,
+    ) -> bool {
+        let sampled = self.sample(ctx);
+        if sampled {
+            ctx.log(tag, msg());
+        }
+        sampled
+    }
+
+    #[expect(clippy::cast_precision_loss)]
+    fn hit(self, h: u64) -> bool {
+        // the top 53 bits fit exactly in a f64, giving a uniform number in 0.0..1.0
+        ((h >> 11) as f64) / ((1u64 << 53) as f64) < self.rate
+    }
+}
+
+const fn clamp(rate: f64) -> f64 {
+    // written so that `NaN`, which fails every comparison, ends up as 0.0
+    if rate > 0.0 {
+        if rate < 1.0 {
+            rate
+        } else {
+            1.0
+        }
+    } else {
+        0.0
+    }
+}
+
+/// The id of the task, without the client/backend markers
+fn task_vxid(ctx: &Ctx) -> u64 {
+    let Some(vsl) = (unsafe { ctx.raw.vsl.as_ref() }) else {
+        return 0;
+    };
+    #[cfg(not(varnishsys_6))]
+    let vxid = vsl.wid.vxid & ffi::VSL_IDENTMASK;
+    #[cfg(varnishsys_6)]
+    let vxid = u64::from(vsl.wid & !(3 << 30));
+    vxid
+}
+
+/// The `splitmix64` finalizer, so that consecutive ids are spread over the whole range
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// A `splitmix64` generator per thread, seeded randomly
+fn next_random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(std::thread::current().id()));
+    }
+    STATE.with(|s| {
+        let state = s.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        s.set(state);
+        mix(state)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use varnish_sys::vcl::TestCtx;
+
+    use super::*;
+
+    #[test]
+    fn sampler() {
+        assert_eq!(Sampler::by_vxid(f64::NAN), Sampler::by_vxid(0.0));
+        assert_eq!(Sampler::random(-1.0), Sampler::random(0.0));
+        assert_eq!(Sampler::random(2.0), Sampler::random(1.0));
+
+        for sampler in [Sampler::by_vxid(0.0), Sampler::random(0.0)] {
+            assert!((0..1000).all(|v| !sampler.sample_vxid(v)));
+        }
+        for sampler in [Sampler::by_vxid(1.0), Sampler::random(1.0)] {
+            assert!((0..1000).all(|v| sampler.sample_vxid(v)));
+            assert!((0..1000).all(|_| sampler.sample_vxid(u64::MAX)));
+        }
+
+        let tenth = Sampler::by_vxid(0.1);
+        let hits = (0..100_000).filter(|&v| tenth.sample_vxid(v)).count();
+        assert!((9_000..11_000).contains(&hits), "{hits}");
+        assert!((0..1000).all(|v| tenth.sample_vxid(v) == tenth.sample_vxid(v)));
+
+        let tenth = Sampler::random(0.1);
+        let hits = (0..100_000).filter(|_| tenth.sample_vxid(0)).count();
+        assert!((9_000..11_000).contains(&hits), "{hits}");
+    }
+
+    #[test]
+    fn sampler_log() {
+        let mut test_ctx = TestCtx::new(100);
+        let mut ctx = test_ctx.ctx();
+        assert!(!Sampler::by_vxid(0.0)
+            .log_with(&mut ctx, LogTag::Debug, || -> String { unreachable!() }));
+        assert_eq!(
+            Sampler::by_vxid(0.5).sample(&ctx),
+            Sampler::by_vxid(0.5).sample_vxid(0)
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Keep state for the lifetime of a client connection
+//!
+//! Varnish has `PRIV_TASK` and `PRIV_TOP` for the state of a request, but nothing like a
+//! `PRIV_SESS` for the state of the connection carrying many requests, e.g. the result of a TLS or
+//! TCP fingerprint, or of an expensive authentication. A [`SessionStore`] fills the gap: it maps
+//! each client session to a value shared by all its requests.
+//!
+//! Varnish does not tell the vmods when a session is closed, so the values are dropped once their
+//! session was idle for a while: the store sweeps its expired entries on its own when new sessions
+//! come in, and can also be swept periodically with a [`Timers`].
+//!
+//! ``` rust
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use varnish::session::SessionStore;
+//! use varnish::timer::Timers;
+//! use varnish::vcl::Ctx;
+//!
+//! struct Fingerprint(String);
+//!
+//! #[derive(Default)]
+//! struct PerVcl {
+//!     timers: Timers,
+//!     sessions: Arc<SessionStore<Fingerprint>>,
+//! }
+//!
+//! let mut vcl = PerVcl::default();
+//! vcl.sessions.sweep_every(&mut vcl.timers, Duration::from_secs(10));
+//!
+//! // e.g. in a function called from `vcl_recv`, computing the fingerprint once per connection
+//! fn fingerprint(ctx: &Ctx, vcl: &PerVcl) -> Option<String> {
+//!     let fp = vcl
+//!         .sessions
+//!         .get_or_insert_with(ctx, || Fingerprint("...".to_string()))?;
+//!     Some(fp.0.clone())
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use varnish_sys::vcl::Ctx;
+
+use crate::timer::{TimerHandle, Timers};
+
+/// Identifies a client session
+///
+/// The address of the session is combined with its id and opening time, because Varnish reuses the
+/// memory of the closed sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    addr: usize,
+    vxid: u64,
+    t_open: u64,
+}
+
+impl SessionKey {
+    /// The key of the client session of `ctx`, `None` outside of a client task
+    pub fn from_ctx(ctx: &Ctx) -> Option<Self> {
+        let sp = unsafe { ctx.raw.sp.as_ref()? };
+        #[cfg(not(varnishsys_6))]
+        let vxid = sp.vxid.vxid;
+        #[cfg(varnishsys_6)]
+        let vxid = u64::from(sp.vxid);
+        Some(Self {
+            addr: std::ptr::from_ref(sp) as usize,
+            vxid,
+            t_open: sp.t_open.0.to_bits(),
+        })
+    }
+}
+
+struct Entry<T> {
+    value: Arc<T>,
+    last_seen: Instant,
+}
+
+struct Inner<T> {
+    entries: HashMap<SessionKey, Entry<T>>,
+    last_sweep: Instant,
+}
+
+/// Values attached to client sessions, see the [module documentation](self)
+pub struct SessionStore<T> {
+    idle_timeout: Duration,
+    inner: Mutex<Inner<T>>,
+}
+
+impl<T> Debug for SessionStore<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionStore")
+            .field("idle_timeout", &self.idle_timeout)
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Default for SessionStore<T> {
+    /// A store dropping the values of the sessions idle for 10 minutes
+    fn default() -> Self {
+        Self::new(Duration::from_secs(600))
+    }
+}
+
+impl<T> SessionStore<T> {
+    /// Create a store dropping the value of a session that was not used for `idle_timeout`. It
+    /// should be longer than the `timeout_idle` parameter of Varnish, plus the duration of the
+    /// slowest requests, so that the values outlive their sessions.
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<T>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get the value of the session of `ctx`
+    pub fn get(&self, ctx: &Ctx) -> Option<Arc<T>> {
+        self.get_key(SessionKey::from_ctx(ctx)?, Instant::now())
+    }
+
+    /// Get the value of the session of `ctx`, or attach the result of `init` to it. Returns
+    /// `None` outside of a client task.
+    pub fn get_or_insert_with(&self, ctx: &Ctx, init: impl FnOnce() -> T) -> Option<Arc<T>> {
+        let key = SessionKey::from_ctx(ctx)?;
+        Some(self.get_or_insert_key(key, Instant::now(), init))
+    }
+
+    /// Attach `value` to the session of `ctx`, returning the previous one. Fails outside of a
+    /// client task, giving `value` back.
+    pub fn insert(&self, ctx: &Ctx, value: T) -> Result<Option<Arc<T>>, T> {
+        let Some(key) = SessionKey::from_ctx(ctx) else {
+            return Err(value);
+        };
+        Ok(self.insert_key(key, Instant::now(), value))
+    }
+
+    /// Detach the value of the session of `ctx`, e.g. when the client logs out
+    pub fn remove(&self, ctx: &Ctx) -> Option<Arc<T>> {
+        let key = SessionKey::from_ctx(ctx)?;
+        self.lock().entries.remove(&key).map(|e| e.value)
+    }
+
+    fn get_key(&self, key: SessionKey, now: Instant) -> Option<Arc<T>> {
+        let mut inner = self.lock();
+        let entry = inner.entries.get_mut(&key)?;
+        entry.last_seen = now;
+        Some(Arc::clone(&entry.value))
+    }
+
+    fn get_or_insert_key(&self, key: SessionKey, now: Instant, init: impl FnOnce() -> T) -> Arc<T> {
+        let mut inner = self.lock();
+        if let Some(entry) = inner.entries.get_mut(&key) {
+            entry.last_seen = now;
+            return Arc::clone(&entry.value);
+        }
+        self.sweep_locked(&mut inner, now, false);
+        let value = Arc::new(init());
+        inner.entries.insert(
+            key,
+            Entry {
+                value: Arc::clone(&value),
+                last_seen: now,
+            },
+        );
+        value
+    }
+
+    fn insert_key(&self, key: SessionKey, now: Instant, value: T) -> Option<Arc<T>> {
+        let mut inner = self.lock();
+        self.sweep_locked(&mut inner, now, false);
+        let entry = Entry {
+            value: Arc::new(value),
+            last_seen: now,
+        };
+        inner.entries.insert(key, entry).map(|e| e.value)
+    }
+
+    /// Drop the expired entries. Unless `force` is set, this is done at most once per idle
+    /// timeout, so that new sessions do not pay for a sweep each time.
+    fn sweep_locked(&self, inner: &mut Inner<T>, now: Instant, force: bool) -> usize {
+        if !force && now.duration_since(inner.last_sweep) < self.idle_timeout {
+            return 0;
+        }
+        inner.last_sweep = now;
+        let before = inner.entries.len();
+        inner
+            .entries
+            .retain(|_, e| now.duration_since(e.last_seen) < self.idle_timeout);
+        before - inner.entries.len()
+    }
+
+    /// Drop the values of the sessions idle for longer than the idle timeout, returning how many
+    /// were dropped
+    pub fn sweep(&self) -> usize {
+        let mut inner = self.lock();
+        self.sweep_locked(&mut inner, Instant::now(), true)
+    }
+
+    /// The number of sessions with a value, including the expired ones not swept yet
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Send + Sync + 'static> SessionStore<T> {
+    /// Sweep the store every `interval` while `timers` are started. The timer does not keep the
+    /// store alive, and cancels itself once the store is dropped.
+    pub fn sweep_every(self: &Arc<Self>, timers: &mut Timers, interval: Duration) -> TimerHandle {
+        let store = Arc::downgrade(self);
+        let handle: Arc<Mutex<Option<TimerHandle>>> = Arc::default();
+        let this = Arc::clone(&handle);
+        let timer = timers.every(interval, move || match store.upgrade() {
+            Some(store) => {
+                store.sweep();
+            }
+            None => {
+                if let Some(timer) = &*this.lock().unwrap_or_else(PoisonError::into_inner) {
+                    timer.cancel();
+                }
+            }
+        });
+        *handle.lock().unwrap_or_else(PoisonError::into_inner) = Some(timer.clone());
+        timer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(addr: usize, vxid: u64) -> SessionKey {
+        SessionKey {
+            addr,
+            vxid,
+            t_open: 0,
+        }
+    }
+
+    #[test]
+    fn session_store() {
+        let store = SessionStore::new(Duration::from_secs(10));
+        let t0 = Instant::now();
+        let at = |s: u64| t0 + Duration::from_secs(s);
+
+        assert_eq!(*store.get_or_insert_key(key(1, 1), at(0), || 1), 1);
+        assert_eq!(*store.get_or_insert_key(key(1, 1), at(1), || 2), 1);
+        // the same memory reused by another session
+        assert_eq!(*store.get_or_insert_key(key(1, 2), at(2), || 3), 3);
+        assert_eq!(store.insert_key(key(2, 3), at(3), 4).as_deref(), None);
+        assert_eq!(store.insert_key(key(2, 3), at(3), 5).as_deref(), Some(&4));
+        assert_eq!(store.len(), 3);
+
+        // keep the first session alive, the others expire
+        assert_eq!(store.get_key(key(1, 1), at(9)).as_deref(), Some(&1));
+        assert_eq!(*store.get_or_insert_key(key(3, 4), at(14), || 6), 6);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get_key(key(1, 2), at(14)), None);
+
+        // no sweep until another idle timeout went by
+        assert_eq!(*store.get_or_insert_key(key(4, 5), at(20), || 7), 7);
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.sweep_locked(&mut store.lock(), at(40), true), 3);
+        assert!(store.is_empty());
+    }
+}
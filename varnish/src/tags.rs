@@ -0,0 +1,333 @@
+//! Invalidate cached objects by tag, like the `xkey` vmod
+//!
+//! Backends tag their responses with headers like `xkey: product-42 category-7`, or
+//! `Surrogate-Key`. When such a response is fetched, [`TagFetchProcessor`] records its tags in a
+//! [`TagIndex`], and marks the object with an [`ID_HEADER`] header. Then:
+//! - [`TagIndex::purge()`] bans exactly the objects carrying a tag,
+//! - [`TagIndex::soft_purge()`] flags them, so that [`TagIndex::hit()`], called from `vcl_hit`,
+//!   expires them while keeping their grace and keep periods, like `purge.soft()` does.
+//!
+//! ``` rust,no_run
+//! use std::ffi::CStr;
+//! use varnish::tags::{TagFetchProcessor, TagIndex, TagSpec};
+//! use varnish::vcl::{Ctx, Event, FetchFilters, VclError};
+//!
+//! // cached objects outlive VCL reloads, so the index usually lives as long as the process
+//! static INDEX: TagIndex = TagIndex::new();
+//!
+//! struct Xkey;
+//!
+//! impl TagSpec for Xkey {
+//!     fn name() -> &'static CStr {
+//!         c"xkey"
+//!     }
+//!     fn index() -> &'static TagIndex {
+//!         &INDEX
+//!     }
+//! }
+//!
+//! // e.g. the body of the `#[event]` function of a vmod, then use
+//! // `set beresp.filters = "xkey " + beresp.filters;` in `vcl_backend_response`
+//! fn event(event: Event, vfp: &mut FetchFilters) {
+//!     if let Event::Load = event {
+//!         vfp.register::<TagFetchProcessor<Xkey>>();
+//!     }
+//! }
+//!
+//! // e.g. the body of `pub fn purge(ctx: &mut Ctx, tag: &str) -> Result<i64, VclError>`
+//! fn purge(ctx: &mut Ctx, tag: &str) -> Result<usize, VclError> {
+//!     INDEX.purge(ctx, tag)
+//! }
+//!
+//! // e.g. the body of `pub fn hit(ctx: &mut Ctx, id: Option<&str>) -> bool`, called from
+//! // `vcl_hit` with `obj.http.x-tag-id`
+//! fn hit(ctx: &mut Ctx, id: Option<&str>) -> bool {
+//!     INDEX.hit(ctx, id)
+//! }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CStr;
+use std::fmt::Write as _;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use varnish_sys::ffi::{vtim_dur, VRT_purge, VCL_DURATION};
+use varnish_sys::vcl::{
+    BanBuilder, Ctx, FetchProcCtx, FetchProcessor, InitResult, PullResult, StrOp, VclError,
+};
+
+/// The header identifying the objects in the index, added to each tagged object
+pub const ID_HEADER: &str = "x-tag-id";
+
+/// The largest number of objects banned by a single ban expression
+const IDS_PER_BAN: usize = 256;
+
+/// How the tags are harvested, and where they are stored
+pub trait TagSpec: 'static {
+    /// The name of the processor, as used in `beresp.filters`
+    fn name() -> &'static CStr;
+    /// The index storing the tags
+    fn index() -> &'static TagIndex;
+    /// The response headers listing the tags, separated by spaces or commas
+    fn headers() -> &'static [&'static str] {
+        &["xkey", "Surrogate-Key"]
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    tags: Vec<String>,
+    expires: SystemTime,
+    soft_purged: bool,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    tags: BTreeMap<String, BTreeSet<u64>>,
+    objects: BTreeMap<u64, Entry>,
+}
+
+impl Inner {
+    fn remove(&mut self, id: u64) {
+        let Some(entry) = self.objects.remove(&id) else {
+            return;
+        };
+        for tag in entry.tags {
+            if let Some(ids) = self.tags.get_mut(&tag) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.tags.remove(&tag);
+                }
+            }
+        }
+    }
+
+    /// Forget the objects that are no longer in the cache
+    fn prune(&mut self, now: SystemTime) {
+        let expired: Vec<u64> = self
+            .objects
+            .iter()
+            .filter(|(_, e)| e.expires <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove(id);
+        }
+    }
+
+    fn live_ids(&mut self, tag: &str) -> Vec<u64> {
+        self.prune(SystemTime::now());
+        self.tags
+            .get(tag)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// An inverted index of the tags of the cached objects, see the [module documentation](self)
+#[derive(Debug, Default)]
+pub struct TagIndex {
+    inner: Mutex<Inner>,
+    next_id: AtomicU64,
+}
+
+impl TagIndex {
+    /// Create an empty index, usually stored in a `static`
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                tags: BTreeMap::new(),
+                objects: BTreeMap::new(),
+            }),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Record an object with its tags, until `expires`. Returns the id of the object.
+    pub fn insert<'a>(&self, tags: impl IntoIterator<Item = &'a str>, expires: SystemTime) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut tags: Vec<String> = tags.into_iter().map(str::to_string).collect();
+        tags.sort_unstable();
+        tags.dedup();
+        let mut inner = self.lock();
+        if id % 1024 == 0 {
+            inner.prune(SystemTime::now());
+        }
+        for tag in &tags {
+            inner.tags.entry(tag.clone()).or_default().insert(id);
+        }
+        inner.objects.insert(
+            id,
+            Entry {
+                tags,
+                expires,
+                soft_purged: false,
+            },
+        );
+        id
+    }
+
+    /// The number of cached objects carrying `tag`
+    pub fn count(&self, tag: &str) -> usize {
+        self.lock().live_ids(tag).len()
+    }
+
+    /// Ban the objects carrying `tag`, and return their number
+    pub fn purge(&self, ctx: &mut Ctx, tag: &str) -> Result<usize, VclError> {
+        let ids = {
+            let mut inner = self.lock();
+            let ids = inner.live_ids(tag);
+            for id in &ids {
+                inner.remove(*id);
+            }
+            ids
+        };
+        for chunk in ids.chunks(IDS_PER_BAN) {
+            let ban = BanBuilder::new().obj_http(ID_HEADER, StrOp::Match, &ids_regex(chunk));
+            ctx.ban_with(&ban)?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Flag the objects carrying `tag` to be soft-purged on their next hit, and return their
+    /// number
+    pub fn soft_purge(&self, tag: &str) -> usize {
+        let mut inner = self.lock();
+        let ids = inner.live_ids(tag);
+        for id in &ids {
+            if let Some(entry) = inner.objects.get_mut(id) {
+                entry.soft_purged = true;
+            }
+        }
+        ids.len()
+    }
+
+    /// Soft-purge the object being hit if it was flagged by [`TagIndex::soft_purge()`], and return
+    /// `true` if it was.
+    ///
+    /// This must be called from `vcl_hit` with the value of the [`ID_HEADER`] header of the
+    /// object. Like `purge.soft()`, all the variants of the object expire immediately, but keep
+    /// their grace and keep periods.
+    pub fn hit(&self, ctx: &mut Ctx, id: Option<&str>) -> bool {
+        let Some(id) = id.and_then(|v| v.trim().parse::<u64>().ok()) else {
+            return false;
+        };
+        let flagged = self
+            .lock()
+            .objects
+            .get_mut(&id)
+            .is_some_and(|e| std::mem::take(&mut e.soft_purged));
+        if flagged {
+            unsafe {
+                VRT_purge(
+                    ctx.raw,
+                    VCL_DURATION(vtim_dur(0.0)),
+                    VCL_DURATION(vtim_dur(-1.0)),
+                    VCL_DURATION(vtim_dur(-1.0)),
+                );
+            }
+        }
+        flagged
+    }
+}
+
+/// Split a tags header value into tags
+fn split_tags(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(|c: char| c == ',' || c.is_ascii_whitespace())
+        .filter(|v| !v.is_empty())
+}
+
+/// A regular expression matching exactly the ids
+fn ids_regex(ids: &[u64]) -> String {
+    let mut re = String::from("^(");
+    for (i, id) in ids.iter().enumerate() {
+        if i > 0 {
+            re.push('|');
+        }
+        let _ = write!(re, "{id}");
+    }
+    re.push_str(")$");
+    re
+}
+
+/// A [`FetchProcessor`] recording the tags of the fetched objects in [`TagSpec::index()`]
+///
+/// It never touches the body, and removes itself from the pipeline once the tags are recorded.
+#[derive(Debug)]
+pub struct TagFetchProcessor<S: TagSpec> {
+    _spec: PhantomData<S>,
+}
+
+impl<S: TagSpec> FetchProcessor for TagFetchProcessor<S> {
+    fn name() -> &'static CStr {
+        S::name()
+    }
+
+    fn new(vrt_ctx: &mut Ctx, vfp_ctx: &mut FetchProcCtx) -> InitResult<Self> {
+        let Some(beresp) = vrt_ctx.beresp_mut() else {
+            return InitResult::Pass;
+        };
+        let headers = S::headers();
+        let tags: Vec<String> = beresp
+            .iter()
+            .filter(|(name, _)| headers.iter().any(|h| h.eq_ignore_ascii_case(name)))
+            .flat_map(|(_, value)| split_tags(value))
+            .map(str::to_string)
+            .collect();
+        // the backend must not choose the id of an object
+        beresp.unset_header(ID_HEADER);
+        if tags.is_empty() {
+            return InitResult::Pass;
+        }
+        // the object headers are copied from beresp after the processors are initialized
+        let expires = unsafe { vfp_ctx.raw.oc.as_ref() }.map_or(SystemTime::now(), |oc| {
+            let secs = oc.t_origin.0 + f64::from(oc.ttl + oc.grace + oc.keep);
+            UNIX_EPOCH + Duration::try_from_secs_f64(secs).unwrap_or_default()
+        });
+        let id = S::index().insert(tags.iter().map(String::as_str), expires);
+        match beresp.set_header(ID_HEADER, &id.to_string()) {
+            Ok(()) => InitResult::Pass,
+            Err(e) => InitResult::Err(e),
+        }
+    }
+
+    fn pull(&mut self, ctx: &mut FetchProcCtx, buf: &mut [u8]) -> PullResult {
+        ctx.pull(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index() {
+        let index = TagIndex::new();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        let a = index.insert(split_tags("a, b b"), later);
+        let b = index.insert(split_tags("b"), later);
+        index.insert(split_tags("a"), SystemTime::now() - Duration::from_secs(1));
+        assert_eq!(
+            (index.count("a"), index.count("b"), index.count("c")),
+            (1, 2, 0)
+        );
+        assert_eq!(index.lock().objects.len(), 2);
+
+        assert_eq!(index.soft_purge("b"), 2);
+        assert!(index.lock().objects[&a].soft_purged);
+        index.lock().remove(b);
+        assert_eq!(index.count("b"), 1);
+        index.lock().remove(a);
+        assert!(index.lock().tags.is_empty());
+
+        assert_eq!(ids_regex(&[3, 14, 15]), "^(3|14|15)$");
+    }
+}
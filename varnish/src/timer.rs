@@ -0,0 +1,482 @@
+//! Run callbacks periodically, or once after a delay, outside of any request
+//!
+//! [`Timers`] owns a set of scheduled callbacks, either closures or types implementing [`Tick`].
+//! It is usually stored in the `#[shared_per_vcl]` state, and fed the VCL events so that its
+//! timers only run while the VCL is warm: they start on `Warm`, and are cancelled on `Cold` and
+//! `Discard`. The callbacks run in a small pool of background threads managed by this crate,
+//! which only exists while at least one [`Timers`] is started.
+//!
+//! ``` rust
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use varnish::timer::Timers;
+//! use varnish::vcl::Event;
+//!
+//! #[derive(Default)]
+//! struct PerVcl {
+//!     timers: Timers,
+//!     refreshes: Arc<AtomicU64>,
+//! }
+//!
+//! // e.g. the body of `#[event] pub fn event(event: Event, #[shared_per_vcl] ...)`
+//! fn event(event: Event, vcl: &mut Option<Box<PerVcl>>) {
+//!     let vcl = vcl.get_or_insert_with(Box::default);
+//!     if let Event::Load = event {
+//!         let refreshes = Arc::clone(&vcl.refreshes);
+//!         vcl.timers.every(Duration::from_secs(30), move || {
+//!             // reload a configuration file, flush statistics, ...
+//!             refreshes.fetch_add(1, Ordering::Relaxed);
+//!         });
+//!     }
+//!     vcl.timers.on_event(event);
+//! }
+//! # let mut vcl = None;
+//! # event(Event::Load, &mut vcl);
+//! # event(Event::Warm, &mut vcl);
+//! # assert!(vcl.as_ref().unwrap().timers.is_started());
+//! # event(Event::Cold, &mut vcl);
+//! ```
+//!
+//! Callbacks have no access to a [`Ctx`](crate::vcl::Ctx), and must not block for long: a pool
+//! thread running a slow callback is not available to the other timers. A callback that panics
+//! is cancelled.
+
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::BinaryHeap;
+use std::fmt::{Debug, Formatter};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use varnish_sys::vcl::Event;
+
+/// The number of threads running the callbacks
+const WORKERS: usize = 2;
+
+/// A callback run by a [`Timers`]
+///
+/// It is implemented for closures, and can be implemented by types holding their own state.
+pub trait Tick: Send + Sync + 'static {
+    fn tick(&self);
+}
+
+impl<F: Fn() + Send + Sync + 'static> Tick for F {
+    fn tick(&self) {
+        self();
+    }
+}
+
+struct Timer {
+    tick: Box<dyn Tick>,
+    delay: Duration,
+    /// `None` for one-shot timers
+    interval: Option<Duration>,
+    /// Changed each time the timer is armed or stopped, to ignore the outdated queue entries
+    generation: AtomicU64,
+    cancelled: AtomicBool,
+    runs: AtomicU64,
+}
+
+impl Debug for Timer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Timer")
+            .field("delay", &self.delay)
+            .field("interval", &self.interval)
+            .field("cancelled", &self.cancelled)
+            .field("runs", &self.runs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Timer {
+    fn is_current(&self, generation: u64) -> bool {
+        !self.cancelled.load(Ordering::Relaxed)
+            && self.generation.load(Ordering::Relaxed) == generation
+    }
+
+    /// Schedule the first run of the timer
+    fn arm(self: &Arc<Self>, shared: &Shared) {
+        if !self.cancelled.load(Ordering::Relaxed) {
+            let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+            shared.push(self, Instant::now() + self.delay, generation);
+        }
+    }
+
+    /// Invalidate the scheduled run of the timer, if any
+    fn disarm(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn run(self: &Arc<Self>, shared: &Shared, generation: u64) {
+        if !self.is_current(generation) {
+            return;
+        }
+        let ok = catch_unwind(AssertUnwindSafe(|| self.tick.tick())).is_ok();
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        match self.interval {
+            Some(interval) if ok => {
+                if self.is_current(generation) {
+                    shared.push(self, Instant::now() + interval, generation);
+                }
+            }
+            _ => self.cancelled.store(true, Ordering::Relaxed),
+        }
+    }
+}
+
+/// A scheduled run of a timer
+struct Entry {
+    at: Instant,
+    seq: u64,
+    generation: u64,
+    timer: Weak<Timer>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    entries: BinaryHeap<Reverse<Entry>>,
+    seq: u64,
+    shutdown: bool,
+}
+
+/// The state shared by the threads of the pool
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    wakeup: Condvar,
+}
+
+impl Debug for Shared {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shared").finish_non_exhaustive()
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push(&self, timer: &Arc<Timer>, at: Instant, generation: u64) {
+        let mut queue = self.lock();
+        queue.seq += 1;
+        let seq = queue.seq;
+        queue.entries.push(Reverse(Entry {
+            at,
+            seq,
+            generation,
+            timer: Arc::downgrade(timer),
+        }));
+        self.wakeup.notify_one();
+    }
+
+    /// Wait for the next due entry, and hand it to the workers until the pool shuts down
+    fn schedule(&self, jobs: &Sender<(Arc<Timer>, u64)>) {
+        let mut queue = self.lock();
+        loop {
+            if queue.shutdown {
+                return;
+            }
+            let now = Instant::now();
+            let wait = match queue.entries.peek() {
+                Some(Reverse(entry)) if entry.at <= now => {
+                    let Reverse(entry) = queue.entries.pop().expect("peeked entry");
+                    if let Some(timer) = entry.timer.upgrade() {
+                        let _ = jobs.send((timer, entry.generation));
+                    }
+                    continue;
+                }
+                Some(Reverse(entry)) => Some(entry.at - now),
+                None => None,
+            };
+            queue = match wait {
+                Some(wait) => {
+                    self.wakeup
+                        .wait_timeout(queue, wait)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .wakeup
+                    .wait(queue)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+}
+
+fn work(shared: &Shared, jobs: &Mutex<Receiver<(Arc<Timer>, u64)>>) {
+    IN_POOL.set(true);
+    loop {
+        let job = jobs.lock().unwrap_or_else(PoisonError::into_inner).recv();
+        let Ok((timer, generation)) = job else {
+            return;
+        };
+        timer.run(shared, generation);
+    }
+}
+
+thread_local! {
+    /// Set in the threads of the pool, which cannot wait for themselves to exit
+    static IN_POOL: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// The background threads, running while at least one [`Timers`] is started
+struct Pool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+    users: usize,
+}
+
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+
+fn lock_pool() -> MutexGuard<'static, Option<Pool>> {
+    POOL.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Pool {
+    fn spawn() -> Self {
+        let shared = Arc::new(Shared::default());
+        let (tx, rx) = channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let mut threads = Vec::with_capacity(WORKERS + 1);
+        let scheduler = Arc::clone(&shared);
+        threads.push(
+            thread::Builder::new()
+                .name("varnish-timer".to_string())
+                .spawn(move || scheduler.schedule(&tx))
+                .expect("failed to spawn the timer thread"),
+        );
+        for idx in 0..WORKERS {
+            let shared = Arc::clone(&shared);
+            let rx = Arc::clone(&rx);
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("varnish-timer-{idx}"))
+                    .spawn(move || work(&shared, &rx))
+                    .expect("failed to spawn a timer worker"),
+            );
+        }
+        Self {
+            shared,
+            threads,
+            users: 0,
+        }
+    }
+
+    fn acquire() -> Arc<Shared> {
+        let mut pool = lock_pool();
+        let pool = pool.get_or_insert_with(Self::spawn);
+        pool.users += 1;
+        Arc::clone(&pool.shared)
+    }
+
+    /// Stop the threads once the last user is gone, and wait for the running callbacks, so that
+    /// no code of the vmod runs after it is unloaded
+    fn release() {
+        let mut guard = lock_pool();
+        let Some(pool) = guard.as_mut() else {
+            return;
+        };
+        pool.users -= 1;
+        if pool.users > 0 {
+            return;
+        }
+        let pool = guard.take().expect("pool checked above");
+        drop(guard);
+        pool.shared.lock().shutdown = true;
+        pool.shared.wakeup.notify_all();
+        if !IN_POOL.get() {
+            for thread in pool.threads {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// A handle to a timer scheduled by a [`Timers`]
+#[derive(Debug, Clone)]
+pub struct TimerHandle(Arc<Timer>);
+
+impl TimerHandle {
+    /// Cancel the timer. A run already in progress is not interrupted.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+        self.0.disarm();
+    }
+
+    /// `true` if the timer was cancelled, has panicked, or was a one-shot timer that already ran
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// The number of times the callback was run
+    pub fn runs(&self) -> u64 {
+        self.0.runs.load(Ordering::Relaxed)
+    }
+}
+
+/// A set of timers that run while started, see the [module documentation](self)
+///
+/// Timers added before [`Timers::start()`] are armed when it is called, and timers added while
+/// started are armed right away. Each start runs the periodic timers after their full interval,
+/// and the one-shot timers that have not run yet after their full delay. Dropping a `Timers`
+/// stops it.
+#[derive(Debug, Default)]
+pub struct Timers {
+    timers: Vec<Arc<Timer>>,
+    pool: Option<Arc<Shared>>,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `tick` every `interval`
+    pub fn every(&mut self, interval: Duration, tick: impl Tick) -> TimerHandle {
+        self.add(interval, Some(interval), Box::new(tick))
+    }
+
+    /// Run `tick` once, `delay` after the timers are started
+    pub fn once(&mut self, delay: Duration, tick: impl Tick) -> TimerHandle {
+        self.add(delay, None, Box::new(tick))
+    }
+
+    fn add(
+        &mut self,
+        delay: Duration,
+        interval: Option<Duration>,
+        tick: Box<dyn Tick>,
+    ) -> TimerHandle {
+        self.timers.retain(|t| !t.cancelled.load(Ordering::Relaxed));
+        let timer = Arc::new(Timer {
+            tick,
+            delay,
+            interval,
+            generation: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            runs: AtomicU64::new(0),
+        });
+        if let Some(shared) = &self.pool {
+            timer.arm(shared);
+        }
+        self.timers.push(Arc::clone(&timer));
+        TimerHandle(timer)
+    }
+
+    /// Start the timers, unless they are already started
+    pub fn start(&mut self) {
+        if self.pool.is_none() {
+            let shared = Pool::acquire();
+            for timer in &self.timers {
+                timer.arm(&shared);
+            }
+            self.pool = Some(shared);
+        }
+    }
+
+    /// Stop the timers, and wait for the running callbacks if no other [`Timers`] is started
+    pub fn stop(&mut self) {
+        if self.pool.take().is_some() {
+            for timer in &self.timers {
+                timer.disarm();
+            }
+            Pool::release();
+        }
+    }
+
+    /// `true` between [`Timers::start()`] and [`Timers::stop()`]
+    pub fn is_started(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    /// Start the timers on [`Event::Warm`], and stop them on [`Event::Cold`] and [`Event::Discard`]
+    pub fn on_event(&mut self, event: Event) {
+        match event {
+            Event::Warm => self.start(),
+            Event::Cold | Event::Discard => self.stop(),
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Timers {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[test]
+    fn timers() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut timers = Timers::new();
+        let counter = Arc::clone(&count);
+        let periodic = timers.every(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let once = timers.once(Duration::from_millis(1), || {});
+        let panics = timers.every(Duration::from_millis(1), || panic!("tick"));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(periodic.runs(), 0);
+
+        timers.on_event(Event::Warm);
+        assert!(timers.is_started());
+        thread::sleep(Duration::from_millis(100));
+        timers.on_event(Event::Cold);
+        assert!(!timers.is_started());
+        assert!(periodic.runs() >= 2);
+        assert_eq!(once.runs(), 1);
+        assert!(once.is_cancelled());
+        assert_eq!(panics.runs(), 1);
+        assert!(panics.is_cancelled());
+
+        let runs = periodic.runs();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(periodic.runs(), runs);
+        assert_eq!(
+            count.load(Ordering::Relaxed),
+            usize::try_from(runs).unwrap()
+        );
+
+        let cancelled = timers.every(Duration::from_millis(1), || {});
+        cancelled.cancel();
+        timers.start();
+        thread::sleep(Duration::from_millis(20));
+        assert!(periodic.runs() > runs);
+        assert_eq!(once.runs(), 1);
+        assert_eq!(cancelled.runs(), 0);
+        drop(timers);
+        assert!(lock_pool().is_none());
+    }
+}
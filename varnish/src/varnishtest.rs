@@ -2,13 +2,18 @@ use std::env;
 use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
 use std::ffi::OsString;
 use std::fmt::Write as _;
+use std::fs;
 use std::io::{stderr, stdout, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, Instant};
 
 use glob::glob;
 
 /// Run all tests that match the glob pattern
+///
+/// If the `VARNISHTEST_JSON` or `VARNISHTEST_JUNIT` environment variables are set, the results
+/// are also written to the files they name, see [`VtcReport`].
 pub fn run_all_tests(
     ld_library_paths: &str,
     vmod_name: &str,
@@ -16,22 +21,22 @@ pub fn run_all_tests(
     timeout: &str,
     debug: bool,
 ) -> Result<(), String> {
-    let vmod_lib_name = format!("{DLL_PREFIX}{vmod_name}{DLL_SUFFIX}");
-    let vmod_path = find_vmod_lib(&vmod_lib_name, ld_library_paths)?;
-    let mut found = false;
-    let mut failed = Vec::new();
-    for test in
-        glob(glob_path).map_err(|e| format!("Failed to find any tests in '{glob_path}': {e}"))?
-    {
-        found = true;
-        let file = test.map_err(|e| format!("Failed to get test path: {e}"))?;
-        if let Err(err) = run_varnish_test(&vmod_path, &file, timeout, debug) {
-            failed.push(format!("{}: {err}", file.display()));
-            eprintln!("{err}");
-        }
+    let report = run_all_tests_report(ld_library_paths, vmod_name, glob_path, timeout, debug)?;
+    if let Some(path) = env::var_os("VARNISHTEST_JSON") {
+        fs::write(&path, report.to_json())
+            .map_err(|e| format!("Failed to write {}: {e}", Path::new(&path).display()))?;
+    }
+    if let Some(path) = env::var_os("VARNISHTEST_JUNIT") {
+        fs::write(&path, report.to_junit())
+            .map_err(|e| format!("Failed to write {}: {e}", Path::new(&path).display()))?;
     }
 
-    if !found {
+    let failed: Vec<_> = report
+        .tests
+        .iter()
+        .filter(|t| t.status == VtcStatus::Failed)
+        .collect();
+    if report.tests.is_empty() {
         Err(format!("No tests found in '{glob_path}'"))
     } else if failed.is_empty() {
         Ok(())
@@ -41,53 +46,418 @@ pub fn run_all_tests(
             // If we only had one failed test, we already printed the error
             let _ = write!(err, "{} tests failed:", failed.len());
             for f in failed {
-                let _ = write!(err, "{f}");
+                let _ = write!(err, "\n{}", f.file.display());
             }
         }
         Err(err)
     }
 }
 
+/// Run all tests that match the glob pattern, and collect their results. Failed tests are not an
+/// error, only being unable to run them is.
+pub fn run_all_tests_report(
+    ld_library_paths: &str,
+    vmod_name: &str,
+    glob_path: &str,
+    timeout: &str,
+    debug: bool,
+) -> Result<VtcReport, String> {
+    let vmod_lib_name = format!("{DLL_PREFIX}{vmod_name}{DLL_SUFFIX}");
+    let vmod_path = find_vmod_lib(&vmod_lib_name, ld_library_paths)?;
+    let mut report = VtcReport {
+        suite: vmod_name.to_string(),
+        varnishd_version: varnishd_version(),
+        tests: Vec::new(),
+    };
+    for test in
+        glob(glob_path).map_err(|e| format!("Failed to find any tests in '{glob_path}': {e}"))?
+    {
+        let file = test.map_err(|e| format!("Failed to get test path: {e}"))?;
+        let (result, cmd) = run_test_case(&vmod_path, &file, timeout, debug)?;
+        if result.status == VtcStatus::Failed {
+            eprintln!("varnishtest {} failed\n{cmd:?}", file.display());
+        }
+        report.tests.push(result);
+    }
+    Ok(report)
+}
+
 pub fn run_varnish_test(
     vmod_path: &Path,
     testfile: &Path,
     timeout: &str,
     debug: bool,
 ) -> Result<(), String> {
+    let (result, cmd) = run_test_case(vmod_path, testfile, timeout, debug)?;
+    if result.status == VtcStatus::Failed {
+        Err(format!(
+            "varnishtest {} failed\n{cmd:?}",
+            testfile.display()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Run one test, returning the command for the error messages
+fn run_test_case(
+    vmod_path: &Path,
+    testfile: &Path,
+    timeout: &str,
+    debug: bool,
+) -> Result<(VtcTestResult, Command), String> {
     eprintln!("Running varnishtest {}", testfile.display());
-    let mut cmd = Command::new("varnishtest");
+    let mut cmd = varnishtest_cmd(vmod_path, testfile, timeout);
     if debug {
         // Keep output, and run in verbose mode
         cmd.arg("-L").arg("-v");
     }
 
+    let start = Instant::now();
+    let output = cmd
+        .output()
+        .map_err(|e| format!("Failed to run varnishtest:\n{cmd:?}\n{e}"))?;
+    let duration = start.elapsed();
+
+    if debug || !output.status.success() {
+        stdout().write_all(&output.stdout).unwrap();
+        stderr().write_all(&output.stderr).unwrap();
+    }
+
+    let status = if output.status.success() {
+        VtcStatus::Passed
+    } else if output.status.code().unwrap_or_default() == 77 {
+        eprintln!("varnishtest exited with code 77, skipping");
+        VtcStatus::Skipped
+    } else {
+        VtcStatus::Failed
+    };
+    let excerpt = if status == VtcStatus::Failed {
+        failure_excerpt(&String::from_utf8_lossy(&output.stdout))
+    } else {
+        String::new()
+    };
+    let result = VtcTestResult {
+        file: testfile.to_path_buf(),
+        status,
+        duration,
+        excerpt,
+    };
+    Ok((result, cmd))
+}
+
+/// The first line printed by `varnishd -V`, e.g. `varnishd (varnish-7.6.1 revision ...)`
+fn varnishd_version() -> Option<String> {
+    let output = Command::new("varnishd").arg("-V").output().ok()?;
+    let text = [output.stderr, output.stdout].concat();
+    String::from_utf8_lossy(&text)
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .map(str::to_string)
+}
+
+/// The lines of a failed run explaining the failure: the `----` lines of varnishtest, with the few
+/// lines before the first one, or the end of the output if there are none
+fn failure_excerpt(log: &str) -> String {
+    const CONTEXT: usize = 5;
+    const TAIL: usize = 20;
+    let lines: Vec<_> = log.lines().collect();
+    let excerpt = match lines.iter().position(|l| l.starts_with("----")) {
+        Some(first) => {
+            let mut excerpt = lines[first.saturating_sub(CONTEXT)..first].to_vec();
+            excerpt.extend(lines[first..].iter().filter(|l| l.starts_with("----")));
+            excerpt
+        }
+        None => lines[lines.len().saturating_sub(TAIL)..].to_vec(),
+    };
+    excerpt.join("\n")
+}
+
+/// The outcome of a VTC test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VtcStatus {
+    Passed,
+    Failed,
+    /// varnishtest exited with code 77, e.g. a `feature` command was not satisfied
+    Skipped,
+}
+
+/// The result of one VTC file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VtcTestResult {
+    pub file: PathBuf,
+    pub status: VtcStatus,
+    pub duration: Duration,
+    /// The lines of the output explaining the failure, empty unless the test failed
+    pub excerpt: String,
+}
+
+/// The results of [`run_all_tests_report()`], to be read by CI systems instead of the output of
+/// varnishtest
+///
+/// [`run_all_tests()`], and so [`run_vtc_tests!`](crate::run_vtc_tests), write the report as
+/// JSON to the file named by the `VARNISHTEST_JSON` environment variable, and as `JUnit` XML to the
+/// one named by `VARNISHTEST_JUNIT`, e.g. `VARNISHTEST_JUNIT=$PWD/junit.xml cargo test`. Relative
+/// paths are relative to the directory of the crate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VtcReport {
+    /// The name of the vmod
+    pub suite: String,
+    /// The version of the `varnishd` found in the `PATH`, if it could be run
+    pub varnishd_version: Option<String>,
+    pub tests: Vec<VtcTestResult>,
+}
+
+impl VtcReport {
+    /// The report as a JSON object, e.g.
+    /// `{"suite":"vmod","varnishd":"...","tests":[{"file":"tests/a.vtc","status":"failed","duration":0.52,"excerpt":"..."}]}`
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"suite\":");
+        push_json_string(&mut json, &self.suite);
+        json.push_str(",\"varnishd\":");
+        match &self.varnishd_version {
+            Some(version) => push_json_string(&mut json, version),
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"tests\":[");
+        for (i, test) in self.tests.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"file\":");
+            push_json_string(&mut json, &test.file.to_string_lossy());
+            let _ = write!(
+                json,
+                ",\"status\":\"{}\",\"duration\":{:.3},\"excerpt\":",
+                test.status.as_str(),
+                test.duration.as_secs_f64()
+            );
+            push_json_string(&mut json, &test.excerpt);
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// The report as a `JUnit` XML test suite, with one test case per VTC file
+    pub fn to_junit(&self) -> String {
+        let count = |status| self.tests.iter().filter(|t| t.status == status).count();
+        let total: Duration = self.tests.iter().map(|t| t.duration).sum();
+        let suite = xml_escape(&self.suite);
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            r#"<testsuite name="{suite}" tests="{}" failures="{}" skipped="{}" time="{:.3}">"#,
+            self.tests.len(),
+            count(VtcStatus::Failed),
+            count(VtcStatus::Skipped),
+            total.as_secs_f64()
+        );
+        if let Some(version) = &self.varnishd_version {
+            let _ = writeln!(
+                xml,
+                r#"  <properties><property name="varnishd" value="{}"/></properties>"#,
+                xml_escape(version)
+            );
+        }
+        for test in &self.tests {
+            let _ = write!(
+                xml,
+                r#"  <testcase name="{}" classname="{suite}" time="{:.3}""#,
+                xml_escape(&test.file.to_string_lossy()),
+                test.duration.as_secs_f64()
+            );
+            match test.status {
+                VtcStatus::Passed => xml.push_str("/>\n"),
+                VtcStatus::Skipped => xml.push_str(">\n    <skipped/>\n  </testcase>\n"),
+                VtcStatus::Failed => {
+                    let _ = write!(
+                        xml,
+                        ">\n    <failure message=\"varnishtest failed\">{}</failure>\n  </testcase>\n",
+                        xml_escape(&test.excerpt)
+                    );
+                }
+            }
+        }
+        xml.push_str("</testsuite>\n");
+        xml
+    }
+}
+
+impl VtcStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            VtcStatus::Passed => "passed",
+            VtcStatus::Failed => "failed",
+            VtcStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// Append `value` to `json` as a JSON string
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", u32::from(c));
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+fn xml_escape(value: &str) -> String {
+    let mut xml = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => xml.push_str("&amp;"),
+            '<' => xml.push_str("&lt;"),
+            '>' => xml.push_str("&gt;"),
+            '"' => xml.push_str("&quot;"),
+            // The control characters other than the whitespaces are not allowed in XML 1.0
+            c if c.is_control() && !matches!(c, '\n' | '\r' | '\t') => {}
+            c => xml.push(c),
+        }
+    }
+    xml
+}
+
+fn varnishtest_cmd(vmod_path: &Path, testfile: &Path, timeout: &str) -> Command {
+    let mut cmd = Command::new("varnishtest");
     let mut vmod_arg = OsString::from("vmod=");
     vmod_arg.push(vmod_path);
-
     cmd.arg("-D")
         .arg(vmod_arg)
         .arg(testfile)
         .env("VARNISHTEST_DURATION", timeout);
+    cmd
+}
 
+/// Run a single test in verbose mode, and keep its output to check the VSL records it produced,
+/// see [`expect_vsl!`](crate::expect_vsl). Use it with [`vtc_output!`](crate::vtc_output).
+pub fn run_varnish_test_output(
+    ld_library_paths: &str,
+    vmod_name: &str,
+    testfile: &str,
+    timeout: &str,
+) -> Result<VtcOutput, String> {
+    let vmod_lib_name = format!("{DLL_PREFIX}{vmod_name}{DLL_SUFFIX}");
+    let vmod_path = find_vmod_lib(&vmod_lib_name, ld_library_paths)?;
+    eprintln!("Running varnishtest {testfile}");
+    let mut cmd = varnishtest_cmd(&vmod_path, Path::new(testfile), timeout);
+    cmd.arg("-v");
     let output = cmd
         .output()
         .map_err(|e| format!("Failed to run varnishtest:\n{cmd:?}\n{e}"))?;
 
-    if debug || !output.status.success() {
+    let skipped = output.status.code() == Some(77);
+    if !output.status.success() && !skipped {
         stdout().write_all(&output.stdout).unwrap();
         stderr().write_all(&output.stderr).unwrap();
+        return Err(format!("varnishtest {testfile} failed\n{cmd:?}"));
     }
-
-    if output.status.success() {
-        Ok(())
-    } else if output.status.code().unwrap_or_default() == 77 {
+    if skipped {
         eprintln!("varnishtest exited with code 77, skipping");
-        Ok(())
-    } else {
-        Err(format!(
-            "varnishtest {} failed\n{cmd:?}",
-            testfile.display()
-        ))
+    }
+    Ok(VtcOutput {
+        log: String::from_utf8_lossy(&output.stdout).into_owned(),
+        skipped,
+    })
+}
+
+/// A VSL record, as logged by `varnishtest -v`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VtcRecord<'a> {
+    /// The name of the `varnish` instance, e.g. `v1`
+    pub instance: &'a str,
+    pub vxid: u64,
+    /// The tag, e.g. `VCL_Log`
+    pub tag: &'a str,
+    /// `c` for client records, `b` for backend ones, and `-` for the others
+    pub kind: char,
+    pub value: &'a str,
+}
+
+/// The output of a successful `varnishtest` run
+#[derive(Debug, Clone)]
+pub struct VtcOutput {
+    log: String,
+    skipped: bool,
+}
+
+impl VtcOutput {
+    /// Wrap the output of `varnishtest -v`
+    pub fn new(log: String) -> Self {
+        Self {
+            log,
+            skipped: false,
+        }
+    }
+
+    /// Check if varnishtest skipped the test, in which case there are no records
+    pub fn is_skipped(&self) -> bool {
+        self.skipped
+    }
+
+    /// The raw output of varnishtest
+    pub fn log(&self) -> &str {
+        &self.log
+    }
+
+    /// The VSL records of all the `varnish` instances, in the order they were logged
+    pub fn records(&self) -> impl Iterator<Item = VtcRecord<'_>> {
+        self.log.lines().filter_map(|line| {
+            // e.g. "**** v1    vsl|       1001 VCL_Log         c hello"
+            let (prefix, rest) = line.split_once("vsl|")?;
+            let instance = prefix.trim_start_matches('*').trim();
+            let (vxid, rest) = rest.trim_start().split_once(' ')?;
+            let (tag, rest) = rest.trim_start().split_once(' ')?;
+            let rest = rest.trim_start();
+            let kind = rest.chars().next()?;
+            Some(VtcRecord {
+                instance,
+                vxid: vxid.parse().ok()?,
+                tag,
+                kind,
+                value: rest[kind.len_utf8()..]
+                    .strip_prefix(' ')
+                    .unwrap_or_default(),
+            })
+        })
+    }
+
+    /// Count the records with `tag` whose value contains `value`
+    pub fn count(&self, tag: &str, value: &str) -> usize {
+        self.records()
+            .filter(|r| r.tag == tag && r.value.contains(value))
+            .count()
+    }
+
+    /// Check that exactly `expected` records with `tag` contain `value`.
+    ///
+    /// Expectations always pass if the test was skipped.
+    pub fn expect_vsl(&self, tag: &str, value: &str, expected: usize) -> Result<(), String> {
+        if self.skipped {
+            return Ok(());
+        }
+        let count = self.count(tag, value);
+        if count == expected {
+            return Ok(());
+        }
+        let mut err =
+            format!("expected {expected} {tag} record(s) containing {value:?}, found {count}");
+        for r in self.records().filter(|r| r.tag == tag) {
+            let _ = write!(err, "\n  {} {} {} {}", r.instance, r.vxid, r.kind, r.value);
+        }
+        Err(err)
     }
 }
 
@@ -100,3 +470,107 @@ pub fn find_vmod_lib(vmod_lib_name: &str, ld_library_paths: &str) -> Result<Path
             format!("Unable to find {vmod_lib_name} in {ld_library_paths}\nHave you built your vmod first?")
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vtc_records() {
+        let output = VtcOutput::new(
+            "\
+**** v1    vsl|          0 CLI             - Rd vcl.load
+**** v1    vsl|       1001 VCL_Log         c hello world
+**** v1    vsl|       1002 VCL_Log         b hello
+**** v1    vsl|       1002 Debug           b
+**** c1    rxresp
+"
+            .to_string(),
+        );
+        let records: Vec<_> = output.records().collect();
+        assert_eq!(records.len(), 4);
+        assert_eq!(
+            records[1],
+            VtcRecord {
+                instance: "v1",
+                vxid: 1001,
+                tag: "VCL_Log",
+                kind: 'c',
+                value: "hello world",
+            }
+        );
+        assert_eq!(records[3].value, "");
+        assert_eq!(output.count("VCL_Log", "hello"), 2);
+        assert!(output.expect_vsl("VCL_Log", "world", 1).is_ok());
+        assert!(output.expect_vsl("Debug", "", 1).is_ok());
+        let err = output.expect_vsl("VCL_Log", "hello", 1).unwrap_err();
+        assert!(err.contains("found 2"), "{err}");
+    }
+
+    #[test]
+    fn vtc_report() {
+        let log = "\
+**** v1    vsl|       1001 VCL_Log         c hello
+**   c1    Waiting for client
+---- c1    EXPECT resp.status (503) == \"200\" failed
+*    top   RESETTING after tests/b.vtc
+---- top   TEST FILE FAILED
+";
+        let excerpt = failure_excerpt(log);
+        assert!(excerpt.starts_with("**** v1"), "{excerpt}");
+        assert!(
+            excerpt.ends_with("---- top   TEST FILE FAILED"),
+            "{excerpt}"
+        );
+        assert!(!excerpt.contains("RESETTING"), "{excerpt}");
+
+        let report = VtcReport {
+            suite: "vmod_x".to_string(),
+            varnishd_version: Some("varnishd (varnish-7.6.1 revision 123)".to_string()),
+            tests: vec![
+                VtcTestResult {
+                    file: PathBuf::from("tests/a.vtc"),
+                    status: VtcStatus::Passed,
+                    duration: Duration::from_millis(1500),
+                    excerpt: String::new(),
+                },
+                VtcTestResult {
+                    file: PathBuf::from("tests/b.vtc"),
+                    status: VtcStatus::Failed,
+                    duration: Duration::from_millis(250),
+                    excerpt: "---- c1 \"200\" <failed>".to_string(),
+                },
+                VtcTestResult {
+                    file: PathBuf::from("tests/c.vtc"),
+                    status: VtcStatus::Skipped,
+                    duration: Duration::ZERO,
+                    excerpt: String::new(),
+                },
+            ],
+        };
+        assert_eq!(
+            report.to_json(),
+            concat!(
+                r#"{"suite":"vmod_x","varnishd":"varnishd (varnish-7.6.1 revision 123)","tests":["#,
+                r#"{"file":"tests/a.vtc","status":"passed","duration":1.500,"excerpt":""},"#,
+                r#"{"file":"tests/b.vtc","status":"failed","duration":0.250,"excerpt":"---- c1 \"200\" <failed>"},"#,
+                r#"{"file":"tests/c.vtc","status":"skipped","duration":0.000,"excerpt":""}]}"#,
+            )
+        );
+        assert_eq!(
+            report.to_junit(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuite name="vmod_x" tests="3" failures="1" skipped="1" time="1.750">
+  <properties><property name="varnishd" value="varnishd (varnish-7.6.1 revision 123)"/></properties>
+  <testcase name="tests/a.vtc" classname="vmod_x" time="1.500"/>
+  <testcase name="tests/b.vtc" classname="vmod_x" time="0.250">
+    <failure message="varnishtest failed">---- c1 &quot;200&quot; &lt;failed&gt;</failure>
+  </testcase>
+  <testcase name="tests/c.vtc" classname="vmod_x" time="0.000">
+    <skipped/>
+  </testcase>
+</testsuite>
+"#
+        );
+    }
+}
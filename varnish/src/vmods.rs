@@ -0,0 +1,402 @@
+//! Call the functions of the vmods bundled with Varnish
+//!
+//! Some of the vmods shipped with Varnish already do what a Rust vmod needs, e.g. `std.duration()`
+//! parsing durations exactly like VCL does, or the `directors.round_robin` director. Rather than
+//! reimplementing them, [`Std`] and [`Directors`] load the shared library of the vmod and call its
+//! C functions with typed arguments.
+//!
+//! The libraries are looked up in the usual `vmod_path` directories, see [`VmodLib::open()`], or
+//! in given ones. Loading fails with an error, rather than crashing, if the library is missing,
+//! lacks one of the functions, or was built for another VRT version than this crate. Varnish
+//! loads its own copy of the vmods imported in VCL, the instance loaded here is separate, which
+//! does not matter for these stateless functions.
+//!
+//! ``` rust
+//! use std::time::Duration;
+//! use varnish::vcl::Ctx;
+//! use varnish::vmods::Std;
+//!
+//! // e.g. loaded once when the VCL is loaded, and kept in the `#[shared_per_vcl]` state
+//! fn load() -> Option<Std> {
+//!     Std::load().ok()
+//! }
+//!
+//! // e.g. in a function called from `vcl_recv`
+//! fn grace(ctx: &mut Ctx, std: &Std) -> Duration {
+//!     let value = ctx
+//!         .req()
+//!         .and_then(|req| req.header("x-grace"))
+//!         .unwrap_or("")
+//!         .to_string();
+//!     std.duration(ctx, &value, Duration::from_secs(10))
+//!         .unwrap_or(Duration::from_secs(10))
+//! }
+//! ```
+
+use std::ffi::{c_char, c_void, CString};
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use libloading::Library;
+use varnish_sys::ffi::{
+    vmod_data, vrt_ctx, VCL_BACKEND, VCL_BOOL, VCL_DURATION, VCL_INT, VCL_IP, VCL_REAL, VCL_STRING,
+    VRT_MAJOR_VERSION, VRT_MINOR_VERSION,
+};
+use varnish_sys::vcl::{Ctx, IntoVCL, VclError};
+
+use crate::func_table::FuncTable;
+
+/// Where Varnish installs its vmods on the common distributions, the default `vmod_path`
+pub const DEFAULT_VMOD_DIRS: &[&str] = &[
+    "/usr/lib/varnish/vmods",
+    "/usr/lib64/varnish/vmods",
+    "/usr/lib/x86_64-linux-gnu/varnish/vmods",
+    "/usr/lib/aarch64-linux-gnu/varnish/vmods",
+    "/usr/local/lib/varnish/vmods",
+];
+
+/// The shared library of a vmod, e.g. `libvmod_std.so`
+#[derive(Clone)]
+pub struct VmodLib {
+    name: String,
+    path: PathBuf,
+    table: Arc<FuncTable>,
+    // dropped after `table`, which points into it
+    lib: Arc<Library>,
+}
+
+impl Debug for VmodLib {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VmodLib")
+            .field("name", &self.name)
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VmodLib {
+    /// Load the vmod called `name` from the first of the [`DEFAULT_VMOD_DIRS`] having it
+    pub fn open(name: &str) -> Result<Self, VclError> {
+        Self::open_in(name, DEFAULT_VMOD_DIRS)
+    }
+
+    /// Load the vmod called `name` from the first of `dirs` having it, e.g. the directories of
+    /// the `vmod_path` parameter
+    pub fn open_in<P: AsRef<Path>>(
+        name: &str,
+        dirs: impl IntoIterator<Item = P>,
+    ) -> Result<Self, VclError> {
+        let file = format!("libvmod_{name}.so");
+        let path = dirs
+            .into_iter()
+            .map(|d| d.as_ref().join(&file))
+            .find(|p| p.is_file())
+            .ok_or_else(|| VclError::String(format!("{file} not found")))?;
+        Self::open_path(name, path)
+    }
+
+    /// Load the vmod called `name` from the library at `path`
+    pub fn open_path(name: &str, path: impl Into<PathBuf>) -> Result<Self, VclError> {
+        let path = path.into();
+        let lib = unsafe { Library::new(&path) }
+            .map_err(|e| VclError::String(format!("cannot load {}: {e}", path.display())))?;
+        let data = Self::data(name, &lib)?;
+        Self::check_abi(name, data)?;
+        let table = unsafe { FuncTable::new(data)? };
+        Ok(Self {
+            name: name.to_string(),
+            path,
+            table: Arc::new(table),
+            lib: Arc::new(lib),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The `Vmod_<name>_Data` of the library, the only symbol exported by a vmod
+    fn data<'a>(name: &str, lib: &'a Library) -> Result<&'a vmod_data, VclError> {
+        let symbol = format!("Vmod_{name}_Data");
+        let cname = CString::new(symbol).map_err(|e| VclError::String(e.to_string()))?;
+        unsafe {
+            let sym = lib
+                .get::<*const vmod_data>(cname.as_bytes_with_nul())
+                .map_err(|e| VclError::String(format!("vmod {name}: {e}")))?;
+            Ok(&**sym)
+        }
+    }
+
+    /// Refuse the vmods built for another VRT version, whose functions may have other arguments,
+    /// like Varnish does when importing them
+    fn check_abi(name: &str, data: &vmod_data) -> Result<(), VclError> {
+        if data.vrt_major != VRT_MAJOR_VERSION || data.vrt_minor > VRT_MINOR_VERSION {
+            return Err(VclError::String(format!(
+                "vmod {name} was built for VRT {}.{}, not {VRT_MAJOR_VERSION}.{VRT_MINOR_VERSION}",
+                data.vrt_major, data.vrt_minor
+            )));
+        }
+        Ok(())
+    }
+
+    /// Get a function of the vmod from its function table, see [`Std::from_lib()`] for the
+    /// naming of the slots. `T` must be the `unsafe extern "C" fn` type of the function.
+    unsafe fn get<T: Copy>(&self, slot: &str) -> Result<T, VclError> {
+        self.table.get(slot)
+    }
+}
+
+fn c_string(s: &str) -> Result<CString, VclError> {
+    CString::new(s).map_err(|_| VclError::CStr(c"vmod arguments cannot contain NULL bytes"))
+}
+
+/// `struct VARGS(duration)` of `vmod_std`
+#[repr(C)]
+struct DurationArgs {
+    valid_s: c_char,
+    valid_fallback: c_char,
+    valid_real: c_char,
+    valid_integer: c_char,
+    s: VCL_STRING,
+    fallback: VCL_DURATION,
+    real: VCL_REAL,
+    integer: VCL_INT,
+}
+
+/// `struct VARGS(ip)` of `vmod_std`
+#[repr(C)]
+struct IpArgs {
+    valid_fallback: c_char,
+    valid_p: c_char,
+    s: VCL_STRING,
+    fallback: VCL_IP,
+    resolve: VCL_BOOL,
+    p: VCL_STRING,
+}
+
+type DurationFn = unsafe extern "C" fn(*const vrt_ctx, *mut DurationArgs) -> VCL_DURATION;
+type IpFn = unsafe extern "C" fn(*const vrt_ctx, *mut IpArgs) -> VCL_IP;
+
+/// Functions of `vmod_std`
+#[derive(Clone)]
+pub struct Std {
+    duration: DurationFn,
+    ip: IpFn,
+    _lib: VmodLib,
+}
+
+impl Debug for Std {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Std").finish_non_exhaustive()
+    }
+}
+
+impl Std {
+    /// Load `vmod_std` from the default directories, see [`VmodLib::open()`]
+    pub fn load() -> Result<Self, VclError> {
+        Self::from_lib(VmodLib::open("std")?)
+    }
+
+    /// Use an already loaded `vmod_std`. Its functions are found in the function table of its
+    /// `Vmod_std_Data`, using the order of its JSON description, as they are not exported.
+    pub fn from_lib(lib: VmodLib) -> Result<Self, VclError> {
+        unsafe {
+            Ok(Self {
+                duration: lib.get("duration")?,
+                ip: lib.get("ip")?,
+                _lib: lib,
+            })
+        }
+    }
+
+    /// `std.duration(s, fallback)`: parse a VCL duration like `1.5h`, or return `fallback`
+    pub fn duration(&self, ctx: &Ctx, s: &str, fallback: Duration) -> Result<Duration, VclError> {
+        let s = c_string(s)?;
+        let mut args = DurationArgs {
+            valid_s: 1,
+            valid_fallback: 1,
+            valid_real: 0,
+            valid_integer: 0,
+            s: VCL_STRING(s.as_ptr()),
+            fallback: fallback.into(),
+            real: VCL_REAL(0.0),
+            integer: VCL_INT(0),
+        };
+        let d = unsafe { (self.duration)(ctx.raw, ptr::from_mut(&mut args)) };
+        Ok(d.into())
+    }
+
+    /// `std.ip(s, fallback, resolve)`: parse an address, or resolve a host name if `resolve` is
+    /// set. Without a `fallback`, a failure also fails the task, like it does in VCL.
+    pub fn ip(
+        &self,
+        ctx: &mut Ctx,
+        s: &str,
+        fallback: Option<SocketAddr>,
+        resolve: bool,
+    ) -> Result<Option<SocketAddr>, VclError> {
+        let s = c_string(s)?;
+        let fallback_ip = match fallback {
+            Some(addr) => addr.into_vcl(&mut ctx.ws)?,
+            None => VCL_IP(ptr::null()),
+        };
+        let mut args = IpArgs {
+            valid_fallback: fallback.is_some().into(),
+            valid_p: 0,
+            s: VCL_STRING(s.as_ptr()),
+            fallback: fallback_ip,
+            resolve: resolve.into(),
+            p: VCL_STRING(ptr::null()),
+        };
+        let ip = unsafe { (self.ip)(ctx.raw, ptr::from_mut(&mut args)) };
+        Ok(ip.into())
+    }
+}
+
+type RrInitFn = unsafe extern "C" fn(*const vrt_ctx, *mut *mut c_void, *const c_char);
+type RrFiniFn = unsafe extern "C" fn(*mut *mut c_void);
+type RrBackendOpFn = unsafe extern "C" fn(*const vrt_ctx, *mut c_void, VCL_BACKEND);
+type RrBackendFn = unsafe extern "C" fn(*const vrt_ctx, *mut c_void) -> VCL_BACKEND;
+
+#[derive(Clone, Copy)]
+struct RoundRobinFns {
+    init: RrInitFn,
+    fini: RrFiniFn,
+    add_backend: RrBackendOpFn,
+    remove_backend: RrBackendOpFn,
+    backend: RrBackendFn,
+}
+
+/// Objects of `vmod_directors`
+#[derive(Clone)]
+pub struct Directors {
+    round_robin: RoundRobinFns,
+    lib: VmodLib,
+}
+
+impl Debug for Directors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Directors").finish_non_exhaustive()
+    }
+}
+
+impl Directors {
+    /// Load `vmod_directors` from the default directories, see [`VmodLib::open()`]
+    pub fn load() -> Result<Self, VclError> {
+        Self::from_lib(VmodLib::open("directors")?)
+    }
+
+    /// Use an already loaded `vmod_directors`, see [`Std::from_lib()`]
+    pub fn from_lib(lib: VmodLib) -> Result<Self, VclError> {
+        unsafe {
+            Ok(Self {
+                round_robin: RoundRobinFns {
+                    init: lib.get("round_robin.__init")?,
+                    fini: lib.get("round_robin.__fini")?,
+                    add_backend: lib.get("round_robin.add_backend")?,
+                    remove_backend: lib.get("round_robin.remove_backend")?,
+                    backend: lib.get("round_robin.backend")?,
+                },
+                lib,
+            })
+        }
+    }
+
+    /// `new name = directors.round_robin()`, this must be done while the VCL is loaded, e.g. in
+    /// an object constructor
+    pub fn round_robin(&self, ctx: &Ctx, name: &str) -> Result<RoundRobin, VclError> {
+        let cname = c_string(name)?;
+        let mut obj = ptr::null_mut();
+        unsafe { (self.round_robin.init)(ctx.raw, ptr::from_mut(&mut obj), cname.as_ptr()) };
+        if obj.is_null() {
+            return Err(VclError::String(format!(
+                "cannot create the round_robin director {name}"
+            )));
+        }
+        Ok(RoundRobin {
+            obj,
+            fns: self.round_robin,
+            _lib: self.lib.clone(),
+        })
+    }
+}
+
+/// A `directors.round_robin` director, created with [`Directors::round_robin()`]
+pub struct RoundRobin {
+    obj: *mut c_void,
+    fns: RoundRobinFns,
+    _lib: VmodLib,
+}
+
+// the C director locks its list of backends
+unsafe impl Send for RoundRobin {}
+unsafe impl Sync for RoundRobin {}
+
+impl Debug for RoundRobin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoundRobin").finish_non_exhaustive()
+    }
+}
+
+impl RoundRobin {
+    pub fn add_backend(&self, ctx: &Ctx, backend: VCL_BACKEND) {
+        unsafe { (self.fns.add_backend)(ctx.raw, self.obj, backend) };
+    }
+
+    pub fn remove_backend(&self, ctx: &Ctx, backend: VCL_BACKEND) {
+        unsafe { (self.fns.remove_backend)(ctx.raw, self.obj, backend) };
+    }
+
+    /// The director itself, to be returned to VCL, e.g. as `req.backend_hint`
+    pub fn backend(&self, ctx: &Ctx) -> VCL_BACKEND {
+        unsafe { (self.fns.backend)(ctx.raw, self.obj) }
+    }
+}
+
+impl Drop for RoundRobin {
+    fn drop(&mut self) {
+        unsafe { (self.fns.fini)(ptr::from_mut(&mut self.obj)) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_vmod() {
+        let dir = std::env::temp_dir().join("varnish-rs-no-vmods");
+        let err = VmodLib::open_in("std", [&dir]).unwrap_err();
+        assert_eq!(err.as_str(), "libvmod_std.so not found");
+
+        let err = VmodLib::open_path("std", dir.join("libvmod_std.so")).unwrap_err();
+        assert!(err.as_str().starts_with("cannot load"));
+    }
+
+    #[test]
+    fn installed_vmods() {
+        // only where Varnish is installed, its vmods export nothing but `Vmod_<name>_Data`
+        let installed = |name: &str| {
+            DEFAULT_VMOD_DIRS
+                .iter()
+                .any(|dir| Path::new(dir).join(format!("libvmod_{name}.so")).is_file())
+        };
+        if installed("std") {
+            Std::load().unwrap();
+            let lib = VmodLib::open("std").unwrap();
+            let err = unsafe { lib.get::<DurationFn>("no_such_function") }.unwrap_err();
+            assert_eq!(err.as_str(), "vmod std has no no_such_function");
+        }
+        if installed("directors") {
+            Directors::load().unwrap();
+        }
+    }
+}
@@ -4,13 +4,17 @@
 //! non-blocking way. The main way to access those counters traditionally is with `varnishstat`,
 //! but the API is generic and allows you to track, filter and read any counter that `varnishd`
 //! (and vmods) are exposing.
+//!
+//! The counters a vmod exports with [`VscSegment`](crate::vcl::VscSegment) are named
+//! `vmod.<vmod>.<instance>.<field>`, e.g. `StatsBuilder::new().include("vmod.rate.*")`.
 
 use std::collections::HashMap;
 use std::ffi::{c_char, c_int, c_void, CStr, CString, NulError};
+use std::fmt;
 use std::marker::PhantomData;
 use std::path::Path;
 use std::ptr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use varnish_sys::ffi;
 use varnish_sys::vcl::{VclError, VclResult};
@@ -316,6 +320,55 @@ impl<'a> Stat<'a> {
             0
         }
     }
+
+    /// The 64 bits of the value, least significant first
+    ///
+    /// Mostly useful for [`Semantics::Bitmap`] statistics, e.g. `VBE.*.happy` where each bit is
+    /// the result of a health probe, the most recent one first.
+    pub fn bits(&self) -> impl Iterator<Item = bool> {
+        let v = self.get_raw_value();
+        (0..u64::BITS).map(move |i| v & (1 << i) != 0)
+    }
+
+    /// The bits of the value paired with `names`, the first name being the least significant bit
+    ///
+    /// Bitmaps don't describe their bits, so their meaning must come from the code exposing them.
+    /// Bits beyond the end of `names` are ignored.
+    pub fn named_bits<'n>(&self, names: &'n [&'n str]) -> impl Iterator<Item = (&'n str, bool)> {
+        names.iter().copied().zip(self.bits())
+    }
+}
+
+/// Display the value according to its [`Format`]: bytes with binary units, durations in seconds,
+/// and bitmaps in hexadecimal. Gauges are clamped, see [`Stat::get_clamped_value()`].
+impl fmt::Display for Stat<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self.semantics {
+            Semantics::Gauge => self.get_clamped_value(),
+            _ => self.get_raw_value(),
+        };
+        fmt_value(f, value, self.format)
+    }
+}
+
+#[expect(clippy::cast_precision_loss)]
+fn fmt_value(f: &mut fmt::Formatter<'_>, value: u64, format: Format) -> fmt::Result {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    match format {
+        Format::Bytes if value >= 1024 => {
+            let mut v = value as f64 / 1024.0;
+            let mut unit = 0;
+            while v >= 1024.0 && unit + 1 < UNITS.len() {
+                v /= 1024.0;
+                unit += 1;
+            }
+            write!(f, "{v:.2} {}", UNITS[unit])
+        }
+        Format::Bytes => write!(f, "{value} B"),
+        Format::Duration => write!(f, "{value}s"),
+        Format::Bitmap => write!(f, "{value:#018x}"),
+        Format::Integer | Format::Unknown => write!(f, "{value}"),
+    }
 }
 
 impl<'a> Stats<'a> {
@@ -346,4 +399,228 @@ impl<'a> Stats<'a> {
         let deleted = std::mem::take(&mut self.internal.deleted);
         (added, deleted)
     }
+
+    /// Read the worker thread counters of `varnishd` from the current set
+    ///
+    /// The `MAIN.*` counters involved must not have been excluded when building the [`Stats`],
+    /// and [`Stats::update()`] must have been called at least once; missing counters read as 0.
+    pub fn thread_pools(&self) -> ThreadPools {
+        let mut pools = ThreadPools::default();
+        for stat in self.internal.points.values() {
+            let field = match stat.name {
+                "MAIN.pools" => &mut pools.pools,
+                "MAIN.threads" => &mut pools.threads,
+                "MAIN.threads_limited" => &mut pools.limited,
+                "MAIN.threads_failed" => &mut pools.failed,
+                "MAIN.thread_queue_len" => &mut pools.queue_len,
+                "MAIN.sess_dropped" => &mut pools.sess_dropped,
+                "MAIN.req_dropped" => &mut pools.req_dropped,
+                _ => continue,
+            };
+            *field = stat.get_clamped_value();
+        }
+        pools
+    }
+}
+
+/// A snapshot of the worker thread counters, see [`Stats::thread_pools()`]
+///
+/// Useful to shed optional work, e.g. skip a remote call, when the pools are saturated.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ThreadPools {
+    /// Number of thread pools (`MAIN.pools`)
+    pub pools: u64,
+    /// Worker threads currently running (`MAIN.threads`)
+    pub threads: u64,
+    /// Threads not created because `thread_pool_max` was reached (`MAIN.threads_limited`)
+    pub limited: u64,
+    /// Threads that failed to be created (`MAIN.threads_failed`)
+    pub failed: u64,
+    /// Requests waiting for a worker thread (`MAIN.thread_queue_len`)
+    pub queue_len: u64,
+    /// Sessions dropped because the queue was full (`MAIN.sess_dropped`)
+    pub sess_dropped: u64,
+    /// HTTP/2 requests dropped because the queue was full (`MAIN.req_dropped`)
+    pub req_dropped: u64,
+}
+
+/// Per-second rates of the counters of a [`Stats`], computed between two [`RateTracker::update()`]
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use varnish::vsc::{RateTracker, StatsBuilder};
+/// let mut stats = StatsBuilder::new().include("MAIN.*").unwrap().build().unwrap();
+/// let mut tracker = RateTracker::new();
+/// loop {
+///     stats.update();
+///     if let Some(rate) = tracker.update(&stats).get("MAIN.client_req") {
+///         println!("{:.1} req/s", rate.per_second);
+///     }
+///     std::thread::sleep(Duration::from_secs(1));
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct RateTracker {
+    last: HashMap<String, u64>,
+    last_update: Option<Instant>,
+    rates: HashMap<String, Rate>,
+}
+
+/// The change of a counter between two [`RateTracker::update()`] calls
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rate {
+    /// How much the counter increased
+    pub delta: u64,
+    /// `delta` divided by the time elapsed between the two updates
+    pub per_second: f64,
+    /// The counter went down, i.e. `varnishd` restarted, and `delta` is the new value
+    pub reset: bool,
+}
+
+impl RateTracker {
+    /// Create a tracker without any previous value
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compare the current counters of `stats` with the ones of the previous call, and return
+    /// their rates by name
+    ///
+    /// Only [`Semantics::Counter`] statistics are tracked. The first call, and the first call
+    /// after a counter appeared, only record the values, so there is no rate for them yet.
+    pub fn update(&mut self, stats: &Stats) -> &HashMap<String, Rate> {
+        let counters = stats
+            .stats()
+            .values()
+            .filter(|s| s.semantics == Semantics::Counter)
+            .map(|s| (s.name, s.get_raw_value()));
+        self.record(Instant::now(), counters)
+    }
+
+    /// The rates computed by the last [`RateTracker::update()`]
+    pub fn rates(&self) -> &HashMap<String, Rate> {
+        &self.rates
+    }
+
+    #[expect(clippy::cast_precision_loss)]
+    fn record<'s>(
+        &mut self,
+        now: Instant,
+        counters: impl Iterator<Item = (&'s str, u64)>,
+    ) -> &HashMap<String, Rate> {
+        let elapsed = self
+            .last_update
+            .replace(now)
+            .map(|last| now.saturating_duration_since(last).as_secs_f64());
+        let mut last = HashMap::with_capacity(self.last.len());
+        self.rates.clear();
+        for (name, value) in counters {
+            if let (Some(elapsed), Some(&previous)) = (elapsed, self.last.get(name)) {
+                let reset = value < previous;
+                let delta = if reset { value } else { value - previous };
+                let per_second = if elapsed > 0.0 {
+                    delta as f64 / elapsed
+                } else {
+                    0.0
+                };
+                self.rates.insert(
+                    name.to_string(),
+                    Rate {
+                        delta,
+                        per_second,
+                        reset,
+                    },
+                );
+            }
+            last.insert(name.to_string(), value);
+        }
+        // counters that disappeared are forgotten
+        self.last = last;
+        &self.rates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(value: &u64, semantics: Semantics, format: Format) -> Stat<'_> {
+        Stat {
+            value,
+            name: "TEST.stat",
+            short_desc: "",
+            long_desc: "",
+            semantics,
+            format,
+        }
+    }
+
+    #[test]
+    fn display() {
+        let check = |value: u64, semantics, format, expected: &str| {
+            assert_eq!(stat(&value, semantics, format).to_string(), expected);
+        };
+        check(42, Semantics::Counter, Format::Integer, "42");
+        check(u64::MAX, Semantics::Gauge, Format::Integer, "0");
+        check(512, Semantics::Counter, Format::Bytes, "512 B");
+        check(1536, Semantics::Counter, Format::Bytes, "1.50 KiB");
+        check(5 << 30, Semantics::Gauge, Format::Bytes, "5.00 GiB");
+        check(3600, Semantics::Counter, Format::Duration, "3600s");
+        check(
+            0b1011,
+            Semantics::Bitmap,
+            Format::Bitmap,
+            "0x000000000000000b",
+        );
+    }
+
+    #[test]
+    fn bits() {
+        let value = 0b101;
+        let happy = stat(&value, Semantics::Bitmap, Format::Bitmap);
+        assert_eq!(happy.bits().count(), 64);
+        assert_eq!(happy.bits().filter(|b| *b).count(), 2);
+        assert_eq!(
+            happy.named_bits(&["a", "b", "c"]).collect::<Vec<_>>(),
+            [("a", true), ("b", false), ("c", true)]
+        );
+    }
+
+    #[test]
+    fn rate_tracker() {
+        let start = Instant::now();
+        let mut tracker = RateTracker::new();
+        assert!(tracker
+            .record(start, [("MAIN.client_req", 100)].into_iter())
+            .is_empty());
+
+        let rates = tracker.record(
+            start + Duration::from_secs(2),
+            [("MAIN.client_req", 150), ("MAIN.new", 7)].into_iter(),
+        );
+        assert_eq!(rates.len(), 1);
+        assert_eq!(
+            rates["MAIN.client_req"],
+            Rate {
+                delta: 50,
+                per_second: 25.0,
+                reset: false,
+            }
+        );
+
+        // varnishd restarted
+        let rates = tracker.record(
+            start + Duration::from_secs(4),
+            [("MAIN.client_req", 10), ("MAIN.new", 9)].into_iter(),
+        );
+        assert_eq!(
+            rates["MAIN.client_req"],
+            Rate {
+                delta: 10,
+                per_second: 5.0,
+                reset: true,
+            }
+        );
+        assert_eq!(rates["MAIN.new"].delta, 2);
+    }
 }
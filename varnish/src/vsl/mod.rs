@@ -0,0 +1,566 @@
+//! Read the Varnish Shared Log
+//!
+//! The VSL (Varnish Shared Log) is where `varnishd` logs everything it does, and what
+//! `varnishlog` and `varnishncsa` read. A [`LogReader`] attaches to a running instance, groups
+//! the records into transactions, filters them with an optional [`VslQuery`], and hands them to a
+//! callback without copying them.
+//!
+//! ``` no_run
+//! use std::ops::ControlFlow;
+//! use std::thread::sleep;
+//! use std::time::Duration;
+//!
+//! use varnish::vcl::LogTag;
+//! use varnish::vsl::{Dispatch, LogReaderBuilder, VslQuery};
+//!
+//! let query = VslQuery::compile("RespStatus >= 500").unwrap();
+//! let mut reader = LogReaderBuilder::new().query(query).build().unwrap();
+//! loop {
+//!     let status = reader.on_transaction(|tx| {
+//!         for record in tx.records() {
+//!             if record.tag() == Some(LogTag::ReqUrl) {
+//!                 println!("{} failed", record.as_str().unwrap_or("?"));
+//!             }
+//!         }
+//!         ControlFlow::Continue(())
+//!     });
+//!     match status.unwrap() {
+//!         Dispatch::More => {}
+//!         Dispatch::Eof => break,
+//!         _ => sleep(Duration::from_millis(10)),
+//!     }
+//! }
+//! ```
+
+use std::ffi::{c_char, c_int, c_uint, c_void, CStr, CString, NulError};
+use std::fmt::{self, Display, Formatter};
+use std::mem::transmute;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::ptr;
+use std::str::Utf8Error;
+use std::time::Duration;
+
+use varnish_sys::ffi;
+use varnish_sys::vcl::{LogTag, VclError, VclResult};
+
+pub mod ncsa;
+
+// Return values of `VSLQ_Dispatch()`, they were plain numbers before `enum vsl_status` existed
+const VSL_E_ABANDON: c_int = -2;
+const VSL_E_OVERRUN: c_int = -3;
+const VSL_E_EOF: c_int = -1;
+const VSL_END: c_int = 0;
+const VSL_MORE: c_int = 1;
+/// Returned by our dispatch callback when the user callback wants to stop
+const DISPATCH_STOPPED: c_int = 2;
+
+/// A compiled VSL query, as used by `varnishlog -q`
+///
+/// See `man vsl-query` for the syntax, e.g. `ReqURL ~ "^/api/" and RespStatus >= 500`.
+#[derive(Debug, Clone)]
+pub struct VslQuery {
+    query: CString,
+}
+
+impl VslQuery {
+    /// Check the syntax of `query`, reporting where it is wrong if it doesn't compile
+    pub fn compile(query: &str) -> Result<Self, QueryError> {
+        let query = CString::new(query).map_err(|e| QueryError {
+            message: "the query cannot contain NULL bytes".to_string(),
+            position: Some(e.nul_position()),
+        })?;
+        unsafe {
+            let vsl = ffi::VSL_New();
+            assert!(!vsl.is_null());
+            let mut vslq = ffi::VSLQ_New(
+                vsl,
+                ptr::null_mut(),
+                ffi::VSL_grouping_e_VSL_g_vxid,
+                query.as_ptr(),
+            );
+            let res = if vslq.is_null() {
+                Err(QueryError::from_message(&vsl_error(vsl)))
+            } else {
+                ffi::VSLQ_Delete(&mut vslq);
+                Ok(Self { query })
+            };
+            ffi::VSL_Delete(vsl);
+            res
+        }
+    }
+
+    /// The query, as passed to [`VslQuery::compile()`]
+    pub fn as_str(&self) -> &str {
+        self.query.to_str().expect("built from a &str")
+    }
+}
+
+/// Why a [`VslQuery`] didn't compile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    /// The error, as reported by `libvarnishapi`
+    pub message: String,
+    /// Byte offset in the query where the error was detected, if known
+    pub position: Option<usize>,
+}
+
+impl QueryError {
+    /// The error messages of `libvarnishapi` point to the faulty token with `(Pos N)`, 1-based
+    fn from_message(message: &str) -> Self {
+        let position = message
+            .split("(Pos ")
+            .nth(1)
+            .and_then(|s| s.split(')').next()?.parse::<usize>().ok()?.checked_sub(1));
+        Self {
+            message: message.trim_end().to_string(),
+            position,
+        }
+    }
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// How records are grouped into transactions, as `varnishlog -g`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Grouping {
+    /// Each record is its own transaction
+    Raw,
+    /// One transaction per VXID, i.e. per session, request, or backend request
+    #[default]
+    Vxid,
+    /// A client request, along with its ESI subrequests, restarts, and backend requests
+    Request,
+    /// A whole session, with all its requests
+    Session,
+}
+
+impl From<Grouping> for ffi::VSL_grouping_e {
+    fn from(value: Grouping) -> Self {
+        match value {
+            Grouping::Raw => ffi::VSL_grouping_e_VSL_g_raw,
+            Grouping::Vxid => ffi::VSL_grouping_e_VSL_g_vxid,
+            Grouping::Request => ffi::VSL_grouping_e_VSL_g_request,
+            Grouping::Session => ffi::VSL_grouping_e_VSL_g_session,
+        }
+    }
+}
+
+/// Initialize and configure a [`LogReader`] but do not attach it to a running `varnishd` instance
+#[derive(Debug)]
+pub struct LogReaderBuilder {
+    vsm: *mut ffi::vsm,
+    vsl: *mut ffi::VSL_data,
+    grouping: Grouping,
+    query: Option<VslQuery>,
+    tail: bool,
+}
+
+impl LogReaderBuilder {
+    /// Create a new `LogReaderBuilder`, reading from the tail of the log with the VXID grouping
+    #[expect(clippy::new_without_default)]
+    pub fn new() -> Self {
+        unsafe {
+            let vsm = ffi::VSM_New();
+            assert!(!vsm.is_null());
+            let vsl = ffi::VSL_New();
+            assert!(!vsl.is_null());
+            Self {
+                vsm,
+                vsl,
+                grouping: Grouping::default(),
+                query: None,
+                tail: true,
+            }
+        }
+    }
+
+    /// Specify where to find the `varnishd` working directory, like `-n` does for `varnishlog`
+    pub fn work_dir(self, dir: &Path) -> Result<Self, NulError> {
+        let c_dir = CString::new(dir.to_str().unwrap())?;
+        let ret = unsafe { ffi::VSM_Arg(self.vsm, 'n' as c_char, c_dir.as_ptr()) };
+        assert_eq!(ret, 1);
+        Ok(self)
+    }
+
+    /// How long to wait for `varnishd` when attaching, `None` waits forever
+    pub fn patience(self, t: Option<Duration>) -> VclResult<Self> {
+        let arg = match t {
+            None => c"off".to_owned(),
+            Some(t) => CString::new(t.as_secs_f64().to_string()).unwrap(),
+        };
+        let ret = unsafe { ffi::VSM_Arg(self.vsm, 't' as c_char, arg.as_ptr()) };
+        if ret != 1 {
+            return Err(vsm_error(self.vsm));
+        }
+        Ok(self)
+    }
+
+    /// How to group records into transactions
+    #[must_use]
+    pub fn grouping(mut self, grouping: Grouping) -> Self {
+        self.grouping = grouping;
+        self
+    }
+
+    /// Only dispatch the transactions matching `query`
+    #[must_use]
+    pub fn query(mut self, query: VslQuery) -> Self {
+        self.query = Some(query);
+        self
+    }
+
+    /// Start from the oldest records still in the log instead of only reading new ones
+    #[must_use]
+    pub fn start_at_head(mut self) -> Self {
+        self.tail = false;
+        self
+    }
+
+    /// How many incomplete transactions to keep before the oldest one is forcibly completed,
+    /// like `varnishlog -L`. This bounds the memory used when the reader falls behind.
+    pub fn incomplete_limit(self, limit: u32) -> VclResult<Self> {
+        self.vsl_arg('L', &limit.to_string())
+    }
+
+    /// How long to wait for an incomplete transaction before forcibly completing it, like
+    /// `varnishlog -T`
+    pub fn incomplete_timeout(self, timeout: Duration) -> VclResult<Self> {
+        self.vsl_arg('T', &timeout.as_secs_f64().to_string())
+    }
+
+    fn vsl_arg(self, opt: char, arg: &str) -> VclResult<Self> {
+        let arg = CString::new(arg).unwrap();
+        if unsafe { ffi::VSL_Arg(self.vsl, opt as c_int, arg.as_ptr()) } != 1 {
+            let err = vsl_error(self.vsl);
+            unsafe { ffi::VSL_ResetError(self.vsl) };
+            return Err(VclError::new(err));
+        }
+        Ok(self)
+    }
+
+    /// Build the [`LogReader`], attaching to a running `varnishd` instance
+    pub fn build(mut self) -> VclResult<LogReader> {
+        if unsafe { ffi::VSM_Attach(self.vsm, -1) } != 0 {
+            let err = vsm_error(self.vsm);
+            unsafe { ffi::VSM_ResetError(self.vsm) };
+            return Err(err);
+        }
+        let query = self
+            .query
+            .as_ref()
+            .map_or(ptr::null(), |q| q.query.as_ptr());
+        let vslq = unsafe { ffi::VSLQ_New(self.vsl, ptr::null_mut(), self.grouping.into(), query) };
+        if vslq.is_null() {
+            return Err(VclError::new(vsl_error(self.vsl)));
+        }
+        let reader = LogReader {
+            vsm: self.vsm,
+            vsl: self.vsl,
+            vslq,
+            has_cursor: false,
+            tail: self.tail,
+            overruns: 0,
+        };
+        // nullify so that .drop() doesn't destroy vsm/vsl
+        self.vsm = ptr::null_mut();
+        self.vsl = ptr::null_mut();
+        Ok(reader)
+    }
+}
+
+impl Drop for LogReaderBuilder {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.vsl.is_null() {
+                ffi::VSL_Delete(self.vsl);
+            }
+            if !self.vsm.is_null() {
+                ffi::VSM_Destroy(&mut self.vsm);
+            }
+        }
+    }
+}
+
+fn vsm_error(p: *const ffi::vsm) -> VclError {
+    unsafe {
+        VclError::new(
+            CStr::from_ptr(ffi::VSM_Error(p))
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+fn vsl_error(p: *const ffi::VSL_data) -> String {
+    unsafe {
+        CStr::from_ptr(ffi::VSL_Error(p))
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// The outcome of [`LogReader::on_transaction()`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Transactions were dispatched, more may be available right away
+    More,
+    /// No new records, wait a bit before trying again
+    Idle,
+    /// The callback stopped the dispatch, the remaining transactions will be dispatched next time
+    Stopped,
+    /// The reader fell too far behind, and `varnishd` overwrote records before they were read.
+    /// They are lost, and the reader will resume from the tail of the log.
+    Overrun,
+    /// `varnishd` abandoned the log, e.g. because it restarted. The reader will resume with the
+    /// new log once it is available.
+    Abandoned,
+    /// The end of the log was reached, only happens when reading a log that is no longer written
+    Eof,
+}
+
+/// A reader of the log of a running `varnishd`, created with a [`LogReaderBuilder`]
+#[derive(Debug)]
+pub struct LogReader {
+    vsm: *mut ffi::vsm,
+    vsl: *mut ffi::VSL_data,
+    vslq: *mut ffi::VSLQ,
+    has_cursor: bool,
+    tail: bool,
+    overruns: u64,
+}
+
+impl LogReader {
+    /// Call `f` for each transaction that is complete, stopping early if it returns
+    /// [`ControlFlow::Break`]
+    ///
+    /// This never blocks: the caller decides how long to wait after [`Dispatch::Idle`], and can
+    /// spread the work by stopping from the callback. Records are read in place, so a callback
+    /// that is too slow makes the reader fall behind `varnishd`, reported as [`Dispatch::Overrun`].
+    pub fn on_transaction<F>(&mut self, mut f: F) -> VclResult<Dispatch>
+    where
+        F: FnMut(&Transaction) -> ControlFlow<()>,
+    {
+        if !self.has_cursor && !self.open_cursor() {
+            return Ok(Dispatch::Idle);
+        }
+        let ret = unsafe {
+            ffi::VSLQ_Dispatch(
+                self.vslq,
+                Some(dispatch_tx::<F>),
+                ptr::from_mut(&mut f).cast::<c_void>(),
+            )
+        };
+        Ok(match ret {
+            VSL_MORE => Dispatch::More,
+            VSL_END => Dispatch::Idle,
+            DISPATCH_STOPPED => Dispatch::Stopped,
+            VSL_E_EOF => Dispatch::Eof,
+            VSL_E_OVERRUN | VSL_E_ABANDON => {
+                unsafe { ffi::VSLQ_SetCursor(self.vslq, ptr::null_mut()) };
+                self.has_cursor = false;
+                self.overruns += 1;
+                if ret == VSL_E_OVERRUN {
+                    Dispatch::Overrun
+                } else {
+                    Dispatch::Abandoned
+                }
+            }
+            _ => Err(VclError::new(vsl_error(self.vsl)))?,
+        })
+    }
+
+    /// How many times the reader lost records, see [`Dispatch::Overrun`] and
+    /// [`Dispatch::Abandoned`]
+    pub fn overruns(&self) -> u64 {
+        self.overruns
+    }
+
+    fn open_cursor(&mut self) -> bool {
+        let mut options = ffi::VSL_COPT_BATCH;
+        if self.tail || self.overruns > 0 {
+            options |= ffi::VSL_COPT_TAIL;
+        }
+        unsafe {
+            ffi::VSM_Status(self.vsm);
+            let mut cursor = ffi::VSL_CursorVSM(self.vsl, self.vsm, options as c_uint);
+            if cursor.is_null() {
+                // The log isn't available yet
+                ffi::VSL_ResetError(self.vsl);
+                return false;
+            }
+            ffi::VSLQ_SetCursor(self.vslq, &mut cursor);
+        }
+        self.has_cursor = true;
+        true
+    }
+}
+
+impl Drop for LogReader {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::VSLQ_Delete(&mut self.vslq);
+            ffi::VSL_Delete(self.vsl);
+            ffi::VSM_Destroy(&mut self.vsm);
+        }
+    }
+}
+
+unsafe extern "C" fn dispatch_tx<F>(
+    _vsl: *mut ffi::VSL_data,
+    trans: *const *mut ffi::VSL_transaction,
+    priv_: *mut c_void,
+) -> c_int
+where
+    F: FnMut(&Transaction) -> ControlFlow<()>,
+{
+    let f = priv_.cast::<F>().as_mut().unwrap();
+    let mut p = trans;
+    while let Some(raw) = (*p).as_ref() {
+        if f(&Transaction { raw }).is_break() {
+            return DISPATCH_STOPPED;
+        }
+        p = p.add(1);
+    }
+    0
+}
+
+/// What a [`Transaction`] describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionKind {
+    /// A client session
+    Session,
+    /// A client request
+    Request,
+    /// A backend request
+    BackendRequest,
+    /// A single record, with [`Grouping::Raw`]
+    Raw,
+    /// Anything else
+    Unknown,
+}
+
+/// A group of records, only valid for the duration of the callback
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    raw: &'a ffi::VSL_transaction,
+}
+
+impl<'a> Transaction<'a> {
+    /// Depth of the transaction in its group, starting at 1
+    pub fn level(&self) -> u32 {
+        self.raw.level
+    }
+
+    /// The transaction id
+    #[cfg_attr(not(varnishsys_6), expect(clippy::useless_conversion))]
+    pub fn vxid(&self) -> u64 {
+        u64::from(self.raw.vxid)
+    }
+
+    /// The id of the transaction that started this one, or 0
+    #[cfg_attr(not(varnishsys_6), expect(clippy::useless_conversion))]
+    pub fn parent_vxid(&self) -> u64 {
+        u64::from(self.raw.vxid_parent)
+    }
+
+    /// What the transaction describes
+    pub fn kind(&self) -> TransactionKind {
+        match self.raw.type_ {
+            ffi::VSL_transaction_e_VSL_t_sess => TransactionKind::Session,
+            ffi::VSL_transaction_e_VSL_t_req => TransactionKind::Request,
+            ffi::VSL_transaction_e_VSL_t_bereq => TransactionKind::BackendRequest,
+            ffi::VSL_transaction_e_VSL_t_raw => TransactionKind::Raw,
+            _ => TransactionKind::Unknown,
+        }
+    }
+
+    /// Iterate over the records of the transaction, from the first one
+    pub fn records(&self) -> Records<'a> {
+        let cursor = unsafe { self.raw.c.as_ref().unwrap() };
+        unsafe { ffi::VSL_ResetCursor(cursor) };
+        Records { cursor }
+    }
+}
+
+/// Iterator over the records of a [`Transaction`]
+#[derive(Debug)]
+pub struct Records<'a> {
+    cursor: &'a ffi::VSL_cursor,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if unsafe { ffi::VSL_Next(self.cursor) } != VSL_MORE {
+            return None;
+        }
+        let ptr = self.cursor.rec.ptr;
+        unsafe {
+            let header = *ptr;
+            let len = (header & ffi::VSL_LENMASK) as usize;
+            let data =
+                std::slice::from_raw_parts(ptr.add(ffi::VSL_OVERHEAD as usize).cast::<u8>(), len);
+            Some(Record {
+                tag: header >> ffi::VSL_IDSHIFT,
+                data,
+            })
+        }
+    }
+}
+
+/// A log record, only valid for the duration of the callback
+#[derive(Debug, Clone, Copy)]
+pub struct Record<'a> {
+    tag: u32,
+    data: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    /// The tag of the record, `None` if this version of `varnishd` doesn't know it
+    pub fn tag(&self) -> Option<LogTag> {
+        self.tag_name()?;
+        // SAFETY: `LogTag` is generated from the same list as the tag names
+        Some(unsafe { transmute::<u32, LogTag>(self.tag) })
+    }
+
+    /// The name of the tag, e.g. `ReqURL`
+    pub fn tag_name(&self) -> Option<&'static str> {
+        let name = unsafe { ffi::VSL_tags[self.tag as usize] };
+        if name.is_null() {
+            None
+        } else {
+            unsafe { CStr::from_ptr(name) }.to_str().ok()
+        }
+    }
+
+    /// The payload, without its terminating `NULL` byte
+    pub fn data(&self) -> &'a [u8] {
+        self.data.strip_suffix(b"\0").unwrap_or(self.data)
+    }
+
+    /// The payload as a string
+    pub fn as_str(&self) -> Result<&'a str, Utf8Error> {
+        std::str::from_utf8(self.data())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_error_position() {
+        let err = QueryError::from_message("Expected string got 'EOI' (Pos 8)\nReqURL ~\n");
+        assert_eq!(err.position, Some(7));
+        assert_eq!(err.message, "Expected string got 'EOI' (Pos 8)\nReqURL ~");
+        assert_eq!(QueryError::from_message("Syntax error").position, None);
+    }
+}
@@ -0,0 +1,623 @@
+//! Format transactions into access log lines, like `varnishncsa`
+//!
+//! [`Formatter`] understands the `varnishncsa -F` format language, e.g. `%h`, `%{Referer}i`,
+//! `%{Varnish:hitmiss}x`, or `%{VCL_Log:key}x`, and produces the same lines as `varnishncsa`
+//! from the records of a client or backend transaction:
+//!
+//! ``` rust
+//! use varnish::vcl::LogTag;
+//! use varnish::vsl::ncsa::Formatter;
+//!
+//! let fmt = Formatter::new(r#"%m %U%q %s "%{User-Agent}i""#).unwrap();
+//! let records = [
+//!     (LogTag::ReqMethod, "GET"),
+//!     (LogTag::ReqUrl, "/search?q=varnish"),
+//!     (LogTag::ReqHeader, "User-Agent: curl/8.0"),
+//!     (LogTag::RespStatus, "200"),
+//! ];
+//! assert_eq!(fmt.format(1001, records), r#"GET /search?q=varnish 200 "curl/8.0""#);
+//! ```
+//!
+//! Times are formatted in UTC, so lines are identical to the ones of a `varnishncsa` running with
+//! `TZ=UTC`.
+
+use std::fmt::{self, Display, Formatter as FmtFormatter};
+use std::time::{Duration, UNIX_EPOCH};
+
+use varnish_sys::vcl::{time, LogTag};
+
+use crate::vsl::{Transaction, TransactionKind};
+
+/// The format used by `varnishncsa` when none is given
+pub const DEFAULT_FORMAT: &str = r#"%h %l %u %t "%r" %s %b "%{Referer}i" "%{User-agent}i""#;
+
+/// A compiled `varnishncsa` format string
+#[derive(Debug, Clone)]
+pub struct Formatter {
+    items: Vec<Item>,
+}
+
+#[derive(Debug, Clone)]
+enum Item {
+    Literal(String),
+    /// `%b`
+    RespBytes,
+    /// `%D`
+    DurationUsec,
+    /// `%H`
+    Protocol,
+    /// `%h`
+    Host,
+    /// `%I`
+    BytesIn,
+    /// `%{X}i`
+    ReqHeader(String),
+    /// `%l`
+    Logname,
+    /// `%m`
+    Method,
+    /// `%{X}o`
+    RespHeader(String),
+    /// `%O`
+    BytesOut,
+    /// `%q`
+    Query,
+    /// `%r`
+    RequestLine,
+    /// `%s`
+    Status,
+    /// `%t` and `%{X}t`
+    Time(TimeFormat),
+    /// `%T`
+    DurationSec,
+    /// `%U`
+    Path,
+    /// `%u`
+    User,
+    /// `%{Varnish:X}x`
+    Varnish(VarnishField),
+    /// `%{VCL_Log:key}x`
+    VclLog(String),
+    /// `%{VSL:tag:prefix[field]}x`
+    Vsl {
+        tag: String,
+        prefix: Option<String>,
+        field: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum TimeFormat {
+    Clf,
+    Sec,
+    Msec,
+    Usec,
+    MsecFrac,
+    UsecFrac,
+    Strftime(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum VarnishField {
+    TimeFirstByte,
+    HitMiss,
+    Handling,
+    Side,
+    Vxid,
+}
+
+/// Why a format string was rejected by [`Formatter::new()`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatError {
+    /// What is wrong
+    pub message: String,
+    /// Byte offset in the format string of the faulty directive
+    pub position: usize,
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut FmtFormatter<'_>) -> fmt::Result {
+        write!(f, "{} (position {})", self.message, self.position)
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl Default for Formatter {
+    fn default() -> Self {
+        Self::new(DEFAULT_FORMAT).expect("valid default format")
+    }
+}
+
+impl Formatter {
+    /// Compile a format string, see `man varnishncsa` for the supported directives
+    pub fn new(format: &str) -> Result<Self, FormatError> {
+        let mut items = Vec::new();
+        let mut literal = String::new();
+        let mut chars = format.char_indices().peekable();
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => literal.push('\n'),
+                    Some((_, 't')) => literal.push('\t'),
+                    Some((_, c)) => literal.push(c),
+                    None => literal.push('\\'),
+                },
+                '%' => {
+                    let err = |message: &str| FormatError {
+                        message: message.to_string(),
+                        position: pos,
+                    };
+                    let arg = if chars.next_if(|(_, c)| *c == '{').is_some() {
+                        let mut arg = String::new();
+                        loop {
+                            match chars.next() {
+                                Some((_, '}')) => break,
+                                Some((_, c)) => arg.push(c),
+                                None => Err(err("Unterminated `%{`"))?,
+                            }
+                        }
+                        Some(arg)
+                    } else {
+                        None
+                    };
+                    let Some((_, directive)) = chars.next() else {
+                        Err(err("Missing directive after `%`"))?
+                    };
+                    if directive == '%' && arg.is_none() {
+                        literal.push('%');
+                        continue;
+                    }
+                    let item = Item::parse(directive, arg).map_err(|e| err(&e))?;
+                    if !literal.is_empty() {
+                        items.push(Item::Literal(std::mem::take(&mut literal)));
+                    }
+                    items.push(item);
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            items.push(Item::Literal(literal));
+        }
+        Ok(Self { items })
+    }
+
+    /// Format a transaction from a [`LogReader`](crate::vsl::LogReader), returning `None` for
+    /// transactions that are neither client nor backend requests, e.g. sessions
+    pub fn format_transaction(&self, tx: &Transaction) -> Option<String> {
+        if !matches!(
+            tx.kind(),
+            TransactionKind::Request | TransactionKind::BackendRequest
+        ) {
+            return None;
+        }
+        let records = tx
+            .records()
+            .filter_map(|r| Some((r.tag()?, r.as_str().ok()?)));
+        Some(self.format(tx.vxid(), records))
+    }
+
+    /// Format the records of the transaction `vxid`, in the order they were logged.
+    ///
+    /// Backend transactions are recognized by their `Bereq*` records.
+    pub fn format<'a>(
+        &self,
+        vxid: u64,
+        records: impl IntoIterator<Item = (LogTag, &'a str)>,
+    ) -> String {
+        let tx = TxFields::new(vxid, records.into_iter().collect());
+        let mut out = String::new();
+        for item in &self.items {
+            item.write(&tx, &mut out);
+        }
+        out
+    }
+}
+
+impl Item {
+    fn parse(directive: char, arg: Option<String>) -> Result<Self, String> {
+        Ok(match (directive, arg) {
+            ('b', None) => Self::RespBytes,
+            ('D', None) => Self::DurationUsec,
+            ('H', None) => Self::Protocol,
+            ('h', None) => Self::Host,
+            ('I', None) => Self::BytesIn,
+            ('i', Some(name)) => Self::ReqHeader(name),
+            ('l', None) => Self::Logname,
+            ('m', None) => Self::Method,
+            ('o', Some(name)) => Self::RespHeader(name),
+            ('O', None) => Self::BytesOut,
+            ('q', None) => Self::Query,
+            ('r', None) => Self::RequestLine,
+            ('s', None) => Self::Status,
+            ('t', None) => Self::Time(TimeFormat::Clf),
+            ('t', Some(fmt)) => Self::Time(match fmt.as_str() {
+                "sec" => TimeFormat::Sec,
+                "msec" => TimeFormat::Msec,
+                "usec" => TimeFormat::Usec,
+                "msec_frac" => TimeFormat::MsecFrac,
+                "usec_frac" => TimeFormat::UsecFrac,
+                _ => TimeFormat::Strftime(fmt),
+            }),
+            ('T', None) => Self::DurationSec,
+            ('U', None) => Self::Path,
+            ('u', None) => Self::User,
+            ('x', Some(arg)) => Self::parse_extended(&arg)?,
+            ('i' | 'o' | 'x', None) => Err(format!("`%{directive}` needs an argument"))?,
+            (_, Some(_)) if "bDHhIlmOqrsTUu".contains(directive) => {
+                Err(format!("`%{directive}` doesn't take an argument"))?
+            }
+            _ => Err(format!("Unknown directive `%{directive}`"))?,
+        })
+    }
+
+    fn parse_extended(arg: &str) -> Result<Self, String> {
+        if let Some(key) = arg.strip_prefix("VCL_Log:") {
+            return Ok(Self::VclLog(key.to_string()));
+        }
+        if let Some(spec) = arg.strip_prefix("VSL:") {
+            let (spec, field) = match spec.strip_suffix(']').and_then(|s| s.split_once('[')) {
+                Some((spec, field)) => match field.parse::<usize>() {
+                    Ok(n) if n > 0 => (spec, Some(n)),
+                    _ => Err(format!("Invalid field `{field}` in `{arg}`"))?,
+                },
+                None => (spec, None),
+            };
+            let (tag, prefix) = match spec.split_once(':') {
+                Some((tag, prefix)) => (tag, Some(prefix.to_string())),
+                None => (spec, None),
+            };
+            if tag.is_empty() {
+                Err(format!("Missing tag in `{arg}`"))?;
+            }
+            return Ok(Self::Vsl {
+                tag: tag.to_string(),
+                prefix,
+                field,
+            });
+        }
+        Ok(Self::Varnish(match arg {
+            "Varnish:time_firstbyte" => VarnishField::TimeFirstByte,
+            "Varnish:hitmiss" => VarnishField::HitMiss,
+            "Varnish:handling" => VarnishField::Handling,
+            "Varnish:side" => VarnishField::Side,
+            "Varnish:vxid" => VarnishField::Vxid,
+            _ => Err(format!("Unknown extended directive `{arg}`"))?,
+        }))
+    }
+
+    fn write(&self, tx: &TxFields, out: &mut String) {
+        let value = match self {
+            Self::Literal(s) => Some(s.clone()),
+            Self::RespBytes => tx.acct(5).filter(|b| *b != "0").map(str::to_string),
+            Self::DurationUsec => tx.end.map(|d| format!("{:.0}", d * 1e6)),
+            Self::Protocol => tx.protocol.map(str::to_string),
+            Self::Host => tx.host.map(str::to_string),
+            Self::BytesIn => tx.acct(if tx.backend { 6 } else { 3 }).map(str::to_string),
+            Self::ReqHeader(name) => find_header(&tx.req_headers, name).map(str::to_string),
+            Self::Logname => None,
+            Self::Method => tx.method.map(str::to_string),
+            Self::RespHeader(name) => find_header(&tx.resp_headers, name).map(str::to_string),
+            Self::BytesOut => tx.acct(if tx.backend { 3 } else { 6 }).map(str::to_string),
+            Self::Query => Some(
+                tx.url
+                    .and_then(|u| u.find('?').map(|i| &u[i..]))
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            Self::RequestLine => Some(tx.request_line()),
+            Self::Status => tx.status.map(str::to_string),
+            Self::Time(fmt) => tx.start.map(|t| fmt.format(t)),
+            Self::DurationSec => tx.end.map(|d| format!("{:.0}", d.trunc())),
+            Self::Path => tx.url.map(|u| u.split('?').next().unwrap_or(u).to_string()),
+            Self::User => tx.user(),
+            Self::Varnish(field) => match field {
+                VarnishField::TimeFirstByte => tx.first_byte.map(str::to_string),
+                VarnishField::HitMiss => tx.hitmiss.map(str::to_string),
+                VarnishField::Handling => tx.handling.map(str::to_string),
+                VarnishField::Side => Some(if tx.backend { "b" } else { "c" }.to_string()),
+                VarnishField::Vxid => Some(tx.vxid.to_string()),
+            },
+            Self::VclLog(key) => tx
+                .records
+                .iter()
+                .rev()
+                .filter(|(tag, _)| *tag == LogTag::VclLog)
+                .find_map(|(_, v)| strip_key(v, key))
+                .map(str::to_string),
+            Self::Vsl { tag, prefix, field } => tx.vsl(tag, prefix.as_deref(), *field),
+        };
+        out.push_str(value.as_deref().unwrap_or("-"));
+    }
+}
+
+/// If `line` is `key: value`, with `key` case-insensitive and optional spaces, return `value`
+fn strip_key<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let (k, v) = line.split_once(':')?;
+    k.eq_ignore_ascii_case(key).then(|| v.trim())
+}
+
+/// The last value of the header `name`
+fn find_header<'a>(headers: &[&'a str], name: &str) -> Option<&'a str> {
+    headers.iter().rev().find_map(|h| strip_key(h, name))
+}
+
+/// The fields of a transaction, extracted from its records
+#[derive(Debug, Default)]
+struct TxFields<'a> {
+    vxid: u64,
+    backend: bool,
+    records: Vec<(LogTag, &'a str)>,
+    host: Option<&'a str>,
+    method: Option<&'a str>,
+    url: Option<&'a str>,
+    protocol: Option<&'a str>,
+    status: Option<&'a str>,
+    req_headers: Vec<&'a str>,
+    resp_headers: Vec<&'a str>,
+    acct: Option<&'a str>,
+    start: Option<f64>,
+    first_byte: Option<&'a str>,
+    end: Option<f64>,
+    hitmiss: Option<&'static str>,
+    handling: Option<&'static str>,
+}
+
+impl<'a> TxFields<'a> {
+    fn new(vxid: u64, records: Vec<(LogTag, &'a str)>) -> Self {
+        let backend = records.iter().any(|(tag, _)| {
+            matches!(
+                tag,
+                LogTag::BereqMethod | LogTag::BereqUrl | LogTag::BereqAcct | LogTag::BackendOpen
+            )
+        });
+        let (first_byte_ts, end_ts) = if backend {
+            ("Beresp", "BerespBody")
+        } else {
+            ("Process", "Resp")
+        };
+        let mut tx = Self {
+            vxid,
+            backend,
+            ..Self::default()
+        };
+        for &(tag, value) in &records {
+            match (backend, tag) {
+                (false, LogTag::ReqStart) => tx.host = tx.host.or(value.split(' ').next()),
+                (true, LogTag::BackendOpen) => tx.host = tx.host.or(value.split(' ').nth(2)),
+                (false, LogTag::ReqMethod) | (true, LogTag::BereqMethod) => {
+                    tx.method = tx.method.or(Some(value));
+                }
+                (false, LogTag::ReqUrl) | (true, LogTag::BereqUrl) => {
+                    tx.url = tx.url.or(Some(value));
+                }
+                (false, LogTag::ReqProtocol) | (true, LogTag::BereqProtocol) => {
+                    tx.protocol = tx.protocol.or(Some(value));
+                }
+                (false, LogTag::RespStatus) | (true, LogTag::BerespStatus) => {
+                    tx.status = Some(value);
+                }
+                (false, LogTag::ReqHeader) | (true, LogTag::BereqHeader) => {
+                    tx.req_headers.push(value);
+                }
+                (false, LogTag::ReqUnset) | (true, LogTag::BereqUnset) => {
+                    tx.req_headers.retain(|h| *h != value);
+                }
+                (false, LogTag::RespHeader) | (true, LogTag::BerespHeader) => {
+                    tx.resp_headers.push(value);
+                }
+                (false, LogTag::RespUnset) | (true, LogTag::BerespUnset) => {
+                    tx.resp_headers.retain(|h| *h != value);
+                }
+                (false, LogTag::ReqAcct) | (true, LogTag::BereqAcct) => tx.acct = Some(value),
+                (_, LogTag::Timestamp) => {
+                    let mut fields = value.split_whitespace();
+                    let label = fields.next().unwrap_or_default().trim_end_matches(':');
+                    let abs = fields.next();
+                    let since_start = fields.next();
+                    if label == "Start" {
+                        tx.start = abs.and_then(|t| t.parse().ok());
+                    } else if label == first_byte_ts {
+                        tx.first_byte = since_start;
+                    }
+                    if label == end_ts {
+                        tx.end = since_start.and_then(|t| t.parse().ok());
+                    }
+                }
+                (false, LogTag::VclCall) => {
+                    let (hitmiss, handling) = match value {
+                        "HIT" => ("hit", "hit"),
+                        "MISS" => ("miss", "miss"),
+                        "PASS" => ("miss", "pass"),
+                        // Arguably, synth isn't a hit or a miss, but miss is less wrong
+                        "SYNTH" => ("miss", "synth"),
+                        _ => continue,
+                    };
+                    tx.hitmiss = Some(hitmiss);
+                    tx.handling = Some(handling);
+                }
+                (false, LogTag::VclReturn) if value == "pipe" => {
+                    tx.hitmiss = Some("miss");
+                    tx.handling = Some("pipe");
+                }
+                _ => {}
+            }
+        }
+        tx.records = records;
+        tx
+    }
+
+    /// The `n`th field of `ReqAcct` or `BereqAcct`, 1-based
+    fn acct(&self, n: usize) -> Option<&'a str> {
+        self.acct?.split_whitespace().nth(n - 1)
+    }
+
+    fn request_line(&self) -> String {
+        let mut line = String::new();
+        line.push_str(self.method.unwrap_or("-"));
+        line.push(' ');
+        let url = self.url.unwrap_or("-");
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            match find_header(&self.req_headers, "Host") {
+                Some(host) if host.starts_with("http://") => line.push_str(host),
+                Some(host) => {
+                    line.push_str("http://");
+                    line.push_str(host);
+                }
+                None => line.push_str("http://localhost"),
+            }
+        }
+        line.push_str(url);
+        line.push(' ');
+        line.push_str(self.protocol.unwrap_or("-"));
+        line
+    }
+
+    /// The user of HTTP basic authentication
+    fn user(&self) -> Option<String> {
+        let auth = find_header(&self.req_headers, "Authorization")?;
+        let (scheme, creds) = auth.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let creds = base64_decode(creds.trim())?;
+        let creds = String::from_utf8_lossy(&creds);
+        Some(creds.split(':').next().unwrap_or_default().to_string())
+    }
+
+    fn vsl(&self, tag: &str, prefix: Option<&str>, field: Option<usize>) -> Option<String> {
+        // `VslTag` names are the VSL names in CamelCase, e.g. `VCL_call` is `VclCall`
+        let normalize = |s: &str| s.replace('_', "").to_ascii_lowercase();
+        let tag = normalize(tag);
+        let value = self.records.iter().find_map(|(t, v)| {
+            if normalize(&format!("{t:?}")) != tag {
+                return None;
+            }
+            match prefix {
+                Some(prefix) => strip_key(v, prefix),
+                None => Some(*v),
+            }
+        })?;
+        match field {
+            Some(n) => value.split_whitespace().nth(n - 1).map(str::to_string),
+            None => Some(value.to_string()),
+        }
+    }
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0_u32;
+    let mut bits = 0;
+    for c in s.bytes().take_while(|c| *c != b'=') {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+impl TimeFormat {
+    fn format(&self, t: f64) -> String {
+        let secs = t.floor() as i64;
+        match self {
+            Self::Clf => strftime("[%d/%b/%Y:%T %z]", secs),
+            Self::Sec => secs.to_string(),
+            Self::Msec => format!("{:.0}", (t * 1e3).floor()),
+            Self::Usec => format!("{:.0}", (t * 1e6).floor()),
+            Self::MsecFrac => format!("{:03.0}", (t.fract() * 1e3).floor()),
+            Self::UsecFrac => format!("{:06.0}", (t.fract() * 1e6).floor()),
+            Self::Strftime(fmt) => strftime(fmt, secs),
+        }
+    }
+}
+
+/// Format a UNIX timestamp in UTC, with the common `strftime()` conversions
+fn strftime(fmt: &str, secs: i64) -> String {
+    let t = UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).unwrap_or(0));
+    let mut out = String::new();
+    let _ = time::format_to(&mut out, t, fmt);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_tx() -> Vec<(LogTag, &'static str)> {
+        vec![
+            (LogTag::Begin, "req 1000 rxreq"),
+            (
+                LogTag::Timestamp,
+                "Start: 1700000000.123456 0.000000 0.000000",
+            ),
+            (LogTag::ReqStart, "192.0.2.1 51234 a0"),
+            (LogTag::ReqMethod, "GET"),
+            (LogTag::ReqUrl, "/index.html?lang=en"),
+            (LogTag::ReqProtocol, "HTTP/1.1"),
+            (LogTag::ReqHeader, "Host: example.com"),
+            (LogTag::ReqHeader, "User-Agent: curl/8.0"),
+            (LogTag::ReqHeader, "Authorization: Basic dXNlcjpwYXNz"),
+            (LogTag::VclCall, "RECV"),
+            (LogTag::VclLog, "cache_key: abc"),
+            (LogTag::VclCall, "HIT"),
+            (
+                LogTag::Timestamp,
+                "Process: 1700000000.123556 0.000100 0.000100",
+            ),
+            (LogTag::RespProtocol, "HTTP/1.1"),
+            (LogTag::RespStatus, "200"),
+            (LogTag::RespHeader, "Content-Type: text/html"),
+            (LogTag::RespHeader, "X-Debug: 1"),
+            (LogTag::RespUnset, "X-Debug: 1"),
+            (
+                LogTag::Timestamp,
+                "Resp: 1700000000.125956 0.002500 0.002400",
+            ),
+            (LogTag::ReqAcct, "80 0 80 200 1234 1434"),
+            (LogTag::End, ""),
+        ]
+    }
+
+    #[test]
+    fn default_format() {
+        let line = Formatter::default().format(1000, client_tx());
+        assert_eq!(
+            line,
+            r#"192.0.2.1 - user [14/Nov/2023:22:13:20 +0000] "GET http://example.com/index.html?lang=en HTTP/1.1" 200 1234 "-" "curl/8.0""#
+        );
+    }
+
+    #[test]
+    fn extended_format() {
+        let fmt = Formatter::new(
+            r"%{Varnish:hitmiss}x %{Varnish:handling}x %{Varnish:side}x %{Varnish:vxid}x %{VCL_Log:cache_key}x %{VSL:ReqStart[2]}x %{X-Debug}o %D %I %O %U %q %{%F %H}t %{msec_frac}t\t100%%",
+        )
+        .unwrap();
+        assert_eq!(
+            fmt.format(1000, client_tx()),
+            "hit hit c 1000 abc 51234 - 2500 80 1434 /index.html ?lang=en 2023-11-14 22 123\t100%"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = Formatter::new("%h %{Host").unwrap_err();
+        assert_eq!(err.position, 3);
+        assert_eq!(Formatter::new("%h %Z").unwrap_err().position, 3);
+        assert!(Formatter::new("%{Varnish:nope}x").is_err());
+        assert!(Formatter::new("%i").is_err());
+    }
+}
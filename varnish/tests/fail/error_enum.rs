@@ -0,0 +1,37 @@
+use varnish::VclEnum;
+
+#[varnish::vmod]
+mod enums {
+    use varnish::VclEnum;
+
+    #[derive(Clone, Copy, VclEnum)]
+    pub enum Fields {
+        Unit,
+        Tuple(i64),
+        #[vcl_enum(rename = "unit")]
+        Dup,
+        #[vcl_enum(rename = "not-valid")]
+        Invalid,
+        #[vcl_enum(name = "foo")]
+        UnknownParam,
+    }
+
+    #[derive(Clone, Copy, VclEnum)]
+    pub enum Empty {}
+
+    #[derive(Clone, Copy, VclEnum)]
+    pub enum Valid {
+        Value,
+    }
+
+    pub fn with_default(#[default(Valid::Missing)] value: Valid) {}
+    pub fn with_literal(#[default("value")] value: Valid) {}
+}
+
+#[derive(Clone, Copy, VclEnum)]
+pub enum Generic<T> {
+    Value,
+    Other(T),
+}
+
+fn main() {}
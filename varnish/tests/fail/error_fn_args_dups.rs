@@ -2,6 +2,10 @@
 mod err_fn_args_dups {
     pub fn dup_ctx(ctx: &Ctx, i: i64, ctx2: &Ctx) {}
     pub fn dup_ctx2(ctx: &Ctx, i: i64, ctx2: &mut Ctx) {}
+    pub fn dup_ws(ws: &Workspace, ws2: &mut Workspace) {}
+    pub fn mut_ctx_ws(ctx: &mut Ctx, ws: &mut Workspace) {}
+    pub fn ws_mut_ctx(ws: &Workspace, ctx: &mut Ctx) {}
+    pub fn ws_view(ws: &mut Workspace, ctx: &RecvCtx) {}
     pub fn dup_shared_vcl(#[shared_per_vcl] a: Option<&i64>, #[shared_per_vcl] b: Option<&i64>) {}
     pub fn dup_shared_task(
         #[shared_per_task] a: Option<Box<i64>>,
@@ -14,6 +14,14 @@ mod err_fn_args_logic {
     pub fn socket_addr_non_opt(_v: SocketAddr) {}
     #[event]
     pub fn vcl_name(#[vcl_name] a: &str) {}
+    #[event]
+    pub fn on_event_view(ctx: &RecvCtx) {}
+    pub fn default_expr_i64(#[arg(default_expr = "10s")] _v: i64) {}
+    pub fn default_expr_bad(#[arg(default_expr = "10 seconds")] _v: Duration) {}
+    pub fn default_expr_both(#[default(1)] #[arg(default_expr = "1s")] _v: Duration) {}
+    pub fn lossy_str(#[arg(lossy)] _v: &str) {}
+    pub fn cow_not_lossy(_v: Cow<str>) {}
+    pub fn lossy_unknown(#[arg(lossless)] _v: Cow<str>) {}
 }
 
 fn main() {}
@@ -0,0 +1,25 @@
+#[varnish::vmod]
+mod names {
+    pub fn _hidden() {}
+    pub fn add(default: i64, _unused: i64) {}
+    pub fn fooBar() {}
+    pub fn foobar() {}
+    pub fn pair(a: i64, A: i64) {}
+
+    impl Store {
+        pub fn new() -> Self {
+            Self
+        }
+        pub fn get(&self) {}
+        pub fn Get(&self) {}
+    }
+}
+
+#[varnish::vmod]
+mod set {
+    pub fn hello() {}
+}
+
+pub struct Store;
+
+fn main() {}
@@ -0,0 +1,48 @@
+pub struct Store<T>(T);
+
+#[varnish::vmod]
+mod obj_generic {
+    use super::Store;
+
+    impl Store<i64> {
+        pub fn new() -> Self {
+            Store(0)
+        }
+    }
+
+    #[vmod_object(name = "store")]
+    impl Store<u8> {
+        pub fn new() -> Self {
+            Store(0)
+        }
+    }
+
+    #[vmod_object(name = "store")]
+    impl Store<bool> {
+        pub fn new() -> Self {
+            Store(false)
+        }
+    }
+
+    #[vmod_object(name = "not valid")]
+    impl Store<f64> {
+        pub fn new() -> Self {
+            Store(0.0)
+        }
+    }
+
+    #[vmod_object(title = "store")]
+    impl Store<String> {
+        pub fn new() -> Self {
+            Store(String::new())
+        }
+    }
+
+    impl<T: Default> Store<T> {
+        pub fn new() -> Self {
+            Store(T::default())
+        }
+    }
+}
+
+fn main() {}
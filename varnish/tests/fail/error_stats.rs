@@ -0,0 +1,26 @@
+use std::sync::atomic::{AtomicI64, AtomicU64};
+
+use varnish::Stats;
+
+fn main() {}
+
+#[derive(Stats)]
+struct Tuple(AtomicU64);
+
+#[derive(Stats)]
+struct Wrong {
+    signed: AtomicI64,
+    #[stats(gauge, counter)]
+    both: AtomicU64,
+    #[stats(rate)]
+    unknown: AtomicU64,
+}
+
+#[derive(Stats)]
+struct CounterOnly {
+    hits: AtomicU64,
+}
+
+fn counter_only(c: &CounterOnly) {
+    c.hits_dec();
+}
@@ -0,0 +1,23 @@
+#[varnish::vmod]
+mod err_types {
+    use std::collections::HashMap;
+
+    pub fn int(_v: u64) {}
+    pub fn real(_v: Option<f32>) {}
+    pub fn list(_v: Vec<String>) {}
+    pub fn slice(_v: &[&str]) {}
+    pub fn map(_v: &HashMap<String, String>) {}
+    pub fn chr(_v: char) {}
+    pub fn unknown(_v: Instant) {}
+    pub fn ret_int() -> u32 {
+        0
+    }
+    pub fn ret_list() -> Vec<String> {
+        Vec::new()
+    }
+    pub fn ret_map() -> Result<HashMap<String, i64>, String> {
+        Ok(HashMap::new())
+    }
+}
+
+fn main() {}
@@ -0,0 +1,38 @@
+use varnish::vmod;
+
+fn main() {}
+
+pub struct Obj;
+
+#[vmod]
+mod ctx_view {
+    use super::Obj;
+    use varnish::vcl::{DeliverCtx, FetchCtx, RecvCtx, VclError};
+
+    /// Read a header from the client request
+    pub fn req_header(ctx: &RecvCtx, name: &str) -> Option<String> {
+        ctx.req().header(name).map(str::to_owned)
+    }
+
+    /// Copy the backend request URL into a response header
+    pub fn copy_url(ctx: &mut FetchCtx, name: &str) -> Result<(), VclError> {
+        let url = ctx.bereq().url().unwrap_or_default().to_owned();
+        ctx.beresp_mut().set_header(name, &url)
+    }
+
+    /// Count the headers of the response
+    pub fn resp_len(ctx: &DeliverCtx) -> i64 {
+        ctx.resp().iter().count() as i64
+    }
+
+    impl Obj {
+        pub fn new() -> Self {
+            Self
+        }
+
+        /// Views are allowed in methods too
+        pub fn is_get(&self, ctx: &mut RecvCtx) -> bool {
+            ctx.req().method() == Some("GET")
+        }
+    }
+}
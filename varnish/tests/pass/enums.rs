@@ -0,0 +1,53 @@
+use varnish::vmod;
+
+fn main() {}
+
+pub struct Hasher;
+
+#[vmod]
+mod enums {
+    use super::Hasher;
+    use varnish::vcl::{Ctx, VclEnum};
+    use varnish::VclEnum;
+
+    #[derive(Clone, Copy, VclEnum)]
+    pub enum Algo {
+        Md5,
+        #[vcl_enum(rename = "sha256")]
+        Sha256,
+        NoHash,
+    }
+
+    #[derive(Clone, Copy, VclEnum)]
+    pub enum Where {
+        Header,
+        Body,
+        Md5,
+    }
+
+    pub fn digest(algo: Algo, data: &str) -> String {
+        match algo {
+            Algo::Md5 => format!("md5:{data}"),
+            Algo::Sha256 => format!("sha256:{data}"),
+            Algo::NoHash => data.to_string(),
+        }
+    }
+
+    pub fn name(#[default(Algo::Sha256)] algo: Algo) -> &'static str {
+        algo.vcl_name()
+    }
+
+    pub fn opt(_ctx: &Ctx, algo: Option<Algo>, place: Option<Where>) -> i64 {
+        algo.map_or(-1, |v| v.index() as i64) + place.map_or(-1, |v| v.index() as i64)
+    }
+
+    impl Hasher {
+        pub fn new(#[default(Where::Body)] _place: Where) -> Self {
+            Self
+        }
+
+        pub fn hash(&self, _algo: Algo) -> i64 {
+            0
+        }
+    }
+}
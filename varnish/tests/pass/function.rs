@@ -6,12 +6,13 @@ fn main() {}
 
 #[vmod]
 mod types {
+    use std::borrow::Cow;
     use std::error::Error;
     use std::ffi::CStr;
-    use std::net::SocketAddr;
+    use std::net::{IpAddr, SocketAddr};
     use std::time::Duration;
     use varnish::ffi::VCL_STRING;
-    use varnish::vcl::{CowProbe, Probe, Workspace};
+    use varnish::vcl::{BackendRef, CowProbe, Ctx, Probe, Strands, Workspace};
     use varnish_sys::vcl::VclError;
 
     // void
@@ -58,6 +59,8 @@ mod types {
     // Duration
     pub fn type_duration(_v: Duration) {}
     pub fn opt_duration(_v: Option<Duration>) {}
+    pub fn type_duration_dflt(#[arg(default_expr = "10s")] _v: Duration) {}
+    pub fn opt_duration_dflt(#[arg(default_expr = "1.5m")] _v: Option<Duration>) {}
     pub fn to_duration() -> Duration {
         Duration::default()
     }
@@ -93,6 +96,22 @@ mod types {
     pub fn opt_str_req(#[required] _v: Option<&str>) {}
     pub fn type_str_dflt(#[default("baz")] _v: &str) {}
     pub fn opt_str_dflt(#[default("baz")] _v: Option<&str>) {}
+    pub fn type_str_lossy(#[arg(lossy)] _v: Cow<str>) {}
+    pub fn opt_str_lossy(#[arg(lossy)] _v: Option<Cow<'_, str>>) {}
+    pub fn opt_str_lossy_req(#[arg(lossy)] #[required] _v: Option<Cow<str>>) {}
+    pub fn str_lossy_ctx(ctx: &mut Ctx, #[arg(lossy)] #[default("baz")] _v: Cow<str>) {}
+    pub fn str_lossy_ws(ctx: &Ctx, ws: &mut Workspace, #[arg(lossy)] _v: Cow<str>) {}
+    // strands
+    pub fn type_strands(_v: Strands) {}
+    pub fn opt_strands(_v: Option<Strands<'_>>) {}
+    pub fn type_str_strands(#[arg(strands)] _v: &str) {}
+    pub fn opt_str_strands(#[arg(strands)] _v: Option<&str>) {}
+    pub fn opt_str_strands_req(#[arg(strands)] #[required] _v: Option<&str>) {}
+    // bytes
+    pub fn type_bytes(_v: &[u8]) {}
+    pub fn opt_bytes(_v: Option<&[u8]>) {}
+    pub fn opt_bytes_req(#[required] _v: Option<&[u8]>) {}
+    pub fn type_bytes_dflt(#[default("baz")] _v: &[u8]) {}
     pub fn to_str() -> &'static str {
         ""
     }
@@ -148,6 +167,20 @@ mod types {
         Err("")
     }
 
+    // IpAddr
+    pub fn type_ipaddr(_v: Option<IpAddr>) {}
+    pub fn type_ipaddr_req(#[required] _v: Option<IpAddr>) {}
+    pub fn to_ipaddr() -> IpAddr {
+        IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)
+    }
+
+    // BackendRef
+    pub fn type_backend(_v: Option<BackendRef>) {}
+    pub fn type_backend_req(#[required] _v: Option<BackendRef>) {}
+    pub fn to_res_backend() -> Result<BackendRef, &'static str> {
+        Err("")
+    }
+
     // VCL_STRING
     pub unsafe fn to_vcl_string() -> VCL_STRING {
         VCL_STRING::default()
@@ -164,4 +197,6 @@ mod types {
     // Workspace
     pub fn get_ws_mut(ws: &mut Workspace) {}
     pub fn get_ws_ref(ws: &Workspace) {}
+    pub fn get_ctx_ws_mut(ctx: &Ctx, ws: &mut Workspace) {}
+    pub fn get_ws_ctx_ref(ws: &Workspace, ctx: &Ctx) {}
 }
@@ -0,0 +1,16 @@
+use varnish::vmod;
+
+fn main() {}
+
+fn setup() -> Result<(), &'static str> {
+    Ok(())
+}
+
+fn teardown() {}
+
+#[vmod(global_init = "crate::setup", global_fini = "super::teardown")]
+mod global1 {
+    pub fn hello() -> &'static str {
+        "hello"
+    }
+}
@@ -0,0 +1,17 @@
+#![expect(unused_variables)]
+
+use varnish::vmod;
+
+fn main() {}
+
+fn setup() {}
+
+#[vmod(global_init = "crate::setup")]
+mod global2 {
+    use varnish::vcl::{Ctx, Event};
+
+    #[event]
+    pub fn on_event(ctx: &Ctx, event: Event) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
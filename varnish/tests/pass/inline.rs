@@ -0,0 +1,48 @@
+#![expect(unused_variables)]
+
+use varnish::vmod;
+
+fn main() {}
+
+pub struct Counter;
+
+#[vmod(inline)]
+mod inlined {
+    use std::time::Duration;
+
+    use super::Counter;
+    use varnish::vcl::{Ctx, Event, VclError};
+
+    pub fn simple(v: i64) -> i64 {
+        v
+    }
+    pub fn res_void(ctx: &mut Ctx, v: i64) -> Result<(), VclError> {
+        Ok(())
+    }
+    pub fn res_int(v: i64) -> Result<i64, &'static str> {
+        Ok(v)
+    }
+    pub fn res_duration(v: Option<Duration>) -> Result<Duration, String> {
+        v.ok_or_else(|| "no duration".to_string())
+    }
+    // the output conversion may fail too, errors are still collected by a closure
+    pub fn res_string(v: &str) -> Result<String, VclError> {
+        Ok(v.to_string())
+    }
+    #[inline(always)]
+    pub fn marked(v: bool) -> bool {
+        v
+    }
+
+    impl Counter {
+        pub fn new() -> Self {
+            Self
+        }
+        pub fn add(&self, v: i64) -> Result<i64, VclError> {
+            Ok(v + 1)
+        }
+    }
+
+    #[event]
+    pub fn on_event(evt: Event) {}
+}
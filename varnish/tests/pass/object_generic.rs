@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+
+use varnish::vmod;
+
+fn main() {}
+
+pub struct Store<T> {
+    value: Mutex<T>,
+}
+
+impl<T: Clone> Store<T> {
+    fn with(value: T) -> Self {
+        Self {
+            value: Mutex::new(value),
+        }
+    }
+
+    fn load(&self) -> T {
+        self.value.lock().unwrap().clone()
+    }
+
+    fn store(&self, value: T) {
+        *self.value.lock().unwrap() = value;
+    }
+}
+
+pub struct Renamed;
+
+#[vmod]
+mod generic {
+    use super::{Renamed, Store};
+
+    /// A store of integers
+    #[vmod_object(name = "intstore")]
+    impl Store<i64> {
+        pub fn new(#[default(0)] value: i64) -> Self {
+            Self::with(value)
+        }
+
+        pub fn get(&self) -> i64 {
+            self.load()
+        }
+
+        pub fn set(&self, value: i64) {
+            self.store(value);
+        }
+    }
+
+    /// A store of strings
+    #[vmod_object(name = "strstore")]
+    impl Store<String> {
+        pub fn new(value: &str) -> Self {
+            Self::with(value.to_string())
+        }
+
+        pub fn get(&self) -> String {
+            self.load()
+        }
+
+        pub fn set(&self, value: &str) {
+            self.store(value.to_string());
+        }
+    }
+
+    #[vmod_object(name = "other_name")]
+    impl Renamed {
+        pub fn new() -> Self {
+            Self
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use varnish::vmod;
+
+fn main() {}
+
+#[vmod]
+mod record {
+    use varnish::vcl::{Ctx, VclError};
+    use varnish::VclRecord;
+
+    #[derive(VclRecord)]
+    pub struct UrlParts {
+        /// The scheme, e.g. `https`
+        scheme: String,
+        host: Option<String>,
+        port: i64,
+    }
+
+    /// Split a URL into its parts
+    pub fn parse_url(url: &str, #[default(80)] default_port: i64) -> UrlParts {
+        UrlParts {
+            scheme: url.split("://").next().unwrap_or_default().to_string(),
+            host: None,
+            port: default_port,
+        }
+    }
+
+    pub fn parse_url_ctx(ctx: &mut Ctx, url: Option<&str>) -> Result<UrlParts, VclError> {
+        Err(VclError::CStr(c"not implemented"))
+    }
+}
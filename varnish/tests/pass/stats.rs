@@ -0,0 +1,43 @@
+#![expect(unused_variables)]
+
+use std::sync::atomic::AtomicU64;
+
+use varnish::{vmod, Stats};
+
+fn main() {}
+
+#[derive(Default, Stats)]
+pub struct Counters {
+    hits: AtomicU64,
+    #[stats(counter)]
+    misses: std::sync::atomic::AtomicU64,
+    #[stats(gauge)]
+    in_flight: AtomicU64,
+}
+
+#[vmod]
+mod stats {
+    use super::Counters;
+    use varnish::vcl::Event;
+
+    #[event]
+    pub fn on_event(evt: Event, #[shared_per_vcl] vcl: &mut Option<Box<Counters>>) {
+        vcl.get_or_insert_with(Box::default);
+    }
+
+    pub fn lookup(#[shared_per_vcl] vcl: Option<&Counters>, hit: bool) -> i64 {
+        let Some(counters) = vcl else { return 0 };
+        counters.in_flight_inc();
+        if hit {
+            counters.hits_inc();
+        } else {
+            counters.misses_add(1);
+        }
+        counters.in_flight_dec();
+        counters.in_flight_set(counters.in_flight_get());
+        counters.in_flight_sub(0);
+        (counters.hits_get() + counters.misses_get())
+            .try_into()
+            .unwrap_or(i64::MAX)
+    }
+}
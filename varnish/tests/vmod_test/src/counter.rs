@@ -0,0 +1,33 @@
+//! A part of the `rustest` VMOD declared outside of the `#[vmod]` module
+
+use std::sync::atomic::AtomicI64;
+
+use varnish::vmod_part;
+
+pub struct Counter {
+    value: AtomicI64,
+}
+
+/// Added to the VMOD by the `parts` parameter of `#[vmod]`
+#[vmod_part]
+pub mod part {
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    use super::Counter;
+
+    pub fn concat(a: &str, #[default("-")] sep: &str, b: &str) -> String {
+        format!("{a}{sep}{b}")
+    }
+
+    impl Counter {
+        pub fn new(#[default(0)] start: i64) -> Self {
+            Self {
+                value: AtomicI64::new(start),
+            }
+        }
+
+        pub fn incr(&self, #[default(1)] step: i64) -> i64 {
+            self.value.fetch_add(step, Ordering::Relaxed) + step
+        }
+    }
+}
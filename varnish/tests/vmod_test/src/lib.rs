@@ -1,26 +1,108 @@
 #![expect(clippy::unnecessary_wraps)]
 
 use std::ffi::CStr;
+use std::sync::Mutex;
 
-use varnish::vcl::{Ctx, FetchProcCtx, FetchProcessor, InitResult, PullResult};
+use varnish::checksum::ChecksumSpec;
+use varnish::tags::{TagIndex, TagSpec};
+use varnish::vcl::{
+    Ctx, FetchProcCtx, FetchProcessor, InitResult, PullResult, TaskLocal, TeeSink, VclError,
+};
 use varnish::vmod;
 
+mod counter;
+
 varnish::run_vtc_tests!("tests/*.vtc");
 
+/// Values collected by `scratch_push()` during the current task
+static SCRATCH: TaskLocal<Vec<String>> = TaskLocal::new();
+
+/// Tags of the cached objects, harvested by the `xkey` fetch processor
+static TAGS: TagIndex = TagIndex::new();
+
+struct Xkey;
+
+impl TagSpec for Xkey {
+    fn name() -> &'static CStr {
+        c"xkey"
+    }
+
+    fn index() -> &'static TagIndex {
+        &TAGS
+    }
+}
+
+struct Checksum;
+
+impl ChecksumSpec for Checksum {
+    fn name() -> &'static CStr {
+        c"checksum"
+    }
+}
+
+/// What the `tee` fetch processor saw of the last body, and whether it saw its end
+static TEED: Mutex<(Vec<u8>, bool)> = Mutex::new((Vec::new(), false));
+
+struct Tee;
+
+impl TeeSink for Tee {
+    fn name() -> &'static CStr {
+        c"tee"
+    }
+
+    fn new(_: &mut Ctx, _: &mut FetchProcCtx) -> InitResult<Self> {
+        *TEED.lock().unwrap() = (Vec::new(), false);
+        InitResult::Ok(Self)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), VclError> {
+        TEED.lock().unwrap().0.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), VclError> {
+        TEED.lock().unwrap().1 = true;
+        Ok(())
+    }
+}
+
+/// A value shared by the tasks, exported as `intreg` and `strreg`
+pub struct Register<T>(Mutex<T>);
+
+impl<T: Clone> Register<T> {
+    fn with(value: T) -> Self {
+        Self(Mutex::new(value))
+    }
+
+    fn load(&self) -> T {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn store(&self, value: T) {
+        *self.0.lock().unwrap() = value;
+    }
+}
+
 /// Test vmod
-#[vmod(docs = "README.md")]
+#[vmod(docs = "README.md", parts(crate::counter::part))]
 mod rustest {
     use std::io::Write;
     use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
     use std::time::Duration;
 
+    use varnish::checksum::ChecksumFetchProcessor;
     use varnish::ffi::VCL_STRING;
-    use varnish::vcl::{CowProbe, Ctx, Event, FetchFilters, Probe, Request, VclError, Workspace};
+    use varnish::tags::TagFetchProcessor;
+    use varnish::vcl::TeeFetchProcessor;
+    use varnish::vcl::{
+        Action, CowProbe, Ctx, Event, FetchFilters, Probe, Request, VclEnum, VclError, Workspace,
+    };
+    use varnish::VclEnum;
 
-    use super::VFPTest;
+    use super::{Register, VFPTest};
 
     pub fn set_hdr(ctx: &mut Ctx, name: &str, value: &str) -> Result<(), VclError> {
-        if let Some(ref mut req) = ctx.http_req {
+        if let Some(req) = ctx.req_mut() {
             Ok(req.set_header(name, value)?)
         } else {
             Err("http_req isn't accessible".into())
@@ -28,7 +110,7 @@ mod rustest {
     }
 
     pub fn unset_hdr(ctx: &mut Ctx, name: &str) -> Result<(), &'static str> {
-        if let Some(ref mut req) = ctx.http_req {
+        if let Some(req) = ctx.req_mut() {
             req.unset_header(name);
             Ok(())
         } else {
@@ -100,6 +182,17 @@ mod rustest {
         }
     }
 
+    pub fn build_ipaddr() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(12, 34, 56, 78))
+    }
+
+    pub fn print_ipaddr(maybe_ip: Option<IpAddr>) -> String {
+        match maybe_ip {
+            None => "0.0.0.0".to_string(),
+            Some(ip) => ip.to_string(),
+        }
+    }
+
     // this is a pretty terrible idea, the request body is probably big, and your workspace is tiny,
     // but hey, it's a test function
     pub unsafe fn req_body(ctx: &mut Ctx) -> Result<VCL_STRING, VclError> {
@@ -118,6 +211,34 @@ mod rustest {
         arg
     }
 
+    pub fn default_duration(#[arg(default_expr = "1.5m")] arg: Duration) -> Duration {
+        arg
+    }
+
+    /// A unit of time
+    #[derive(Clone, Copy, VclEnum)]
+    pub enum Unit {
+        Seconds,
+        Minutes,
+        #[vcl_enum(rename = "h")]
+        Hours,
+    }
+
+    /// Convert `duration` to `unit`
+    pub fn convert_duration(duration: Duration, #[default(Unit::Seconds)] unit: Unit) -> f64 {
+        let secs = duration.as_secs_f64();
+        match unit {
+            Unit::Seconds => secs,
+            Unit::Minutes => secs / 60.0,
+            Unit::Hours => secs / 3600.0,
+        }
+    }
+
+    /// The VCL name of `unit`, or `none`
+    pub fn unit_name(unit: Option<Unit>) -> &'static str {
+        unit.map_or("none", VclEnum::vcl_name)
+    }
+
     pub fn cowprobe_prop(probe: Option<CowProbe<'_>>) -> String {
         probe_prop(probe.map(|v| v.to_owned()))
     }
@@ -140,10 +261,98 @@ mod rustest {
         }
     }
 
+    /// Append `value` to the scratch list of the current task
+    pub fn scratch_push(ctx: &Ctx, value: &str) -> Result<(), VclError> {
+        super::SCRATCH
+            .get_or_init(ctx, Vec::new)?
+            .push(value.to_string());
+        Ok(())
+    }
+
+    /// Join the scratch list of the current task, or return an empty string
+    pub fn scratch_join(ctx: &Ctx, sep: &str) -> Result<String, VclError> {
+        Ok(super::SCRATCH
+            .get(ctx)?
+            .map(|v| v.join(sep))
+            .unwrap_or_default())
+    }
+
+    /// Ban the objects tagged with `tag`, and return their number
+    pub fn tag_purge(ctx: &mut Ctx, tag: &str) -> Result<i64, VclError> {
+        let count = super::TAGS.purge(ctx, tag)?;
+        Ok(i64::try_from(count).unwrap_or(i64::MAX))
+    }
+
+    /// Soft-purge the objects tagged with `tag` on their next hit, and return their number
+    pub fn tag_softpurge(tag: &str) -> i64 {
+        i64::try_from(super::TAGS.soft_purge(tag)).unwrap_or(i64::MAX)
+    }
+
+    /// To be called from `vcl_hit` with `obj.http.x-tag-id`
+    pub fn tag_hit(ctx: &mut Ctx, id: Option<&str>) -> bool {
+        super::TAGS.hit(ctx, id)
+    }
+
+    /// Use `data` as the cache key, to be called from `vcl_hash`
+    pub fn hash_data(ctx: &mut Ctx, data: &str) -> Result<(), VclError> {
+        ctx.hash_data(data)
+    }
+
+    /// The last body seen by the `tee` fetch processor
+    pub fn teed_body() -> String {
+        String::from_utf8_lossy(&super::TEED.lock().unwrap().0).into_owned()
+    }
+
+    /// The `tee` fetch processor was told the last body was over
+    pub fn teed_finished() -> bool {
+        super::TEED.lock().unwrap().1
+    }
+
+    /// End the current subroutine with `return(synth(status))`
+    pub fn return_synth(ctx: &mut Ctx, status: i64) -> Result<(), VclError> {
+        let status = u16::try_from(status).map_err(|e| VclError::new(e.to_string()))?;
+        ctx.set_handling(Action::Synth(status))
+    }
+
+    /// An integer register
+    #[vmod_object(name = "intreg")]
+    impl Register<i64> {
+        pub fn new(#[default(0)] value: i64) -> Self {
+            Self::with(value)
+        }
+
+        pub fn get(&self) -> i64 {
+            self.load()
+        }
+
+        pub fn set(&self, value: i64) {
+            self.store(value);
+        }
+    }
+
+    /// A string register
+    #[vmod_object(name = "strreg")]
+    impl Register<String> {
+        pub fn new(value: &str) -> Self {
+            Self::with(value.to_string())
+        }
+
+        pub fn get(&self) -> String {
+            self.load()
+        }
+
+        pub fn set(&self, value: &str) {
+            self.store(value.to_string());
+        }
+    }
+
     #[event]
     pub fn event(event: Event, vfp: &mut FetchFilters) {
         if let Event::Load = event {
             vfp.register::<VFPTest>();
+            vfp.register::<TagFetchProcessor<super::Xkey>>();
+            vfp.register::<ChecksumFetchProcessor<super::Checksum>>();
+            vfp.register::<TeeFetchProcessor<super::Tee>>();
         }
     }
 }